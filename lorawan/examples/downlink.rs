@@ -1,20 +1,18 @@
 //! LoRaWAN MAC Command and Downlink Processing Example
 //!
-//! This example demonstrates comprehensive MAC command handling:
-//! - Complete implementation of all LoRaWAN 1.0.3 MAC commands
-//! - Proper response generation for network requests
-//! - Dynamic device configuration updates
-//! - Automatic channel management
+//! This example demonstrates downlink handling:
+//! - MAC commands applied and answered by the device
+//! - Decrypted application data returned with its port
 //! - LED feedback for operations:
 //!   * Fast blink: Radio error
 //!   * Double blink: Device error
 //!   * Triple blink: Join error
 //!   * Solid LED: Transmitting
-//!   * Double pulse: Downlink received
+//!   * Double pulse: Downlink received (triple on port 2)
 //!
-//! The device sends a status message every minute and processes any
-//! received MAC commands, demonstrating proper network management
-//! and configuration capabilities. Supported commands include:
+//! The device sends a status message every minute and signals application
+//! downlinks on the LED. The network can manage the device with MAC commands
+//! at any time, e.g.:
 //! - Device status requests
 //! - Duty cycle settings
 //! - RX parameter updates
@@ -28,7 +26,7 @@ use lorawan::{
     class::OperatingMode,
     config::device::{AESKey, DeviceConfig},
     device::LoRaWANDevice,
-    lorawan::{commands::MacCommand, region::US915},
+    lorawan::region::US915,
     radio::sx127x::SX127x,
 };
 
@@ -69,7 +67,7 @@ fn main() -> ! {
     let dio0 = pins.d3.into_floating_input();
     let dio1 = pins.d6.into_floating_input();
     let dio2 = pins.d9.into_floating_input();
-    let radio = match SX127x::new(spi, cs, reset, dio0, dio1, dio2) {
+    let radio = match SX127x::new(spi, cs, reset, dio0, dio1, dio2, hal::delay::Delay::new()) {
         Ok(r) => r,
         Err(_) => loop {
             status_led.toggle().ok();
@@ -107,11 +105,9 @@ fn main() -> ! {
     }
     status_led.set_low().ok();
 
-    // Buffer for received data
-    let mut rx_buffer = [0u8; 256];
     let mut delay = hal::delay::Delay::new();
 
-    // Main loop - handle downlink commands
+    // Main loop - send status and handle downlinks
     loop {
         // Send status update
         let mut payload: Vec<u8, 32> = Vec::new();
@@ -126,73 +122,20 @@ fn main() -> ! {
         }
         status_led.set_low().ok();
 
-        // Check for downlink in both receive windows
+        // Run both receive windows. MAC commands are answered by the device
+        // in the next uplink, only application data is returned.
         for _ in 0..2 {
-            if let Ok(size) = device.receive(&mut rx_buffer) {
-                if size > 0 {
-                    // Process MAC commands in FRMPayload
-                    if let Some(commands) = device.get_mac_commands() {
-                        for cmd in commands {
-                            match cmd {
-                                MacCommand::DevStatusReq => {
-                                    // Respond with device status
-                                    let battery = 255; // Full battery
-                                    let margin = 20; // Good link margin
-                                    device.send_device_status(battery, margin).ok();
-                                }
-                                MacCommand::DutyCycleReq(max_duty_cycle) => {
-                                    // Update duty cycle settings
-                                    device.set_duty_cycle(max_duty_cycle).ok();
-                                }
-                                MacCommand::RXParamSetupReq {
-                                    rx1_dr_offset,
-                                    rx2_data_rate,
-                                    freq,
-                                } => {
-                                    // Update RX parameters
-                                    device
-                                        .set_rx_params(
-                                            rx1_dr_offset,
-                                            rx2_data_rate,
-                                            rx2_data_rate,
-                                            freq,
-                                        )
-                                        .ok();
-                                }
-                                MacCommand::NewChannelReq {
-                                    ch_index,
-                                    freq,
-                                    min_dr,
-                                    max_dr,
-                                } => {
-                                    // Configure new channel
-                                    device.set_channel(ch_index, freq, min_dr, max_dr).ok();
-                                }
-                                MacCommand::DlChannelReq { ch_index, freq } => {
-                                    // Configure downlink channel
-                                    device.set_dl_channel(ch_index, freq).ok();
-                                }
-                                MacCommand::LinkCheckReq => {
-                                    // Link check request received
-                                    // Response will be handled automatically
-                                }
-                                _ => {
-                                    // Handle other MAC commands
-                                }
-                            }
-                        }
-                    }
-
-                    // Indicate received downlink
-                    for _ in 0..2 {
-                        status_led.set_high().ok();
-                        delay.delay_ms(100u32);
-                        status_led.set_low().ok();
-                        delay.delay_ms(100u32);
-                    }
+            device.process().ok();
+            if let Some(downlink) = device.take_downlink() {
+                // Indicate received downlink, one pulse more on port 2
+                let pulses = if downlink.port == 2 { 3 } else { 2 };
+                for _ in 0..pulses {
+                    status_led.set_high().ok();
+                    delay.delay_ms(100u32);
+                    status_led.set_low().ok();
+                    delay.delay_ms(100u32);
                 }
             }
-            device.process().ok();
             delay.delay_ms(1000u32);
         }
 
@@ -19,10 +19,11 @@
 #![no_std]
 #![no_main]
 
+use heapless::Vec;
 use lorawan::{
     class::OperatingMode,
-    config::device::{AESKey, DeviceConfig},
-    device::LoRaWANDevice,
+    config::device::DeviceConfig,
+    device::{DeviceEvent, JoinPolicy, LoRaWANDevice},
     lorawan::region::US915,
     radio::sx127x::SX127x,
 };
@@ -30,12 +31,11 @@ use lorawan::{
 use cortex_m_rt::entry;
 use panic_halt as _;
 
-// Example DevEUI, AppEUI and AppKey - replace with your own from TTN console
-const DEVEUI: [u8; 8] = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]; // LSB
-const APPEUI: [u8; 8] = [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]; // LSB
-const APPKEY: [u8; 16] = [
-    0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F, 0x10,
-]; // MSB
+// Example DevEUI, AppEUI and AppKey - replace with your own, copied from the
+// TTN console as shown there (most significant byte first)
+const DEVEUI: &str = "0807060504030201";
+const APPEUI: &str = "0102030405060708";
+const APPKEY: &str = "0102030405060708090A0B0C0D0E0F10";
 
 #[entry]
 fn main() -> ! {
@@ -63,7 +63,7 @@ fn main() -> ! {
     let dio0 = pins.d3.into_floating_input();
     let dio1 = pins.d6.into_floating_input();
     let dio2 = pins.d9.into_floating_input();
-    let radio = match SX127x::new(spi, cs, reset, dio0, dio1, dio2) {
+    let radio = match SX127x::new(spi, cs, reset, dio0, dio1, dio2, hal::delay::Delay::new()) {
         Ok(r) => r,
         Err(_) => {
             // Rapid blink on radio init error
@@ -75,7 +75,8 @@ fn main() -> ! {
     };
 
     // Create device configuration
-    let config = DeviceConfig::new_otaa(DEVEUI, APPEUI, AESKey::new(APPKEY));
+    let config = DeviceConfig::new_otaa_hex(DEVEUI, APPEUI, APPKEY).unwrap();
+    let (dev_eui, app_eui, app_key) = (config.dev_eui, config.app_eui, config.app_key.clone());
 
     // Initialize LoRaWAN device
     let mut device = match LoRaWANDevice::new(radio, config, US915::new(), OperatingMode::ClassA) {
@@ -94,16 +95,33 @@ fn main() -> ! {
         }
     };
 
+    // Retry the join with a growing backoff, alternating between the 125 kHz
+    // and 500 kHz channels
+    device.set_join_policy(JoinPolicy {
+        max_attempts: 8,
+        base_backoff_ms: 10_000,
+        max_backoff_ms: 600_000,
+        dr_sequence: Vec::from_slice(&[0, 4]).unwrap(),
+    });
+
     // Join network
     red_led.set_high().ok();
-    if let Err(_) = device.join_otaa(DEVEUI, APPEUI, AESKey::new(APPKEY)) {
-        // Triple blink on join error
-        loop {
-            for _ in 0..3 {
-                red_led.toggle().ok();
-                hal::delay::Delay::new().delay_ms(100u32);
+    device.start_join(dev_eui, app_eui, app_key).ok();
+    loop {
+        let now = device.get_mac_layer().get_time();
+        match device.poll(now) {
+            Ok(Some(DeviceEvent::JoinAccepted)) => break,
+            Ok(Some(DeviceEvent::JoinTimeout)) | Err(_) => {
+                // Triple blink on join error
+                loop {
+                    for _ in 0..3 {
+                        red_led.toggle().ok();
+                        hal::delay::Delay::new().delay_ms(100u32);
+                    }
+                    hal::delay::Delay::new().delay_ms(500u32);
+                }
             }
-            hal::delay::Delay::new().delay_ms(500u32);
+            _ => {}
         }
     }
     red_led.set_low().ok();
@@ -0,0 +1,226 @@
+//! LoRaWAN on the Nucleo-WL55JC
+//!
+//! This example runs the stack on the sub-GHz radio built into the STM32WL55:
+//! - SUBGHZSPI transport, no radio wiring needed
+//! - TCXO at 1.7 V on PB0-VDD_TCXO
+//! - RF switch on PC3, PC4 and PC5, set from the radio commands sent
+//! - SysTick as the time source of the stack
+//! - LED feedback:
+//!   * Blue LED: Joining
+//!   * Green LED: Transmitting
+//!   * Red LED: Error
+//!
+//! The device joins an EU868 network and sends a counter every minute.
+//! The Radio IRQ stays disabled in the NVIC, the driver polls its pending bit.
+
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embedded_hal::blocking::delay::DelayMs;
+use lorawan::{
+    class::OperatingMode,
+    config::device::{AESKey, DeviceConfig},
+    device::LoRaWANDevice,
+    lorawan::region::EU868,
+    radio::{
+        stm32wl::SubGhz,
+        sx126x::{RadioError, SX126x, Sx126xConfig, Sx126xInterface, TcxoVoltage},
+    },
+    timing::Clock,
+};
+use stm32wlxx_hal::{
+    cortex_m::{self, peripheral::syst::SystClkSource},
+    gpio::{pins, Output, PortB, PortC},
+    pac,
+};
+
+use cortex_m_rt::{entry, exception};
+use panic_halt as _;
+
+// Example DevEUI, AppEUI and AppKey - replace with your own from TTN console
+const DEVEUI: [u8; 8] = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]; // LSB
+const APPEUI: [u8; 8] = [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]; // LSB
+const APPKEY: [u8; 16] = [
+    0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F, 0x10,
+]; // MSB
+
+/// SysTick frequency, the 4 MHz MSI the STM32WL starts from
+const SYSCLK_HZ: u32 = 4_000_000;
+
+/// Radio opcodes selecting the RF switch path
+const SET_SLEEP: u8 = 0x84;
+const SET_TX: u8 = 0x83;
+const SET_RX: u8 = 0x82;
+
+/// Milliseconds since boot, counted by the SysTick exception
+struct SysTickClock {
+    ms: AtomicU32,
+}
+
+impl Clock for SysTickClock {
+    fn now_ms(&self) -> u32 {
+        self.ms.load(Ordering::Relaxed)
+    }
+}
+
+static CLOCK: SysTickClock = SysTickClock {
+    ms: AtomicU32::new(0),
+};
+
+#[exception]
+fn SysTick() {
+    CLOCK.ms.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Delay of the radio driver, waiting on the SysTick clock
+struct ClockDelay;
+
+impl DelayMs<u32> for ClockDelay {
+    fn delay_ms(&mut self, ms: u32) {
+        CLOCK.delay_until(CLOCK.now_ms().wrapping_add(ms));
+    }
+}
+
+/// SUBGHZSPI transport driving the RF switch of the Nucleo board
+///
+/// The switch follows the commands sent to the radio: the high power PA
+/// path before a transmission, the receive path before a reception, off
+/// when the radio sleeps.
+struct NucleoSubGhz {
+    subghz: SubGhz,
+    fe_ctrl1: Output<pins::C4>,
+    fe_ctrl2: Output<pins::C5>,
+    fe_ctrl3: Output<pins::C3>,
+}
+
+impl NucleoSubGhz {
+    fn set_switch(&mut self, ctrl1: bool, ctrl2: bool, ctrl3: bool) {
+        self.fe_ctrl1.set_level(ctrl1.into());
+        self.fe_ctrl2.set_level(ctrl2.into());
+        self.fe_ctrl3.set_level(ctrl3.into());
+    }
+}
+
+impl Sx126xInterface for NucleoSubGhz {
+    fn write(&mut self, header: &[u8], data: &[u8]) -> Result<(), RadioError> {
+        match header.first() {
+            Some(&SET_TX) => self.set_switch(false, true, true),
+            Some(&SET_RX) => self.set_switch(true, false, true),
+            Some(&SET_SLEEP) => self.set_switch(false, false, false),
+            _ => {}
+        }
+        self.subghz.write(header, data)
+    }
+
+    fn read(&mut self, header: &[u8], data: &mut [u8]) -> Result<(), RadioError> {
+        self.subghz.read(header, data)
+    }
+
+    fn is_busy(&mut self) -> Result<bool, RadioError> {
+        self.subghz.is_busy()
+    }
+
+    fn irq_pending(&mut self) -> Result<bool, RadioError> {
+        self.subghz.irq_pending()
+    }
+
+    fn set_reset(&mut self, asserted: bool) -> Result<(), RadioError> {
+        self.subghz.set_reset(asserted)
+    }
+}
+
+/// Blink the red LED forever
+fn fail(led: &mut Output<pins::B11>) -> ! {
+    loop {
+        led.toggle_level();
+        ClockDelay.delay_ms(100);
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    let mut dp = pac::Peripherals::take().unwrap();
+    let mut cp = pac::CorePeripherals::take().unwrap();
+
+    // 1 ms SysTick
+    cp.SYST.set_clock_source(SystClkSource::Core);
+    cp.SYST.set_reload(SYSCLK_HZ / 1000 - 1);
+    cp.SYST.clear_current();
+    cp.SYST.enable_counter();
+    cp.SYST.enable_interrupt();
+
+    // LEDs and RF switch
+    let gpiob = PortB::split(dp.GPIOB, &mut dp.RCC);
+    let gpioc = PortC::split(dp.GPIOC, &mut dp.RCC);
+    let (mut blue_led, mut green_led, mut red_led, fe_ctrl1, fe_ctrl2, fe_ctrl3) =
+        cortex_m::interrupt::free(|cs| {
+            (
+                Output::default(gpiob.b15, cs),
+                Output::default(gpiob.b9, cs),
+                Output::default(gpiob.b11, cs),
+                Output::default(gpioc.c4, cs),
+                Output::default(gpioc.c5, cs),
+                Output::default(gpioc.c3, cs),
+            )
+        });
+
+    // SAFETY: the HAL's SUBGHZ driver is never created, so the radio
+    // registers are only used through this transport
+    let subghz = unsafe { SubGhz::new() };
+    let interface = NucleoSubGhz {
+        subghz,
+        fe_ctrl1,
+        fe_ctrl2,
+        fe_ctrl3,
+    };
+
+    // The RF switch is driven above, DIO2 is not bonded out
+    let config = Sx126xConfig::default()
+        .with_tcxo(TcxoVoltage::V1_7, 5)
+        .with_dio2_as_rf_switch(false);
+    let radio = match SX126x::with_interface(interface, ClockDelay, config) {
+        Ok(radio) => radio,
+        Err(_) => fail(&mut red_led),
+    };
+
+    let config = DeviceConfig::new_otaa(DEVEUI, APPEUI, AESKey::new(APPKEY));
+    let mut device = match LoRaWANDevice::new(radio, config, EU868::new(), OperatingMode::ClassA) {
+        Ok(device) => device,
+        Err(_) => fail(&mut red_led),
+    };
+    // The radio has no time source
+    device.set_clock(&CLOCK);
+
+    // Join network
+    blue_led.set_level_high();
+    if device
+        .join_otaa(DEVEUI, APPEUI, AESKey::new(APPKEY))
+        .is_err()
+    {
+        fail(&mut red_led);
+    }
+    blue_led.set_level_low();
+
+    // Main loop - send a counter every minute
+    let mut counter = 0u32;
+    loop {
+        green_led.set_level_high();
+        if device.send_data(1, &counter.to_le_bytes(), false).is_err() {
+            red_led.set_level_high();
+        } else {
+            red_led.set_level_low();
+        }
+        green_led.set_level_low();
+
+        // Run both receive windows
+        for _ in 0..2 {
+            device.process().ok();
+            ClockDelay.delay_ms(1000);
+        }
+
+        counter = counter.wrapping_add(1);
+        ClockDelay.delay_ms(60_000);
+    }
+}
@@ -1,9 +1,10 @@
 #![no_std]
 
+use heapless::Vec;
 use lorawan::{
     class::OperatingMode,
     config::device::{AESKey, DeviceConfig},
-    device::LoRaWANDevice,
+    device::{DeviceEvent, JoinPolicy, LoRaWANDevice},
     lorawan::region::US915,
     radio::sx127x::SX127x,
 };
@@ -41,7 +42,8 @@ fn main() -> ! {
     let dio0 = pins.d3.into_floating_input();
     let dio1 = pins.d6.into_floating_input();
     let dio2 = pins.d9.into_floating_input();
-    let radio = SX127x::new(spi, cs, reset, dio0, dio1, dio2).expect("Failed to initialize radio");
+    let radio = SX127x::new(spi, cs, reset, dio0, dio1, dio2, hal::delay::Delay::new())
+        .expect("Failed to initialize radio");
 
     // Create device configuration
     let config = DeviceConfig::new_otaa(DEVEUI, APPEUI, AESKey::new(APPKEY));
@@ -54,13 +56,30 @@ fn main() -> ! {
     let mut device = LoRaWANDevice::new(radio, config, region, OperatingMode::ClassA)
         .expect("Failed to initialize device");
 
+    // Retry the join with a growing backoff, alternating between the 125 kHz
+    // and 500 kHz channels
+    device.set_join_policy(JoinPolicy {
+        max_attempts: 8,
+        base_backoff_ms: 10_000,
+        max_backoff_ms: 600_000,
+        dr_sequence: Vec::from_slice(&[0, 4]).unwrap(),
+    });
+
     // Join network with OTAA
     status_led.set_high().ok();
-    if let Err(_) = device.join_otaa(DEVEUI, APPEUI, AESKey::new(APPKEY)) {
-        // Rapid blink on error
-        loop {
-            status_led.toggle().ok();
-            hal::delay::Delay::new().delay_ms(100u32);
+    device.start_join(DEVEUI, APPEUI, AESKey::new(APPKEY)).ok();
+    loop {
+        let now = device.get_mac_layer().get_time();
+        match device.poll(now) {
+            Ok(Some(DeviceEvent::JoinAccepted)) => break,
+            Ok(Some(DeviceEvent::JoinTimeout)) | Err(_) => {
+                // Rapid blink on error
+                loop {
+                    status_led.toggle().ok();
+                    hal::delay::Delay::new().delay_ms(100u32);
+                }
+            }
+            _ => {}
         }
     }
     status_led.set_low().ok();
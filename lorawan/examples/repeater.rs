@@ -0,0 +1,80 @@
+//! LoRaWAN Repeater Example
+//!
+//! This example forwards the uplinks of devices out of reach of a gateway:
+//! - Hardware initialization for Adafruit Feather M0 with RFM95
+//! - Listening on the channels of US915 sub-band 2, hopping every 2 seconds
+//! - Duplicate suppression, so two repeaters in range do not loop frames
+//! - LED status indication
+//!   * Fast blink: Radio initialization error
+//!   * Single blink: Frame forwarded
+//!
+//! Frames are forwarded on the frequency they were received on. Set a
+//! frequency map with `Repeater::set_frequency_map` to forward them on
+//! another channel instead.
+
+#![no_std]
+#![no_main]
+
+use lorawan::{
+    lorawan::region::US915,
+    radio::sx127x::SX127x,
+    repeater::{Repeater, RepeaterEvent},
+};
+
+use cortex_m_rt::entry;
+use panic_halt as _;
+
+/// Time on a channel before hopping to the next
+const DWELL_MS: u32 = 2_000;
+
+#[entry]
+fn main() -> ! {
+    // Initialize LED pins for status indication
+    let peripherals = hal::Peripherals::take().unwrap();
+    let pins = hal::Pins::new(peripherals.PORT);
+    let mut red_led = pins.d13.into_push_pull_output();
+
+    // Initialize SPI for radio
+    let spi = hal::spi_master(
+        &mut peripherals.PM,
+        peripherals.SERCOM4,
+        &mut peripherals.GCLK,
+        pins.sck,
+        pins.mosi,
+        pins.miso,
+        hal::spi::Polarity::IdleLow,
+        hal::spi::Phase::CaptureOnFirstTransition,
+        1.mhz(),
+    );
+
+    // Initialize radio
+    let cs = pins.rfm_cs.into_push_pull_output();
+    let reset = pins.rfm_rst.into_push_pull_output();
+    let dio0 = pins.d3.into_floating_input();
+    let dio1 = pins.d6.into_floating_input();
+    let dio2 = pins.d9.into_floating_input();
+    let radio = match SX127x::new(spi, cs, reset, dio0, dio1, dio2, hal::delay::Delay::new()) {
+        Ok(r) => r,
+        Err(_) => {
+            // Rapid blink on radio init error
+            loop {
+                red_led.toggle().ok();
+                hal::delay::Delay::new().delay_ms(100u32);
+            }
+        }
+    };
+
+    // Listen on the channels of sub-band 2, as used by TTN
+    let mut region = US915::new();
+    region.set_sub_band(1);
+    let mut repeater: Repeater<_, _> = Repeater::new(radio, region, DWELL_MS);
+
+    let mut delay = hal::delay::Delay::new();
+    loop {
+        if let Ok(Some(RepeaterEvent::Forwarded { .. })) = repeater.poll() {
+            red_led.set_high().ok();
+            delay.delay_ms(50u32);
+            red_led.set_low().ok();
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Application Layer Clock Synchronization (TS003)
+//!
+//! The device sends its time in an AppTimeReq on port 202 and the network
+//! answers with the correction to apply in an AppTimeAns. This synchronizes
+//! the network time without Class B beacons or DeviceTimeReq.
+//!
+//! Times are GPS epoch seconds. The correction is applied to the time of the
+//! AppTimeReq, so the delay until the answer arrives does not matter. Each
+//! request carries a 4-bit token that the answer echoes; answers to older
+//! requests are ignored.
+//!
+//! DeviceAppTimePeriodicityReq is answered as not supported, send
+//! `app_time_req` from the application at the interval it needs.
+
+use core::fmt;
+
+use heapless::Vec;
+
+use crate::class::class_b::timing::NetworkTime;
+
+/// FPort of the clock synchronization package
+pub const CLOCK_SYNC_PORT: u8 = 202;
+
+/// PackageIdentifier of the clock synchronization package
+pub const PACKAGE_IDENTIFIER: u8 = 1;
+
+/// PackageVersion implemented
+pub const PACKAGE_VERSION: u8 = 1;
+
+/// Size of an AppTimeReq
+pub const APP_TIME_REQ_SIZE: usize = 6;
+
+/// Maximum size of the answers to one downlink
+pub const MAX_ANSWER_SIZE: usize = 16;
+
+const PACKAGE_VERSION_CID: u8 = 0x00;
+const APP_TIME_CID: u8 = 0x01;
+const PERIODICITY_CID: u8 = 0x02;
+const FORCE_RESYNC_CID: u8 = 0x03;
+
+/// Errors of the clock synchronization package
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ClockSyncError {
+    /// Command shorter than its payload
+    InvalidLength,
+    /// Command not defined by the package
+    UnknownCommand(u8),
+}
+
+impl fmt::Display for ClockSyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClockSyncError::InvalidLength => f.write_str("invalid clock sync command length"),
+            ClockSyncError::UnknownCommand(cid) => {
+                write!(f, "unknown clock sync command {:#04x}", cid)
+            }
+        }
+    }
+}
+
+impl core::error::Error for ClockSyncError {}
+
+/// Result of a downlink on the clock synchronization port
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClockSyncOutcome {
+    /// Answers to send on `CLOCK_SYNC_PORT`, empty if there are none
+    pub answer: Vec<u8, MAX_ANSWER_SIZE>,
+    /// Correction of an AppTimeAns in seconds, applied to the network time
+    pub correction: Option<i32>,
+    /// Number of AppTimeReq requested by a ForceDeviceResyncReq
+    pub resync_transmissions: u8,
+}
+
+/// State of the clock synchronization package
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ClockSync {
+    /// TokenReq of the next AppTimeReq
+    token: u8,
+    /// DeviceTime and local time of the AppTimeReq awaiting its answer
+    request: Option<(u32, u32)>,
+    /// Last correction applied in seconds
+    last_correction: Option<i32>,
+}
+
+impl ClockSync {
+    /// Create the package state
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the token the next AppTimeAns must echo
+    pub fn token(&self) -> u8 {
+        self.token
+    }
+
+    /// Get the last correction applied in seconds
+    pub fn last_correction(&self) -> Option<i32> {
+        self.last_correction
+    }
+
+    /// Build an AppTimeReq with the device time at local time `local_ms`
+    ///
+    /// The device time is taken from `network_time`, 0 before the first
+    /// synchronization. With `ans_required` the network answers even if the
+    /// device time is correct. Retransmissions keep the token until an
+    /// answer is received.
+    pub fn app_time_req(
+        &mut self,
+        network_time: &NetworkTime,
+        local_ms: u32,
+        ans_required: bool,
+    ) -> [u8; APP_TIME_REQ_SIZE] {
+        let device_time = device_time(network_time, local_ms);
+        self.request = Some((device_time, local_ms));
+
+        let mut request = [0u8; APP_TIME_REQ_SIZE];
+        request[0] = APP_TIME_CID;
+        request[1..5].copy_from_slice(&device_time.to_le_bytes());
+        request[5] = self.token | u8::from(ans_required) << 4;
+        request
+    }
+
+    /// Handle the payload of a downlink on `CLOCK_SYNC_PORT`
+    ///
+    /// The correction of an AppTimeAns carrying the token of the last
+    /// request is applied to `network_time`, then the token advances.
+    /// `local_ms` is the local time the downlink was received at.
+    pub fn handle_downlink(
+        &mut self,
+        payload: &[u8],
+        network_time: &mut NetworkTime,
+        local_ms: u32,
+    ) -> Result<ClockSyncOutcome, ClockSyncError> {
+        let mut outcome = ClockSyncOutcome::default();
+        let mut rest = payload;
+        while let Some((&cid, args)) = rest.split_first() {
+            let len = match cid {
+                PACKAGE_VERSION_CID => 0,
+                APP_TIME_CID => 5,
+                PERIODICITY_CID | FORCE_RESYNC_CID => 1,
+                cid => return Err(ClockSyncError::UnknownCommand(cid)),
+            };
+            let args = args.get(..len).ok_or(ClockSyncError::InvalidLength)?;
+            rest = &rest[1 + len..];
+
+            match cid {
+                PACKAGE_VERSION_CID => {
+                    let _ = outcome.answer.extend_from_slice(&[
+                        PACKAGE_VERSION_CID,
+                        PACKAGE_IDENTIFIER,
+                        PACKAGE_VERSION,
+                    ]);
+                }
+                APP_TIME_CID => {
+                    let correction = i32::from_le_bytes([args[0], args[1], args[2], args[3]]);
+                    if self.apply_correction(correction, args[4] & 0x0F, network_time) {
+                        outcome.correction = Some(correction);
+                    }
+                }
+                PERIODICITY_CID => {
+                    // Status bit 0: periodicity not supported
+                    let time = device_time(network_time, local_ms).to_le_bytes();
+                    let _ = outcome.answer.extend_from_slice(&[
+                        PERIODICITY_CID,
+                        0x01,
+                        time[0],
+                        time[1],
+                        time[2],
+                        time[3],
+                    ]);
+                }
+                _ => outcome.resync_transmissions = args[0] & 0x07,
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Apply the correction of an AppTimeAns if `token` matches the request
+    fn apply_correction(
+        &mut self,
+        correction: i32,
+        token: u8,
+        network_time: &mut NetworkTime,
+    ) -> bool {
+        let Some((device_time, local_ms)) = self.request else {
+            return false;
+        };
+        if token != self.token {
+            debug!("AppTimeAns ignored: token {=u8}", token);
+            return false;
+        }
+        let gps_time = (device_time as i64 + correction as i64).max(0) as u64;
+        network_time.sync_gps_time(gps_time * 1_000, local_ms);
+        self.request = None;
+        self.token = (self.token + 1) & 0x0F;
+        self.last_correction = Some(correction);
+        true
+    }
+}
+
+/// Get the device time in GPS epoch seconds, 0 before the first synchronization
+fn device_time(network_time: &NetworkTime, local_ms: u32) -> u32 {
+    network_time
+        .gps_time_ms_at(local_ms)
+        .map_or(0, |gps_time_ms| (gps_time_ms / 1_000) as u32)
+}
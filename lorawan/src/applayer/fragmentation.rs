@@ -0,0 +1,692 @@
+//! Fragmented Data Block Transport (TS004)
+//!
+//! Receives a data block, e.g. a firmware image, sent on port 201 as
+//! fragments of equal size, usually to a multicast group. The server sets up
+//! a session with FragSessionSetupReq, then sends the `nb_frag` uncoded
+//! fragments of the block followed by coded fragments, each the XOR of a
+//! pseudo-random half of the uncoded ones. Any missing uncoded fragments are
+//! recovered from enough coded fragments.
+//!
+//! The fragments, the reconstruction matrix and the scratch space of the
+//! decoder live in a `FragmentStore`, which may be external flash. The
+//! package itself only keeps a few counters and a 16-byte buffer in RAM.
+//! The number of missing fragments that can be recovered is set with
+//! `Fragmentation::new`; `required_capacity` gives the store size a session
+//! needs.
+//!
+//! Only fragmentation session 0 and fragmentation matrix 0 are supported.
+//! Uncoded fragments received after the first coded one are ignored, the
+//! server sends them in order.
+//!
+//! The application passes the downlinks on `FRAGMENTATION_PORT` to
+//! `Fragmentation::handle_downlink` and sends the answers as uplinks on the
+//! same port, after a random delay of up to `FragSession::block_ack_delay_s`
+//! for FragSessionStatusAns.
+
+use core::fmt;
+
+use heapless::Vec;
+
+/// FPort of the fragmented data block transport package
+pub const FRAGMENTATION_PORT: u8 = 201;
+
+/// PackageIdentifier of the fragmented data block transport package
+pub const PACKAGE_IDENTIFIER: u8 = 3;
+
+/// PackageVersion implemented
+pub const PACKAGE_VERSION: u8 = 1;
+
+/// Maximum number of uncoded fragments of a session
+pub const MAX_FRAGMENTS: u16 = 0x3FFF;
+
+/// Maximum size of the answers to one downlink
+pub const MAX_ANSWER_SIZE: usize = 16;
+
+const PACKAGE_VERSION_CID: u8 = 0x00;
+const STATUS_CID: u8 = 0x01;
+const SETUP_CID: u8 = 0x02;
+const DELETE_CID: u8 = 0x03;
+const DATA_FRAGMENT_CID: u8 = 0x08;
+
+/// FragSessionSetupAns status bits
+const SETUP_ENCODING_UNSUPPORTED: u8 = 0x01;
+const SETUP_NOT_ENOUGH_MEMORY: u8 = 0x02;
+const SETUP_INDEX_NOT_SUPPORTED: u8 = 0x04;
+
+/// FragSessionDeleteAns status bit
+const DELETE_NO_SESSION: u8 = 0x04;
+
+/// FragSessionStatusAns status bit
+const STATUS_NOT_ENOUGH_MATRIX_MEMORY: u8 = 0x01;
+
+/// Size of the RAM buffer of the store operations
+const CHUNK_SIZE: usize = 16;
+
+/// Storage of the fragments and the reconstruction matrix
+///
+/// The store is addressed by byte offset from 0 to `capacity`. Blocks of at
+/// most 16 bytes are read and written, at any offset, so flash backed stores
+/// need a page buffer or a flash that can be rewritten in place.
+pub trait FragmentStore {
+    /// Get the size of the store in bytes
+    fn capacity(&self) -> usize;
+
+    /// Write `data` at `offset`
+    ///
+    /// Returns false if the data could not be written.
+    fn write_block(&mut self, offset: usize, data: &[u8]) -> bool;
+
+    /// Read `data.len()` bytes at `offset` into `data`
+    ///
+    /// Returns false if the data could not be read.
+    fn read_block(&mut self, offset: usize, data: &mut [u8]) -> bool;
+}
+
+/// Errors of the fragmented data block transport package
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FragmentationError {
+    /// Command shorter than its payload, or read beyond the data block
+    InvalidLength,
+    /// Command not defined by the package
+    UnknownCommand(u8),
+    /// The fragment store failed to read or write
+    Store,
+}
+
+impl fmt::Display for FragmentationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FragmentationError::InvalidLength => {
+                f.write_str("invalid fragmentation command length")
+            }
+            FragmentationError::UnknownCommand(cid) => {
+                write!(f, "unknown fragmentation command {:#04x}", cid)
+            }
+            FragmentationError::Store => f.write_str("fragment store failed"),
+        }
+    }
+}
+
+impl core::error::Error for FragmentationError {}
+
+/// Result of a downlink on the fragmentation port
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FragmentationOutcome {
+    /// Answers to send on `FRAGMENTATION_PORT`, empty if there are none
+    pub answer: Vec<u8, MAX_ANSWER_SIZE>,
+    /// The data block was completed by this downlink
+    pub complete: bool,
+}
+
+/// Parameters of a fragmentation session
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FragSession {
+    /// Multicast groups the fragments are sent to, one bit per group
+    pub mc_group_mask: u8,
+    /// Number of uncoded fragments
+    pub nb_frag: u16,
+    /// Size of each fragment in bytes
+    pub frag_size: u8,
+    /// Maximum delay of a FragSessionStatusAns in seconds, the minimum is
+    /// half of it
+    pub block_ack_delay_s: u16,
+    /// Padding bytes at the end of the last uncoded fragment
+    pub padding: u8,
+    /// Descriptor of the data block, defined by the application
+    pub descriptor: u32,
+}
+
+impl FragSession {
+    /// Get the size of the data block in bytes
+    pub fn data_len(&self) -> usize {
+        (self.nb_frag as usize * self.frag_size as usize).saturating_sub(self.padding as usize)
+    }
+}
+
+/// Reception state of the session
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct DecoderState {
+    /// Fragments received, uncoded and coded
+    received: u16,
+    /// Distinct uncoded fragments received
+    uncoded: u16,
+    /// Uncoded fragments missing once the first coded one was received
+    lost: Option<u16>,
+    /// Rows of the reconstruction matrix
+    rank: u16,
+    /// More fragments missing than `max_missing`
+    matrix_overflow: bool,
+    /// Data block reconstructed
+    complete: bool,
+}
+
+/// Store layout of a session
+///
+/// The uncoded fragments come first, in order, followed by a bitmap of the
+/// uncoded fragments received, the scratch space of a coded fragment, the
+/// indices of the missing fragments and the reconstruction matrix. The
+/// matrix has a row per missing fragment, with a bit per missing fragment.
+/// A row is set once its diagonal bit is set; the reduced coded fragment of
+/// the row is kept in the slot of its missing fragment until the matrix is
+/// solved.
+struct Layout {
+    nb_frag: usize,
+    frag_size: usize,
+    bitmap_len: usize,
+    row_len: usize,
+    max_missing: usize,
+}
+
+impl Layout {
+    fn new(nb_frag: u16, frag_size: u8, max_missing: u16) -> Self {
+        Self {
+            nb_frag: nb_frag as usize,
+            frag_size: frag_size as usize,
+            bitmap_len: (nb_frag as usize).div_ceil(8),
+            row_len: (max_missing as usize).div_ceil(8),
+            max_missing: max_missing as usize,
+        }
+    }
+
+    fn fragment(&self, index: usize) -> usize {
+        index * self.frag_size
+    }
+
+    /// Bitmap of the uncoded fragments received
+    fn received(&self) -> usize {
+        self.nb_frag * self.frag_size
+    }
+
+    /// Bitmap of the uncoded fragments of a coded fragment
+    fn coded_row(&self) -> usize {
+        self.received() + self.bitmap_len
+    }
+
+    /// Data of a coded fragment
+    fn coded_data(&self) -> usize {
+        self.coded_row() + self.bitmap_len
+    }
+
+    /// Indices of the missing fragments, 2 bytes each
+    fn missing(&self) -> usize {
+        self.coded_data() + self.frag_size
+    }
+
+    /// Row of a coded fragment over the missing fragments
+    fn missing_row(&self) -> usize {
+        self.missing() + 2 * self.max_missing
+    }
+
+    fn matrix_row(&self, row: usize) -> usize {
+        self.missing_row() + self.row_len * (1 + row)
+    }
+
+    fn capacity(&self) -> usize {
+        self.matrix_row(self.max_missing)
+    }
+}
+
+/// State of the fragmented data block transport package
+#[derive(Debug)]
+pub struct Fragmentation<S: FragmentStore> {
+    store: S,
+    /// Missing fragments that can be recovered
+    max_missing: u16,
+    session: Option<FragSession>,
+    state: DecoderState,
+}
+
+impl<S: FragmentStore> Fragmentation<S> {
+    /// Create the package state
+    ///
+    /// Up to `max_missing` missing uncoded fragments of a session can be
+    /// recovered from coded fragments.
+    pub fn new(store: S, max_missing: u16) -> Self {
+        Self {
+            store,
+            max_missing,
+            session: None,
+            state: DecoderState::default(),
+        }
+    }
+
+    /// Get the store size needed by a session
+    pub fn required_capacity(nb_frag: u16, frag_size: u8, max_missing: u16) -> usize {
+        Layout::new(nb_frag, frag_size, max_missing).capacity()
+    }
+
+    /// Get the current session, if one was set up
+    pub fn session(&self) -> Option<&FragSession> {
+        self.session.as_ref()
+    }
+
+    /// Check if the data block of the session is complete
+    pub fn is_complete(&self) -> bool {
+        self.state.complete
+    }
+
+    /// Get the fragment store
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Get the fragment store mutably
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    /// Read the data block at `offset` into `data`
+    ///
+    /// The data block is at the start of the store; it is only complete once
+    /// `is_complete` returns true.
+    pub fn read_data(&mut self, offset: usize, data: &mut [u8]) -> Result<(), FragmentationError> {
+        let data_len = self.session.as_ref().map_or(0, FragSession::data_len);
+        if offset + data.len() > data_len {
+            return Err(FragmentationError::InvalidLength);
+        }
+        store_result(self.store.read_block(offset, data))
+    }
+
+    /// Handle the payload of a downlink on `FRAGMENTATION_PORT`
+    pub fn handle_downlink(
+        &mut self,
+        payload: &[u8],
+    ) -> Result<FragmentationOutcome, FragmentationError> {
+        let mut outcome = FragmentationOutcome::default();
+        let mut rest = payload;
+        while let Some((&cid, args)) = rest.split_first() {
+            let len = match cid {
+                PACKAGE_VERSION_CID => 0,
+                STATUS_CID | DELETE_CID => 1,
+                SETUP_CID => 10,
+                // A DataFragment takes the rest of the payload
+                DATA_FRAGMENT_CID => args.len().max(2),
+                cid => return Err(FragmentationError::UnknownCommand(cid)),
+            };
+            let args = args.get(..len).ok_or(FragmentationError::InvalidLength)?;
+            rest = &rest[1 + len..];
+
+            match cid {
+                PACKAGE_VERSION_CID => {
+                    let _ = outcome.answer.extend_from_slice(&[
+                        PACKAGE_VERSION_CID,
+                        PACKAGE_IDENTIFIER,
+                        PACKAGE_VERSION,
+                    ]);
+                }
+                STATUS_CID => {
+                    if let Some(answer) = self.status(args[0]) {
+                        let _ = outcome.answer.extend_from_slice(&answer);
+                    }
+                }
+                SETUP_CID => {
+                    let status = self.setup(args)?;
+                    let _ = outcome.answer.extend_from_slice(&[SETUP_CID, status]);
+                }
+                DELETE_CID => {
+                    let index = args[0] & 0x03;
+                    let mut status = index;
+                    if index != 0 || self.session.take().is_none() {
+                        status |= DELETE_NO_SESSION;
+                    }
+                    let _ = outcome.answer.extend_from_slice(&[DELETE_CID, status]);
+                }
+                _ => {
+                    let index_and_n = u16::from_le_bytes([args[0], args[1]]);
+                    if index_and_n >> 14 == 0 {
+                        let was_complete = self.state.complete;
+                        self.data_fragment(index_and_n & 0x3FFF, &args[2..])?;
+                        outcome.complete |= !was_complete && self.state.complete;
+                    }
+                }
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Build the FragSessionStatusAns, `None` if the device does not answer
+    fn status(&self, param: u8) -> Option<[u8; 5]> {
+        let all_participants = param & 0x01 != 0;
+        let index = (param >> 1) & 0x03;
+        if index != 0 || self.session.is_none() || (self.state.complete && !all_participants) {
+            return None;
+        }
+
+        let state = &self.state;
+        let nb_frag = self.session.as_ref().map_or(0, |session| session.nb_frag);
+        let missing = match state.lost {
+            _ if state.complete => 0,
+            Some(lost) => lost - state.rank,
+            None => nb_frag - state.uncoded,
+        };
+        let received = (state.received & 0x3FFF) | (index as u16) << 14;
+        let status = if state.matrix_overflow {
+            STATUS_NOT_ENOUGH_MATRIX_MEMORY
+        } else {
+            0
+        };
+        let received = received.to_le_bytes();
+        Some([
+            STATUS_CID,
+            received[0],
+            received[1],
+            missing.min(255) as u8,
+            status,
+        ])
+    }
+
+    /// Set up a session from a FragSessionSetupReq, returning the answer status
+    fn setup(&mut self, args: &[u8]) -> Result<u8, FragmentationError> {
+        let index = (args[0] >> 4) & 0x03;
+        let nb_frag = u16::from_le_bytes([args[1], args[2]]);
+        let frag_size = args[3];
+        let control = args[4];
+        let session = FragSession {
+            mc_group_mask: args[0] & 0x0F,
+            nb_frag,
+            frag_size,
+            block_ack_delay_s: 32 << (control & 0x07),
+            padding: args[5],
+            descriptor: u32::from_le_bytes([args[6], args[7], args[8], args[9]]),
+        };
+
+        let mut status = 0;
+        // An empty session cannot be encoded either
+        if (control >> 3) & 0x07 != 0 || nb_frag == 0 || nb_frag > MAX_FRAGMENTS || frag_size == 0 {
+            status |= SETUP_ENCODING_UNSUPPORTED;
+        }
+        if index != 0 {
+            status |= SETUP_INDEX_NOT_SUPPORTED;
+        }
+        let layout = Layout::new(nb_frag, frag_size, self.max_missing);
+        if layout.capacity() > self.store.capacity() {
+            status |= SETUP_NOT_ENOUGH_MEMORY;
+        }
+        if status != 0 {
+            debug!("FragSessionSetupReq rejected: {=u8:#x}", status);
+            return Ok(index << 6 | status);
+        }
+
+        fill_zero(&mut self.store, layout.received(), layout.bitmap_len)?;
+        self.session = Some(session);
+        self.state = DecoderState::default();
+        Ok(index << 6)
+    }
+
+    /// Handle DataFragment `n`, counted from 1
+    fn data_fragment(&mut self, n: u16, data: &[u8]) -> Result<(), FragmentationError> {
+        let Some(session) = &self.session else {
+            return Ok(());
+        };
+        if n == 0 || data.len() != session.frag_size as usize || self.state.complete {
+            return Ok(());
+        }
+        let layout = Layout::new(session.nb_frag, session.frag_size, self.max_missing);
+        let nb_frag = session.nb_frag;
+        self.state.received = self.state.received.saturating_add(1);
+
+        if n <= nb_frag {
+            let index = n as usize - 1;
+            if self.state.lost.is_some() || get_bit(&mut self.store, layout.received(), index)? {
+                return Ok(());
+            }
+            store_result(self.store.write_block(layout.fragment(index), data))?;
+            set_bit(&mut self.store, layout.received(), index)?;
+            self.state.uncoded += 1;
+            self.state.complete = self.state.uncoded == nb_frag;
+            return Ok(());
+        }
+
+        let lost = match self.state.lost {
+            Some(lost) => lost,
+            None => self.collect_missing(&layout)?,
+        };
+        if self.state.matrix_overflow || lost == 0 {
+            return Ok(());
+        }
+        store_result(self.store.write_block(layout.coded_data(), data))?;
+        if self.reduce_coded(&layout, n - nb_frag)? {
+            self.state.rank += 1;
+            if self.state.rank == lost {
+                self.solve(&layout, lost as usize)?;
+                self.state.complete = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// List the missing uncoded fragments when the first coded one arrives
+    fn collect_missing(&mut self, layout: &Layout) -> Result<u16, FragmentationError> {
+        let mut lost = 0;
+        for index in 0..layout.nb_frag {
+            if get_bit(&mut self.store, layout.received(), index)? {
+                continue;
+            }
+            if lost < layout.max_missing {
+                let offset = layout.missing() + 2 * lost;
+                store_result(
+                    self.store
+                        .write_block(offset, &(index as u16).to_le_bytes()),
+                )?;
+            }
+            lost += 1;
+        }
+        if lost > layout.max_missing {
+            debug!("{=usize} fragments missing, matrix too small", lost);
+            self.state.matrix_overflow = true;
+        } else {
+            fill_zero(
+                &mut self.store,
+                layout.matrix_row(0),
+                layout.row_len * layout.max_missing,
+            )?;
+        }
+        self.state.lost = Some(lost as u16);
+        Ok(lost as u16)
+    }
+
+    /// Reduce coded fragment `n`, counted from 1 after the uncoded ones
+    ///
+    /// The uncoded fragments received are removed from the coded fragment,
+    /// then the rows of the matrix. Returns true if the remaining row was
+    /// added to the matrix, false if the fragment brought nothing new.
+    fn reduce_coded(&mut self, layout: &Layout, n: u16) -> Result<bool, FragmentationError> {
+        let store = &mut self.store;
+        fill_zero(store, layout.coded_row(), layout.bitmap_len)?;
+        for index in parity_row(n, layout.nb_frag as u16) {
+            set_bit(store, layout.coded_row(), index)?;
+        }
+
+        fill_zero(store, layout.missing_row(), layout.row_len)?;
+        let mut missing = 0;
+        for index in 0..layout.nb_frag {
+            let received = get_bit(store, layout.received(), index)?;
+            if get_bit(store, layout.coded_row(), index)? {
+                if received {
+                    xor_blocks(
+                        store,
+                        layout.coded_data(),
+                        layout.fragment(index),
+                        layout.frag_size,
+                    )?;
+                } else {
+                    set_bit(store, layout.missing_row(), missing)?;
+                }
+            }
+            if !received {
+                missing += 1;
+            }
+        }
+
+        while let Some(first) = first_bit(store, layout.missing_row(), missing)? {
+            let slot = layout.fragment(missing_index(store, layout, first)?);
+            if !get_bit(store, layout.matrix_row(first), first)? {
+                xor_blocks(
+                    store,
+                    layout.matrix_row(first),
+                    layout.missing_row(),
+                    layout.row_len,
+                )?;
+                copy_block(store, slot, layout.coded_data(), layout.frag_size)?;
+                return Ok(true);
+            }
+            xor_blocks(
+                store,
+                layout.missing_row(),
+                layout.matrix_row(first),
+                layout.row_len,
+            )?;
+            xor_blocks(store, layout.coded_data(), slot, layout.frag_size)?;
+        }
+        Ok(false)
+    }
+
+    /// Solve the full rank matrix, leaving the missing fragments in their slots
+    fn solve(&mut self, layout: &Layout, lost: usize) -> Result<(), FragmentationError> {
+        let store = &mut self.store;
+        for row in (0..lost).rev() {
+            let slot = layout.fragment(missing_index(store, layout, row)?);
+            for column in row + 1..lost {
+                if get_bit(store, layout.matrix_row(row), column)? {
+                    let solved = layout.fragment(missing_index(store, layout, column)?);
+                    xor_blocks(store, slot, solved, layout.frag_size)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Get the indices of the uncoded fragments of coded fragment `n`
+///
+/// `n` counts from 1 after the `nb_frag` uncoded fragments. This is the
+/// pseudo-random row of fragmentation matrix 0; an index may be returned
+/// more than once but is part of the coded fragment once.
+fn parity_row(n: u16, nb_frag: u16) -> impl Iterator<Item = usize> {
+    let nb_frag = nb_frag as u32;
+    let modulus = nb_frag + u32::from(nb_frag.is_power_of_two());
+    let mut x = 1 + 1001 * n as u32;
+    (0..nb_frag / 2).map(move |_| loop {
+        x = prbs23(x);
+        let r = x % modulus;
+        if r < nb_frag {
+            break r as usize;
+        }
+    })
+}
+
+/// Step the 23-bit PRBS of the fragmentation matrix
+fn prbs23(x: u32) -> u32 {
+    let feedback = (x ^ (x >> 5)) & 0x01;
+    (x >> 1) + (feedback << 22)
+}
+
+fn store_result(ok: bool) -> Result<(), FragmentationError> {
+    if ok {
+        Ok(())
+    } else {
+        Err(FragmentationError::Store)
+    }
+}
+
+/// Get the fragment index of missing fragment `missing`
+fn missing_index<S: FragmentStore>(
+    store: &mut S,
+    layout: &Layout,
+    missing: usize,
+) -> Result<usize, FragmentationError> {
+    let mut index = [0; 2];
+    store_result(store.read_block(layout.missing() + 2 * missing, &mut index))?;
+    Ok(u16::from_le_bytes(index) as usize)
+}
+
+fn fill_zero<S: FragmentStore>(
+    store: &mut S,
+    offset: usize,
+    len: usize,
+) -> Result<(), FragmentationError> {
+    let zeros = [0; CHUNK_SIZE];
+    for start in (0..len).step_by(CHUNK_SIZE) {
+        let end = len.min(start + CHUNK_SIZE);
+        store_result(store.write_block(offset + start, &zeros[..end - start]))?;
+    }
+    Ok(())
+}
+
+fn get_bit<S: FragmentStore>(
+    store: &mut S,
+    offset: usize,
+    bit: usize,
+) -> Result<bool, FragmentationError> {
+    let mut byte = [0];
+    store_result(store.read_block(offset + bit / 8, &mut byte))?;
+    Ok(byte[0] & 1 << (bit % 8) != 0)
+}
+
+fn set_bit<S: FragmentStore>(
+    store: &mut S,
+    offset: usize,
+    bit: usize,
+) -> Result<(), FragmentationError> {
+    let mut byte = [0];
+    store_result(store.read_block(offset + bit / 8, &mut byte))?;
+    byte[0] |= 1 << (bit % 8);
+    store_result(store.write_block(offset + bit / 8, &byte))
+}
+
+/// Find the first set bit of the `bits` bits at `offset`
+fn first_bit<S: FragmentStore>(
+    store: &mut S,
+    offset: usize,
+    bits: usize,
+) -> Result<Option<usize>, FragmentationError> {
+    let mut chunk = [0; CHUNK_SIZE];
+    let len = bits.div_ceil(8);
+    for start in (0..len).step_by(CHUNK_SIZE) {
+        let chunk = &mut chunk[..len.min(start + CHUNK_SIZE) - start];
+        store_result(store.read_block(offset + start, chunk))?;
+        if let Some(i) = chunk.iter().position(|&byte| byte != 0) {
+            let bit = 8 * (start + i) + chunk[i].trailing_zeros() as usize;
+            return Ok((bit < bits).then_some(bit));
+        }
+    }
+    Ok(None)
+}
+
+/// XOR the `len` bytes at `src` into those at `dst`
+fn xor_blocks<S: FragmentStore>(
+    store: &mut S,
+    dst: usize,
+    src: usize,
+    len: usize,
+) -> Result<(), FragmentationError> {
+    let mut a = [0; CHUNK_SIZE];
+    let mut b = [0; CHUNK_SIZE];
+    for start in (0..len).step_by(CHUNK_SIZE) {
+        let size = len.min(start + CHUNK_SIZE) - start;
+        store_result(store.read_block(dst + start, &mut a[..size]))?;
+        store_result(store.read_block(src + start, &mut b[..size]))?;
+        a.iter_mut().zip(&b).for_each(|(a, b)| *a ^= b);
+        store_result(store.write_block(dst + start, &a[..size]))?;
+    }
+    Ok(())
+}
+
+/// Copy the `len` bytes at `src` to `dst`
+fn copy_block<S: FragmentStore>(
+    store: &mut S,
+    dst: usize,
+    src: usize,
+    len: usize,
+) -> Result<(), FragmentationError> {
+    let mut chunk = [0; CHUNK_SIZE];
+    for start in (0..len).step_by(CHUNK_SIZE) {
+        let chunk = &mut chunk[..len.min(start + CHUNK_SIZE) - start];
+        store_result(store.read_block(src + start, chunk))?;
+        store_result(store.write_block(dst + start, chunk))?;
+    }
+    Ok(())
+}
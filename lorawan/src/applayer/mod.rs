@@ -0,0 +1,11 @@
+//! Application layer packages
+//!
+//! Packages of the LoRa Alliance that run on top of LoRaWAN on their own
+//! FPort. They are optional: the device only handles the ports of clock
+//! synchronization and remote multicast setup once the application enables
+//! them, while the application passes the downlinks of fragmentation to the
+//! package itself.
+
+pub mod clock_sync;
+pub mod fragmentation;
+pub mod multicast_setup;
@@ -0,0 +1,326 @@
+//! Remote Multicast Setup (TS005)
+//!
+//! The network sets up multicast groups on port 200 with McGroupSetupReq,
+//! carrying the McKey of the group encrypted with the McKEKey derived from
+//! the GenAppKey of the device, and opens multicast sessions with
+//! McClassCSessionReq and McClassBSessionReq. During a session the device
+//! temporarily switches to Class C or B to receive the group downlinks,
+//! e.g. the fragments of a firmware update, then returns to its class.
+//!
+//! The package builds the answers and tells the device which groups to add
+//! or remove and which session to schedule. Session times are GPS epoch
+//! seconds; without a network time, see `request_time_sync`, sessions start
+//! right away.
+
+use core::fmt;
+
+use heapless::Vec;
+
+use crate::{
+    class::OperatingMode,
+    config::device::{AESKey, DevAddr},
+    crypto::{CryptoBackend, SoftwareCrypto, BLOCK_SIZE},
+    lorawan::{
+        multicast::{MulticastGroup, MAX_MULTICAST_GROUPS},
+        region::Region,
+    },
+};
+
+/// FPort of the remote multicast setup package
+pub const MULTICAST_SETUP_PORT: u8 = 200;
+
+/// PackageIdentifier of the remote multicast setup package
+pub const PACKAGE_IDENTIFIER: u8 = 2;
+
+/// PackageVersion implemented
+pub const PACKAGE_VERSION: u8 = 1;
+
+/// Number of McGroupIDs of the package
+pub const MAX_MC_GROUP_IDS: usize = 4;
+
+/// Maximum size of the answers to one downlink
+pub const MAX_ANSWER_SIZE: usize = 32;
+
+const PACKAGE_VERSION_CID: u8 = 0x00;
+const GROUP_STATUS_CID: u8 = 0x01;
+const GROUP_SETUP_CID: u8 = 0x02;
+const GROUP_DELETE_CID: u8 = 0x03;
+const CLASS_C_SESSION_CID: u8 = 0x04;
+const CLASS_B_SESSION_CID: u8 = 0x05;
+
+/// McGroupDeleteAns status bit
+const GROUP_UNDEFINED: u8 = 0x04;
+
+/// McClassCSessionAns and McClassBSessionAns status bits
+const SESSION_DR_ERROR: u8 = 0x04;
+const SESSION_FREQ_ERROR: u8 = 0x08;
+const SESSION_GROUP_UNDEFINED: u8 = 0x10;
+
+/// Errors of the remote multicast setup package
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MulticastSetupError {
+    /// Command shorter than its payload
+    InvalidLength,
+    /// Command not defined by the package
+    UnknownCommand(u8),
+}
+
+impl fmt::Display for MulticastSetupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MulticastSetupError::InvalidLength => {
+                f.write_str("invalid multicast setup command length")
+            }
+            MulticastSetupError::UnknownCommand(cid) => {
+                write!(f, "unknown multicast setup command {:#04x}", cid)
+            }
+        }
+    }
+}
+
+impl core::error::Error for MulticastSetupError {}
+
+/// Multicast group defined by a McGroupSetupReq
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct McGroupParams {
+    /// Multicast address of the group
+    pub addr: DevAddr,
+    /// Last frame counter of the group, it is removed once received
+    pub max_fcnt: u32,
+}
+
+/// Change of the multicast groups of the device
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum McGroupChange {
+    /// Add the group, or replace the group of the same address
+    Setup(MulticastGroup),
+    /// Remove the group of the address
+    Delete(DevAddr),
+}
+
+/// Multicast session of a McClassCSessionReq or McClassBSessionReq
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct McSession {
+    /// McGroupID of the group
+    pub group_id: u8,
+    /// Multicast address of the group
+    pub addr: DevAddr,
+    /// Class of the session, Class B or C
+    pub mode: OperatingMode,
+    /// Time until the session starts in seconds, 0 if it started already
+    /// or the device time is unknown
+    pub time_to_start_s: u32,
+    /// Duration of the session from its start in seconds
+    pub duration_s: u32,
+    /// Downlink frequency in Hz, 0 for the default ping slot channel
+    pub frequency: u32,
+    /// Downlink data rate index
+    pub data_rate: u8,
+    /// Ping slot periodicity of a Class B session
+    pub periodicity: u8,
+}
+
+/// Result of a downlink on the remote multicast setup port
+#[derive(Debug, Clone, Default)]
+pub struct MulticastSetupOutcome {
+    /// Answers to send on `MULTICAST_SETUP_PORT`, empty if there are none
+    pub answer: Vec<u8, MAX_ANSWER_SIZE>,
+    /// Groups to add or remove, in order
+    pub changes: Vec<McGroupChange, { 2 * MAX_MULTICAST_GROUPS }>,
+    /// Session to schedule, replacing the previous one
+    pub session: Option<McSession>,
+}
+
+/// State of the remote multicast setup package
+pub struct MulticastSetup {
+    /// Key encryption key of the McKeys
+    mc_ke_key: AESKey,
+    /// Groups by McGroupID
+    groups: [Option<McGroupParams>; MAX_MC_GROUP_IDS],
+    crypto: &'static dyn CryptoBackend,
+}
+
+impl MulticastSetup {
+    /// Create the package state for the GenAppKey of the device
+    pub fn new(gen_app_key: &AESKey) -> Self {
+        Self::with_crypto_backend(gen_app_key, &SoftwareCrypto)
+    }
+
+    /// Create the package state running the key derivation on `crypto`
+    pub fn with_crypto_backend(gen_app_key: &AESKey, crypto: &'static dyn CryptoBackend) -> Self {
+        Self {
+            mc_ke_key: crypto.derive_mc_ke_key(gen_app_key),
+            groups: [None; MAX_MC_GROUP_IDS],
+            crypto,
+        }
+    }
+
+    /// Get the group of `group_id`, if set up
+    pub fn group(&self, group_id: u8) -> Option<&McGroupParams> {
+        self.groups.get(group_id as usize)?.as_ref()
+    }
+
+    /// Handle the payload of a downlink on `MULTICAST_SETUP_PORT`
+    ///
+    /// Session frequencies and data rates are checked against `region`.
+    /// `gps_time_s` is the current GPS time of the device, if known.
+    pub fn handle_downlink<REG: Region>(
+        &mut self,
+        payload: &[u8],
+        region: &REG,
+        gps_time_s: Option<u32>,
+    ) -> Result<MulticastSetupOutcome, MulticastSetupError> {
+        let mut outcome = MulticastSetupOutcome::default();
+        let mut rest = payload;
+        while let Some((&cid, args)) = rest.split_first() {
+            let len = match cid {
+                PACKAGE_VERSION_CID => 0,
+                GROUP_STATUS_CID | GROUP_DELETE_CID => 1,
+                GROUP_SETUP_CID => 29,
+                CLASS_C_SESSION_CID | CLASS_B_SESSION_CID => 10,
+                cid => return Err(MulticastSetupError::UnknownCommand(cid)),
+            };
+            let args = args.get(..len).ok_or(MulticastSetupError::InvalidLength)?;
+            rest = &rest[1 + len..];
+
+            match cid {
+                PACKAGE_VERSION_CID => {
+                    let _ = outcome.answer.extend_from_slice(&[
+                        PACKAGE_VERSION_CID,
+                        PACKAGE_IDENTIFIER,
+                        PACKAGE_VERSION,
+                    ]);
+                }
+                GROUP_STATUS_CID => self.group_status(args[0] & 0x0F, &mut outcome.answer),
+                GROUP_SETUP_CID => {
+                    let group_id = args[0] & 0x03;
+                    self.setup_group(group_id, args, &mut outcome);
+                    let _ = outcome
+                        .answer
+                        .extend_from_slice(&[GROUP_SETUP_CID, group_id]);
+                }
+                GROUP_DELETE_CID => {
+                    let group_id = args[0] & 0x03;
+                    let status = match self.groups[group_id as usize].take() {
+                        Some(group) => {
+                            let _ = outcome.changes.push(McGroupChange::Delete(group.addr));
+                            group_id
+                        }
+                        None => group_id | GROUP_UNDEFINED,
+                    };
+                    let _ = outcome
+                        .answer
+                        .extend_from_slice(&[GROUP_DELETE_CID, status]);
+                }
+                _ => {
+                    let (status, session) = self.session(cid, args, region, gps_time_s);
+                    let _ = outcome.answer.extend_from_slice(&[cid, status]);
+                    if let Some(session) = session {
+                        let time_to_start = session.time_to_start_s.to_le_bytes();
+                        let _ = outcome.answer.extend_from_slice(&time_to_start[..3]);
+                        outcome.session = Some(session);
+                    }
+                }
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Append the McGroupStatusAns of the groups of `mask`
+    fn group_status(&self, mask: u8, answer: &mut Vec<u8, MAX_ANSWER_SIZE>) {
+        let defined = self.groups.iter().filter(|group| group.is_some()).count() as u8;
+        let answered = (0..MAX_MC_GROUP_IDS as u8)
+            .filter(|&id| mask & (1 << id) != 0 && self.groups[id as usize].is_some());
+        let ans_mask = answered.clone().fold(0, |ans_mask, id| ans_mask | 1 << id);
+
+        let _ = answer.extend_from_slice(&[GROUP_STATUS_CID, defined << 4 | ans_mask]);
+        for id in answered {
+            if let Some(group) = &self.groups[id as usize] {
+                let _ = answer.push(id);
+                let _ = answer.extend_from_slice(group.addr.as_bytes());
+            }
+        }
+    }
+
+    /// Set up group `group_id` from the arguments of a McGroupSetupReq
+    fn setup_group(&mut self, group_id: u8, args: &[u8], outcome: &mut MulticastSetupOutcome) {
+        let addr = DevAddr::new([args[1], args[2], args[3], args[4]]);
+        let mut mc_key_encrypted = [0u8; BLOCK_SIZE];
+        mc_key_encrypted.copy_from_slice(&args[5..21]);
+        let min_fcnt = u32::from_le_bytes([args[21], args[22], args[23], args[24]]);
+        let max_fcnt = u32::from_le_bytes([args[25], args[26], args[27], args[28]]);
+
+        let mc_key = self
+            .crypto
+            .unwrap_mc_key(&self.mc_ke_key, &mc_key_encrypted);
+        let (nwk_skey, app_skey) = self.crypto.derive_mc_session_keys(&mc_key, addr);
+        let mut group = MulticastGroup::new(addr, nwk_skey, app_skey);
+        group.fcnt_down = min_fcnt;
+
+        if let Some(previous) = self.groups[group_id as usize] {
+            if previous.addr != addr {
+                let _ = outcome.changes.push(McGroupChange::Delete(previous.addr));
+            }
+        }
+        self.groups[group_id as usize] = Some(McGroupParams { addr, max_fcnt });
+        let _ = outcome.changes.push(McGroupChange::Setup(group));
+    }
+
+    /// Check a McClassCSessionReq or McClassBSessionReq
+    ///
+    /// Returns the status of the answer and the session, if accepted.
+    fn session<REG: Region>(
+        &self,
+        cid: u8,
+        args: &[u8],
+        region: &REG,
+        gps_time_s: Option<u32>,
+    ) -> (u8, Option<McSession>) {
+        let group_id = args[0] & 0x03;
+        let session_time = u32::from_le_bytes([args[1], args[2], args[3], args[4]]);
+        let frequency = u32::from_le_bytes([args[6], args[7], args[8], 0]) * 100;
+        let data_rate = args[9];
+        let class_b = cid == CLASS_B_SESSION_CID;
+
+        let mut status = group_id;
+        let group = self.groups[group_id as usize];
+        if group.is_none() {
+            status |= SESSION_GROUP_UNDEFINED;
+        }
+        // Class B sessions may use the default ping slot channel
+        if !(region.is_valid_frequency(frequency) || class_b && frequency == 0) {
+            status |= SESSION_FREQ_ERROR;
+        }
+        if region.data_rate_from_index(data_rate).is_none() {
+            status |= SESSION_DR_ERROR;
+        }
+        let Some(group) = group.filter(|_| status == group_id) else {
+            debug!("multicast session rejected: {=u8:#x}", status);
+            return (status, None);
+        };
+
+        // Class B sessions last 2^TimeOut beacon periods, Class C 2^TimeOut s
+        let timeout = args[5] & 0x0F;
+        let (mode, duration_s) = if class_b {
+            (OperatingMode::ClassB, 128u32 << timeout)
+        } else {
+            (OperatingMode::ClassC, 1u32 << timeout)
+        };
+        let offset_s = gps_time_s.map_or(0, |now| session_time.wrapping_sub(now) as i32);
+        let session = McSession {
+            group_id,
+            addr: group.addr,
+            mode,
+            time_to_start_s: offset_s.clamp(0, 0xFF_FFFF) as u32,
+            duration_s: (duration_s as i32 + offset_s.min(0)).max(0) as u32,
+            frequency,
+            data_rate,
+            periodicity: (args[5] >> 4) & 0x07,
+        };
+        (status, Some(session))
+    }
+}
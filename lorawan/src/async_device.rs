@@ -0,0 +1,363 @@
+//! Async LoRaWAN device interface
+//!
+//! `AsyncLoRaWANDevice` runs the non-blocking state machine of
+//! `LoRaWANDevice` on an `AsyncRadio`. The state machine drives a
+//! `RadioBridge`, which holds the frame to transmit and the frame received in
+//! a window. The async front-end moves them over the real radio and sleeps
+//! until the receive windows with an `embedded-hal-async` delay.
+
+use core::marker::PhantomData;
+
+use embedded_hal_async::delay::DelayNs;
+use heapless::Vec;
+
+use crate::{
+    class::OperatingMode,
+    config::device::{AESKey, DeviceConfig},
+    device::{DeviceError, DeviceEvent, LoRaWANDevice},
+    lorawan::{
+        mac::{rx_window_timeout, ConfirmedResult, Downlink, MacError, MAX_FRAME_SIZE},
+        region::{DataRate, Region},
+    },
+    radio::traits::{AsyncRadio, ModulationParams, Radio, RadioCapabilities, RxConfig, TxConfig},
+    timing::has_elapsed,
+};
+
+/// Blocking radio the device state machine runs on
+///
+/// Transmissions are buffered until the async front-end sends them, and
+/// `receive` returns the frame the front-end received for the window.
+pub struct RadioBridge<E> {
+    time: u32,
+    frequency: u32,
+    tx: Option<(TxConfig, Vec<u8, MAX_FRAME_SIZE>)>,
+    tx_config: Option<TxConfig>,
+    rx: Option<(Vec<u8, MAX_FRAME_SIZE>, i16, i8)>,
+    rssi: i16,
+    snr: i8,
+    _error: PhantomData<fn() -> E>,
+}
+
+impl<E> RadioBridge<E> {
+    fn new() -> Self {
+        Self {
+            time: 0,
+            frequency: 0,
+            tx: None,
+            tx_config: None,
+            rx: None,
+            rssi: i16::MIN,
+            snr: 0,
+            _error: PhantomData,
+        }
+    }
+}
+
+impl<E> Radio for RadioBridge<E> {
+    type Error = E;
+
+    fn init(&mut self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn set_frequency(&mut self, freq: u32) -> Result<(), E> {
+        self.frequency = freq;
+        Ok(())
+    }
+
+    fn get_frequency(&self) -> u32 {
+        self.frequency
+    }
+
+    fn set_tx_power(&mut self, _power: i8) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn transmit(&mut self, data: &[u8]) -> Result<(), E> {
+        if let (Some(config), Ok(frame)) = (self.tx_config, Vec::from_slice(data)) {
+            self.tx = Some((config, frame));
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, E> {
+        let Some((frame, rssi, snr)) = self.rx.take() else {
+            return Ok(0);
+        };
+        let len = frame.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&frame[..len]);
+        self.rssi = rssi;
+        self.snr = snr;
+        Ok(len)
+    }
+
+    fn configure_tx(&mut self, config: TxConfig) -> Result<(), E> {
+        self.frequency = config.frequency;
+        self.tx_config = Some(config);
+        Ok(())
+    }
+
+    fn configure_rx(&mut self, config: RxConfig) -> Result<(), E> {
+        self.frequency = config.frequency;
+        // Nothing received yet, Listen-Before-Talk always sees a free channel
+        self.rssi = i16::MIN;
+        self.snr = 0;
+        Ok(())
+    }
+
+    fn get_rssi(&mut self) -> Result<i16, E> {
+        Ok(self.rssi)
+    }
+
+    fn get_snr(&mut self) -> Result<i8, E> {
+        Ok(self.snr)
+    }
+
+    fn cad(&mut self, _params: &ModulationParams) -> Result<bool, E> {
+        // Detection needs the real radio, always open the window
+        Ok(true)
+    }
+
+    fn random_u32(&mut self) -> Result<u32, E> {
+        // No access to the radio noise here, derive a value from the clock
+        Ok(self.time.wrapping_mul(0x9E37_79B9).rotate_left(13))
+    }
+
+    fn is_transmitting(&mut self) -> Result<bool, E> {
+        Ok(false)
+    }
+
+    fn set_rx_gain(&mut self, _gain: u8) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn set_low_power_mode(&mut self, _enabled: bool) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn sleep(&mut self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn standby(&mut self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn get_time(&self) -> u32 {
+        self.time
+    }
+
+    fn capabilities(&self) -> RadioCapabilities {
+        // The async radio does not report its limits, accept everything
+        RadioCapabilities {
+            continuous_rx: true,
+            cad: true,
+            supports_fsk: false,
+            min_power_dbm: i8::MIN,
+            max_power_dbm: i8::MAX,
+            min_frequency: 0,
+            max_frequency: u32::MAX,
+        }
+    }
+}
+
+/// LoRaWAN device on an async radio
+pub struct AsyncLoRaWANDevice<R: AsyncRadio, D: DelayNs, REG: Region> {
+    radio: R,
+    delay: D,
+    device: LoRaWANDevice<RadioBridge<R::Error>, REG>,
+    /// Downlink received by the last exchange, returned by `next_downlink`
+    downlink: Option<Downlink>,
+}
+
+impl<R: AsyncRadio, D: DelayNs, REG: Region> AsyncLoRaWANDevice<R, D, REG> {
+    /// Create new async LoRaWAN device
+    ///
+    /// `delay` is used to sleep until the receive windows open.
+    pub async fn new(
+        mut radio: R,
+        delay: D,
+        config: DeviceConfig,
+        region: REG,
+        mode: OperatingMode,
+    ) -> Result<Self, DeviceError<R::Error>> {
+        radio.init().await.map_err(radio_error)?;
+        let device = LoRaWANDevice::new(RadioBridge::new(), config, region, mode)?;
+        Ok(Self {
+            radio,
+            delay,
+            device,
+            downlink: None,
+        })
+    }
+
+    /// Get the radio
+    pub fn radio(&self) -> &R {
+        &self.radio
+    }
+
+    /// Get mutable access to the radio
+    pub fn radio_mut(&mut self) -> &mut R {
+        &mut self.radio
+    }
+
+    /// Get the device state machine, e.g. to save the session
+    pub fn device(&self) -> &LoRaWANDevice<RadioBridge<R::Error>, REG> {
+        &self.device
+    }
+
+    /// Get mutable access to the device state machine
+    pub fn device_mut(&mut self) -> &mut LoRaWANDevice<RadioBridge<R::Error>, REG> {
+        &mut self.device
+    }
+
+    /// Join network using OTAA
+    ///
+    /// Join requests are retransmitted as set by the join policy of the
+    /// device. Fails with `MacError::Timeout` if no join accept is received.
+    pub async fn join_otaa(
+        &mut self,
+        dev_eui: [u8; 8],
+        app_eui: [u8; 8],
+        app_key: AESKey,
+    ) -> Result<(), DeviceError<R::Error>> {
+        self.device.start_join(dev_eui, app_eui, app_key)?;
+        loop {
+            match self.step().await? {
+                Some(DeviceEvent::JoinAccepted) => return Ok(()),
+                Some(DeviceEvent::JoinTimeout) => return Err(DeviceError::Mac(MacError::Timeout)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Send data and wait for the end of its receive windows
+    ///
+    /// A confirmed uplink is sent once. Returns its outcome, or `None` for
+    /// unconfirmed data. A downlink received in the windows is kept for
+    /// `next_downlink`.
+    pub async fn send_data(
+        &mut self,
+        port: u8,
+        data: &[u8],
+        confirmed: bool,
+    ) -> Result<Option<ConfirmedResult>, DeviceError<R::Error>> {
+        self.device.start_uplink(port, data, confirmed)?;
+        let mut acked = false;
+        while self.device.is_busy() {
+            let event = self.step().await?;
+            acked |= matches!(event, Some(DeviceEvent::AckReceived));
+            self.keep_downlink(event);
+        }
+
+        // Collect the events queued by the last window
+        while let Some(event) = self.step().await? {
+            self.keep_downlink(Some(event));
+        }
+
+        Ok(confirmed.then_some(ConfirmedResult { acked, attempts: 1 }))
+    }
+
+    /// Wait for the next downlink carrying application data
+    ///
+    /// Returns the downlink of the last `send_data` first. Class C devices
+    /// then listen on RX2 until a downlink arrives. Class A and B devices
+    /// only receive after an uplink here and return `None` instead.
+    pub async fn next_downlink(&mut self) -> Result<Option<Downlink>, DeviceError<R::Error>> {
+        loop {
+            if let Some(downlink) = self.downlink.take() {
+                return Ok(Some(downlink));
+            }
+            if self.device.operating_mode() != OperatingMode::ClassC {
+                return Ok(None);
+            }
+
+            let (frequency, data_rate) = self.device.get_mac_layer().rx2_window();
+            self.listen(frequency, data_rate, 0).await?;
+            while let Some(event) = self.step().await? {
+                self.keep_downlink(Some(event));
+            }
+        }
+    }
+
+    /// Keep the downlink of an event for `next_downlink`
+    fn keep_downlink(&mut self, event: Option<DeviceEvent>) {
+        if let Some(DeviceEvent::DownlinkReceived(downlink)) = event {
+            self.downlink = Some(downlink);
+        }
+    }
+
+    /// Open the due receive window, poll the device and send its transmission
+    async fn step(&mut self) -> Result<Option<DeviceEvent>, DeviceError<R::Error>> {
+        if let Some(at) = self.device.next_join_attempt() {
+            self.sleep_until(at).await;
+        }
+        if let Some((opens_at, frequency, data_rate)) = self.device.next_rx_window() {
+            self.sleep_until(opens_at).await;
+            self.listen(frequency, data_rate, rx_window_timeout(data_rate))
+                .await?;
+        }
+
+        let now = self.radio.get_time();
+        self.bridge().time = now;
+        let event = self.device.poll(now)?;
+
+        if let Some((config, frame)) = self.bridge().tx.take() {
+            self.radio.configure_tx(config).await.map_err(radio_error)?;
+            self.radio.transmit(&frame).await.map_err(radio_error)?;
+            self.device.set_tx_end(self.radio.get_time());
+        }
+        Ok(event)
+    }
+
+    /// Sleep until the time `at` of the radio clock
+    async fn sleep_until(&mut self, at: u32) {
+        let now = self.radio.get_time();
+        if !has_elapsed(now, at) {
+            self.delay.delay_ms(at.wrapping_sub(now)).await;
+        }
+    }
+
+    /// Receive a frame and hand it to the bridge for the next poll
+    async fn listen(
+        &mut self,
+        frequency: u32,
+        data_rate: DataRate,
+        timeout_ms: u32,
+    ) -> Result<(), DeviceError<R::Error>> {
+        let config = RxConfig {
+            frequency,
+            timeout_ms,
+            modulation: ModulationParams {
+                sync_word: self.device.get_mac_layer().network_type().sync_word(),
+                ..data_rate.downlink_modulation()
+            },
+        };
+        self.radio.configure_rx(config).await.map_err(radio_error)?;
+
+        let mut buffer = [0u8; MAX_FRAME_SIZE];
+        let len = self.radio.receive(&mut buffer).await.map_err(radio_error)?;
+        if len > 0 {
+            let rssi = self.radio.get_rssi().await.map_err(radio_error)?;
+            let snr = self.radio.get_snr().await.map_err(radio_error)?;
+            if let Ok(frame) = Vec::from_slice(&buffer[..len]) {
+                self.bridge().rx = Some((frame, rssi, snr));
+            }
+        }
+        Ok(())
+    }
+
+    /// Bridge of the MAC layer of the current class
+    fn bridge(&mut self) -> &mut RadioBridge<R::Error> {
+        self.device.get_mac_layer_mut().get_radio_mut()
+    }
+}
+
+/// Wrap an error of the async radio
+fn radio_error<E>(error: E) -> DeviceError<E> {
+    DeviceError::Mac(MacError::Radio(error))
+}
@@ -5,53 +5,117 @@
 
 use super::{DeviceClass, OperatingMode};
 use crate::config::device::{AESKey, SessionState};
-use crate::lorawan::mac::{MacError, MacLayer};
-use crate::lorawan::region::Region;
+use crate::lorawan::mac::{ConfirmedResult, MacError, MacLayer};
+use crate::lorawan::region::{Channel, Region};
 use crate::radio::traits::Radio;
+use crate::timing::has_elapsed;
+
+/// Receive windows scheduled after an uplink
+#[derive(Debug, Clone)]
+enum RxWindow {
+    /// No uplink waiting for its receive windows
+    Idle,
+    /// Waiting for RX1 of an uplink on a channel
+    Rx1 { tx_end: u32, channel: Channel },
+    /// Waiting for RX2
+    Rx2 { tx_end: u32 },
+}
 
 /// Class A device implementation
 pub struct ClassA<R: Radio, REG: Region> {
     /// MAC layer
     mac: MacLayer<R, REG>,
+    /// Next receive window
+    rx_window: RxWindow,
 }
 
 impl<R: Radio, REG: Region> ClassA<R, REG> {
     /// Create new Class A device
-    pub fn new(mac: MacLayer<R, REG>) -> Self {
-        Self { mac }
+    pub fn new(mut mac: MacLayer<R, REG>) -> Self {
+        mac.set_device_class(OperatingMode::ClassA);
+        Self {
+            mac,
+            rx_window: RxWindow::Idle,
+        }
+    }
+
+    /// Take back the MAC layer, e.g. to switch the device class
+    pub fn into_mac_layer(self) -> MacLayer<R, REG> {
+        self.mac
+    }
+
+    /// Get the time RX2 of the last uplink opens at
+    ///
+    /// Returns `None` once the receive windows of the uplink are done. The
+    /// next uplink has to wait for them.
+    pub fn rx2_opens_at(&self) -> Option<u32> {
+        match self.rx_window {
+            RxWindow::Idle => None,
+            RxWindow::Rx1 { tx_end, .. } | RxWindow::Rx2 { tx_end } => {
+                Some(tx_end.wrapping_add(self.mac.receive_delays().1))
+            }
+        }
+    }
+
+    /// Open the receive window that is due, if any
+    ///
+    /// RX1 opens `receive_delay1` after the uplink on the frequency and data
+    /// rate derived from the uplink channel. RX2 is only opened if nothing
+    /// was received in RX1. Returns the length of the frame received into
+    /// the frame buffer of the MAC layer.
+    fn open_due_window(&mut self) -> Result<usize, MacError<R::Error>> {
+        let now = self.mac.get_time();
+        let (rx1_delay, rx2_delay) = self.mac.receive_delays();
+
+        match self.rx_window.clone() {
+            RxWindow::Idle => Ok(0),
+            RxWindow::Rx1 { tx_end, channel } => {
+                if !has_elapsed(now, tx_end.wrapping_add(rx1_delay)) {
+                    return Ok(0);
+                }
+                let (frequency, data_rate) = self.mac.rx1_window(&channel);
+                let len = self.mac.receive_window_frame(frequency, data_rate)?;
+                self.rx_window = if len > 0 {
+                    RxWindow::Idle
+                } else {
+                    RxWindow::Rx2 { tx_end }
+                };
+                Ok(len)
+            }
+            RxWindow::Rx2 { tx_end } => {
+                if !has_elapsed(now, tx_end.wrapping_add(rx2_delay)) {
+                    return Ok(0);
+                }
+                self.rx_window = RxWindow::Idle;
+                let (frequency, data_rate) = self.mac.rx2_window();
+                self.mac.receive_window_frame(frequency, data_rate)
+            }
+        }
     }
 }
 
 impl<R: Radio, REG: Region> DeviceClass<R, REG> for ClassA<R, REG> {
-    type Error = MacError<R::Error>;
-
     fn operating_mode(&self) -> OperatingMode {
         OperatingMode::ClassA
     }
 
     fn process(&mut self) -> Result<(), MacError<R::Error>> {
-        // Process RX windows
-        let mut buffer = [0u8; 256];
-        if let Ok(len) = self.mac.receive(&mut buffer) {
+        // Process RX windows, or whatever the radio received outside of them
+        let received = if matches!(self.rx_window, RxWindow::Idle) {
+            self.mac.receive_frame()
+        } else {
+            self.open_due_window()
+        };
+        if let Ok(len) = received {
             // Only process if we received data
             if len > 0 {
-                // Decrypt and verify payload
-                let payload = self.mac.decrypt_payload(&buffer[..len])?;
-
-                // Extract MAC commands if present (port 0)
-                if let Some(port) = payload.first() {
-                    if *port == 0 {
-                        // Extract and process MAC commands from FRMPayload
-                        if let Some(commands) = self.mac.extract_mac_commands(&payload[1..]) {
-                            for command in commands {
-                                self.mac.process_mac_command(command)?;
-                            }
-                        }
-                    }
+                // A pending join expects a join accept rather than a data frame
+                if self.mac.is_join_pending() {
+                    return self.mac.process_received_join_accept(len);
                 }
 
-                // Increment frame counter after successful reception
-                self.mac.increment_frame_counter_down();
+                // Verify and decrypt the data frame and apply its MAC commands
+                self.mac.handle_received_downlink(len)?;
             }
         }
         Ok(())
@@ -62,12 +126,21 @@ impl<R: Radio, REG: Region> DeviceClass<R, REG> for ClassA<R, REG> {
         port: u8,
         data: &[u8],
         confirmed: bool,
-    ) -> Result<(), MacError<R::Error>> {
+    ) -> Result<Option<ConfirmedResult>, MacError<R::Error>> {
         if confirmed {
-            self.mac.send_confirmed(port, data)
-        } else {
-            self.mac.send_unconfirmed(port, data)
+            // Confirmed uplinks listen for their ACK in RX1 and RX2 themselves
+            self.rx_window = RxWindow::Idle;
+            return self.mac.send_confirmed(port, data).map(Some);
+        }
+
+        self.mac.send_unconfirmed(port, data)?;
+        if let Some(channel) = self.mac.get_last_uplink_channel() {
+            self.rx_window = RxWindow::Rx1 {
+                tx_end: self.mac.get_time(),
+                channel: channel.clone(),
+            };
         }
+        Ok(None)
     }
 
     fn send_join_request(
@@ -79,6 +152,10 @@ impl<R: Radio, REG: Region> DeviceClass<R, REG> for ClassA<R, REG> {
         self.mac.join_request(dev_eui, app_eui, app_key)
     }
 
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, MacError<R::Error>> {
+        self.mac.receive(buffer)
+    }
+
     fn get_session_state(&self) -> SessionState {
         self.mac.get_session_state().clone()
     }
@@ -87,7 +164,7 @@ impl<R: Radio, REG: Region> DeviceClass<R, REG> for ClassA<R, REG> {
         &self.mac
     }
 
-    fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, MacError<R::Error>> {
-        self.mac.receive(buffer)
+    fn get_mac_layer_mut(&mut self) -> &mut MacLayer<R, REG> {
+        &mut self.mac
     }
 }
@@ -2,13 +2,15 @@
 //!
 //! This module handles beacon synchronization and tracking including:
 //! - Beacon acquisition and synchronization
+//! - Beacon frame parsing and CRC verification
 //! - Beacon timing and window calculation
+//! - Beacon-less operation for up to 2 hours after the last beacon
 //! - Beacon loss detection and recovery
 
 use crate::{
     lorawan::{
         mac::{MacError, MacLayer},
-        region::Region,
+        region::{BeaconLayout, Channel, Region, BEACON_PERIOD_SECONDS},
     },
     radio::traits::Radio,
 };
@@ -19,8 +21,69 @@ const BEACON_RESERVED: u32 = 2_120;
 const BEACON_WINDOW: u32 = 122_880;
 const BEACON_GUARD: u32 = 3_000;
 
-/// Maximum beacon missed before declaring loss
-const MAX_BEACON_MISSED: u8 = 3;
+/// Class B continues without beacons for 2 hours after the last one (ms)
+pub const BEACONLESS_OPERATION_TIME: u32 = 7_200_000;
+
+/// Largest beacon frame of any region in bytes
+pub const MAX_BEACON_SIZE: usize = 23;
+
+/// Size of the GwSpecific field of a beacon
+pub const GW_SPECIFIC_SIZE: usize = 7;
+
+/// Compute the CRC-16 of a beacon field (CCITT polynomial 0x1021, initial 0)
+pub fn beacon_crc(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Beacon frame
+///
+/// `RFU | Time | CRC | GwSpecific | RFU | CRC`, each CRC covering the fields
+/// before it back to the previous one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BeaconFrame {
+    /// GPS time of the beacon in seconds, modulo 2^32
+    pub time: u32,
+    /// InfoDesc and gateway coordinates or network information
+    pub gw_specific: [u8; GW_SPECIFIC_SIZE],
+}
+
+impl BeaconFrame {
+    /// Parse a beacon frame with the layout of the region
+    ///
+    /// Returns `None` if the size does not match or either CRC fails.
+    pub fn parse(data: &[u8], layout: BeaconLayout) -> Option<Self> {
+        if data.len() != layout.size() {
+            return None;
+        }
+        let (first, second) = data.split_at(layout.rfu1 + 4 + 2);
+        let crc_ok = |part: &[u8]| {
+            let (fields, crc) = part.split_at(part.len() - 2);
+            beacon_crc(fields) == u16::from_le_bytes([crc[0], crc[1]])
+        };
+        if !crc_ok(first) || !crc_ok(second) {
+            return None;
+        }
+
+        let time = &first[layout.rfu1..layout.rfu1 + 4];
+        let mut gw_specific = [0u8; GW_SPECIFIC_SIZE];
+        gw_specific.copy_from_slice(&second[..GW_SPECIFIC_SIZE]);
+        Some(Self {
+            time: u32::from_le_bytes([time[0], time[1], time[2], time[3]]),
+            gw_specific,
+        })
+    }
+}
 
 /// Beacon tracking state
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -31,6 +94,8 @@ pub enum BeaconState {
     Searching,
     /// Synchronized with network beacons
     Synchronized,
+    /// Beacons missed, Class B continues from the timing of the last beacon
+    BeaconLess,
     /// Lost beacon synchronization
     Lost,
 }
@@ -40,9 +105,11 @@ pub enum BeaconState {
 pub struct BeaconTracker {
     /// Current beacon state
     state: BeaconState,
-    /// Time of last received beacon
+    /// Local time the last beacon was received at
     last_beacon_time: u32,
-    /// Number of consecutive missed beacons
+    /// GPS time carried by the last beacon
+    last_gps_time: Option<u32>,
+    /// Number of beacon periods since the last beacon without beacon
     missed_beacons: u8,
     /// Beacon timing drift (ppm)
     timing_drift: i32,
@@ -54,26 +121,27 @@ impl BeaconTracker {
         Self {
             state: BeaconState::Idle,
             last_beacon_time: 0,
+            last_gps_time: None,
             missed_beacons: 0,
             timing_drift: 0,
         }
     }
 
     /// Start beacon acquisition
-    pub fn start_acquisition<R: Radio + Clone, REG: Region>(
+    pub fn start_acquisition<R: Radio, REG: Region>(
         &mut self,
         mac: &mut MacLayer<R, REG>,
     ) -> Result<(), MacError<R::Error>> {
         // Configure radio for beacon reception
         let beacon_channel = mac
-            .get_region_mut()
             .get_next_beacon_channel()
             .ok_or(MacError::InvalidChannel)?;
 
-        mac.set_rx_config(
+        mac.set_beacon_rx_config(
             beacon_channel.frequency,
             beacon_channel.min_dr,
-            BEACON_WINDOW as u32,
+            mac.get_region().beacon_layout().size() as u8,
+            BEACON_WINDOW,
         )?;
 
         self.state = BeaconState::Searching;
@@ -81,7 +149,7 @@ impl BeaconTracker {
     }
 
     /// Process beacon tracking
-    pub fn process<R: Radio + Clone, REG: Region>(
+    pub fn process<R: Radio, REG: Region>(
         &mut self,
         mac: &mut MacLayer<R, REG>,
     ) -> Result<(), MacError<R::Error>> {
@@ -89,7 +157,7 @@ impl BeaconTracker {
             BeaconState::Searching => {
                 self.process_beacon_search(mac)?;
             }
-            BeaconState::Synchronized => {
+            BeaconState::Synchronized | BeaconState::BeaconLess => {
                 self.process_beacon_tracking(mac)?;
             }
             BeaconState::Lost => {
@@ -101,47 +169,66 @@ impl BeaconTracker {
     }
 
     /// Process beacon search
-    fn process_beacon_search<R: Radio + Clone, REG: Region>(
+    fn process_beacon_search<R: Radio, REG: Region>(
         &mut self,
         mac: &mut MacLayer<R, REG>,
     ) -> Result<(), MacError<R::Error>> {
         // Try to receive beacon
-        if let Some(beacon) = self.receive_beacon(mac)? {
-            // Validate beacon
-            if self.validate_beacon(&beacon) {
-                self.last_beacon_time = beacon.time;
-                self.state = BeaconState::Synchronized;
-                self.missed_beacons = 0;
-            }
+        if let Some((beacon, rx_time)) = self.receive_beacon(mac)? {
+            self.last_beacon_time = rx_time;
+            self.last_gps_time = Some(beacon.time);
+            self.state = BeaconState::Synchronized;
+            self.missed_beacons = 0;
         }
         Ok(())
     }
 
     /// Process synchronized beacon tracking
-    fn process_beacon_tracking<R: Radio + Clone, REG: Region>(
+    fn process_beacon_tracking<R: Radio, REG: Region>(
         &mut self,
         mac: &mut MacLayer<R, REG>,
     ) -> Result<(), MacError<R::Error>> {
         let current_time = mac.get_time();
+        let elapsed = current_time.wrapping_sub(self.last_beacon_time);
 
-        // Check if we're in beacon window
-        if self.is_beacon_window(current_time) {
-            if let Some(beacon) = self.receive_beacon(mac)? {
-                // Update timing
-                self.update_timing(beacon.time);
-                self.missed_beacons = 0;
-            } else {
-                self.missed_beacons += 1;
-                if self.missed_beacons >= MAX_BEACON_MISSED {
-                    self.state = BeaconState::Lost;
-                }
-            }
+        // Beacon-less operation ends 2 hours after the last beacon
+        if elapsed > BEACONLESS_OPERATION_TIME {
+            self.state = BeaconState::Lost;
+            return Ok(());
+        }
+
+        // Listen around each beacon expected since the last one
+        let periods = (elapsed + BEACON_INTERVAL / 2) / BEACON_INTERVAL;
+        if periods == 0 || elapsed.abs_diff(periods * BEACON_INTERVAL) > BEACON_GUARD {
+            return Ok(());
+        }
+
+        // The beacon hops to the channel of the next period
+        let beacon_channel = self
+            .expected_beacon_channel(mac, current_time)
+            .ok_or(MacError::InvalidChannel)?;
+        mac.set_beacon_rx_config(
+            beacon_channel.frequency,
+            beacon_channel.min_dr,
+            mac.get_region().beacon_layout().size() as u8,
+            BEACON_WINDOW,
+        )?;
+
+        if let Some((beacon, rx_time)) = self.receive_beacon(mac)? {
+            // Update timing
+            self.update_timing(rx_time, periods);
+            self.last_gps_time = Some(beacon.time);
+            self.state = BeaconState::Synchronized;
+            self.missed_beacons = 0;
+        } else if periods > u32::from(self.missed_beacons) {
+            self.missed_beacons = periods as u8;
+            self.state = BeaconState::BeaconLess;
         }
         Ok(())
     }
 
     /// Process beacon recovery
-    fn process_beacon_recovery<R: Radio + Clone, REG: Region>(
+    fn process_beacon_recovery<R: Radio, REG: Region>(
         &mut self,
         mac: &mut MacLayer<R, REG>,
     ) -> Result<(), MacError<R::Error>> {
@@ -149,40 +236,54 @@ impl BeaconTracker {
         let search_window = BEACON_WINDOW + 2 * BEACON_GUARD;
 
         // Configure radio with wider window
-        let beacon_channel = mac
-            .get_region_mut()
-            .get_next_beacon_channel()
+        let current_time = mac.get_time();
+        let beacon_channel = self
+            .expected_beacon_channel(mac, current_time)
             .ok_or(MacError::InvalidChannel)?;
 
-        mac.set_rx_config(
+        mac.set_beacon_rx_config(
             beacon_channel.frequency,
             beacon_channel.min_dr,
+            mac.get_region().beacon_layout().size() as u8,
             search_window,
         )?;
 
         // Try to reacquire beacon
-        if let Some(beacon) = self.receive_beacon(mac)? {
-            if self.validate_beacon(&beacon) {
-                self.last_beacon_time = beacon.time;
-                self.state = BeaconState::Synchronized;
-                self.missed_beacons = 0;
-            }
+        if let Some((beacon, rx_time)) = self.receive_beacon(mac)? {
+            self.last_beacon_time = rx_time;
+            self.last_gps_time = Some(beacon.time);
+            self.state = BeaconState::Synchronized;
+            self.missed_beacons = 0;
         }
         Ok(())
     }
 
-    /// Check if current time is in beacon window
-    fn is_beacon_window(&self, current_time: u32) -> bool {
-        let time_since_beacon = current_time.wrapping_sub(self.last_beacon_time);
-        let window_start = BEACON_INTERVAL - BEACON_GUARD;
-        let window_end = BEACON_INTERVAL + BEACON_GUARD;
-
-        time_since_beacon >= window_start && time_since_beacon <= window_end
+    /// Get the channel of the beacon expected around `current_time`
+    ///
+    /// Follows the beacon hopping from the GPS time of the last beacon, or
+    /// scans the beacon channels when none was received yet.
+    fn expected_beacon_channel<R: Radio, REG: Region>(
+        &self,
+        mac: &mut MacLayer<R, REG>,
+        current_time: u32,
+    ) -> Option<Channel> {
+        match self.last_gps_time {
+            Some(gps_time) => {
+                // Round to the nearest beacon period
+                let elapsed = current_time.wrapping_sub(self.last_beacon_time);
+                let periods = (elapsed + BEACON_INTERVAL / 2) / BEACON_INTERVAL;
+                let beacon_time = gps_time.wrapping_add(periods * BEACON_PERIOD_SECONDS);
+                mac.beacon_channel(beacon_time)
+            }
+            None => mac.get_next_beacon_channel(),
+        }
     }
 
-    /// Update beacon timing
-    fn update_timing(&mut self, beacon_time: u32) {
-        let expected_time = self.last_beacon_time.wrapping_add(BEACON_INTERVAL);
+    /// Update beacon timing from a beacon `periods` after the last one
+    fn update_timing(&mut self, beacon_time: u32, periods: u32) {
+        let expected_time = self
+            .last_beacon_time
+            .wrapping_add(periods * BEACON_INTERVAL);
         let drift = beacon_time.wrapping_sub(expected_time) as i32;
 
         // Update timing drift using exponential moving average
@@ -190,12 +291,6 @@ impl BeaconTracker {
         self.last_beacon_time = beacon_time;
     }
 
-    /// Validate received beacon
-    fn validate_beacon(&self, beacon: &BeaconData) -> bool {
-        // Basic validation: check if beacon info is not all zeros
-        !beacon.info.iter().all(|&b| b == 0)
-    }
-
     /// Get current beacon state
     pub fn state(&self) -> BeaconState {
         self.state
@@ -206,31 +301,60 @@ impl BeaconTracker {
         self.state == BeaconState::Synchronized
     }
 
-    /// Get last beacon time
+    /// Check if Class B can operate, synchronized or without beacons
+    pub fn is_operational(&self) -> bool {
+        matches!(
+            self.state,
+            BeaconState::Synchronized | BeaconState::BeaconLess
+        )
+    }
+
+    /// Get the number of beacon periods since the last beacon without beacon
+    pub fn missed_beacons(&self) -> u8 {
+        self.missed_beacons
+    }
+
+    /// Get the time since the last beacon was received in ms
+    pub fn time_since_beacon(&self, current_time: u32) -> u32 {
+        current_time.wrapping_sub(self.last_beacon_time)
+    }
+
+    /// Get the GPS and local time of the beacon period of `current_time`
+    ///
+    /// Without beacons the periods are extrapolated from the last beacon.
+    pub fn beacon_period(&self, current_time: u32) -> Option<(u32, u32)> {
+        let gps_time = self.last_gps_time?;
+        let periods = self.time_since_beacon(current_time) / BEACON_INTERVAL;
+        Some((
+            gps_time.wrapping_add(periods * BEACON_PERIOD_SECONDS),
+            self.last_beacon_time
+                .wrapping_add(periods * BEACON_INTERVAL),
+        ))
+    }
+
+    /// Get the local time the last beacon was received at
     pub fn last_beacon_time(&self) -> u32 {
         self.last_beacon_time
     }
 
-    /// Receive beacon
-    fn receive_beacon<R: Radio + Clone, REG: Region>(
+    /// Get the GPS time of the last beacon in seconds, if any
+    pub fn last_gps_time(&self) -> Option<u32> {
+        self.last_gps_time
+    }
+
+    /// Receive a beacon and the local time it was received at
+    ///
+    /// Frames of the wrong size or failing a CRC are ignored.
+    fn receive_beacon<R: Radio, REG: Region>(
         &mut self,
         mac: &mut MacLayer<R, REG>,
-    ) -> Result<Option<BeaconData>, MacError<R::Error>> {
-        let mut buffer = [0u8; 17]; // Beacon size is 17 bytes
-        match mac.receive(&mut buffer) {
-            Ok(size) if size == 17 => Ok(Some(BeaconData {
-                time: mac.get_time(),
-                info: buffer,
-            })),
-            Ok(_) => Ok(None),
-            Err(e) => Err(e),
-        }
+    ) -> Result<Option<(BeaconFrame, u32)>, MacError<R::Error>> {
+        let layout = mac.get_region().beacon_layout();
+        let mut buffer = [0u8; MAX_BEACON_SIZE];
+        let len = mac.receive(&mut buffer)?;
+        Ok(
+            BeaconFrame::parse(&buffer[..len.min(MAX_BEACON_SIZE)], layout)
+                .map(|beacon| (beacon, mac.get_time())),
+        )
     }
 }
-
-/// Beacon data structure
-#[derive(Debug)]
-struct BeaconData {
-    time: u32,
-    info: [u8; 17],
-}
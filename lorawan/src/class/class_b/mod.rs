@@ -2,59 +2,76 @@
 //!
 //! This module implements LoRaWAN Class B functionality including:
 //! - Beacon synchronization and tracking
-//! - Ping slot timing and randomization
+//! - Ping slot timing
 //! - Network time synchronization
+//! - Beacon-less operation with ping slots widened for the clock drift
 //! - Beacon loss detection and recovery
+//! - Multicast downlinks in the ping slots
 
 pub mod beacon;
 pub mod ping_slot;
 pub mod timing;
 
+use core::time::Duration;
+
 use crate::{
     class::{DeviceClass, OperatingMode},
-    config::device::{AESKey, SessionState},
+    config::device::{AESKey, DevAddr, SessionState},
     lorawan::{
-        mac::{MacError, MacLayer},
-        region::{DataRate, Region},
+        mac::{ConfirmedResult, MacError, MacLayer},
+        region::Region,
     },
     radio::traits::Radio,
 };
 
 use self::{
     beacon::{BeaconState, BeaconTracker},
-    ping_slot::{PingSlotConfig, PingSlotScheduler},
-    timing::NetworkTime,
+    ping_slot::{PingSlotScheduler, PingSlotState, SLOT_LEN},
 };
 
 /// Maximum number of ping slots per beacon period
 const MAX_PING_SLOTS: usize = 16;
 
 /// Class B device implementation
-pub struct ClassB<R: Radio + Clone, REG: Region> {
+pub struct ClassB<R: Radio, REG: Region> {
     /// MAC layer for radio communication
     mac: MacLayer<R, REG>,
     /// Beacon tracking state
     beacon_tracker: BeaconTracker,
-    /// Ping slot configuration
-    ping_slot_config: PingSlotConfig,
     /// Ping slot scheduler
     ping_scheduler: PingSlotScheduler,
-    /// Network time synchronization
-    network_time: NetworkTime,
+    /// Ping slots opened and missed
+    ping_slot_state: PingSlotState,
+    /// Beacon state entered since the last `take_beacon_state_change`
+    beacon_state_change: Option<BeaconState>,
+    /// Address the ping slots are computed from, the device address if not set
+    ping_slot_addr: Option<DevAddr>,
 }
 
-impl<R: Radio + Clone, REG: Region> ClassB<R, REG> {
+impl<R: Radio, REG: Region> ClassB<R, REG> {
     /// Create new Class B device
-    pub fn new(mac: MacLayer<R, REG>) -> Self {
+    pub fn new(mut mac: MacLayer<R, REG>) -> Self {
+        mac.set_device_class(OperatingMode::ClassB);
         Self {
             mac,
             beacon_tracker: BeaconTracker::new(),
-            ping_slot_config: PingSlotConfig::default(),
             ping_scheduler: PingSlotScheduler::new(),
-            network_time: NetworkTime::new(),
+            ping_slot_state: PingSlotState::new(),
+            beacon_state_change: None,
+            ping_slot_addr: None,
         }
     }
 
+    /// Take back the MAC layer, e.g. to switch the device class
+    pub fn into_mac_layer(self) -> MacLayer<R, REG> {
+        self.mac
+    }
+
+    /// Get the beacon tracker
+    pub fn beacon_tracker(&self) -> &BeaconTracker {
+        &self.beacon_tracker
+    }
+
     /// Start Class B operation
     pub fn start(&mut self) -> Result<(), MacError<R::Error>> {
         // Start beacon acquisition
@@ -62,19 +79,51 @@ impl<R: Radio + Clone, REG: Region> ClassB<R, REG> {
         Ok(())
     }
 
+    /// Check if the device is synchronized and operating in Class B
+    ///
+    /// Until the first beacon is received, and once beacons are lost for 2
+    /// hours, the device only receives after its uplinks as in Class A.
+    pub fn is_active(&self) -> bool {
+        self.beacon_tracker.is_operational()
+    }
+
+    /// Get the ping slots opened and missed
+    pub fn ping_slot_state(&self) -> &PingSlotState {
+        &self.ping_slot_state
+    }
+
+    /// Take the beacon state entered since the last call, if it changed
+    pub fn take_beacon_state_change(&mut self) -> Option<BeaconState> {
+        self.beacon_state_change.take()
+    }
+
     /// Process Class B operations
+    ///
+    /// Starts beacon acquisition on the first call.
     pub fn process(&mut self) -> Result<(), MacError<R::Error>> {
+        if self.beacon_tracker.state() == BeaconState::Idle {
+            self.start()?;
+        }
+
         // Process beacon tracking
+        let state = self.beacon_tracker.state();
         self.beacon_tracker.process(&mut self.mac)?;
+        if self.beacon_tracker.state() != state {
+            self.beacon_state_change = Some(self.beacon_tracker.state());
+        }
 
-        // Update network time if beacon synchronized
-        if self.beacon_tracker.is_synchronized() {
-            self.network_time
-                .update(self.beacon_tracker.last_beacon_time());
+        // Declare Class B to the network only while beacons are tracked
+        let active = self.is_active();
+        self.mac.set_class_b(active);
+
+        // Update network time from the GPS time of the last beacon
+        if let Some(gps_time) = self.beacon_tracker.last_gps_time() {
+            let local_ms = self.beacon_tracker.last_beacon_time();
+            self.mac.get_network_time_mut().update(gps_time, local_ms);
         }
 
         // Process ping slots if synchronized
-        if let BeaconState::Synchronized = self.beacon_tracker.state() {
+        if active {
             self.process_ping_slots()?;
         }
 
@@ -82,59 +131,131 @@ impl<R: Radio + Clone, REG: Region> ClassB<R, REG> {
     }
 
     /// Configure ping slot parameters
+    ///
+    /// The periodicity is sent to the network with a PingSlotInfoReq in the
+    /// next uplink.
     pub fn configure_ping_slots(&mut self, periodicity: u8) -> Result<(), MacError<R::Error>> {
-        self.ping_slot_config.set_periodicity(periodicity);
+        self.mac
+            .get_ping_slot_config_mut()
+            .set_periodicity(periodicity);
+        if let Some((beacon_time, _)) = self.beacon_tracker.beacon_period(self.mac.get_time()) {
+            self.update_ping_schedule(beacon_time);
+        }
+        self.mac.request_ping_slot_info(periodicity)
+    }
+
+    /// Compute the ping slots from `addr` instead of the device address
+    ///
+    /// Used by multicast Class B sessions, whose ping slots follow the
+    /// address of the group. `None` returns to the device address.
+    pub fn set_ping_slot_addr(&mut self, addr: Option<DevAddr>) {
+        self.ping_slot_addr = addr;
+        if let Some((beacon_time, _)) = self.beacon_tracker.beacon_period(self.mac.get_time()) {
+            self.update_ping_schedule(beacon_time);
+        }
+    }
+
+    /// Compute the ping slots of the beacon period of `beacon_time`
+    fn update_ping_schedule(&mut self, beacon_time: u32) {
+        let dev_addr = self
+            .ping_slot_addr
+            .unwrap_or(self.mac.get_session_state().dev_addr);
         self.ping_scheduler
-            .update_schedule(&self.ping_slot_config, self.network_time.current_time());
-        Ok(())
+            .update_schedule(self.mac.get_ping_slot_config(), dev_addr, beacon_time);
+    }
+
+    /// Get the receive timeout of a ping slot in ms
+    ///
+    /// Without beacons the slot is widened on both sides by the drift of the
+    /// local clock accumulated since the last beacon.
+    pub fn ping_slot_timeout(&self) -> u32 {
+        let elapsed = self.beacon_tracker.time_since_beacon(self.mac.get_time());
+        let drift_ppm = self
+            .mac
+            .get_network_time()
+            .drift_compensation()
+            .unsigned_abs();
+        let drift = u64::from(elapsed) * u64::from(drift_ppm) / 1_000_000;
+        SLOT_LEN + 2 * drift as u32
     }
 
     /// Process ping slots
     fn process_ping_slots(&mut self) -> Result<(), MacError<R::Error>> {
-        let current_time = self.network_time.current_time();
+        // Without beacons the periods follow the timing of the last one
+        let now = self.mac.get_time();
+        let Some((beacon_time, period_start)) = self.beacon_tracker.beacon_period(now) else {
+            return Ok(());
+        };
+        if self.ping_scheduler.beacon_time() != Some(beacon_time) {
+            self.update_ping_schedule(beacon_time);
+        }
 
         // Check if we need to open a ping slot
-        if let Some(slot) = self.ping_scheduler.next_slot(current_time) {
-            self.open_ping_slot(slot)?;
+        let time = now.wrapping_sub(period_start);
+        if let Some(slot) = self.ping_scheduler.next_slot(time) {
+            if !self.ping_slot_state.is_opened(beacon_time, slot) {
+                self.open_ping_slot(beacon_time, slot)?;
+            }
         }
 
         Ok(())
     }
 
     /// Open a ping receive slot
-    fn open_ping_slot(&mut self, _slot: u32) -> Result<(), MacError<R::Error>> {
+    fn open_ping_slot(&mut self, beacon_time: u32, slot: u32) -> Result<(), MacError<R::Error>> {
+        // Ping slots hop with the beacon period of the schedule
+        let frequency = self
+            .mac
+            .ping_slot_frequency(beacon_time)
+            .ok_or(MacError::InvalidChannel)?;
+        let data_rate = self
+            .mac
+            .get_region()
+            .data_rate_from_index(self.mac.get_ping_slot_config().data_rate())
+            .ok_or(MacError::InvalidDataRate)?;
+
         // Configure radio for ping slot reception
-        self.mac.set_rx_config(
-            self.ping_slot_config.frequency(),
-            DataRate::from_index(self.ping_slot_config.data_rate()),
-            30, // 30ms ping slot timeout
-        )?;
+        let timeout = self.ping_slot_timeout();
+        self.mac.set_rx_config(frequency, data_rate, timeout)?;
+        self.mac
+            .power_manager_mut()
+            .record_rx(Duration::from_millis(timeout as u64));
 
         // Start reception for ping slot duration
-        let mut buffer = [0u8; 256];
-        self.mac.receive(&mut buffer)?;
-
-        Ok(())
+        let len = self.mac.receive_frame()?;
+        let result = match len {
+            0 => Ok(false),
+            len => self
+                .mac
+                .handle_received_downlink_or_multicast(len)
+                .map(|_| true),
+        };
+        self.ping_slot_state
+            .record(beacon_time, slot, matches!(result, Ok(true)));
+        result.map(|_| ())
     }
 }
 
-impl<R: Radio + Clone, REG: Region> DeviceClass<R, REG> for ClassB<R, REG> {
-    type Error = MacError<R::Error>;
-
+impl<R: Radio, REG: Region> DeviceClass<R, REG> for ClassB<R, REG> {
     fn operating_mode(&self) -> OperatingMode {
         OperatingMode::ClassB
     }
 
-    fn process(&mut self) -> Result<(), Self::Error> {
+    fn process(&mut self) -> Result<(), MacError<R::Error>> {
         // Call the process implementation from ClassB
         ClassB::process(self)
     }
 
-    fn send_data(&mut self, port: u8, data: &[u8], confirmed: bool) -> Result<(), Self::Error> {
+    fn send_data(
+        &mut self,
+        port: u8,
+        data: &[u8],
+        confirmed: bool,
+    ) -> Result<Option<ConfirmedResult>, MacError<R::Error>> {
         if confirmed {
-            self.mac.send_confirmed(port, data)
+            self.mac.send_confirmed(port, data).map(Some)
         } else {
-            self.mac.send_unconfirmed(port, data)
+            self.mac.send_unconfirmed(port, data).map(|_| None)
         }
     }
 
@@ -143,11 +264,11 @@ impl<R: Radio + Clone, REG: Region> DeviceClass<R, REG> for ClassB<R, REG> {
         dev_eui: [u8; 8],
         app_eui: [u8; 8],
         app_key: AESKey,
-    ) -> Result<(), Self::Error> {
+    ) -> Result<(), MacError<R::Error>> {
         self.mac.join_request(dev_eui, app_eui, app_key)
     }
 
-    fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, MacError<R::Error>> {
         self.mac.receive(buffer)
     }
 
@@ -158,4 +279,8 @@ impl<R: Radio + Clone, REG: Region> DeviceClass<R, REG> for ClassB<R, REG> {
     fn get_mac_layer(&self) -> &MacLayer<R, REG> {
         &self.mac
     }
+
+    fn get_mac_layer_mut(&mut self) -> &mut MacLayer<R, REG> {
+        &mut self.mac
+    }
 }
@@ -1,15 +1,22 @@
 //! LoRaWAN Class B Ping Slot Management
 //!
-//! This module handles ping slot timing and randomization including:
-//! - Ping slot scheduling and calculation
-//! - Randomization for collision avoidance
-//! - Timing synchronization with beacons
+//! This module handles ping slot timing including:
+//! - Ping slot periodicity and channel configuration
+//! - The ping offset of each beacon period, derived from the beacon time and
+//!   DevAddr as in the LoRaWAN Class B specification
+//! - Ping slot scheduling within the beacon window
+//! - Tracking of the ping slots opened and missed
 
 use core::cmp::min;
-use heapless::Vec;
 
-/// Maximum number of ping slots per beacon period
-const MAX_PING_SLOTS: usize = 16;
+use crate::config::device::{AESKey, DevAddr};
+use crate::crypto::{CryptoBackend, SoftwareCrypto, BLOCK_SIZE};
+
+/// Time reserved for the beacon at the start of a beacon period (ms)
+pub const BEACON_RESERVED: u32 = 2_120;
+
+/// Length of a ping slot (ms)
+pub const SLOT_LEN: u32 = 30;
 
 /// Ping slot configuration
 #[derive(Debug, Clone)]
@@ -37,20 +44,38 @@ impl PingSlotConfig {
         self.periodicity = min(periodicity, 7);
     }
 
+    /// Get ping slot periodicity
+    pub fn periodicity(&self) -> u8 {
+        self.periodicity
+    }
+
+    /// Set ping slot frequency and data rate
+    ///
+    /// A frequency of 0 selects the region default.
+    pub fn set_channel(&mut self, frequency: u32, data_rate: u8) {
+        self.frequency = frequency;
+        self.data_rate = data_rate;
+    }
+
     /// Get ping slot data rate
     pub fn data_rate(&self) -> u8 {
         self.data_rate
     }
 
-    /// Get ping slot frequency
+    /// Get ping slot frequency, 0 for the region default
     pub fn frequency(&self) -> u32 {
         self.frequency
     }
 
-    /// Get number of ping slots per beacon period
+    /// Get number of ping slots per beacon period (pingNb)
     pub fn slots_per_beacon(&self) -> u32 {
         128 >> self.periodicity
     }
+
+    /// Get the number of slots between two ping slots (pingPeriod)
+    pub fn ping_period(&self) -> u32 {
+        4096 / self.slots_per_beacon()
+    }
 }
 
 impl Default for PingSlotConfig {
@@ -63,63 +88,127 @@ impl Default for PingSlotConfig {
     }
 }
 
+/// Compute the ping offset of a beacon period
+///
+/// `Rand = aes128_encrypt(0x00..00, BeaconTime | DevAddr | pad16)` and
+/// `pingOffset = (Rand[0] + Rand[1] * 256) % pingPeriod`, in slots.
+pub fn ping_offset(beacon_time: u32, dev_addr: DevAddr, ping_period: u32) -> u32 {
+    let mut block = [0u8; BLOCK_SIZE];
+    block[0..4].copy_from_slice(&beacon_time.to_le_bytes());
+    block[4..8].copy_from_slice(dev_addr.as_bytes());
+    SoftwareCrypto.encrypt_block(&AESKey::new([0; 16]), &mut block);
+    u32::from(u16::from_le_bytes([block[0], block[1]])) % ping_period.max(1)
+}
+
 /// Ping slot scheduler
+///
+/// Slot N of a beacon period opens at
+/// `BEACON_RESERVED + (pingOffset + N * pingPeriod) * SLOT_LEN` ms after the
+/// start of the beacon.
 #[derive(Debug)]
 pub struct PingSlotScheduler {
-    /// Scheduled ping slots
-    slots: Vec<u32, MAX_PING_SLOTS>,
-    /// Random seed for slot calculation
-    rand_seed: u32,
+    /// Ping offset of the current beacon period, in slots
+    ping_offset: u32,
+    /// Slots between two ping slots
+    ping_period: u32,
+    /// Ping slots per beacon period
+    ping_nb: u32,
+    /// Beacon time the schedule was computed for
+    beacon_time: Option<u32>,
 }
 
 impl PingSlotScheduler {
     /// Create new ping slot scheduler
     pub fn new() -> Self {
         Self {
-            slots: Vec::new(),
-            rand_seed: 0,
+            ping_offset: 0,
+            ping_period: 4096,
+            ping_nb: 0,
+            beacon_time: None,
         }
     }
 
-    /// Update ping slot schedule
-    pub fn update_schedule(&mut self, config: &PingSlotConfig, _beacon_time: u32) {
-        self.slots.clear();
+    /// Update the ping slot schedule for the beacon period of `beacon_time`
+    pub fn update_schedule(
+        &mut self,
+        config: &PingSlotConfig,
+        dev_addr: DevAddr,
+        beacon_time: u32,
+    ) {
+        self.ping_period = config.ping_period();
+        self.ping_nb = config.slots_per_beacon();
+        self.ping_offset = ping_offset(beacon_time, dev_addr, self.ping_period);
+        self.beacon_time = Some(beacon_time);
+    }
 
-        let num_slots = config.slots_per_beacon();
-        let beacon_reserved = 2_120; // ms
+    /// Get the beacon time of the current schedule, if any
+    pub fn beacon_time(&self) -> Option<u32> {
+        self.beacon_time
+    }
 
-        // Calculate ping slots using device address as randomization seed
-        for i in 0..num_slots {
-            let slot_time = beacon_reserved + self.calculate_slot_offset(i);
-            if self.slots.push(slot_time).is_err() {
-                break;
-            }
-        }
+    /// Get the ping offset of the current beacon period, in slots
+    pub fn ping_offset(&self) -> u32 {
+        self.ping_offset
     }
 
-    /// Calculate randomized slot offset
-    fn calculate_slot_offset(&self, slot_index: u32) -> u32 {
-        // Base offset ensures minimum spacing (40ms)
-        let base_offset = slot_index * 40;
+    /// Get the start of ping slot `index` in ms from the start of the beacon
+    pub fn slot_time(&self, index: u32) -> Option<u32> {
+        (index < self.ping_nb)
+            .then(|| BEACON_RESERVED + (self.ping_offset + index * self.ping_period) * SLOT_LEN)
+    }
 
-        // Add random offset that won't violate minimum spacing
-        let hash = self.rand_seed.wrapping_mul(slot_index.wrapping_add(1));
-        let random_offset = hash % 5;
+    /// Get the next ping slot starting after `time` ms into the beacon period
+    pub fn next_slot(&self, time: u32) -> Option<u32> {
+        let first = self.slot_time(0)?;
+        let index = if time < first {
+            0
+        } else {
+            (time - first) / (self.ping_period * SLOT_LEN) + 1
+        };
+        self.slot_time(index)
+    }
+}
+
+/// Reception state of the ping slots
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PingSlotState {
+    /// Ping slots opened
+    pub opened: u32,
+    /// Ping slots a downlink was received in
+    pub received: u32,
+    /// Ping slots in a row without downlink
+    pub consecutive_missed: u32,
+    /// Beacon time and start of the last ping slot opened
+    pub last_slot: Option<(u32, u32)>,
+}
 
-        base_offset.saturating_add(random_offset)
+impl PingSlotState {
+    /// Create new ping slot state
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    /// Get next ping slot time
-    pub fn next_slot(&self, current_time: u32) -> Option<u32> {
-        self.slots
-            .iter()
-            .find(|&&slot| slot > current_time)
-            .copied()
+    /// Check if the slot starting at `slot` ms into the beacon period of
+    /// `beacon_time` was opened already
+    pub fn is_opened(&self, beacon_time: u32, slot: u32) -> bool {
+        self.last_slot == Some((beacon_time, slot))
     }
 
-    /// Set random seed for slot calculation
-    pub fn set_random_seed(&mut self, seed: u32) {
-        self.rand_seed = seed;
+    /// Record an opened ping slot and whether a downlink was received in it
+    pub fn record(&mut self, beacon_time: u32, slot: u32, received: bool) {
+        self.opened = self.opened.saturating_add(1);
+        self.last_slot = Some((beacon_time, slot));
+        if received {
+            self.received = self.received.saturating_add(1);
+            self.consecutive_missed = 0;
+        } else {
+            self.consecutive_missed = self.consecutive_missed.saturating_add(1);
+        }
+    }
+
+    /// Get the number of ping slots opened without downlink
+    pub fn missed(&self) -> u32 {
+        self.opened - self.received
     }
 }
 
@@ -127,46 +216,73 @@ impl PingSlotScheduler {
 mod tests {
     use super::*;
 
+    /// DevAddr 0x01020304
+    fn dev_addr() -> DevAddr {
+        DevAddr::new([0x04, 0x03, 0x02, 0x01])
+    }
+
     #[test]
-    fn test_ping_slot_calculation() {
-        let mut config = PingSlotConfig::default();
-        config.set_periodicity(1); // 64 slots
+    fn test_ping_offset_known_answers() {
+        // Rand = AES128(0, 0) = 66 E9 4B D4 ..., 0xE966 = 59750
+        let zero = DevAddr::new([0; 4]);
+        assert_eq!(ping_offset(0, zero, 32), 6);
+        assert_eq!(ping_offset(0, zero, 4096), 2406);
 
-        let mut scheduler = PingSlotScheduler::new();
-        scheduler.set_random_seed(0x12345678);
-        scheduler.update_schedule(&config, 0);
-
-        // Verify number of slots
-        assert_eq!(scheduler.slots.len(), 16); // Limited by MAX_PING_SLOTS
-
-        // Verify slot spacing
-        let mut last_slot = 0;
-        for (i, &slot) in scheduler.slots.iter().enumerate() {
-            let spacing = slot.saturating_sub(last_slot);
-            assert!(
-                slot >= last_slot + 30,
-                "Slot {} has insufficient spacing: {} ms (slot time: {}, last slot: {})",
-                i,
-                spacing,
-                slot,
-                last_slot
-            );
-            last_slot = slot;
-        }
+        // Rand starts with 82 F3, then with 99 FF one beacon period later
+        assert_eq!(ping_offset(1_234_567_808, dev_addr(), 32), 2);
+        assert_eq!(ping_offset(1_234_567_808, dev_addr(), 256), 130);
+        assert_eq!(ping_offset(1_234_567_808, dev_addr(), 4096), 898);
+        assert_eq!(ping_offset(1_234_567_936, dev_addr(), 32), 25);
+        assert_eq!(ping_offset(1_234_567_936, dev_addr(), 4096), 3993);
+
+        // DevAddr is used in its over-the-air byte order
+        let reversed = DevAddr::new([0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(ping_offset(1_234_567_808, reversed, 64), 55);
     }
 
     #[test]
-    fn test_next_slot() {
+    fn test_ping_slot_schedule() {
         let mut config = PingSlotConfig::default();
-        config.set_periodicity(2); // 32 slots
+        config.set_periodicity(3); // 16 slots, one every 256
+        assert_eq!(config.ping_period(), 256);
 
         let mut scheduler = PingSlotScheduler::new();
-        scheduler.update_schedule(&config, 0);
+        assert_eq!(scheduler.next_slot(0), None);
+        scheduler.update_schedule(&config, dev_addr(), 1_234_567_808);
+        assert_eq!(scheduler.ping_offset(), 130);
+        assert_eq!(scheduler.slot_time(0), Some(2_120 + 130 * 30));
+        assert_eq!(scheduler.slot_time(15), Some(2_120 + (130 + 15 * 256) * 30));
+        assert_eq!(scheduler.slot_time(16), None);
 
-        // Test next slot finding
-        if let Some(first_slot) = scheduler.next_slot(0) {
-            assert!(first_slot >= 2_120); // After beacon reserved
-            assert!(scheduler.next_slot(first_slot).unwrap() > first_slot);
-        }
+        // The last slot ends within the beacon window
+        assert!(scheduler.slot_time(15).unwrap() + SLOT_LEN <= 2_120 + 122_880);
+
+        assert_eq!(scheduler.next_slot(0), scheduler.slot_time(0));
+        let first = scheduler.slot_time(0).unwrap();
+        assert_eq!(scheduler.next_slot(first), scheduler.slot_time(1));
+        assert_eq!(
+            scheduler.next_slot(first + 256 * 30 - 1),
+            scheduler.slot_time(1)
+        );
+        assert_eq!(scheduler.next_slot(scheduler.slot_time(15).unwrap()), None);
+    }
+
+    #[test]
+    fn test_ping_slot_state() {
+        let mut state = PingSlotState::new();
+        assert!(!state.is_opened(1_234_567_808, 6_020));
+
+        state.record(1_234_567_808, 6_020, false);
+        state.record(1_234_567_808, 13_700, false);
+        assert!(state.is_opened(1_234_567_808, 13_700));
+        assert!(!state.is_opened(1_234_567_936, 13_700));
+        assert_eq!(state.consecutive_missed, 2);
+
+        state.record(1_234_567_808, 21_380, true);
+        assert_eq!(state.opened, 3);
+        assert_eq!(state.received, 1);
+        assert_eq!(state.missed(), 2);
+        assert_eq!(state.consecutive_missed, 0);
+        assert_eq!(state.last_slot, Some((1_234_567_808, 21_380)));
     }
 }
@@ -5,11 +5,13 @@
 //! - GPS time conversion
 //! - Drift compensation
 
+use crate::timing::Clock;
+
 /// GPS epoch offset from Unix epoch (seconds)
 const GPS_EPOCH_OFFSET: u32 = 315964800;
 
 /// Network time synchronization
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NetworkTime {
     /// Local time offset from network time (milliseconds)
     time_offset: i32,
@@ -19,6 +21,8 @@ pub struct NetworkTime {
     drift_compensation: i32,
     /// Last synchronization time
     last_sync: u32,
+    /// GPS time in milliseconds and the local time it was valid at
+    gps_reference: Option<(u64, u32)>,
 }
 
 impl NetworkTime {
@@ -29,36 +33,46 @@ impl NetworkTime {
             timing_error: 0,
             drift_compensation: 0,
             last_sync: 0,
+            gps_reference: None,
         }
     }
 
-    /// Update network time from beacon
-    pub fn update(&mut self, beacon_time: u32) {
-        // For first update, just store the time
-        if self.last_sync == 0 {
-            self.last_sync = beacon_time;
-            return;
+    /// Update network time from a beacon
+    ///
+    /// `gps_time` is the Time field of the beacon in seconds, received at
+    /// local time `local_ms`. The drift of the local clock is measured
+    /// against the GPS time since the previous synchronization; the same
+    /// beacon again is ignored.
+    pub fn update(&mut self, gps_time: u32, local_ms: u32) {
+        let gps_time_ms = gps_time as u64 * 1_000;
+        if let Some((last_gps_ms, last_local_ms)) = self.gps_reference {
+            if gps_time_ms <= last_gps_ms {
+                return;
+            }
+            let gps_delta = (gps_time_ms - last_gps_ms).min(i32::MAX as u64) as i64;
+            let local_delta = local_ms.wrapping_sub(last_local_ms) as i64;
+            // Positive when the local clock runs slow
+            let error_ms = gps_delta - local_delta;
+
+            // Update timing error with exponential moving average
+            self.timing_error = ((self.timing_error as i64 * 7 + error_ms * 1000) / 8) as i32;
+
+            // Calculate drift compensation in parts per million
+            self.drift_compensation = (error_ms * 1_000_000 / gps_delta) as i32;
         }
 
-        // Calculate time since last sync
-        let time_delta = beacon_time.wrapping_sub(self.last_sync);
-        let expected_time = self.last_sync.wrapping_add(128_000);
-        let actual_error = beacon_time.wrapping_sub(expected_time);
-        let error_ms = actual_error as i32;
-
-        // Update timing error with exponential moving average
-        self.timing_error = (self.timing_error * 7 + error_ms * 1000) / 8;
-
-        // Calculate drift compensation in parts per million
-        self.drift_compensation = (error_ms * 1_000_000) / time_delta as i32;
+        self.sync_gps_time(gps_time_ms, local_ms);
+        self.last_sync = local_ms;
+    }
 
-        // Store current time for next update
-        self.last_sync = beacon_time;
+    /// Get the drift compensation of the local clock in ppm
+    pub fn drift_compensation(&self) -> i32 {
+        self.drift_compensation
     }
 
-    /// Get current network time
-    pub fn current_time(&self) -> u32 {
-        let local_time = self.get_local_time();
+    /// Get current network time from the local clock
+    pub fn current_time<C: Clock>(&self, clock: &C) -> u32 {
+        let local_time = clock.now_ms();
         let time_since_sync = local_time.wrapping_sub(self.last_sync);
 
         // Apply drift compensation
@@ -85,11 +99,37 @@ impl NetworkTime {
         self.time_offset = offset;
     }
 
-    /// Get local system time
-    fn get_local_time(&self) -> u32 {
-        // This should be implemented to return the local system time
-        // For now, we return a dummy value
-        0
+    /// Get time offset
+    pub fn time_offset(&self) -> i32 {
+        self.time_offset
+    }
+
+    /// Synchronize with the GPS time received in a DeviceTimeAns
+    ///
+    /// `gps_time_ms` is the time since the GPS epoch at local time `local_ms`.
+    /// Network time then follows GPS time in milliseconds, modulo 2^32.
+    pub fn sync_gps_time(&mut self, gps_time_ms: u64, local_ms: u32) {
+        self.time_offset = (gps_time_ms as u32).wrapping_sub(local_ms) as i32;
+        self.gps_reference = Some((gps_time_ms, local_ms));
+    }
+
+    /// Get the time since the GPS epoch in milliseconds, once synchronized
+    pub fn gps_time_ms<C: Clock>(&self, clock: &C) -> Option<u64> {
+        self.gps_time_ms_at(clock.now_ms())
+    }
+
+    /// Get the time since the GPS epoch in milliseconds at local time `local_ms`
+    pub fn gps_time_ms_at(&self, local_ms: u32) -> Option<u64> {
+        let (gps_time_ms, reference_ms) = self.gps_reference?;
+        Some(gps_time_ms + local_ms.wrapping_sub(reference_ms) as u64)
+    }
+
+    /// Get the Unix time in seconds, once synchronized
+    ///
+    /// Leap seconds between GPS and UTC are not applied.
+    pub fn unix_time<C: Clock>(&self, clock: &C) -> Option<u32> {
+        let gps_seconds = (self.gps_time_ms(clock)? / 1_000) as u32;
+        Some(gps_seconds.wrapping_add(GPS_EPOCH_OFFSET))
     }
 }
 
@@ -3,152 +3,392 @@
 //! Class C devices extend Class A by keeping the RX2 window open continuously
 //! when not transmitting. This allows for minimal downlink latency at the cost
 //! of increased power consumption.
+//!
+//! After an uplink the device listens on RX2 (RXC) until RX1 opens, receives
+//! in RX1 and then goes back to continuous RX2. Radios like the SX126x stop
+//! receiving after each packet, so RX2 is configured again after every
+//! reception or error.
+//!
+//! Received application data is kept in a bounded queue until
+//! `pop_downlink`, or handed to a handler registered with
+//! `set_downlink_handler` as soon as it is received.
+//!
+//! At a critical battery level continuous reception is suspended, only the
+//! receive windows after an uplink are opened.
+//!
+//! Downlinks of multicast groups are accepted in continuous reception, not
+//! in RX1.
+
+use core::time::Duration;
+
+use heapless::Vec;
 
 use super::{DeviceClass, OperatingMode};
 use crate::config::device::{AESKey, SessionState};
-use crate::lorawan::mac::{MacError, MacLayer};
+use crate::device::power::PowerState;
+use crate::lorawan::mac::{rx_window_timeout, ConfirmedResult, Downlink, MacError, MacLayer};
 use crate::lorawan::region::{DataRate, Region};
 use crate::radio::traits::Radio;
+use crate::timing::has_elapsed;
 use core::fmt::Debug;
 
-/// Battery level monitoring thresholds
-const BATTERY_CRITICAL_THRESHOLD: u8 = 10;
-const BATTERY_LOW_THRESHOLD: u8 = 30;
+/// Default time to wait for an application uplink carrying an owed ACK (ms)
+const DEFAULT_ACK_TIMEOUT: u32 = 5_000;
+
+/// Maximum number of downlinks kept by the downlink queue
+pub const MAX_DOWNLINK_QUEUE_DEPTH: usize = 8;
+
+/// Default number of downlinks kept by the downlink queue
+pub const DEFAULT_DOWNLINK_QUEUE_DEPTH: usize = 4;
+
+/// What to do with a downlink received while the downlink queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueOverflow {
+    /// Drop the oldest queued downlink to make room
+    DropOldest,
+    /// Drop the new downlink
+    Reject,
+}
 
 /// RX window states
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum RxWindowState {
     /// RX1 window active
     Rx1Active,
+    /// RX2 window active until RX1 of the last uplink opens
+    Rx1Pending {
+        opens_at: u32,
+        frequency: u32,
+        data_rate: DataRate,
+    },
     /// RX2 window active (continuous)
     Rx2Active,
     /// Temporarily suspended (e.g. during TX)
     Suspended,
 }
 
-/// Power management state
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct PowerState {
-    /// Battery level (0-255, 0=external power)
-    battery_level: u8,
-    /// Power saving mode enabled
-    power_save: bool,
+/// Signal quality readings
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct SignalMetrics {
     /// Last RSSI reading
     last_rssi: i16,
     /// Last SNR reading
     last_snr: i8,
 }
 
-impl PowerState {
-    fn new() -> Self {
-        Self {
-            battery_level: 255,
-            power_save: false,
-            last_rssi: 0,
-            last_snr: 0,
-        }
-    }
-
-    fn is_battery_critical(&self) -> bool {
-        self.battery_level > 0 && self.battery_level <= BATTERY_CRITICAL_THRESHOLD
-    }
-
-    fn is_battery_low(&self) -> bool {
-        self.battery_level > 0 && self.battery_level <= BATTERY_LOW_THRESHOLD
-    }
-}
-
 /// Class C device implementation
 pub struct ClassC<R, REG>
 where
-    R: Radio + Clone,
+    R: Radio,
     REG: Region + Debug + Clone,
 {
     /// MAC layer
     mac: MacLayer<R, REG>,
-    /// RX2 frequency
-    rx2_frequency: u32,
-    /// RX2 data rate
-    rx2_data_rate: u8,
     /// Current RX window state
     rx_state: RxWindowState,
-    /// Power management state
-    power_state: PowerState,
+    /// RX2 frequency and data rate the radio was configured with
+    rx2_window: Option<(u32, DataRate)>,
+    /// Signal quality readings
+    signal: SignalMetrics,
+    /// Time the continuous reception was last accounted for
+    rx_since: Option<u32>,
     /// Error recovery attempts
     recovery_attempts: u8,
+    /// Time to wait before acknowledging a confirmed downlink with an empty uplink
+    ack_timeout: Option<u32>,
+    /// Time the owed ACK was first seen
+    ack_requested_at: Option<u32>,
+    /// Received downlinks, oldest first
+    downlinks: Vec<Downlink, MAX_DOWNLINK_QUEUE_DEPTH>,
+    /// Number of downlinks the queue keeps
+    queue_depth: usize,
+    /// Policy when the queue is full
+    overflow: QueueOverflow,
+    /// Downlinks dropped because the queue was full
+    dropped_downlinks: u32,
+    /// Handler receiving downlinks instead of the queue
+    downlink_handler: Option<fn(&Downlink)>,
+    /// RX window state to restore after a raw radio operation
+    raw_suspended: Option<RxWindowState>,
 }
 
 impl<R, REG> ClassC<R, REG>
 where
-    R: Radio + Clone,
+    R: Radio,
     REG: Region + Debug + Clone,
 {
     /// Create new Class C device
-    pub fn new(mac: MacLayer<R, REG>, rx2_frequency: u32, rx2_data_rate: u8) -> Self {
+    ///
+    /// The RX2 settings of the session, from the join accept or
+    /// RXParamSetupReq, take precedence over `rx2_frequency` and
+    /// `rx2_data_rate`.
+    pub fn new(mut mac: MacLayer<R, REG>, rx2_frequency: u32, rx2_data_rate: u8) -> Self {
+        let session = mac.get_session_state();
+        let frequency = session.rx2_frequency.unwrap_or(rx2_frequency);
+        let data_rate = session.rx2_data_rate.unwrap_or(rx2_data_rate);
+        mac.set_rx2_params(frequency, data_rate);
+        mac.set_device_class(OperatingMode::ClassC);
         Self {
             mac,
-            rx2_frequency,
-            rx2_data_rate,
             rx_state: RxWindowState::Rx2Active,
-            power_state: PowerState::new(),
+            rx2_window: None,
+            signal: SignalMetrics::default(),
+            rx_since: None,
             recovery_attempts: 0,
+            ack_timeout: Some(DEFAULT_ACK_TIMEOUT),
+            ack_requested_at: None,
+            downlinks: Vec::new(),
+            queue_depth: DEFAULT_DOWNLINK_QUEUE_DEPTH,
+            overflow: QueueOverflow::DropOldest,
+            dropped_downlinks: 0,
+            downlink_handler: None,
+            raw_suspended: None,
+        }
+    }
+
+    /// Take back the MAC layer, e.g. to switch the device class
+    pub fn into_mac_layer(self) -> MacLayer<R, REG> {
+        self.mac
+    }
+
+    /// Set how long to wait for an application uplink after a confirmed downlink
+    ///
+    /// If no uplink is sent within the timeout, the ACK is sent in an empty
+    /// uplink. `None` leaves acknowledging to the application.
+    pub fn set_ack_timeout(&mut self, timeout_ms: Option<u32>) {
+        self.ack_timeout = timeout_ms;
+    }
+
+    /// Set the number of downlinks the queue keeps
+    ///
+    /// `depth` is limited to 1..=`MAX_DOWNLINK_QUEUE_DEPTH`. Downlinks beyond
+    /// a smaller depth are dropped as on overflow.
+    pub fn set_downlink_queue_depth(&mut self, depth: usize) {
+        self.queue_depth = depth.clamp(1, MAX_DOWNLINK_QUEUE_DEPTH);
+        while self.downlinks.len() > self.queue_depth {
+            self.downlinks.remove(0);
+            self.dropped_downlinks = self.dropped_downlinks.saturating_add(1);
         }
     }
 
+    /// Set what to do with a downlink received while the queue is full
+    pub fn set_queue_overflow(&mut self, overflow: QueueOverflow) {
+        self.overflow = overflow;
+    }
+
+    /// Get the number of downlinks dropped because the queue was full
+    pub fn dropped_downlinks(&self) -> u32 {
+        self.dropped_downlinks
+    }
+
+    /// Set a handler called from `process` with each received downlink
+    ///
+    /// Downlinks passed to the handler are not queued. `None` queues them
+    /// again.
+    pub fn set_downlink_handler(&mut self, handler: Option<fn(&Downlink)>) {
+        self.downlink_handler = handler;
+    }
+
+    /// Take the oldest received downlink, if any
+    pub fn pop_downlink(&mut self) -> Option<Downlink> {
+        if self.downlinks.is_empty() {
+            // Downlinks of confirmed uplinks stay in the MAC layer
+            return self.mac.take_downlink();
+        }
+        Some(self.downlinks.remove(0))
+    }
+
+    /// Hand the application data of the last frame to the handler or queue
+    fn deliver_downlink(&mut self) {
+        let Some(downlink) = self.mac.take_downlink() else {
+            return;
+        };
+        if let Some(handler) = self.downlink_handler {
+            handler(&downlink);
+            return;
+        }
+
+        if self.downlinks.len() >= self.queue_depth {
+            self.dropped_downlinks = self.dropped_downlinks.saturating_add(1);
+            match self.overflow {
+                QueueOverflow::DropOldest => {
+                    self.downlinks.remove(0);
+                }
+                QueueOverflow::Reject => return,
+            }
+        }
+        let _ = self.downlinks.push(downlink);
+    }
+
+    /// Send an empty uplink if a confirmed downlink went unacknowledged for too long
+    fn send_owed_ack(&mut self) -> Result<(), MacError<R::Error>> {
+        if !self.mac.has_pending_ack() {
+            self.ack_requested_at = None;
+            return Ok(());
+        }
+        let Some(timeout) = self.ack_timeout else {
+            return Ok(());
+        };
+
+        let now = self.mac.get_time();
+        let requested_at = *self.ack_requested_at.get_or_insert(now);
+        if now.wrapping_sub(requested_at) < timeout {
+            return Ok(());
+        }
+
+        self.ack_requested_at = None;
+        self.suspend_rx();
+        let result = self.mac.send_mac_commands();
+        let (rx1_delay, _) = self.mac.receive_delays();
+        self.after_uplink(result.is_ok(), rx1_delay)?;
+        result
+    }
+
     /// Configure RX2 window parameters
     pub fn configure_rx2(
         &mut self,
         frequency: u32,
         data_rate: u8,
     ) -> Result<(), MacError<R::Error>> {
-        self.rx2_frequency = frequency;
-        self.rx2_data_rate = data_rate;
+        self.mac.set_rx2_params(frequency, data_rate);
         self.resume_rx2()
     }
 
+    /// Restart continuous reception after the RX2 settings changed
+    pub(crate) fn restart_rx2(&mut self) -> Result<(), MacError<R::Error>> {
+        self.resume_rx2()
+    }
+
+    /// Listen on RX2 until RX1 of the uplink just sent opens `rx1_delay` ms later
+    ///
+    /// Without a transmission only RX2 is resumed.
+    fn after_uplink(&mut self, sent: bool, rx1_delay: u32) -> Result<(), MacError<R::Error>> {
+        self.resume_rx2()?;
+        if !sent {
+            return Ok(());
+        }
+        if let Some(channel) = self.mac.get_last_uplink_channel().cloned() {
+            let (frequency, data_rate) = self.mac.rx1_window(&channel);
+            self.rx_state = RxWindowState::Rx1Pending {
+                opens_at: self.mac.get_time().wrapping_add(rx1_delay),
+                frequency,
+                data_rate,
+            };
+        }
+        Ok(())
+    }
+
     /// Start RX1 window
-    fn start_rx1(&mut self, frequency: u32, data_rate: u8) -> Result<(), MacError<R::Error>> {
+    fn start_rx1(&mut self, frequency: u32, data_rate: DataRate) -> Result<(), MacError<R::Error>> {
         self.rx_state = RxWindowState::Rx1Active;
-        self.mac.set_rx_config(
-            frequency,
-            DataRate::from_index(data_rate),
-            1000, // 1 second RX1 window
-        )
+        self.mac
+            .set_rx_config(frequency, data_rate, rx_window_timeout(data_rate))
+    }
+
+    /// Receive in RX1, then resume RX2 continuous reception
+    fn receive_rx1(
+        &mut self,
+        frequency: u32,
+        data_rate: DataRate,
+    ) -> Result<usize, MacError<R::Error>> {
+        self.start_rx1(frequency, data_rate)?;
+        let result = self.mac.receive_frame();
+        self.resume_rx2()?;
+        result
+    }
+
+    /// Receive in continuous RX2, re-arming the radio after a packet
+    fn receive_rx2(&mut self) -> Result<usize, MacError<R::Error>> {
+        let len = self.mac.receive_frame()?;
+        if len > 0 {
+            self.resume_rx2()?;
+        }
+        Ok(len)
     }
 
     /// Resume RX2 continuous reception
     fn resume_rx2(&mut self) -> Result<(), MacError<R::Error>> {
-        // Only resume if not in power saving mode
-        if !self.power_state.power_save {
-            self.rx_state = RxWindowState::Rx2Active;
-            self.mac.set_rx_config(
-                self.rx2_frequency,
-                DataRate::from_index(self.rx2_data_rate),
-                0, // Continuous reception
-            )?;
+        // Only resume if the battery is not critical
+        if self.is_power_critical() {
+            self.suspend_rx();
+            return Ok(());
+        }
+        self.account_rx();
+        self.rx_state = RxWindowState::Rx2Active;
+        let (frequency, data_rate) = self.mac.rx2_window();
+        self.mac.set_rx_config(
+            frequency, data_rate, 0, // Continuous reception
+        )?;
+        self.rx2_window = Some((frequency, data_rate));
+        Ok(())
+    }
+
+    /// Suspend reception for a raw radio operation of the device
+    ///
+    /// Nested calls keep the state of the first one.
+    pub(crate) fn suspend_for_raw(&mut self) {
+        if self.raw_suspended.is_none() {
+            self.raw_suspended = Some(self.rx_state);
+            self.suspend_rx();
+        }
+    }
+
+    /// Resume reception after a raw radio operation
+    ///
+    /// RX2 is configured again as the raw operation changed the radio
+    /// settings. A pending RX1 window of the last uplink is kept.
+    pub(crate) fn resume_after_raw(&mut self) -> Result<(), MacError<R::Error>> {
+        let Some(state) = self.raw_suspended.take() else {
+            return Ok(());
+        };
+        self.resume_rx2()?;
+        if matches!(state, RxWindowState::Rx1Pending { .. }) {
+            self.rx_state = state;
         }
         Ok(())
     }
 
     /// Suspend reception (e.g. for transmission)
     fn suspend_rx(&mut self) {
+        self.account_rx();
+        self.rx_since = None;
         self.rx_state = RxWindowState::Suspended;
     }
 
-    /// Update power state
-    pub fn update_power_state(&mut self, battery_level: u8) {
-        self.power_state.battery_level = battery_level;
-
-        // Enable power saving if battery is low
-        if self.power_state.is_battery_low() {
-            self.power_state.power_save = true;
+    /// Record the time spent in continuous reception since the last call
+    fn account_rx(&mut self) {
+        let now = self.mac.get_time();
+        if let Some(since) = self.rx_since.replace(now) {
+            let elapsed = Duration::from_millis(now.wrapping_sub(since) as u64);
+            self.mac.power_manager_mut().record_rx(elapsed);
         }
     }
 
+    /// Check if the battery is too low for continuous reception
+    fn is_power_critical(&self) -> bool {
+        self.mac.power_manager().get_state() == PowerState::Critical
+    }
+
+    /// Update the power state with a battery level
+    ///
+    /// Continuous reception stops at a critical level and resumes once the
+    /// level recovers.
+    pub fn update_power_state(&mut self, battery_level: u8) {
+        self.mac.power_manager_mut().update_battery(battery_level);
+    }
+
     /// Update signal quality metrics
     fn update_signal_metrics(&mut self) -> Result<(), MacError<R::Error>> {
-        self.power_state.last_rssi = self.mac.get_radio_mut().get_rssi()?;
-        self.power_state.last_snr = self.mac.get_radio_mut().get_snr()?;
+        self.signal.last_rssi = self
+            .mac
+            .get_radio_mut()
+            .get_rssi()
+            .map_err(MacError::Radio)?;
+        self.signal.last_snr = self
+            .mac
+            .get_radio_mut()
+            .get_snr()
+            .map_err(MacError::Radio)?;
         Ok(())
     }
 
@@ -162,7 +402,7 @@ where
             Err(error)
         } else {
             // Try to recover by resetting radio and resuming RX2
-            self.mac.get_radio_mut().reset()?;
+            self.mac.get_radio_mut().reset().map_err(MacError::Radio)?;
             self.resume_rx2()
         }
     }
@@ -170,44 +410,67 @@ where
 
 impl<R, REG> DeviceClass<R, REG> for ClassC<R, REG>
 where
-    R: Radio + Clone,
+    R: Radio,
     REG: Region + Debug + Clone,
 {
-    type Error = MacError<R::Error>;
-
     fn operating_mode(&self) -> OperatingMode {
         OperatingMode::ClassC
     }
 
     fn process(&mut self) -> Result<(), MacError<R::Error>> {
+        // Follow the power state set by the battery level
+        match self.rx_state {
+            RxWindowState::Rx2Active if self.is_power_critical() => self.suspend_rx(),
+            RxWindowState::Suspended if !self.is_power_critical() => self.resume_rx2()?,
+            RxWindowState::Rx2Active => self.account_rx(),
+            _ => {}
+        }
+
         // Update signal metrics periodically
         if let Err(e) = self.update_signal_metrics() {
             self.handle_radio_error(e)?;
         }
 
-        // Process received data
-        let mut buffer = [0u8; 256];
-        match self.mac.receive(&mut buffer) {
+        // Process received data, in RX1 once it is due
+        let (received, rxc) = match self.rx_state {
+            RxWindowState::Rx1Pending {
+                opens_at,
+                frequency,
+                data_rate,
+            } if has_elapsed(self.mac.get_time(), opens_at) => {
+                (self.receive_rx1(frequency, data_rate), false)
+            }
+            RxWindowState::Suspended => (Ok(0), false),
+            _ if self.is_power_critical() => (Ok(0), false),
+            _ => (self.receive_rx2(), true),
+        };
+        match received {
             Ok(len) if len > 0 => {
                 // Reset recovery counter on successful reception
                 self.recovery_attempts = 0;
 
-                // Process received data
-                let payload = self.mac.decrypt_payload(&buffer[..len])?;
-
-                // Handle MAC commands if present
-                if let Some(port) = payload.first() {
-                    if *port == 0 {
-                        if let Some(commands) = self.mac.extract_mac_commands(&payload[1..]) {
-                            for command in commands {
-                                self.mac.process_mac_command(command)?;
-                            }
-                        }
-                    }
+                // A pending join expects a join accept rather than a data frame
+                let result = if self.mac.is_join_pending() {
+                    self.mac.process_received_join_accept(len)
+                } else {
+                    // Verify and decrypt the data frame and apply its MAC
+                    // commands, multicast downlinks are only received in RXC
+                    let result = if rxc {
+                        self.mac.handle_received_downlink_or_multicast(len)
+                    } else {
+                        self.mac.handle_received_downlink(len)
+                    };
+                    self.deliver_downlink();
+                    result.map(|_| ())
+                };
+
+                // The join accept or RXParamSetupReq may have moved RX2
+                if self.rx_state == RxWindowState::Rx2Active
+                    && self.rx2_window != Some(self.mac.rx2_window())
+                {
+                    self.resume_rx2()?;
                 }
-
-                // Update frame counter
-                self.mac.increment_frame_counter_down();
+                result?;
             }
             Err(e) => {
                 self.handle_radio_error(e)?;
@@ -215,7 +478,7 @@ where
             _ => {}
         }
 
-        Ok(())
+        self.send_owed_ack()
     }
 
     fn send_data(
@@ -223,20 +486,20 @@ where
         port: u8,
         data: &[u8],
         confirmed: bool,
-    ) -> Result<(), MacError<R::Error>> {
+    ) -> Result<Option<ConfirmedResult>, MacError<R::Error>> {
         // Suspend RX2 during transmission
         self.suspend_rx();
 
-        // Send data
-        let result = if confirmed {
-            self.mac.send_confirmed(port, data)
-        } else {
-            self.mac.send_unconfirmed(port, data)
-        };
-
-        // Resume RX2 after transmission
-        self.resume_rx2()?;
-
+        // Confirmed uplinks receive their ACK in RX1 and RX2 right away,
+        // unconfirmed ones listen on RX2 until RX1
+        if confirmed {
+            let result = self.mac.send_confirmed(port, data).map(Some);
+            self.resume_rx2()?;
+            return result;
+        }
+        let result = self.mac.send_unconfirmed(port, data).map(|_| None);
+        let (rx1_delay, _) = self.mac.receive_delays();
+        self.after_uplink(result.is_ok(), rx1_delay)?;
         result
     }
 
@@ -252,8 +515,9 @@ where
         // Send join request
         let result = self.mac.join_request(dev_eui, app_eui, app_key);
 
-        // Resume RX2 after join
-        self.resume_rx2()?;
+        // Listen on RX2 until RX1 of the join accept
+        let (rx1_delay, _) = self.mac.join_accept_delays();
+        self.after_uplink(result.is_ok(), rx1_delay)?;
 
         result
     }
@@ -272,4 +536,8 @@ where
     fn get_mac_layer(&self) -> &MacLayer<R, REG> {
         &self.mac
     }
+
+    fn get_mac_layer_mut(&mut self) -> &mut MacLayer<R, REG> {
+        &mut self.mac
+    }
 }
@@ -16,12 +16,14 @@ pub use class_b::ClassB;
 pub mod class_c;
 
 use crate::config::device::{AESKey, SessionState};
-use crate::lorawan::mac::MacLayer;
+use crate::lorawan::mac::{ConfirmedResult, MacError, MacLayer};
 use crate::lorawan::region::Region;
 use crate::radio::traits::Radio;
 
 /// Device operating mode
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum OperatingMode {
     /// Class A: Basic bi-directional communication
     ClassA,
@@ -32,18 +34,25 @@ pub enum OperatingMode {
 }
 
 /// Common trait for all device classes
+///
+/// Implemented by `ClassA`, `ClassB` and `ClassC`, so application code can be
+/// written once for any class.
 pub trait DeviceClass<R: Radio, REG: Region> {
-    /// Error type for device operations
-    type Error;
-
     /// Get current operating mode
     fn operating_mode(&self) -> OperatingMode;
 
     /// Process device operations
-    fn process(&mut self) -> Result<(), Self::Error>;
+    fn process(&mut self) -> Result<(), MacError<R::Error>>;
 
     /// Send data
-    fn send_data(&mut self, port: u8, data: &[u8], confirmed: bool) -> Result<(), Self::Error>;
+    ///
+    /// Confirmed uplinks report whether the network acknowledged them.
+    fn send_data(
+        &mut self,
+        port: u8,
+        data: &[u8],
+        confirmed: bool,
+    ) -> Result<Option<ConfirmedResult>, MacError<R::Error>>;
 
     /// Send join request
     fn send_join_request(
@@ -51,16 +60,19 @@ pub trait DeviceClass<R: Radio, REG: Region> {
         dev_eui: [u8; 8],
         app_eui: [u8; 8],
         app_key: AESKey,
-    ) -> Result<(), Self::Error>;
+    ) -> Result<(), MacError<R::Error>>;
 
     /// Receive data
-    fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error>;
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, MacError<R::Error>>;
 
     /// Get session state
     fn get_session_state(&self) -> SessionState;
 
     /// Get MAC layer reference
     fn get_mac_layer(&self) -> &MacLayer<R, REG>;
+
+    /// Get mutable MAC layer reference
+    fn get_mac_layer_mut(&mut self) -> &mut MacLayer<R, REG>;
 }
 
 /// RX window configuration
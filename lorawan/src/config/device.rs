@@ -5,10 +5,72 @@
 //! - Device address handling
 //! - AES key management
 //! - Device configuration for OTAA and ABP activation
-//! - Session state tracking
+//! - Session state tracking and serialization
+//! - DevNonce persistence
 
-/// Device address (4 bytes)
+use core::fmt;
+
+use super::hex;
+use crate::class::OperatingMode;
+
+/// Error parsing a hex string
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParseError {
+    /// Too few or too many hex digits
+    InvalidLength,
+    /// Character that is neither a hex digit nor a separator
+    InvalidCharacter,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::InvalidLength => f.write_str("wrong number of hex digits"),
+            ParseError::InvalidCharacter => f.write_str("invalid hex character"),
+        }
+    }
+}
+
+impl core::error::Error for ParseError {}
+
+/// Reason a device configuration was rejected by `DeviceConfig::validate`
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConfigError {
+    /// DevEUI is all zeros
+    ZeroDevEui,
+    /// DevEUI or AppEUI is all ones, which is not a valid EUI-64
+    InvalidEui,
+    /// AppKey of an OTAA device is all zeros
+    ZeroAppKey,
+    /// DevAddr of an ABP device is zero
+    ZeroDevAddr,
+    /// Session key of an ABP device is missing or all zeros
+    InvalidSessionKey,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::ZeroDevEui => f.write_str("DevEUI is zero"),
+            ConfigError::InvalidEui => f.write_str("EUI is all ones"),
+            ConfigError::ZeroAppKey => f.write_str("AppKey is zero"),
+            ConfigError::ZeroDevAddr => f.write_str("DevAddr is zero"),
+            ConfigError::InvalidSessionKey => f.write_str("session key missing or zero"),
+        }
+    }
+}
+
+impl core::error::Error for ConfigError {}
+
+/// Device address (4 bytes)
+///
+/// The bytes are kept in over-the-air order, least significant first.
+/// `Display` and `from_hex` use the most significant first order of network
+/// server consoles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct DevAddr {
     bytes: [u8; 4],
 }
@@ -19,14 +81,54 @@ impl DevAddr {
         Self { bytes }
     }
 
+    /// Parse a device address written most significant byte first, e.g. `"260B1A2C"`
+    pub fn from_hex(text: &str) -> Result<Self, ParseError> {
+        let mut bytes: [u8; 4] = hex::parse(text)?;
+        bytes.reverse();
+        Ok(Self::new(bytes))
+    }
+
     /// Get the raw bytes of the device address
     pub fn as_bytes(&self) -> &[u8; 4] {
         &self.bytes
     }
 }
 
+impl fmt::Display for DevAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        hex::write(f, self.bytes.iter().rev(), true)
+    }
+}
+
+impl fmt::LowerHex for DevAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        hex::write(f, self.bytes.iter().rev(), false)
+    }
+}
+
+/// Serialized as a hex string like `Display`
+#[cfg(feature = "serde")]
+impl serde::Serialize for DevAddr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        hex::serde::reversed::serialize(&self.bytes, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for DevAddr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        hex::serde::reversed::deserialize(deserializer).map(Self::new)
+    }
+}
+
 /// AES-128 key (16 bytes)
-#[derive(Debug, Clone)]
+///
+/// The key bytes are never printed by `Debug` or `defmt`, and there is no
+/// `Display`. Format with `{:x}` to print them on purpose. With the `serde`
+/// feature they are serialized as a hex string, which is meant for
+/// provisioning tools; keep the output out of logs. With the `zeroize` feature
+/// they are also wiped from memory when the key is dropped.
+#[derive(Clone)]
 pub struct AESKey {
     bytes: [u8; 16],
 }
@@ -37,21 +139,144 @@ impl AESKey {
         Self { bytes }
     }
 
+    /// Parse a key from hex, e.g. as copied from a network server console
+    pub fn from_hex(text: &str) -> Result<Self, ParseError> {
+        hex::parse(text).map(Self::new)
+    }
+
     /// Get the raw bytes of the key
     pub fn as_bytes(&self) -> &[u8; 16] {
         &self.bytes
     }
 }
 
-/// 64-bit Extended Unique Identifier (EUI)
+impl fmt::Debug for AESKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AESKey(****)")
+    }
+}
+
+impl fmt::LowerHex for AESKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        hex::write(f, &self.bytes, false)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for AESKey {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "AESKey(****)")
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for AESKey {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        hex::serde::serialize(&self.bytes, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for AESKey {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        hex::serde::deserialize(deserializer).map(Self::new)
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for AESKey {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.bytes);
+    }
+}
+
+/// 64-bit Extended Unique Identifier (EUI), least significant byte first
 pub type EUI64 = [u8; 8];
 
+/// 64-bit Extended Unique Identifier (EUI)
+///
+/// Like `EUI64` the bytes are kept least significant first, the order of the
+/// join request. Network server consoles usually show EUIs most significant
+/// byte first, which is also how `Display` writes them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Eui64(EUI64);
+
+impl Eui64 {
+    /// Create an EUI from bytes, least significant first
+    pub fn new(bytes: EUI64) -> Self {
+        Self(bytes)
+    }
+
+    /// Parse an EUI written most significant byte first, e.g. `"70B3D57ED0000001"`
+    pub fn from_hex_msb(text: &str) -> Result<Self, ParseError> {
+        let mut bytes: EUI64 = hex::parse(text)?;
+        bytes.reverse();
+        Ok(Self(bytes))
+    }
+
+    /// Parse an EUI written least significant byte first
+    pub fn from_hex_lsb(text: &str) -> Result<Self, ParseError> {
+        hex::parse(text).map(Self)
+    }
+
+    /// Get the bytes, least significant first
+    pub fn as_bytes(&self) -> &EUI64 {
+        &self.0
+    }
+}
+
+impl From<EUI64> for Eui64 {
+    fn from(bytes: EUI64) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<Eui64> for EUI64 {
+    fn from(eui: Eui64) -> Self {
+        eui.0
+    }
+}
+
+impl fmt::Display for Eui64 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        hex::write(f, self.0.iter().rev(), true)
+    }
+}
+
+impl fmt::LowerHex for Eui64 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        hex::write(f, self.0.iter().rev(), false)
+    }
+}
+
+/// Serialized as a hex string like `Display`
+#[cfg(feature = "serde")]
+impl serde::Serialize for Eui64 {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        hex::serde::reversed::serialize(&self.0, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Eui64 {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        hex::serde::reversed::deserialize(deserializer).map(Self)
+    }
+}
+
 /// Device configuration
+///
+/// With the `serde` feature EUIs, the address and keys are hex strings,
+/// EUIs and the address most significant byte first.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceConfig {
     /// Device EUI (unique device identifier)
+    #[cfg_attr(feature = "serde", serde(with = "hex::serde::reversed"))]
     pub dev_eui: EUI64,
     /// Application EUI (unique application identifier)
+    #[cfg_attr(feature = "serde", serde(with = "hex::serde::reversed"))]
     pub app_eui: EUI64,
     /// Application key (root key for OTAA)
     pub app_key: AESKey,
@@ -61,6 +286,9 @@ pub struct DeviceConfig {
     pub nwk_skey: Option<AESKey>,
     /// Application session key (derived during activation)
     pub app_skey: Option<AESKey>,
+    /// Send an empty uplink when the network signals more pending downlinks
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub auto_poll: bool,
 }
 
 impl DeviceConfig {
@@ -73,6 +301,52 @@ impl DeviceConfig {
             dev_addr: None,
             nwk_skey: None,
             app_skey: None,
+            auto_poll: false,
+        }
+    }
+
+    /// Create a new device configuration for OTAA activation from hex strings
+    ///
+    /// The EUIs are written most significant byte first and the AppKey as
+    /// shown by network server consoles such as The Things Network.
+    pub fn new_otaa_hex(dev_eui: &str, app_eui: &str, app_key: &str) -> Result<Self, ParseError> {
+        Ok(Self::new_otaa(
+            Eui64::from_hex_msb(dev_eui)?.into(),
+            Eui64::from_hex_msb(app_eui)?.into(),
+            AESKey::from_hex(app_key)?,
+        ))
+    }
+
+    /// Check the configuration for values that cannot work
+    ///
+    /// OTAA devices need a DevEUI and an AppKey that are not all zeros. ABP
+    /// devices, configured with a DevAddr, need a non-zero DevAddr and both
+    /// session keys. EUIs may not be all ones. The AppEUI may be zero, as
+    /// some network servers use an empty JoinEUI.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.dev_eui == [0xFF; 8] || self.app_eui == [0xFF; 8] {
+            return Err(ConfigError::InvalidEui);
+        }
+        let Some(dev_addr) = self.dev_addr else {
+            if self.dev_eui == [0; 8] {
+                return Err(ConfigError::ZeroDevEui);
+            }
+            if is_zero_key(&self.app_key) {
+                return Err(ConfigError::ZeroAppKey);
+            }
+            return Ok(());
+        };
+
+        if dev_addr.as_bytes() == &[0; 4] {
+            return Err(ConfigError::ZeroDevAddr);
+        }
+        match (&self.nwk_skey, &self.app_skey) {
+            (Some(nwk_skey), Some(app_skey))
+                if !is_zero_key(nwk_skey) && !is_zero_key(app_skey) =>
+            {
+                Ok(())
+            }
+            _ => Err(ConfigError::InvalidSessionKey),
         }
     }
 
@@ -91,13 +365,145 @@ impl DeviceConfig {
             dev_addr: Some(dev_addr),
             nwk_skey: Some(nwk_skey),
             app_skey: Some(app_skey),
+            auto_poll: false,
+        }
+    }
+}
+
+/// Check if a key was left at all zeros
+fn is_zero_key(key: &AESKey) -> bool {
+    key.as_bytes() == &[0; 16]
+}
+
+/// Persistent storage of the DevNonce used in join requests
+///
+/// Network servers reject join requests that reuse a DevNonce, so the last
+/// value must survive a reboot. Without a store the MAC layer only counts in
+/// RAM, in the session state. The methods take `&self` so a store can be
+/// shared as a `&'static` reference; implementations that write to flash or
+/// EEPROM need interior mutability.
+pub trait DevNonceStore {
+    /// Load the last DevNonce used, `None` if no join request was sent yet
+    fn load(&self) -> Option<u16>;
+
+    /// Save the DevNonce of a join request before it is sent
+    ///
+    /// Returns false if the value could not be stored.
+    fn save(&self, dev_nonce: u16) -> bool;
+}
+
+/// Persistent storage of the frame counters of an ABP session
+///
+/// ABP devices keep their session across reboots, and network servers
+/// reject uplinks that reuse a frame counter. To limit flash wear the MAC
+/// layer saves the counters only every few uplinks and skips the uplinks
+/// that may have been sent since the last save when loading them. As for
+/// `DevNonceStore`, implementations writing to flash need interior
+/// mutability.
+pub trait FrameCounterStore {
+    /// Load the uplink and downlink frame counters, `None` if none were saved
+    fn load(&self) -> Option<(u32, u32)>;
+
+    /// Save the uplink and downlink frame counters
+    ///
+    /// Returns false if the values could not be stored.
+    fn save(&self, fcnt_up: u32, fcnt_down: u32) -> bool;
+}
+
+/// Default maximum gap between two accepted downlink frame counters
+pub const MAX_FCNT_GAP: u32 = 16_384;
+
+/// Size of a serialized session state in bytes
+pub const SESSION_STATE_SIZE: usize = 65;
+
+/// Version of the serialized session state layout
+const SESSION_STATE_VERSION: u8 = 2;
+
+/// Size of a session state serialized by the first layout, still accepted
+const SESSION_STATE_V1_SIZE: usize = 61;
+
+/// Flags of the optional fields of a serialized session state
+const FLAG_RX2_DATA_RATE: u8 = 0x01;
+const FLAG_RX2_FREQUENCY: u8 = 0x02;
+const FLAG_DEV_NONCE: u8 = 0x04;
+
+/// Session state deserialization error
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SessionError {
+    /// Data is not `SESSION_STATE_SIZE` bytes long
+    InvalidLength,
+    /// Layout version not supported
+    UnsupportedVersion,
+    /// Checksum mismatch, the data is corrupted
+    InvalidChecksum,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::InvalidLength => f.write_str("invalid session state length"),
+            SessionError::UnsupportedVersion => f.write_str("unsupported session state version"),
+            SessionError::InvalidChecksum => f.write_str("session state checksum mismatch"),
+        }
+    }
+}
+
+impl core::error::Error for SessionError {}
+
+/// How the device obtained its session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ActivationState {
+    /// No session, no join in progress
+    #[default]
+    Idle,
+    /// Join request sent, waiting for the join accept
+    Joining,
+    /// Session of an OTAA join
+    OTAAActivated,
+    /// Session with keys provisioned for ABP
+    ABPActivated,
+}
+
+impl ActivationState {
+    /// Check if the state holds a session
+    pub fn is_activated(self) -> bool {
+        matches!(
+            self,
+            ActivationState::OTAAActivated | ActivationState::ABPActivated
+        )
+    }
+
+    fn from_byte(byte: u8) -> Self {
+        match byte {
+            1 => ActivationState::Joining,
+            2 => ActivationState::OTAAActivated,
+            3 => ActivationState::ABPActivated,
+            _ => ActivationState::Idle,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            ActivationState::Idle => 0,
+            ActivationState::Joining => 1,
+            ActivationState::OTAAActivated => 2,
+            ActivationState::ABPActivated => 3,
         }
     }
 }
 
 /// Session state
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SessionState {
+    /// How the session was obtained
+    pub activation_state: ActivationState,
+    /// Class the device operates in
+    pub device_class: OperatingMode,
     /// Device address
     pub dev_addr: DevAddr,
     /// Network session key
@@ -108,40 +514,202 @@ pub struct SessionState {
     pub fcnt_up: u32,
     /// Downlink frame counter
     pub fcnt_down: u32,
+    /// Data rate offset between uplink and RX1 downlink
+    pub rx1_dr_offset: u8,
+    /// Data rate of the RX2 window, the region default if not set
+    pub rx2_data_rate: Option<u8>,
+    /// Frequency of the RX2 window, the region default if not set
+    pub rx2_frequency: Option<u32>,
+    /// Delay between end of uplink and RX1 window (seconds)
+    pub rx1_delay: u8,
+    /// Maximum gap accepted between the expected and received downlink counter
+    pub max_fcnt_gap: u32,
+    /// DevNonce of the last join request
+    pub last_dev_nonce: Option<u16>,
+    /// DevNonce of the join the session keys were derived from, 0 for ABP
+    pub dev_nonce: u16,
+}
+
+impl Default for SessionState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SessionState {
     /// Create a new empty session state with default values
     pub fn new() -> Self {
         Self {
+            activation_state: ActivationState::Idle,
+            device_class: OperatingMode::ClassA,
             dev_addr: DevAddr::new([0; 4]),
             nwk_skey: AESKey::new([0; 16]),
             app_skey: AESKey::new([0; 16]),
             fcnt_up: 0,
             fcnt_down: 0,
+            rx1_dr_offset: 0,
+            rx2_data_rate: None,
+            rx2_frequency: None,
+            rx1_delay: 1,
+            max_fcnt_gap: MAX_FCNT_GAP,
+            last_dev_nonce: None,
+            dev_nonce: 0,
         }
     }
 
     /// Create a new session state for ABP activation
     pub fn new_abp(dev_addr: DevAddr, nwk_skey: AESKey, app_skey: AESKey) -> Self {
         Self {
+            activation_state: ActivationState::ABPActivated,
+            device_class: OperatingMode::ClassA,
             dev_addr,
             nwk_skey,
             app_skey,
             fcnt_up: 0,
             fcnt_down: 0,
+            rx1_dr_offset: 0,
+            rx2_data_rate: None,
+            rx2_frequency: None,
+            rx1_delay: 1,
+            max_fcnt_gap: MAX_FCNT_GAP,
+            last_dev_nonce: None,
+            dev_nonce: 0,
         }
     }
 
     /// Create a new session state from OTAA join response
     pub fn from_join_accept(dev_addr: DevAddr, nwk_skey: AESKey, app_skey: AESKey) -> Self {
         Self {
+            activation_state: ActivationState::OTAAActivated,
+            device_class: OperatingMode::ClassA,
             dev_addr,
             nwk_skey,
             app_skey,
             fcnt_up: 0,
             fcnt_down: 0,
+            rx1_dr_offset: 0,
+            rx2_data_rate: None,
+            rx2_frequency: None,
+            rx1_delay: 1,
+            max_fcnt_gap: MAX_FCNT_GAP,
+            last_dev_nonce: None,
+            dev_nonce: 0,
+        }
+    }
+
+    /// Serialize the session state, e.g. to keep it in retained RAM or flash
+    ///
+    /// The layout is versioned and ends with a CRC-16. It contains the session
+    /// keys in plain text, so it must be stored where only the device can read
+    /// it.
+    pub fn to_bytes(&self) -> [u8; SESSION_STATE_SIZE] {
+        let mut flags = 0;
+        if self.rx2_data_rate.is_some() {
+            flags |= FLAG_RX2_DATA_RATE;
+        }
+        if self.rx2_frequency.is_some() {
+            flags |= FLAG_RX2_FREQUENCY;
+        }
+        if self.last_dev_nonce.is_some() {
+            flags |= FLAG_DEV_NONCE;
+        }
+
+        let mut bytes = [0u8; SESSION_STATE_SIZE];
+        bytes[0] = SESSION_STATE_VERSION;
+        bytes[1] = flags;
+        bytes[2..6].copy_from_slice(self.dev_addr.as_bytes());
+        bytes[6..22].copy_from_slice(self.nwk_skey.as_bytes());
+        bytes[22..38].copy_from_slice(self.app_skey.as_bytes());
+        bytes[38..42].copy_from_slice(&self.fcnt_up.to_le_bytes());
+        bytes[42..46].copy_from_slice(&self.fcnt_down.to_le_bytes());
+        bytes[46] = self.rx1_dr_offset;
+        bytes[47] = self.rx2_data_rate.unwrap_or(0);
+        bytes[48..52].copy_from_slice(&self.rx2_frequency.unwrap_or(0).to_le_bytes());
+        bytes[52] = self.rx1_delay;
+        bytes[53..57].copy_from_slice(&self.max_fcnt_gap.to_le_bytes());
+        bytes[57..59].copy_from_slice(&self.last_dev_nonce.unwrap_or(0).to_le_bytes());
+        bytes[59] = self.activation_state.to_byte();
+        bytes[60] = match self.device_class {
+            OperatingMode::ClassA => 0,
+            OperatingMode::ClassB => 1,
+            OperatingMode::ClassC => 2,
+        };
+        bytes[61..63].copy_from_slice(&self.dev_nonce.to_le_bytes());
+
+        let crc = crc16(&bytes[..SESSION_STATE_SIZE - 2]);
+        bytes[SESSION_STATE_SIZE - 2..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// Deserialize a session state written by `to_bytes`
+    ///
+    /// Sessions of the first layout, without activation state, are accepted
+    /// too. They are taken as OTAA sessions if a DevNonce was stored, as ABP
+    /// sessions otherwise, and as Class A.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SessionError> {
+        let size = match bytes.first() {
+            Some(1) => SESSION_STATE_V1_SIZE,
+            _ => SESSION_STATE_SIZE,
+        };
+        if bytes.len() != size {
+            return Err(SessionError::InvalidLength);
+        }
+        if bytes[0] != 1 && bytes[0] != SESSION_STATE_VERSION {
+            return Err(SessionError::UnsupportedVersion);
         }
+        let crc = u16::from_le_bytes([bytes[size - 2], bytes[size - 1]]);
+        if crc16(&bytes[..size - 2]) != crc {
+            return Err(SessionError::InvalidChecksum);
+        }
+
+        let u32_at =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let key_at = |i: usize| {
+            let mut key = [0u8; 16];
+            key.copy_from_slice(&bytes[i..i + 16]);
+            AESKey::new(key)
+        };
+        let flags = bytes[1];
+
+        let mut session = Self {
+            activation_state: ActivationState::Idle,
+            device_class: OperatingMode::ClassA,
+            dev_addr: DevAddr::new([bytes[2], bytes[3], bytes[4], bytes[5]]),
+            nwk_skey: key_at(6),
+            app_skey: key_at(22),
+            fcnt_up: u32_at(38),
+            fcnt_down: u32_at(42),
+            rx1_dr_offset: bytes[46],
+            rx2_data_rate: (flags & FLAG_RX2_DATA_RATE != 0).then_some(bytes[47]),
+            rx2_frequency: (flags & FLAG_RX2_FREQUENCY != 0).then_some(u32_at(48)),
+            rx1_delay: bytes[52],
+            max_fcnt_gap: u32_at(53),
+            last_dev_nonce: (flags & FLAG_DEV_NONCE != 0)
+                .then_some(u16::from_le_bytes([bytes[57], bytes[58]])),
+            dev_nonce: 0,
+        };
+
+        if size == SESSION_STATE_V1_SIZE {
+            let provisioned =
+                !session.dev_addr.as_bytes().iter().all(|&x| x == 0) && session.is_active();
+            session.activation_state = match session.last_dev_nonce {
+                _ if !provisioned => ActivationState::Idle,
+                Some(dev_nonce) => {
+                    session.dev_nonce = dev_nonce;
+                    ActivationState::OTAAActivated
+                }
+                None => ActivationState::ABPActivated,
+            };
+        } else {
+            session.activation_state = ActivationState::from_byte(bytes[59]);
+            session.device_class = match bytes[60] {
+                1 => OperatingMode::ClassB,
+                2 => OperatingMode::ClassC,
+                _ => OperatingMode::ClassA,
+            };
+            session.dev_nonce = u16::from_le_bytes([bytes[61], bytes[62]]);
+        }
+        Ok(session)
     }
 
     /// Reset frame counters
@@ -158,7 +726,25 @@ impl SessionState {
     }
 
     /// Check if device is joined to network
+    ///
+    /// True for sessions of an OTAA join or ABP, whatever their keys.
     pub fn is_joined(&self) -> bool {
-        !self.dev_addr.as_bytes().iter().all(|&x| x == 0) && self.is_active()
+        self.activation_state.is_activated()
+    }
+}
+
+/// CRC-16/CCITT-FALSE checksum
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
     }
+    crc
 }
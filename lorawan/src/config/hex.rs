@@ -0,0 +1,119 @@
+//! Hex string representation of keys, addresses and EUIs
+//!
+//! Bytes are written as uppercase hex without separators. Parsing accepts
+//! either case and ignores `:`, `-`, `_` and spaces between the digits, so
+//! `"70B3D57ED0000001"` and `"70:b3:d5:7e:d0:00:00:01"` are the same EUI.
+//! Invalid input is reported without echoing it, since it may be a key.
+
+use core::fmt;
+
+use super::device::ParseError;
+
+/// Parse `N` bytes from a hex string, in the order they are written
+pub(crate) fn parse<const N: usize>(text: &str) -> Result<[u8; N], ParseError> {
+    let mut bytes = [0u8; N];
+    let mut digits = 0;
+    for c in text.chars() {
+        if matches!(c, ':' | '-' | '_' | ' ') {
+            continue;
+        }
+        let digit = c.to_digit(16).ok_or(ParseError::InvalidCharacter)? as u8;
+        let byte = bytes.get_mut(digits / 2).ok_or(ParseError::InvalidLength)?;
+        *byte = (*byte << 4) | digit;
+        digits += 1;
+    }
+    if digits != 2 * N {
+        return Err(ParseError::InvalidLength);
+    }
+    Ok(bytes)
+}
+
+/// Write bytes as hex, in upper or lower case
+pub(crate) fn write<'a>(
+    f: &mut fmt::Formatter,
+    bytes: impl IntoIterator<Item = &'a u8>,
+    upper: bool,
+) -> fmt::Result {
+    for byte in bytes {
+        if upper {
+            write!(f, "{:02X}", byte)?;
+        } else {
+            write!(f, "{:02x}", byte)?;
+        }
+    }
+    Ok(())
+}
+
+/// Serde support, values are hex strings in the order they are written
+#[cfg(feature = "serde")]
+pub(crate) mod serde {
+    use core::fmt;
+
+    use serde::{de, Deserializer, Serializer};
+
+    /// Longest value written, an AES key
+    const MAX_BYTES: usize = 16;
+
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+    /// Serialize bytes as an uppercase hex string
+    pub fn serialize<S: Serializer, const N: usize>(
+        bytes: &[u8; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut buffer = [0u8; 2 * MAX_BYTES];
+        let text = &mut buffer[..2 * N.min(MAX_BYTES)];
+        for (pair, byte) in text.chunks_exact_mut(2).zip(bytes) {
+            pair[0] = DIGITS[(byte >> 4) as usize];
+            pair[1] = DIGITS[(byte & 0x0F) as usize];
+        }
+        // Cannot fail, only ASCII digits were written
+        serializer.serialize_str(core::str::from_utf8(text).unwrap_or_default())
+    }
+
+    /// Deserialize bytes from a hex string, with or without separators
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[u8; N], D::Error> {
+        deserializer.deserialize_str(HexVisitor::<N>)
+    }
+
+    /// Bytes stored least significant first, written most significant first
+    pub mod reversed {
+        use serde::{Deserializer, Serializer};
+
+        /// Serialize bytes as an uppercase hex string, last byte first
+        pub fn serialize<S: Serializer, const N: usize>(
+            bytes: &[u8; N],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            let mut msb = *bytes;
+            msb.reverse();
+            super::serialize(&msb, serializer)
+        }
+
+        /// Deserialize bytes from a hex string, last byte first
+        pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+            deserializer: D,
+        ) -> Result<[u8; N], D::Error> {
+            let mut bytes: [u8; N] = super::deserialize(deserializer)?;
+            bytes.reverse();
+            Ok(bytes)
+        }
+    }
+
+    struct HexVisitor<const N: usize>;
+
+    impl<const N: usize> de::Visitor<'_> for HexVisitor<N> {
+        type Value = [u8; N];
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{} bytes as a hex string", N)
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<[u8; N], E> {
+            super::parse(value)
+                .map_err(|_| E::invalid_value(de::Unexpected::Other("hex string"), &self))
+        }
+    }
+}
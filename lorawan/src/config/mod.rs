@@ -9,4 +9,7 @@
 /// Device configuration and session state
 pub mod device;
 
+/// Hex string representation of keys, addresses and EUIs
+pub(crate) mod hex;
+
 pub use device::DeviceConfig;
@@ -5,9 +5,15 @@
 //! - Payload encryption/decryption
 //! - Join accept encryption
 //! - Session key derivation
+//! - Multicast key unwrapping and derivation (remote multicast setup, TS005)
+//!
+//! The operations are built on the AES primitives of a [`CryptoBackend`], so
+//! keys can be kept in a secure element or crypto peripheral. The free
+//! functions use the software backend, [`SoftwareCrypto`].
 
-use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
 use aes::Aes128;
+use cmac::{Cmac, Mac};
 use heapless::Vec;
 
 use crate::config::device::{AESKey, DevAddr};
@@ -16,7 +22,31 @@ use crate::config::device::{AESKey, DevAddr};
 pub const MIC_SIZE: usize = 4;
 
 /// Block size for AES-128
-const BLOCK_SIZE: usize = 16;
+pub const BLOCK_SIZE: usize = 16;
+
+/// Maximum FRMPayload size in bytes (MACPayload of 250 bytes without FOpts)
+pub const MAX_PAYLOAD_SIZE: usize = 242;
+
+/// Maximum join accept data handled by the join accept functions
+pub const MAX_JOIN_ACCEPT_DATA: usize = 256;
+
+/// Cryptographic operation error
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CryptoError {
+    /// Payload longer than `MAX_PAYLOAD_SIZE`
+    PayloadTooLong,
+}
+
+impl core::fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CryptoError::PayloadTooLong => f.write_str("payload too long"),
+        }
+    }
+}
+
+impl core::error::Error for CryptoError {}
 
 /// Direction identifiers for cryptographic operations
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -27,8 +57,218 @@ pub enum Direction {
     Down = 1,
 }
 
+/// AES-128 backend of the LoRaWAN security functions
+///
+/// Only `encrypt_block` and `cmac` must be implemented. A backend that keeps
+/// keys in hardware can map the `AESKey` it is given to a key slot instead of
+/// using its bytes, and override `derive_session_keys` so the session keys
+/// never leave the hardware either.
+pub trait CryptoBackend {
+    /// Encrypt a single block in place
+    fn encrypt_block(&self, key: &AESKey, block: &mut [u8; BLOCK_SIZE]);
+
+    /// Compute the AES-CMAC (RFC 4493) of a sequence of message parts
+    fn cmac(&self, key: &AESKey, parts: &[&[u8]]) -> [u8; BLOCK_SIZE];
+
+    /// Derive network and application session keys from join accept
+    fn derive_session_keys(
+        &self,
+        app_key: &AESKey,
+        app_nonce: &[u8; 3],
+        net_id: &[u8; 3],
+        dev_nonce: u16,
+    ) -> (AESKey, AESKey) {
+        let derive = |key_type: u8| {
+            let mut block = [0u8; BLOCK_SIZE];
+            block[0] = key_type;
+            block[1..4].copy_from_slice(app_nonce);
+            block[4..7].copy_from_slice(net_id);
+            block[7..9].copy_from_slice(&dev_nonce.to_le_bytes());
+            self.encrypt_block(app_key, &mut block);
+            AESKey::new(block)
+        };
+
+        // Network Session Key, then Application Session Key
+        (derive(0x01), derive(0x02))
+    }
+
+    /// Derive the McKEKey of remote multicast setup from the GenAppKey
+    ///
+    /// `McRootKey = aes128_encrypt(GenAppKey, 0x00 | pad16)` and
+    /// `McKEKey = aes128_encrypt(McRootKey, 0x00 | pad16)`, as for LoRaWAN
+    /// 1.0.x devices.
+    fn derive_mc_ke_key(&self, gen_app_key: &AESKey) -> AESKey {
+        let mut block = [0u8; BLOCK_SIZE];
+        self.encrypt_block(gen_app_key, &mut block);
+        let mc_root_key = AESKey::new(block);
+
+        let mut block = [0u8; BLOCK_SIZE];
+        self.encrypt_block(&mc_root_key, &mut block);
+        AESKey::new(block)
+    }
+
+    /// Unwrap the McKey of a McGroupSetupReq with the McKEKey
+    ///
+    /// As for join accepts, the server encrypts the McKey with the AES
+    /// decrypt operation so that devices only need the encrypt operation.
+    fn unwrap_mc_key(&self, mc_ke_key: &AESKey, mc_key_encrypted: &[u8; BLOCK_SIZE]) -> AESKey {
+        let mut block = *mc_key_encrypted;
+        self.encrypt_block(mc_ke_key, &mut block);
+        AESKey::new(block)
+    }
+
+    /// Derive the session keys of a multicast group from its McKey
+    ///
+    /// Returns (McNwkSKey, McAppSKey), each `aes128_encrypt(McKey, type |
+    /// McAddr | pad16)` with type 0x02 and 0x01.
+    fn derive_mc_session_keys(&self, mc_key: &AESKey, mc_addr: DevAddr) -> (AESKey, AESKey) {
+        let derive = |key_type: u8| {
+            let mut block = [0u8; BLOCK_SIZE];
+            block[0] = key_type;
+            block[1..5].copy_from_slice(mc_addr.as_bytes());
+            self.encrypt_block(mc_key, &mut block);
+            AESKey::new(block)
+        };
+
+        (derive(0x02), derive(0x01))
+    }
+
+    /// Compute Message Integrity Code (MIC) for a LoRaWAN message
+    fn compute_mic(
+        &self,
+        key: &AESKey,
+        data: &[u8],
+        dev_addr: DevAddr,
+        fcnt: u32,
+        dir: Direction,
+    ) -> [u8; MIC_SIZE] {
+        let mut b0 = [0u8; BLOCK_SIZE];
+        b0[0] = 0x49; // MIC block identifier
+        b0[5] = dir as u8;
+        b0[6..10].copy_from_slice(dev_addr.as_bytes());
+        b0[10..14].copy_from_slice(&fcnt.to_le_bytes());
+        b0[15] = data.len() as u8;
+
+        truncate_mic(&self.cmac(key, &[&b0, data]))
+    }
+
+    /// Compute Message Integrity Code (MIC) for a join request or join accept
+    fn compute_join_mic(&self, key: &AESKey, data: &[u8]) -> [u8; MIC_SIZE] {
+        truncate_mic(&self.cmac(key, &[data]))
+    }
+
+    /// Encrypt or decrypt payload using AES-128 in CTR mode
+    fn encrypt_payload(
+        &self,
+        key: &AESKey,
+        dev_addr: DevAddr,
+        fcnt: u32,
+        dir: Direction,
+        payload: &[u8],
+    ) -> Result<Vec<u8, MAX_PAYLOAD_SIZE>, CryptoError> {
+        let mut result = Vec::from_slice(payload).map_err(|_| CryptoError::PayloadTooLong)?;
+        self.encrypt_payload_in_place(key, dev_addr, fcnt, dir, &mut result)?;
+        Ok(result)
+    }
+
+    /// Encrypt or decrypt payload in place using AES-128 in CTR mode
+    ///
+    /// Same as `encrypt_payload` without a copy of the payload.
+    fn encrypt_payload_in_place(
+        &self,
+        key: &AESKey,
+        dev_addr: DevAddr,
+        fcnt: u32,
+        dir: Direction,
+        payload: &mut [u8],
+    ) -> Result<(), CryptoError> {
+        if payload.len() > MAX_PAYLOAD_SIZE {
+            return Err(CryptoError::PayloadTooLong);
+        }
+
+        for (i, chunk) in payload.chunks_mut(BLOCK_SIZE).enumerate() {
+            let mut a = [0u8; BLOCK_SIZE];
+            a[0] = 0x01; // Data encryption
+            a[5] = dir as u8;
+            a[6..10].copy_from_slice(dev_addr.as_bytes());
+            a[10..14].copy_from_slice(&fcnt.to_le_bytes());
+            a[15] = (i + 1) as u8;
+
+            self.encrypt_block(key, &mut a);
+            for (byte, &key_byte) in chunk.iter_mut().zip(a.iter()) {
+                *byte ^= key_byte;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decrypt join accept message
+    ///
+    /// Only the first `MAX_JOIN_ACCEPT_DATA` bytes are decrypted.
+    fn decrypt_join_accept(&self, key: &AESKey, data: &[u8]) -> Vec<u8, 256> {
+        let mut result = Vec::new();
+
+        for chunk in data[..data.len().min(MAX_JOIN_ACCEPT_DATA)].chunks(BLOCK_SIZE) {
+            let mut block = [0u8; BLOCK_SIZE];
+            block[..chunk.len()].copy_from_slice(chunk);
+            self.encrypt_block(key, &mut block);
+            // Cannot fail, the data was limited to the capacity above
+            let _ = result.extend_from_slice(&block[..chunk.len()]);
+        }
+
+        result
+    }
+}
+
+/// Software AES-128 backend
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SoftwareCrypto;
+
+impl CryptoBackend for SoftwareCrypto {
+    fn encrypt_block(&self, key: &AESKey, block: &mut [u8; BLOCK_SIZE]) {
+        let cipher = <Aes128 as KeyInit>::new_from_slice(key.as_bytes()).unwrap();
+        cipher.encrypt_block(block.into());
+    }
+
+    fn cmac(&self, key: &AESKey, parts: &[&[u8]]) -> [u8; BLOCK_SIZE] {
+        let mut mac = <Cmac<Aes128> as Mac>::new_from_slice(key.as_bytes()).unwrap();
+        for part in parts {
+            mac.update(part);
+        }
+
+        let mut result = [0u8; BLOCK_SIZE];
+        result.copy_from_slice(&mac.finalize().into_bytes());
+        result
+    }
+}
+
+/// Keep the first four bytes of a CMAC as MIC
+fn truncate_mic(cmac: &[u8; BLOCK_SIZE]) -> [u8; MIC_SIZE] {
+    let mut mic = [0u8; MIC_SIZE];
+    mic.copy_from_slice(&cmac[..MIC_SIZE]);
+    mic
+}
+
+/// Compare a received MIC with the computed one in constant time
+///
+/// All bytes are compared regardless of where the first mismatch is, so the
+/// time taken does not leak how much of a forged MIC was correct.
+pub fn mic_matches(received: &[u8], computed: &[u8; MIC_SIZE]) -> bool {
+    if received.len() != MIC_SIZE {
+        return false;
+    }
+    let diff = received
+        .iter()
+        .zip(computed)
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+    core::hint::black_box(diff) == 0
+}
+
 /// Compute Message Integrity Code (MIC) for a LoRaWAN message
 ///
+/// The MIC is the first four bytes of `aes128_cmac(key, B0 | msg)` where B0
+/// carries the direction, device address, frame counter and message length.
+///
 /// # Arguments
 /// * `key` - AES key for MIC computation
 /// * `data` - Data to compute MIC for
@@ -42,46 +282,14 @@ pub fn compute_mic(
     fcnt: u32,
     dir: Direction,
 ) -> [u8; MIC_SIZE] {
-    let cipher = Aes128::new_from_slice(key.as_bytes()).unwrap();
-    let mut b0 = [0u8; BLOCK_SIZE];
-    b0[0] = 0x49; // MIC block identifier
-    b0[5] = dir as u8;
-    b0[6..10].copy_from_slice(dev_addr.as_bytes());
-    b0[10..14].copy_from_slice(&fcnt.to_le_bytes());
-    b0[15] = data.len() as u8;
-
-    // Initialize CMAC with first block
-    let mut x = b0;
-    cipher.encrypt_block((&mut x).into());
-
-    // Process data blocks
-    let k = (data.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
-    for i in 0..k {
-        let start = i * BLOCK_SIZE;
-        let end = (start + BLOCK_SIZE).min(data.len());
-
-        // XOR with previous block
-        for j in 0..end.saturating_sub(start) {
-            x[j] ^= data[start + j];
-        }
-
-        // If this is the last block and it's not full, pad with zeros (already done by initialization)
-        if i == k - 1 && end.saturating_sub(start) < BLOCK_SIZE {
-            x[end.saturating_sub(start)] ^= 0x80; // Add padding bit
-        }
-
-        // Encrypt block
-        cipher.encrypt_block((&mut x).into());
-    }
-
-    // Return first 4 bytes as MIC
-    let mut mic = [0u8; MIC_SIZE];
-    mic.copy_from_slice(&x[..MIC_SIZE]);
-    mic
+    SoftwareCrypto.compute_mic(key, data, dev_addr, fcnt, dir)
 }
 
 /// Encrypt or decrypt payload using AES-128 in CTR mode
 ///
+/// The keystream is made of the encrypted blocks A1..Ak, whose counters
+/// start at 1. Payloads longer than `MAX_PAYLOAD_SIZE` are rejected.
+///
 /// # Arguments
 /// * `key` - AES key for encryption/decryption
 /// * `dev_addr` - Device address
@@ -94,54 +302,57 @@ pub fn encrypt_payload(
     fcnt: u32,
     dir: Direction,
     payload: &[u8],
-) -> Vec<u8, 256> {
-    let cipher = <Aes128 as KeyInit>::new_from_slice(key.as_bytes()).unwrap();
-    let mut result = Vec::new();
-
-    let k = (payload.len() + 15) / 16;
-
-    for i in 0..k {
-        let mut a = [0u8; BLOCK_SIZE];
-        a[0] = 0x01; // Data encryption
-        a[5] = dir as u8;
-        a[6..10].copy_from_slice(dev_addr.as_bytes());
-        a[10..14].copy_from_slice(&fcnt.to_le_bytes());
-        a[15] = i as u8;
-
-        let mut s = a;
-        cipher.encrypt_block((&mut s).into());
-
-        let start = i * 16;
-        let end = (start + 16).min(payload.len());
-        for j in start..end {
-            result.push(payload[j] ^ s[j - start]).unwrap();
-        }
-    }
+) -> Result<Vec<u8, MAX_PAYLOAD_SIZE>, CryptoError> {
+    SoftwareCrypto.encrypt_payload(key, dev_addr, fcnt, dir, payload)
+}
 
-    result
+/// Encrypt or decrypt payload in place using AES-128 in CTR mode
+///
+/// Same as `encrypt_payload`, the result replaces `payload`.
+pub fn encrypt_payload_in_place(
+    key: &AESKey,
+    dev_addr: DevAddr,
+    fcnt: u32,
+    dir: Direction,
+    payload: &mut [u8],
+) -> Result<(), CryptoError> {
+    SoftwareCrypto.encrypt_payload_in_place(key, dev_addr, fcnt, dir, payload)
 }
 
 /// Encrypt join accept message
 ///
+/// This is the network side of the exchange: the join server runs the AES
+/// *decrypt* operation over the join accept so that end devices only need
+/// the encrypt operation to recover it.
+///
 /// # Arguments
 /// * `key` - AES key for encryption
-/// * `data` - Join accept data to encrypt
+/// * `data` - Join accept data to encrypt (without MHDR, including MIC),
+///   only the first `MAX_JOIN_ACCEPT_DATA` bytes are encrypted
 pub fn encrypt_join_accept(key: &AESKey, data: &[u8]) -> Vec<u8, 256> {
     let cipher = Aes128::new_from_slice(key.as_bytes()).unwrap();
     let mut result = Vec::new();
 
-    for chunk in data.chunks(16) {
+    for chunk in data[..data.len().min(MAX_JOIN_ACCEPT_DATA)].chunks(BLOCK_SIZE) {
         let mut block = [0u8; BLOCK_SIZE];
         block[..chunk.len()].copy_from_slice(chunk);
-        cipher.encrypt_block((&mut block).into());
-        for &b in &block[..chunk.len()] {
-            result.push(b).unwrap();
-        }
+        cipher.decrypt_block((&mut block).into());
+        // Cannot fail, the data was limited to the capacity above
+        let _ = result.extend_from_slice(&block[..chunk.len()]);
     }
 
     result
 }
 
+/// Decrypt join accept message
+///
+/// # Arguments
+/// * `key` - Application key used for the join
+/// * `data` - Encrypted join accept (without MHDR, including MIC)
+pub fn decrypt_join_accept(key: &AESKey, data: &[u8]) -> Vec<u8, 256> {
+    SoftwareCrypto.decrypt_join_accept(key, data)
+}
+
 /// Derive network and application session keys from join accept
 ///
 /// # Arguments
@@ -155,72 +366,54 @@ pub fn derive_session_keys(
     net_id: &[u8; 3],
     dev_nonce: u16,
 ) -> (AESKey, AESKey) {
-    let cipher = Aes128::new_from_slice(app_key.as_bytes()).unwrap();
+    SoftwareCrypto.derive_session_keys(app_key, app_nonce, net_id, dev_nonce)
+}
 
-    // Generate Network Session Key
-    let mut nwk_skey = [0u8; BLOCK_SIZE];
-    nwk_skey[0] = 0x01;
-    nwk_skey[1..4].copy_from_slice(app_nonce);
-    nwk_skey[4..7].copy_from_slice(net_id);
-    nwk_skey[7..9].copy_from_slice(&dev_nonce.to_le_bytes());
-    cipher.encrypt_block((&mut nwk_skey).into());
+/// Derive the McKEKey of remote multicast setup from the GenAppKey
+pub fn derive_mc_ke_key(gen_app_key: &AESKey) -> AESKey {
+    SoftwareCrypto.derive_mc_ke_key(gen_app_key)
+}
 
-    // Generate Application Session Key
-    let mut app_skey = [0u8; BLOCK_SIZE];
-    app_skey[0] = 0x02;
-    app_skey[1..4].copy_from_slice(app_nonce);
-    app_skey[4..7].copy_from_slice(net_id);
-    app_skey[7..9].copy_from_slice(&dev_nonce.to_le_bytes());
-    cipher.encrypt_block((&mut app_skey).into());
+/// Unwrap the encrypted McKey of a McGroupSetupReq with the McKEKey
+pub fn unwrap_mc_key(mc_ke_key: &AESKey, mc_key_encrypted: &[u8; BLOCK_SIZE]) -> AESKey {
+    SoftwareCrypto.unwrap_mc_key(mc_ke_key, mc_key_encrypted)
+}
 
-    (AESKey::new(nwk_skey), AESKey::new(app_skey))
+/// Wrap a McKey for a McGroupSetupReq with the McKEKey
+///
+/// This is the network side of `unwrap_mc_key`, it runs the AES *decrypt*
+/// operation.
+pub fn wrap_mc_key(mc_ke_key: &AESKey, mc_key: &AESKey) -> [u8; BLOCK_SIZE] {
+    let cipher = Aes128::new_from_slice(mc_ke_key.as_bytes()).unwrap();
+    let mut block = *mc_key.as_bytes();
+    cipher.decrypt_block((&mut block).into());
+    block
+}
+
+/// Derive the session keys of a multicast group, (McNwkSKey, McAppSKey)
+pub fn derive_mc_session_keys(mc_key: &AESKey, mc_addr: DevAddr) -> (AESKey, AESKey) {
+    SoftwareCrypto.derive_mc_session_keys(mc_key, mc_addr)
 }
 
 /// Compute Message Integrity Code (MIC) for a LoRaWAN join request
 ///
+/// The MIC is the first four bytes of `aes128_cmac(AppKey, MHDR | AppEUI | DevEUI | DevNonce)`.
+///
 /// # Arguments
 /// * `key` - Application key for MIC computation
 /// * `data` - Join request data to compute MIC for
 pub fn compute_join_request_mic(key: &AESKey, data: &[u8]) -> [u8; MIC_SIZE] {
-    let cipher = Aes128::new_from_slice(key.as_bytes()).unwrap();
-    let mut b0 = [0u8; BLOCK_SIZE];
-    b0[0] = 0x49; // MIC block identifier
-    b0[1..].copy_from_slice(&data[..data.len().min(BLOCK_SIZE - 1)]);
-
-    // Initialize CMAC with first block
-    let mut x = b0;
-    cipher.encrypt_block((&mut x).into());
-
-    // Process remaining data blocks if any
-    if data.len() > BLOCK_SIZE - 1 {
-        let remaining = &data[BLOCK_SIZE - 1..];
-        let k = (remaining.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
-
-        for i in 0..k {
-            let start = i * BLOCK_SIZE;
-            let end = (start + BLOCK_SIZE).min(remaining.len());
-
-            // XOR with previous block
-            for j in 0..end.saturating_sub(start) {
-                x[j] ^= remaining[start + j];
-            }
-
-            // If this is the last block and it's not full, pad with zeros (already done by initialization)
-            if i == k - 1 && end.saturating_sub(start) < BLOCK_SIZE {
-                x[end.saturating_sub(start)] ^= 0x80; // Add padding bit
-            }
-
-            // Encrypt block
-            cipher.encrypt_block((&mut x).into());
-        }
-    } else {
-        // If all data fit in first block, just add padding
-        x[data.len()] ^= 0x80;
-        cipher.encrypt_block((&mut x).into());
-    }
+    SoftwareCrypto.compute_join_mic(key, data)
+}
 
-    // Return first 4 bytes as MIC
-    let mut mic = [0u8; MIC_SIZE];
-    mic.copy_from_slice(&x[..MIC_SIZE]);
-    mic
+/// Compute Message Integrity Code (MIC) for a LoRaWAN join accept
+///
+/// The join accept MIC covers MHDR | AppNonce | NetID | DevAddr | DLSettings |
+/// RxDelay | CFList and uses the same construction as the join request MIC.
+///
+/// # Arguments
+/// * `key` - Application key for MIC computation
+/// * `data` - Decrypted join accept data (without MIC) to compute MIC for
+pub fn compute_join_accept_mic(key: &AESKey, data: &[u8]) -> [u8; MIC_SIZE] {
+    SoftwareCrypto.compute_join_mic(key, data)
 }
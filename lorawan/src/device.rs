@@ -3,58 +3,432 @@
 //! This module provides the main device interface for LoRaWAN communication.
 //! It handles device configuration, activation, and message handling.
 
+pub mod power;
+
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+
 use crate::{
-    class::{class_a::ClassA, class_b::ClassB, class_c::ClassC, DeviceClass, OperatingMode},
-    config::device::{AESKey, DeviceConfig, SessionState},
+    applayer::{
+        clock_sync::{ClockSync, CLOCK_SYNC_PORT},
+        multicast_setup::{
+            McGroupChange, McSession, MulticastSetup, MAX_MC_GROUP_IDS, MULTICAST_SETUP_PORT,
+        },
+    },
+    class::{
+        class_a::ClassA,
+        class_b::{beacon::BeaconState, ping_slot::PingSlotConfig, timing::NetworkTime, ClassB},
+        class_c::ClassC,
+        DeviceClass, OperatingMode,
+    },
+    config::device::{
+        AESKey, ConfigError, DevAddr, DevNonceStore, DeviceConfig, FrameCounterStore, SessionError,
+        SessionState, SESSION_STATE_SIZE,
+    },
+    crypto::{CryptoBackend, SoftwareCrypto},
+    device::power::{PowerMetrics, PowerState, StatusProvider},
     lorawan::{
-        mac::{MacError, MacLayer},
-        region::Region,
+        commands::MacCommand,
+        duty_cycle::AirtimeBudget,
+        mac::{
+            AdrState, ConfirmedResult, Downlink, LinkCheckResult, LinkStats, MacError, MacLayer,
+            MAX_MAC_COMMANDS, MAX_MAC_PAYLOAD,
+        },
+        multicast::MulticastGroup,
+        phy::NetworkType,
+        region::{Channel, DataRate, Region},
     },
-    radio::traits::Radio,
+    radio::traits::{Radio, RxConfig, TxConfig},
+    timing::{has_elapsed, Clock},
 };
+use heapless::Vec;
 
 /// LoRaWAN device error type
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum DeviceError<E> {
     /// MAC layer error
     Mac(MacError<E>),
-    /// Invalid configuration
-    InvalidConfig,
+    /// Device configuration rejected by `DeviceConfig::validate`
+    InvalidConfig(ConfigError),
     /// Invalid state for operation
     InvalidState,
+    /// No session yet, join the network or restore a session first
+    NotJoined,
+    /// Saved session state could not be restored
+    Session(SessionError),
+    /// Transmitting now would exceed the duty cycle or airtime budget, or
+    /// overlap the receive windows of the last uplink
+    TooEarly {
+        /// Time until the uplink is allowed, see `next_tx_opportunity`
+        retry_after_ms: u32,
+    },
+    /// The radio lacks a capability of the device class, e.g. continuous
+    /// reception for Class C
+    UnsupportedClass(OperatingMode),
+}
+
+impl<E> fmt::Display for DeviceError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceError::Mac(_) => f.write_str("MAC layer error"),
+            DeviceError::InvalidConfig(_) => f.write_str("invalid device configuration"),
+            DeviceError::InvalidState => f.write_str("invalid state for operation"),
+            DeviceError::NotJoined => f.write_str("not joined to a network"),
+            DeviceError::Session(_) => f.write_str("session state could not be restored"),
+            DeviceError::TooEarly { retry_after_ms } => write!(
+                f,
+                "too early to transmit, retry after {} ms",
+                retry_after_ms
+            ),
+            DeviceError::UnsupportedClass(mode) => {
+                write!(f, "{:?} not supported by the radio", mode)
+            }
+        }
+    }
+}
+
+impl<E: core::error::Error + 'static> core::error::Error for DeviceError<E> {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            DeviceError::Mac(error) => Some(error),
+            DeviceError::InvalidConfig(error) => Some(error),
+            DeviceError::Session(error) => Some(error),
+            _ => None,
+        }
+    }
 }
 
 impl<E> From<MacError<E>> for DeviceError<E> {
     fn from(error: MacError<E>) -> Self {
-        DeviceError::Mac(error)
+        match error {
+            MacError::NotJoined => DeviceError::NotJoined,
+            error => DeviceError::Mac(error),
+        }
     }
 }
 
-/// LoRaWAN device implementation
-pub struct LoRaWANDevice<R: Radio + Clone, REG: Region> {
-    /// Current operating mode
+/// Event reported by `LoRaWANDevice::poll`
+// Without an allocator the downlink payload cannot be boxed
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum DeviceEvent {
+    /// A join request or uplink was transmitted
+    TxComplete,
+    /// A valid join accept was received, the device is joined
+    JoinAccepted,
+    /// No join accept was received in either receive window
+    JoinTimeout,
+    /// Application data was received
+    DownlinkReceived(Downlink),
+    /// The network acknowledged the confirmed uplink
+    AckReceived,
+    /// No acknowledgement was received in either receive window
+    AckTimeout,
+    /// A MAC command of the network was applied
+    MacCommandApplied(MacCommand),
+    /// The Class B beacon tracking changed state, e.g. beacons were lost
+    BeaconStateChanged(BeaconState),
+    /// The uplink frame counter reached the rejoin threshold
+    ///
+    /// Start a new join before the counter is exhausted, see
+    /// `LoRaWANDevice::set_rejoin_threshold`.
+    RejoinSuggested {
+        /// Uplink frame counter of the session
+        fcnt_up: u32,
+    },
+    /// The network time was corrected by an AppTimeAns, see
+    /// `LoRaWANDevice::request_time_sync`
+    TimeSynced {
+        /// Correction applied in seconds
+        correction_s: i32,
+    },
+    /// A multicast session of the remote multicast setup package started,
+    /// the device switched to the class of the session
+    MulticastSessionStarted {
+        /// McGroupID of the session
+        group_id: u8,
+    },
+    /// The multicast session ended, the device returned to its class
+    MulticastSessionEnded {
+        /// McGroupID of the session
+        group_id: u8,
+    },
+}
+
+/// Default uplink frame counter at which `RejoinSuggested` is reported
+///
+/// Leaves 65535 uplinks to complete the join.
+pub const DEFAULT_REJOIN_THRESHOLD: u32 = 0xFFFF_0000;
+
+/// Maximum number of data rates in a `JoinPolicy`
+pub const MAX_JOIN_DATA_RATES: usize = 8;
+
+/// Retransmission of the join requests started by `start_join`
+///
+/// When no join accept arrives, the next join request is sent after a
+/// backoff that doubles with every attempt, from `base_backoff_ms` up to
+/// `max_backoff_ms`. The attempts cycle through the data rates of
+/// `dr_sequence`, e.g. `[0, 4]` alternates between the 125 kHz and 500 kHz
+/// channels of US915. Without data rates join requests go out at the most
+/// robust data rate of the channel.
+#[derive(Debug, Clone)]
+pub struct JoinPolicy {
+    /// Join requests sent before `JoinTimeout` is reported
+    pub max_attempts: u8,
+    /// Wait after the first join request without join accept in ms
+    pub base_backoff_ms: u32,
+    /// Longest wait between two join requests in ms
+    pub max_backoff_ms: u32,
+    /// Data rate index of each attempt, repeated
+    pub dr_sequence: Vec<u8, MAX_JOIN_DATA_RATES>,
+}
+
+impl Default for JoinPolicy {
+    /// A single join request, retries are left to the application
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            base_backoff_ms: 10_000,
+            max_backoff_ms: 3_600_000,
+            dr_sequence: Vec::new(),
+        }
+    }
+}
+
+impl JoinPolicy {
+    /// Get the wait in ms after `attempts` join requests without join accept
+    pub fn backoff(&self, attempts: u8) -> u32 {
+        let doublings = u32::from(attempts.saturating_sub(1)).min(31);
+        self.base_backoff_ms
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff_ms)
+    }
+
+    /// Get the data rate index of the attempt, counted from 0
+    pub fn data_rate(&self, attempt: u8) -> Option<u8> {
+        match self.dr_sequence.len() {
+            0 => None,
+            len => Some(self.dr_sequence[attempt as usize % len]),
+        }
+    }
+}
+
+/// Transmission waiting for the next `poll`
+#[derive(Debug, Clone)]
+enum PendingTx {
+    /// OTAA join request
+    Join {
+        dev_eui: [u8; 8],
+        app_eui: [u8; 8],
+        app_key: AESKey,
+    },
+    /// Data uplink, the payload is kept by the device
+    Uplink { port: u8, confirmed: bool },
+}
+
+/// Frame an exchange expects in its receive windows
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Expect {
+    /// Join accept
+    JoinAccept,
+    /// Any downlink
+    Downlink,
+    /// Downlink with the ACK bit set
+    Ack,
+}
+
+/// Step of the exchange driven by `poll`
+#[derive(Debug, Clone)]
+enum PollState {
+    /// No exchange in progress
+    Idle,
+    /// Transmit on the next poll
+    Tx(PendingTx),
+    /// Waiting for RX1 after a transmission
+    Rx1 {
+        tx_end: u32,
+        channel: Channel,
+        expect: Expect,
+    },
+    /// Waiting for RX2 after a transmission
+    Rx2 { tx_end: u32, expect: Expect },
+    /// Waiting for the backoff of the join policy to retransmit
+    Backoff { until: u32, tx: PendingTx },
+}
+
+/// Multicast session scheduled by the remote multicast setup package
+struct ScheduledSession {
+    session: McSession,
+    /// Local times the session starts and ends at
+    start_ms: u32,
+    end_ms: u32,
+    /// Settings to restore at the end, once the session started
+    restore: Option<SessionRestore>,
+}
+
+/// Device settings changed by a multicast session
+struct SessionRestore {
+    mode: OperatingMode,
+    rx2_frequency: Option<u32>,
+    rx2_data_rate: Option<u8>,
+    ping_slot_config: PingSlotConfig,
+}
+
+/// Check that `radio` has the capabilities the class of `mode` needs
+fn check_class_supported<R: Radio>(
+    radio: &R,
     mode: OperatingMode,
-    /// Class A implementation
-    class_a: ClassA<R, REG>,
-    /// Class B implementation
-    class_b: Option<ClassB<R, REG>>,
-    /// Class C implementation
-    class_c: Option<ClassC<R, REG>>,
+) -> Result<(), DeviceError<R::Error>> {
+    match mode {
+        OperatingMode::ClassC if !radio.capabilities().continuous_rx => {
+            Err(DeviceError::UnsupportedClass(mode))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Device class driving the MAC layer
+// Without an allocator the classes cannot be boxed, the device holds one
+#[allow(clippy::large_enum_variant)]
+enum ClassState<R: Radio, REG: Region> {
+    A(ClassA<R, REG>),
+    B(ClassB<R, REG>),
+    C(ClassC<R, REG>),
+}
+
+impl<R: Radio, REG: Region> ClassState<R, REG> {
+    /// Run `mac` in the class of `mode`
+    fn new(mac: MacLayer<R, REG>, mode: OperatingMode) -> Self {
+        match mode {
+            OperatingMode::ClassA => ClassState::A(ClassA::new(mac)),
+            OperatingMode::ClassB => ClassState::B(ClassB::new(mac)),
+            OperatingMode::ClassC => {
+                let region = mac.get_region();
+                let (frequency, data_rate) = (region.rx2_frequency(), region.rx2_data_rate());
+                ClassState::C(ClassC::new(mac, frequency, data_rate))
+            }
+        }
+    }
+
+    fn into_mac_layer(self) -> MacLayer<R, REG> {
+        match self {
+            ClassState::A(class_a) => class_a.into_mac_layer(),
+            ClassState::B(class_b) => class_b.into_mac_layer(),
+            ClassState::C(class_c) => class_c.into_mac_layer(),
+        }
+    }
+
+    fn as_class(&self) -> &dyn DeviceClass<R, REG> {
+        match self {
+            ClassState::A(class_a) => class_a,
+            ClassState::B(class_b) => class_b,
+            ClassState::C(class_c) => class_c,
+        }
+    }
+
+    fn as_class_mut(&mut self) -> &mut dyn DeviceClass<R, REG> {
+        match self {
+            ClassState::A(class_a) => class_a,
+            ClassState::B(class_b) => class_b,
+            ClassState::C(class_c) => class_c,
+        }
+    }
+}
+
+/// LoRaWAN device implementation
+pub struct LoRaWANDevice<R: Radio, REG: Region> {
+    /// Active device class, owning the MAC layer
+    ///
+    /// Only `None` while `set_operating_mode` moves the MAC layer.
+    class: Option<ClassState<R, REG>>,
+    /// Poll the network for pending downlinks automatically
+    auto_poll: bool,
+    /// Retransmission of join requests
+    join_policy: JoinPolicy,
+    /// Join request of the join in progress, retransmitted by the policy
+    join_request: Option<PendingTx>,
+    /// Join requests sent by the join in progress
+    join_attempts: u8,
+    /// Join request of the last `start_join`, for rejoins
+    join_credentials: Option<PendingTx>,
+    /// Uplink frame counter at which a rejoin is suggested
+    rejoin_threshold: u32,
+    /// `RejoinSuggested` was reported for the session
+    rejoin_reported: bool,
+    /// Start a join once the rejoin threshold is reached
+    auto_rejoin: bool,
+    /// Exchange driven by `poll`
+    poll_state: PollState,
+    /// Payload of the uplink waiting for the next `poll`
+    tx_payload: Vec<u8, MAX_MAC_PAYLOAD>,
+    /// MAC commands applied since the last poll, reported by `poll`
+    applied_commands: Vec<MacCommand, MAX_MAC_COMMANDS>,
+    /// Clock synchronization package, `None` while disabled
+    clock_sync: Option<ClockSync>,
+    /// Remote multicast setup package, `None` while disabled
+    multicast_setup: Option<MulticastSetup>,
+    /// Multicast session scheduled or running
+    mc_session: Option<ScheduledSession>,
+    /// Session replacing the running one once it ended
+    mc_next_session: Option<ScheduledSession>,
 }
 
-impl<R: Radio + Clone, REG: Region> LoRaWANDevice<R, REG> {
+impl<R: Radio, REG: Region> LoRaWANDevice<R, REG> {
     /// Create new LoRaWAN device
+    ///
+    /// Fails with `InvalidConfig` if the configuration does not pass
+    /// `DeviceConfig::validate`, and with `UnsupportedClass` for Class C on a
+    /// radio without continuous reception.
     pub fn new(
         radio: R,
         config: DeviceConfig,
         region: REG,
         mode: OperatingMode,
     ) -> Result<Self, DeviceError<R::Error>> {
+        Self::with_crypto_backend(radio, config, region, mode, &SoftwareCrypto)
+    }
+
+    /// Create new LoRaWAN device without validating the configuration
+    ///
+    /// Meant for test fixtures with placeholder credentials.
+    pub fn new_unchecked(radio: R, config: DeviceConfig, region: REG, mode: OperatingMode) -> Self {
+        Self::build(radio, config, region, mode, &SoftwareCrypto)
+    }
+
+    /// Create new LoRaWAN device running the security functions on `crypto`
+    ///
+    /// Use this to keep keys in a secure element or crypto peripheral. The
+    /// configuration is validated as by `new`.
+    pub fn with_crypto_backend(
+        radio: R,
+        config: DeviceConfig,
+        region: REG,
+        mode: OperatingMode,
+        crypto: &'static dyn CryptoBackend,
+    ) -> Result<Self, DeviceError<R::Error>> {
+        config.validate().map_err(DeviceError::InvalidConfig)?;
+        check_class_supported(&radio, mode)?;
+        Ok(Self::build(radio, config, region, mode, crypto))
+    }
+
+    /// Create the device and its session from the configuration
+    fn build(
+        radio: R,
+        config: DeviceConfig,
+        region: REG,
+        mode: OperatingMode,
+        crypto: &'static dyn CryptoBackend,
+    ) -> Self {
         // Initialize session state based on device configuration
         let session = match (config.dev_addr, config.nwk_skey, config.app_skey) {
             (Some(addr), Some(nwk), Some(app)) => {
-                // ABP activation - use provided keys
-                SessionState::new_abp(addr, nwk, app)
+                // ABP activation - use provided keys and the RX2 defaults of
+                // the region until RXParamSetupReq
+                let mut session = SessionState::new_abp(addr, nwk, app);
+                session.rx2_frequency = Some(region.rx2_frequency());
+                session.rx2_data_rate = Some(region.rx2_data_rate());
+                session
             }
             _ => {
                 // OTAA activation - start with empty session
@@ -62,158 +436,960 @@ impl<R: Radio + Clone, REG: Region> LoRaWANDevice<R, REG> {
             }
         };
 
-        let mac = MacLayer::new(radio.clone(), region.clone(), session.clone());
-        let class_a = ClassA::new(mac);
-
-        let mut device = Self {
-            mode,
-            class_a,
-            class_b: None,
-            class_c: None,
-        };
-
-        // Initialize additional device classes if needed
-        match mode {
-            OperatingMode::ClassB => {
-                let mac = MacLayer::new(radio.clone(), region.clone(), session.clone());
-                device.class_b = Some(ClassB::new(mac));
-            }
-            OperatingMode::ClassC => {
-                let mac = MacLayer::new(radio, region.clone(), session.clone());
-                device.class_c = Some(ClassC::new(
-                    mac,
-                    region.rx2_frequency(),
-                    region.rx2_data_rate(),
-                ));
-            }
-            _ => {}
+        let mac = MacLayer::with_crypto_backend(radio, region, session, crypto);
+        Self {
+            class: Some(ClassState::new(mac, mode)),
+            auto_poll: config.auto_poll,
+            join_policy: JoinPolicy::default(),
+            join_request: None,
+            join_attempts: 0,
+            join_credentials: None,
+            rejoin_threshold: DEFAULT_REJOIN_THRESHOLD,
+            rejoin_reported: false,
+            auto_rejoin: false,
+            poll_state: PollState::Idle,
+            tx_payload: Vec::new(),
+            applied_commands: Vec::new(),
+            clock_sync: None,
+            multicast_setup: None,
+            mc_session: None,
+            mc_next_session: None,
         }
+    }
+
+    /// Get the active device class
+    fn class(&self) -> &dyn DeviceClass<R, REG> {
+        self.class.as_ref().expect("device class").as_class()
+    }
 
-        Ok(device)
+    /// Get the mutable active device class
+    fn class_mut(&mut self) -> &mut dyn DeviceClass<R, REG> {
+        self.class.as_mut().expect("device class").as_class_mut()
     }
 
     /// Get current operating mode
     pub fn operating_mode(&self) -> OperatingMode {
-        self.mode
+        self.class().operating_mode()
+    }
+
+    /// Check if the device operates in Class B
+    ///
+    /// False in Class B mode until a beacon is received, and once beacons
+    /// are lost for 2 hours. The device then only receives as in Class A.
+    pub fn is_class_b_active(&self) -> bool {
+        matches!(&self.class, Some(ClassState::B(class_b)) if class_b.is_active())
     }
 
     /// Set operating mode
+    ///
+    /// The MAC layer moves to the new class, so the session, frame counters,
+    /// pending MAC commands and settings such as ADR are kept.
+    ///
+    /// Class B starts with the beacon acquisition on the next `process`. The
+    /// device declares itself Class B in its uplinks once synchronized, see
+    /// `is_class_b_active`. Class C fails with `UnsupportedClass` if the
+    /// radio has no continuous reception.
     pub fn set_operating_mode(&mut self, mode: OperatingMode) -> Result<(), DeviceError<R::Error>> {
         // Don't do anything if mode isn't changing
-        if self.mode == mode {
+        if self.operating_mode() == mode {
             return Ok(());
         }
+        check_class_supported(self.get_mac_layer().get_radio(), mode)?;
 
-        // Get current session state from active class
-        let session = match self.mode {
-            OperatingMode::ClassA => self.class_a.get_session_state(),
-            OperatingMode::ClassB => self
-                .class_b
-                .as_ref()
-                .ok_or(DeviceError::InvalidState)?
-                .get_session_state(),
-            OperatingMode::ClassC => self
-                .class_c
-                .as_ref()
-                .ok_or(DeviceError::InvalidState)?
-                .get_session_state(),
+        let class = self.class.take().ok_or(DeviceError::InvalidState)?;
+        self.class = Some(ClassState::new(class.into_mac_layer(), mode));
+        Ok(())
+    }
+
+    /// Process device operations
+    ///
+    /// With auto-poll enabled, an empty uplink is sent when a downlink signals
+    /// that the network has more downlinks queued. Until the duty cycle allows
+    /// it the poll stays pending, and a later call sends it.
+    pub fn process(&mut self) -> Result<(), DeviceError<R::Error>> {
+        self.class_mut().process()?;
+
+        if self.auto_poll && self.downlink_pending() && self.next_tx_opportunity(0)? == 0 {
+            self.send_data(0, &[], false)?;
+        }
+        Ok(())
+    }
+
+    /// Start an OTAA join, transmitted by the next `poll`
+    ///
+    /// `poll` then reports `JoinAccepted`, or `JoinTimeout` once the join
+    /// requests of the join policy went unanswered.
+    pub fn start_join(
+        &mut self,
+        dev_eui: [u8; 8],
+        app_eui: [u8; 8],
+        app_key: AESKey,
+    ) -> Result<(), DeviceError<R::Error>> {
+        let tx = PendingTx::Join {
+            dev_eui,
+            app_eui,
+            app_key,
         };
+        self.start_tx(tx.clone())?;
+        self.join_request = Some(tx.clone());
+        self.join_credentials = Some(tx);
+        self.join_attempts = 0;
+        Ok(())
+    }
+
+    /// Set the uplink frame counter at which `poll` reports `RejoinSuggested`
+    ///
+    /// The event is reported once per session, after the exchange that
+    /// reached the threshold. Uplinks fail with `FrameCounterExhausted` once
+    /// the counter reaches `u32::MAX`.
+    pub fn set_rejoin_threshold(&mut self, fcnt_up: u32) {
+        self.rejoin_threshold = fcnt_up;
+    }
+
+    /// Get the uplink frame counter at which a rejoin is suggested
+    pub fn rejoin_threshold(&self) -> u32 {
+        self.rejoin_threshold
+    }
+
+    /// Rejoin automatically once the rejoin threshold is reached
+    ///
+    /// After reporting `RejoinSuggested`, `poll` sends a join request with
+    /// the credentials of the last `start_join` and the join policy. Has no
+    /// effect on ABP devices, which need a new session from the application.
+    pub fn set_auto_rejoin(&mut self, enabled: bool) {
+        self.auto_rejoin = enabled;
+    }
+
+    /// Report that the rejoin threshold was reached, once per session
+    fn check_rejoin(&mut self) -> Option<DeviceEvent> {
+        let session = self.get_mac_layer().get_session_state();
+        let (joined, fcnt_up) = (session.is_joined(), session.fcnt_up);
+        if !joined || fcnt_up < self.rejoin_threshold {
+            // New session or counters reset
+            self.rejoin_reported = false;
+            return None;
+        }
+        if self.rejoin_reported || !matches!(self.poll_state, PollState::Idle) {
+            return None;
+        }
+        self.rejoin_reported = true;
+
+        if let (true, Some(tx)) = (self.auto_rejoin, self.join_credentials.clone()) {
+            self.join_request = Some(tx.clone());
+            self.join_attempts = 0;
+            self.poll_state = PollState::Tx(tx);
+        }
+        Some(DeviceEvent::RejoinSuggested { fcnt_up })
+    }
+
+    /// Set how `start_join` retransmits join requests
+    pub fn set_join_policy(&mut self, policy: JoinPolicy) {
+        self.join_policy = policy;
+    }
 
-        // Get radio and region from current class
-        let (radio, region) = match self.mode {
-            OperatingMode::ClassA => {
-                let mac = self.class_a.get_mac_layer();
-                (mac.get_radio().clone(), mac.get_region().clone())
+    /// Get the join policy
+    pub fn join_policy(&self) -> &JoinPolicy {
+        &self.join_policy
+    }
+
+    /// Get the number of join requests sent by the last `start_join`
+    pub fn join_attempts(&self) -> u8 {
+        self.join_attempts
+    }
+
+    /// Get the time the next join request of the join policy is sent at
+    ///
+    /// Returns `None` unless the device waits for the backoff of a join.
+    pub fn next_join_attempt(&self) -> Option<u32> {
+        match self.poll_state {
+            PollState::Backoff { until, .. } => Some(until),
+            _ => None,
+        }
+    }
+
+    /// Schedule the next join request of the policy, or report the timeout
+    fn retry_join(&mut self, now_ms: u32) -> Option<DeviceEvent> {
+        match self.join_request.take() {
+            Some(tx) if self.join_attempts < self.join_policy.max_attempts => {
+                let until = now_ms.wrapping_add(self.join_policy.backoff(self.join_attempts));
+                self.join_request = Some(tx.clone());
+                self.poll_state = PollState::Backoff { until, tx };
+                None
             }
-            OperatingMode::ClassB => {
-                let class_b = self.class_b.as_ref().ok_or(DeviceError::InvalidState)?;
-                let mac = class_b.get_mac_layer();
-                (mac.get_radio().clone(), mac.get_region().clone())
+            _ => Some(DeviceEvent::JoinTimeout),
+        }
+    }
+
+    /// Start an uplink, transmitted by the next `poll`
+    ///
+    /// A confirmed uplink is sent once, `poll` then reports `AckReceived` or
+    /// `AckTimeout` and retransmission is left to the application. Fails with
+    /// `NotJoined` before the device has a session.
+    pub fn start_uplink(
+        &mut self,
+        port: u8,
+        data: &[u8],
+        confirmed: bool,
+    ) -> Result<(), DeviceError<R::Error>> {
+        if self.is_busy() {
+            return Err(DeviceError::InvalidState);
+        }
+        if !self.get_mac_layer().get_session_state().is_joined() {
+            return Err(DeviceError::NotJoined);
+        }
+        if self.get_mac_layer().frame_counter_exhausted() {
+            return Err(DeviceError::Mac(MacError::FrameCounterExhausted));
+        }
+        self.tx_payload.clear();
+        self.tx_payload
+            .extend_from_slice(data)
+            .map_err(|_| DeviceError::Mac(MacError::BufferTooSmall))?;
+        self.start_tx(PendingTx::Uplink { port, confirmed })
+    }
+
+    /// Queue a transmission if no exchange is in progress
+    fn start_tx(&mut self, tx: PendingTx) -> Result<(), DeviceError<R::Error>> {
+        if !matches!(self.poll_state, PollState::Idle) {
+            return Err(DeviceError::InvalidState);
+        }
+        self.poll_state = PollState::Tx(tx);
+        Ok(())
+    }
+
+    /// Check if an exchange started by `start_join` or `start_uplink` is in progress
+    pub fn is_busy(&self) -> bool {
+        !matches!(self.poll_state, PollState::Idle)
+    }
+
+    /// Advance the device without blocking and report what happened
+    ///
+    /// Call this regularly, at least around the receive windows, with the
+    /// current time of a millisecond clock. A pending transmission is sent,
+    /// then RX1 and RX2 are opened when due. Only one event is returned per
+    /// call; call again while events are returned. While idle, Class B and C
+    /// devices keep receiving through their class.
+    pub fn poll(&mut self, now_ms: u32) -> Result<Option<DeviceEvent>, DeviceError<R::Error>> {
+        if let Some(event) = self.next_queued_event() {
+            return Ok(Some(event));
+        }
+
+        match self.poll_state.clone() {
+            PollState::Idle => {
+                if let Some(event) = self.update_multicast_session(now_ms)? {
+                    return Ok(Some(event));
+                }
+                if self.operating_mode() != OperatingMode::ClassA {
+                    self.process()?;
+                }
+                Ok(self.next_queued_event())
             }
-            OperatingMode::ClassC => {
-                let class_c = self.class_c.as_ref().ok_or(DeviceError::InvalidState)?;
-                let mac = class_c.get_mac_layer();
-                (mac.get_radio().clone(), mac.get_region().clone())
+            PollState::Tx(tx) => {
+                // A failed transmission ends the exchange
+                self.poll_state = PollState::Idle;
+                let payload = core::mem::take(&mut self.tx_payload);
+                let join_data_rate = self.join_policy.data_rate(self.join_attempts);
+                if matches!(tx, PendingTx::Join { .. }) {
+                    self.join_attempts = self.join_attempts.saturating_add(1);
+                }
+                let mac = self.get_mac_layer_mut();
+                let expect = match tx {
+                    PendingTx::Join {
+                        dev_eui,
+                        app_eui,
+                        app_key,
+                    } => {
+                        match join_data_rate {
+                            Some(data_rate) => {
+                                mac.join_request_at(dev_eui, app_eui, app_key, data_rate)?
+                            }
+                            None => mac.join_request(dev_eui, app_eui, app_key)?,
+                        }
+                        Expect::JoinAccept
+                    }
+                    PendingTx::Uplink {
+                        port,
+                        confirmed: true,
+                    } => {
+                        mac.send_confirmed_once(port, &payload)?;
+                        Expect::Ack
+                    }
+                    PendingTx::Uplink {
+                        port,
+                        confirmed: false,
+                    } => {
+                        mac.send_unconfirmed(port, &payload)?;
+                        Expect::Downlink
+                    }
+                };
+                let channel = self
+                    .get_mac_layer()
+                    .get_last_uplink_channel()
+                    .cloned()
+                    .ok_or(DeviceError::InvalidState)?;
+                self.poll_state = PollState::Rx1 {
+                    tx_end: now_ms,
+                    channel,
+                    expect,
+                };
+                Ok(Some(DeviceEvent::TxComplete))
+            }
+            PollState::Rx1 {
+                tx_end,
+                channel,
+                expect,
+            } => {
+                let (rx1_delay, _) = self.receive_delays(expect);
+                if !has_elapsed(now_ms, tx_end.wrapping_add(rx1_delay)) {
+                    return Ok(None);
+                }
+                let (frequency, data_rate) = self.rx1_window(&channel, expect);
+                self.poll_state = PollState::Rx2 { tx_end, expect };
+                self.poll_window(frequency, data_rate, expect)
+            }
+            PollState::Rx2 { tx_end, expect } => {
+                let (_, rx2_delay) = self.receive_delays(expect);
+                if !has_elapsed(now_ms, tx_end.wrapping_add(rx2_delay)) {
+                    return Ok(None);
+                }
+                self.poll_state = PollState::Idle;
+                let (frequency, data_rate) = self.get_mac_layer().rx2_window();
+                match self.poll_window(frequency, data_rate, expect)? {
+                    Some(event) => Ok(Some(event)),
+                    None => Ok(match expect {
+                        Expect::JoinAccept => self.retry_join(now_ms),
+                        Expect::Downlink => self.next_queued_event(),
+                        Expect::Ack => Some(DeviceEvent::AckTimeout),
+                    }),
+                }
+            }
+            PollState::Backoff { until, tx } => {
+                if !has_elapsed(now_ms, until) {
+                    return Ok(None);
+                }
+                self.poll_state = PollState::Tx(tx);
+                self.poll(now_ms)
             }
+        }
+    }
+
+    /// Get the next receive window `poll` opens as (opens at, frequency, data rate)
+    ///
+    /// Returns `None` unless an exchange waits for RX1 or RX2.
+    pub fn next_rx_window(&self) -> Option<(u32, u32, DataRate)> {
+        match &self.poll_state {
+            PollState::Rx1 {
+                tx_end,
+                channel,
+                expect,
+            } => {
+                let (rx1_delay, _) = self.receive_delays(*expect);
+                let (frequency, data_rate) = self.rx1_window(channel, *expect);
+                Some((tx_end.wrapping_add(rx1_delay), frequency, data_rate))
+            }
+            PollState::Rx2 { tx_end, expect } => {
+                let (_, rx2_delay) = self.receive_delays(*expect);
+                let (frequency, data_rate) = self.get_mac_layer().rx2_window();
+                Some((tx_end.wrapping_add(rx2_delay), frequency, data_rate))
+            }
+            _ => None,
+        }
+    }
+
+    /// Set the end of the transmission the receive windows are timed from
+    ///
+    /// `poll` takes the time it transmitted at as the end of the transmission.
+    /// Call this when the radio reports the actual end later.
+    pub fn set_tx_end(&mut self, end_ms: u32) {
+        if let PollState::Rx1 { tx_end, .. } | PollState::Rx2 { tx_end, .. } = &mut self.poll_state
+        {
+            *tx_end = end_ms;
+        }
+    }
+
+    /// Frequency and data rate of RX1 after a transmission on `channel`
+    fn rx1_window(&self, channel: &Channel, expect: Expect) -> (u32, DataRate) {
+        let mac = self.get_mac_layer();
+        match expect {
+            // Join accepts always use RX1DROffset 0
+            Expect::JoinAccept => mac.get_region().rx1_window(channel, 0),
+            _ => mac.rx1_window(channel),
+        }
+    }
+
+    /// Delays of RX1 and RX2 after the end of a transmission
+    fn receive_delays(&self, expect: Expect) -> (u32, u32) {
+        let mac = self.get_mac_layer();
+        match expect {
+            Expect::JoinAccept => mac.join_accept_delays(),
+            _ => mac.receive_delays(),
+        }
+    }
+
+    /// Open a receive window of the exchange and handle the received frame
+    ///
+    /// Frames that cannot be verified are ignored. The exchange ends once
+    /// the expected frame is received.
+    fn poll_window(
+        &mut self,
+        frequency: u32,
+        data_rate: DataRate,
+        expect: Expect,
+    ) -> Result<Option<DeviceEvent>, DeviceError<R::Error>> {
+        let mac = self.get_mac_layer_mut();
+        let len = mac.receive_window_frame(frequency, data_rate)?;
+        if len == 0 {
+            return Ok(None);
+        }
+
+        if expect == Expect::JoinAccept {
+            return match mac.process_received_join_accept(len) {
+                Ok(()) => {
+                    self.poll_state = PollState::Idle;
+                    self.join_request = None;
+                    Ok(Some(DeviceEvent::JoinAccepted))
+                }
+                Err(MacError::Radio(e)) => Err(DeviceError::Mac(MacError::Radio(e))),
+                Err(_) => Ok(None),
+            };
+        }
+
+        let frame = match mac.handle_received_downlink(len) {
+            Ok(frame) => frame,
+            Err(MacError::Radio(e)) => return Err(DeviceError::Mac(MacError::Radio(e))),
+            Err(_) => return Ok(None),
         };
+        if let Some(commands) = mac.extract_mac_commands(frame.mac_commands()) {
+            self.applied_commands = commands;
+        }
+        self.poll_state = PollState::Idle;
 
-        // Initialize new class based on requested mode
-        match mode {
-            OperatingMode::ClassA => {
-                let mac = MacLayer::new(radio, region, session);
-                self.class_a = ClassA::new(mac);
-                self.class_b = None;
-                self.class_c = None;
+        if expect == Expect::Ack {
+            return Ok(Some(if frame.fhdr.f_ctrl.ack {
+                DeviceEvent::AckReceived
+            } else {
+                DeviceEvent::AckTimeout
+            }));
+        }
+        Ok(self.next_queued_event())
+    }
+
+    /// Report the applied MAC commands, then the application data
+    fn next_queued_event(&mut self) -> Option<DeviceEvent> {
+        if let Some(state) = match &mut self.class {
+            Some(ClassState::B(class_b)) => class_b.take_beacon_state_change(),
+            _ => None,
+        } {
+            return Some(DeviceEvent::BeaconStateChanged(state));
+        }
+        if !self.applied_commands.is_empty() {
+            return Some(DeviceEvent::MacCommandApplied(
+                self.applied_commands.remove(0),
+            ));
+        }
+        while let Some(downlink) = self.pop_downlink() {
+            if downlink.multicast {
+                self.expire_multicast_groups();
             }
-            OperatingMode::ClassB => {
-                self.class_a = ClassA::new(MacLayer::new(
-                    radio.clone(),
-                    region.clone(),
-                    session.clone(),
-                ));
-                let mac = MacLayer::new(radio, region.clone(), session);
-                self.class_b = Some(ClassB::new(mac));
-                self.class_c = None;
+            let event = match downlink.port {
+                CLOCK_SYNC_PORT if self.clock_sync.is_some() => self.handle_clock_sync(&downlink),
+                MULTICAST_SETUP_PORT if self.multicast_setup.is_some() && !downlink.multicast => {
+                    self.handle_multicast_setup(&downlink)
+                }
+                _ => Some(DeviceEvent::DownlinkReceived(downlink)),
+            };
+            if event.is_some() {
+                return event;
             }
-            OperatingMode::ClassC => {
-                self.class_a = ClassA::new(MacLayer::new(
-                    radio.clone(),
-                    region.clone(),
-                    session.clone(),
-                ));
-                let mac = MacLayer::new(radio, region.clone(), session);
-                self.class_c = Some(ClassC::new(
-                    mac,
-                    region.rx2_frequency(),
-                    region.rx2_data_rate(),
-                ));
-                self.class_b = None;
+        }
+        self.check_rejoin()
+    }
+
+    /// Handle a downlink of the remote multicast setup package
+    ///
+    /// Groups are added and removed right away, sessions are started and
+    /// ended by `poll`. Answers are sent as for clock synchronization.
+    fn handle_multicast_setup(&mut self, downlink: &Downlink) -> Option<DeviceEvent> {
+        let (Some(class), Some(setup)) = (self.class.as_mut(), self.multicast_setup.as_mut())
+        else {
+            return None;
+        };
+        let mac = class.as_class_mut().get_mac_layer_mut();
+        let now = mac.get_time();
+        let gps_time_s = mac
+            .get_network_time()
+            .gps_time_ms_at(now)
+            .map(|gps_time_ms| (gps_time_ms / 1_000) as u32);
+        let outcome = match setup.handle_downlink(&downlink.payload, mac.get_region(), gps_time_s) {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                debug!("multicast setup downlink dropped: {}", e);
+                return None;
+            }
+        };
+
+        for change in &outcome.changes {
+            match change {
+                McGroupChange::Setup(group) => {
+                    if mac.add_multicast_group(group.clone()).is_err() {
+                        debug!("multicast group table full");
+                    }
+                }
+                McGroupChange::Delete(addr) => {
+                    mac.remove_multicast_group(*addr);
+                }
+            }
+        }
+        // The sessions of deleted groups end
+        for change in &outcome.changes {
+            if let McGroupChange::Delete(addr) = change {
+                if self.mc_next_session.as_ref().map(|next| next.session.addr) == Some(*addr) {
+                    self.mc_next_session = None;
+                }
+                match &mut self.mc_session {
+                    Some(current) if current.session.addr == *addr => {
+                        if current.restore.is_some() {
+                            current.end_ms = now;
+                        } else {
+                            self.mc_session = self.mc_next_session.take();
+                        }
+                    }
+                    _ => {}
+                }
             }
         }
 
-        self.mode = mode;
-        Ok(())
+        if let Some(session) = outcome.session {
+            let start_ms = now.wrapping_add(session.time_to_start_s.saturating_mul(1_000));
+            let scheduled = ScheduledSession {
+                end_ms: start_ms.wrapping_add(session.duration_s.saturating_mul(1_000)),
+                start_ms,
+                session,
+                restore: None,
+            };
+            // A running session ends first
+            match &mut self.mc_session {
+                Some(current) if current.restore.is_some() => {
+                    current.end_ms = now;
+                    self.mc_next_session = Some(scheduled);
+                }
+                _ => self.mc_session = Some(scheduled),
+            }
+        }
+
+        if !outcome.answer.is_empty() {
+            let _ = self.start_uplink(MULTICAST_SETUP_PORT, &outcome.answer, false);
+        }
+        None
     }
 
-    /// Process device operations
-    pub fn process(&mut self) -> Result<(), DeviceError<R::Error>> {
-        match self.mode {
-            OperatingMode::ClassA => self.class_a.process()?,
-            OperatingMode::ClassB => {
-                if let Some(class_b) = &mut self.class_b {
-                    class_b.process()?;
-                }
+    /// Remove the groups set up by the network whose last frame was received
+    fn expire_multicast_groups(&mut self) {
+        let (Some(class), Some(setup)) = (self.class.as_mut(), self.multicast_setup.as_ref())
+        else {
+            return;
+        };
+        let mac = class.as_class_mut().get_mac_layer_mut();
+        for group_id in 0..MAX_MC_GROUP_IDS as u8 {
+            let Some(params) = setup.group(group_id) else {
+                continue;
+            };
+            let expired = mac
+                .multicast_groups()
+                .iter()
+                .any(|group| group.addr == params.addr && group.fcnt_down > params.max_fcnt);
+            if expired {
+                mac.remove_multicast_group(params.addr);
             }
-            OperatingMode::ClassC => {
-                if let Some(class_c) = &mut self.class_c {
-                    class_c.process()?;
+        }
+    }
+
+    /// Start or end the multicast session when due
+    fn update_multicast_session(
+        &mut self,
+        now_ms: u32,
+    ) -> Result<Option<DeviceEvent>, DeviceError<R::Error>> {
+        let Some(scheduled) = &mut self.mc_session else {
+            return Ok(None);
+        };
+        let group_id = scheduled.session.group_id;
+
+        if let Some(restore) = scheduled
+            .restore
+            .take_if(|_| has_elapsed(now_ms, scheduled.end_ms))
+        {
+            self.mc_session = self.mc_next_session.take();
+            self.restore_after_session(restore)?;
+            return Ok(Some(DeviceEvent::MulticastSessionEnded { group_id }));
+        }
+        if scheduled.restore.is_some() || !has_elapsed(now_ms, scheduled.start_ms) {
+            return Ok(None);
+        }
+        // Sessions that already ended are dropped
+        if has_elapsed(now_ms, scheduled.end_ms) {
+            self.mc_session = self.mc_next_session.take();
+            return Ok(None);
+        }
+
+        let session = scheduled.session.clone();
+        match self.start_multicast_session(&session) {
+            Ok(restore) => {
+                if let Some(scheduled) = &mut self.mc_session {
+                    scheduled.restore = Some(restore);
                 }
+                Ok(Some(DeviceEvent::MulticastSessionStarted { group_id }))
+            }
+            Err(e) => {
+                self.mc_session = self.mc_next_session.take();
+                Err(e)
+            }
+        }
+    }
+
+    /// Switch to the class of a multicast session
+    ///
+    /// Class C sessions receive continuously on the frequency and data rate
+    /// of the session, used by RX2 as well while it lasts. Class B sessions
+    /// compute the ping slots from the group address with the periodicity
+    /// of the session.
+    fn start_multicast_session(
+        &mut self,
+        session: &McSession,
+    ) -> Result<SessionRestore, DeviceError<R::Error>> {
+        let mac = self.get_mac_layer();
+        let restore = SessionRestore {
+            mode: self.operating_mode(),
+            rx2_frequency: mac.get_session_state().rx2_frequency,
+            rx2_data_rate: mac.get_session_state().rx2_data_rate,
+            ping_slot_config: mac.get_ping_slot_config().clone(),
+        };
+        self.set_operating_mode(session.mode)?;
+
+        let result = match &mut self.class {
+            Some(ClassState::C(class_c)) => {
+                class_c.configure_rx2(session.frequency, session.data_rate)
+            }
+            Some(ClassState::B(class_b)) => {
+                let config = class_b.get_mac_layer_mut().get_ping_slot_config_mut();
+                config.set_periodicity(session.periodicity);
+                config.set_channel(session.frequency, session.data_rate);
+                class_b.set_ping_slot_addr(Some(session.addr));
+                Ok(())
+            }
+            _ => Ok(()),
+        };
+        match result {
+            Ok(()) => Ok(restore),
+            Err(e) => {
+                self.restore_after_session(restore)?;
+                Err(e.into())
             }
         }
+    }
+
+    /// Return to the class and settings of the device before a multicast session
+    fn restore_after_session(
+        &mut self,
+        restore: SessionRestore,
+    ) -> Result<(), DeviceError<R::Error>> {
+        let mac = self.get_mac_layer_mut();
+        mac.restore_rx2_params(restore.rx2_frequency, restore.rx2_data_rate);
+        *mac.get_ping_slot_config_mut() = restore.ping_slot_config;
+        if let Some(ClassState::B(class_b)) = &mut self.class {
+            class_b.set_ping_slot_addr(None);
+        }
+
+        self.set_operating_mode(restore.mode)?;
+        if let Some(ClassState::C(class_c)) = &mut self.class {
+            class_c.restart_rx2()?;
+        }
+        Ok(())
+    }
+
+    /// Handle a downlink of the clock synchronization package
+    ///
+    /// Answers are sent in an uplink started right away, which is dropped if
+    /// an exchange is in progress.
+    fn handle_clock_sync(&mut self, downlink: &Downlink) -> Option<DeviceEvent> {
+        let (Some(class), Some(clock_sync)) = (self.class.as_mut(), self.clock_sync.as_mut())
+        else {
+            return None;
+        };
+        let mac = class.as_class_mut().get_mac_layer_mut();
+        let now = mac.get_time();
+        let outcome =
+            match clock_sync.handle_downlink(&downlink.payload, mac.get_network_time_mut(), now) {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    debug!("clock sync downlink dropped: {}", e);
+                    return None;
+                }
+            };
+
+        if !outcome.answer.is_empty() {
+            let _ = self.start_uplink(CLOCK_SYNC_PORT, &outcome.answer, false);
+        } else if outcome.resync_transmissions > 0 {
+            let _ = self.request_time_sync();
+        }
+        outcome
+            .correction
+            .map(|correction_s| DeviceEvent::TimeSynced { correction_s })
+    }
+
+    /// Check if the network has more downlinks queued for the device
+    pub fn downlink_pending(&self) -> bool {
+        self.get_mac_layer().downlink_pending()
+    }
+
+    /// Time in ms until the duty cycle allows the next uplink
+    pub fn time_until_next_tx(&self) -> u32 {
+        self.get_mac_layer().time_until_next_tx()
+    }
+
+    /// Set the provider of the battery level reported in DevStatusAns
+    ///
+    /// The level is also read after every uplink to update the power state.
+    pub fn set_status_provider(&mut self, provider: &'static mut dyn StatusProvider) {
+        self.get_mac_layer_mut().set_status_provider(provider);
+    }
+
+    /// Get the TX, RX and sleep time recorded so far
+    pub fn power_metrics(&self) -> &PowerMetrics {
+        self.get_mac_layer().power_manager().get_metrics()
+    }
+
+    /// Get the power state set by the battery level
+    pub fn power_state(&self) -> PowerState {
+        self.get_mac_layer().power_manager().get_state()
+    }
+
+    /// Get the link statistics since the last reset
+    pub fn stats(&self) -> LinkStats {
+        self.get_mac_layer().link_stats()
+    }
+
+    /// Reset the link statistics
+    pub fn reset_stats(&mut self) {
+        self.get_mac_layer_mut().reset_link_stats();
+    }
+
+    /// Put the radio to sleep until the next transmission or receive window
+    pub fn sleep(&mut self) -> Result<(), DeviceError<R::Error>> {
+        Ok(self.get_mac_layer_mut().sleep()?)
+    }
+
+    /// Set the time source replacing the radio clock
+    ///
+    /// See `MacLayer::set_clock`.
+    pub fn set_clock(&mut self, clock: &'static dyn Clock) {
+        self.get_mac_layer_mut().set_clock(clock);
+    }
+
+    /// Set the storage that keeps the last DevNonce across reboots
+    pub fn set_dev_nonce_store(&mut self, store: &'static dyn DevNonceStore) {
+        self.get_mac_layer_mut().set_dev_nonce_store(store);
+    }
+
+    /// Draw DevNonces from the radio RNG instead of counting them up
+    ///
+    /// See `MacLayer::set_random_dev_nonces`.
+    pub fn set_random_dev_nonces(&mut self, random: bool) {
+        self.get_mac_layer_mut().set_random_dev_nonces(random);
+    }
+
+    /// Set the storage that keeps the frame counters of an ABP session across reboots
+    ///
+    /// See `MacLayer::set_frame_counter_store`.
+    pub fn set_frame_counter_store(
+        &mut self,
+        store: &'static dyn FrameCounterStore,
+        save_interval: u32,
+    ) {
+        self.get_mac_layer_mut()
+            .set_frame_counter_store(store, save_interval);
+    }
+
+    /// Set the uplink and downlink frame counters persisted by the application
+    pub fn set_frame_counters(&mut self, fcnt_up: u32, fcnt_down: u32) {
+        self.get_mac_layer_mut()
+            .set_frame_counters(fcnt_up, fcnt_down);
+    }
+
+    /// Add a multicast group, or replace the group with the same address
+    ///
+    /// Downlinks of the group are received in Class B ping slots and Class C
+    /// continuous reception, and returned with `multicast` set. Fails once
+    /// `MAX_MULTICAST_GROUPS` groups are set.
+    pub fn add_multicast_group(
+        &mut self,
+        group: MulticastGroup,
+    ) -> Result<(), DeviceError<R::Error>> {
+        Ok(self.get_mac_layer_mut().add_multicast_group(group)?)
+    }
+
+    /// Remove the multicast group with address `addr` and return it
+    pub fn remove_multicast_group(&mut self, addr: DevAddr) -> Option<MulticastGroup> {
+        self.get_mac_layer_mut().remove_multicast_group(addr)
+    }
+
+    /// Get the multicast groups, with their downlink frame counters
+    pub fn multicast_groups(&self) -> &[MulticastGroup] {
+        self.get_mac_layer().multicast_groups()
+    }
+
+    /// Get the last answer of the network to a link check
+    pub fn last_link_check(&self) -> Option<LinkCheckResult> {
+        self.get_mac_layer().last_link_check()
+    }
+
+    /// Ask the network for the current time in the next uplink
+    pub fn request_device_time(&mut self) -> Result<(), DeviceError<R::Error>> {
+        self.get_mac_layer_mut().request_device_time()?;
         Ok(())
     }
 
+    /// Get the network time, synchronized by DeviceTimeAns and beacons
+    pub fn network_time(&self) -> &NetworkTime {
+        self.get_mac_layer().get_network_time()
+    }
+
+    /// Start an AppTimeReq of the clock synchronization package (TS003)
+    ///
+    /// Enables the package and sends the request as an uplink on port 202
+    /// with the next `poll`. The correction of the answer is applied to the
+    /// network time and reported as `TimeSynced`. Fails as `start_uplink`.
+    pub fn request_time_sync(&mut self) -> Result<(), DeviceError<R::Error>> {
+        let mac = self.get_mac_layer();
+        let (network_time, now) = (mac.get_network_time().clone(), mac.get_time());
+        let request = self
+            .clock_sync
+            .get_or_insert_with(ClockSync::new)
+            .app_time_req(&network_time, now, true);
+        self.start_uplink(CLOCK_SYNC_PORT, &request, false)
+    }
+
+    /// Enable or disable the clock synchronization package
+    ///
+    /// While enabled, downlinks on port 202 are handled by the package
+    /// instead of being reported by `poll`, and its requests such as
+    /// PackageVersionReq are answered.
+    pub fn set_clock_sync(&mut self, enabled: bool) {
+        self.clock_sync = enabled.then(|| self.clock_sync.take().unwrap_or_default());
+    }
+
+    /// Get the clock synchronization package, if enabled
+    pub fn clock_sync(&self) -> Option<&ClockSync> {
+        self.clock_sync.as_ref()
+    }
+
+    /// Enable the remote multicast setup package (TS005) with the GenAppKey
+    ///
+    /// While enabled, downlinks on port 200 are handled by the package
+    /// instead of being reported by `poll`. Groups set up by the network are
+    /// added to the multicast groups of the device, and multicast sessions
+    /// switch the device to Class C or B while they last, reported as
+    /// `MulticastSessionStarted` and `MulticastSessionEnded`. Session times
+    /// need the network time, see `request_time_sync`. `None` disables the
+    /// package, the groups and sessions set up are kept.
+    pub fn set_multicast_setup(&mut self, gen_app_key: Option<&AESKey>) {
+        let crypto = self.get_mac_layer().get_crypto_backend();
+        self.multicast_setup =
+            gen_app_key.map(|key| MulticastSetup::with_crypto_backend(key, crypto));
+    }
+
+    /// Get the remote multicast setup package, if enabled
+    pub fn multicast_setup(&self) -> Option<&MulticastSetup> {
+        self.multicast_setup.as_ref()
+    }
+
+    /// Get the multicast session scheduled or running, if any
+    pub fn multicast_session(&self) -> Option<&McSession> {
+        self.mc_session.as_ref().map(|scheduled| &scheduled.session)
+    }
+
+    /// Check that the network still receives the device
+    ///
+    /// Sends an empty uplink with a LinkCheckReq and processes the device
+    /// until the answer arrives. Returns `None` if no answer is received
+    /// within `timeout_ms`.
+    pub fn check_link(
+        &mut self,
+        timeout_ms: u32,
+    ) -> Result<Option<LinkCheckResult>, DeviceError<R::Error>> {
+        self.get_mac_layer_mut().request_link_check()?;
+        let sent_at = self.get_mac_layer().get_time();
+        self.send_data(0, &[], false)?;
+
+        let deadline = sent_at.wrapping_add(timeout_ms);
+        loop {
+            self.process()?;
+            if let Some(result) = self.last_link_check() {
+                if has_elapsed(result.received_at, sent_at) {
+                    return Ok(Some(result));
+                }
+            }
+            if has_elapsed(self.get_mac_layer().get_time(), deadline) {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Enable or disable polling for pending downlinks
+    pub fn set_auto_poll(&mut self, enabled: bool) {
+        self.auto_poll = enabled;
+    }
+
+    /// Set the time-on-air allowed per period on top of the duty cycle
+    ///
+    /// E.g. `set_airtime_budget(30_000, 86_400_000)` for a fair-use policy of
+    /// 30 s per day.
+    pub fn set_airtime_budget(&mut self, budget_ms: u32, period_ms: u32) {
+        self.get_mac_layer_mut()
+            .set_airtime_budget(Some(AirtimeBudget::new(budget_ms, period_ms)));
+    }
+
+    /// Time in ms until an uplink with `payload_len` bytes of data is allowed
+    ///
+    /// Takes the duty cycle, the airtime budget at the current data rate and
+    /// the receive windows or join retransmission still ahead into account.
+    /// Fails if the payload does not fit at the current data rate.
+    pub fn next_tx_opportunity(&self, payload_len: usize) -> Result<u32, DeviceError<R::Error>> {
+        let mac = self.get_mac_layer();
+        let now = mac.get_time();
+        let wait = mac.time_until_uplink(payload_len)?;
+
+        // The exchange in progress ends with its RX2
+        let busy_until = match &self.poll_state {
+            PollState::Rx1 { tx_end, expect, .. } | PollState::Rx2 { tx_end, expect } => {
+                Some(tx_end.wrapping_add(self.receive_delays(*expect).1))
+            }
+            PollState::Backoff { until, .. } => Some(*until),
+            _ => match &self.class {
+                Some(ClassState::A(class_a)) => class_a.rx2_opens_at(),
+                _ => None,
+            },
+        };
+        Ok(match busy_until {
+            Some(until) if !has_elapsed(now, until) => wait.max(until.wrapping_sub(now)),
+            _ => wait,
+        })
+    }
+
     /// Send data
+    ///
+    /// Returns the outcome of a confirmed uplink, or `None` for unconfirmed data.
+    /// Fails with `NotJoined` before the device has a session, and with
+    /// `TooEarly` until `next_tx_opportunity` allows the uplink.
     pub fn send_data(
         &mut self,
         port: u8,
         data: &[u8],
         confirmed: bool,
-    ) -> Result<(), DeviceError<R::Error>> {
-        match self.mode {
-            OperatingMode::ClassA => self.class_a.send_data(port, data, confirmed)?,
-            OperatingMode::ClassB => {
-                if let Some(class_b) = &mut self.class_b {
-                    class_b.send_data(port, data, confirmed)?;
-                }
-            }
-            OperatingMode::ClassC => {
-                if let Some(class_c) = &mut self.class_c {
-                    class_c.send_data(port, data, confirmed)?;
-                }
-            }
+    ) -> Result<Option<ConfirmedResult>, DeviceError<R::Error>> {
+        let retry_after_ms = self.next_tx_opportunity(data.len())?;
+        if retry_after_ms > 0 {
+            return Err(DeviceError::TooEarly { retry_after_ms });
         }
-        Ok(())
+        Ok(self.class_mut().send_data(port, data, confirmed)?)
     }
 
     /// Join network using OTAA
@@ -223,57 +1399,227 @@ impl<R: Radio + Clone, REG: Region> LoRaWANDevice<R, REG> {
         app_eui: [u8; 8],
         app_key: AESKey,
     ) -> Result<(), DeviceError<R::Error>> {
-        match self.mode {
-            OperatingMode::ClassA => self.class_a.send_join_request(dev_eui, app_eui, app_key)?,
-            OperatingMode::ClassB => {
-                if let Some(class_b) = &mut self.class_b {
-                    class_b.send_join_request(dev_eui, app_eui, app_key)?;
-                }
-            }
-            OperatingMode::ClassC => {
-                if let Some(class_c) = &mut self.class_c {
-                    class_c.send_join_request(dev_eui, app_eui, app_key)?;
-                }
-            }
-        }
+        self.class_mut()
+            .send_join_request(dev_eui, app_eui, app_key)?;
         Ok(())
     }
 
-    /// Receive data
+    /// Take the application data of the last downlink, if any
+    ///
+    /// Downlinks are received by `process` and by confirmed uplinks. Their
+    /// MIC is verified and the payload decrypted; MAC commands are applied
+    /// by the device and never returned here.
+    pub fn take_downlink(&mut self) -> Option<Downlink> {
+        self.get_mac_layer_mut().take_downlink()
+    }
+
+    /// Get the Class C device, e.g. to configure its downlink queue
+    pub fn get_class_c_mut(&mut self) -> Option<&mut ClassC<R, REG>> {
+        match &mut self.class {
+            Some(ClassState::C(class_c)) => Some(class_c),
+            _ => None,
+        }
+    }
+
+    /// Take the oldest downlink received, if any
+    ///
+    /// Class C devices queue the downlinks received by continuous reception,
+    /// see `ClassC::set_downlink_queue_depth`. The other classes return the
+    /// last downlink as `take_downlink`.
+    pub fn pop_downlink(&mut self) -> Option<Downlink> {
+        match &mut self.class {
+            Some(ClassState::C(class_c)) => class_c.pop_downlink(),
+            _ => self.take_downlink(),
+        }
+    }
+
+    /// Receive raw frame data from the radio
     pub fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, DeviceError<R::Error>> {
-        match self.mode {
-            OperatingMode::ClassA => Ok(self.class_a.receive(buffer)?),
-            OperatingMode::ClassB => {
-                if let Some(class_b) = &mut self.class_b {
-                    Ok(class_b.receive(buffer)?)
-                } else {
-                    Ok(0)
-                }
-            }
-            OperatingMode::ClassC => {
-                if let Some(class_c) = &mut self.class_c {
-                    Ok(class_c.receive(buffer)?)
-                } else {
-                    Ok(0)
-                }
+        Ok(self.class_mut().receive(buffer)?)
+    }
+
+    /// Transmit a frame outside of LoRaWAN, e.g. to a point-to-point LoRa peer
+    ///
+    /// The active class is suspended for the transmission and resumed after
+    /// it, so Class C goes back to continuous RX2. The session, frame
+    /// counters, pending ACK and MAC commands are untouched.
+    ///
+    /// The airtime counts toward the duty cycle of the band of
+    /// `config.frequency`: this fails with `TooEarly` while the band is
+    /// silent, and uplinks wait for the off-time of the raw frame. Fails with
+    /// `InvalidState` while an exchange waits for its receive windows.
+    pub fn send_raw(&mut self, data: &[u8], config: TxConfig) -> Result<(), DeviceError<R::Error>> {
+        self.suspend_for_raw()?;
+        let result = self.get_mac_layer_mut().transmit_raw(data, config);
+        let resumed = self.resume_after_raw();
+        match result {
+            Err(MacError::DutyCycleLimited { retry_after_ms }) => {
+                Err(DeviceError::TooEarly { retry_after_ms })
             }
+            result => Ok(result.and(resumed)?),
+        }
+    }
+
+    /// Receive a frame outside of LoRaWAN for up to `timeout_ms`
+    ///
+    /// The frame is copied to `buffer` without being parsed. The active class
+    /// is suspended and resumed as for `send_raw`. Returns the length of the
+    /// frame, 0 if none was received.
+    pub fn receive_raw(
+        &mut self,
+        buffer: &mut [u8],
+        config: RxConfig,
+        timeout_ms: u32,
+    ) -> Result<usize, DeviceError<R::Error>> {
+        self.suspend_for_raw()?;
+        let config = RxConfig {
+            timeout_ms,
+            ..config
+        };
+        let result = self.get_mac_layer_mut().receive_raw(buffer, config);
+        let resumed = self.resume_after_raw();
+        Ok(result.and_then(|len| resumed.map(|()| len))?)
+    }
+
+    /// Get the radio for direct use, with the active class suspended
+    ///
+    /// The class resumes when the returned guard is dropped, Class C then
+    /// configures continuous RX2 again. Frames sent through the guard are
+    /// not counted by the duty cycle tracker, prefer `send_raw`. Fails with
+    /// `InvalidState` while an exchange waits for its receive windows.
+    pub fn radio_mut(&mut self) -> Result<RawRadio<'_, R, REG>, DeviceError<R::Error>> {
+        self.suspend_for_raw()?;
+        Ok(RawRadio { device: self })
+    }
+
+    /// Suspend the active class for a raw radio operation
+    fn suspend_for_raw(&mut self) -> Result<(), DeviceError<R::Error>> {
+        // A raw operation would shift the receive windows of the exchange
+        if matches!(
+            self.poll_state,
+            PollState::Rx1 { .. } | PollState::Rx2 { .. }
+        ) {
+            return Err(DeviceError::InvalidState);
+        }
+        if let Some(ClassState::C(class_c)) = &mut self.class {
+            class_c.suspend_for_raw();
+        }
+        Ok(())
+    }
+
+    /// Resume the active class after a raw radio operation
+    fn resume_after_raw(&mut self) -> Result<(), MacError<R::Error>> {
+        match &mut self.class {
+            Some(ClassState::C(class_c)) => class_c.resume_after_raw(),
+            _ => Ok(()),
         }
     }
 
     /// Get current session state
     pub fn get_session_state(&self) -> SessionState {
-        match self.mode {
-            OperatingMode::ClassA => self.class_a.get_session_state(),
-            OperatingMode::ClassB => self
-                .class_b
-                .as_ref()
-                .expect("Class B not initialized")
-                .get_session_state(),
-            OperatingMode::ClassC => self
-                .class_c
-                .as_ref()
-                .expect("Class C not initialized")
-                .get_session_state(),
-        }
+        self.class().get_session_state()
+    }
+
+    /// Serialize the session state of the active class
+    ///
+    /// Keep the result across deep sleep or reboots and pass it to
+    /// `restore_session` to continue the session without a new join.
+    pub fn save_session(&self) -> [u8; SESSION_STATE_SIZE] {
+        self.get_mac_layer().get_session_state().to_bytes()
+    }
+
+    /// Restore a session state written by `save_session`
+    pub fn restore_session(&mut self, data: &[u8]) -> Result<(), DeviceError<R::Error>> {
+        let session = SessionState::from_bytes(data).map_err(DeviceError::Session)?;
+
+        self.get_mac_layer_mut().set_session_state(session);
+        Ok(())
+    }
+
+    /// Enable or disable adaptive data rate
+    ///
+    /// Disabling it restores the data rate and output power set by the
+    /// application, if any.
+    pub fn set_adr(&mut self, enabled: bool) {
+        self.get_mac_layer_mut().set_adr(enabled);
+    }
+
+    /// Set the uplink data rate, e.g. to pin SF9 with ADR disabled
+    ///
+    /// With ADR enabled, the network overrides it until ADR is disabled.
+    pub fn set_data_rate(&mut self, data_rate: DataRate) -> Result<(), DeviceError<R::Error>> {
+        Ok(self.get_mac_layer_mut().set_data_rate(data_rate)?)
+    }
+
+    /// Get the data rate of the next uplink
+    pub fn data_rate(&self) -> Option<DataRate> {
+        self.get_mac_layer().data_rate()
+    }
+
+    /// Set the uplink output power in dBm
+    ///
+    /// See `MacLayer::set_tx_power_dbm`.
+    pub fn set_tx_power_dbm(&mut self, power: i8) -> Result<(), DeviceError<R::Error>> {
+        Ok(self.get_mac_layer_mut().set_tx_power_dbm(power)?)
+    }
+
+    /// Get the output power in dBm of the next uplink
+    pub fn tx_power_dbm(&self) -> i8 {
+        self.get_mac_layer().tx_power_dbm()
+    }
+
+    /// Set the antenna gain in dBi, see `MacLayer::set_antenna_gain`
+    pub fn set_antenna_gain(&mut self, gain: i8) {
+        self.get_mac_layer_mut().set_antenna_gain(gain);
+    }
+
+    /// Set the network type, selecting the sync word of all packets
+    ///
+    /// Public by default, private networks use the sync word 0x12.
+    pub fn set_network_type(&mut self, network: NetworkType) {
+        self.get_mac_layer_mut().set_network_type(network);
+    }
+
+    /// Get the ADR state of the active device class
+    pub fn get_adr_state(&self) -> AdrState {
+        self.get_mac_layer().get_adr_state()
+    }
+
+    /// Get the MAC layer of the active device class
+    pub fn get_mac_layer(&self) -> &MacLayer<R, REG> {
+        self.class().get_mac_layer()
+    }
+
+    /// Get the mutable MAC layer of the active device class
+    pub fn get_mac_layer_mut(&mut self) -> &mut MacLayer<R, REG> {
+        self.class_mut().get_mac_layer_mut()
+    }
+}
+
+/// Radio of a device with the device class suspended
+///
+/// Returned by `LoRaWANDevice::radio_mut`. Dropping the guard resumes the
+/// class; errors configuring the radio again are dropped with it.
+pub struct RawRadio<'a, R: Radio, REG: Region> {
+    device: &'a mut LoRaWANDevice<R, REG>,
+}
+
+impl<R: Radio, REG: Region> Deref for RawRadio<'_, R, REG> {
+    type Target = R;
+
+    fn deref(&self) -> &R {
+        self.device.get_mac_layer().get_radio()
+    }
+}
+
+impl<R: Radio, REG: Region> DerefMut for RawRadio<'_, R, REG> {
+    fn deref_mut(&mut self) -> &mut R {
+        self.device.get_mac_layer_mut().get_radio_mut()
+    }
+}
+
+impl<R: Radio, REG: Region> Drop for RawRadio<'_, R, REG> {
+    fn drop(&mut self) {
+        let _ = self.device.resume_after_raw();
     }
 }
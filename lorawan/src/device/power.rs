@@ -5,11 +5,26 @@
 //! - Power consumption tracking
 //! - Power saving modes
 //! - Duty cycle management
+//!
+//! The MAC layer owns a `PowerManager` and records the time-on-air of every
+//! transmission, the receive windows and the time the radio sleeps. The
+//! `StatusProvider` drives the power state. All math is done in
+//! integers, so no FPU is needed.
 
 use core::time::Duration;
 
+/// Typical TX current in mA
+const TX_CURRENT_MA: u64 = 120;
+
+/// Typical RX current in mA
+const RX_CURRENT_MA: u64 = 12;
+
+/// Typical sleep current in µA
+const SLEEP_CURRENT_UA: u64 = 1;
+
 /// Power consumption states
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum PowerState {
     /// Normal operation
     Normal,
@@ -19,12 +34,31 @@ pub enum PowerState {
     Critical,
 }
 
+/// Source of the battery level reported in DevStatusAns
+///
+/// Plugged into the MAC layer as a `&'static mut` reference, e.g. from a
+/// `StaticCell`, so it can own the ADC or fuel gauge it reads. Closures and
+/// functions returning the level implement it as well.
+pub trait StatusProvider {
+    /// Read the battery level
+    ///
+    /// Returns 0 for external power, 1-254 for the battery level or 255 if
+    /// the level cannot be measured.
+    fn battery_level(&mut self) -> u8;
+}
+
+impl<F: FnMut() -> u8> StatusProvider for F {
+    fn battery_level(&mut self) -> u8 {
+        self()
+    }
+}
+
 /// Power consumption metrics
 #[derive(Debug, Clone)]
 pub struct PowerMetrics {
     /// Battery level (0-255, 0=external power)
     pub battery_level: u8,
-    /// Estimated power consumption in mA
+    /// Estimated average current consumption in mA, see `average_current_ua`
     pub current_consumption: u16,
     /// Time spent in TX mode
     pub tx_time: Duration,
@@ -34,6 +68,12 @@ pub struct PowerMetrics {
     pub sleep_time: Duration,
 }
 
+impl Default for PowerMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PowerMetrics {
     /// Create new power metrics
     pub fn new() -> Self {
@@ -54,22 +94,19 @@ impl PowerMetrics {
     /// Add TX time
     pub fn add_tx_time(&mut self, duration: Duration) {
         self.tx_time += duration;
-        // Typical TX current: 120mA
-        self.current_consumption += (duration.as_millis() as u16 * 120) / 1000;
+        self.update_consumption();
     }
 
     /// Add RX time
     pub fn add_rx_time(&mut self, duration: Duration) {
         self.rx_time += duration;
-        // Typical RX current: 12mA
-        self.current_consumption += (duration.as_millis() as u16 * 12) / 1000;
+        self.update_consumption();
     }
 
     /// Add sleep time
     pub fn add_sleep_time(&mut self, duration: Duration) {
         self.sleep_time += duration;
-        // Typical sleep current: 1µA
-        self.current_consumption += (duration.as_millis() as u16) / 1_000_000;
+        self.update_consumption();
     }
 
     /// Get total active time
@@ -77,14 +114,44 @@ impl PowerMetrics {
         self.tx_time + self.rx_time
     }
 
-    /// Get duty cycle percentage
-    pub fn get_duty_cycle(&self) -> f32 {
-        let total = self.get_active_time() + self.sleep_time;
-        if total.as_secs() == 0 {
-            return 0.0;
+    /// Get the share of time spent in TX or RX in permille (0-1000)
+    pub fn get_duty_cycle_permille(&self) -> u16 {
+        let active = as_millis(self.get_active_time()) as u128;
+        let total = active + as_millis(self.sleep_time) as u128;
+        if total == 0 {
+            return 0;
         }
-        (self.get_active_time().as_secs_f32() / total.as_secs_f32()) * 100.0
+        (active * 1000 / total) as u16
+    }
+
+    /// Get the estimated charge drawn in mA·ms
+    ///
+    /// Divide by 3_600_000 for mAh.
+    pub fn charge_mams(&self) -> u64 {
+        let tx = as_millis(self.tx_time).saturating_mul(TX_CURRENT_MA);
+        let rx = as_millis(self.rx_time).saturating_mul(RX_CURRENT_MA);
+        let sleep = as_millis(self.sleep_time).saturating_mul(SLEEP_CURRENT_UA) / 1000;
+        tx.saturating_add(rx).saturating_add(sleep)
     }
+
+    /// Get the estimated average current over the recorded time in µA
+    pub fn average_current_ua(&self) -> u32 {
+        let total = as_millis(self.get_active_time() + self.sleep_time);
+        if total == 0 {
+            return 0;
+        }
+        (self.charge_mams().saturating_mul(1000) / total).min(u32::MAX as u64) as u32
+    }
+
+    /// Update the average current in mA
+    fn update_consumption(&mut self) {
+        self.current_consumption = (self.average_current_ua() / 1000).min(u16::MAX as u32) as u16;
+    }
+}
+
+/// Duration in ms, saturating at `u64::MAX`
+fn as_millis(duration: Duration) -> u64 {
+    duration.as_millis().min(u64::MAX as u128) as u64
 }
 
 /// Power management configuration
@@ -94,8 +161,8 @@ pub struct PowerConfig {
     pub critical_threshold: u8,
     /// Low battery threshold (0-255)
     pub low_threshold: u8,
-    /// Maximum duty cycle (%)
-    pub max_duty_cycle: f32,
+    /// Maximum duty cycle in permille (0-1000)
+    pub max_duty_cycle_permille: u16,
     /// Power saving mode enabled
     pub power_saving_enabled: bool,
 }
@@ -105,13 +172,14 @@ impl Default for PowerConfig {
         Self {
             critical_threshold: 10,
             low_threshold: 30,
-            max_duty_cycle: 1.0,
+            max_duty_cycle_permille: 10,
             power_saving_enabled: false,
         }
     }
 }
 
 /// Power manager for LoRaWAN devices
+#[derive(Debug, Clone)]
 pub struct PowerManager {
     /// Power configuration
     config: PowerConfig,
@@ -121,6 +189,12 @@ pub struct PowerManager {
     state: PowerState,
 }
 
+impl Default for PowerManager {
+    fn default() -> Self {
+        Self::new(PowerConfig::default())
+    }
+}
+
 impl PowerManager {
     /// Create new power manager
     pub fn new(config: PowerConfig) -> Self {
@@ -132,12 +206,18 @@ impl PowerManager {
     }
 
     /// Update battery level and check thresholds
+    ///
+    /// 0 (external power) and 255 (not measured) never count as a low
+    /// battery.
     pub fn update_battery(&mut self, level: u8) -> PowerState {
         self.metrics.update_battery(level);
-        
-        self.state = if level <= self.config.critical_threshold {
+        let on_battery = level != 0 && level != 255;
+
+        self.state = if on_battery && level <= self.config.critical_threshold {
             PowerState::Critical
-        } else if level <= self.config.low_threshold || self.config.power_saving_enabled {
+        } else if (on_battery && level <= self.config.low_threshold)
+            || self.config.power_saving_enabled
+        {
             PowerState::PowerSaving
         } else {
             PowerState::Normal
@@ -163,7 +243,7 @@ impl PowerManager {
 
     /// Check if duty cycle limit is exceeded
     pub fn is_duty_cycle_exceeded(&self) -> bool {
-        self.metrics.get_duty_cycle() > self.config.max_duty_cycle
+        self.metrics.get_duty_cycle_permille() > self.config.max_duty_cycle_permille
     }
 
     /// Get current power metrics
@@ -187,9 +267,10 @@ impl PowerManager {
     /// Disable power saving mode
     pub fn disable_power_saving(&mut self) {
         self.config.power_saving_enabled = false;
-        if self.state == PowerState::PowerSaving && 
-           self.metrics.battery_level > self.config.low_threshold {
+        if self.state == PowerState::PowerSaving
+            && !(1..=self.config.low_threshold).contains(&self.metrics.battery_level)
+        {
             self.state = PowerState::Normal;
         }
     }
-} 
\ No newline at end of file
+}
@@ -0,0 +1,22 @@
+//! Logging macros
+//!
+//! With the `defmt` feature the macros forward to `defmt`, otherwise they
+//! only borrow their arguments so no unused variables are reported.
+
+macro_rules! trace {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(feature = "defmt")]
+        ::defmt::trace!($s $(, $x)*);
+        #[cfg(not(feature = "defmt"))]
+        let _ = ($(&$x,)*);
+    }};
+}
+
+macro_rules! debug {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(feature = "defmt")]
+        ::defmt::debug!($s $(, $x)*);
+        #[cfg(not(feature = "defmt"))]
+        let _ = ($(&$x,)*);
+    }};
+}
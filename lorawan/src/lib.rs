@@ -8,9 +8,10 @@
 //! - Complete LoRaWAN 1.0.3 implementation
 //! - Class A, B, and C device support
 //! - OTAA and ABP activation
-//! - Configurable regions (US915, EU868, etc.)
+//! - Configurable regions (US915, EU868, AS923, IN865, KR920, CN470, RU864)
 //! - Hardware abstraction layer for radio drivers
-//! - No unsafe code
+//! - No unsafe code outside the register access of the STM32WL radio
+//! - Optional `defmt` logging and formatting of the public types
 //!
 //! # Example
 //! ```ignore
@@ -45,12 +46,23 @@
 #![warn(missing_docs)]
 #![no_std]
 
+// Logging macros, declared first so every module can use them
+#[macro_use]
+mod fmt;
+
+/// Application layer packages
+pub mod applayer;
+
 /// Device class implementations (A, B, C)
 pub mod class;
 
 /// Device and network configuration
 pub mod config;
 
+/// Async device interface
+#[cfg(feature = "async")]
+pub mod async_device;
+
 /// Cryptographic functions
 pub mod crypto;
 
@@ -62,3 +74,12 @@ pub mod lorawan;
 
 /// Radio hardware abstraction layer
 pub mod radio;
+
+/// LoRaWAN repeater
+pub mod repeater;
+
+/// Packet sniffer for site surveys
+pub mod sniffer;
+
+/// Monotonic time source
+pub mod timing;
@@ -1,31 +1,67 @@
+use heapless::Vec;
+
 use crate::lorawan::mac::MacError;
 
 /// MAC command identifiers
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
 pub enum CommandIdentifier {
+    /// Link check request
     LinkCheckReq = 0x02,
+    /// Link check answer
     LinkCheckAns = 0x82,
+    /// Link ADR request
     LinkADRReq = 0x03,
+    /// Link ADR answer
     LinkADRAns = 0x83,
+    /// Duty cycle request
     DutyCycleReq = 0x04,
+    /// Duty cycle answer
     DutyCycleAns = 0x84,
+    /// RX parameter setup request
     RXParamSetupReq = 0x05,
+    /// RX parameter setup answer
     RXParamSetupAns = 0x85,
+    /// Device status request
     DevStatusReq = 0x06,
+    /// Device status answer
     DevStatusAns = 0x86,
+    /// New channel request
     NewChannelReq = 0x07,
+    /// New channel answer
     NewChannelAns = 0x87,
+    /// RX timing setup request
     RXTimingSetupReq = 0x08,
+    /// RX timing setup answer
     RXTimingSetupAns = 0x88,
+    /// TX parameter setup request
     TxParamSetupReq = 0x09,
+    /// TX parameter setup answer
     TxParamSetupAns = 0x89,
+    /// Downlink channel request
     DlChannelReq = 0x0A,
+    /// Downlink channel answer
     DlChannelAns = 0x8A,
+    /// Device time request
+    DeviceTimeReq = 0x0D,
+    /// Device time answer
+    DeviceTimeAns = 0x8D,
+    /// Ping slot info request
+    PingSlotInfoReq = 0x10,
+    /// Ping slot info answer
+    PingSlotInfoAns = 0x90,
+    /// Ping slot channel request
+    PingSlotChannelReq = 0x11,
+    /// Ping slot channel answer
+    PingSlotChannelAns = 0x91,
+    /// Beacon frequency request
+    BeaconFreqReq = 0x13,
+    /// Beacon frequency answer
+    BeaconFreqAns = 0x93,
 }
 
 /// MAC command
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MacCommand {
     /// Link check request
     LinkCheckReq,
@@ -142,14 +178,53 @@ pub enum MacCommand {
         /// Uplink frequency exists
         uplink_freq_exists: bool,
     },
+    /// Device time request
+    DeviceTimeReq,
+    /// Device time answer
+    DeviceTimeAns {
+        /// Seconds since the GPS epoch at the end of the uplink
+        seconds: u32,
+        /// Fractional second in 1/256 s
+        fraction: u8,
+    },
+    /// Ping slot info request (Class B)
+    PingSlotInfoReq {
+        /// Ping slot periodicity (0-7)
+        periodicity: u8,
+    },
+    /// Ping slot info answer
+    PingSlotInfoAns,
+    /// Ping slot channel request (Class B)
+    PingSlotChannelReq {
+        /// Frequency, 0 for the region default
+        freq: u32,
+        /// Data rate
+        data_rate: u8,
+    },
+    /// Ping slot channel answer
+    PingSlotChannelAns {
+        /// Channel frequency OK
+        channel_freq_ok: bool,
+        /// Data rate OK
+        data_rate_ok: bool,
+    },
+    /// Beacon frequency request (Class B)
+    BeaconFreqReq {
+        /// Frequency, 0 for the region default
+        freq: u32,
+    },
+    /// Beacon frequency answer
+    BeaconFreqAns {
+        /// Beacon frequency OK
+        beacon_freq_ok: bool,
+    },
 }
 
 impl MacCommand {
-    /// Parse MAC command from bytes
+    /// Parse a MAC command sent by the network (downlink)
     pub fn from_bytes(cid: u8, payload: &[u8]) -> Option<Self> {
         match cid {
-            0x02 => Some(MacCommand::LinkCheckReq),
-            0x82 if payload.len() >= 2 => Some(MacCommand::LinkCheckAns {
+            0x02 if payload.len() >= 2 => Some(MacCommand::LinkCheckAns {
                 margin: payload[0],
                 gateway_count: payload[1],
             }),
@@ -157,66 +232,269 @@ impl MacCommand {
                 data_rate: payload[0] >> 4,
                 tx_power: payload[0] & 0x0F,
                 ch_mask: u16::from_le_bytes([payload[1], payload[2]]),
-                ch_mask_cntl: payload[3] >> 4,
+                ch_mask_cntl: (payload[3] >> 4) & 0x07,
                 nb_trans: payload[3] & 0x0F,
             }),
-            0x83 if payload.len() >= 1 => Some(MacCommand::LinkADRAns {
-                power_ack: (payload[0] & 0x04) != 0,
-                data_rate_ack: (payload[0] & 0x02) != 0,
-                channel_mask_ack: (payload[0] & 0x01) != 0,
-            }),
-            0x04 if payload.len() >= 1 => Some(MacCommand::DutyCycleReq {
-                max_duty_cycle: payload[0],
+            0x04 if !payload.is_empty() => Some(MacCommand::DutyCycleReq {
+                max_duty_cycle: payload[0] & 0x0F,
             }),
-            0x84 => Some(MacCommand::DutyCycleAns),
             0x05 if payload.len() >= 4 => Some(MacCommand::RXParamSetupReq {
-                rx1_dr_offset: payload[0] >> 4,
+                rx1_dr_offset: (payload[0] >> 4) & 0x07,
                 rx2_data_rate: payload[0] & 0x0F,
-                freq: u32::from_le_bytes([payload[1], payload[2], payload[3], 0]),
-            }),
-            0x85 if payload.len() >= 1 => Some(MacCommand::RXParamSetupAns {
-                rx1_dr_offset_ack: (payload[0] & 0x04) != 0,
-                rx2_data_rate_ack: (payload[0] & 0x02) != 0,
-                channel_ack: (payload[0] & 0x01) != 0,
+                freq: decode_frequency(&payload[1..4]),
             }),
             0x06 => Some(MacCommand::DevStatusReq),
-            0x86 if payload.len() >= 2 => Some(MacCommand::DevStatusAns {
-                battery: payload[0],
-                margin: payload[1] as i8,
-            }),
             0x07 if payload.len() >= 5 => Some(MacCommand::NewChannelReq {
                 ch_index: payload[0],
-                freq: u32::from_le_bytes([payload[1], payload[2], payload[3], 0]),
+                freq: decode_frequency(&payload[1..4]),
                 max_dr: payload[4] >> 4,
                 min_dr: payload[4] & 0x0F,
             }),
-            0x87 if payload.len() >= 1 => Some(MacCommand::NewChannelAns {
-                channel_freq_ok: (payload[0] & 0x02) != 0,
-                data_rate_ok: (payload[0] & 0x01) != 0,
-            }),
-            0x08 if payload.len() >= 1 => Some(MacCommand::RXTimingSetupReq {
+            0x08 if !payload.is_empty() => Some(MacCommand::RXTimingSetupReq {
                 delay: payload[0] & 0x0F,
             }),
-            0x88 => Some(MacCommand::RXTimingSetupAns),
-            0x09 if payload.len() >= 1 => Some(MacCommand::TxParamSetupReq {
+            0x09 if !payload.is_empty() => Some(MacCommand::TxParamSetupReq {
                 downlink_dwell_time: (payload[0] & 0x20) != 0,
                 uplink_dwell_time: (payload[0] & 0x10) != 0,
                 max_eirp: payload[0] & 0x0F,
             }),
-            0x89 => Some(MacCommand::TxParamSetupAns),
             0x0A if payload.len() >= 4 => Some(MacCommand::DlChannelReq {
                 ch_index: payload[0],
-                freq: u32::from_le_bytes([payload[1], payload[2], payload[3], 0]),
+                freq: decode_frequency(&payload[1..4]),
+            }),
+            0x0D if payload.len() >= 5 => Some(MacCommand::DeviceTimeAns {
+                seconds: u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]),
+                fraction: payload[4],
             }),
-            0x8A if payload.len() >= 1 => Some(MacCommand::DlChannelAns {
-                channel_freq_ok: (payload[0] & 0x02) != 0,
-                uplink_freq_exists: (payload[0] & 0x01) != 0,
+            0x10 => Some(MacCommand::PingSlotInfoAns),
+            0x11 if payload.len() >= 4 => Some(MacCommand::PingSlotChannelReq {
+                freq: decode_frequency(&payload[0..3]),
+                data_rate: payload[3] & 0x0F,
+            }),
+            0x13 if payload.len() >= 3 => Some(MacCommand::BeaconFreqReq {
+                freq: decode_frequency(&payload[0..3]),
             }),
             _ => None,
         }
     }
 
-    /// Get command length in bytes
+    /// Parse a MAC command sent by the device (uplink)
+    pub fn from_uplink_bytes(cid: u8, payload: &[u8]) -> Option<Self> {
+        match cid {
+            0x02 => Some(MacCommand::LinkCheckReq),
+            0x03 if !payload.is_empty() => Some(MacCommand::LinkADRAns {
+                power_ack: (payload[0] & 0x04) != 0,
+                data_rate_ack: (payload[0] & 0x02) != 0,
+                channel_mask_ack: (payload[0] & 0x01) != 0,
+            }),
+            0x04 => Some(MacCommand::DutyCycleAns),
+            0x05 if !payload.is_empty() => Some(MacCommand::RXParamSetupAns {
+                rx1_dr_offset_ack: (payload[0] & 0x04) != 0,
+                rx2_data_rate_ack: (payload[0] & 0x02) != 0,
+                channel_ack: (payload[0] & 0x01) != 0,
+            }),
+            0x06 if payload.len() >= 2 => Some(MacCommand::DevStatusAns {
+                battery: payload[0],
+                // Sign-extend the 6-bit margin
+                margin: ((payload[1] << 2) as i8) >> 2,
+            }),
+            0x07 if !payload.is_empty() => Some(MacCommand::NewChannelAns {
+                channel_freq_ok: (payload[0] & 0x01) != 0,
+                data_rate_ok: (payload[0] & 0x02) != 0,
+            }),
+            0x08 => Some(MacCommand::RXTimingSetupAns),
+            0x09 => Some(MacCommand::TxParamSetupAns),
+            0x0A if !payload.is_empty() => Some(MacCommand::DlChannelAns {
+                channel_freq_ok: (payload[0] & 0x01) != 0,
+                uplink_freq_exists: (payload[0] & 0x02) != 0,
+            }),
+            0x0D => Some(MacCommand::DeviceTimeReq),
+            0x10 if !payload.is_empty() => Some(MacCommand::PingSlotInfoReq {
+                periodicity: payload[0] & 0x07,
+            }),
+            0x11 if !payload.is_empty() => Some(MacCommand::PingSlotChannelAns {
+                channel_freq_ok: (payload[0] & 0x01) != 0,
+                data_rate_ok: (payload[0] & 0x02) != 0,
+            }),
+            0x13 if !payload.is_empty() => Some(MacCommand::BeaconFreqAns {
+                beacon_freq_ok: (payload[0] & 0x01) != 0,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Get the CID of the command as sent over the air
+    ///
+    /// Answers use the same CID as the request they answer.
+    pub fn cid(&self) -> u8 {
+        match self {
+            MacCommand::LinkCheckReq | MacCommand::LinkCheckAns { .. } => 0x02,
+            MacCommand::LinkADRReq { .. } | MacCommand::LinkADRAns { .. } => 0x03,
+            MacCommand::DutyCycleReq { .. } | MacCommand::DutyCycleAns => 0x04,
+            MacCommand::RXParamSetupReq { .. } | MacCommand::RXParamSetupAns { .. } => 0x05,
+            MacCommand::DevStatusReq | MacCommand::DevStatusAns { .. } => 0x06,
+            MacCommand::NewChannelReq { .. } | MacCommand::NewChannelAns { .. } => 0x07,
+            MacCommand::RXTimingSetupReq { .. } | MacCommand::RXTimingSetupAns => 0x08,
+            MacCommand::TxParamSetupReq { .. } | MacCommand::TxParamSetupAns => 0x09,
+            MacCommand::DlChannelReq { .. } | MacCommand::DlChannelAns { .. } => 0x0A,
+            MacCommand::DeviceTimeReq | MacCommand::DeviceTimeAns { .. } => 0x0D,
+            MacCommand::PingSlotInfoReq { .. } | MacCommand::PingSlotInfoAns => 0x10,
+            MacCommand::PingSlotChannelReq { .. } | MacCommand::PingSlotChannelAns { .. } => 0x11,
+            MacCommand::BeaconFreqReq { .. } | MacCommand::BeaconFreqAns { .. } => 0x13,
+        }
+    }
+
+    /// Serialize the command (CID followed by payload)
+    ///
+    /// Returns `false` and leaves the buffer untouched if the command does
+    /// not fit.
+    pub fn serialize(&self, buf: &mut Vec<u8, 15>) -> bool {
+        let mut command: Vec<u8, 6> = Vec::new();
+        command.push(self.cid()).ok();
+
+        let payload: &[u8] = match *self {
+            MacCommand::LinkCheckReq
+            | MacCommand::DutyCycleAns
+            | MacCommand::DevStatusReq
+            | MacCommand::RXTimingSetupAns
+            | MacCommand::TxParamSetupAns
+            | MacCommand::DeviceTimeReq
+            | MacCommand::PingSlotInfoAns => &[],
+            MacCommand::LinkCheckAns {
+                margin,
+                gateway_count,
+            } => &[margin, gateway_count],
+            MacCommand::LinkADRReq {
+                data_rate,
+                tx_power,
+                ch_mask,
+                ch_mask_cntl,
+                nb_trans,
+            } => {
+                let [mask_low, mask_high] = ch_mask.to_le_bytes();
+                &[
+                    (data_rate << 4) | (tx_power & 0x0F),
+                    mask_low,
+                    mask_high,
+                    ((ch_mask_cntl & 0x07) << 4) | (nb_trans & 0x0F),
+                ]
+            }
+            MacCommand::LinkADRAns {
+                power_ack,
+                data_rate_ack,
+                channel_mask_ack,
+            } => &[status_byte(&[power_ack, data_rate_ack, channel_mask_ack])],
+            MacCommand::DutyCycleReq { max_duty_cycle } => &[max_duty_cycle & 0x0F],
+            MacCommand::RXParamSetupReq {
+                rx1_dr_offset,
+                rx2_data_rate,
+                freq,
+            } => {
+                let [f0, f1, f2] = encode_frequency(freq);
+                &[
+                    ((rx1_dr_offset & 0x07) << 4) | (rx2_data_rate & 0x0F),
+                    f0,
+                    f1,
+                    f2,
+                ]
+            }
+            MacCommand::RXParamSetupAns {
+                rx1_dr_offset_ack,
+                rx2_data_rate_ack,
+                channel_ack,
+            } => &[status_byte(&[
+                rx1_dr_offset_ack,
+                rx2_data_rate_ack,
+                channel_ack,
+            ])],
+            // Margin is a 6-bit signed value
+            MacCommand::DevStatusAns { battery, margin } => &[battery, margin as u8 & 0x3F],
+            MacCommand::NewChannelReq {
+                ch_index,
+                freq,
+                max_dr,
+                min_dr,
+            } => {
+                let [f0, f1, f2] = encode_frequency(freq);
+                &[ch_index, f0, f1, f2, (max_dr << 4) | (min_dr & 0x0F)]
+            }
+            MacCommand::NewChannelAns {
+                channel_freq_ok,
+                data_rate_ok,
+            } => &[status_byte(&[data_rate_ok, channel_freq_ok])],
+            MacCommand::RXTimingSetupReq { delay } => &[delay & 0x0F],
+            MacCommand::TxParamSetupReq {
+                downlink_dwell_time,
+                uplink_dwell_time,
+                max_eirp,
+            } => &[
+                (status_byte(&[downlink_dwell_time, uplink_dwell_time]) << 4) | (max_eirp & 0x0F),
+            ],
+            MacCommand::DlChannelReq { ch_index, freq } => {
+                let [f0, f1, f2] = encode_frequency(freq);
+                &[ch_index, f0, f1, f2]
+            }
+            MacCommand::DlChannelAns {
+                channel_freq_ok,
+                uplink_freq_exists,
+            } => &[status_byte(&[uplink_freq_exists, channel_freq_ok])],
+            MacCommand::DeviceTimeAns { seconds, fraction } => {
+                let [s0, s1, s2, s3] = seconds.to_le_bytes();
+                &[s0, s1, s2, s3, fraction]
+            }
+            MacCommand::PingSlotInfoReq { periodicity } => &[periodicity & 0x07],
+            MacCommand::PingSlotChannelReq { freq, data_rate } => {
+                let [f0, f1, f2] = encode_frequency(freq);
+                &[f0, f1, f2, data_rate & 0x0F]
+            }
+            MacCommand::PingSlotChannelAns {
+                channel_freq_ok,
+                data_rate_ok,
+            } => &[status_byte(&[data_rate_ok, channel_freq_ok])],
+            MacCommand::BeaconFreqReq { freq } => &encode_frequency(freq),
+            MacCommand::BeaconFreqAns { beacon_freq_ok } => &[beacon_freq_ok as u8],
+        };
+        command.extend_from_slice(payload).ok();
+
+        buf.extend_from_slice(&command).is_ok()
+    }
+
+    /// Check if the command is sent by the device rather than the network
+    pub fn is_uplink(&self) -> bool {
+        matches!(
+            self,
+            MacCommand::LinkCheckReq
+                | MacCommand::LinkADRAns { .. }
+                | MacCommand::DutyCycleAns
+                | MacCommand::RXParamSetupAns { .. }
+                | MacCommand::DevStatusAns { .. }
+                | MacCommand::NewChannelAns { .. }
+                | MacCommand::RXTimingSetupAns
+                | MacCommand::TxParamSetupAns
+                | MacCommand::DlChannelAns { .. }
+                | MacCommand::DeviceTimeReq
+                | MacCommand::PingSlotInfoReq { .. }
+                | MacCommand::PingSlotChannelAns { .. }
+                | MacCommand::BeaconFreqAns { .. }
+        )
+    }
+
+    /// Check if the command is an answer that is repeated in every uplink
+    ///
+    /// These answers are sent until the device receives a downlink, so the
+    /// network knows the new settings are in use.
+    pub fn is_sticky(&self) -> bool {
+        matches!(
+            self,
+            MacCommand::RXParamSetupAns { .. }
+                | MacCommand::RXTimingSetupAns
+                | MacCommand::DlChannelAns { .. }
+                | MacCommand::PingSlotChannelAns { .. }
+        )
+    }
+
+    /// Get command payload length in bytes, without the CID
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
         match self {
             MacCommand::LinkCheckReq => 0,
@@ -237,6 +515,14 @@ impl MacCommand {
             MacCommand::TxParamSetupAns => 0,
             MacCommand::DlChannelReq { .. } => 4,
             MacCommand::DlChannelAns { .. } => 1,
+            MacCommand::DeviceTimeReq => 0,
+            MacCommand::DeviceTimeAns { .. } => 5,
+            MacCommand::PingSlotInfoReq { .. } => 1,
+            MacCommand::PingSlotInfoAns => 0,
+            MacCommand::PingSlotChannelReq { .. } => 4,
+            MacCommand::PingSlotChannelAns { .. } => 1,
+            MacCommand::BeaconFreqReq { .. } => 3,
+            MacCommand::BeaconFreqAns { .. } => 1,
         }
     }
 
@@ -326,6 +612,10 @@ impl MacCommand {
                 // Not implemented in most regions
                 Err(MacError::UnknownCommand)
             }
+            MacCommand::PingSlotChannelReq { .. } | MacCommand::BeaconFreqReq { .. } => {
+                // Class B settings are applied by the MAC layer
+                Err(MacError::UnknownCommand)
+            }
             MacCommand::LinkADRAns { .. }
             | MacCommand::DutyCycleAns
             | MacCommand::RXParamSetupAns { .. }
@@ -333,10 +623,32 @@ impl MacCommand {
             | MacCommand::NewChannelAns { .. }
             | MacCommand::RXTimingSetupAns
             | MacCommand::TxParamSetupAns
-            | MacCommand::DlChannelAns { .. } => {
+            | MacCommand::DlChannelAns { .. }
+            | MacCommand::DeviceTimeReq
+            | MacCommand::DeviceTimeAns { .. }
+            | MacCommand::PingSlotInfoReq { .. }
+            | MacCommand::PingSlotInfoAns
+            | MacCommand::PingSlotChannelAns { .. }
+            | MacCommand::BeaconFreqAns { .. } => {
                 // These are answers, not requests - they don't need processing
                 Ok(None)
             }
         }
     }
 }
+
+/// Decode a 24-bit little endian frequency in units of 100 Hz
+fn decode_frequency(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]) * 100
+}
+
+/// Encode a frequency as 24-bit little endian value in units of 100 Hz
+fn encode_frequency(freq: u32) -> [u8; 3] {
+    let [f0, f1, f2, _] = (freq / 100).to_le_bytes();
+    [f0, f1, f2]
+}
+
+/// Pack status flags into a byte, the first flag being the most significant bit
+fn status_byte(flags: &[bool]) -> u8 {
+    flags.iter().fold(0, |byte, &flag| (byte << 1) | flag as u8)
+}
@@ -0,0 +1,131 @@
+//! Duty cycle accounting
+//!
+//! Every uplink keeps its sub-band silent for an off time derived from the
+//! time-on-air and the band's duty cycle limit. The aggregated limit set by
+//! the network with `DutyCycleReq` applies across all bands. An optional
+//! airtime budget per period, e.g. the fair-use policy of a network, limits
+//! the total time-on-air on top of that.
+
+use crate::lorawan::region::Band;
+use crate::timing::has_elapsed;
+
+/// Maximum number of duty cycle bands of any region
+pub const MAX_BANDS: usize = 8;
+
+/// Per-band and aggregated transmit budgets
+#[derive(Debug, Clone, Default)]
+pub struct DutyCycle {
+    band_ready_at: [Option<u32>; MAX_BANDS],
+    aggregated_ready_at: Option<u32>,
+    max_duty_cycle: u8,
+}
+
+impl DutyCycle {
+    /// Create a tracker without any transmission history
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the aggregated duty cycle limit to 1/2^max_duty_cycle
+    ///
+    /// 0 removes the limit.
+    pub fn set_max_duty_cycle(&mut self, max_duty_cycle: u8) {
+        self.max_duty_cycle = max_duty_cycle.min(15);
+    }
+
+    /// Get the aggregated duty cycle limit exponent
+    pub fn max_duty_cycle(&self) -> u8 {
+        self.max_duty_cycle
+    }
+
+    /// Time in ms until a transmission in `band` is allowed
+    pub fn time_until_tx(&self, band: Option<usize>, now: u32) -> u32 {
+        let band_wait = band
+            .and_then(|band| self.band_ready_at.get(band).copied())
+            .map_or(0, |ready_at| remaining(ready_at, now));
+        band_wait.max(remaining(self.aggregated_ready_at, now))
+    }
+
+    /// Account for a transmission of `airtime_ms` that ended at `now`
+    pub fn record_tx(&mut self, band: Option<(usize, &Band)>, airtime_ms: u32, now: u32) {
+        if let Some((index, band)) = band {
+            if let Some(ready_at) = self.band_ready_at.get_mut(index) {
+                *ready_at = Some(now.wrapping_add(off_time(airtime_ms, band.duty_cycle as u32)));
+            }
+        }
+
+        if self.max_duty_cycle > 0 {
+            let off = off_time(airtime_ms, 1 << self.max_duty_cycle);
+            self.aggregated_ready_at = Some(now.wrapping_add(off));
+        }
+    }
+}
+
+/// Time-on-air allowed per period
+///
+/// A period starts with the first transmission after the previous period
+/// ended. A frame is always allowed as the first of a period, even if it is
+/// longer than the budget.
+#[derive(Debug, Clone)]
+pub struct AirtimeBudget {
+    budget_ms: u32,
+    period_ms: u32,
+    period_start: Option<u32>,
+    used_ms: u32,
+}
+
+impl AirtimeBudget {
+    /// Create a budget of `budget_ms` time-on-air every `period_ms`
+    pub fn new(budget_ms: u32, period_ms: u32) -> Self {
+        Self {
+            budget_ms,
+            period_ms,
+            period_start: None,
+            used_ms: 0,
+        }
+    }
+
+    /// Get the time-on-air used in the current period
+    pub fn used_ms(&self, now: u32) -> u32 {
+        match self.period_end() {
+            Some(end) if !has_elapsed(now, end) => self.used_ms,
+            _ => 0,
+        }
+    }
+
+    /// Time in ms until a transmission of `airtime_ms` fits in the budget
+    pub fn time_until_tx(&self, airtime_ms: u32, now: u32) -> u32 {
+        let used_ms = self.used_ms(now);
+        if used_ms == 0 || used_ms.saturating_add(airtime_ms) <= self.budget_ms {
+            return 0;
+        }
+        remaining(self.period_end(), now)
+    }
+
+    /// Account for a transmission of `airtime_ms` that ended at `now`
+    pub fn record_tx(&mut self, airtime_ms: u32, now: u32) {
+        if self.used_ms(now) == 0 {
+            self.period_start = Some(now);
+            self.used_ms = 0;
+        }
+        self.used_ms = self.used_ms.saturating_add(airtime_ms);
+    }
+
+    fn period_end(&self) -> Option<u32> {
+        self.period_start
+            .map(|start| start.wrapping_add(self.period_ms))
+    }
+}
+
+/// Silent time after a transmission under a 1/factor duty cycle
+fn off_time(airtime_ms: u32, factor: u32) -> u32 {
+    airtime_ms.saturating_mul(factor.saturating_sub(1))
+}
+
+/// Time left until `ready_at`, 0 if it has passed
+fn remaining(ready_at: Option<u32>, now: u32) -> u32 {
+    match ready_at {
+        Some(ready_at) if !has_elapsed(now, ready_at) => ready_at.wrapping_sub(now),
+        _ => 0,
+    }
+}
@@ -0,0 +1,657 @@
+//! LoRaWAN frame codec
+//!
+//! Parses and builds PHYPayloads without a radio or MAC layer, so gateways,
+//! bridges and host tests can use the same framing as the device. Parsing
+//! only checks the structure of a frame, MIC verification and decryption
+//! take the keys when they are needed. The MAC layer builds and parses all
+//! its frames with this module.
+
+use heapless::Vec;
+
+use crate::config::device::{AESKey, DevAddr};
+use crate::crypto::{self, CryptoBackend, Direction, MIC_SIZE};
+
+/// Maximum FRMPayload size of the frames built and received
+///
+/// 51 bytes with the `max-frame-64` feature, the limit of the lowest data
+/// rates.
+#[cfg(not(feature = "max-frame-64"))]
+pub const MAX_MAC_PAYLOAD: usize = 242;
+/// Maximum FRMPayload size of the frames built and received
+///
+/// 51 bytes with the `max-frame-64` feature, the limit of the lowest data
+/// rates, instead of 242.
+#[cfg(feature = "max-frame-64")]
+pub const MAX_MAC_PAYLOAD: usize = 51;
+
+/// Maximum frame size, the size of the frame buffers
+///
+/// 64 bytes with the `max-frame-64` feature, for deployments limited to
+/// low data rates. Longer frames cannot be sent or received.
+#[cfg(not(feature = "max-frame-64"))]
+pub const MAX_FRAME_SIZE: usize = 256;
+/// Maximum frame size, the size of the frame buffers
+///
+/// 64 bytes with the `max-frame-64` feature instead of 256, for deployments
+/// limited to low data rates. Longer frames cannot be sent or received.
+#[cfg(feature = "max-frame-64")]
+pub const MAX_FRAME_SIZE: usize = 64;
+
+/// Size of a frame header without FOpts (DevAddr + FCtrl + FCnt)
+pub const MIN_FHDR_SIZE: usize = 7;
+
+/// Maximum size of a frame header (DevAddr + FCtrl + FCnt + FOpts)
+pub const MAX_FHDR_SIZE: usize = 22;
+
+/// Size of a join request (MHDR + AppEUI + DevEUI + DevNonce + MIC)
+pub const JOIN_REQUEST_SIZE: usize = 23;
+
+/// Size of a join accept without CFList (MHDR + payload + MIC)
+pub const JOIN_ACCEPT_SIZE: usize = 17;
+
+/// Size of the optional CFList of a join accept
+pub const CF_LIST_SIZE: usize = 16;
+
+/// Maximum size of a join accept
+pub const JOIN_ACCEPT_MAX_SIZE: usize = JOIN_ACCEPT_SIZE + CF_LIST_SIZE;
+
+/// Smallest data frame (MHDR + FHDR + MIC)
+const MIN_DATA_FRAME_SIZE: usize = 1 + MIN_FHDR_SIZE + MIC_SIZE;
+
+/// Frame codec errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FrameError {
+    /// Frame too short or too long for its type
+    InvalidLength,
+    /// Unsupported message type or major version, or conflicting fields
+    InvalidFrame,
+    /// MIC does not match the frame
+    InvalidMic,
+}
+
+impl core::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FrameError::InvalidLength => f.write_str("invalid frame length"),
+            FrameError::InvalidFrame => f.write_str("invalid frame"),
+            FrameError::InvalidMic => f.write_str("invalid MIC"),
+        }
+    }
+}
+
+impl core::error::Error for FrameError {}
+
+/// Message type of the MHDR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MType {
+    /// Join request
+    JoinRequest = 0,
+    /// Join accept
+    JoinAccept = 1,
+    /// Unconfirmed data up
+    UnconfirmedDataUp = 2,
+    /// Unconfirmed data down
+    UnconfirmedDataDown = 3,
+    /// Confirmed data up
+    ConfirmedDataUp = 4,
+    /// Confirmed data down
+    ConfirmedDataDown = 5,
+    /// Proprietary frame
+    Proprietary = 7,
+}
+
+impl MType {
+    /// Get the message type of a MHDR, `None` for the RFU type
+    pub fn from_mhdr(mhdr: u8) -> Option<Self> {
+        match mhdr >> 5 {
+            0 => Some(Self::JoinRequest),
+            1 => Some(Self::JoinAccept),
+            2 => Some(Self::UnconfirmedDataUp),
+            3 => Some(Self::UnconfirmedDataDown),
+            4 => Some(Self::ConfirmedDataUp),
+            5 => Some(Self::ConfirmedDataDown),
+            7 => Some(Self::Proprietary),
+            _ => None,
+        }
+    }
+
+    /// Get the MHDR of a LoRaWAN R1 frame of this type
+    pub fn mhdr(self) -> u8 {
+        (self as u8) << 5
+    }
+
+    /// Get the message type of a data frame
+    fn data(direction: Direction, confirmed: bool) -> Self {
+        match (direction, confirmed) {
+            (Direction::Up, false) => Self::UnconfirmedDataUp,
+            (Direction::Up, true) => Self::ConfirmedDataUp,
+            (Direction::Down, false) => Self::UnconfirmedDataDown,
+            (Direction::Down, true) => Self::ConfirmedDataDown,
+        }
+    }
+}
+
+/// Frame control field
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FCtrl {
+    /// Adaptive data rate enabled
+    pub adr: bool,
+    /// ADR acknowledgment request
+    pub adr_ack_req: bool,
+    /// Frame pending bit
+    pub ack: bool,
+    /// Frame pending bit
+    pub fpending: bool,
+    /// Class B enabled, uplink only, shares the bit of FPending
+    pub class_b: bool,
+    /// FOpts field length
+    pub foptslen: u8,
+}
+
+impl Default for FCtrl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FCtrl {
+    /// Create a new frame control field with default values
+    pub fn new() -> Self {
+        Self {
+            adr: false,
+            adr_ack_req: false,
+            ack: false,
+            fpending: false,
+            class_b: false,
+            foptslen: 0,
+        }
+    }
+
+    /// Parse a downlink frame control field
+    pub fn from_byte(byte: u8) -> Self {
+        Self {
+            adr: byte & 0x80 != 0,
+            adr_ack_req: false,
+            ack: byte & 0x20 != 0,
+            fpending: byte & 0x10 != 0,
+            class_b: false,
+            foptslen: byte & 0x0F,
+        }
+    }
+
+    /// Parse an uplink frame control field
+    pub fn from_uplink_byte(byte: u8) -> Self {
+        Self {
+            adr: byte & 0x80 != 0,
+            adr_ack_req: byte & 0x40 != 0,
+            ack: byte & 0x20 != 0,
+            fpending: false,
+            class_b: byte & 0x10 != 0,
+            foptslen: byte & 0x0F,
+        }
+    }
+
+    /// Convert frame control field to byte representation
+    pub fn to_byte(&self) -> u8 {
+        let mut byte = 0;
+        if self.adr {
+            byte |= 0x80;
+        }
+        if self.adr_ack_req {
+            byte |= 0x40;
+        }
+        if self.ack {
+            byte |= 0x20;
+        }
+        if self.fpending || self.class_b {
+            byte |= 0x10;
+        }
+        byte |= self.foptslen & 0x0F;
+        byte
+    }
+}
+
+/// Frame header
+#[derive(Debug, Clone, PartialEq)]
+pub struct FHDR {
+    /// Device address
+    pub dev_addr: DevAddr,
+    /// Frame control field
+    pub f_ctrl: FCtrl,
+    /// Frame counter
+    pub f_cnt: u16,
+    /// Frame options
+    pub f_opts: Vec<u8, 15>,
+}
+
+impl FHDR {
+    /// Serialize frame header to bytes
+    ///
+    /// FOptsLen is taken from `f_opts`, whatever `f_ctrl.foptslen` says.
+    pub fn serialize(&self) -> Vec<u8, MAX_FHDR_SIZE> {
+        // Cannot fail, FOpts holds at most 15 bytes
+        let mut f_ctrl = self.f_ctrl;
+        f_ctrl.foptslen = self.f_opts.len() as u8;
+        let mut buffer = Vec::new();
+        let _ = buffer.extend_from_slice(self.dev_addr.as_bytes());
+        let _ = buffer.push(f_ctrl.to_byte());
+        let _ = buffer.extend_from_slice(&self.f_cnt.to_le_bytes());
+        let _ = buffer.extend_from_slice(&self.f_opts);
+        buffer
+    }
+
+    /// Parse a frame header from the start of a MACPayload
+    ///
+    /// Returns the header and its size, or `None` if the data is too short
+    /// for the FOpts length given in FCtrl.
+    pub fn parse(data: &[u8]) -> Option<(Self, usize)> {
+        if data.len() < MIN_FHDR_SIZE {
+            return None;
+        }
+
+        let f_ctrl = FCtrl::from_byte(data[4]);
+        let size = MIN_FHDR_SIZE + f_ctrl.foptslen as usize;
+        let mut f_opts = Vec::new();
+        f_opts
+            .extend_from_slice(data.get(MIN_FHDR_SIZE..size)?)
+            .ok()?;
+
+        let fhdr = Self {
+            dev_addr: DevAddr::new([data[0], data[1], data[2], data[3]]),
+            f_ctrl,
+            f_cnt: u16::from_le_bytes([data[5], data[6]]),
+            f_opts,
+        };
+        Some((fhdr, size))
+    }
+}
+
+/// Frame parsed from a PHYPayload
+#[derive(Debug, Clone)]
+pub enum PhyPayload<'a> {
+    /// Join request
+    JoinRequest(JoinRequest),
+    /// Join accept, still encrypted
+    JoinAccept(EncryptedJoinAccept<'a>),
+    /// Data frame sent by a device
+    DataUp(DataFrame<'a>),
+    /// Data frame sent by the network
+    DataDown(DataFrame<'a>),
+}
+
+impl<'a> PhyPayload<'a> {
+    /// Parse the structure of a frame
+    ///
+    /// Only LoRaWAN R1 frames are accepted, proprietary frames and the RFU
+    /// message type fail with `InvalidFrame`. The MIC is not verified.
+    pub fn parse(data: &'a [u8]) -> Result<Self, FrameError> {
+        let mhdr = *data.first().ok_or(FrameError::InvalidLength)?;
+        if mhdr & 0x03 != 0 {
+            return Err(FrameError::InvalidFrame);
+        }
+
+        match MType::from_mhdr(mhdr) {
+            Some(MType::JoinRequest) => JoinRequest::parse(data).map(Self::JoinRequest),
+            Some(MType::JoinAccept) => EncryptedJoinAccept::parse(data).map(Self::JoinAccept),
+            Some(MType::UnconfirmedDataUp) | Some(MType::ConfirmedDataUp) => {
+                DataFrame::parse(data, Direction::Up).map(Self::DataUp)
+            }
+            Some(MType::UnconfirmedDataDown) | Some(MType::ConfirmedDataDown) => {
+                DataFrame::parse(data, Direction::Down).map(Self::DataDown)
+            }
+            Some(MType::Proprietary) | None => Err(FrameError::InvalidFrame),
+        }
+    }
+}
+
+/// Join request
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinRequest {
+    /// AppEUI (JoinEUI) as sent, little endian
+    pub app_eui: [u8; 8],
+    /// DevEUI as sent, little endian
+    pub dev_eui: [u8; 8],
+    /// DevNonce
+    pub dev_nonce: u16,
+    /// Received MIC
+    pub mic: [u8; MIC_SIZE],
+}
+
+impl JoinRequest {
+    fn parse(data: &[u8]) -> Result<Self, FrameError> {
+        if data.len() != JOIN_REQUEST_SIZE {
+            return Err(FrameError::InvalidLength);
+        }
+
+        let mut app_eui = [0u8; 8];
+        app_eui.copy_from_slice(&data[1..9]);
+        let mut dev_eui = [0u8; 8];
+        dev_eui.copy_from_slice(&data[9..17]);
+        let mut mic = [0u8; MIC_SIZE];
+        mic.copy_from_slice(&data[19..]);
+        Ok(Self {
+            app_eui,
+            dev_eui,
+            dev_nonce: u16::from_le_bytes([data[17], data[18]]),
+            mic,
+        })
+    }
+
+    /// Check the MIC of the join request with the AppKey
+    pub fn verify_mic(&self, crypto: &dyn CryptoBackend, app_key: &AESKey) -> bool {
+        let frame = build_join_request(crypto, app_key, self.app_eui, self.dev_eui, self.dev_nonce);
+        let mic = crypto.compute_join_mic(app_key, &frame[..JOIN_REQUEST_SIZE - MIC_SIZE]);
+        crypto::mic_matches(&self.mic, &mic)
+    }
+}
+
+/// Build a join request
+pub fn build_join_request(
+    crypto: &dyn CryptoBackend,
+    app_key: &AESKey,
+    app_eui: [u8; 8],
+    dev_eui: [u8; 8],
+    dev_nonce: u16,
+) -> [u8; JOIN_REQUEST_SIZE] {
+    let mut frame = [0u8; JOIN_REQUEST_SIZE];
+    frame[0] = MType::JoinRequest.mhdr();
+    frame[1..9].copy_from_slice(&app_eui);
+    frame[9..17].copy_from_slice(&dev_eui);
+    frame[17..19].copy_from_slice(&dev_nonce.to_le_bytes());
+
+    // MIC over MHDR | AppEUI | DevEUI | DevNonce
+    let mic = crypto.compute_join_mic(app_key, &frame[..JOIN_REQUEST_SIZE - MIC_SIZE]);
+    frame[JOIN_REQUEST_SIZE - MIC_SIZE..].copy_from_slice(&mic);
+    frame
+}
+
+/// Join accept as received, everything after the MHDR is encrypted
+#[derive(Debug, Clone)]
+pub struct EncryptedJoinAccept<'a> {
+    mhdr: u8,
+    encrypted: &'a [u8],
+}
+
+impl<'a> EncryptedJoinAccept<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, FrameError> {
+        if data.len() != JOIN_ACCEPT_SIZE && data.len() != JOIN_ACCEPT_MAX_SIZE {
+            return Err(FrameError::InvalidLength);
+        }
+        Ok(Self {
+            mhdr: data[0],
+            encrypted: &data[1..],
+        })
+    }
+
+    /// Decrypt the join accept with the AppKey and verify its MIC
+    pub fn decrypt(
+        &self,
+        crypto: &dyn CryptoBackend,
+        app_key: &AESKey,
+    ) -> Result<JoinAccept, FrameError> {
+        // The MIC is encrypted together with the payload
+        let decrypted = crypto.decrypt_join_accept(app_key, self.encrypted);
+        let mic_offset = decrypted.len() - MIC_SIZE;
+
+        let mut msg: Vec<u8, JOIN_ACCEPT_MAX_SIZE> = Vec::new();
+        msg.push(self.mhdr).map_err(|_| FrameError::InvalidLength)?;
+        msg.extend_from_slice(&decrypted[..mic_offset])
+            .map_err(|_| FrameError::InvalidLength)?;
+
+        let mic = crypto.compute_join_mic(app_key, &msg);
+        if !crypto::mic_matches(&decrypted[mic_offset..], &mic) {
+            return Err(FrameError::InvalidMic);
+        }
+
+        Ok(JoinAccept {
+            app_nonce: [decrypted[0], decrypted[1], decrypted[2]],
+            net_id: [decrypted[3], decrypted[4], decrypted[5]],
+            dev_addr: DevAddr::new([decrypted[6], decrypted[7], decrypted[8], decrypted[9]]),
+            dl_settings: decrypted[10],
+            rx_delay: decrypted[11],
+            cf_list: decrypted[12..mic_offset].try_into().ok(),
+        })
+    }
+}
+
+/// Decrypted join accept
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinAccept {
+    /// AppNonce (JoinNonce)
+    pub app_nonce: [u8; 3],
+    /// NetID
+    pub net_id: [u8; 3],
+    /// Device address of the session
+    pub dev_addr: DevAddr,
+    /// DLSettings, RX1DROffset in bits 4-6 and RX2 data rate in bits 0-3
+    pub dl_settings: u8,
+    /// RxDelay, the delay in seconds is in bits 0-3
+    pub rx_delay: u8,
+    /// Optional list of channels or channel mask
+    pub cf_list: Option<[u8; CF_LIST_SIZE]>,
+}
+
+impl JoinAccept {
+    /// Get the JoinNonce as integer
+    pub fn join_nonce(&self) -> u32 {
+        u32::from_le_bytes([self.app_nonce[0], self.app_nonce[1], self.app_nonce[2], 0])
+    }
+}
+
+/// Build an encrypted join accept, as sent by the join server
+///
+/// The network encrypts with AES decryption, which only the software backend
+/// provides.
+pub fn build_join_accept(
+    app_key: &AESKey,
+    join_accept: &JoinAccept,
+) -> Vec<u8, JOIN_ACCEPT_MAX_SIZE> {
+    let mut msg: Vec<u8, JOIN_ACCEPT_MAX_SIZE> = Vec::new();
+    // Cannot fail, the fields fit in a join accept with CFList
+    let _ = msg.push(MType::JoinAccept.mhdr());
+    let _ = msg.extend_from_slice(&join_accept.app_nonce);
+    let _ = msg.extend_from_slice(&join_accept.net_id);
+    let _ = msg.extend_from_slice(join_accept.dev_addr.as_bytes());
+    let _ = msg.push(join_accept.dl_settings);
+    let _ = msg.push(join_accept.rx_delay);
+    if let Some(cf_list) = &join_accept.cf_list {
+        let _ = msg.extend_from_slice(cf_list);
+    }
+    let mic = crypto::compute_join_accept_mic(app_key, &msg);
+    let _ = msg.extend_from_slice(&mic);
+
+    let mut frame = Vec::new();
+    let _ = frame.push(msg[0]);
+    let _ = frame.extend_from_slice(&crypto::encrypt_join_accept(app_key, &msg[1..]));
+    frame
+}
+
+/// Data frame parsed from a PHYPayload, FRMPayload still encrypted
+#[derive(Debug, Clone)]
+pub struct DataFrame<'a> {
+    /// Confirmed data frame
+    pub confirmed: bool,
+    /// Sent by the device or by the network
+    pub direction: Direction,
+    /// Frame header
+    pub fhdr: FHDR,
+    /// Frame port, `None` if the frame carries no FRMPayload
+    pub f_port: Option<u8>,
+    /// Encrypted FRMPayload
+    pub frm_payload: &'a [u8],
+    /// Received MIC
+    pub mic: [u8; MIC_SIZE],
+    /// MHDR up to the MIC, the part covered by the MIC
+    msg: &'a [u8],
+}
+
+impl<'a> DataFrame<'a> {
+    fn parse(data: &'a [u8], direction: Direction) -> Result<Self, FrameError> {
+        if data.len() < MIN_DATA_FRAME_SIZE {
+            return Err(FrameError::InvalidLength);
+        }
+
+        let (msg, mic) = data.split_at(data.len() - MIC_SIZE);
+        let (mut fhdr, fhdr_size) = FHDR::parse(&msg[1..]).ok_or(FrameError::InvalidLength)?;
+        if direction == Direction::Up {
+            fhdr.f_ctrl = FCtrl::from_uplink_byte(msg[5]);
+        }
+
+        let (f_port, frm_payload) = match &msg[1 + fhdr_size..] {
+            [] => (None, &[][..]),
+            [f_port, frm_payload @ ..] => (Some(*f_port), frm_payload),
+        };
+        if frm_payload.len() > MAX_MAC_PAYLOAD {
+            return Err(FrameError::InvalidLength);
+        }
+        // MAC commands cannot be sent in FOpts and FRMPayload at once
+        if f_port == Some(0) && !fhdr.f_opts.is_empty() {
+            return Err(FrameError::InvalidFrame);
+        }
+
+        Ok(Self {
+            confirmed: matches!(
+                MType::from_mhdr(msg[0]),
+                Some(MType::ConfirmedDataUp) | Some(MType::ConfirmedDataDown)
+            ),
+            direction,
+            fhdr,
+            f_port,
+            frm_payload,
+            mic: [mic[0], mic[1], mic[2], mic[3]],
+            msg,
+        })
+    }
+
+    /// Check the MIC with the NwkSKey and the full 32-bit frame counter
+    pub fn verify_mic(&self, crypto: &dyn CryptoBackend, nwk_skey: &AESKey, fcnt: u32) -> bool {
+        let mic = crypto.compute_mic(nwk_skey, self.msg, self.fhdr.dev_addr, fcnt, self.direction);
+        crypto::mic_matches(&self.mic, &mic)
+    }
+
+    /// Decrypt the FRMPayload with the full 32-bit frame counter
+    ///
+    /// MAC commands on port 0 are encrypted with the NwkSKey, application
+    /// data with the AppSKey.
+    pub fn decrypt_payload(
+        &self,
+        crypto: &dyn CryptoBackend,
+        nwk_skey: &AESKey,
+        app_skey: &AESKey,
+        fcnt: u32,
+    ) -> Result<Vec<u8, MAX_MAC_PAYLOAD>, FrameError> {
+        let key = if self.f_port == Some(0) {
+            nwk_skey
+        } else {
+            app_skey
+        };
+        let mut payload =
+            Vec::from_slice(self.frm_payload).map_err(|_| FrameError::InvalidLength)?;
+        crypto
+            .encrypt_payload_in_place(key, self.fhdr.dev_addr, fcnt, self.direction, &mut payload)
+            .map_err(|_| FrameError::InvalidLength)?;
+        Ok(payload)
+    }
+}
+
+/// Fields of a data frame to build
+#[derive(Debug, Clone)]
+pub struct DataFrameParams<'a> {
+    /// Confirmed data frame
+    pub confirmed: bool,
+    /// Device address
+    pub dev_addr: DevAddr,
+    /// Frame control field, FOptsLen is taken from `f_opts`
+    pub f_ctrl: FCtrl,
+    /// Full 32-bit frame counter, the lower 16 bits are sent
+    pub fcnt: u32,
+    /// MAC commands in FOpts, at most 15 bytes
+    pub f_opts: &'a [u8],
+    /// Frame port, `None` to send no FRMPayload
+    pub f_port: Option<u8>,
+    /// FRMPayload before encryption
+    pub payload: &'a [u8],
+}
+
+/// Build and sign a data frame sent by a device
+pub fn build_data_up(
+    crypto: &dyn CryptoBackend,
+    params: &DataFrameParams,
+    nwk_skey: &AESKey,
+    app_skey: &AESKey,
+) -> Result<Vec<u8, MAX_FRAME_SIZE>, FrameError> {
+    build_data(crypto, params, Direction::Up, nwk_skey, app_skey)
+}
+
+/// Build and sign a data frame sent by the network
+pub fn build_data_down(
+    crypto: &dyn CryptoBackend,
+    params: &DataFrameParams,
+    nwk_skey: &AESKey,
+    app_skey: &AESKey,
+) -> Result<Vec<u8, MAX_FRAME_SIZE>, FrameError> {
+    build_data(crypto, params, Direction::Down, nwk_skey, app_skey)
+}
+
+/// Build a data frame, FRMPayload on port 0 is encrypted with the NwkSKey
+fn build_data(
+    crypto: &dyn CryptoBackend,
+    params: &DataFrameParams,
+    direction: Direction,
+    nwk_skey: &AESKey,
+    app_skey: &AESKey,
+) -> Result<Vec<u8, MAX_FRAME_SIZE>, FrameError> {
+    if params.f_opts.len() > 15 || params.payload.len() > MAX_MAC_PAYLOAD {
+        return Err(FrameError::InvalidLength);
+    }
+    match params.f_port {
+        None if !params.payload.is_empty() => return Err(FrameError::InvalidFrame),
+        Some(0) if !params.f_opts.is_empty() => return Err(FrameError::InvalidFrame),
+        _ => {}
+    }
+
+    let mut buffer: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+    buffer
+        .push(MType::data(direction, params.confirmed).mhdr())
+        .map_err(|_| FrameError::InvalidLength)?;
+
+    let mut f_ctrl = params.f_ctrl;
+    f_ctrl.foptslen = params.f_opts.len() as u8;
+    let fhdr = FHDR {
+        dev_addr: params.dev_addr,
+        f_ctrl,
+        f_cnt: params.fcnt as u16,
+        f_opts: Vec::from_slice(params.f_opts).map_err(|_| FrameError::InvalidLength)?,
+    };
+    buffer
+        .extend_from_slice(&fhdr.serialize())
+        .map_err(|_| FrameError::InvalidLength)?;
+
+    if let Some(f_port) = params.f_port {
+        buffer.push(f_port).map_err(|_| FrameError::InvalidLength)?;
+        let key = if f_port == 0 { nwk_skey } else { app_skey };
+        // Encrypted in the frame, without a copy of the payload
+        let start = buffer.len();
+        buffer
+            .extend_from_slice(params.payload)
+            .map_err(|_| FrameError::InvalidLength)?;
+        crypto
+            .encrypt_payload_in_place(
+                key,
+                params.dev_addr,
+                params.fcnt,
+                direction,
+                &mut buffer[start..],
+            )
+            .map_err(|_| FrameError::InvalidLength)?;
+    }
+
+    let mic = crypto.compute_mic(nwk_skey, &buffer, params.dev_addr, params.fcnt, direction);
+    buffer
+        .extend_from_slice(&mic)
+        .map_err(|_| FrameError::InvalidLength)?;
+    Ok(buffer)
+}
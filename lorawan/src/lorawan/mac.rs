@@ -1,23 +1,43 @@
+use core::fmt;
+use core::time::Duration;
+
 use heapless::Vec;
 
 use super::commands::MacCommand;
-use super::phy::PhyLayer;
-use super::region::{Channel, DataRate, Region, US915};
-use crate::config::device::{AESKey, DevAddr, SessionState};
-use crate::crypto::{self, Direction, MIC_SIZE};
-use crate::radio::traits::Radio;
-
-/// Maximum MAC payload size
-pub const MAX_MAC_PAYLOAD: usize = 242;
-
-/// Maximum frame size
-pub const MAX_FRAME_SIZE: usize = 256;
+use super::duty_cycle::{AirtimeBudget, DutyCycle};
+use super::frame::{self, DataFrameParams, FrameError, JoinAccept, PhyPayload};
+use super::multicast::{self, MulticastGroup, MAX_MULTICAST_GROUPS};
+use super::phy::{self, NetworkType, PhyLayer, TimingParams};
+use super::region::{Channel, ChannelSelection, DataRate, Region, US915};
+use super::replay::ReplayCache;
+use crate::class::{
+    class_b::{ping_slot::PingSlotConfig, timing::NetworkTime},
+    OperatingMode,
+};
+use crate::config::device::{
+    AESKey, ActivationState, DevAddr, DevNonceStore, FrameCounterStore, SessionState,
+};
+use crate::crypto::{CryptoBackend, SoftwareCrypto, MIC_SIZE};
+use crate::device::power::{PowerManager, StatusProvider};
+use crate::radio::traits::{Radio, RxConfig, TxConfig};
+use crate::timing::Clock;
+
+pub use super::frame::{
+    FCtrl, FHDR, MAX_FHDR_SIZE, MAX_FRAME_SIZE, MAX_MAC_PAYLOAD, MIN_FHDR_SIZE,
+};
 
 /// Maximum number of MAC commands
 pub const MAX_MAC_COMMANDS: usize = 8;
 
+/// Uplinks without downlink after which ADRACKReq is set
+pub const ADR_ACK_LIMIT: u32 = 64;
+
+/// Uplinks after ADRACKReq before each step of the ADR backoff
+pub const ADR_ACK_DELAY: u32 = 32;
+
 /// MAC layer errors
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum MacError<E> {
     /// Radio error
     Radio(E),
@@ -45,93 +65,278 @@ pub enum MacError<E> {
     InvalidChannel,
     /// Invalid port
     InvalidPort,
-    /// Invalid payload size
-    InvalidPayloadSize,
+    /// Payload too large for the current data rate
+    InvalidPayloadSize {
+        /// Largest payload allowed
+        max_size: usize,
+    },
     /// Invalid configuration
     InvalidConfig,
     /// Timeout
     Timeout,
+    /// No free channel found by Listen-Before-Talk
+    ChannelBusy,
+    /// Frame counter replayed or too far ahead
+    InvalidFrameCounter,
+    /// Transmitting now would exceed the duty cycle limits
+    DutyCycleLimited {
+        /// Time until a transmission is allowed again
+        retry_after_ms: u32,
+    },
+    /// No unused DevNonce is left or it could not be stored
+    DevNonceUnavailable,
+    /// Downlink or join accept already received
+    DuplicateFrame,
+    /// The uplink frame counter reached its last value, rejoin or reset
+    /// the session
+    FrameCounterExhausted,
+    /// Output power outside the range of the radio, the clamped power was
+    /// applied
+    TxPowerClamped {
+        /// EIRP in dBm applied instead
+        applied_dbm: i8,
+    },
 }
 
-impl<E> From<E> for MacError<E> {
-    fn from(error: E) -> Self {
-        MacError::Radio(error)
+impl<E> fmt::Display for MacError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MacError::Radio(_) => f.write_str("radio error"),
+            MacError::InvalidFrame => f.write_str("invalid frame format"),
+            MacError::InvalidLength => f.write_str("invalid length"),
+            MacError::InvalidValue => f.write_str("invalid value"),
+            MacError::UnknownCommand => f.write_str("unknown MAC command"),
+            MacError::BufferTooSmall => f.write_str("buffer too small"),
+            MacError::NotJoined => f.write_str("not joined to a network"),
+            MacError::InvalidMic => f.write_str("invalid MIC"),
+            MacError::InvalidAddress => f.write_str("frame for another device"),
+            MacError::InvalidFrequency => f.write_str("invalid frequency"),
+            MacError::InvalidDataRate => f.write_str("invalid data rate"),
+            MacError::InvalidChannel => f.write_str("invalid channel"),
+            MacError::InvalidPort => f.write_str("invalid port"),
+            MacError::InvalidPayloadSize { max_size } => {
+                write!(f, "payload too large, at most {} bytes", max_size)
+            }
+            MacError::InvalidConfig => f.write_str("invalid configuration"),
+            MacError::Timeout => f.write_str("timeout"),
+            MacError::ChannelBusy => f.write_str("no free channel"),
+            MacError::InvalidFrameCounter => f.write_str("frame counter replayed or too far ahead"),
+            MacError::DutyCycleLimited { retry_after_ms } => {
+                write!(f, "duty cycle limited, retry after {} ms", retry_after_ms)
+            }
+            MacError::DevNonceUnavailable => f.write_str("no DevNonce available"),
+            MacError::DuplicateFrame => f.write_str("duplicate frame"),
+            MacError::FrameCounterExhausted => f.write_str("uplink frame counter exhausted"),
+            MacError::TxPowerClamped { applied_dbm } => {
+                write!(f, "TX power clamped to {} dBm", applied_dbm)
+            }
+        }
     }
 }
 
-/// Frame control field
-#[derive(Debug, Clone, Copy)]
-pub struct FCtrl {
-    /// Adaptive data rate enabled
-    pub adr: bool,
-    /// ADR acknowledgment request
-    pub adr_ack_req: bool,
-    /// Frame pending bit
-    pub ack: bool,
-    /// Frame pending bit
-    pub fpending: bool,
-    /// FOpts field length
-    pub foptslen: u8,
+impl<E: core::error::Error + 'static> core::error::Error for MacError<E> {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            MacError::Radio(error) => Some(error),
+            _ => None,
+        }
+    }
 }
 
-impl FCtrl {
-    /// Create a new frame control field with default values
-    pub fn new() -> Self {
-        Self {
-            adr: false,
-            adr_ack_req: false,
-            ack: false,
-            fpending: false,
-            foptslen: 0,
+impl<E> MacError<E> {
+    /// Get the error without the radio error, e.g. to keep it in statistics
+    pub fn kind(&self) -> MacError<()> {
+        match self {
+            MacError::Radio(_) => MacError::Radio(()),
+            MacError::InvalidFrame => MacError::InvalidFrame,
+            MacError::InvalidLength => MacError::InvalidLength,
+            MacError::InvalidValue => MacError::InvalidValue,
+            MacError::UnknownCommand => MacError::UnknownCommand,
+            MacError::BufferTooSmall => MacError::BufferTooSmall,
+            MacError::NotJoined => MacError::NotJoined,
+            MacError::InvalidMic => MacError::InvalidMic,
+            MacError::InvalidAddress => MacError::InvalidAddress,
+            MacError::InvalidFrequency => MacError::InvalidFrequency,
+            MacError::InvalidDataRate => MacError::InvalidDataRate,
+            MacError::InvalidChannel => MacError::InvalidChannel,
+            MacError::InvalidPort => MacError::InvalidPort,
+            MacError::InvalidPayloadSize { max_size } => MacError::InvalidPayloadSize {
+                max_size: *max_size,
+            },
+            MacError::InvalidConfig => MacError::InvalidConfig,
+            MacError::Timeout => MacError::Timeout,
+            MacError::ChannelBusy => MacError::ChannelBusy,
+            MacError::InvalidFrameCounter => MacError::InvalidFrameCounter,
+            MacError::DutyCycleLimited { retry_after_ms } => MacError::DutyCycleLimited {
+                retry_after_ms: *retry_after_ms,
+            },
+            MacError::DevNonceUnavailable => MacError::DevNonceUnavailable,
+            MacError::DuplicateFrame => MacError::DuplicateFrame,
+            MacError::FrameCounterExhausted => MacError::FrameCounterExhausted,
+            MacError::TxPowerClamped { applied_dbm } => MacError::TxPowerClamped {
+                applied_dbm: *applied_dbm,
+            },
         }
     }
+}
 
-    /// Convert frame control field to byte representation
-    pub fn to_byte(&self) -> u8 {
-        let mut byte = 0;
-        if self.adr {
-            byte |= 0x80;
-        }
-        if self.adr_ack_req {
-            byte |= 0x40;
-        }
-        if self.ack {
-            byte |= 0x20;
-        }
-        if self.fpending {
-            byte |= 0x10;
-        }
-        byte |= self.foptslen & 0x0F;
-        byte
+/// Convert an error of the frame codec
+fn frame_error<E>(error: FrameError) -> MacError<E> {
+    match error {
+        FrameError::InvalidLength => MacError::InvalidLength,
+        FrameError::InvalidFrame => MacError::InvalidFrame,
+        FrameError::InvalidMic => MacError::InvalidMic,
     }
 }
 
-/// Frame header
-#[derive(Debug)]
-pub struct FHDR {
-    /// Device address
-    pub dev_addr: DevAddr,
-    /// Frame control field
-    pub f_ctrl: FCtrl,
-    /// Frame counter
-    pub f_cnt: u16,
-    /// Frame options
-    pub f_opts: Vec<u8, 15>,
+/// Default number of transmissions of an unacknowledged confirmed uplink
+pub const DEFAULT_CONFIRMED_ATTEMPTS: u8 = 8;
+
+/// Preamble symbols a receive window must cover to detect a downlink
+const RX_WINDOW_SYMBOLS: u32 = 8;
+
+/// Margin added to receive windows for clock inaccuracy (ms)
+const RX_WINDOW_MARGIN: u32 = 20;
+
+/// Get how long a receive window stays open at a data rate, in ms
+///
+/// Long enough to detect the preamble of a downlink.
+pub fn rx_window_timeout(data_rate: DataRate) -> u32 {
+    (RX_WINDOW_SYMBOLS * data_rate.symbol_time_us()).div_ceil(1_000) + RX_WINDOW_MARGIN
 }
 
-impl FHDR {
-    /// Serialize frame header to bytes
-    pub fn serialize(&self) -> Vec<u8, 16> {
-        let mut buffer = Vec::new();
-        let addr_bytes = self.dev_addr.as_bytes();
-        buffer.extend_from_slice(addr_bytes).unwrap();
-        buffer.push(self.f_ctrl.to_byte()).unwrap();
-        buffer.extend_from_slice(&self.f_cnt.to_le_bytes()).unwrap();
-        buffer.extend_from_slice(&self.f_opts).unwrap();
-        buffer
+/// Outcome of a confirmed uplink
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConfirmedResult {
+    /// Acknowledgement received from the network
+    pub acked: bool,
+    /// Number of transmissions of the frame
+    pub attempts: u8,
+}
+
+/// ADR state of the MAC layer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdrState {
+    /// Adaptive data rate enabled
+    pub enabled: bool,
+    /// Uplinks sent since the last downlink (ADR_ACK_CNT)
+    pub ack_counter: u32,
+    /// Current uplink data rate index
+    pub data_rate: u8,
+    /// Current TX power index
+    pub tx_power: u8,
+}
+
+/// Answer of the network to a LinkCheckReq
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkCheckResult {
+    /// Link margin of the uplink above the demodulation floor in dB
+    pub margin: u8,
+    /// Number of gateways that received the uplink
+    pub gateway_count: u8,
+    /// Time the answer was received in milliseconds
+    pub received_at: u32,
+}
+
+/// Parsed and decrypted downlink data frame
+#[derive(Debug, Clone)]
+pub struct DownlinkFrame {
+    /// Confirmed data down
+    pub confirmed: bool,
+    /// Frame header
+    pub fhdr: FHDR,
+    /// Full 32-bit frame counter the frame was verified with
+    pub fcnt: u32,
+    /// Frame port, `None` if the frame carries no FRMPayload
+    pub f_port: Option<u8>,
+    /// Decrypted FRMPayload
+    pub payload: Vec<u8, MAX_MAC_PAYLOAD>,
+}
+
+impl DownlinkFrame {
+    /// Get the MAC commands of the frame, from FOpts or a port 0 FRMPayload
+    ///
+    /// A frame never carries both, see `parse_downlink`.
+    pub fn mac_commands(&self) -> &[u8] {
+        match self.f_port {
+            Some(0) => &self.payload,
+            _ => &self.fhdr.f_opts,
+        }
     }
 }
 
+/// Downlink verified by `check_downlink`, not applied to the session yet
+struct CheckedDownlink {
+    frame: DownlinkFrame,
+    /// MIC of the frame, kept by the replay cache
+    mic: [u8; MIC_SIZE],
+    /// Index of the multicast group the frame is addressed to, if any
+    group: Option<usize>,
+}
+
+/// Counters of the downlinks dropped by the MAC layer
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MacStats {
+    /// Downlinks addressed to other devices
+    pub frames_for_other_devices: u32,
+    /// Downlinks with an invalid MIC
+    pub mic_failures: u32,
+    /// Duplicate downlinks and replayed join accepts
+    pub duplicates_dropped: u32,
+}
+
+/// Link statistics of a device, for field debugging
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkStats {
+    /// Uplinks transmitted, including join requests and retransmissions
+    pub uplinks: u32,
+    /// Downlinks accepted
+    pub downlinks: u32,
+    /// Join requests transmitted
+    pub join_attempts: u32,
+    /// Join accepts processed
+    pub joins: u32,
+    /// Downlinks with an invalid MIC
+    pub mic_failures: u32,
+    /// Receptions that failed in the radio, such as CRC errors
+    pub rx_errors: u32,
+    /// Downlinks addressed to other devices
+    pub frames_for_other_devices: u32,
+    /// Duplicate downlinks and replayed join accepts
+    pub duplicates_dropped: u32,
+    /// RSSI of the last received frame in dBm
+    pub last_rssi: Option<i16>,
+    /// SNR of the last received frame in dB
+    pub last_snr: Option<i8>,
+    /// Last error of a transmission or reception
+    pub last_error: Option<MacError<()>>,
+    /// Time-on-air of the uplinks in ms
+    pub airtime_ms: u32,
+    /// Share of the time since the reset spent transmitting in permille
+    pub duty_cycle_permille: u16,
+}
+
+/// Application data received in a downlink
+#[derive(Debug, Clone)]
+pub struct Downlink {
+    /// Application port (1-223)
+    pub port: u8,
+    /// Decrypted application payload
+    pub payload: Vec<u8, MAX_MAC_PAYLOAD>,
+    /// RSSI of the frame in dBm
+    pub rssi: i16,
+    /// SNR of the frame in dB
+    pub snr: i8,
+    /// Frame counter of the downlink
+    pub fcnt: u32,
+    /// The downlink was confirmed and is acknowledged by the next uplink
+    pub confirmed: bool,
+    /// The downlink was addressed to a multicast group
+    pub multicast: bool,
+}
+
 /// MAC layer
 pub struct MacLayer<R: Radio, REG: Region> {
     /// PHY layer
@@ -142,19 +347,152 @@ pub struct MacLayer<R: Radio, REG: Region> {
     session: SessionState,
     /// MAC commands to be sent
     pending_commands: Vec<MacCommand, MAX_MAC_COMMANDS>,
+    /// Persistent storage of the DevNonce, if any
+    dev_nonce_store: Option<&'static dyn DevNonceStore>,
+    /// Draw DevNonces from the radio RNG instead of counting them up
+    random_dev_nonces: bool,
+    /// Persistent storage of the frame counters and the uplinks between saves
+    fcnt_store: Option<(&'static dyn FrameCounterStore, u32)>,
+    /// Uplink frame counter last saved to the store
+    fcnt_saved: u32,
+    /// AppKey of the outstanding join request, if any
+    join_key: Option<AESKey>,
+    /// Number of transmissions of each uplink set by LinkADRReq
+    nb_trans: u8,
+    /// Transmissions of a confirmed uplink before giving up
+    confirmed_attempts: u8,
+    /// Adaptive data rate enabled
+    adr_enabled: bool,
+    /// Data rate index and TX power index set by the application, restored
+    /// when ADR is disabled
+    manual_tx_params: (Option<u8>, Option<u8>),
+    /// Uplinks sent since the last downlink (ADR_ACK_CNT)
+    adr_ack_cnt: u32,
+    /// A confirmed downlink is waiting to be acknowledged
+    pending_ack: bool,
+    /// The network has more downlinks queued (FPending)
+    downlink_pending: bool,
+    /// Class B bit of the uplinks, set while beacons are tracked
+    class_b: bool,
+    /// Channel of the last uplink, used to derive RX1
+    last_uplink_channel: Option<Channel>,
+    /// Counters of the link since the last reset
+    stats: LinkStats,
+    /// Time the link statistics were reset
+    stats_since: u32,
+    /// Recently accepted downlinks and JoinNonces
+    replay_cache: ReplayCache,
+    /// Multicast groups the device receives downlinks of
+    multicast_groups: Vec<MulticastGroup, MAX_MULTICAST_GROUPS>,
+    /// Airtime budgets of the duty cycle bands
+    duty_cycle: DutyCycle,
+    /// Time-on-air allowed per period on top of the duty cycle, if any
+    airtime_budget: Option<AirtimeBudget>,
+    /// Last answer to a LinkCheckReq
+    last_link_check: Option<LinkCheckResult>,
+    /// SNR of the last received frame in dB
+    last_snr: Option<i8>,
+    /// RSSI of the last received frame in dBm
+    last_rssi: Option<i16>,
+    /// Application data of the last downlink not taken yet
+    downlink: Option<Downlink>,
+    /// Source of the battery level reported in DevStatusAns
+    status_provider: Option<&'static mut dyn StatusProvider>,
+    /// Time spent transmitting, receiving and sleeping
+    power: PowerManager,
+    /// Local time the radio was put to sleep at
+    sleep_since: Option<u32>,
+    /// Local time at the end of the last uplink
+    last_tx_end: u32,
+    /// Network time from DeviceTimeAns and beacons
+    network_time: NetworkTime,
+    /// Class B ping slot settings
+    ping_slot_config: PingSlotConfig,
+    /// Beacon frequency set by BeaconFreqReq
+    beacon_frequency: Option<u32>,
+    /// AES backend used for MICs, encryption and key derivation
+    crypto: &'static dyn CryptoBackend,
+    /// Frame buffer of the receive windows, shared by the device classes
+    rx_buffer: [u8; MAX_FRAME_SIZE],
 }
 
 impl<R: Radio, REG: Region> MacLayer<R, REG> {
     /// Create new MAC layer
     pub fn new(radio: R, region: REG, session: SessionState) -> Self {
+        Self::with_crypto_backend(radio, region, session, &SoftwareCrypto)
+    }
+
+    /// Create new MAC layer reading the time from `clock` instead of the radio
+    ///
+    /// Receive windows, the duty cycle and Class B timing all follow the
+    /// clock, e.g. an RTC that keeps running while the radio sleeps.
+    pub fn with_clock(
+        radio: R,
+        region: REG,
+        session: SessionState,
+        clock: &'static dyn Clock,
+    ) -> Self {
+        let mut mac = Self::new(radio, region, session);
+        mac.set_clock(clock);
+        mac
+    }
+
+    /// Create new MAC layer running the security functions on `crypto`
+    pub fn with_crypto_backend(
+        radio: R,
+        region: REG,
+        session: SessionState,
+        crypto: &'static dyn CryptoBackend,
+    ) -> Self {
+        let mut phy = PhyLayer::new(radio);
+        phy.config.timing = TimingParams::from_region(&region);
+        phy.config.timing.set_rx_delay(session.rx1_delay);
         Self {
-            phy: PhyLayer::new(radio),
+            phy,
             region,
             session,
             pending_commands: Vec::new(),
+            dev_nonce_store: None,
+            random_dev_nonces: false,
+            fcnt_store: None,
+            fcnt_saved: 0,
+            join_key: None,
+            nb_trans: 1,
+            confirmed_attempts: DEFAULT_CONFIRMED_ATTEMPTS,
+            adr_enabled: false,
+            manual_tx_params: (None, None),
+            adr_ack_cnt: 0,
+            pending_ack: false,
+            downlink_pending: false,
+            class_b: false,
+            last_uplink_channel: None,
+            stats: LinkStats::default(),
+            stats_since: 0,
+            replay_cache: ReplayCache::default(),
+            multicast_groups: Vec::new(),
+            duty_cycle: DutyCycle::new(),
+            airtime_budget: None,
+            last_link_check: None,
+            last_snr: None,
+            last_rssi: None,
+            downlink: None,
+            status_provider: None,
+            power: PowerManager::default(),
+            sleep_since: None,
+            last_tx_end: 0,
+            network_time: NetworkTime::new(),
+            ping_slot_config: PingSlotConfig::default(),
+            beacon_frequency: None,
+            crypto,
+            rx_buffer: [0; MAX_FRAME_SIZE],
         }
     }
 
+    /// Get the AES backend
+    pub fn get_crypto_backend(&self) -> &'static dyn CryptoBackend {
+        self.crypto
+    }
+
     /// Get radio reference
     pub fn get_radio(&self) -> &R {
         &self.phy.radio
@@ -180,6 +518,26 @@ impl<R: Radio, REG: Region> MacLayer<R, REG> {
         &self.session
     }
 
+    /// Replace the session state, e.g. with one restored after a reboot
+    ///
+    /// The device class of the session is kept, it is set by the class
+    /// running the MAC layer.
+    pub fn set_session_state(&mut self, session: SessionState) {
+        let device_class = self.session.device_class;
+        self.session = session;
+        self.session.device_class = device_class;
+        self.join_key = None;
+        self.adr_ack_cnt = 0;
+
+        // Configure PHY layer with the new timing
+        self.phy.config.timing.set_rx_delay(self.session.rx1_delay);
+    }
+
+    /// Record the class running the MAC layer in the session
+    pub(crate) fn set_device_class(&mut self, mode: OperatingMode) {
+        self.session.device_class = mode;
+    }
+
     /// Get device address
     pub fn get_device_address(&self) -> Option<DevAddr> {
         Some(self.session.dev_addr)
@@ -192,203 +550,1384 @@ impl<R: Radio, REG: Region> MacLayer<R, REG> {
         data_rate: DataRate,
         timeout_ms: u32,
     ) -> Result<(), MacError<R::Error>> {
+        self.wake();
         self.phy
             .configure_rx::<REG>(frequency, data_rate, timeout_ms)
             .map_err(MacError::Radio)
     }
 
+    /// Set RX configuration for a Class B beacon of `size` bytes
+    pub fn set_beacon_rx_config(
+        &mut self,
+        frequency: u32,
+        data_rate: DataRate,
+        size: u8,
+        timeout_ms: u32,
+    ) -> Result<(), MacError<R::Error>> {
+        self.wake();
+        self.phy
+            .configure_beacon_rx(frequency, data_rate, size, timeout_ms)
+            .map_err(MacError::Radio)
+    }
+
     /// Get RX1 parameters
+    ///
+    /// The RX1 data rate takes the RX1DROffset of the session into account.
     pub fn get_rx1_params(&mut self) -> Result<(u32, DataRate), MacError<R::Error>> {
         let channel = self
             .region
             .get_next_channel()
             .ok_or(MacError::InvalidChannel)?;
-        Ok(self.region.rx1_window(&channel))
+        Ok(self.region.rx1_window(&channel, self.session.rx1_dr_offset))
+    }
+
+    /// Enable or disable adaptive data rate
+    pub fn set_adr(&mut self, enabled: bool) {
+        self.adr_enabled = enabled;
+        self.adr_ack_cnt = 0;
+
+        // The settings of the application replace those of the network
+        if !enabled {
+            let (data_rate, tx_power) = self.manual_tx_params;
+            if let Some(data_rate) = data_rate {
+                self.region.set_data_rate(data_rate);
+            }
+            if let Some(tx_power) = tx_power {
+                self.region.set_tx_power(tx_power);
+            }
+        }
+    }
+
+    /// Set or clear the Class B bit of the next uplinks
+    ///
+    /// Only set while the device is synchronized to the beacons.
+    pub fn set_class_b(&mut self, enabled: bool) {
+        self.class_b = enabled;
+    }
+
+    /// Check if uplinks declare the device as Class B
+    pub fn is_class_b(&self) -> bool {
+        self.class_b
+    }
+
+    /// Get the ADR state
+    pub fn get_adr_state(&self) -> AdrState {
+        AdrState {
+            enabled: self.adr_enabled,
+            ack_counter: self.adr_ack_cnt,
+            data_rate: self.region.data_rate(),
+            tx_power: self.region.tx_power(),
+        }
+    }
+
+    /// Reset the ADR backoff, which has to happen on every received downlink
+    pub fn reset_adr_ack_counter(&mut self) {
+        self.adr_ack_cnt = 0;
+    }
+
+    /// Frame control field of the next uplink
+    fn uplink_f_ctrl(&self) -> FCtrl {
+        let mut f_ctrl = FCtrl::new();
+        f_ctrl.ack = self.pending_ack;
+        f_ctrl.adr = self.adr_enabled;
+        f_ctrl.adr_ack_req = self.adr_enabled && self.adr_ack_cnt >= ADR_ACK_LIMIT;
+        f_ctrl.class_b = self.class_b;
+        f_ctrl
+    }
+
+    /// Advance the ADR backoff after an uplink
+    ///
+    /// ADRACKReq is set once ADR_ACK_LIMIT uplinks went without downlink.
+    /// Every ADR_ACK_DELAY uplinks after that the device regains connectivity
+    /// step by step: first the TX power is raised to the maximum, then the
+    /// data rate is lowered towards the default and finally the default
+    /// channels are enabled again.
+    fn update_adr_backoff(&mut self) {
+        if !self.adr_enabled {
+            return;
+        }
+
+        self.adr_ack_cnt = self.adr_ack_cnt.saturating_add(1);
+        let Some(backoff_uplinks) = self.adr_ack_cnt.checked_sub(ADR_ACK_LIMIT + ADR_ACK_DELAY)
+        else {
+            return;
+        };
+        if backoff_uplinks % ADR_ACK_DELAY != 0 {
+            return;
+        }
+
+        if self.region.tx_power() > 0 {
+            self.region.set_tx_power(0);
+            return;
+        }
+
+        let data_rate = self.region.data_rate();
+        self.region.set_data_rate(data_rate.saturating_sub(1));
+        if self.region.data_rate() == data_rate {
+            self.region.enable_default_channels();
+        }
     }
 
     /// Send unconfirmed data
     pub fn send_unconfirmed(&mut self, f_port: u8, data: &[u8]) -> Result<(), MacError<R::Error>> {
-        let mut buffer: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+        self.send_data_frame(false, f_port, data)
+    }
+
+    /// Send confirmed data
+    ///
+    /// The frame is retransmitted with the same frame counter until a downlink
+    /// with the ACK bit set arrives in RX1 or RX2. It is sent at most the
+    /// configured number of confirmed attempts, or NbTrans times if higher,
+    /// and the data rate is lowered after every second unacknowledged
+    /// transmission as long as the frame fits in its MACPayload limit. The
+    /// data rate is restored after the exchange, unless a LinkADRReq received
+    /// meanwhile set a new one.
+    pub fn send_confirmed(
+        &mut self,
+        f_port: u8,
+        data: &[u8],
+    ) -> Result<ConfirmedResult, MacError<R::Error>> {
+        let (frame, sent_commands) = self.build_data_frame(true, f_port, data)?;
+        let mut lowered = None;
+        let result = self.transmit_confirmed(&frame, sent_commands, &mut lowered);
+        if let Some((data_rate, lowered)) = lowered {
+            if self.region.data_rate() == lowered {
+                self.region.set_data_rate(data_rate);
+            }
+        }
+        result
+    }
 
-        // Add MAC header
-        buffer.push(0x40).map_err(|_| MacError::BufferTooSmall)?; // Unconfirmed Data Up
+    /// Transmit a confirmed frame until it is acknowledged
+    ///
+    /// `lowered` is set to the data rate before the first reduction and the
+    /// last data rate retransmissions used.
+    fn transmit_confirmed(
+        &mut self,
+        frame: &[u8],
+        sent_commands: usize,
+        lowered: &mut Option<(u8, u8)>,
+    ) -> Result<ConfirmedResult, MacError<R::Error>> {
+        let max_attempts = self.confirmed_attempts.max(self.nb_trans);
+
+        let mut attempts = 0;
+        while attempts < max_attempts {
+            let data_rate = self.uplink_data_rate()?;
+            let channel = self.transmit_uplink(frame, Some(data_rate))?;
+            attempts += 1;
+            if attempts == 1 {
+                self.commit_uplink(sent_commands);
+            }
 
-        // Add frame header
-        let fhdr = FHDR {
-            dev_addr: self.session.dev_addr,
-            f_ctrl: FCtrl::new(),
-            f_cnt: self.session.fcnt_up as u16,
-            f_opts: Vec::new(),
-        };
-        buffer
-            .extend_from_slice(&fhdr.serialize())
-            .map_err(|_| MacError::BufferTooSmall)?;
+            if self.receive_ack(&channel)? {
+                return Ok(ConfirmedResult {
+                    acked: true,
+                    attempts,
+                });
+            }
 
-        // Add port
-        buffer.push(f_port).map_err(|_| MacError::BufferTooSmall)?;
+            if attempts % 2 == 0 && attempts < max_attempts {
+                let current = self.region.data_rate();
+                let data_rate = current.saturating_sub(1);
+                let mac_payload_len = frame.len() - 1 - MIC_SIZE;
+                if mac_payload_len <= self.region.max_payload_size(data_rate) as usize {
+                    self.region.set_data_rate(data_rate);
+                    let before = lowered.map_or(current, |(before, _)| before);
+                    *lowered = Some((before, data_rate));
+                }
+            }
+        }
 
-        // Add encrypted payload
-        let encrypted = crypto::encrypt_payload(
-            &self.session.app_skey,
-            self.session.dev_addr,
+        Ok(ConfirmedResult {
+            acked: false,
+            attempts,
+        })
+    }
+
+    /// Send confirmed data once without listening for the acknowledgement
+    ///
+    /// The caller opens the receive windows and retransmits if no downlink
+    /// with the ACK bit set arrives.
+    pub fn send_confirmed_once(
+        &mut self,
+        f_port: u8,
+        data: &[u8],
+    ) -> Result<(), MacError<R::Error>> {
+        self.send_data_frame(true, f_port, data)
+    }
+
+    /// Set the number of transmissions of a confirmed uplink before giving up
+    pub fn set_confirmed_attempts(&mut self, attempts: u8) {
+        self.confirmed_attempts = attempts.max(1);
+    }
+
+    /// Send the queued MAC commands in an uplink without application payload
+    pub fn send_mac_commands(&mut self) -> Result<(), MacError<R::Error>> {
+        self.send_data_frame(false, 0, &[])
+    }
+
+    /// Check if MAC commands are waiting to be sent
+    pub fn has_pending_commands(&self) -> bool {
+        !self.pending_commands.is_empty()
+    }
+
+    /// Build and transmit a data uplink
+    ///
+    /// Commands are only removed from the queue once the frame has been
+    /// handed to the radio.
+    fn send_data_frame(
+        &mut self,
+        confirmed: bool,
+        f_port: u8,
+        data: &[u8],
+    ) -> Result<(), MacError<R::Error>> {
+        let (frame, sent_commands) = self.build_data_frame(confirmed, f_port, data)?;
+        trace!(
+            "uplink built: FCnt {=u32}, {=usize} bytes",
             self.session.fcnt_up,
-            Direction::Up,
-            data,
+            frame.len()
         );
-        buffer
-            .extend_from_slice(&encrypted)
-            .map_err(|_| MacError::BufferTooSmall)?;
+        let data_rate = self.uplink_data_rate()?;
+        self.transmit_uplink(&frame, Some(data_rate))?;
+        self.commit_uplink(sent_commands);
+        Ok(())
+    }
+
+    /// Build a data uplink with the current frame counter
+    ///
+    /// Fails with `NotJoined` without a session, the keys are not set yet,
+    /// and with `FrameCounterExhausted` once the counter reached `u32::MAX`.
+    /// Queued MAC commands are carried in FOpts as far as they fit. Without
+    /// application payload, commands that do not fit in FOpts are sent as a
+    /// port 0 FRMPayload encrypted with the NwkSKey instead; otherwise they
+    /// stay queued for the next uplink. Returns the frame and the number of
+    /// queued commands it carries.
+    fn build_data_frame(
+        &self,
+        confirmed: bool,
+        f_port: u8,
+        data: &[u8],
+    ) -> Result<(Vec<u8, MAX_FRAME_SIZE>, usize), MacError<R::Error>> {
+        if !self.session.is_joined() {
+            return Err(MacError::NotJoined);
+        }
+        if self.frame_counter_exhausted() {
+            return Err(MacError::FrameCounterExhausted);
+        }
+
+        let (mut f_opts, fopts_commands) = self.pending_fopts();
+
+        // Spill all commands to a port 0 FRMPayload if they do not fit in FOpts
+        let mut mac_payload: Vec<u8, MAX_MAC_PAYLOAD> = Vec::new();
+        let spill = data.is_empty() && fopts_commands < self.pending_commands.len();
+        if spill {
+            for command in &self.pending_commands {
+                let mut buf = Vec::new();
+                command.serialize(&mut buf);
+                mac_payload
+                    .extend_from_slice(&buf)
+                    .map_err(|_| MacError::BufferTooSmall)?;
+            }
+            f_opts.clear();
+        }
+
+        // FHDR with FOpts, FPort and FRMPayload must fit in the MACPayload
+        // limit of the data rate
+        let payload_len = if spill { mac_payload.len() } else { data.len() };
+        let max_size = self.max_frm_payload(f_opts.len());
+        if payload_len > max_size {
+            return Err(MacError::InvalidPayloadSize { max_size });
+        }
 
-        // Add MIC
-        let mic = crypto::compute_mic(
+        // MAC commands on port 0 use the NwkSKey, an empty frame has no FPort
+        let (f_port, payload) = if spill {
+            (Some(0), mac_payload.as_slice())
+        } else if data.is_empty() {
+            (None, data)
+        } else {
+            (Some(f_port), data)
+        };
+        let params = DataFrameParams {
+            confirmed,
+            dev_addr: self.session.dev_addr,
+            f_ctrl: self.uplink_f_ctrl(),
+            fcnt: self.session.fcnt_up,
+            f_opts: &f_opts,
+            f_port,
+            payload,
+        };
+        let buffer = frame::build_data_up(
+            self.crypto,
+            &params,
             &self.session.nwk_skey,
-            &buffer,
-            self.session.dev_addr,
-            self.session.fcnt_up,
-            Direction::Up,
-        );
-        buffer
-            .extend_from_slice(&mic)
-            .map_err(|_| MacError::BufferTooSmall)?;
+            &self.session.app_skey,
+        )
+        .map_err(frame_error)?;
+
+        let sent_commands = if spill {
+            self.pending_commands.len()
+        } else {
+            fopts_commands
+        };
+        Ok((buffer, sent_commands))
+    }
+
+    /// Get the largest FRMPayload next to `fopts_len` bytes of FOpts
+    ///
+    /// Limited by the MACPayload size of the data rate and the frame buffers.
+    fn max_frm_payload(&self, fopts_len: usize) -> usize {
+        (self.region.max_payload_size(self.region.data_rate()) as usize)
+            .min(MAX_FRAME_SIZE - 1 - MIC_SIZE)
+            .saturating_sub(MIN_FHDR_SIZE + 1 + fopts_len)
+            .min(MAX_MAC_PAYLOAD)
+    }
 
-        // Transmit
-        self.phy.transmit(&buffer).map_err(MacError::Radio)?;
+    /// Serialize the queued MAC commands that fit in FOpts
+    ///
+    /// Returns FOpts and the number of commands it carries.
+    fn pending_fopts(&self) -> (Vec<u8, 15>, usize) {
+        let mut f_opts = Vec::new();
+        let mut fopts_commands = 0;
+        for command in &self.pending_commands {
+            if !command.serialize(&mut f_opts) {
+                break;
+            }
+            fopts_commands += 1;
+        }
+        (f_opts, fopts_commands)
+    }
+
+    /// Check if the uplink frame counter reached its last value
+    ///
+    /// The counter must not roll over within a session, so `u32::MAX` is
+    /// never sent. OTAA devices rejoin before, ABP devices need new keys.
+    pub fn frame_counter_exhausted(&self) -> bool {
+        self.session.fcnt_up == u32::MAX
+    }
+
+    /// Get the current uplink data rate
+    fn uplink_data_rate(&self) -> Result<DataRate, MacError<R::Error>> {
+        self.region
+            .data_rate_from_index(self.region.data_rate())
+            .ok_or(MacError::InvalidDataRate)
+    }
+
+    /// Update the session after the first transmission of an uplink
+    fn commit_uplink(&mut self, sent_commands: usize) {
+        // The ACK bit went out with this frame, and it opens new receive
+        // windows for pending downlinks
+        self.pending_ack = false;
+        self.downlink_pending = false;
+
+        // Drop the commands that went out with this frame, sticky answers
+        // stay until a downlink is received
+        let remaining: Vec<MacCommand, MAX_MAC_COMMANDS> = self
+            .pending_commands
+            .iter()
+            .enumerate()
+            .filter(|(i, command)| *i >= sent_commands || command.is_sticky())
+            .map(|(_, command)| command.clone())
+            .collect();
+        self.pending_commands = remaining;
 
         // Increment frame counter
         self.session.fcnt_up = self.session.fcnt_up.wrapping_add(1);
+        self.save_frame_counters();
+        self.update_adr_backoff();
+    }
+
+    /// Get the delays of RX1 and RX2 after the end of an uplink in milliseconds
+    ///
+    /// RX1 opens after the RxDelay of the session or of the last
+    /// RXTimingSetupReq, RX2 one second later.
+    pub fn receive_delays(&self) -> (u32, u32) {
+        let timing = &self.phy.config.timing;
+        (timing.rx1_delay, timing.rx2_delay)
+    }
 
+    /// Get the delays of the join accept windows after a join request in ms
+    pub fn join_accept_delays(&self) -> (u32, u32) {
+        let timing = &self.phy.config.timing;
+        (timing.join_accept_delay1, timing.join_accept_delay2)
+    }
+
+    /// Get the receive window delays
+    pub fn timing(&self) -> &TimingParams {
+        &self.phy.config.timing
+    }
+
+    /// Set the receive window delays
+    ///
+    /// Replaced by the RxDelay of the next session or RXTimingSetupReq.
+    pub fn set_timing(&mut self, timing: TimingParams) {
+        self.phy.config.timing = timing;
+    }
+
+    /// Get the frequency and data rate of RX1 for an uplink channel
+    pub fn rx1_window(&self, channel: &Channel) -> (u32, DataRate) {
+        self.region.rx1_window(channel, self.session.rx1_dr_offset)
+    }
+
+    /// Get the frequency and data rate of RX2
+    ///
+    /// Settings from the join accept or RXParamSetupReq take precedence over
+    /// the region defaults.
+    pub fn rx2_window(&self) -> (u32, DataRate) {
+        let (frequency, data_rate) = self.region.rx2_window();
+        let frequency = self.session.rx2_frequency.unwrap_or(frequency);
+        let data_rate = self
+            .session
+            .rx2_data_rate
+            .and_then(|index| self.region.data_rate_from_index(index))
+            .unwrap_or(data_rate);
+        (frequency, data_rate)
+    }
+
+    /// Set the frequency and data rate index of RX2
+    pub fn set_rx2_params(&mut self, frequency: u32, data_rate: u8) {
+        self.session.rx2_frequency = Some(frequency);
+        self.session.rx2_data_rate = Some(data_rate);
+    }
+
+    /// Restore RX2 settings saved from the session, `None` for the region defaults
+    pub(crate) fn restore_rx2_params(&mut self, frequency: Option<u32>, data_rate: Option<u8>) {
+        self.session.rx2_frequency = frequency;
+        self.session.rx2_data_rate = data_rate;
+    }
+
+    /// Get the channel of the last uplink
+    pub fn get_last_uplink_channel(&self) -> Option<&Channel> {
+        self.last_uplink_channel.as_ref()
+    }
+
+    /// Open a receive window and read the frame received in it, if any
+    ///
+    /// The window stays open long enough to detect the preamble of a
+    /// downlink at the data rate of the window.
+    pub fn receive_window(
+        &mut self,
+        frequency: u32,
+        data_rate: DataRate,
+        buffer: &mut [u8],
+    ) -> Result<usize, MacError<R::Error>> {
+        self.open_window(frequency, data_rate)?;
+        self.receive(buffer)
+    }
+
+    /// Open a receive window and read the frame received in it into the
+    /// frame buffer, see `receive_frame`
+    pub fn receive_window_frame(
+        &mut self,
+        frequency: u32,
+        data_rate: DataRate,
+    ) -> Result<usize, MacError<R::Error>> {
+        self.open_window(frequency, data_rate)?;
+        self.receive_frame()
+    }
+
+    /// Configure the radio for a receive window
+    fn open_window(
+        &mut self,
+        frequency: u32,
+        data_rate: DataRate,
+    ) -> Result<(), MacError<R::Error>> {
+        self.wake();
+        let timeout = rx_window_timeout(data_rate);
+        self.phy
+            .configure_rx::<REG>(frequency, data_rate, timeout)
+            .map_err(MacError::Radio)?;
+        trace!("RX window opened: {=u32} Hz, {}", frequency, data_rate);
+        self.power.record_rx(Duration::from_millis(timeout as u64));
         Ok(())
     }
 
-    /// Send confirmed data
-    pub fn send_confirmed(&mut self, f_port: u8, data: &[u8]) -> Result<(), MacError<R::Error>> {
-        let mut buffer: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
+    /// Listen in RX1 and RX2 after an uplink and check for an acknowledgement
+    ///
+    /// RX2 is only opened if no valid downlink arrived in RX1. Frames that
+    /// cannot be verified are ignored.
+    fn receive_ack(&mut self, channel: &Channel) -> Result<bool, MacError<R::Error>> {
+        let windows = [self.rx1_window(channel), self.rx2_window()];
+
+        for (frequency, data_rate) in windows {
+            let len = self.receive_window_frame(frequency, data_rate)?;
+            if len == 0 {
+                continue;
+            }
+            match self.handle_received_downlink(len) {
+                Ok(frame) => return Ok(frame.fhdr.f_ctrl.ack),
+                Err(MacError::Radio(e)) => return Err(MacError::Radio(e)),
+                Err(_) => continue,
+            }
+        }
+        Ok(false)
+    }
 
-        // Add MAC header
-        buffer.push(0x80).map_err(|_| MacError::BufferTooSmall)?; // Confirmed Data Up
+    /// Receive a downlink and process its MAC commands
+    ///
+    /// The commands are read from FOpts or from a port 0 FRMPayload.
+    /// Application data on any other port is kept until `take_downlink`.
+    pub fn handle_downlink(&mut self, data: &[u8]) -> Result<DownlinkFrame, MacError<R::Error>> {
+        let checked = self.check_downlink(data, false);
+        self.process_downlink(checked)
+    }
 
-        // Add frame header
-        let fhdr = FHDR {
-            dev_addr: self.session.dev_addr,
-            f_ctrl: FCtrl::new(),
-            f_cnt: self.session.fcnt_up as u16,
-            f_opts: Vec::new(),
+    /// Handle the downlink of `len` bytes in the frame buffer
+    ///
+    /// See `receive_frame` and `handle_downlink`.
+    pub fn handle_received_downlink(
+        &mut self,
+        len: usize,
+    ) -> Result<DownlinkFrame, MacError<R::Error>> {
+        let checked = self.check_downlink(&self.rx_buffer[..len], false);
+        self.process_downlink(checked)
+    }
+
+    /// Handle the downlink of `len` bytes in the frame buffer, which may be
+    /// addressed to a multicast group
+    ///
+    /// For Class B ping slots and Class C continuous reception. Multicast
+    /// downlinks are verified and decrypted with the keys of their group,
+    /// only its frame counter advances. See `add_multicast_group`.
+    pub fn handle_received_downlink_or_multicast(
+        &mut self,
+        len: usize,
+    ) -> Result<DownlinkFrame, MacError<R::Error>> {
+        let checked = self.check_downlink(&self.rx_buffer[..len], true);
+        self.process_downlink(checked)
+    }
+
+    /// Accept a checked downlink and process its MAC commands
+    fn process_downlink(
+        &mut self,
+        checked: Result<CheckedDownlink, MacError<R::Error>>,
+    ) -> Result<DownlinkFrame, MacError<R::Error>> {
+        let multicast = matches!(&checked, Ok(checked) if checked.group.is_some());
+        let frame = self.accept_downlink(checked)?;
+        if let Some(port) = frame.f_port.filter(|&port| port != 0) {
+            self.downlink = Some(Downlink {
+                port,
+                payload: frame.payload.clone(),
+                rssi: self.last_rssi.unwrap_or(0),
+                snr: self.last_snr.unwrap_or(0),
+                fcnt: frame.fcnt,
+                confirmed: frame.confirmed,
+                multicast,
+            });
+        }
+        if multicast {
+            return Ok(frame);
+        }
+        if let Some(commands) = self.extract_mac_commands(frame.mac_commands()) {
+            self.process_mac_commands(&commands)?;
+        }
+        Ok(frame)
+    }
+
+    /// Take the application data of the last downlink, if any
+    pub fn take_downlink(&mut self) -> Option<Downlink> {
+        self.downlink.take()
+    }
+
+    /// Parse, verify and decrypt a downlink data frame
+    ///
+    /// The frame must be a data down frame addressed to the session. The
+    /// 16-bit FCnt of the frame is extended to the 32-bit counter the MIC is
+    /// verified with. FRMPayload is decrypted with the AppSKey, or with the
+    /// NwkSKey when it carries MAC commands on port 0. Frames with MAC
+    /// commands in both FOpts and a port 0 FRMPayload fail with
+    /// `InvalidFrame`.
+    pub fn parse_downlink(&self, data: &[u8]) -> Result<DownlinkFrame, MacError<R::Error>> {
+        let frame = match PhyPayload::parse(data).map_err(frame_error)? {
+            PhyPayload::DataDown(frame) => frame,
+            _ => return Err(MacError::InvalidFrame),
         };
-        buffer
-            .extend_from_slice(&fhdr.serialize())
-            .map_err(|_| MacError::BufferTooSmall)?;
+        if frame.fhdr.dev_addr != self.session.dev_addr {
+            return Err(MacError::InvalidAddress);
+        }
 
-        // Add port
-        buffer.push(f_port).map_err(|_| MacError::BufferTooSmall)?;
+        let fcnt = self.reconstruct_fcnt_down(frame.fhdr.f_cnt)?;
+        if !frame.verify_mic(self.crypto, &self.session.nwk_skey, fcnt) {
+            return Err(MacError::InvalidMic);
+        }
 
-        // Add encrypted payload
-        let encrypted = crypto::encrypt_payload(
-            &self.session.app_skey,
-            self.session.dev_addr,
-            self.session.fcnt_up,
-            Direction::Up,
-            data,
-        );
-        buffer
-            .extend_from_slice(&encrypted)
-            .map_err(|_| MacError::BufferTooSmall)?;
+        let payload = frame
+            .decrypt_payload(
+                self.crypto,
+                &self.session.nwk_skey,
+                &self.session.app_skey,
+                fcnt,
+            )
+            .map_err(frame_error)?;
+
+        Ok(DownlinkFrame {
+            confirmed: frame.confirmed,
+            fhdr: frame.fhdr,
+            fcnt,
+            f_port: frame.f_port,
+            payload,
+        })
+    }
 
-        // Add MIC
-        let mic = crypto::compute_mic(
-            &self.session.nwk_skey,
-            &buffer,
-            self.session.dev_addr,
-            self.session.fcnt_up,
-            Direction::Up,
-        );
-        buffer
-            .extend_from_slice(&mic)
-            .map_err(|_| MacError::BufferTooSmall)?;
+    /// Parse, verify and decrypt a downlink addressed to a multicast group
+    ///
+    /// As `parse_downlink` with the keys and frame counter of the group.
+    /// Frames that are not allowed as multicast downlinks fail with
+    /// `InvalidFrame`. Returns the index of the group with the frame.
+    fn parse_multicast(&self, data: &[u8]) -> Result<(usize, DownlinkFrame), MacError<R::Error>> {
+        let frame = match PhyPayload::parse(data).map_err(frame_error)? {
+            PhyPayload::DataDown(frame) => frame,
+            _ => return Err(MacError::InvalidFrame),
+        };
+        let (index, group) = self
+            .multicast_groups
+            .iter()
+            .enumerate()
+            .find(|(_, group)| group.addr == frame.fhdr.dev_addr)
+            .ok_or(MacError::InvalidAddress)?;
+        if !multicast::is_valid_multicast(&frame) {
+            return Err(MacError::InvalidFrame);
+        }
 
-        // Transmit
-        self.phy.transmit(&buffer).map_err(MacError::Radio)?;
+        let fcnt = self.reconstruct_fcnt(group.fcnt_down, frame.fhdr.f_cnt)?;
+        if !frame.verify_mic(self.crypto, &group.nwk_skey, fcnt) {
+            return Err(MacError::InvalidMic);
+        }
+        let payload = frame
+            .decrypt_payload(self.crypto, &group.nwk_skey, &group.app_skey, fcnt)
+            .map_err(frame_error)?;
+
+        Ok((
+            index,
+            DownlinkFrame {
+                confirmed: false,
+                fhdr: frame.fhdr,
+                fcnt,
+                f_port: frame.f_port,
+                payload,
+            },
+        ))
+    }
 
-        // Increment frame counter
-        self.session.fcnt_up = self.session.fcnt_up.wrapping_add(1);
+    /// Extend a received 16-bit FCnt to the full 32-bit downlink counter
+    ///
+    /// The counter must not be below the next expected value and may be at
+    /// most `max_fcnt_gap` ahead of it, so replayed frames are rejected.
+    fn reconstruct_fcnt_down(&self, f_cnt: u16) -> Result<u32, MacError<R::Error>> {
+        self.reconstruct_fcnt(self.session.fcnt_down, f_cnt)
+    }
+
+    /// Extend a received 16-bit FCnt to a full counter expected at `expected`
+    fn reconstruct_fcnt(&self, expected: u32, f_cnt: u16) -> Result<u32, MacError<R::Error>> {
+        let mut fcnt = (expected & 0xFFFF_0000) | f_cnt as u32;
+        if fcnt < expected {
+            // The 16-bit counter rolled over
+            fcnt = fcnt
+                .checked_add(0x1_0000)
+                .ok_or(MacError::InvalidFrameCounter)?;
+        }
+        if fcnt - expected > self.session.max_fcnt_gap {
+            return Err(MacError::InvalidFrameCounter);
+        }
+        Ok(fcnt)
+    }
+
+    /// Receive a downlink data frame and update the session
+    ///
+    /// On success the downlink frame counter advances past the frame and the
+    /// ADR backoff is reset. A confirmed downlink is acknowledged in the next
+    /// uplink and FPending is kept until the next uplink. MAC commands are
+    /// left to the caller. Frames for other devices, frames with an invalid
+    /// MIC and duplicates of accepted frames are counted and rejected
+    /// without changing the session.
+    pub fn receive_downlink(&mut self, data: &[u8]) -> Result<DownlinkFrame, MacError<R::Error>> {
+        let checked = self.check_downlink(data, false);
+        self.accept_downlink(checked)
+    }
+
+    /// Update the session with a checked downlink and count it
+    fn accept_downlink(
+        &mut self,
+        checked: Result<CheckedDownlink, MacError<R::Error>>,
+    ) -> Result<DownlinkFrame, MacError<R::Error>> {
+        let result = self.apply_downlink(checked);
+        let frame = result.map_err(|e| self.record_error(e))?;
+        self.stats.downlinks = self.stats.downlinks.wrapping_add(1);
+        Ok(frame)
+    }
+
+    /// Verify a downlink data frame without changing the session
+    ///
+    /// With `multicast`, frames addressed to a multicast group are verified
+    /// with its keys. Only borrows the MAC layer, so the frame can be in its
+    /// frame buffer.
+    fn check_downlink(
+        &self,
+        data: &[u8],
+        multicast: bool,
+    ) -> Result<CheckedDownlink, MacError<R::Error>> {
+        // Without a session there are no keys to verify the frame with
+        if !self.session.is_joined() {
+            return Err(MacError::NotJoined);
+        }
+        if self.is_duplicate_downlink(data) {
+            return Err(MacError::DuplicateFrame);
+        }
+        let (group, frame) = match self.parse_downlink(data) {
+            Err(MacError::InvalidAddress) if multicast => {
+                let (index, frame) = self.parse_multicast(data)?;
+                (Some(index), frame)
+            }
+            result => (None, result?),
+        };
+        let mut mic = [0u8; MIC_SIZE];
+        mic.copy_from_slice(&data[data.len() - MIC_SIZE..]);
+        Ok(CheckedDownlink { frame, mic, group })
+    }
+
+    /// Count a rejected downlink, or update the session with a verified one
+    fn apply_downlink(
+        &mut self,
+        checked: Result<CheckedDownlink, MacError<R::Error>>,
+    ) -> Result<DownlinkFrame, MacError<R::Error>> {
+        let CheckedDownlink { frame, mic, group } = match checked {
+            Ok(checked) => checked,
+            Err(MacError::DuplicateFrame) => {
+                self.stats.duplicates_dropped = self.stats.duplicates_dropped.wrapping_add(1);
+                return Err(MacError::DuplicateFrame);
+            }
+            Err(MacError::InvalidAddress) => {
+                self.stats.frames_for_other_devices =
+                    self.stats.frames_for_other_devices.wrapping_add(1);
+                return Err(MacError::InvalidAddress);
+            }
+            Err(MacError::InvalidMic) => {
+                debug!("downlink dropped: invalid MIC");
+                self.stats.mic_failures = self.stats.mic_failures.wrapping_add(1);
+                return Err(MacError::InvalidMic);
+            }
+            Err(e) => return Err(e),
+        };
+        self.replay_cache
+            .record_downlink(frame.fhdr.dev_addr, frame.fcnt, mic);
+        if let Some(index) = group {
+            // Multicast downlinks leave the session of the device alone
+            self.multicast_groups[index].fcnt_down = frame.fcnt.wrapping_add(1);
+            return Ok(frame);
+        }
+        self.session.fcnt_down = frame.fcnt.wrapping_add(1);
+        self.reset_adr_ack_counter();
+        self.clear_sticky_commands();
+        if frame.confirmed {
+            self.pending_ack = true;
+        }
+        self.downlink_pending = frame.fhdr.f_ctrl.fpending;
+        Ok(frame)
+    }
+
+    /// Check if a downlink is a copy of one accepted before
+    fn is_duplicate_downlink(&self, data: &[u8]) -> bool {
+        match PhyPayload::parse(data) {
+            Ok(PhyPayload::DataDown(frame)) => {
+                self.replay_cache
+                    .is_duplicate(frame.fhdr.dev_addr, frame.fhdr.f_cnt, &frame.mic)
+            }
+            _ => false,
+        }
+    }
+
+    /// Add a multicast group, or replace the group with the same address
+    ///
+    /// Fails with `BufferTooSmall` once `MAX_MULTICAST_GROUPS` groups are set.
+    pub fn add_multicast_group(&mut self, group: MulticastGroup) -> Result<(), MacError<R::Error>> {
+        match self
+            .multicast_groups
+            .iter_mut()
+            .find(|existing| existing.addr == group.addr)
+        {
+            Some(existing) => *existing = group,
+            None => self
+                .multicast_groups
+                .push(group)
+                .map_err(|_| MacError::BufferTooSmall)?,
+        }
+        Ok(())
+    }
+
+    /// Remove the multicast group with address `addr` and return it
+    pub fn remove_multicast_group(&mut self, addr: DevAddr) -> Option<MulticastGroup> {
+        let index = self
+            .multicast_groups
+            .iter()
+            .position(|group| group.addr == addr)?;
+        Some(self.multicast_groups.remove(index))
+    }
+
+    /// Get the multicast groups of the device
+    pub fn multicast_groups(&self) -> &[MulticastGroup] {
+        &self.multicast_groups
+    }
+
+    /// Check if the network has more downlinks queued for the device
+    ///
+    /// Set by the FPending bit of the last downlink. The device should send
+    /// an uplink soon, possibly empty, to open receive windows for them.
+    pub fn downlink_pending(&self) -> bool {
+        self.downlink_pending
+    }
+
+    /// Drop the sticky answers once the network has answered with a downlink
+    fn clear_sticky_commands(&mut self) {
+        let remaining: Vec<MacCommand, MAX_MAC_COMMANDS> = self
+            .pending_commands
+            .iter()
+            .filter(|command| !command.is_sticky())
+            .cloned()
+            .collect();
+        self.pending_commands = remaining;
+    }
+
+    /// Check if the next uplink must acknowledge a confirmed downlink
+    pub fn has_pending_ack(&self) -> bool {
+        self.pending_ack
+    }
+
+    /// Get the number of downlinks addressed to other devices
+    pub fn get_frames_for_other_devices(&self) -> u32 {
+        self.stats.frames_for_other_devices
+    }
+
+    /// Get the number of downlinks rejected because of an invalid MIC
+    pub fn get_mic_failures(&self) -> u32 {
+        self.stats.mic_failures
+    }
+
+    /// Get the counters of the dropped downlinks
+    pub fn get_stats(&self) -> MacStats {
+        MacStats {
+            frames_for_other_devices: self.stats.frames_for_other_devices,
+            mic_failures: self.stats.mic_failures,
+            duplicates_dropped: self.stats.duplicates_dropped,
+        }
+    }
+
+    /// Get the link statistics since the last reset
+    pub fn link_stats(&self) -> LinkStats {
+        let elapsed = self.get_time().wrapping_sub(self.stats_since);
+        let duty_cycle_permille = match elapsed {
+            0 => 0,
+            elapsed => (self.stats.airtime_ms as u64 * 1000 / elapsed as u64).min(1000) as u16,
+        };
+        LinkStats {
+            last_rssi: self.last_rssi,
+            last_snr: self.last_snr,
+            duty_cycle_permille,
+            ..self.stats
+        }
+    }
+
+    /// Reset the link statistics
+    pub fn reset_link_stats(&mut self) {
+        self.stats = LinkStats::default();
+        self.stats_since = self.get_time();
+    }
+
+    /// Keep an error as the last error of the link statistics
+    fn record_error(&mut self, error: MacError<R::Error>) -> MacError<R::Error> {
+        self.stats.last_error = Some(error.kind());
+        error
+    }
+
+    /// Get the duplicate and replay detection cache
+    pub fn get_replay_cache(&self) -> &ReplayCache {
+        &self.replay_cache
+    }
+
+    /// Get mutable access to the duplicate and replay detection cache
+    pub fn get_replay_cache_mut(&mut self) -> &mut ReplayCache {
+        &mut self.replay_cache
+    }
+
+    /// Ask the network for a link check in the next uplink
+    pub fn request_link_check(&mut self) -> Result<(), MacError<R::Error>> {
+        self.queue_mac_command(MacCommand::LinkCheckReq)
+    }
+
+    /// Ask the network for the GPS time in the next uplink
+    pub fn request_device_time(&mut self) -> Result<(), MacError<R::Error>> {
+        self.queue_mac_command(MacCommand::DeviceTimeReq)
+    }
+
+    /// Get the network time
+    pub fn get_network_time(&self) -> &NetworkTime {
+        &self.network_time
+    }
+
+    /// Get the network time mutably
+    pub fn get_network_time_mut(&mut self) -> &mut NetworkTime {
+        &mut self.network_time
+    }
+
+    /// Tell the network the Class B ping slot periodicity in the next uplink
+    pub fn request_ping_slot_info(&mut self, periodicity: u8) -> Result<(), MacError<R::Error>> {
+        self.queue_mac_command(MacCommand::PingSlotInfoReq {
+            periodicity: periodicity.min(7),
+        })
+    }
+
+    /// Get the Class B ping slot settings
+    pub fn get_ping_slot_config(&self) -> &PingSlotConfig {
+        &self.ping_slot_config
+    }
+
+    /// Get the Class B ping slot settings mutably
+    pub fn get_ping_slot_config_mut(&mut self) -> &mut PingSlotConfig {
+        &mut self.ping_slot_config
+    }
+
+    /// Get the beacon frequency set by the network, if any
+    pub fn get_beacon_frequency(&self) -> Option<u32> {
+        self.beacon_frequency
+    }
+
+    /// Get the last answer to a LinkCheckReq
+    pub fn last_link_check(&self) -> Option<LinkCheckResult> {
+        self.last_link_check
+    }
+
+    /// Get the aggregated duty cycle limit set by DutyCycleReq
+    pub fn get_max_duty_cycle(&self) -> u8 {
+        self.duty_cycle.max_duty_cycle()
+    }
+
+    /// Time in ms until an uplink is allowed on any enabled channel
+    pub fn time_until_next_tx(&self) -> u32 {
+        let now = self.get_time();
+        self.region
+            .enabled_channels()
+            .map(|c| self.channel_wait(c, now))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Set the time-on-air allowed per period, e.g. the fair-use policy of a network
+    ///
+    /// `None` removes the budget.
+    pub fn set_airtime_budget(&mut self, budget: Option<AirtimeBudget>) {
+        self.airtime_budget = budget;
+    }
+
+    /// Get the airtime budget, if any
+    pub fn airtime_budget(&self) -> Option<&AirtimeBudget> {
+        self.airtime_budget.as_ref()
+    }
+
+    /// Time in ms until an uplink with `payload_len` bytes of application data is allowed
+    ///
+    /// Combines the duty cycle of the enabled channels and the airtime budget
+    /// for the time-on-air of the frame at the current data rate. Fails if
+    /// the payload does not fit at that data rate next to the queued MAC
+    /// commands sent in FOpts.
+    pub fn time_until_uplink(&self, payload_len: usize) -> Result<u32, MacError<R::Error>> {
+        let data_rate = self.uplink_data_rate()?;
+        let fopts_len = self.pending_fopts().0.len();
+        let max_size = self.max_frm_payload(fopts_len);
+        if payload_len > max_size {
+            return Err(MacError::InvalidPayloadSize { max_size });
+        }
+
+        // MHDR, FHDR, FPort, FRMPayload and MIC
+        let frame_len = 1 + MIN_FHDR_SIZE + fopts_len + 1 + payload_len + MIC_SIZE;
+        let budget_wait = self.airtime_budget.as_ref().map_or(0, |budget| {
+            budget.time_until_tx(data_rate.time_on_air_ms(frame_len), self.get_time())
+        });
+        Ok(self.time_until_next_tx().max(budget_wait))
+    }
+
+    /// Time in ms until the duty cycle allows an uplink on a channel
+    fn channel_wait(&self, channel: &Channel, now: u32) -> u32 {
+        let band = self.region.band_index(channel.frequency);
+        self.duty_cycle.time_until_tx(band, now)
+    }
+
+    /// Extract MAC commands
+    pub fn extract_mac_commands(
+        &self,
+        payload: &[u8],
+    ) -> Option<Vec<MacCommand, MAX_MAC_COMMANDS>> {
+        let mut commands = Vec::new();
+        let mut rest = payload;
+        while let Some((&cid, args)) = rest.split_first() {
+            let cmd = MacCommand::from_bytes(cid, args)?;
+            rest = args.get(cmd.len()..)?;
+            commands.push(cmd).ok()?;
+        }
+        Some(commands)
+    }
+
+    /// Get MAC commands queued for the next uplink
+    pub fn get_pending_commands(&self) -> &[MacCommand] {
+        &self.pending_commands
+    }
+
+    /// Queue MAC command
+    pub fn queue_mac_command(&mut self, command: MacCommand) -> Result<(), MacError<R::Error>> {
+        self.pending_commands
+            .push(command)
+            .map_err(|_| MacError::BufferTooSmall)
+    }
+
+    /// Increment frame counter down
+    pub fn increment_frame_counter_down(&mut self) {
+        self.session.fcnt_down = self.session.fcnt_down.wrapping_add(1);
+    }
+
+    /// Receive data
+    pub fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, MacError<R::Error>> {
+        let result = self.phy.receive(buffer);
+        self.finish_receive(result)
+    }
+
+    /// Receive into the frame buffer of the MAC layer
+    ///
+    /// Returns the length of the frame, handled with
+    /// `handle_received_downlink` or `process_received_join_accept`. The
+    /// device classes share this buffer instead of each keeping one.
+    pub fn receive_frame(&mut self) -> Result<usize, MacError<R::Error>> {
+        let result = self.phy.receive(&mut self.rx_buffer);
+        self.finish_receive(result)
+    }
+
+    /// Count a failed reception, or keep the signal quality of a frame
+    fn finish_receive(
+        &mut self,
+        result: Result<usize, R::Error>,
+    ) -> Result<usize, MacError<R::Error>> {
+        let len = match result {
+            Ok(len) => len,
+            Err(e) => {
+                self.stats.rx_errors = self.stats.rx_errors.wrapping_add(1);
+                return Err(self.record_error(MacError::Radio(e)));
+            }
+        };
+        if len > 0 {
+            self.last_snr = Some(self.phy.radio.get_snr().map_err(MacError::Radio)?);
+            self.last_rssi = Some(self.phy.radio.get_rssi().map_err(MacError::Radio)?);
+        }
+        Ok(len)
+    }
+
+    /// Get the SNR of the last received frame in dB
+    pub fn get_last_snr(&self) -> Option<i8> {
+        self.last_snr
+    }
+
+    /// Get the RSSI of the last received frame in dBm
+    pub fn get_last_rssi(&self) -> Option<i16> {
+        self.last_rssi
+    }
+
+    /// Set the provider of the battery level reported in DevStatusAns
+    ///
+    /// Without a provider 255, level not measured, is reported.
+    pub fn set_status_provider(&mut self, provider: &'static mut dyn StatusProvider) {
+        self.status_provider = Some(provider);
+    }
+
+    /// Read the battery level from the provider and update the power state
+    ///
+    /// Without a provider 255 is returned and the power state set by the
+    /// application is kept.
+    fn update_battery_level(&mut self) -> u8 {
+        let Some(provider) = self.status_provider.as_mut() else {
+            return 255;
+        };
+        let level = provider.battery_level();
+        self.power.update_battery(level);
+        level
+    }
+
+    /// Get the power manager tracking TX, RX and sleep time
+    pub fn power_manager(&self) -> &PowerManager {
+        &self.power
+    }
+
+    /// Get mutable access to the power manager
+    pub fn power_manager_mut(&mut self) -> &mut PowerManager {
+        &mut self.power
+    }
+
+    /// Put the radio to sleep
+    ///
+    /// The time until the next transmission or receive window is recorded
+    /// as sleep time.
+    pub fn sleep(&mut self) -> Result<(), MacError<R::Error>> {
+        self.phy.radio.sleep().map_err(MacError::Radio)?;
+        self.sleep_since = Some(self.get_time());
+        Ok(())
+    }
+
+    /// Record the time slept since `sleep`, if the radio was sleeping
+    fn wake(&mut self) {
+        if let Some(since) = self.sleep_since.take() {
+            let slept = self.get_time().wrapping_sub(since);
+            self.power.record_sleep(Duration::from_millis(slept as u64));
+        }
+    }
+
+    /// Set the storage that keeps the last DevNonce across reboots
+    pub fn set_dev_nonce_store(&mut self, store: &'static dyn DevNonceStore) {
+        self.dev_nonce_store = Some(store);
+    }
+
+    /// Draw DevNonces from the radio RNG instead of counting them up
+    ///
+    /// Only used without a DevNonce store. A RAM counter starts over at every
+    /// reboot, random DevNonces make a reuse after a reboot unlikely but may
+    /// repeat an earlier one.
+    pub fn set_random_dev_nonces(&mut self, random: bool) {
+        self.random_dev_nonces = random;
+    }
+
+    /// Set the storage that keeps the frame counters across reboots
+    ///
+    /// The counters are saved every `save_interval` uplinks. Counters found
+    /// in the store are loaded right away, skipping the uplinks that may have
+    /// been sent after the last save, so set the store before the first
+    /// uplink.
+    pub fn set_frame_counter_store(
+        &mut self,
+        store: &'static dyn FrameCounterStore,
+        save_interval: u32,
+    ) {
+        let save_interval = save_interval.max(1);
+        if let Some((fcnt_up, fcnt_down)) = store.load() {
+            let fcnt_up = fcnt_up.saturating_add(save_interval - 1);
+            self.session.fcnt_up = self.session.fcnt_up.max(fcnt_up);
+            self.session.fcnt_down = self.session.fcnt_down.max(fcnt_down);
+        }
+        self.fcnt_saved = self.session.fcnt_up;
+        self.fcnt_store = Some((store, save_interval));
+    }
+
+    /// Set the uplink and downlink frame counters, e.g. restored by the application
+    pub fn set_frame_counters(&mut self, fcnt_up: u32, fcnt_down: u32) {
+        self.session.fcnt_up = fcnt_up;
+        self.session.fcnt_down = fcnt_down;
+        self.fcnt_saved = fcnt_up;
+    }
+
+    /// Save the frame counters once `save_interval` uplinks were sent
+    fn save_frame_counters(&mut self) {
+        let Some((store, save_interval)) = self.fcnt_store else {
+            return;
+        };
+        let fcnt_up = self.session.fcnt_up;
+        // A failed save is retried after the next uplink
+        if fcnt_up.wrapping_sub(self.fcnt_saved) >= save_interval
+            && store.save(fcnt_up, self.session.fcnt_down)
+        {
+            self.fcnt_saved = fcnt_up;
+        }
+    }
+
+    /// Process the MAC commands of a downlink
+    ///
+    /// Contiguous LinkADRReq commands form a block that is applied
+    /// atomically, all other commands are processed one by one.
+    ///
+    /// Each command is handled on its own: invalid parameters are answered
+    /// with a NACK where the answer has status bits, and a command that fails
+    /// otherwise, e.g. because the answer queue is full, is skipped without
+    /// undoing the commands before it. Only radio errors abort the processing.
+    pub fn process_mac_commands(
+        &mut self,
+        commands: &[MacCommand],
+    ) -> Result<(), MacError<R::Error>> {
+        let mut i = 0;
+        while i < commands.len() {
+            let block_len = commands[i..]
+                .iter()
+                .take_while(|c| matches!(c, MacCommand::LinkADRReq { .. }))
+                .count();
+
+            let cid = commands[i].cid();
+            let (result, processed) = if block_len > 0 {
+                let block = &commands[i..i + block_len];
+                (self.process_link_adr_block(block), block_len)
+            } else {
+                (self.process_mac_command(commands[i].clone()), 1)
+            };
+            match result {
+                Ok(()) => debug!(
+                    "MAC command processed: CID {=u8:#04x} x{=usize}",
+                    cid, processed
+                ),
+                Err(MacError::Radio(e)) => return Err(MacError::Radio(e)),
+                Err(_) => debug!("MAC command skipped: CID {=u8:#04x}", cid),
+            }
+            i += processed;
+        }
+        Ok(())
+    }
+
+    /// Process a block of LinkADRReq commands
+    ///
+    /// The channel masks are applied in order, while the data rate, TX power
+    /// and NbTrans are taken from the last command. The block is only applied
+    /// if every part of it is accepted, and each command is answered with the
+    /// same LinkADRAns.
+    fn process_link_adr_block(&mut self, block: &[MacCommand]) -> Result<(), MacError<R::Error>> {
+        let mut region = self.region.clone();
+        let mut channel_mask_ack = true;
+        let mut last = None;
+
+        for command in block {
+            if let MacCommand::LinkADRReq {
+                data_rate,
+                tx_power,
+                ch_mask,
+                ch_mask_cntl,
+                nb_trans,
+            } = *command
+            {
+                if region.is_valid_channel_mask(ch_mask, ch_mask_cntl) {
+                    region.apply_channel_mask(ch_mask, ch_mask_cntl);
+                } else {
+                    channel_mask_ack = false;
+                }
+                last = Some((data_rate, tx_power, nb_trans));
+            }
+        }
+
+        let Some((data_rate, tx_power, nb_trans)) = last else {
+            return Ok(());
+        };
+
+        // A mask that disables every channel is rejected
+        if region.enabled_channels().next().is_none() {
+            channel_mask_ack = false;
+        }
+
+        // The data rate must lie in the range of at least one enabled channel
+        let data_rate_ack = region.is_valid_data_rate(data_rate)
+            && region.enabled_channels().any(|c| {
+                let min_dr = region.data_rate_index(c.min_dr);
+                let max_dr = region.data_rate_index(c.max_dr);
+                matches!((min_dr, max_dr), (Some(min), Some(max)) if (min..=max).contains(&data_rate))
+            });
+        let power_ack = region.is_valid_tx_power(tx_power);
+
+        if power_ack && data_rate_ack && channel_mask_ack {
+            region.set_data_rate(data_rate);
+            region.set_tx_power(tx_power);
+            self.region = region;
+
+            // NbTrans 0 keeps the current setting
+            if nb_trans > 0 {
+                self.nb_trans = nb_trans;
+            }
+        }
 
+        for _ in block {
+            self.queue_mac_command(MacCommand::LinkADRAns {
+                power_ack,
+                data_rate_ack,
+                channel_mask_ack,
+            })?;
+        }
         Ok(())
     }
 
-    /// Decrypt payload
-    pub fn decrypt_payload(
-        &self,
-        data: &[u8],
-    ) -> Result<Vec<u8, MAX_MAC_PAYLOAD>, MacError<R::Error>> {
-        if data.len() < MIC_SIZE {
-            return Err(MacError::InvalidLength);
-        }
+    /// Get the number of transmissions of each uplink
+    pub fn get_nb_trans(&self) -> u8 {
+        self.nb_trans
+    }
 
-        let payload = &data[..data.len() - MIC_SIZE];
-        let mic = &data[data.len() - MIC_SIZE..];
+    /// Set the maximum output power of the radio in dBm
+    pub fn set_max_tx_power(&mut self, power: i8) {
+        self.phy.config.max_tx_power = power;
+    }
 
-        // Verify MIC
-        let computed_mic = crypto::compute_mic(
-            &self.session.nwk_skey,
-            payload,
-            self.session.dev_addr,
-            self.session.fcnt_down,
-            Direction::Down,
-        );
-        if mic != computed_mic {
-            return Err(MacError::InvalidMic);
-        }
+    /// Set the antenna gain in dBi
+    ///
+    /// TX power indexes set the EIRP, the radio output power is lowered by
+    /// the gain to stay within the limits of the region.
+    pub fn set_antenna_gain(&mut self, gain: i8) {
+        self.phy.config.antenna_gain = gain;
+    }
 
-        // Decrypt payload
-        let decrypted = crypto::encrypt_payload(
-            &self.session.app_skey,
-            self.session.dev_addr,
-            self.session.fcnt_down,
-            Direction::Down,
-            payload,
-        );
+    /// Set the network type, selecting the sync word of all packets
+    pub fn set_network_type(&mut self, network: NetworkType) {
+        self.phy.config.network = network;
+    }
 
-        let mut result = Vec::new();
-        result
-            .extend_from_slice(&decrypted)
-            .map_err(|_| MacError::BufferTooSmall)?;
-        Ok(result)
+    /// Get the network type
+    pub fn network_type(&self) -> NetworkType {
+        self.phy.config.network
     }
 
-    /// Extract MAC commands
-    pub fn extract_mac_commands(
-        &self,
-        payload: &[u8],
-    ) -> Option<Vec<MacCommand, MAX_MAC_COMMANDS>> {
-        let mut commands = Vec::new();
-        let mut i = 0;
-        while i < payload.len() {
-            let cid = payload[i];
-            i += 1;
-            if let Some(cmd) = MacCommand::from_bytes(cid, &payload[i..]) {
-                commands.push(cmd.clone()).ok()?;
-                i += cmd.len();
-            } else {
-                return None;
-            }
-        }
-        Some(commands)
+    /// Set the uplink data rate
+    ///
+    /// With ADR enabled, LinkADRReq overrides it until ADR is disabled.
+    pub fn set_data_rate(&mut self, data_rate: DataRate) -> Result<(), MacError<R::Error>> {
+        let index = (0..16)
+            .find(|&index| {
+                self.region.is_valid_data_rate(index)
+                    && self.region.data_rate_from_index(index) == Some(data_rate)
+            })
+            .ok_or(MacError::InvalidDataRate)?;
+        self.region.set_data_rate(index);
+        self.manual_tx_params.0 = Some(index);
+        Ok(())
     }
 
-    /// Queue MAC command
-    pub fn queue_mac_command(&mut self, command: MacCommand) -> Result<(), MacError<R::Error>> {
-        self.pending_commands
-            .push(command)
-            .map_err(|_| MacError::BufferTooSmall)
+    /// Get the data rate of the next uplink
+    pub fn data_rate(&self) -> Option<DataRate> {
+        self.region.data_rate_from_index(self.region.data_rate())
     }
 
-    /// Increment frame counter down
-    pub fn increment_frame_counter_down(&mut self) {
-        self.session.fcnt_down = self.session.fcnt_down.wrapping_add(1);
+    /// Set the uplink output power in dBm
+    ///
+    /// The power is rounded down to a TX power step of the region and must
+    /// not exceed its maximum EIRP. With ADR enabled, LinkADRReq overrides
+    /// it until ADR is disabled.
+    ///
+    /// A power the radio cannot output, after the antenna gain, is clamped
+    /// to the range of `Radio::capabilities`. The clamped power is applied
+    /// and reported with `MacError::TxPowerClamped`.
+    pub fn set_tx_power_dbm(&mut self, power: i8) -> Result<(), MacError<R::Error>> {
+        if power > self.region.max_eirp() as i8 {
+            return Err(MacError::InvalidValue);
+        }
+        let gain = self.phy.config.antenna_gain;
+        let clamped = self
+            .phy
+            .radio
+            .capabilities()
+            .clamp_power(power.saturating_sub(gain))
+            .saturating_add(gain);
+        let index = (0..16)
+            .find(|&index| {
+                self.region.is_valid_tx_power(index) && self.region.tx_power_dbm(index) <= clamped
+            })
+            .ok_or(MacError::InvalidValue)?;
+        self.region.set_tx_power(index);
+        self.manual_tx_params.1 = Some(index);
+        if clamped != power {
+            return Err(MacError::TxPowerClamped {
+                applied_dbm: self.region.tx_power_dbm(index),
+            });
+        }
+        Ok(())
     }
 
-    /// Receive data
-    pub fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, MacError<R::Error>> {
-        self.phy.receive(buffer).map_err(MacError::Radio)
+    /// Get the output power in dBm of the next uplink
+    ///
+    /// The antenna gain is taken off the EIRP of the TX power index, and the
+    /// power limited to the maximum output power of the radio.
+    pub fn tx_power_dbm(&self) -> i8 {
+        self.phy
+            .output_power(self.region.tx_power_dbm(self.region.tx_power()))
     }
 
     /// Process MAC command
@@ -396,58 +1935,21 @@ impl<R: Radio, REG: Region> MacLayer<R, REG> {
         match command {
             MacCommand::LinkCheckReq => {
                 // Queue a link check request to be sent in the next uplink
-                self.queue_mac_command(MacCommand::LinkCheckReq)
+                self.request_link_check()
             }
             MacCommand::LinkCheckAns {
                 margin,
                 gateway_count,
             } => {
-                // Store link quality information for application use
-                // Margin is the link margin in dB of the last successful uplink
-                // Gateway count is the number of gateways that received the uplink
+                // Keep the link quality for the application
+                self.last_link_check = Some(LinkCheckResult {
+                    margin,
+                    gateway_count,
+                    received_at: self.get_time(),
+                });
                 Ok(())
             }
-            MacCommand::LinkADRReq {
-                data_rate,
-                tx_power,
-                ch_mask,
-                ch_mask_cntl,
-                nb_trans,
-            } => {
-                let mut power_ack = false;
-                let mut data_rate_ack = false;
-                let mut channel_mask_ack = false;
-
-                // Validate and set TX power if in valid range
-                if self.region.is_valid_tx_power(tx_power) {
-                    self.region.set_tx_power(tx_power);
-                    power_ack = true;
-                }
-
-                // Validate and set data rate if supported
-                if self.region.is_valid_data_rate(data_rate) {
-                    self.region.set_data_rate(data_rate);
-                    data_rate_ack = true;
-                }
-
-                // Apply channel mask if valid
-                if self.region.is_valid_channel_mask(ch_mask, ch_mask_cntl) {
-                    self.region.apply_channel_mask(ch_mask, ch_mask_cntl);
-                    channel_mask_ack = true;
-                }
-
-                // Set number of transmissions if specified
-                if nb_trans > 0 {
-                    // Store nb_trans for future uplinks
-                }
-
-                // Queue acknowledgment
-                self.queue_mac_command(MacCommand::LinkADRAns {
-                    power_ack,
-                    data_rate_ack,
-                    channel_mask_ack,
-                })
-            }
+            MacCommand::LinkADRReq { .. } => self.process_link_adr_block(&[command]),
             MacCommand::LinkADRAns {
                 power_ack,
                 data_rate_ack,
@@ -462,13 +1964,10 @@ impl<R: Radio, REG: Region> MacLayer<R, REG> {
                 }
             }
             MacCommand::DutyCycleReq { max_duty_cycle } => {
-                // Set the maximum duty cycle
-                // max_duty_cycle = 0 means no duty cycle limitation
-                // max_duty_cycle = 1 means 1/1 duty cycle (100%)
-                // max_duty_cycle = 2 means 1/2 duty cycle (50%)
-                // max_duty_cycle = 16 means 1/16 duty cycle (6.25%)
+                // The aggregated duty cycle is limited to 1/2^max_duty_cycle,
+                // 0 means no limitation
                 if max_duty_cycle <= 15 {
-                    // Store duty cycle for future transmissions
+                    self.duty_cycle.set_max_duty_cycle(max_duty_cycle);
                     self.queue_mac_command(MacCommand::DutyCycleAns)
                 } else {
                     Err(MacError::InvalidValue)
@@ -483,26 +1982,14 @@ impl<R: Radio, REG: Region> MacLayer<R, REG> {
                 rx2_data_rate,
                 freq,
             } => {
-                let mut rx1_dr_offset_ack = false;
-                let mut rx2_data_rate_ack = false;
-                let mut channel_ack = false;
-
-                // Validate RX1 data rate offset
-                if rx1_dr_offset <= 5 {
-                    // Store RX1 data rate offset
-                    rx1_dr_offset_ack = true;
-                }
-
-                // Validate RX2 data rate
-                if self.region.is_valid_data_rate(rx2_data_rate) {
-                    // Store RX2 data rate
-                    rx2_data_rate_ack = true;
-                }
+                let rx1_dr_offset_ack = rx1_dr_offset <= 5;
+                let rx2_data_rate_ack = self.region.is_valid_data_rate(rx2_data_rate);
+                let channel_ack = self.region.is_valid_frequency(freq);
 
-                // Validate frequency
-                if self.region.is_valid_frequency(freq) {
-                    // Store RX2 frequency
-                    channel_ack = true;
+                // The settings are only applied if all of them are valid
+                if rx1_dr_offset_ack && rx2_data_rate_ack && channel_ack {
+                    self.session.rx1_dr_offset = rx1_dr_offset;
+                    self.set_rx2_params(freq, rx2_data_rate);
                 }
 
                 // Queue acknowledgment
@@ -525,13 +2012,11 @@ impl<R: Radio, REG: Region> MacLayer<R, REG> {
                 }
             }
             MacCommand::DevStatusReq => {
-                // Queue device status response with battery and margin information
-                // Battery: 0 = external power, 1-254 = battery level, 255 = cannot measure
-                // Margin: SNR of last received DevStatusReq [-32,31]
-                self.queue_mac_command(MacCommand::DevStatusAns {
-                    battery: 0, // Example: external power
-                    margin: 0,  // Example: 0 dB margin
-                })
+                // Margin is the SNR of the downlink carrying the request,
+                // limited to the 6-bit range of the answer
+                let battery = self.update_battery_level();
+                let margin = self.last_snr.unwrap_or(0).clamp(-32, 31);
+                self.queue_mac_command(MacCommand::DevStatusAns { battery, margin })
             }
             MacCommand::DevStatusAns {
                 battery: _,
@@ -546,6 +2031,16 @@ impl<R: Radio, REG: Region> MacLayer<R, REG> {
                 min_dr,
                 max_dr,
             } => {
+                // A frequency of 0 disables the channel, which is refused for
+                // the default channels and in regions with a fixed channel plan
+                if freq == 0 {
+                    let removed = self.region.remove_channel(ch_index);
+                    return self.queue_mac_command(MacCommand::NewChannelAns {
+                        channel_freq_ok: removed,
+                        data_rate_ok: removed,
+                    });
+                }
+
                 let mut channel_freq_ok = false;
                 let mut data_rate_ok = false;
 
@@ -564,9 +2059,14 @@ impl<R: Radio, REG: Region> MacLayer<R, REG> {
 
                 // If valid, create new channel
                 if channel_freq_ok && data_rate_ok {
-                    // Validate channel index
-                    if ch_index as usize >= self.region.get_max_channels() {
-                        return Err(MacError::InvalidChannel);
+                    // Indexes beyond the channels of the region and regions
+                    // with a fixed channel plan reject the channel
+                    if ch_index as usize >= self.region.get_max_channels()
+                        || !self
+                            .region
+                            .add_or_modify_channel(ch_index, freq, min_dr, max_dr)
+                    {
+                        channel_freq_ok = false;
                     }
                 }
 
@@ -593,7 +2093,8 @@ impl<R: Radio, REG: Region> MacLayer<R, REG> {
                 // delay = 1 means 1 second
                 // delay = 15 means 15 seconds
                 if delay <= 15 {
-                    // Store RX1 delay
+                    self.session.rx1_delay = delay.max(1);
+                    self.phy.config.timing.set_rx_delay(delay);
                     self.queue_mac_command(MacCommand::RXTimingSetupAns)
                 } else {
                     Err(MacError::InvalidValue)
@@ -608,19 +2109,15 @@ impl<R: Radio, REG: Region> MacLayer<R, REG> {
                 uplink_dwell_time,
                 max_eirp,
             } => {
-                // Set TX parameters
-                if max_eirp <= 15 {
-                    // Store dwell time settings locally
-                    let _dl_dwell = downlink_dwell_time;
-                    let _ul_dwell = uplink_dwell_time;
-
-                    // Convert max_eirp to dBm: 2 dBm steps starting from 8 dBm
-                    let eirp_dbm = 8 + (2 * max_eirp as u32);
-                    self.phy.radio.set_tx_power(eirp_dbm as i8)?;
-
+                // Only regions with dwell time limits (AS923) answer the
+                // command, everywhere else it is ignored
+                if self
+                    .region
+                    .set_tx_params(uplink_dwell_time, downlink_dwell_time, max_eirp)
+                {
                     self.queue_mac_command(MacCommand::TxParamSetupAns)
                 } else {
-                    Err(MacError::InvalidValue)
+                    Ok(())
                 }
             }
             MacCommand::TxParamSetupAns => {
@@ -631,8 +2128,9 @@ impl<R: Radio, REG: Region> MacLayer<R, REG> {
                 let mut channel_freq_ok = false;
                 let mut uplink_freq_exists = false;
 
-                // Validate frequency
-                if self.region.is_valid_frequency(freq) {
+                // Validate frequency, 0 removes the downlink frequency of the
+                // channel so RX1 uses the uplink frequency again
+                if freq == 0 || self.region.is_valid_frequency(freq) {
                     channel_freq_ok = true;
                 }
 
@@ -645,7 +2143,10 @@ impl<R: Radio, REG: Region> MacLayer<R, REG> {
 
                 // If valid, update downlink frequency
                 if channel_freq_ok && uplink_freq_exists {
-                    // Update channel downlink frequency
+                    let frequency = (freq != 0).then_some(freq);
+                    if !self.region.set_downlink_frequency(ch_index, frequency) {
+                        channel_freq_ok = false;
+                    }
                 }
 
                 // Queue acknowledgment
@@ -665,72 +2166,405 @@ impl<R: Radio, REG: Region> MacLayer<R, REG> {
                     Err(MacError::InvalidValue)
                 }
             }
+            MacCommand::DeviceTimeReq => self.request_device_time(),
+            MacCommand::DeviceTimeAns { seconds, fraction } => {
+                // The time refers to the end of the uplink carrying the request
+                let gps_time_ms = seconds as u64 * 1_000 + (fraction as u64 * 1_000) / 256;
+                self.network_time
+                    .sync_gps_time(gps_time_ms, self.last_tx_end);
+                Ok(())
+            }
+            MacCommand::PingSlotInfoReq { periodicity } => self.request_ping_slot_info(periodicity),
+            MacCommand::PingSlotInfoAns => {
+                // Acknowledgment of the ping slot periodicity
+                Ok(())
+            }
+            MacCommand::PingSlotChannelReq { freq, data_rate } => {
+                // A frequency of 0 selects the default ping slot frequency
+                let channel_freq_ok = freq == 0 || self.region.is_valid_frequency(freq);
+                let data_rate_ok = self.region.data_rate_from_index(data_rate).is_some();
+
+                // The settings are only applied if both of them are valid
+                if channel_freq_ok && data_rate_ok {
+                    self.ping_slot_config.set_channel(freq, data_rate);
+                }
+
+                self.queue_mac_command(MacCommand::PingSlotChannelAns {
+                    channel_freq_ok,
+                    data_rate_ok,
+                })
+            }
+            MacCommand::PingSlotChannelAns {
+                channel_freq_ok,
+                data_rate_ok,
+            } => {
+                // Process response about ping slot channel setup
+                if channel_freq_ok && data_rate_ok {
+                    Ok(())
+                } else {
+                    Err(MacError::InvalidValue)
+                }
+            }
+            MacCommand::BeaconFreqReq { freq } => {
+                // A frequency of 0 selects the default beacon channels
+                let beacon_freq_ok = freq == 0 || self.region.is_valid_frequency(freq);
+                if beacon_freq_ok {
+                    self.beacon_frequency = (freq != 0).then_some(freq);
+                }
+                self.queue_mac_command(MacCommand::BeaconFreqAns { beacon_freq_ok })
+            }
+            MacCommand::BeaconFreqAns { beacon_freq_ok } => {
+                // Process response about beacon frequency setup
+                if beacon_freq_ok {
+                    Ok(())
+                } else {
+                    Err(MacError::InvalidValue)
+                }
+            }
         }
     }
 
-    /// Join request
+    /// Send an OTAA join request
+    ///
+    /// Builds the JoinRequest PHYPayload (MHDR | AppEUI | DevEUI | DevNonce | MIC),
+    /// transmits it on the next join channel of the region and prepares the
+    /// radio for the join-accept window. The DevNonce used is retained so the
+    /// session keys can be derived once the join accept arrives.
+    ///
+    /// DevNonces count up from 0, one per request. Without a DevNonce store the
+    /// count lives in RAM and starts over at every reboot; with one it goes on
+    /// from the stored value, which is saved before the request is sent, and
+    /// no request is sent if that fails. No request is sent either once all
+    /// values have been used. `set_random_dev_nonces` draws them from the
+    /// radio RNG instead when there is no store.
     pub fn join_request(
         &mut self,
         dev_eui: [u8; 8],
         app_eui: [u8; 8],
         app_key: AESKey,
     ) -> Result<(), MacError<R::Error>> {
-        let mut buffer: Vec<u8, MAX_FRAME_SIZE> = Vec::new();
-
-        // Add MAC header (Join Request)
-        buffer.push(0x00).map_err(|_| MacError::BufferTooSmall)?;
-
-        // Add AppEUI (Little Endian)
-        buffer
-            .extend_from_slice(&app_eui)
-            .map_err(|_| MacError::BufferTooSmall)?;
-
-        // Add DevEUI (Little Endian)
-        buffer
-            .extend_from_slice(&dev_eui)
-            .map_err(|_| MacError::BufferTooSmall)?;
-
-        // Generate random DevNonce
-        let dev_nonce = {
-            let mut nonce = [0u8; 2];
-            // Use last channel as entropy source
-            let entropy = self
+        self.send_join_request(dev_eui, app_eui, app_key, None)
+    }
+
+    /// Send an OTAA join request at the data rate of index `data_rate`
+    ///
+    /// Only channels that can carry the data rate are used, e.g. the 500 kHz
+    /// channels for DR4 in US915. The data rate becomes the uplink data rate
+    /// of the region, which RX1 of the join accept is derived from.
+    pub fn join_request_at(
+        &mut self,
+        dev_eui: [u8; 8],
+        app_eui: [u8; 8],
+        app_key: AESKey,
+        data_rate: u8,
+    ) -> Result<(), MacError<R::Error>> {
+        let rate = self
+            .region
+            .data_rate_from_index(data_rate)
+            .ok_or(MacError::InvalidDataRate)?;
+        self.region.set_data_rate(data_rate);
+        self.send_join_request(dev_eui, app_eui, app_key, Some(rate))
+    }
+
+    /// Build and transmit a join request, at the most robust data rate of the
+    /// channel if `data_rate` is `None`
+    fn send_join_request(
+        &mut self,
+        dev_eui: [u8; 8],
+        app_eui: [u8; 8],
+        app_key: AESKey,
+        data_rate: Option<DataRate>,
+    ) -> Result<(), MacError<R::Error>> {
+        // Use a fresh DevNonce for every join attempt
+        let dev_nonce = self.next_dev_nonce()?;
+        let frame = frame::build_join_request(self.crypto, &app_key, app_eui, dev_eui, dev_nonce);
+
+        let channel = self.transmit_uplink(&frame, data_rate)?;
+        self.stats.join_attempts = self.stats.join_attempts.wrapping_add(1);
+
+        self.session.last_dev_nonce = Some(dev_nonce);
+        if !self.session.is_joined() {
+            self.session.activation_state = ActivationState::Joining;
+        }
+        self.join_key = Some(app_key);
+
+        // Configure RX1 window for join accept, which always uses RX1DROffset 0;
+        // the join accept delays only schedule when the window opens
+        let (rx1_freq, rx1_dr) = self.region.rx1_window(&channel, 0);
+        self.phy
+            .configure_rx::<REG>(rx1_freq, rx1_dr, rx_window_timeout(rx1_dr))
+            .map_err(MacError::Radio)?;
+
+        Ok(())
+    }
+
+    /// Transmit an uplink frame on the next channel of the region
+    ///
+    /// Channels that cannot carry the requested data rate are skipped. When
+    /// `data_rate` is `None` the most robust data rate of the channel is used.
+    /// In regions that mandate Listen-Before-Talk every channel is sensed
+    /// first and the transmission hops to the next channel while it is busy.
+    /// The output power follows the TX power index of the region. Returns the
+    /// channel the frame was sent on.
+    fn transmit_uplink(
+        &mut self,
+        frame: &[u8],
+        data_rate: Option<DataRate>,
+    ) -> Result<Channel, MacError<R::Error>> {
+        let result = self.transmit_on_next_channel(frame, data_rate);
+        result.map_err(|e| self.record_error(e))
+    }
+
+    /// Transmit an uplink frame on the next usable channel
+    fn transmit_on_next_channel(
+        &mut self,
+        frame: &[u8],
+        data_rate: Option<DataRate>,
+    ) -> Result<Channel, MacError<R::Error>> {
+        self.wake();
+        let lbt = self.region.lbt_config();
+        let now = self.get_time();
+        let mut busy_channels = 0;
+        let mut skipped_channels = 0;
+        let mut retry_after_ms: Option<u32> = None;
+
+        loop {
+            let channel = self
                 .region
                 .get_next_channel()
-                .map(|c| c.frequency)
-                .unwrap_or(0);
-            nonce[0] = (entropy & 0xFF) as u8;
-            nonce[1] = ((entropy >> 8) & 0xFF) as u8;
-            nonce
+                .ok_or(MacError::InvalidChannel)?;
+            let data_rate = data_rate.unwrap_or(channel.min_dr);
+
+            if let Some(budget) = &self.airtime_budget {
+                let airtime_ms = data_rate.time_on_air_ms(frame.len());
+                let retry_after_ms = budget.time_until_tx(airtime_ms, now);
+                if retry_after_ms > 0 {
+                    return Err(MacError::DutyCycleLimited { retry_after_ms });
+                }
+            }
+
+            // Skip channels that cannot carry the data rate or whose band has
+            // used up its duty cycle budget
+            let wait = self.channel_wait(&channel, now);
+            if data_rate.bandwidth() != channel.min_dr.bandwidth() || wait > 0 {
+                if wait > 0 {
+                    retry_after_ms = Some(retry_after_ms.map_or(wait, |r| r.min(wait)));
+                }
+                skipped_channels += 1;
+                if skipped_channels > self.region.channels() {
+                    return Err(match retry_after_ms {
+                        Some(retry_after_ms) => MacError::DutyCycleLimited { retry_after_ms },
+                        None => MacError::InvalidChannel,
+                    });
+                }
+                continue;
+            }
+
+            if let Some(lbt) = &lbt {
+                if !self
+                    .phy
+                    .is_channel_free(&channel, data_rate, lbt)
+                    .map_err(MacError::Radio)?
+                {
+                    busy_channels += 1;
+                    if busy_channels > lbt.max_retries {
+                        return Err(MacError::ChannelBusy);
+                    }
+                    continue;
+                }
+            }
+
+            let power = self.region.tx_power_dbm(self.region.tx_power());
+            self.phy
+                .configure_tx::<REG>(&channel, data_rate, power)
+                .map_err(MacError::Radio)?;
+            self.phy.transmit(frame).map_err(MacError::Radio)?;
+            self.last_uplink_channel = Some(channel.clone());
+            self.last_tx_end = self.get_time();
+
+            let band = self
+                .region
+                .band_index(channel.frequency)
+                .map(|index| (index, &self.region.bands()[index]));
+            let airtime_ms = data_rate.time_on_air_ms(frame.len());
+            self.duty_cycle
+                .record_tx(band, airtime_ms, self.last_tx_end);
+            if let Some(budget) = &mut self.airtime_budget {
+                budget.record_tx(airtime_ms, self.last_tx_end);
+            }
+            self.region.record_airtime(channel.frequency, airtime_ms);
+            self.stats.uplinks = self.stats.uplinks.wrapping_add(1);
+            self.stats.airtime_ms = self.stats.airtime_ms.saturating_add(airtime_ms);
+            self.power
+                .record_tx(Duration::from_millis(airtime_ms as u64));
+            self.update_battery_level();
+            return Ok(channel);
+        }
+    }
+
+    /// Transmit a frame outside of LoRaWAN, e.g. to a point-to-point peer
+    ///
+    /// `data` is sent as is with `config`; the session, frame counters,
+    /// pending ACK and receive window timing are untouched. The airtime
+    /// counts toward the duty cycle of the band of `config.frequency`, so
+    /// this fails with `DutyCycleLimited` while the band is silent, and
+    /// uplinks wait for the off-time of the raw frame. The airtime budget,
+    /// a network policy, only counts LoRaWAN uplinks.
+    pub fn transmit_raw(
+        &mut self,
+        data: &[u8],
+        config: TxConfig,
+    ) -> Result<(), MacError<R::Error>> {
+        self.wake();
+        let now = self.get_time();
+        let band_index = self.region.band_index(config.frequency);
+        let retry_after_ms = self.duty_cycle.time_until_tx(band_index, now);
+        if retry_after_ms > 0 {
+            return Err(MacError::DutyCycleLimited { retry_after_ms });
+        }
+
+        let radio = &mut self.phy.radio;
+        radio.configure_tx(config).map_err(MacError::Radio)?;
+        radio.transmit(data).map_err(MacError::Radio)?;
+
+        let end = self.get_time();
+        let band = band_index.map(|index| (index, &self.region.bands()[index]));
+        let airtime_ms = phy::time_on_air(data.len(), &config.modulation);
+        self.duty_cycle.record_tx(band, airtime_ms, end);
+        self.region.record_airtime(config.frequency, airtime_ms);
+        self.power
+            .record_tx(Duration::from_millis(airtime_ms as u64));
+        Ok(())
+    }
+
+    /// Receive a frame outside of LoRaWAN with `config`
+    ///
+    /// The frame goes to `buffer` without being parsed, the session is
+    /// untouched. Returns the length of the frame, 0 if none was received.
+    pub fn receive_raw(
+        &mut self,
+        buffer: &mut [u8],
+        config: RxConfig,
+    ) -> Result<usize, MacError<R::Error>> {
+        self.wake();
+        let radio = &mut self.phy.radio;
+        radio.configure_rx(config).map_err(MacError::Radio)?;
+        radio.receive(buffer).map_err(MacError::Radio)
+    }
+
+    /// Get the next unused DevNonce and save it to the store
+    ///
+    /// DevNonces are counted up from 0, in RAM and in the store if there is
+    /// one, so none is reused across reboots. Without a store they are drawn
+    /// from the radio RNG if `set_random_dev_nonces` opted in.
+    fn next_dev_nonce(&mut self) -> Result<u16, MacError<R::Error>> {
+        if self.random_dev_nonces && self.dev_nonce_store.is_none() {
+            let random = self.phy.radio.random_u32().map_err(MacError::Radio)? as u16;
+            // Never the DevNonce of the previous attempt
+            return Ok(match self.session.last_dev_nonce {
+                Some(last) if last == random => random.wrapping_add(1),
+                _ => random,
+            });
+        }
+
+        // The store may be ahead of the session after a reboot, or behind it
+        // if it lost a write
+        let stored = self.dev_nonce_store.and_then(|store| store.load());
+        let dev_nonce = match stored.max(self.session.last_dev_nonce) {
+            Some(last) => last.checked_add(1).ok_or(MacError::DevNonceUnavailable)?,
+            None => 0,
         };
 
-        // Add DevNonce
-        buffer
-            .extend_from_slice(&dev_nonce)
-            .map_err(|_| MacError::BufferTooSmall)?;
+        if let Some(store) = self.dev_nonce_store {
+            if !store.save(dev_nonce) {
+                return Err(MacError::DevNonceUnavailable);
+            }
+        }
+        Ok(dev_nonce)
+    }
 
-        // Calculate and add MIC
-        let mic = crypto::compute_join_request_mic(&app_key, &buffer);
-        buffer
-            .extend_from_slice(&mic)
-            .map_err(|_| MacError::BufferTooSmall)?;
+    /// Get the DevNonce used by the last join request
+    pub fn get_dev_nonce(&self) -> u16 {
+        self.session.last_dev_nonce.unwrap_or(0)
+    }
 
-        // Get next channel for transmission
-        let channel = self
-            .region
-            .get_next_channel()
-            .ok_or(MacError::InvalidChannel)?;
+    /// Check if a join request is waiting for its join accept
+    pub fn is_join_pending(&self) -> bool {
+        self.join_key.is_some()
+    }
 
-        // Configure radio for transmission
-        self.phy.configure_tx::<REG>(&channel, DataRate::SF7BW125)?;
+    /// Process a JoinAccept downlink
+    ///
+    /// Decrypts the frame with the AppKey of the outstanding join request,
+    /// verifies its MIC, derives the session keys and installs the new session
+    /// together with the RX parameters carried in DLSettings and RxDelay.
+    /// Replayed join accepts are counted and rejected.
+    pub fn process_join_accept(&mut self, data: &[u8]) -> Result<(), MacError<R::Error>> {
+        let join_accept = self.decrypt_join_accept(data);
+        self.finish_join(join_accept)
+    }
 
-        // Transmit join request
-        self.phy.transmit(&buffer)?;
+    /// Process the join accept of `len` bytes in the frame buffer
+    ///
+    /// See `receive_frame` and `process_join_accept`.
+    pub fn process_received_join_accept(&mut self, len: usize) -> Result<(), MacError<R::Error>> {
+        let join_accept = self.decrypt_join_accept(&self.rx_buffer[..len]);
+        self.finish_join(join_accept)
+    }
 
-        // Configure RX1 window for join accept
-        let (rx1_freq, rx1_dr) = self.region.rx1_window(&channel);
-        self.phy
-            .configure_rx::<REG>(rx1_freq, rx1_dr, self.region.join_accept_delay1())?;
+    /// Start the session of a decrypted join accept and count the join
+    fn finish_join(
+        &mut self,
+        join_accept: Result<JoinAccept, MacError<R::Error>>,
+    ) -> Result<(), MacError<R::Error>> {
+        let result = join_accept.and_then(|join_accept| self.accept_join(join_accept));
+        result.map_err(|e| self.record_error(e))?;
+        self.stats.joins = self.stats.joins.wrapping_add(1);
+        debug!("joined: DevAddr {}", self.session.dev_addr);
+        Ok(())
+    }
+
+    /// Decrypt a join accept with the AppKey of the outstanding join request
+    fn decrypt_join_accept(&self, data: &[u8]) -> Result<JoinAccept, MacError<R::Error>> {
+        let app_key = self.join_key.as_ref().ok_or(MacError::InvalidFrame)?;
+
+        // MHDR | AppNonce | NetID | DevAddr | DLSettings | RxDelay | [CFList] | MIC
+        match PhyPayload::parse(data).map_err(frame_error)? {
+            PhyPayload::JoinAccept(join_accept) => join_accept
+                .decrypt(self.crypto, app_key)
+                .map_err(frame_error),
+            _ => Err(MacError::InvalidFrame),
+        }
+    }
+
+    /// Verify a join accept and start the session
+    fn accept_join(&mut self, join_accept: JoinAccept) -> Result<(), MacError<R::Error>> {
+        let app_key = self.join_key.clone().ok_or(MacError::InvalidFrame)?;
+
+        let join_nonce = join_accept.join_nonce();
+        if self.replay_cache.is_join_replay(join_nonce) {
+            self.stats.duplicates_dropped = self.stats.duplicates_dropped.wrapping_add(1);
+            return Err(MacError::DuplicateFrame);
+        }
+        self.replay_cache.record_join_nonce(join_nonce);
+        let dl_settings = join_accept.dl_settings;
+        let rx_delay = join_accept.rx_delay & 0x0F;
+
+        let (nwk_skey, app_skey) = self.crypto.derive_session_keys(
+            &app_key,
+            &join_accept.app_nonce,
+            &join_accept.net_id,
+            self.get_dev_nonce(),
+        );
+
+        let mut session = SessionState::from_join_accept(join_accept.dev_addr, nwk_skey, app_skey);
+        session.rx1_dr_offset = (dl_settings >> 4) & 0x07;
+        session.rx2_data_rate = Some(dl_settings & 0x0F);
+        // A RxDelay of 0 means 1 second
+        session.rx1_delay = if rx_delay == 0 { 1 } else { rx_delay };
+        session.last_dev_nonce = self.session.last_dev_nonce;
+        session.dev_nonce = self.get_dev_nonce();
+        self.set_session_state(session);
 
         Ok(())
     }
@@ -745,6 +2579,17 @@ impl<R: Radio, REG: Region> MacLayer<R, REG> {
         }
     }
 
+    /// Select US915 uplink channels at random, seeded from the radio RNG
+    pub fn enable_random_channels(&mut self) -> Result<(), MacError<R::Error>> {
+        let seed = self.phy.radio.random_u32().map_err(MacError::Radio)?;
+        if let Some(us915) = self.region.as_any_mut().downcast_mut::<US915>() {
+            us915.set_channel_selection(ChannelSelection::Random { seed });
+            Ok(())
+        } else {
+            Err(MacError::InvalidConfig)
+        }
+    }
+
     /// Get next channel
     pub fn get_next_channel(&mut self) -> Result<Channel, MacError<R::Error>> {
         self.region
@@ -758,8 +2603,43 @@ impl<R: Radio, REG: Region> MacLayer<R, REG> {
     }
 
     /// Get next beacon channel
+    ///
+    /// The frequency set by the network with BeaconFreqReq replaces the
+    /// region beacon channels.
     pub fn get_next_beacon_channel(&mut self) -> Option<Channel> {
-        self.region.get_next_beacon_channel()
+        let mut channel = self.region.get_next_beacon_channel()?;
+        if let Some(frequency) = self.beacon_frequency {
+            channel.frequency = frequency;
+        }
+        Some(channel)
+    }
+
+    /// Get the beacon channel of the beacon period of `beacon_time`
+    ///
+    /// The frequency set by the network with BeaconFreqReq replaces the
+    /// region beacon channels.
+    pub fn beacon_channel(&self, beacon_time: u32) -> Option<Channel> {
+        let mut channel = self.region.beacon_channel(beacon_time)?;
+        if let Some(frequency) = self.beacon_frequency {
+            channel.frequency = frequency;
+        }
+        Some(channel)
+    }
+
+    /// Get the ping slot frequency of the beacon period of `beacon_time`
+    ///
+    /// The frequency set with PingSlotChannelReq comes first, then the one
+    /// set with BeaconFreqReq, then the region ping slot channel of the
+    /// session DevAddr.
+    pub fn ping_slot_frequency(&self, beacon_time: u32) -> Option<u32> {
+        match self.ping_slot_config.frequency() {
+            0 => self.beacon_frequency.or_else(|| {
+                self.region
+                    .ping_slot_channel(beacon_time, self.session.dev_addr)
+                    .map(|channel| channel.frequency)
+            }),
+            frequency => Some(frequency),
+        }
     }
 
     /// Get uplink frame counter
@@ -772,92 +2652,12 @@ impl<R: Radio, REG: Region> MacLayer<R, REG> {
         self.session.fcnt_down
     }
 
-    fn handle_mac_command(&mut self, command: MacCommand) -> Result<(), MacError<R::Error>> {
-        match command {
-            MacCommand::LinkCheckReq
-            | MacCommand::LinkCheckAns { .. }
-            | MacCommand::LinkADRReq { .. }
-            | MacCommand::LinkADRAns { .. }
-            | MacCommand::DutyCycleReq { .. }
-            | MacCommand::DutyCycleAns
-            | MacCommand::RXParamSetupReq { .. }
-            | MacCommand::RXParamSetupAns { .. }
-            | MacCommand::DevStatusReq
-            | MacCommand::DevStatusAns { .. }
-            | MacCommand::NewChannelAns { .. }
-            | MacCommand::RXTimingSetupAns
-            | MacCommand::TxParamSetupAns
-            | MacCommand::DlChannelAns { .. } => Ok(()),
-
-            MacCommand::NewChannelReq {
-                ch_index,
-                freq,
-                min_dr: _,
-                max_dr: _,
-            } => {
-                // Validate and configure new channel
-                if !self.region.is_valid_frequency(freq) {
-                    return Err(MacError::InvalidFrequency);
-                }
-                if ch_index as usize >= self.region.get_max_channels() {
-                    return Err(MacError::InvalidChannel);
-                }
-                Ok(())
-            }
-            MacCommand::RXTimingSetupReq { delay } => {
-                // RX1 delay is in seconds, 0 means 1 second
-                let rx1_delay = if delay == 0 { 1 } else { delay as u32 };
-
-                // Configure PHY layer with new timing
-                self.phy.config.timing.rx1_delay = rx1_delay;
-                self.phy.config.timing.rx2_delay = rx1_delay + 1;
-
-                // Send acknowledgment
-                self.queue_mac_command(MacCommand::RXTimingSetupAns)
-            }
-            MacCommand::TxParamSetupReq {
-                downlink_dwell_time,
-                uplink_dwell_time,
-                max_eirp,
-            } => {
-                // Set TX parameters
-                if max_eirp <= 15 {
-                    // Store dwell time settings locally
-                    let _dl_dwell = downlink_dwell_time;
-                    let _ul_dwell = uplink_dwell_time;
-
-                    // Convert max_eirp to dBm: 2 dBm steps starting from 8 dBm
-                    let eirp_dbm = 8 + (2 * max_eirp as u32);
-                    self.phy.radio.set_tx_power(eirp_dbm as i8)?;
-
-                    self.queue_mac_command(MacCommand::TxParamSetupAns)
-                } else {
-                    Err(MacError::InvalidValue)
-                }
-            }
-            MacCommand::DlChannelReq { ch_index, freq } => {
-                let mut channel_freq_ok = false;
-                let mut uplink_freq_exists = false;
-
-                // Validate frequency
-                if self.region.is_valid_frequency(freq) {
-                    channel_freq_ok = true;
-                }
-
-                // Check if uplink frequency exists for this channel
-                if let Some(channel) = self.region.get_channel(ch_index) {
-                    if channel.frequency > 0 {
-                        uplink_freq_exists = true;
-                    }
-                }
-
-                // Queue acknowledgment
-                self.queue_mac_command(MacCommand::DlChannelAns {
-                    channel_freq_ok,
-                    uplink_freq_exists,
-                })
-            }
-        }
+    /// Set the time source replacing the radio clock
+    ///
+    /// Set it before the first transmission, times already recorded are not
+    /// converted.
+    pub fn set_clock(&mut self, clock: &'static dyn Clock) {
+        self.phy.set_clock(clock);
     }
 
     /// Get current time in milliseconds
@@ -9,14 +9,26 @@
 /// MAC command handling
 pub mod commands;
 
+/// Duty cycle accounting
+pub mod duty_cycle;
+
+/// Frame parsing and building
+pub mod frame;
+
 /// MAC layer implementation
 pub mod mac;
 
+/// Multicast groups
+pub mod multicast;
+
 /// PHY layer operations
 pub mod phy;
 
 /// Regional parameters and configurations
 pub mod region;
 
+/// Duplicate downlink and join accept replay detection
+pub mod replay;
+
 pub use mac::{MacError, MacLayer};
 pub use phy::{PhyConfig, PhyLayer, TimingParams};
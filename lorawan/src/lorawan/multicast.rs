@@ -0,0 +1,67 @@
+//! Multicast groups
+//!
+//! A multicast group has its own DevAddr, session keys and downlink frame
+//! counter, shared by the devices of the group, e.g. for firmware updates
+//! over the air. Groups are set up by the application, usually from the
+//! remote multicast setup messages of the network.
+//!
+//! Multicast downlinks are only received by Class B ping slots and Class C
+//! continuous reception, never in the receive windows after an uplink. They
+//! are unconfirmed and carry application data only: frames with MAC
+//! commands or the ACK bit set are dropped, the ADR and FPending bits are
+//! ignored.
+
+use super::frame::DataFrame;
+use crate::config::device::{AESKey, DevAddr};
+
+/// Maximum number of multicast groups of a device
+pub const MAX_MULTICAST_GROUPS: usize = 4;
+
+/// Session of a multicast group
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MulticastGroup {
+    /// Multicast address of the group
+    pub addr: DevAddr,
+    /// Network session key of the group (McNwkSKey)
+    pub nwk_skey: AESKey,
+    /// Application session key of the group (McAppSKey)
+    pub app_skey: AESKey,
+    /// Next expected downlink frame counter of the group
+    pub fcnt_down: u32,
+    /// Ping slot periodicity of a Class B session, 0 to 7
+    pub periodicity: Option<u8>,
+    /// Frequency of the group downlinks, the class default if not set
+    pub frequency: Option<u32>,
+    /// Data rate of the group downlinks, the class default if not set
+    pub data_rate: Option<u8>,
+}
+
+impl MulticastGroup {
+    /// Create a group with its address and session keys
+    ///
+    /// The frame counter starts at 0, the downlinks are received with the
+    /// frequency and data rate of the device class.
+    pub fn new(addr: DevAddr, nwk_skey: AESKey, app_skey: AESKey) -> Self {
+        Self {
+            addr,
+            nwk_skey,
+            app_skey,
+            fcnt_down: 0,
+            periodicity: None,
+            frequency: None,
+            data_rate: None,
+        }
+    }
+}
+
+/// Check that a frame is allowed as a multicast downlink
+///
+/// Multicast frames are unconfirmed, carry application data on a port other
+/// than 0, no FOpts and have the ACK bit cleared.
+pub(crate) fn is_valid_multicast(frame: &DataFrame) -> bool {
+    !frame.confirmed
+        && !frame.fhdr.f_ctrl.ack
+        && frame.fhdr.f_opts.is_empty()
+        && matches!(frame.f_port, Some(port) if port != 0)
+}
@@ -1,51 +1,143 @@
-use super::region::{Channel, DataRate, Region};
+use super::region::{Channel, DataRate, LbtConfig, Region};
 use crate::radio::traits::{ModulationParams, Radio, RxConfig, TxConfig};
+use crate::timing::Clock;
 
 /// PHY layer timing parameters
-#[derive(Debug, Clone, Copy)]
+///
+/// Delays of the receive windows after the end of an uplink. The MAC layer
+/// takes them from the region, and RxDelay of the session or
+/// RXTimingSetupReq moves RX1 and RX2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TimingParams {
-    /// RX1 delay in seconds
+    /// RX1 delay in milliseconds
     pub rx1_delay: u32,
-    /// RX2 delay in seconds
+    /// RX2 delay in milliseconds
     pub rx2_delay: u32,
-    /// Join accept delay 1 in seconds
+    /// Join accept delay 1 in milliseconds
     pub join_accept_delay1: u32,
-    /// Join accept delay 2 in seconds
+    /// Join accept delay 2 in milliseconds
     pub join_accept_delay2: u32,
 }
 
 impl Default for TimingParams {
     fn default() -> Self {
         Self {
-            rx1_delay: 1,
-            rx2_delay: 2,
-            join_accept_delay1: 5,
-            join_accept_delay2: 6,
+            rx1_delay: 1_000,
+            rx2_delay: 2_000,
+            join_accept_delay1: 5_000,
+            join_accept_delay2: 6_000,
         }
     }
 }
 
+impl TimingParams {
+    /// Get the default delays of a region
+    pub fn from_region<REG: Region>(region: &REG) -> Self {
+        Self {
+            rx1_delay: region.receive_delay1(),
+            rx2_delay: region.receive_delay2(),
+            join_accept_delay1: region.join_accept_delay1(),
+            join_accept_delay2: region.join_accept_delay2(),
+        }
+    }
+
+    /// Set RX1 to `rx_delay` seconds, 0 meaning 1, and RX2 one second later
+    pub fn set_rx_delay(&mut self, rx_delay: u8) {
+        self.rx1_delay = u32::from(rx_delay.clamp(1, 15)) * 1_000;
+        self.rx2_delay = self.rx1_delay + 1_000;
+    }
+}
+
 /// PHY layer configuration
 #[derive(Debug, Clone)]
 pub struct PhyConfig {
     /// Timing parameters
     pub timing: TimingParams,
+    /// Maximum output power of the radio in dBm
+    pub max_tx_power: i8,
+    /// Antenna gain in dBi, subtracted from the EIRP of the region
+    pub antenna_gain: i8,
+    /// Network type selecting the sync word
+    pub network: NetworkType,
 }
 
 impl Default for PhyConfig {
     fn default() -> Self {
         Self {
             timing: TimingParams::default(),
+            max_tx_power: 14,
+            antenna_gain: 0,
+            network: NetworkType::Public,
+        }
+    }
+}
+
+/// LoRaWAN preamble length in symbols
+pub const PREAMBLE_LENGTH: u16 = 8;
+
+/// Class B beacon preamble length in symbols
+pub const BEACON_PREAMBLE_LENGTH: u16 = 10;
+
+/// Sync word of public LoRaWAN networks
+pub const PUBLIC_SYNC_WORD: u8 = 0x34;
+
+/// Sync word of private LoRa networks
+pub const PRIVATE_SYNC_WORD: u8 = 0x12;
+
+/// Network type, set by the sync word of every packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkType {
+    /// Public LoRaWAN network, e.g. TTN or Helium
+    Public,
+    /// Private network, e.g. a ChirpStack setup with private gateways
+    Private,
+}
+
+impl NetworkType {
+    /// Get the sync word of the network type
+    pub fn sync_word(&self) -> u8 {
+        match self {
+            NetworkType::Public => PUBLIC_SYNC_WORD,
+            NetworkType::Private => PRIVATE_SYNC_WORD,
         }
     }
 }
 
+/// Get the duration of a LoRa symbol in microseconds
+pub fn symbol_time_us(params: &ModulationParams) -> u32 {
+    ((1_000_000u64 << params.spreading_factor) / params.bandwidth.max(1) as u64) as u32
+}
+
+/// Get the time-on-air of a LoRa packet in milliseconds
+///
+/// Implements the symbol count formula of the SX127x and SX126x datasheets.
+/// Low data rate optimization is applied when a symbol lasts 16 ms or more.
+pub fn time_on_air(payload_len: usize, params: &ModulationParams) -> u32 {
+    let sf = params.spreading_factor as i32;
+    let symbol_time_us = symbol_time_us(params) as u64;
+
+    let de = i32::from(symbol_time_us >= 16_000);
+    let ih = i32::from(params.implicit_header.is_some());
+    let crc = i32::from(params.crc);
+    let cr = params.coding_rate.clamp(5, 8) as i32 - 4;
+
+    let bits = 8 * payload_len as i32 - 4 * sf + 28 + 16 * crc - 20 * ih;
+    let divisor = 4 * (sf - 2 * de);
+    let payload_symbols = 8 + ((bits + divisor - 1) / divisor).max(0) * (cr + 4);
+
+    // Preamble plus 4.25 symbols of sync word, counted in quarter symbols
+    let symbols_x4 = 4 * (params.preamble_length as u64 + payload_symbols as u64) + 17;
+    (symbols_x4 * symbol_time_us).div_ceil(4_000) as u32
+}
+
 /// PHY layer
 pub struct PhyLayer<R: Radio> {
     /// Radio driver
     pub radio: R,
     /// Configuration
     pub config: PhyConfig,
+    /// Time source, the radio clock if `None`
+    clock: Option<&'static dyn Clock>,
 }
 
 impl<R: Radio> PhyLayer<R> {
@@ -54,33 +146,62 @@ impl<R: Radio> PhyLayer<R> {
         Self {
             radio,
             config: PhyConfig::default(),
+            clock: None,
         }
     }
 
+    /// Set the time source replacing the radio clock
+    pub fn set_clock(&mut self, clock: &'static dyn Clock) {
+        self.clock = Some(clock);
+    }
+
+    /// Get the time-on-air of a LoRa packet in milliseconds
+    pub fn time_on_air(payload_len: usize, params: &ModulationParams) -> u32 {
+        time_on_air(payload_len, params)
+    }
+
     /// Initialize radio
     pub fn init(&mut self) -> Result<(), R::Error> {
         self.radio.init()
     }
 
-    /// Configure radio for transmission
+    /// Apply the sync word of the network type to `modulation`
+    pub fn for_network(&self, modulation: ModulationParams) -> ModulationParams {
+        ModulationParams {
+            sync_word: self.config.network.sync_word(),
+            ..modulation
+        }
+    }
+
+    /// Get the output power in dBm radiating `eirp` dBm
+    ///
+    /// The antenna gain is subtracted and the power limited to the maximum
+    /// power of the radio, then to the range of `Radio::capabilities`.
+    pub fn output_power(&self, eirp: i8) -> i8 {
+        let power = eirp
+            .saturating_sub(self.config.antenna_gain)
+            .min(self.config.max_tx_power);
+        self.radio.capabilities().clamp_power(power)
+    }
+
+    /// Configure radio for transmission at `eirp` dBm
+    ///
+    /// See `output_power` for the power set on the radio.
     pub fn configure_tx<REG: Region>(
         &mut self,
         channel: &Channel,
         data_rate: DataRate,
+        eirp: i8,
     ) -> Result<(), R::Error> {
         let config = TxConfig {
             frequency: channel.frequency,
-            power: 14, // Default to 14 dBm
-            modulation: ModulationParams {
-                spreading_factor: data_rate.spreading_factor(),
-                bandwidth: data_rate.bandwidth(),
-                coding_rate: 5,
-            },
+            power: self.output_power(eirp),
+            modulation: self.for_network(data_rate.modulation()),
         };
         self.radio.configure_tx(config)
     }
 
-    /// Configure radio for reception
+    /// Configure radio for reception of a downlink
     pub fn configure_rx<REG: Region>(
         &mut self,
         frequency: u32,
@@ -89,16 +210,76 @@ impl<R: Radio> PhyLayer<R> {
     ) -> Result<(), R::Error> {
         let config = RxConfig {
             frequency,
-            modulation: ModulationParams {
-                spreading_factor: data_rate.spreading_factor(),
-                bandwidth: data_rate.bandwidth(),
-                coding_rate: 5,
-            },
+            modulation: self.for_network(data_rate.downlink_modulation()),
             timeout_ms,
         };
         self.radio.configure_rx(config)
     }
 
+    /// Configure radio for reception of a Class B beacon of `size` bytes
+    pub fn configure_beacon_rx(
+        &mut self,
+        frequency: u32,
+        data_rate: DataRate,
+        size: u8,
+        timeout_ms: u32,
+    ) -> Result<(), R::Error> {
+        let config = RxConfig {
+            frequency,
+            modulation: self.for_network(data_rate.beacon_modulation(size)),
+            timeout_ms,
+        };
+        self.radio.configure_rx(config)
+    }
+
+    /// Listen on a channel and check that it is free for transmission
+    ///
+    /// The radio is put in receive mode on the channel for the LBT scan
+    /// duration and the channel is considered busy if the RSSI reaches the
+    /// threshold.
+    pub fn is_channel_free(
+        &mut self,
+        channel: &Channel,
+        data_rate: DataRate,
+        lbt: &LbtConfig,
+    ) -> Result<bool, R::Error> {
+        let config = RxConfig {
+            frequency: channel.frequency,
+            modulation: self.for_network(data_rate.modulation()),
+            timeout_ms: lbt.scan_duration_ms,
+        };
+        self.radio.configure_rx(config)?;
+
+        let rssi = self.radio.get_rssi()?;
+        Ok(rssi < lbt.threshold_dbm)
+    }
+
+    /// Receive a downlink only if a LoRa preamble is detected first
+    ///
+    /// A Channel Activity Detection on `frequency` decides whether the receive
+    /// window is opened at all. Returns 0 without receiving if the channel is
+    /// quiet.
+    pub fn cad_then_receive(
+        &mut self,
+        frequency: u32,
+        data_rate: DataRate,
+        timeout_ms: u32,
+        buffer: &mut [u8],
+    ) -> Result<usize, R::Error> {
+        let modulation = self.for_network(data_rate.downlink_modulation());
+        self.radio.set_frequency(frequency)?;
+        if !self.radio.cad(&modulation)? {
+            return Ok(0);
+        }
+
+        self.radio.configure_rx(RxConfig {
+            frequency,
+            modulation,
+            timeout_ms,
+        })?;
+        self.radio.receive(buffer)
+    }
+
     /// Transmit data
     pub fn transmit(&mut self, data: &[u8]) -> Result<(), R::Error> {
         self.radio.transmit(data)
@@ -126,6 +307,9 @@ impl<R: Radio> PhyLayer<R> {
 
     /// Get current time in milliseconds
     pub fn get_time(&self) -> u32 {
-        self.radio.get_time()
+        match self.clock {
+            Some(clock) => clock.now_ms(),
+            None => self.radio.now_ms(),
+        }
     }
 }
@@ -0,0 +1,376 @@
+//! AS923 region (915-928 MHz)
+//!
+//! AS923 is shared by several countries that use different parts of the band,
+//! so the channel plan is shifted by a per-group frequency offset. Devices may
+//! also be subject to a 400 ms dwell time limit, which the network enables with
+//! `TxParamSetupReq`.
+
+use core::any::Any;
+use heapless::Vec;
+
+use super::{set_dynamic_channel, set_dynamic_downlink_frequency, Channel, DataRate, Region};
+
+/// Maximum number of channels in AS923
+pub const AS923_MAX_CHANNELS: usize = 16;
+
+/// Default channel frequencies of AS923-1
+const DEFAULT_CHANNELS: [u32; 2] = [923_200_000, 923_400_000];
+
+/// RX2 frequency of AS923-1
+const RX2_FREQUENCY: u32 = 923_200_000;
+
+/// Beacon frequency of AS923-1
+const BEACON_FREQUENCY: u32 = 923_400_000;
+
+/// Maximum MACPayload size per data rate without dwell time limit
+const MAX_PAYLOAD: [u8; 7] = [59, 59, 59, 123, 250, 250, 250];
+
+/// Maximum MACPayload size per data rate with the 400 ms dwell time limit
+const MAX_PAYLOAD_DWELL: [u8; 7] = [0, 0, 19, 61, 133, 250, 250];
+
+/// Lowest data rate usable within the 400 ms dwell time limit
+const DWELL_TIME_MIN_DR: u8 = 2;
+
+/// MaxEIRP values of TxParamSetupReq in dBm
+const MAX_EIRP_TABLE: [u8; 16] = [
+    8, 10, 12, 13, 14, 16, 18, 20, 21, 24, 26, 27, 29, 30, 33, 36,
+];
+
+/// AS923 frequency group
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AS923Group {
+    /// AS923-1, no frequency offset
+    Group1,
+    /// AS923-2, -1.8 MHz offset
+    Group2,
+    /// AS923-3, -6.6 MHz offset
+    Group3,
+    /// AS923-4, -5.9 MHz offset
+    Group4,
+}
+
+impl AS923Group {
+    /// Get the offset applied to the AS923-1 frequencies in Hz
+    pub fn frequency_offset(&self) -> i32 {
+        match self {
+            AS923Group::Group1 => 0,
+            AS923Group::Group2 => -1_800_000,
+            AS923Group::Group3 => -6_600_000,
+            AS923Group::Group4 => -5_900_000,
+        }
+    }
+
+    /// Get the frequency range (min, max) of the group in Hz
+    pub fn frequency_range(&self) -> (u32, u32) {
+        match self {
+            AS923Group::Group1 => (915_000_000, 928_000_000),
+            AS923Group::Group2 => (920_000_000, 923_000_000),
+            AS923Group::Group3 => (915_000_000, 921_000_000),
+            AS923Group::Group4 => (917_000_000, 920_000_000),
+        }
+    }
+
+    /// Apply the group offset to an AS923-1 frequency
+    fn apply_offset(&self, frequency: u32) -> u32 {
+        frequency.wrapping_add_signed(self.frequency_offset())
+    }
+}
+
+/// AS923 region implementation
+#[derive(Debug, Clone)]
+pub struct AS923 {
+    group: AS923Group,
+    channels: Vec<Channel, AS923_MAX_CHANNELS>,
+    data_rate: u8,
+    tx_power: u8,
+    uplink_dwell_time: bool,
+    downlink_dwell_time: bool,
+    max_eirp: u8,
+    last_channel: usize,
+}
+
+impl Default for AS923 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AS923 {
+    /// Create new AS923-1 region
+    pub fn new() -> Self {
+        Self::with_group(AS923Group::Group1)
+    }
+
+    /// Create new AS923 region for a frequency group
+    pub fn with_group(group: AS923Group) -> Self {
+        let mut channels = Vec::new();
+
+        // Two default channels, DR0-DR5
+        for frequency in DEFAULT_CHANNELS {
+            channels
+                .push(Channel {
+                    frequency: group.apply_offset(frequency),
+                    min_dr: DataRate::SF12BW125,
+                    max_dr: DataRate::SF7BW125,
+                    enabled: true,
+                    downlink_frequency: None,
+                })
+                .unwrap();
+        }
+
+        Self {
+            group,
+            channels,
+            data_rate: DWELL_TIME_MIN_DR,
+            tx_power: 0,
+            uplink_dwell_time: false,
+            downlink_dwell_time: false,
+            max_eirp: 16,
+            last_channel: 0,
+        }
+    }
+
+    /// Get frequency group
+    pub fn group(&self) -> AS923Group {
+        self.group
+    }
+
+    /// Check if the 400 ms uplink dwell time limit is active
+    pub fn uplink_dwell_time(&self) -> bool {
+        self.uplink_dwell_time
+    }
+
+    /// Check if the 400 ms downlink dwell time limit is active
+    pub fn downlink_dwell_time(&self) -> bool {
+        self.downlink_dwell_time
+    }
+
+    /// Lowest data rate usable for uplinks
+    fn min_uplink_data_rate(&self) -> u8 {
+        if self.uplink_dwell_time {
+            DWELL_TIME_MIN_DR
+        } else {
+            0
+        }
+    }
+
+    /// Lowest data rate usable for downlinks
+    fn min_downlink_data_rate(&self) -> u8 {
+        if self.downlink_dwell_time {
+            DWELL_TIME_MIN_DR
+        } else {
+            0
+        }
+    }
+}
+
+impl Region for AS923 {
+    fn name(&self) -> &'static str {
+        "AS923"
+    }
+
+    fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    fn get_max_channels(&self) -> usize {
+        AS923_MAX_CHANNELS
+    }
+
+    fn get_channel(&self, index: u8) -> Option<&Channel> {
+        self.channels.get(index as usize)
+    }
+
+    fn is_valid_frequency(&self, frequency: u32) -> bool {
+        frequency >= self.min_frequency() && frequency <= self.max_frequency()
+    }
+
+    fn is_valid_data_rate(&self, data_rate: u8) -> bool {
+        // DR0-DR6 are LoRa, DR7 (FSK) is not supported
+        data_rate <= 6
+    }
+
+    fn data_rate(&self) -> u8 {
+        // Raised to DR2 while the uplink dwell time limit is active
+        self.data_rate.max(self.min_uplink_data_rate())
+    }
+
+    fn set_data_rate(&mut self, data_rate: u8) {
+        if self.is_valid_data_rate(data_rate) {
+            self.data_rate = data_rate;
+        }
+    }
+
+    fn is_valid_tx_power(&self, tx_power: u8) -> bool {
+        // TXPower 0-7: MaxEIRP down to MaxEIRP - 14 dB
+        tx_power <= 7
+    }
+
+    fn set_tx_power(&mut self, tx_power: u8) {
+        if self.is_valid_tx_power(tx_power) {
+            self.tx_power = tx_power;
+        }
+    }
+
+    fn tx_power(&self) -> u8 {
+        self.tx_power
+    }
+
+    fn max_eirp(&self) -> u8 {
+        self.max_eirp
+    }
+
+    fn set_channel(&mut self, index: u8, channel: Channel) -> bool {
+        set_dynamic_channel(
+            &mut self.channels,
+            DEFAULT_CHANNELS.len(),
+            index as usize,
+            channel,
+        )
+    }
+
+    fn set_downlink_frequency(&mut self, index: u8, frequency: Option<u32>) -> bool {
+        set_dynamic_downlink_frequency(&mut self.channels, index as usize, frequency)
+    }
+
+    fn set_tx_params(
+        &mut self,
+        uplink_dwell_time: bool,
+        downlink_dwell_time: bool,
+        max_eirp: u8,
+    ) -> bool {
+        self.uplink_dwell_time = uplink_dwell_time;
+        self.downlink_dwell_time = downlink_dwell_time;
+        self.max_eirp = MAX_EIRP_TABLE[(max_eirp & 0x0F) as usize];
+        true
+    }
+
+    fn is_valid_channel_mask(&self, ch_mask: u16, ch_mask_cntl: u8) -> bool {
+        match ch_mask_cntl {
+            // Mask must not enable undefined channels
+            0 => (0..AS923_MAX_CHANNELS)
+                .filter(|i| ch_mask & (1 << i) != 0)
+                .all(|i| self.channels.get(i).is_some_and(|c| c.frequency != 0)),
+            // All channels on
+            6 => true,
+            _ => false,
+        }
+    }
+
+    fn apply_channel_mask(&mut self, ch_mask: u16, ch_mask_cntl: u8) {
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            match ch_mask_cntl {
+                0 => channel.enabled = (ch_mask & (1 << i)) != 0,
+                6 => channel.enabled = channel.frequency != 0,
+                _ => {}
+            }
+        }
+    }
+
+    fn min_frequency(&self) -> u32 {
+        self.group.frequency_range().0
+    }
+
+    fn max_frequency(&self) -> u32 {
+        self.group.frequency_range().1
+    }
+
+    fn rx2_frequency(&self) -> u32 {
+        self.group.apply_offset(RX2_FREQUENCY)
+    }
+
+    fn rx2_data_rate(&self) -> u8 {
+        2 // DR2 (SF10/125kHz)
+    }
+
+    fn max_payload_size(&self, data_rate: u8) -> u8 {
+        let table = if self.uplink_dwell_time {
+            &MAX_PAYLOAD_DWELL
+        } else {
+            &MAX_PAYLOAD
+        };
+        table.get(data_rate as usize).copied().unwrap_or(0)
+    }
+
+    fn receive_delay1(&self) -> u32 {
+        1_000 // 1 second
+    }
+
+    fn receive_delay2(&self) -> u32 {
+        2_000 // 2 seconds
+    }
+
+    fn join_accept_delay1(&self) -> u32 {
+        5_000 // 5 seconds
+    }
+
+    fn join_accept_delay2(&self) -> u32 {
+        6_000 // 6 seconds
+    }
+
+    fn enabled_channels(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.iter().filter(|c| c.enabled)
+    }
+
+    fn get_next_channel(&mut self) -> Option<Channel> {
+        let enabled_channels: Vec<Channel, AS923_MAX_CHANNELS> =
+            self.enabled_channels().cloned().collect();
+        if enabled_channels.is_empty() {
+            return None;
+        }
+        let next_channel = (self.last_channel + 1) % enabled_channels.len();
+        let channel = enabled_channels[next_channel].clone();
+        self.last_channel = next_channel;
+        Some(channel)
+    }
+
+    fn rx1_window(&self, tx_channel: &Channel, rx1_dr_offset: u8) -> (u32, DataRate) {
+        // RX1DROffset 6 and 7 raise the data rate by 1 and 2
+        let uplink_dr = self.data_rate() as i8;
+        let offset = match rx1_dr_offset {
+            6 => -1,
+            7 => -2,
+            offset => offset.min(5) as i8,
+        };
+
+        // RX1 uses the channel downlink frequency, the data rate is capped at DR5 and
+        // must stay within the downlink dwell time limit
+        let rx1_dr = (uplink_dr - offset).clamp(self.min_downlink_data_rate() as i8, 5) as u8;
+        let data_rate = self
+            .data_rate_from_index(rx1_dr)
+            .unwrap_or(DataRate::SF10BW125);
+
+        (tx_channel.rx1_frequency(), data_rate)
+    }
+
+    fn rx2_window(&self) -> (u32, DataRate) {
+        (self.rx2_frequency(), DataRate::SF10BW125)
+    }
+
+    fn get_beacon_channels(&self) -> Vec<Channel, 8> {
+        let mut channels = Vec::new();
+        // AS923 beacons use a single channel at DR3
+        channels
+            .push(Channel {
+                frequency: self.group.apply_offset(BEACON_FREQUENCY),
+                min_dr: DataRate::SF9BW125,
+                max_dr: DataRate::SF9BW125,
+                enabled: true,
+                downlink_frequency: None,
+            })
+            .unwrap();
+        channels
+    }
+
+    fn get_next_beacon_channel(&mut self) -> Option<Channel> {
+        self.get_beacon_channels().first().cloned()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
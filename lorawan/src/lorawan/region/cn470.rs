@@ -0,0 +1,294 @@
+//! CN470 region (470-510 MHz)
+//!
+//! CN470 has 96 uplink channels and 48 downlink channels. Downlinks in RX1 are
+//! sent on the downlink channel given by the uplink channel modulo 48.
+
+use core::any::Any;
+use heapless::Vec;
+
+use super::{BeaconLayout, Channel, DataRate, Region};
+
+/// Maximum number of channels in CN470
+pub const CN470_MAX_CHANNELS: usize = 96;
+
+/// Number of downlink channels
+const DOWNLINK_CHANNELS: u32 = 48;
+
+/// Frequency of uplink channel 0
+const UPLINK_BASE_FREQUENCY: u32 = 470_300_000;
+
+/// Frequency of downlink channel 0
+const DOWNLINK_BASE_FREQUENCY: u32 = 500_300_000;
+
+/// Channel spacing
+const CHANNEL_SPACING: u32 = 200_000;
+
+/// Default maximum EIRP in dBm
+const MAX_EIRP: u8 = 19;
+
+/// RX2 frequency
+const RX2_FREQUENCY: u32 = 505_300_000;
+
+/// Frequency of beacon channel 0
+const BEACON_BASE_FREQUENCY: u32 = 508_300_000;
+
+/// Maximum MACPayload size per data rate
+const MAX_PAYLOAD: [u8; 6] = [59, 59, 59, 123, 250, 250];
+
+/// CN470 region implementation
+#[derive(Debug, Clone)]
+pub struct CN470 {
+    channels: Vec<Channel, CN470_MAX_CHANNELS>,
+    data_rate: u8,
+    tx_power: u8,
+    last_channel: usize,
+    last_beacon_channel: usize,
+}
+
+impl Default for CN470 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CN470 {
+    /// Create new CN470 region
+    pub fn new() -> Self {
+        let mut channels = Vec::new();
+
+        // Initialize 96 125 kHz upstream channels
+        for i in 0..CN470_MAX_CHANNELS as u32 {
+            channels
+                .push(Channel {
+                    frequency: UPLINK_BASE_FREQUENCY + i * CHANNEL_SPACING,
+                    min_dr: DataRate::SF12BW125,
+                    max_dr: DataRate::SF7BW125,
+                    enabled: true,
+                    downlink_frequency: None,
+                })
+                .unwrap();
+        }
+
+        Self {
+            channels,
+            data_rate: 0,
+            tx_power: 0,
+            last_channel: 0,
+            last_beacon_channel: 0,
+        }
+    }
+
+    /// Get enabled channels
+    pub fn get_enabled_channels(&self) -> Vec<Channel, CN470_MAX_CHANNELS> {
+        self.enabled_channels().cloned().collect()
+    }
+
+    /// Enable only the 8 channels of a sub-band (0-11)
+    ///
+    /// Most CN470 gateways listen on 8 channels, so devices are usually
+    /// restricted to one block of 8 consecutive uplink channels.
+    pub fn set_sub_band(&mut self, sub_band: u8) {
+        let sub_band = sub_band.min(11) as usize;
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            channel.enabled = i / 8 == sub_band;
+        }
+    }
+
+    /// Get the downlink channel used in RX1 for an uplink channel
+    pub fn rx1_channel(uplink_channel: u8) -> u8 {
+        uplink_channel % DOWNLINK_CHANNELS as u8
+    }
+}
+
+impl Region for CN470 {
+    fn name(&self) -> &'static str {
+        "CN470"
+    }
+
+    fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    fn get_max_channels(&self) -> usize {
+        CN470_MAX_CHANNELS
+    }
+
+    fn get_channel(&self, index: u8) -> Option<&Channel> {
+        self.channels.get(index as usize)
+    }
+
+    fn is_valid_frequency(&self, frequency: u32) -> bool {
+        frequency >= self.min_frequency() && frequency <= self.max_frequency()
+    }
+
+    fn is_valid_data_rate(&self, data_rate: u8) -> bool {
+        // CN470 supports DR0-DR5 (SF12/125kHz to SF7/125kHz)
+        data_rate <= 5
+    }
+
+    fn data_rate(&self) -> u8 {
+        self.data_rate
+    }
+
+    fn set_data_rate(&mut self, data_rate: u8) {
+        if self.is_valid_data_rate(data_rate) {
+            self.data_rate = data_rate;
+        }
+    }
+
+    fn data_rate_from_index(&self, index: u8) -> Option<DataRate> {
+        match index {
+            0..=5 => Some(DataRate::from_index(index)),
+            _ => None,
+        }
+    }
+
+    fn is_valid_tx_power(&self, tx_power: u8) -> bool {
+        // TXPower 0-7: MaxEIRP down to MaxEIRP - 14 dB
+        tx_power <= 7
+    }
+
+    fn set_tx_power(&mut self, tx_power: u8) {
+        if self.is_valid_tx_power(tx_power) {
+            self.tx_power = tx_power;
+        }
+    }
+
+    fn tx_power(&self) -> u8 {
+        self.tx_power
+    }
+
+    fn max_eirp(&self) -> u8 {
+        MAX_EIRP
+    }
+
+    fn is_valid_channel_mask(&self, _ch_mask: u16, ch_mask_cntl: u8) -> bool {
+        // ChMaskCntl 0-5 select a block of 16 channels, 6 enables all channels
+        ch_mask_cntl <= 6
+    }
+
+    fn apply_channel_mask(&mut self, ch_mask: u16, ch_mask_cntl: u8) {
+        if ch_mask_cntl <= 5 {
+            let base_idx = (ch_mask_cntl as usize) * 16;
+            for i in 0..16 {
+                if let Some(channel) = self.channels.get_mut(base_idx + i) {
+                    channel.enabled = (ch_mask & (1 << i)) != 0;
+                }
+            }
+        } else if ch_mask_cntl == 6 {
+            for channel in self.channels.iter_mut() {
+                channel.enabled = true;
+            }
+        }
+    }
+
+    fn min_frequency(&self) -> u32 {
+        470_000_000
+    }
+
+    fn max_frequency(&self) -> u32 {
+        510_000_000
+    }
+
+    fn rx2_frequency(&self) -> u32 {
+        RX2_FREQUENCY
+    }
+
+    fn rx2_data_rate(&self) -> u8 {
+        0 // DR0 (SF12/125kHz)
+    }
+
+    fn max_payload_size(&self, data_rate: u8) -> u8 {
+        MAX_PAYLOAD.get(data_rate as usize).copied().unwrap_or(0)
+    }
+
+    fn receive_delay1(&self) -> u32 {
+        1_000 // 1 second
+    }
+
+    fn receive_delay2(&self) -> u32 {
+        2_000 // 2 seconds
+    }
+
+    fn join_accept_delay1(&self) -> u32 {
+        5_000 // 5 seconds
+    }
+
+    fn join_accept_delay2(&self) -> u32 {
+        6_000 // 6 seconds
+    }
+
+    fn enabled_channels(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.iter().filter(|c| c.enabled)
+    }
+
+    fn get_next_channel(&mut self) -> Option<Channel> {
+        let enabled_channels: Vec<Channel, CN470_MAX_CHANNELS> =
+            self.enabled_channels().cloned().collect();
+        if enabled_channels.is_empty() {
+            return None;
+        }
+        let next_channel = (self.last_channel + 1) % enabled_channels.len();
+        let channel = enabled_channels[next_channel].clone();
+        self.last_channel = next_channel;
+        Some(channel)
+    }
+
+    fn rx1_window(&self, tx_channel: &Channel, rx1_dr_offset: u8) -> (u32, DataRate) {
+        // RX1 uses downlink channel (uplink channel modulo 48)
+        let uplink_channel =
+            tx_channel.frequency.saturating_sub(UPLINK_BASE_FREQUENCY) / CHANNEL_SPACING;
+        let frequency =
+            DOWNLINK_BASE_FREQUENCY + (uplink_channel % DOWNLINK_CHANNELS) * CHANNEL_SPACING;
+
+        // RX1 data rate is the uplink data rate minus the offset
+        let rx1_dr = self.data_rate.saturating_sub(rx1_dr_offset.min(5));
+        let data_rate = DataRate::from_index(rx1_dr);
+
+        (frequency, data_rate)
+    }
+
+    fn rx2_window(&self) -> (u32, DataRate) {
+        (RX2_FREQUENCY, DataRate::SF12BW125)
+    }
+
+    fn get_beacon_channels(&self) -> Vec<Channel, 8> {
+        let mut channels = Vec::new();
+        // CN470 beacon channels: 508.3 MHz + n * 200 kHz, n = 0..7
+        for i in 0..8 {
+            channels
+                .push(Channel {
+                    frequency: BEACON_BASE_FREQUENCY + i * CHANNEL_SPACING,
+                    min_dr: DataRate::SF10BW125,
+                    max_dr: DataRate::SF10BW125,
+                    enabled: true,
+                    downlink_frequency: None,
+                })
+                .unwrap();
+        }
+        channels
+    }
+
+    fn beacon_layout(&self) -> BeaconLayout {
+        BeaconLayout { rfu1: 3, rfu2: 1 }
+    }
+
+    fn get_next_beacon_channel(&mut self) -> Option<Channel> {
+        let beacon_channels = self.get_beacon_channels();
+        if beacon_channels.is_empty() {
+            return None;
+        }
+
+        let index = (self.last_beacon_channel + 1) % beacon_channels.len();
+        self.last_beacon_channel = index;
+        Some(beacon_channels[index].clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,283 @@
+//! EU868 region (863-870 MHz)
+
+use core::any::Any;
+use heapless::Vec;
+
+use super::{set_dynamic_channel, set_dynamic_downlink_frequency, Band, Channel, DataRate, Region};
+
+/// Maximum number of channels in EU868
+pub const EU868_MAX_CHANNELS: usize = 16;
+
+/// Default channel frequencies
+const DEFAULT_CHANNELS: [u32; 3] = [868_100_000, 868_300_000, 868_500_000];
+
+/// Default maximum EIRP in dBm
+const MAX_EIRP: u8 = 16;
+
+/// RX2 frequency
+const RX2_FREQUENCY: u32 = 869_525_000;
+
+/// Beacon frequency
+const BEACON_FREQUENCY: u32 = 869_525_000;
+
+/// Duty cycle sub-bands g, g1, g2, g3 and g4 of ETSI EN 300 220
+const BANDS: [Band; 5] = [
+    Band {
+        min_frequency: 863_000_000,
+        max_frequency: 868_000_000,
+        duty_cycle: 100,
+    },
+    Band {
+        min_frequency: 868_000_000,
+        max_frequency: 868_600_000,
+        duty_cycle: 100,
+    },
+    Band {
+        min_frequency: 868_700_000,
+        max_frequency: 869_200_000,
+        duty_cycle: 1_000,
+    },
+    Band {
+        min_frequency: 869_400_000,
+        max_frequency: 869_650_000,
+        duty_cycle: 10,
+    },
+    Band {
+        min_frequency: 869_700_000,
+        max_frequency: 870_000_000,
+        duty_cycle: 100,
+    },
+];
+
+/// Maximum MACPayload size per data rate
+const MAX_PAYLOAD: [u8; 7] = [59, 59, 59, 123, 230, 230, 230];
+
+/// EU868 region implementation
+#[derive(Debug, Clone)]
+pub struct EU868 {
+    channels: Vec<Channel, EU868_MAX_CHANNELS>,
+    data_rate: u8,
+    tx_power: u8,
+    last_channel: usize,
+}
+
+impl Default for EU868 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EU868 {
+    /// Create new EU868 region
+    pub fn new() -> Self {
+        let mut channels = Vec::new();
+
+        // Three default join channels, DR0-DR5
+        for frequency in DEFAULT_CHANNELS {
+            channels
+                .push(Channel {
+                    frequency,
+                    min_dr: DataRate::SF12BW125,
+                    max_dr: DataRate::SF7BW125,
+                    enabled: true,
+                    downlink_frequency: None,
+                })
+                .unwrap();
+        }
+
+        Self {
+            channels,
+            data_rate: 0,
+            tx_power: 0,
+            last_channel: 0,
+        }
+    }
+}
+
+impl Region for EU868 {
+    fn name(&self) -> &'static str {
+        "EU868"
+    }
+
+    fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    fn get_max_channels(&self) -> usize {
+        EU868_MAX_CHANNELS
+    }
+
+    fn get_channel(&self, index: u8) -> Option<&Channel> {
+        self.channels.get(index as usize)
+    }
+
+    fn is_valid_frequency(&self, frequency: u32) -> bool {
+        frequency >= self.min_frequency() && frequency <= self.max_frequency()
+    }
+
+    fn is_valid_data_rate(&self, data_rate: u8) -> bool {
+        // DR0-DR6 are LoRa, DR7 (FSK) is not supported
+        data_rate <= 6
+    }
+
+    fn data_rate(&self) -> u8 {
+        self.data_rate
+    }
+
+    fn set_data_rate(&mut self, data_rate: u8) {
+        if self.is_valid_data_rate(data_rate) {
+            self.data_rate = data_rate;
+        }
+    }
+
+    fn is_valid_tx_power(&self, tx_power: u8) -> bool {
+        // TXPower 0-7: MaxEIRP down to MaxEIRP - 14 dB
+        tx_power <= 7
+    }
+
+    fn set_tx_power(&mut self, tx_power: u8) {
+        if self.is_valid_tx_power(tx_power) {
+            self.tx_power = tx_power;
+        }
+    }
+
+    fn tx_power(&self) -> u8 {
+        self.tx_power
+    }
+
+    fn max_eirp(&self) -> u8 {
+        MAX_EIRP
+    }
+
+    fn set_channel(&mut self, index: u8, channel: Channel) -> bool {
+        set_dynamic_channel(
+            &mut self.channels,
+            DEFAULT_CHANNELS.len(),
+            index as usize,
+            channel,
+        )
+    }
+
+    fn bands(&self) -> &[Band] {
+        &BANDS
+    }
+
+    fn set_downlink_frequency(&mut self, index: u8, frequency: Option<u32>) -> bool {
+        set_dynamic_downlink_frequency(&mut self.channels, index as usize, frequency)
+    }
+
+    fn is_valid_channel_mask(&self, ch_mask: u16, ch_mask_cntl: u8) -> bool {
+        match ch_mask_cntl {
+            // Mask must not enable undefined channels
+            0 => (0..EU868_MAX_CHANNELS)
+                .filter(|i| ch_mask & (1 << i) != 0)
+                .all(|i| self.channels.get(i).is_some_and(|c| c.frequency != 0)),
+            // All channels on
+            6 => true,
+            _ => false,
+        }
+    }
+
+    fn apply_channel_mask(&mut self, ch_mask: u16, ch_mask_cntl: u8) {
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            match ch_mask_cntl {
+                0 => channel.enabled = (ch_mask & (1 << i)) != 0,
+                6 => channel.enabled = channel.frequency != 0,
+                _ => {}
+            }
+        }
+    }
+
+    fn min_frequency(&self) -> u32 {
+        863_000_000
+    }
+
+    fn max_frequency(&self) -> u32 {
+        870_000_000
+    }
+
+    fn rx2_frequency(&self) -> u32 {
+        RX2_FREQUENCY
+    }
+
+    fn rx2_data_rate(&self) -> u8 {
+        0 // DR0 (SF12/125kHz)
+    }
+
+    fn max_payload_size(&self, data_rate: u8) -> u8 {
+        MAX_PAYLOAD.get(data_rate as usize).copied().unwrap_or(0)
+    }
+
+    fn receive_delay1(&self) -> u32 {
+        1_000 // 1 second
+    }
+
+    fn receive_delay2(&self) -> u32 {
+        2_000 // 2 seconds
+    }
+
+    fn join_accept_delay1(&self) -> u32 {
+        5_000 // 5 seconds
+    }
+
+    fn join_accept_delay2(&self) -> u32 {
+        6_000 // 6 seconds
+    }
+
+    fn enabled_channels(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.iter().filter(|c| c.enabled)
+    }
+
+    fn get_next_channel(&mut self) -> Option<Channel> {
+        let enabled_channels: Vec<Channel, EU868_MAX_CHANNELS> =
+            self.enabled_channels().cloned().collect();
+        if enabled_channels.is_empty() {
+            return None;
+        }
+        let next_channel = (self.last_channel + 1) % enabled_channels.len();
+        let channel = enabled_channels[next_channel].clone();
+        self.last_channel = next_channel;
+        Some(channel)
+    }
+
+    fn rx1_window(&self, tx_channel: &Channel, rx1_dr_offset: u8) -> (u32, DataRate) {
+        // RX1 uses the channel downlink frequency and the uplink data rate minus the offset
+        let rx1_dr = self.data_rate.saturating_sub(rx1_dr_offset.min(5));
+        let data_rate = self
+            .data_rate_from_index(rx1_dr)
+            .unwrap_or(DataRate::SF12BW125);
+
+        (tx_channel.rx1_frequency(), data_rate)
+    }
+
+    fn rx2_window(&self) -> (u32, DataRate) {
+        (RX2_FREQUENCY, DataRate::SF12BW125)
+    }
+
+    fn get_beacon_channels(&self) -> Vec<Channel, 8> {
+        let mut channels = Vec::new();
+        // EU868 beacons use a single channel at DR3
+        channels
+            .push(Channel {
+                frequency: BEACON_FREQUENCY,
+                min_dr: DataRate::SF9BW125,
+                max_dr: DataRate::SF9BW125,
+                enabled: true,
+                downlink_frequency: None,
+            })
+            .unwrap();
+        channels
+    }
+
+    fn get_next_beacon_channel(&mut self) -> Option<Channel> {
+        self.get_beacon_channels().first().cloned()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
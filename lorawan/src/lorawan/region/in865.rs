@@ -0,0 +1,262 @@
+//! IN865 region (865-867 MHz)
+
+use core::any::Any;
+use heapless::Vec;
+
+use super::{set_dynamic_channel, set_dynamic_downlink_frequency, Channel, DataRate, Region};
+
+/// Maximum number of channels in IN865
+pub const IN865_MAX_CHANNELS: usize = 16;
+
+/// Default channel frequencies
+const DEFAULT_CHANNELS: [u32; 3] = [865_062_500, 865_402_500, 865_985_000];
+
+/// Default maximum EIRP in dBm
+const MAX_EIRP: u8 = 30;
+
+/// RX2 frequency
+const RX2_FREQUENCY: u32 = 866_550_000;
+
+/// Beacon frequency
+const BEACON_FREQUENCY: u32 = 866_550_000;
+
+/// Maximum MACPayload size per data rate
+const MAX_PAYLOAD: [u8; 6] = [59, 59, 59, 123, 250, 250];
+
+/// IN865 region implementation
+#[derive(Debug, Clone)]
+pub struct IN865 {
+    channels: Vec<Channel, IN865_MAX_CHANNELS>,
+    data_rate: u8,
+    tx_power: u8,
+    last_channel: usize,
+}
+
+impl Default for IN865 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IN865 {
+    /// Create new IN865 region
+    pub fn new() -> Self {
+        let mut channels = Vec::new();
+
+        // Three default channels, DR0-DR5
+        for frequency in DEFAULT_CHANNELS {
+            channels
+                .push(Channel {
+                    frequency,
+                    min_dr: DataRate::SF12BW125,
+                    max_dr: DataRate::SF7BW125,
+                    enabled: true,
+                    downlink_frequency: None,
+                })
+                .unwrap();
+        }
+
+        Self {
+            channels,
+            data_rate: 0,
+            tx_power: 0,
+            last_channel: 0,
+        }
+    }
+}
+
+impl Region for IN865 {
+    fn name(&self) -> &'static str {
+        "IN865"
+    }
+
+    fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    fn get_max_channels(&self) -> usize {
+        IN865_MAX_CHANNELS
+    }
+
+    fn get_channel(&self, index: u8) -> Option<&Channel> {
+        self.channels.get(index as usize)
+    }
+
+    fn is_valid_frequency(&self, frequency: u32) -> bool {
+        frequency >= self.min_frequency() && frequency <= self.max_frequency()
+    }
+
+    fn is_valid_data_rate(&self, data_rate: u8) -> bool {
+        // DR0-DR5 are LoRa, DR6 is RFU and DR7 (FSK) is not supported
+        data_rate <= 5
+    }
+
+    fn data_rate(&self) -> u8 {
+        self.data_rate
+    }
+
+    fn set_data_rate(&mut self, data_rate: u8) {
+        if self.is_valid_data_rate(data_rate) {
+            self.data_rate = data_rate;
+        }
+    }
+
+    fn data_rate_from_index(&self, index: u8) -> Option<DataRate> {
+        match index {
+            0..=5 => Some(DataRate::from_index(index)),
+            _ => None,
+        }
+    }
+
+    fn is_valid_tx_power(&self, tx_power: u8) -> bool {
+        // TXPower 0-10: MaxEIRP down to MaxEIRP - 20 dB
+        tx_power <= 10
+    }
+
+    fn set_tx_power(&mut self, tx_power: u8) {
+        if self.is_valid_tx_power(tx_power) {
+            self.tx_power = tx_power;
+        }
+    }
+
+    fn tx_power(&self) -> u8 {
+        self.tx_power
+    }
+
+    fn max_eirp(&self) -> u8 {
+        MAX_EIRP
+    }
+
+    fn set_channel(&mut self, index: u8, channel: Channel) -> bool {
+        set_dynamic_channel(
+            &mut self.channels,
+            DEFAULT_CHANNELS.len(),
+            index as usize,
+            channel,
+        )
+    }
+
+    fn set_downlink_frequency(&mut self, index: u8, frequency: Option<u32>) -> bool {
+        set_dynamic_downlink_frequency(&mut self.channels, index as usize, frequency)
+    }
+
+    fn is_valid_channel_mask(&self, ch_mask: u16, ch_mask_cntl: u8) -> bool {
+        match ch_mask_cntl {
+            // Mask must not enable undefined channels
+            0 => (0..IN865_MAX_CHANNELS)
+                .filter(|i| ch_mask & (1 << i) != 0)
+                .all(|i| self.channels.get(i).is_some_and(|c| c.frequency != 0)),
+            // All channels on
+            6 => true,
+            _ => false,
+        }
+    }
+
+    fn apply_channel_mask(&mut self, ch_mask: u16, ch_mask_cntl: u8) {
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            match ch_mask_cntl {
+                0 => channel.enabled = (ch_mask & (1 << i)) != 0,
+                6 => channel.enabled = channel.frequency != 0,
+                _ => {}
+            }
+        }
+    }
+
+    fn min_frequency(&self) -> u32 {
+        865_000_000
+    }
+
+    fn max_frequency(&self) -> u32 {
+        867_000_000
+    }
+
+    fn rx2_frequency(&self) -> u32 {
+        RX2_FREQUENCY
+    }
+
+    fn rx2_data_rate(&self) -> u8 {
+        2 // DR2 (SF10/125kHz)
+    }
+
+    fn max_payload_size(&self, data_rate: u8) -> u8 {
+        MAX_PAYLOAD.get(data_rate as usize).copied().unwrap_or(0)
+    }
+
+    fn receive_delay1(&self) -> u32 {
+        1_000 // 1 second
+    }
+
+    fn receive_delay2(&self) -> u32 {
+        2_000 // 2 seconds
+    }
+
+    fn join_accept_delay1(&self) -> u32 {
+        5_000 // 5 seconds
+    }
+
+    fn join_accept_delay2(&self) -> u32 {
+        6_000 // 6 seconds
+    }
+
+    fn enabled_channels(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.iter().filter(|c| c.enabled)
+    }
+
+    fn get_next_channel(&mut self) -> Option<Channel> {
+        let enabled_channels: Vec<Channel, IN865_MAX_CHANNELS> =
+            self.enabled_channels().cloned().collect();
+        if enabled_channels.is_empty() {
+            return None;
+        }
+        let next_channel = (self.last_channel + 1) % enabled_channels.len();
+        let channel = enabled_channels[next_channel].clone();
+        self.last_channel = next_channel;
+        Some(channel)
+    }
+
+    fn rx1_window(&self, tx_channel: &Channel, rx1_dr_offset: u8) -> (u32, DataRate) {
+        // RX1DROffset 6 and 7 raise the data rate by 1 and 2
+        let offset = match rx1_dr_offset {
+            6 => -1,
+            7 => -2,
+            offset => offset.min(5) as i8,
+        };
+
+        // RX1 uses the channel downlink frequency, the data rate is capped at DR5
+        let rx1_dr = (self.data_rate as i8 - offset).clamp(0, 5) as u8;
+        let data_rate = DataRate::from_index(rx1_dr);
+
+        (tx_channel.rx1_frequency(), data_rate)
+    }
+
+    fn rx2_window(&self) -> (u32, DataRate) {
+        (RX2_FREQUENCY, DataRate::SF10BW125)
+    }
+
+    fn get_beacon_channels(&self) -> Vec<Channel, 8> {
+        let mut channels = Vec::new();
+        // IN865 beacons use a single channel at DR4
+        channels
+            .push(Channel {
+                frequency: BEACON_FREQUENCY,
+                min_dr: DataRate::SF8BW125,
+                max_dr: DataRate::SF8BW125,
+                enabled: true,
+                downlink_frequency: None,
+            })
+            .unwrap();
+        channels
+    }
+
+    fn get_next_beacon_channel(&mut self) -> Option<Channel> {
+        self.get_beacon_channels().first().cloned()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,531 @@
+//! LoRaWAN regional parameters
+//!
+//! This module defines the [`Region`] trait together with the channel plans
+//! of the supported regions.
+
+use core::any::Any;
+use core::fmt::Debug;
+use heapless::Vec;
+
+use super::phy::{time_on_air, BEACON_PREAMBLE_LENGTH, PREAMBLE_LENGTH, PUBLIC_SYNC_WORD};
+use crate::config::device::DevAddr;
+use crate::radio::traits::ModulationParams;
+
+pub mod as923;
+pub mod cn470;
+pub mod eu868;
+pub mod in865;
+pub mod kr920;
+pub mod ru864;
+pub mod us915;
+
+pub use as923::{AS923Group, AS923};
+pub use cn470::CN470;
+pub use eu868::EU868;
+pub use in865::IN865;
+pub use kr920::KR920;
+pub use ru864::RU864;
+pub use us915::{ChannelSelection, US915};
+
+/// Maximum number of channels of any region (CN470)
+pub const MAX_CHANNELS: usize = 96;
+
+/// Beacon period in seconds
+pub const BEACON_PERIOD_SECONDS: u32 = 128;
+
+/// Channel configuration
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Channel {
+    /// Frequency in Hz
+    pub frequency: u32,
+    /// Minimum data rate
+    pub min_dr: DataRate,
+    /// Maximum data rate
+    pub max_dr: DataRate,
+    /// Channel enabled
+    pub enabled: bool,
+    /// RX1 frequency set by DlChannelReq, the uplink frequency if `None`
+    pub downlink_frequency: Option<u32>,
+}
+
+impl Channel {
+    /// Frequency of the RX1 window for uplinks on this channel
+    pub fn rx1_frequency(&self) -> u32 {
+        self.downlink_frequency.unwrap_or(self.frequency)
+    }
+
+    /// Unused channel slot of a dynamic channel plan
+    pub(crate) fn unused() -> Self {
+        Self {
+            frequency: 0,
+            min_dr: DataRate::SF12BW125,
+            max_dr: DataRate::SF12BW125,
+            enabled: false,
+            downlink_frequency: None,
+        }
+    }
+}
+
+/// Data rate configuration
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DataRate {
+    /// SF12/125kHz
+    SF12BW125,
+    /// SF11/125kHz
+    SF11BW125,
+    /// SF10/125kHz
+    SF10BW125,
+    /// SF9/125kHz
+    SF9BW125,
+    /// SF8/125kHz
+    SF8BW125,
+    /// SF7/125kHz
+    SF7BW125,
+    /// SF7/250kHz
+    SF7BW250,
+    /// SF12/500kHz
+    SF12BW500,
+    /// SF11/500kHz
+    SF11BW500,
+    /// SF10/500kHz
+    SF10BW500,
+    /// SF9/500kHz
+    SF9BW500,
+    /// SF8/500kHz
+    SF8BW500,
+    /// SF7/500kHz
+    SF7BW500,
+}
+
+impl DataRate {
+    /// Convert from data rate index to DataRate
+    pub fn from_index(index: u8) -> Self {
+        match index {
+            0 => DataRate::SF12BW125,
+            1 => DataRate::SF11BW125,
+            2 => DataRate::SF10BW125,
+            3 => DataRate::SF9BW125,
+            4 => DataRate::SF8BW125,
+            5 => DataRate::SF7BW125,
+            6 => DataRate::SF8BW500,
+            _ => DataRate::SF12BW125, // Default to slowest rate for invalid index
+        }
+    }
+
+    /// Get spreading factor
+    pub fn spreading_factor(&self) -> u8 {
+        match self {
+            DataRate::SF12BW125 | DataRate::SF12BW500 => 12,
+            DataRate::SF11BW125 | DataRate::SF11BW500 => 11,
+            DataRate::SF10BW125 | DataRate::SF10BW500 => 10,
+            DataRate::SF9BW125 | DataRate::SF9BW500 => 9,
+            DataRate::SF8BW125 | DataRate::SF8BW500 => 8,
+            DataRate::SF7BW125 | DataRate::SF7BW250 | DataRate::SF7BW500 => 7,
+        }
+    }
+
+    /// Get bandwidth in Hz
+    pub fn bandwidth(&self) -> u32 {
+        match self {
+            DataRate::SF7BW250 => 250_000,
+            DataRate::SF12BW500
+            | DataRate::SF11BW500
+            | DataRate::SF10BW500
+            | DataRate::SF9BW500
+            | DataRate::SF8BW500
+            | DataRate::SF7BW500 => 500_000,
+            _ => 125_000,
+        }
+    }
+
+    /// Get the duration of one LoRa symbol in microseconds
+    pub fn symbol_time_us(&self) -> u32 {
+        ((1_000_000u64 << self.spreading_factor()) / self.bandwidth() as u64) as u32
+    }
+
+    /// Get the modulation used by LoRaWAN uplinks at this data rate
+    ///
+    /// LoRaWAN uses 8 preamble symbols, explicit header, CRC, coding rate
+    /// 4/5 and the public sync word.
+    pub fn modulation(&self) -> ModulationParams {
+        ModulationParams {
+            spreading_factor: self.spreading_factor(),
+            bandwidth: self.bandwidth(),
+            coding_rate: 5,
+            preamble_length: PREAMBLE_LENGTH,
+            implicit_header: None,
+            crc: true,
+            iq_inverted: false,
+            sync_word: PUBLIC_SYNC_WORD,
+        }
+    }
+
+    /// Get the modulation of downlinks at this data rate
+    ///
+    /// Downlinks are sent with inverted IQ, so devices do not receive each
+    /// other's uplinks.
+    pub fn downlink_modulation(&self) -> ModulationParams {
+        ModulationParams {
+            iq_inverted: true,
+            ..self.modulation()
+        }
+    }
+
+    /// Get the modulation of Class B beacons of `size` bytes at this data rate
+    ///
+    /// Beacons use 10 preamble symbols, implicit header and no CRC, with IQ
+    /// not inverted.
+    pub fn beacon_modulation(&self, size: u8) -> ModulationParams {
+        ModulationParams {
+            preamble_length: BEACON_PREAMBLE_LENGTH,
+            implicit_header: Some(size),
+            crc: false,
+            ..self.modulation()
+        }
+    }
+
+    /// Get the time-on-air of a PHYPayload in milliseconds
+    pub fn time_on_air_ms(&self, payload_len: usize) -> u32 {
+        time_on_air(payload_len, &self.modulation())
+    }
+}
+
+/// Sub-band with its own duty cycle limit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Band {
+    /// Lowest frequency of the band in Hz
+    pub min_frequency: u32,
+    /// Highest frequency of the band in Hz
+    pub max_frequency: u32,
+    /// Duty cycle limit as 1/duty_cycle, e.g. 100 for 1%
+    pub duty_cycle: u16,
+}
+
+/// Listen-Before-Talk parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LbtConfig {
+    /// RSSI above which a channel is considered busy (dBm)
+    pub threshold_dbm: i16,
+    /// Time to listen on the channel before deciding (ms)
+    pub scan_duration_ms: u32,
+    /// Number of busy channels tolerated before giving up
+    pub max_retries: u8,
+}
+
+/// Layout of the beacon frame of a region
+///
+/// A beacon is `RFU | Time | CRC | GwSpecific | RFU | CRC`, only the sizes
+/// of the two RFU fields differ between regions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BeaconLayout {
+    /// Size of the RFU field before Time
+    pub rfu1: usize,
+    /// Size of the RFU field after GwSpecific
+    pub rfu2: usize,
+}
+
+impl BeaconLayout {
+    /// Layout of the 17-byte beacon used by most regions
+    pub const DEFAULT: Self = Self { rfu1: 2, rfu2: 0 };
+
+    /// Get the size of the beacon frame
+    pub const fn size(&self) -> usize {
+        self.rfu1 + 4 + 2 + 7 + self.rfu2 + 2
+    }
+}
+
+/// LoRaWAN region trait
+pub trait Region: Any + Debug + Clone {
+    /// Get region name
+    fn name(&self) -> &'static str;
+
+    /// Get number of channels
+    fn channels(&self) -> usize;
+
+    /// Get maximum number of channels
+    fn get_max_channels(&self) -> usize;
+
+    /// Get channel by index
+    fn get_channel(&self, index: u8) -> Option<&Channel>;
+
+    /// Check if frequency is valid for this region
+    fn is_valid_frequency(&self, frequency: u32) -> bool;
+
+    /// Check if data rate is valid for this region
+    fn is_valid_data_rate(&self, data_rate: u8) -> bool;
+
+    /// Get the data rate index used for uplinks
+    fn data_rate(&self) -> u8;
+
+    /// Set data rate
+    fn set_data_rate(&mut self, data_rate: u8);
+
+    /// Get the modulation of a data rate index
+    ///
+    /// Defaults to the table shared by most regions: DR0-DR5 are SF12-SF7 at
+    /// 125 kHz and DR6 is SF7 at 250 kHz.
+    fn data_rate_from_index(&self, index: u8) -> Option<DataRate> {
+        match index {
+            0..=5 => Some(DataRate::from_index(index)),
+            6 => Some(DataRate::SF7BW250),
+            _ => None,
+        }
+    }
+
+    /// Get the lowest data rate index with a modulation
+    ///
+    /// Uplink data rates come first in every table, so the channel limits
+    /// map to their uplink index.
+    fn data_rate_index(&self, data_rate: DataRate) -> Option<u8> {
+        (0..16).find(|&index| self.data_rate_from_index(index) == Some(data_rate))
+    }
+
+    /// Check if TX power is valid for this region
+    fn is_valid_tx_power(&self, tx_power: u8) -> bool;
+
+    /// Set TX power
+    fn set_tx_power(&mut self, tx_power: u8);
+
+    /// Get the TX power index used for uplinks
+    fn tx_power(&self) -> u8;
+
+    /// Get the maximum EIRP in dBm, which TX power index 0 corresponds to
+    fn max_eirp(&self) -> u8;
+
+    /// Get the output power in dBm of a TX power index
+    ///
+    /// Every TX power step lowers the output power by 2 dB from the maximum
+    /// EIRP of the region.
+    fn tx_power_dbm(&self, tx_power: u8) -> i8 {
+        (self.max_eirp() as i8).saturating_sub(2 * tx_power.min(15) as i8)
+    }
+
+    /// Get the duty cycle bands of the region
+    ///
+    /// Regions without duty cycle limits have no bands.
+    fn bands(&self) -> &[Band] {
+        &[]
+    }
+
+    /// Get the index of the duty cycle band containing a frequency
+    fn band_index(&self, frequency: u32) -> Option<usize> {
+        self.bands()
+            .iter()
+            .position(|b| (b.min_frequency..=b.max_frequency).contains(&frequency))
+    }
+
+    /// Create or modify a channel as requested by NewChannelReq
+    ///
+    /// Returns `false` if the region does not allow the channel to be defined,
+    /// which is the case for regions with a fixed channel plan.
+    fn set_channel(&mut self, _index: u8, _channel: Channel) -> bool {
+        false
+    }
+
+    /// Create or modify an enabled channel
+    ///
+    /// Returns `false` if the data rates are unknown or the channel cannot be
+    /// defined, e.g. because it is a default channel.
+    fn add_or_modify_channel(&mut self, index: u8, frequency: u32, min_dr: u8, max_dr: u8) -> bool {
+        match (
+            self.data_rate_from_index(min_dr),
+            self.data_rate_from_index(max_dr),
+        ) {
+            (Some(min_dr), Some(max_dr)) => self.set_channel(
+                index,
+                Channel {
+                    frequency,
+                    min_dr,
+                    max_dr,
+                    enabled: true,
+                    downlink_frequency: None,
+                },
+            ),
+            _ => false,
+        }
+    }
+
+    /// Set the RX1 frequency of a channel as requested by DlChannelReq
+    ///
+    /// `None` restores the uplink frequency. Returns `false` if the channel is
+    /// not defined or the region does not support the command.
+    fn set_downlink_frequency(&mut self, _index: u8, _frequency: Option<u32>) -> bool {
+        false
+    }
+
+    /// Remove a channel, as requested by a NewChannelReq with frequency 0
+    ///
+    /// Default channels cannot be removed.
+    fn remove_channel(&mut self, index: u8) -> bool {
+        self.set_channel(index, Channel::unused())
+    }
+
+    /// Apply the dwell time and EIRP limits of a TxParamSetupReq
+    ///
+    /// Returns `false` if the region does not support the command, in which
+    /// case it must be ignored.
+    fn set_tx_params(
+        &mut self,
+        _uplink_dwell_time: bool,
+        _downlink_dwell_time: bool,
+        _max_eirp: u8,
+    ) -> bool {
+        false
+    }
+
+    /// Check if channel mask is valid for this region
+    fn is_valid_channel_mask(&self, ch_mask: u16, ch_mask_cntl: u8) -> bool;
+
+    /// Apply channel mask to region
+    fn apply_channel_mask(&mut self, ch_mask: u16, ch_mask_cntl: u8);
+
+    /// Enable the default channels again
+    ///
+    /// Defaults to ChMaskCntl 6, which turns on all defined channels of the
+    /// region.
+    fn enable_default_channels(&mut self) {
+        self.apply_channel_mask(0x00FF, 6);
+    }
+
+    /// Get minimum frequency
+    fn min_frequency(&self) -> u32;
+
+    /// Get maximum frequency
+    fn max_frequency(&self) -> u32;
+
+    /// Get RX2 frequency
+    fn rx2_frequency(&self) -> u32;
+
+    /// Get RX2 data rate
+    fn rx2_data_rate(&self) -> u8;
+
+    /// Get maximum MACPayload size for data rate
+    ///
+    /// The MACPayload covers FHDR, FPort and FRMPayload, so the application
+    /// payload is limited to this size minus 8 bytes and the FOpts length.
+    fn max_payload_size(&self, data_rate: u8) -> u8;
+
+    /// Get receive delay 1 in milliseconds
+    ///
+    /// The MAC layer reads the delays once into its `TimingParams`.
+    fn receive_delay1(&self) -> u32;
+
+    /// Get receive delay 2 in milliseconds
+    fn receive_delay2(&self) -> u32;
+
+    /// Get join accept delay 1 in milliseconds
+    fn join_accept_delay1(&self) -> u32;
+
+    /// Get join accept delay 2 in milliseconds
+    fn join_accept_delay2(&self) -> u32;
+
+    /// Get enabled channels
+    fn enabled_channels(&self) -> impl Iterator<Item = &Channel>;
+
+    /// Get next channel for transmission
+    fn get_next_channel(&mut self) -> Option<Channel>;
+
+    /// Record the airtime of an uplink sent on `frequency`
+    ///
+    /// Regions that balance the airtime of their channels override this.
+    fn record_airtime(&mut self, _frequency: u32, _airtime_ms: u32) {}
+
+    /// Get RX1 window parameters
+    ///
+    /// The RX1 data rate is derived from the current uplink data rate and the
+    /// RX1DROffset of the session.
+    fn rx1_window(&self, tx_channel: &Channel, rx1_dr_offset: u8) -> (u32, DataRate);
+
+    /// Get RX2 window parameters
+    fn rx2_window(&self) -> (u32, DataRate);
+
+    /// Get the Listen-Before-Talk parameters of the region
+    ///
+    /// Regions that mandate LBT return the parameters the transmit path has
+    /// to apply before every uplink; all others return `None`.
+    fn lbt_config(&self) -> Option<LbtConfig> {
+        None
+    }
+
+    /// Get beacon channels
+    fn get_beacon_channels(&self) -> Vec<Channel, 8>;
+
+    /// Get the layout of the beacon frame
+    fn beacon_layout(&self) -> BeaconLayout {
+        BeaconLayout::DEFAULT
+    }
+
+    /// Get next beacon channel to search for a beacon on
+    fn get_next_beacon_channel(&mut self) -> Option<Channel>;
+
+    /// Get the beacon channel of the beacon period of `beacon_time`
+    ///
+    /// `beacon_time` is in seconds since the GPS epoch. Regions with several
+    /// beacon channels hop every period: `floor(beacon_time / 128) mod N`.
+    fn beacon_channel(&self, beacon_time: u32) -> Option<Channel> {
+        let channels = self.get_beacon_channels();
+        let period = beacon_time / BEACON_PERIOD_SECONDS;
+        channels
+            .get(period as usize % channels.len().max(1))
+            .cloned()
+    }
+
+    /// Get the default ping slot channel of a device in a beacon period
+    ///
+    /// Ping slots hop over the beacon channels with
+    /// `(DevAddr + floor(beacon_time / 128)) mod N`.
+    fn ping_slot_channel(&self, beacon_time: u32, dev_addr: DevAddr) -> Option<Channel> {
+        let channels = self.get_beacon_channels();
+        let period = beacon_time / BEACON_PERIOD_SECONDS;
+        let dev_addr = u32::from_le_bytes(*dev_addr.as_bytes());
+        let index = (dev_addr as u64 + period as u64) % channels.len().max(1) as u64;
+        channels.get(index as usize).cloned()
+    }
+
+    /// Convert to Any
+    fn as_any(&self) -> &dyn Any;
+
+    /// Convert to Any mut
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+/// Define a channel of a dynamic channel plan
+///
+/// Channels below `default_channels` are fixed by the region and cannot be
+/// modified. Gaps up to `index` are filled with disabled channels.
+pub(crate) fn set_dynamic_channel<const N: usize>(
+    channels: &mut Vec<Channel, N>,
+    default_channels: usize,
+    index: usize,
+    channel: Channel,
+) -> bool {
+    if index < default_channels || index >= N {
+        return false;
+    }
+
+    while channels.len() <= index {
+        if channels.push(Channel::unused()).is_err() {
+            return false;
+        }
+    }
+
+    channels[index] = channel;
+    true
+}
+
+/// Set the RX1 frequency of a channel of a dynamic channel plan
+pub(crate) fn set_dynamic_downlink_frequency<const N: usize>(
+    channels: &mut Vec<Channel, N>,
+    index: usize,
+    frequency: Option<u32>,
+) -> bool {
+    match channels.get_mut(index) {
+        Some(channel) if channel.frequency != 0 => {
+            channel.downlink_frequency = frequency;
+            true
+        }
+        _ => false,
+    }
+}
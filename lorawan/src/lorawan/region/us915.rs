@@ -0,0 +1,432 @@
+//! US915 region (902-928 MHz)
+
+use core::any::Any;
+use heapless::Vec;
+
+use super::{BeaconLayout, Channel, DataRate, Region};
+
+/// Maximum number of channels in US915 (64 125 kHz + 8 500 kHz)
+pub const US915_MAX_CHANNELS: usize = 72;
+
+/// Maximum conducted output power in dBm
+const MAX_EIRP: u8 = 30;
+
+/// Uplink channel selection of `US915::get_next_channel`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelSelection {
+    /// Enabled channels in order
+    RoundRobin,
+    /// Enabled channels in a shuffled order, all used once per hop cycle
+    Random {
+        /// Seed of the generator, e.g. from `Radio::random_u32`
+        seed: u32,
+    },
+    /// Enabled channel with the least airtime recorded by `record_airtime`
+    LowestAirtime,
+}
+
+/// US915 region implementation
+#[derive(Debug, Clone)]
+pub struct US915 {
+    channels: Vec<Channel, US915_MAX_CHANNELS>,
+    data_rate: DataRate,
+    tx_power: u8,
+    sub_band: u8,
+    last_channel: usize,
+    last_beacon_channel: usize,
+    selection: ChannelSelection,
+    /// State of the generator of random channel selection
+    random_state: u32,
+    /// Channels used in the current hop cycle, one bit per channel index
+    hop_cycle: u128,
+    /// Airtime of the uplinks on each channel in ms
+    airtime_ms: [u32; US915_MAX_CHANNELS],
+}
+
+impl Default for US915 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl US915 {
+    /// Create new US915 region
+    pub fn new() -> Self {
+        let mut channels = Vec::new();
+
+        // Initialize 64 125 kHz upstream channels
+        for i in 0..64 {
+            let freq = 902_300_000 + (i as u32 * 200_000);
+            channels
+                .push(Channel {
+                    frequency: freq,
+                    min_dr: DataRate::SF10BW125,
+                    max_dr: DataRate::SF7BW125,
+                    enabled: true,
+                    downlink_frequency: None,
+                })
+                .unwrap();
+        }
+
+        // Initialize 8 500 kHz upstream channels
+        for i in 0..8 {
+            let freq = 903_000_000 + (i as u32 * 1_600_000);
+            channels
+                .push(Channel {
+                    frequency: freq,
+                    min_dr: DataRate::SF8BW500,
+                    max_dr: DataRate::SF8BW500,
+                    enabled: true,
+                    downlink_frequency: None,
+                })
+                .unwrap();
+        }
+
+        Self {
+            channels,
+            data_rate: DataRate::SF10BW125,
+            tx_power: 0,
+            sub_band: 0,
+            last_channel: 0,
+            last_beacon_channel: 0,
+            selection: ChannelSelection::RoundRobin,
+            random_state: 1,
+            hop_cycle: 0,
+            airtime_ms: [0; US915_MAX_CHANNELS],
+        }
+    }
+
+    /// Get current data rate
+    pub fn get_data_rate(&self) -> DataRate {
+        self.data_rate
+    }
+
+    /// Get enabled channels
+    pub fn get_enabled_channels(&self) -> Vec<Channel, US915_MAX_CHANNELS> {
+        self.enabled_channels().cloned().collect()
+    }
+
+    /// Set the sub-band (0-7)
+    pub fn set_sub_band(&mut self, sub_band: u8) {
+        self.sub_band = sub_band.min(7);
+
+        // Enable only channels in the selected sub-band
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            if i < 64 {
+                // 125 kHz channels (0-63)
+                let channel_sub_band = (i / 8) as u8;
+                channel.enabled = channel_sub_band == self.sub_band;
+            } else {
+                // 500 kHz channels (64-71)
+                let channel_sub_band = (i - 64) as u8;
+                channel.enabled = channel_sub_band == self.sub_band;
+            }
+        }
+    }
+
+    /// Set how uplink channels are selected, round-robin by default
+    ///
+    /// Starts a new hop cycle.
+    pub fn set_channel_selection(&mut self, selection: ChannelSelection) {
+        if let ChannelSelection::Random { seed } = selection {
+            // Xorshift never leaves 0
+            self.random_state = seed.max(1);
+        }
+        self.selection = selection;
+        self.hop_cycle = 0;
+    }
+
+    /// Get how uplink channels are selected
+    pub fn channel_selection(&self) -> ChannelSelection {
+        self.selection
+    }
+
+    /// Next value of the channel selection generator
+    fn next_random(&mut self) -> u32 {
+        let mut x = self.random_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random_state = x;
+        x
+    }
+
+    /// Index of the next channel of the shuffled hop cycle
+    fn next_shuffled(&mut self, enabled: &[usize]) -> usize {
+        if enabled.iter().all(|&i| self.hop_cycle & (1 << i) != 0) {
+            // All channels used, start a new cycle
+            self.hop_cycle = 0;
+        }
+        let unused: Vec<usize, US915_MAX_CHANNELS> = enabled
+            .iter()
+            .copied()
+            .filter(|&i| self.hop_cycle & (1 << i) == 0)
+            .collect();
+        let index = unused[self.next_random() as usize % unused.len()];
+        self.hop_cycle |= 1 << index;
+        index
+    }
+
+    /// Index of the enabled channel with the least airtime
+    ///
+    /// Ties go to the first channel after the last one used.
+    fn next_lowest_airtime(&self, enabled: &[usize]) -> usize {
+        (1..=enabled.len())
+            .map(|offset| enabled[(self.last_channel + offset) % enabled.len()])
+            .min_by_key(|&i| self.airtime_ms[i])
+            .unwrap_or(enabled[0])
+    }
+
+    /// Configure for TTN US915
+    pub fn configure_ttn_us915(&mut self) {
+        // TTN US915 uses sub-band 2 (channels 8-15 and 65)
+        self.set_sub_band(1); // 0-based index for sub-band 2
+
+        // Enable only the 8 125 kHz channels and 1 500 kHz channel
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            channel.enabled = (8..16).contains(&i) || i == 65;
+        }
+    }
+}
+
+impl Region for US915 {
+    fn name(&self) -> &'static str {
+        "US915"
+    }
+
+    fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    fn get_max_channels(&self) -> usize {
+        US915_MAX_CHANNELS
+    }
+
+    fn get_channel(&self, index: u8) -> Option<&Channel> {
+        self.channels.get(index as usize)
+    }
+
+    fn is_valid_frequency(&self, frequency: u32) -> bool {
+        frequency >= self.min_frequency() && frequency <= self.max_frequency()
+    }
+
+    fn is_valid_data_rate(&self, data_rate: u8) -> bool {
+        // US915 supports DR0-DR4 (SF10/125kHz to SF7/125kHz)
+        // and DR8-DR13 (SF12/500kHz to SF7/500kHz)
+        data_rate <= 4 || (8..=13).contains(&data_rate)
+    }
+
+    fn is_valid_tx_power(&self, tx_power: u8) -> bool {
+        // TXPower 0-14: 30 dBm down to 2 dBm
+        tx_power <= 14
+    }
+
+    fn set_tx_power(&mut self, tx_power: u8) {
+        if self.is_valid_tx_power(tx_power) {
+            self.tx_power = tx_power;
+        }
+    }
+
+    fn tx_power(&self) -> u8 {
+        self.tx_power
+    }
+
+    fn max_eirp(&self) -> u8 {
+        MAX_EIRP
+    }
+
+    fn min_frequency(&self) -> u32 {
+        902_000_000
+    }
+
+    fn max_frequency(&self) -> u32 {
+        928_000_000
+    }
+
+    fn rx2_frequency(&self) -> u32 {
+        923_300_000
+    }
+
+    fn rx2_data_rate(&self) -> u8 {
+        8 // DR8 (SF12/500kHz)
+    }
+
+    fn max_payload_size(&self, data_rate: u8) -> u8 {
+        match data_rate {
+            0 => 19,        // SF10/125kHz
+            1 => 61,        // SF9/125kHz
+            2 => 133,       // SF8/125kHz
+            3 => 250,       // SF7/125kHz
+            4 => 250,       // SF8/500kHz
+            8 => 61,        // SF12/500kHz
+            9 => 137,       // SF11/500kHz
+            10..=13 => 250, // SF10/500kHz to SF7/500kHz
+            _ => 0,         // Invalid data rate
+        }
+    }
+
+    fn receive_delay1(&self) -> u32 {
+        1_000 // 1 second
+    }
+
+    fn receive_delay2(&self) -> u32 {
+        2_000 // 2 seconds
+    }
+
+    fn join_accept_delay1(&self) -> u32 {
+        5_000 // 5 seconds
+    }
+
+    fn join_accept_delay2(&self) -> u32 {
+        6_000 // 6 seconds
+    }
+
+    fn enabled_channels(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.iter().filter(|c| c.enabled)
+    }
+
+    fn get_next_channel(&mut self) -> Option<Channel> {
+        let enabled: Vec<usize, US915_MAX_CHANNELS> = (0..self.channels.len())
+            .filter(|&i| self.channels[i].enabled)
+            .collect();
+        if enabled.is_empty() {
+            return None;
+        }
+        let index = match self.selection {
+            ChannelSelection::RoundRobin => enabled[(self.last_channel + 1) % enabled.len()],
+            ChannelSelection::Random { .. } => self.next_shuffled(&enabled),
+            ChannelSelection::LowestAirtime => self.next_lowest_airtime(&enabled),
+        };
+        self.last_channel = enabled.iter().position(|&i| i == index).unwrap_or(0);
+        Some(self.channels[index].clone())
+    }
+
+    fn record_airtime(&mut self, frequency: u32, airtime_ms: u32) {
+        if let Some(index) = self.channels.iter().position(|c| c.frequency == frequency) {
+            self.airtime_ms[index] = self.airtime_ms[index].saturating_add(airtime_ms);
+        }
+    }
+
+    fn rx1_window(&self, tx_channel: &Channel, rx1_dr_offset: u8) -> (u32, DataRate) {
+        // Uplink channel number, 500 kHz channels follow the 64 125 kHz ones
+        let channel_index = if tx_channel.min_dr == DataRate::SF8BW500 {
+            64 + tx_channel.frequency.saturating_sub(903_000_000) / 1_600_000
+        } else {
+            tx_channel.frequency.saturating_sub(902_300_000) / 200_000
+        };
+
+        // RX1 uses downlink channel (uplink channel modulo 8)
+        let frequency = 923_300_000 + (channel_index % 8) * 600_000;
+
+        // RX1 data rate is DR10 + uplink DR - RX1DROffset, within DR8-DR13
+        let rx1_dr = (10 + self.data_rate() as i8 - rx1_dr_offset.min(3) as i8).clamp(8, 13);
+        let data_rate = self
+            .data_rate_from_index(rx1_dr as u8)
+            .unwrap_or(DataRate::SF12BW500);
+
+        (frequency, data_rate)
+    }
+
+    fn rx2_window(&self) -> (u32, DataRate) {
+        // RX2 uses fixed frequency and data rate
+        (923_300_000, DataRate::SF12BW500)
+    }
+
+    fn get_beacon_channels(&self) -> Vec<Channel, 8> {
+        let mut channels = Vec::new();
+        // US915 beacon channels: 923.3 MHz + n * 600 kHz, n = 0..7, at DR8
+        for i in 0..8 {
+            channels
+                .push(Channel {
+                    frequency: 923_300_000 + i * 600_000,
+                    min_dr: DataRate::SF12BW500,
+                    max_dr: DataRate::SF12BW500,
+                    enabled: true,
+                    downlink_frequency: None,
+                })
+                .unwrap();
+        }
+        channels
+    }
+
+    fn beacon_layout(&self) -> BeaconLayout {
+        BeaconLayout { rfu1: 5, rfu2: 3 }
+    }
+
+    fn get_next_beacon_channel(&mut self) -> Option<Channel> {
+        let beacon_channels = self.get_beacon_channels();
+        if beacon_channels.is_empty() {
+            return None;
+        }
+
+        // Scan the beacon channels in turn
+        let index = (self.last_beacon_channel + 1) % beacon_channels.len();
+        self.last_beacon_channel = index;
+        Some(beacon_channels[index].clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn data_rate(&self) -> u8 {
+        (0..=4)
+            .find(|&index| self.data_rate_from_index(index) == Some(self.data_rate))
+            .unwrap_or(0)
+    }
+
+    fn set_data_rate(&mut self, data_rate: u8) {
+        // Only DR0-DR4 are uplink data rates
+        if data_rate <= 4 {
+            if let Some(data_rate) = self.data_rate_from_index(data_rate) {
+                self.data_rate = data_rate;
+            }
+        }
+    }
+
+    fn data_rate_from_index(&self, index: u8) -> Option<DataRate> {
+        match index {
+            0 => Some(DataRate::SF10BW125),
+            1 => Some(DataRate::SF9BW125),
+            2 => Some(DataRate::SF8BW125),
+            3 => Some(DataRate::SF7BW125),
+            4 => Some(DataRate::SF8BW500),
+            8 => Some(DataRate::SF12BW500),
+            9 => Some(DataRate::SF11BW500),
+            10 => Some(DataRate::SF10BW500),
+            11 => Some(DataRate::SF9BW500),
+            12 => Some(DataRate::SF8BW500),
+            13 => Some(DataRate::SF7BW500),
+            _ => None,
+        }
+    }
+
+    fn is_valid_channel_mask(&self, ch_mask: u16, ch_mask_cntl: u8) -> bool {
+        match ch_mask_cntl {
+            // Blocks of 16 125 kHz channels
+            0..=3 => true,
+            // 500 kHz channels, alone or with all 125 kHz channels on (6) or off (7)
+            4 | 6 | 7 => ch_mask & !0xFF == 0,
+            _ => false,
+        }
+    }
+
+    fn apply_channel_mask(&mut self, ch_mask: u16, ch_mask_cntl: u8) {
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            match (ch_mask_cntl, i) {
+                // Mask applies to channels 16 * ChMaskCntl to 16 * ChMaskCntl + 15
+                (0..=3, _) if i / 16 == ch_mask_cntl as usize => {
+                    channel.enabled = (ch_mask & (1 << (i % 16))) != 0;
+                }
+                (6, 0..=63) => channel.enabled = true,
+                (7, 0..=63) => channel.enabled = false,
+                (4 | 6 | 7, 64..) => channel.enabled = (ch_mask & (1 << (i - 64))) != 0,
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Duplicate downlink and join accept replay detection
+//!
+//! Gateways may deliver the same downlink in RX1 and RX2, and old join
+//! accepts can be replayed by an attacker. The cache remembers the last
+//! accepted downlinks by DevAddr, FCnt and MIC, and the JoinNonces of the
+//! last join accepts. A join accept whose JoinNonce was seen before, or by
+//! default one that is not greater than the last one, as recommended by
+//! LoRaWAN 1.0.4, is a replay.
+
+use heapless::Vec;
+
+use crate::config::device::DevAddr;
+use crate::crypto::MIC_SIZE;
+
+/// Maximum number of downlinks and JoinNonces remembered
+pub const MAX_REPLAY_CACHE_SIZE: usize = 8;
+
+/// Default number of downlinks and JoinNonces remembered
+pub const DEFAULT_REPLAY_CACHE_SIZE: usize = 4;
+
+/// Recently accepted downlinks and JoinNonces
+#[derive(Debug, Clone)]
+pub struct ReplayCache {
+    downlinks: Vec<(DevAddr, u32, [u8; MIC_SIZE]), MAX_REPLAY_CACHE_SIZE>,
+    join_nonces: Vec<u32, MAX_REPLAY_CACHE_SIZE>,
+    size: usize,
+    increasing_join_nonce: bool,
+}
+
+impl Default for ReplayCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_REPLAY_CACHE_SIZE)
+    }
+}
+
+impl ReplayCache {
+    /// Create an empty cache remembering `size` entries of each kind
+    ///
+    /// `size` is limited to 1..=`MAX_REPLAY_CACHE_SIZE`.
+    pub fn new(size: usize) -> Self {
+        Self {
+            downlinks: Vec::new(),
+            join_nonces: Vec::new(),
+            size: size.clamp(1, MAX_REPLAY_CACHE_SIZE),
+            increasing_join_nonce: true,
+        }
+    }
+
+    /// Get the number of entries remembered of each kind
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Require each JoinNonce to be greater than the last one
+    ///
+    /// On by default. Networks that draw the JoinNonce (AppNonce) at random,
+    /// as LoRaWAN 1.0.3 allows, need it off; repeated JoinNonces are still
+    /// rejected then.
+    pub fn set_increasing_join_nonce(&mut self, enabled: bool) {
+        self.increasing_join_nonce = enabled;
+    }
+
+    /// Check if a downlink with this 16-bit FCnt and MIC was accepted before
+    pub fn is_duplicate(&self, dev_addr: DevAddr, f_cnt: u16, mic: &[u8]) -> bool {
+        self.downlinks
+            .iter()
+            .any(|(a, fcnt, m)| *a == dev_addr && *fcnt as u16 == f_cnt && m[..] == *mic)
+    }
+
+    /// Remember an accepted downlink
+    pub fn record_downlink(&mut self, dev_addr: DevAddr, fcnt: u32, mic: [u8; MIC_SIZE]) {
+        if self.downlinks.len() >= self.size {
+            self.downlinks.remove(0);
+        }
+        let _ = self.downlinks.push((dev_addr, fcnt, mic));
+    }
+
+    /// Check if a join accept with this JoinNonce is a replay
+    pub fn is_join_replay(&self, join_nonce: u32) -> bool {
+        if self.join_nonces.contains(&join_nonce) {
+            return true;
+        }
+        self.increasing_join_nonce
+            && self
+                .join_nonces
+                .last()
+                .is_some_and(|&last| join_nonce <= last)
+    }
+
+    /// Remember the JoinNonce of an accepted join accept
+    pub fn record_join_nonce(&mut self, join_nonce: u32) {
+        if self.join_nonces.len() >= self.size {
+            self.join_nonces.remove(0);
+        }
+        let _ = self.join_nonces.push(join_nonce);
+    }
+}
@@ -0,0 +1,158 @@
+//! Simulated SX127x family chip behind mock SPI, pin and delay implementations
+
+use core::cell::{Cell, RefCell};
+use core::convert::Infallible;
+
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use heapless::Vec;
+
+use super::fsk_lora_regs::{REG_FIFO, REG_FIFO_ADDR_PTR};
+
+/// Register file and FIFO of a simulated SX127x
+pub struct Chip {
+    pub registers: [u8; 0x80],
+    pub fifo: [u8; 256],
+    /// Address byte of the current SPI transaction
+    command: Option<u8>,
+    /// Bytes sent in each transaction since the log was cleared
+    pub log: Vec<Vec<u8, 32>, 16>,
+}
+
+impl Chip {
+    pub fn new() -> Self {
+        Self {
+            registers: [0; 0x80],
+            fifo: [0; 256],
+            command: None,
+            log: Vec::new(),
+        }
+    }
+
+    /// Start a transaction on chip select
+    fn select(&mut self) {
+        self.command = None;
+        // Only the transactions since the last clear are checked
+        let _ = self.log.push(Vec::new());
+    }
+
+    /// Exchange one byte of the current transaction
+    fn exchange(&mut self, byte: u8) -> u8 {
+        if let Some(transaction) = self.log.last_mut() {
+            let _ = transaction.push(byte);
+        }
+        let Some(command) = self.command else {
+            self.command = Some(byte);
+            return 0;
+        };
+        let addr = (command & 0x7F) as usize;
+        let write = command & 0x80 != 0;
+
+        if addr == REG_FIFO as usize {
+            // The FIFO is accessed at FifoAddrPtr, which auto-increments
+            let ptr = &mut self.registers[REG_FIFO_ADDR_PTR as usize];
+            let index = *ptr as usize;
+            *ptr = ptr.wrapping_add(1);
+            if write {
+                self.fifo[index] = byte;
+                return 0;
+            }
+            return self.fifo[index];
+        }
+
+        // Burst access continues at the next register
+        self.command = Some(command.wrapping_add(1));
+        if write {
+            self.registers[addr] = byte;
+            0
+        } else {
+            self.registers[addr]
+        }
+    }
+}
+
+pub struct Spi<'a>(pub &'a RefCell<Chip>);
+
+impl Transfer<u8> for Spi<'_> {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+        let mut chip = self.0.borrow_mut();
+        for word in words.iter_mut() {
+            *word = chip.exchange(*word);
+        }
+        Ok(words)
+    }
+}
+
+impl Write<u8> for Spi<'_> {
+    type Error = Infallible;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+        let mut chip = self.0.borrow_mut();
+        for &word in words {
+            chip.exchange(word);
+        }
+        Ok(())
+    }
+}
+
+/// Chip select, starts a new transaction on each edge
+pub struct Cs<'a>(pub &'a RefCell<Chip>);
+
+impl OutputPin for Cs<'_> {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().select();
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().command = None;
+        Ok(())
+    }
+}
+
+pub struct Pin(pub bool);
+
+impl OutputPin for Pin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+impl InputPin for Pin {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        Ok(self.0)
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(!self.0)
+    }
+}
+
+/// Delay counting the elapsed milliseconds
+pub struct Delay<'a>(pub &'a Cell<u32>);
+
+impl DelayMs<u32> for Delay<'_> {
+    fn delay_ms(&mut self, ms: u32) {
+        self.0.set(self.0.get() + ms);
+    }
+}
+
+/// Check the bytes of the transactions since the last clear
+pub fn assert_transactions(chip: &RefCell<Chip>, expected: &[&[u8]]) {
+    let log = core::mem::take(&mut chip.borrow_mut().log);
+    let log: Vec<&[u8], 16> = log.iter().map(|t| &t[..]).collect();
+    assert_eq!(&log[..], expected);
+}
@@ -0,0 +1,86 @@
+//! Register map shared by the SX127x family (SX1272/73 and SX1276/77/78/79)
+//!
+//! The LoRa registers below have the same address and layout on all of
+//! them. RegModemConfig1/2, the PA and the RSSI offsets differ and are
+//! defined by each driver.
+
+// Register addresses
+pub const REG_FIFO: u8 = 0x00;
+pub const REG_OP_MODE: u8 = 0x01;
+pub const REG_LNA: u8 = 0x0C;
+pub const REG_FIFO_ADDR_PTR: u8 = 0x0D;
+pub const REG_FIFO_TX_BASE_ADDR: u8 = 0x0E;
+pub const REG_FIFO_RX_BASE_ADDR: u8 = 0x0F;
+pub const REG_FIFO_RX_CURRENT_ADDR: u8 = 0x10;
+pub const REG_RX_NB_BYTES: u8 = 0x13;
+pub const REG_PKT_SNR_VALUE: u8 = 0x19;
+pub const REG_PKT_RSSI_VALUE: u8 = 0x1A;
+pub const REG_RSSI_VALUE: u8 = 0x1B;
+pub const REG_FRF_MSB: u8 = 0x06;
+pub const REG_FRF_MID: u8 = 0x07;
+pub const REG_FRF_LSB: u8 = 0x08;
+pub const REG_PA_CONFIG: u8 = 0x09;
+pub const REG_MODEM_CONFIG_1: u8 = 0x1D;
+pub const REG_MODEM_CONFIG_2: u8 = 0x1E;
+pub const REG_PREAMBLE_MSB: u8 = 0x20;
+pub const REG_PREAMBLE_LSB: u8 = 0x21;
+pub const REG_RSSI_WIDEBAND: u8 = 0x2C;
+pub const REG_INVERT_IQ: u8 = 0x33;
+pub const REG_SYNC_WORD: u8 = 0x39;
+pub const REG_INVERT_IQ_2: u8 = 0x3B;
+pub const REG_IRQ_FLAGS_MASK: u8 = 0x11;
+pub const REG_IRQ_FLAGS: u8 = 0x12;
+pub const REG_PAYLOAD_LENGTH: u8 = 0x22;
+pub const REG_DIO_MAPPING_1: u8 = 0x40;
+
+// Margin added to the time-on-air before a transmission times out (ms)
+pub const TX_TIMEOUT_MARGIN_MS: u32 = 100;
+
+// TX timeout before any transmit configuration, longer than any LoRaWAN frame (ms)
+pub const DEFAULT_TX_TIMEOUT_MS: u32 = 10_000;
+
+// RegInvertIQ and RegInvertIQ2 values for normal and inverted RX IQ
+pub const INVERT_IQ_OFF: u8 = 0x27;
+pub const INVERT_IQ_RX: u8 = 0x66;
+pub const INVERT_IQ_2_OFF: u8 = 0x1D;
+pub const INVERT_IQ_2_ON: u8 = 0x19;
+
+// MSB of the SPI address byte, set for writes and clear for reads
+pub const SPI_WRITE: u8 = 0x80;
+
+// LongRangeMode bit of RegOpMode
+pub const LONG_RANGE_MODE: u8 = 0x80;
+
+// Operating modes
+pub const MODE_SLEEP: u8 = 0x00;
+pub const MODE_STDBY: u8 = 0x01;
+pub const MODE_TX: u8 = 0x03;
+pub const MODE_RX: u8 = 0x05;
+pub const MODE_CAD: u8 = 0x07;
+
+// IRQ flags
+pub const IRQ_CAD_DETECTED_MASK: u8 = 0x01;
+pub const IRQ_CAD_DONE_MASK: u8 = 0x04;
+pub const IRQ_TX_DONE_MASK: u8 = 0x08;
+pub const IRQ_RX_DONE_MASK: u8 = 0x40;
+pub const IRQ_RX_TIMEOUT_MASK: u8 = 0x80;
+pub const IRQ_ALL_MASK: u8 = 0xFF;
+
+// DIO0 mapping of RegDioMapping1, DIO1 stays on RxTimeout
+pub const DIO0_RX_DONE: u8 = 0x00;
+pub const DIO0_TX_DONE: u8 = 0x40;
+pub const DIO0_CAD_DONE: u8 = 0x80;
+
+// IRQs unmasked by init
+pub const USED_IRQS: u8 = IRQ_TX_DONE_MASK
+    | IRQ_RX_DONE_MASK
+    | IRQ_RX_TIMEOUT_MASK
+    | IRQ_CAD_DONE_MASK
+    | IRQ_CAD_DETECTED_MASK;
+
+// FIFO base address of both TX and RX, each may use the whole FIFO
+pub const FIFO_BASE_ADDR: u8 = 0x00;
+
+// LnaGain G1 (maximum gain) and LnaBoostHf (150% LNA current)
+pub const LNA_MAX_GAIN: u8 = 0x20;
+pub const LNA_BOOST_HF: u8 = 0x03;
@@ -3,24 +3,49 @@
 //! This module provides traits and implementations for LoRa radio hardware:
 //! - Common radio traits for hardware abstraction
 //! - SX127x series radio driver (SX1276/77/78/79)
+//! - SX1272/73 radio driver
 //! - SX126x series radio driver (when enabled with "sx126x" feature)
+//! - STM32WL sub-GHz radio (when enabled with "stm32wl" feature)
 //! - Configuration types for radio operation
 
 #[cfg(feature = "sx126x")]
 /// SX126x series radio driver
 pub mod sx126x;
 
+#[cfg(feature = "stm32wl")]
+/// STM32WL sub-GHz radio
+pub mod stm32wl;
+
 /// SX127x series radio driver
 pub mod sx127x;
 
+/// SX1272/73 radio driver
+pub mod sx1272;
+
+/// Register map shared by the SX127x family
+mod fsk_lora_regs;
+
+#[cfg(test)]
+mod chip_mock;
+
 /// Common traits for radio hardware abstraction
 pub mod traits;
 
 #[cfg(feature = "sx126x")]
-pub use sx126x::SX126x;
+pub use sx126x::{CadExitMode, CadSymbols, SX126x, Sx126xConfig, Sx126xInterface};
+
+#[cfg(feature = "stm32wl")]
+pub use stm32wl::Stm32wlRadio;
 
 /// Re-export of SX127x radio driver
 pub use sx127x::SX127x;
 
+/// Re-export of SX1272 radio driver
+pub use sx1272::SX1272;
+
 /// Re-export of Radio trait
-pub use traits::Radio;
+pub use traits::{Radio, RadioCapabilities};
+
+/// Re-export of AsyncRadio trait
+#[cfg(feature = "async")]
+pub use traits::AsyncRadio;
@@ -0,0 +1,196 @@
+//! STM32WL sub-GHz radio
+//!
+//! The STM32WL integrates an SX126x behind the SUBGHZSPI peripheral. NSS,
+//! BUSY and the reset are driven through the PWR and RCC registers and the
+//! radio IRQs are combined into the Radio IRQ line of the NVIC, so no GPIO
+//! is needed. `SubGhz` implements `Sx126xInterface` for the SX126x driver:
+//!
+//! - use `Sx126xConfig::with_tcxo` on boards clocking the radio from a TCXO
+//!   on PB0-VDD_TCXO, e.g. 1.7 V on the Nucleo-WL55JC
+//! - DIO2 is not bonded out, the RF switch is driven by the application
+//!
+//! The registers are accessed directly, which is why `SubGhz::new` is
+//! unsafe: the driver must be the only user of SUBGHZSPI and of the radio
+//! bits of PWR and RCC. The Radio IRQ is polled through its NVIC pending bit,
+//! it should stay disabled in the NVIC.
+
+use core::ptr::{read_volatile, write_volatile};
+
+use embedded_hal::blocking::delay::DelayMs;
+
+use super::sx126x::{RadioError, SX126x, Sx126xConfig, Sx126xInterface};
+
+mod rcc {
+    pub const BASE: usize = 0x5800_0000;
+    pub const APB3ENR: usize = BASE + 0x060;
+    pub const CSR: usize = BASE + 0x094;
+    /// SUBGHZSPI clock enable
+    pub const SUBGHZSPIEN: u32 = 1 << 0;
+    /// Sub-GHz radio reset
+    pub const RFRST: u32 = 1 << 15;
+    /// Sub-GHz radio in reset
+    pub const RFRSTF: u32 = 1 << 14;
+}
+
+mod pwr {
+    pub const BASE: usize = 0x5800_0400;
+    pub const SR2: usize = BASE + 0x014;
+    pub const SUBGHZSPICR: usize = BASE + 0x090;
+    /// Radio busy signal
+    pub const RFBUSYS: u32 = 1 << 1;
+    /// SUBGHZSPI NSS, high when released
+    pub const NSS: u32 = 1 << 15;
+}
+
+mod spi {
+    pub const BASE: usize = 0x5801_0000;
+    pub const CR1: usize = BASE;
+    pub const CR2: usize = BASE + 0x04;
+    pub const SR: usize = BASE + 0x08;
+    pub const DR: usize = BASE + 0x0C;
+    /// Master, software NSS, PCLK3 / 4
+    pub const CR1_MASTER: u32 = (1 << 2) | (0b001 << 3) | (1 << 8) | (1 << 9);
+    pub const CR1_SPE: u32 = 1 << 6;
+    /// 8-bit frames, RXNE on a quarter-full FIFO
+    pub const CR2_8BIT: u32 = (0b0111 << 8) | (1 << 12);
+    pub const SR_RXNE: u32 = 1 << 0;
+    pub const SR_TXE: u32 = 1 << 1;
+    pub const SR_BSY: u32 = 1 << 7;
+}
+
+mod nvic {
+    /// Interrupt set-pending register holding IRQ 32..63
+    pub const ISPR1: usize = 0xE000_E204;
+    /// Interrupt clear-pending register holding IRQ 32..63
+    pub const ICPR1: usize = 0xE000_E284;
+    /// Radio IRQ, line 50
+    pub const RADIO_IRQ: u32 = 1 << (50 - 32);
+}
+
+fn read_reg(address: usize) -> u32 {
+    // SAFETY: `address` is one of the peripheral registers above
+    unsafe { read_volatile(address as *const u32) }
+}
+
+fn write_reg(address: usize, value: u32) {
+    // SAFETY: `address` is one of the peripheral registers above
+    unsafe { write_volatile(address as *mut u32, value) }
+}
+
+fn modify_reg(address: usize, f: impl FnOnce(u32) -> u32) {
+    write_reg(address, f(read_reg(address)));
+}
+
+/// SUBGHZSPI transport of the STM32WL radio
+pub struct SubGhz {
+    _private: (),
+}
+
+impl SubGhz {
+    /// Enable the SUBGHZSPI clock and configure it as SPI master
+    ///
+    /// # Safety
+    ///
+    /// Must run on an STM32WL, and while the returned value lives nothing
+    /// else may access SUBGHZSPI, the SUBGHZSPICR and SR2 radio bits of PWR,
+    /// the radio reset of RCC or the Radio IRQ of the NVIC. In particular at
+    /// most one `SubGhz` may exist at a time, and not next to the HAL's own
+    /// sub-GHz driver.
+    pub unsafe fn new() -> Self {
+        modify_reg(rcc::APB3ENR, |v| v | rcc::SUBGHZSPIEN);
+        // Read back to make sure the clock is running
+        let _ = read_reg(rcc::APB3ENR);
+
+        modify_reg(pwr::SUBGHZSPICR, |v| v | pwr::NSS);
+        write_reg(spi::CR1, 0);
+        write_reg(spi::CR2, spi::CR2_8BIT);
+        write_reg(spi::CR1, spi::CR1_MASTER);
+        write_reg(spi::CR1, spi::CR1_MASTER | spi::CR1_SPE);
+        Self { _private: () }
+    }
+
+    /// Send one byte and return the byte received meanwhile
+    fn exchange(&mut self, byte: u8) -> u8 {
+        while read_reg(spi::SR) & spi::SR_TXE == 0 {
+            core::hint::spin_loop();
+        }
+        // SAFETY: 8-bit access to the data register sends a single frame
+        unsafe { write_volatile(spi::DR as *mut u8, byte) };
+        while read_reg(spi::SR) & spi::SR_RXNE == 0 {
+            core::hint::spin_loop();
+        }
+        // SAFETY: 8-bit access to the data register reads a single frame
+        unsafe { read_volatile(spi::DR as *const u8) }
+    }
+
+    fn select(&mut self) {
+        modify_reg(pwr::SUBGHZSPICR, |v| v & !pwr::NSS);
+    }
+
+    fn deselect(&mut self) {
+        while read_reg(spi::SR) & spi::SR_BSY != 0 {
+            core::hint::spin_loop();
+        }
+        modify_reg(pwr::SUBGHZSPICR, |v| v | pwr::NSS);
+    }
+}
+
+impl Sx126xInterface for SubGhz {
+    fn write(&mut self, header: &[u8], data: &[u8]) -> Result<(), RadioError> {
+        self.select();
+        for &byte in header.iter().chain(data) {
+            self.exchange(byte);
+        }
+        self.deselect();
+        Ok(())
+    }
+
+    fn read(&mut self, header: &[u8], data: &mut [u8]) -> Result<(), RadioError> {
+        self.select();
+        for &byte in header {
+            self.exchange(byte);
+        }
+        for byte in data.iter_mut() {
+            *byte = self.exchange(0);
+        }
+        self.deselect();
+        Ok(())
+    }
+
+    fn is_busy(&mut self) -> Result<bool, RadioError> {
+        Ok(read_reg(pwr::SR2) & pwr::RFBUSYS != 0)
+    }
+
+    fn irq_pending(&mut self) -> Result<bool, RadioError> {
+        // The line stays pending while a radio IRQ flag is set, clearing it
+        // first makes the pending bit follow the flags
+        write_reg(nvic::ICPR1, nvic::RADIO_IRQ);
+        Ok(read_reg(nvic::ISPR1) & nvic::RADIO_IRQ != 0)
+    }
+
+    fn set_reset(&mut self, asserted: bool) -> Result<(), RadioError> {
+        if asserted {
+            modify_reg(rcc::CSR, |v| v | rcc::RFRST);
+        } else {
+            modify_reg(rcc::CSR, |v| v & !rcc::RFRST);
+            while read_reg(rcc::CSR) & rcc::RFRSTF != 0 {
+                core::hint::spin_loop();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// SX126x driver on the STM32WL sub-GHz radio
+pub type Stm32wlRadio<DELAY> = SX126x<SubGhz, DELAY>;
+
+impl<DELAY: DelayMs<u32>> SX126x<SubGhz, DELAY> {
+    /// Create new driver for the STM32WL sub-GHz radio
+    ///
+    /// # Safety
+    ///
+    /// The driver takes over the radio registers, see `SubGhz::new`.
+    pub unsafe fn new_stm32wl(delay: DELAY, config: Sx126xConfig) -> Result<Self, RadioError> {
+        Self::with_interface(SubGhz::new(), delay, config)
+    }
+}
@@ -6,7 +6,9 @@ use embedded_hal::{
 };
 
 #[cfg(feature = "sx126x")]
-use crate::radio::traits::{ModulationParams, Radio, RxConfig, TxConfig};
+use crate::lorawan::{phy::symbol_time_us, region::DataRate};
+#[cfg(feature = "sx126x")]
+use crate::radio::traits::{ModulationParams, Radio, RadioCapabilities, RxConfig, TxConfig};
 
 // SX126x Register Map
 #[cfg(feature = "sx126x")]
@@ -30,6 +32,8 @@ mod registers {
     pub const REG_IQ_POLARITY_SETUP: u16 = 0x0736;
     pub const REG_LORA_SYNC_WORD_MSB: u16 = 0x0740;
     pub const REG_LORA_SYNC_WORD_LSB: u16 = 0x0741;
+    pub const REG_RX_GAIN: u16 = 0x08AC;
+    pub const REG_RANDOM_NUMBER_GEN_0: u16 = 0x0819;
 }
 
 #[cfg(feature = "sx126x")]
@@ -42,6 +46,7 @@ mod commands {
     pub const STOP_TIMER_ON_PREAMBLE: u8 = 0x9F;
     pub const SET_RX_DUTY_CYCLE: u8 = 0x94;
     pub const SET_CAD: u8 = 0xC5;
+    pub const SET_CAD_PARAMS: u8 = 0x88;
     pub const SET_TX_CONTINUOUS_WAVE: u8 = 0xD1;
     pub const SET_TX_INFINITE_PREAMBLE: u8 = 0xD2;
     pub const SET_REGULATOR_MODE: u8 = 0x96;
@@ -55,6 +60,7 @@ mod commands {
     pub const READ_BUFFER: u8 = 0x1E;
     pub const SET_DIO_IRQ_PARAMS: u8 = 0x08;
     pub const GET_IRQ_STATUS: u8 = 0x12;
+    pub const GET_RX_BUFFER_STATUS: u8 = 0x13;
     pub const CLR_IRQ_STATUS: u8 = 0x02;
     pub const SET_DIO2_AS_RF_SWITCH_CTRL: u8 = 0x9D;
     pub const SET_DIO3_AS_TCXO_CTRL: u8 = 0x97;
@@ -70,8 +76,55 @@ mod commands {
     pub const RESET_STATS: u8 = 0x00;
 }
 
+// IRQ flags of SetDioIrqParams, GetIrqStatus and ClearIrqStatus
+#[cfg(feature = "sx126x")]
+mod irq {
+    pub const TX_DONE: u16 = 1 << 0;
+    pub const RX_DONE: u16 = 1 << 1;
+    pub const CRC_ERR: u16 = 1 << 6;
+    pub const CAD_DONE: u16 = 1 << 7;
+    pub const CAD_DETECTED: u16 = 1 << 8;
+    pub const TIMEOUT: u16 = 1 << 9;
+    pub const ALL: u16 = 0xFFFF;
+}
+
+// RegRxGain values
+#[cfg(feature = "sx126x")]
+const RX_GAIN_POWER_SAVING: u8 = 0x94;
+#[cfg(feature = "sx126x")]
+const RX_GAIN_BOOSTED: u8 = 0x96;
+
+// SetSleep configurations: cold start loses the configuration, warm start retains it
+#[cfg(feature = "sx126x")]
+const SLEEP_COLD_START: u8 = 0x00;
+#[cfg(feature = "sx126x")]
+const SLEEP_WARM_START: u8 = 0x04;
+
+// cadDetPeak of SF7 to SF12 for a two-symbol CAD, cadDetMin is the same for all
+#[cfg(feature = "sx126x")]
+const CAD_DET_PEAK: [u8; 6] = [22, 22, 23, 24, 25, 28];
+#[cfg(feature = "sx126x")]
+const CAD_DET_MIN: u8 = 10;
+
+/// CalibrateImage frequency bytes of the band `freq` is in
+#[cfg(feature = "sx126x")]
+fn image_calibration_band(freq: u32) -> [u8; 2] {
+    match freq {
+        f if f > 900_000_000 => [0xE1, 0xE9], // 902-928 MHz
+        f if f > 850_000_000 => [0xD7, 0xDB], // 863-870 MHz
+        f if f > 770_000_000 => [0xC1, 0xC5], // 779-787 MHz
+        f if f > 460_000_000 => [0x75, 0x81], // 470-510 MHz
+        _ => [0x6B, 0x6F],                    // 430-440 MHz
+    }
+}
+
+// SetRx timeout for continuous reception
+#[cfg(feature = "sx126x")]
+const RX_CONTINUOUS: u32 = 0xFF_FFFF;
+
 #[cfg(feature = "sx126x")]
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum RadioError {
     /// SPI transfer error
     Spi,
@@ -83,29 +136,275 @@ pub enum RadioError {
     Hardware,
     /// Operation timeout
     Timeout,
+    /// Received packet failed its payload CRC
+    Crc,
+}
+
+#[cfg(feature = "sx126x")]
+impl core::fmt::Display for RadioError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RadioError::Spi => f.write_str("SPI transfer error"),
+            RadioError::Gpio => f.write_str("GPIO error"),
+            RadioError::Config => f.write_str("invalid configuration"),
+            RadioError::Hardware => f.write_str("radio hardware error"),
+            RadioError::Timeout => f.write_str("operation timeout"),
+            RadioError::Crc => f.write_str("payload CRC error"),
+        }
+    }
+}
+
+#[cfg(feature = "sx126x")]
+impl core::error::Error for RadioError {}
+
+/// Number of symbols a CAD listens for
+#[cfg(feature = "sx126x")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CadSymbols {
+    /// 1 symbol
+    One = 0,
+    /// 2 symbols
+    Two = 1,
+    /// 4 symbols
+    Four = 2,
+    /// 8 symbols
+    Eight = 3,
+    /// 16 symbols
+    Sixteen = 4,
+}
+
+/// Operation following a CAD
+#[cfg(feature = "sx126x")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CadExitMode {
+    /// Back to standby
+    CadOnly = 0,
+    /// Receive if activity was detected, for the last RX timeout
+    CadRx = 1,
+}
+
+/// TCXO supply voltage output on DIO3
+#[cfg(feature = "sx126x")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TcxoVoltage {
+    /// 1.6 V
+    V1_6 = 0x00,
+    /// 1.7 V
+    V1_7 = 0x01,
+    /// 1.8 V
+    V1_8 = 0x02,
+    /// 2.2 V
+    V2_2 = 0x03,
+    /// 2.4 V
+    V2_4 = 0x04,
+    /// 2.7 V
+    V2_7 = 0x05,
+    /// 3.0 V
+    V3_0 = 0x06,
+    /// 3.3 V
+    V3_3 = 0x07,
+}
+
+/// Regulator powering the radio
+#[cfg(feature = "sx126x")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegulatorMode {
+    /// LDO only
+    Ldo = 0x00,
+    /// DC-DC converter and LDO
+    DcDc = 0x01,
+}
+
+/// Chip variant, which selects the power amplifier
+#[cfg(feature = "sx126x")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sx126xVariant {
+    /// SX1261, low power PA up to +15 dBm
+    Sx1261,
+    /// SX1262, high power PA up to +22 dBm
+    Sx1262,
+}
+
+/// Board configuration of an SX126x
+///
+/// The default is an SX1262 with a crystal, DC-DC regulator and DIO2
+/// driving the RF switch.
+#[cfg(feature = "sx126x")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sx126xConfig {
+    /// TCXO voltage and startup time in milliseconds, `None` for a crystal
+    pub tcxo: Option<(TcxoVoltage, u32)>,
+    /// Regulator mode
+    pub regulator: RegulatorMode,
+    /// Chip variant
+    pub variant: Sx126xVariant,
+    /// DIO2 drives the RF switch
+    pub dio2_as_rf_switch: bool,
+}
+
+#[cfg(feature = "sx126x")]
+impl Default for Sx126xConfig {
+    fn default() -> Self {
+        Self {
+            tcxo: None,
+            regulator: RegulatorMode::DcDc,
+            variant: Sx126xVariant::Sx1262,
+            dio2_as_rf_switch: true,
+        }
+    }
+}
+
+#[cfg(feature = "sx126x")]
+impl Sx126xConfig {
+    /// Power a TCXO from DIO3 at `voltage`, waiting `startup_ms` for it to settle
+    pub fn with_tcxo(mut self, voltage: TcxoVoltage, startup_ms: u32) -> Self {
+        self.tcxo = Some((voltage, startup_ms));
+        self
+    }
+
+    /// Use `regulator` to power the radio
+    pub fn with_regulator(mut self, regulator: RegulatorMode) -> Self {
+        self.regulator = regulator;
+        self
+    }
+
+    /// Select the chip variant
+    pub fn with_variant(mut self, variant: Sx126xVariant) -> Self {
+        self.variant = variant;
+        self
+    }
+
+    /// Let DIO2 drive the RF switch, or leave it to the board
+    pub fn with_dio2_as_rf_switch(mut self, enabled: bool) -> Self {
+        self.dio2_as_rf_switch = enabled;
+        self
+    }
 }
 
+/// Transport between the SX126x command layer and the radio
+///
+/// Each call is one command transaction, framed by NSS. Commands are only
+/// sent once `is_busy` is clear.
 #[cfg(feature = "sx126x")]
-pub struct SX126x<SPI, CS, RESET, BUSY, DIO1, DELAY>
+pub trait Sx126xInterface {
+    /// Send `header` followed by `data`
+    fn write(&mut self, header: &[u8], data: &[u8]) -> Result<(), RadioError>;
+
+    /// Send `header`, then clock the response into `data`
+    fn read(&mut self, header: &[u8], data: &mut [u8]) -> Result<(), RadioError>;
+
+    /// Check if the radio is still processing the last command
+    fn is_busy(&mut self) -> Result<bool, RadioError>;
+
+    /// Check if the radio IRQ line is asserted
+    fn irq_pending(&mut self) -> Result<bool, RadioError>;
+
+    /// Hold the radio in reset, or release it
+    fn set_reset(&mut self, asserted: bool) -> Result<(), RadioError>;
+}
+
+/// External SX126x on an SPI bus, with BUSY, DIO1 and NRESET on GPIOs
+#[cfg(feature = "sx126x")]
+pub struct SpiInterface<SPI, CS, RESET, BUSY, DIO1> {
+    spi: SPI,
+    cs: CS,
+    reset: RESET,
+    busy: BUSY,
+    dio1: DIO1,
+}
+
+#[cfg(feature = "sx126x")]
+impl<SPI, CS, RESET, BUSY, DIO1> SpiInterface<SPI, CS, RESET, BUSY, DIO1>
 where
     SPI: Transfer<u8> + Write<u8>,
     CS: OutputPin,
     RESET: OutputPin,
     BUSY: InputPin,
     DIO1: InputPin,
+{
+    /// Create new SPI transport
+    pub fn new(spi: SPI, cs: CS, reset: RESET, busy: BUSY, dio1: DIO1) -> Self {
+        Self {
+            spi,
+            cs,
+            reset,
+            busy,
+            dio1,
+        }
+    }
+}
+
+#[cfg(feature = "sx126x")]
+impl<SPI, CS, RESET, BUSY, DIO1> Sx126xInterface for SpiInterface<SPI, CS, RESET, BUSY, DIO1>
+where
+    SPI: Transfer<u8> + Write<u8>,
+    CS: OutputPin,
+    RESET: OutputPin,
+    BUSY: InputPin,
+    DIO1: InputPin,
+{
+    fn write(&mut self, header: &[u8], data: &[u8]) -> Result<(), RadioError> {
+        self.cs.set_low().map_err(|_| RadioError::Gpio)?;
+        self.spi.write(header).map_err(|_| RadioError::Spi)?;
+        if !data.is_empty() {
+            self.spi.write(data).map_err(|_| RadioError::Spi)?;
+        }
+        self.cs.set_high().map_err(|_| RadioError::Gpio)
+    }
+
+    fn read(&mut self, header: &[u8], data: &mut [u8]) -> Result<(), RadioError> {
+        self.cs.set_low().map_err(|_| RadioError::Gpio)?;
+        self.spi.write(header).map_err(|_| RadioError::Spi)?;
+        if !data.is_empty() {
+            self.spi.transfer(data).map_err(|_| RadioError::Spi)?;
+        }
+        self.cs.set_high().map_err(|_| RadioError::Gpio)
+    }
+
+    fn is_busy(&mut self) -> Result<bool, RadioError> {
+        self.busy.is_high().map_err(|_| RadioError::Gpio)
+    }
+
+    fn irq_pending(&mut self) -> Result<bool, RadioError> {
+        self.dio1.is_high().map_err(|_| RadioError::Gpio)
+    }
+
+    fn set_reset(&mut self, asserted: bool) -> Result<(), RadioError> {
+        // NRESET is active low
+        if asserted {
+            self.reset.set_low().map_err(|_| RadioError::Gpio)
+        } else {
+            self.reset.set_high().map_err(|_| RadioError::Gpio)
+        }
+    }
+}
+
+/// SX126x driver
+///
+/// The command layer runs on any `Sx126xInterface`: `SpiInterface` for an
+/// external chip, or the SUBGHZ peripheral of an STM32WL.
+#[cfg(feature = "sx126x")]
+pub struct SX126x<IF, DELAY>
+where
+    IF: Sx126xInterface,
     DELAY: DelayMs<u32>,
 {
-    spi: SPI,
-    cs: CS,
-    reset: RESET,
-    busy: BUSY,
-    dio1: DIO1,
+    interface: IF,
     delay: DELAY,
+    config: Sx126xConfig,
     frequency: u32,
+    /// Receive window timeout of the last `configure_rx`, 0 for continuous
+    rx_timeout_ms: u32,
+    /// Band the image rejection was last calibrated for
+    calibrated_band: Option<[u8; 2]>,
+    /// Modulation of the last `configure_tx`, sets the packet of `transmit`
+    tx_modulation: Option<ModulationParams>,
+    cad_symbols: CadSymbols,
+    cad_exit_mode: CadExitMode,
 }
 
 #[cfg(feature = "sx126x")]
-impl<SPI, CS, RESET, BUSY, DIO1, DELAY> SX126x<SPI, CS, RESET, BUSY, DIO1, DELAY>
+impl<SPI, CS, RESET, BUSY, DIO1, DELAY> SX126x<SpiInterface<SPI, CS, RESET, BUSY, DIO1>, DELAY>
 where
     SPI: Transfer<u8> + Write<u8>,
     CS: OutputPin,
@@ -123,6 +422,7 @@ where
     /// * `busy` - Busy pin
     /// * `dio1` - DIO1 interrupt pin
     /// * `delay` - Delay implementation
+    /// * `config` - Board configuration, applied by `init`
     pub fn new(
         spi: SPI,
         cs: CS,
@@ -130,32 +430,67 @@ where
         busy: BUSY,
         dio1: DIO1,
         delay: DELAY,
+        config: Sx126xConfig,
+    ) -> Result<Self, RadioError> {
+        let interface = SpiInterface::new(spi, cs, reset, busy, dio1);
+        Self::with_interface(interface, delay, config)
+    }
+}
+
+#[cfg(feature = "sx126x")]
+impl<IF, DELAY> SX126x<IF, DELAY>
+where
+    IF: Sx126xInterface,
+    DELAY: DelayMs<u32>,
+{
+    /// Create new SX126x driver instance on `interface`
+    pub fn with_interface(
+        interface: IF,
+        delay: DELAY,
+        config: Sx126xConfig,
     ) -> Result<Self, RadioError> {
         let mut radio = Self {
-            spi,
-            cs,
-            reset,
-            busy,
-            dio1,
+            interface,
             delay,
+            config,
             frequency: 0,
+            rx_timeout_ms: 0,
+            calibrated_band: None,
+            tx_modulation: None,
+            cad_symbols: CadSymbols::Two,
+            cad_exit_mode: CadExitMode::CadOnly,
         };
+        radio.hard_reset()?;
 
-        // Reset sequence
-        radio.reset.set_high().map_err(|_| RadioError::Gpio)?;
-        radio.delay.delay_ms(2); // 2ms high pulse
-        radio.reset.set_low().map_err(|_| RadioError::Gpio)?;
-        radio.delay.delay_ms(10); // 10ms low for reset
+        Ok(radio)
+    }
 
-        // Wait for busy to go low indicating device is ready
-        radio.wait_busy()?;
+    /// Set the length of a CAD and the operation that follows it
+    pub fn set_cad_params(&mut self, symbols: CadSymbols, exit_mode: CadExitMode) {
+        self.cad_symbols = symbols;
+        self.cad_exit_mode = exit_mode;
+    }
 
-        Ok(radio)
+    /// Get the transport
+    pub fn interface(&self) -> &IF {
+        &self.interface
+    }
+
+    /// Pulse the reset and wait until the radio is ready
+    fn hard_reset(&mut self) -> Result<(), RadioError> {
+        self.interface.set_reset(true)?;
+        self.delay.delay_ms(2);
+        self.interface.set_reset(false)?;
+        self.delay.delay_ms(10);
+        self.calibrated_band = None;
+
+        // Wait for busy to go low indicating device is ready
+        self.wait_busy()
     }
 
     fn wait_busy(&mut self) -> Result<(), RadioError> {
         for _ in 0..1000 {
-            if self.busy.is_low().map_err(|_| RadioError::Gpio)? {
+            if !self.interface.is_busy()? {
                 return Ok(());
             }
             core::hint::spin_loop();
@@ -164,53 +499,208 @@ where
     }
 
     fn write_command(&mut self, command: u8, data: &[u8]) -> Result<(), RadioError> {
-        self.cs.set_low().map_err(|_| RadioError::Gpio)?;
-        self.spi.write(&[command]).map_err(|_| RadioError::Spi)?;
-        if !data.is_empty() {
-            self.spi.write(data).map_err(|_| RadioError::Spi)?;
-        }
-        self.cs.set_high().map_err(|_| RadioError::Gpio)?;
+        self.interface.write(&[command], data)?;
         self.wait_busy()
     }
 
     fn read_command(&mut self, command: u8, data: &mut [u8]) -> Result<(), RadioError> {
-        self.cs.set_low().map_err(|_| RadioError::Gpio)?;
-        self.spi.write(&[command]).map_err(|_| RadioError::Spi)?;
-        self.spi.write(&[0]).map_err(|_| RadioError::Spi)?; // NOP for response
-        if !data.is_empty() {
-            self.spi.transfer(data).map_err(|_| RadioError::Spi)?;
-        }
-        self.cs.set_high().map_err(|_| RadioError::Gpio)?;
+        // NOP clocking out the status byte before the response
+        self.interface.read(&[command, 0], data)?;
+        self.wait_busy()
+    }
+
+    /// Write a command whose parameters are followed by a data block
+    fn write_command_with_data(
+        &mut self,
+        command: u8,
+        params: &[u8],
+        data: &[u8],
+    ) -> Result<(), RadioError> {
+        let mut header = [command, 0, 0];
+        header[1..=params.len()].copy_from_slice(params);
+        self.interface.write(&header[..=params.len()], data)?;
         self.wait_busy()
     }
 
     fn write_register(&mut self, address: u16, data: &[u8]) -> Result<(), RadioError> {
         let addr_bytes = [(address >> 8) as u8, address as u8];
-        self.write_command(commands::WRITE_REGISTER, &[&addr_bytes, data].concat())
+        self.write_command_with_data(commands::WRITE_REGISTER, &addr_bytes, data)
     }
 
     fn read_register(&mut self, address: u16, data: &mut [u8]) -> Result<(), RadioError> {
-        let addr_bytes = [(address >> 8) as u8, address as u8];
-        self.cs.set_low().map_err(|_| RadioError::Gpio)?;
-        self.spi
-            .write(&[commands::READ_REGISTER])
-            .map_err(|_| RadioError::Spi)?;
-        self.spi.write(&addr_bytes).map_err(|_| RadioError::Spi)?;
-        self.spi.write(&[0]).map_err(|_| RadioError::Spi)?; // NOP
-        self.spi.transfer(data).map_err(|_| RadioError::Spi)?;
-        self.cs.set_high().map_err(|_| RadioError::Gpio)?;
+        let [msb, lsb] = address.to_be_bytes();
+        self.interface
+            .read(&[commands::READ_REGISTER, msb, lsb, 0], data)?;
+        self.wait_busy()
+    }
+
+    /// Read the data buffer from `offset`
+    ///
+    /// ReadBuffer takes the offset, then returns a status byte before the data.
+    fn read_buffer(&mut self, offset: u8, data: &mut [u8]) -> Result<(), RadioError> {
+        self.interface
+            .read(&[commands::READ_BUFFER, offset, 0], data)?;
         self.wait_busy()
     }
+
+    /// Get the pending IRQ flags
+    fn irq_status(&mut self) -> Result<u16, RadioError> {
+        let mut status = [0u8; 2];
+        self.read_command(commands::GET_IRQ_STATUS, &mut status)?;
+        Ok(u16::from_be_bytes(status))
+    }
+
+    /// Clear IRQ flags
+    fn clear_irq_status(&mut self, flags: u16) -> Result<(), RadioError> {
+        self.write_command(commands::CLR_IRQ_STATUS, &flags.to_be_bytes())
+    }
+
+    /// Set LoRa spreading factor, bandwidth and coding rate
+    fn set_modulation(&mut self, modulation: &ModulationParams) -> Result<(), RadioError> {
+        let sf = modulation.spreading_factor.clamp(5, 12);
+        let bw = match modulation.bandwidth {
+            b if b <= 10_400 => 0x00,
+            b if b <= 15_600 => 0x01,
+            b if b <= 20_800 => 0x02,
+            b if b <= 31_250 => 0x03,
+            b if b <= 41_700 => 0x04,
+            b if b <= 62_500 => 0x05,
+            b if b <= 125_000 => 0x06,
+            b if b <= 250_000 => 0x07,
+            _ => 0x08,
+        };
+        let cr = modulation.coding_rate.clamp(5, 8) - 4;
+
+        let mod_params = [
+            sf,   // SF5-SF12
+            bw,   // Bandwidth
+            cr,   // Coding rate
+            0x00, // Low data rate optimize off
+        ];
+
+        self.write_command(commands::SET_MODULATION_PARAMS, &mod_params)
+    }
+
+    /// Set preamble, header, CRC and IQ of the LoRa packet
+    ///
+    /// `payload_len` is sent in explicit header mode, in implicit header mode
+    /// the fixed length of `modulation` is used.
+    fn set_packet_params(
+        &mut self,
+        modulation: &ModulationParams,
+        payload_len: u8,
+    ) -> Result<(), RadioError> {
+        let [preamble_msb, preamble_lsb] = modulation.preamble_length.to_be_bytes();
+        let (header, len) = match modulation.implicit_header {
+            Some(len) => (0x01, len),
+            None => (0x00, payload_len),
+        };
+        let packet_params = [
+            preamble_msb,
+            preamble_lsb,
+            header,
+            len,
+            u8::from(modulation.crc),
+            u8::from(modulation.iq_inverted),
+        ];
+        self.write_command(commands::SET_PKT_PARAMS, &packet_params)?;
+
+        // Datasheet 15.4: bit 2 of the IQ polarity register must be cleared
+        // with inverted IQ and set otherwise
+        let mut iq_setup = [0u8];
+        self.read_register(registers::REG_IQ_POLARITY_SETUP, &mut iq_setup)?;
+        let iq_setup = if modulation.iq_inverted {
+            iq_setup[0] & !0x04
+        } else {
+            iq_setup[0] | 0x04
+        };
+        self.write_register(registers::REG_IQ_POLARITY_SETUP, &[iq_setup])
+    }
+
+    /// Set the LoRa sync word, e.g. 0x34 for public networks
+    ///
+    /// Each nibble of the SX127x style sync word is followed by 0x4.
+    fn set_sync_word(&mut self, sync_word: u8) -> Result<(), RadioError> {
+        self.write_register(
+            registers::REG_LORA_SYNC_WORD_MSB,
+            &[(sync_word & 0xF0) | 0x04, (sync_word << 4) | 0x04],
+        )
+    }
+
+    /// Enable the IRQs `flags` on DIO1 and clear the pending ones
+    fn set_irq_params(&mut self, flags: u16) -> Result<(), RadioError> {
+        let [msb, lsb] = flags.to_be_bytes();
+        self.write_command(
+            commands::SET_DIO_IRQ_PARAMS,
+            &[msb, lsb, msb, lsb, 0, 0, 0, 0],
+        )?;
+        self.clear_irq_status(irq::ALL)
+    }
+
+    /// Route RxDone, Timeout and CrcErr to DIO1 and enter RX
+    ///
+    /// `timeout_ms` of 0 selects continuous reception.
+    fn start_rx(&mut self, timeout_ms: u32) -> Result<(), RadioError> {
+        self.set_irq_params(irq::RX_DONE | irq::TIMEOUT | irq::CRC_ERR)?;
+
+        // Timeout in steps of 15.625 us
+        let timeout = if timeout_ms == 0 {
+            RX_CONTINUOUS
+        } else {
+            timeout_ms.saturating_mul(64).min(RX_CONTINUOUS - 1)
+        };
+        let timeout = timeout.to_be_bytes();
+        self.write_command(commands::SET_RX, &timeout[1..])
+    }
+
+    /// Poll the IRQ line every millisecond until it is set or `timeout_ms` elapsed
+    fn wait_irq(&mut self, timeout_ms: u32) -> bool {
+        let mut elapsed_ms = 0;
+        loop {
+            if self.interface.irq_pending().unwrap_or(false) {
+                return true;
+            }
+            if elapsed_ms >= timeout_ms {
+                return false;
+            }
+            self.delay.delay_ms(1);
+            elapsed_ms += 1;
+        }
+    }
+
+    /// Read the packet signalled by the IRQ flags `status`
+    ///
+    /// Length and start offset of the payload come from GetRxBufferStatus.
+    fn read_packet(&mut self, status: u16, buffer: &mut [u8]) -> Result<usize, RadioError> {
+        if status & irq::CRC_ERR != 0 {
+            return Err(RadioError::Crc);
+        }
+        if status & irq::RX_DONE == 0 {
+            return Ok(0);
+        }
+
+        let mut rx_status = [0u8; 2];
+        self.read_command(commands::GET_RX_BUFFER_STATUS, &mut rx_status)?;
+        let [len, offset] = rx_status;
+        let len = len as usize;
+        if len > buffer.len() {
+            return Err(RadioError::Config);
+        }
+
+        self.read_buffer(offset, &mut buffer[..len])?;
+        Ok(len)
+    }
+
+    /// Check if a packet was received
+    pub fn is_receiving(&mut self) -> Result<bool, RadioError> {
+        Ok(self.irq_status()? & irq::RX_DONE != 0)
+    }
 }
 
 #[cfg(feature = "sx126x")]
-impl<SPI, CS, RESET, BUSY, DIO1, DELAY> Radio for SX126x<SPI, CS, RESET, BUSY, DIO1, DELAY>
+impl<IF, DELAY> Radio for SX126x<IF, DELAY>
 where
-    SPI: Transfer<u8> + Write<u8>,
-    CS: OutputPin,
-    RESET: OutputPin,
-    BUSY: InputPin,
-    DIO1: InputPin,
+    IF: Sx126xInterface,
     DELAY: DelayMs<u32>,
 {
     type Error = RadioError;
@@ -219,25 +709,43 @@ where
         // Set to standby mode
         self.write_command(commands::SET_STANDBY, &[0])?; // STDBY_RC
 
+        // The TCXO must be powered before the calibration
+        if let Some((voltage, startup_ms)) = self.config.tcxo {
+            // Startup time in steps of 15.625 us
+            let delay = startup_ms.saturating_mul(64).min(0xFF_FFFF).to_be_bytes();
+            self.write_command(
+                commands::SET_DIO3_AS_TCXO_CTRL,
+                &[voltage as u8, delay[1], delay[2], delay[3]],
+            )?;
+        }
+
+        self.write_command(commands::SET_REGULATOR_MODE, &[self.config.regulator as u8])?;
+
+        // Calibrate all blocks
+        self.write_command(commands::CALIBRATE, &[0x7F])?;
+        self.calibrated_band = None;
+
         // Set packet type to LoRa
         self.write_command(commands::SET_PKT_TYPE, &[0x01])?;
 
-        // Set DIO2 as RF switch control
-        self.write_command(commands::SET_DIO2_AS_RF_SWITCH_CTRL, &[0x01])?;
+        if self.config.dio2_as_rf_switch {
+            self.write_command(commands::SET_DIO2_AS_RF_SWITCH_CTRL, &[0x01])?;
+        }
 
         // Configure for LoRa operation
         self.write_register(registers::REG_LORA_SYNC_WORD_MSB, &[0x34, 0x44])?;
 
-        // Set regulator mode to DC-DC
-        self.write_command(commands::SET_REGULATOR_MODE, &[0x01])?;
-
-        // Calibrate all blocks
-        self.write_command(commands::CALIBRATE, &[0x7F])?;
-
         Ok(())
     }
 
     fn set_frequency(&mut self, freq: u32) -> Result<(), Self::Error> {
+        // The image rejection must be calibrated again in a new band
+        let band = image_calibration_band(freq);
+        if self.calibrated_band != Some(band) {
+            self.write_command(commands::CALIBRATE_IMAGE, &band)?;
+            self.calibrated_band = Some(band);
+        }
+
         self.frequency = freq;
         let frf = ((freq as u64) << 25) / 32000000;
         let freq_bytes = [
@@ -249,34 +757,37 @@ where
         self.write_command(commands::SET_RF_FREQUENCY, &freq_bytes)
     }
 
+    fn get_frequency(&self) -> u32 {
+        self.frequency
+    }
+
     fn set_tx_power(&mut self, power: i8) -> Result<(), Self::Error> {
-        let power = power.clamp(2, 22) as u8;
-        // Configure PA
-        self.write_command(commands::SET_PA_CONFIG, &[0x04, 0x07, 0x00, 0x01])?;
+        // Configure PA: duty cycle, hpMax, device select and paLut
+        let (power, pa_config) = match self.config.variant {
+            Sx126xVariant::Sx1261 => (power.clamp(-17, 15), [0x06, 0x00, 0x01, 0x01]),
+            Sx126xVariant::Sx1262 => (power.clamp(2, 22), [0x04, 0x07, 0x00, 0x01]),
+        };
+        self.write_command(commands::SET_PA_CONFIG, &pa_config)?;
         // Set power
-        self.write_command(commands::SET_TX_PARAMS, &[power, 0x04])
+        self.write_command(commands::SET_TX_PARAMS, &[power as u8, 0x04])
     }
 
     fn transmit(&mut self, buffer: &[u8]) -> Result<(), Self::Error> {
         // Write data to buffer
-        self.write_command(commands::WRITE_BUFFER, &[0, &buffer[..]].concat())?;
+        self.write_command_with_data(commands::WRITE_BUFFER, &[0], buffer)?;
 
-        // Set packet parameters
-        let packet_params = [
-            0x00,               // Preamble length MSB
-            0x08,               // Preamble length LSB
-            0x00,               // Header type (explicit)
-            buffer.len() as u8, // Payload length
-            0x01,               // CRC on
-            0x00,               // Standard IQ
-        ];
-        self.write_command(commands::SET_PKT_PARAMS, &packet_params)?;
+        // Packet of the last configure_tx, a LoRaWAN uplink by default
+        let modulation = self
+            .tx_modulation
+            .unwrap_or_else(|| DataRate::SF7BW125.modulation());
+        self.set_packet_params(&modulation, buffer.len() as u8)?;
 
         // Start transmission
+        self.set_irq_params(irq::TX_DONE)?;
         self.write_command(commands::SET_TX, &[0x00, 0x00, 0x00])?;
 
         // Wait for TX done interrupt
-        while !self.dio1.is_high().map_err(|_| RadioError::Gpio)? {
+        while !self.interface.irq_pending()? {
             core::hint::spin_loop();
         }
 
@@ -287,102 +798,55 @@ where
     }
 
     fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
-        // Set to RX mode
-        self.write_command(commands::SET_RX, &[0x00, 0x00, 0x00])?;
+        let continuous = self.rx_timeout_ms == 0;
+        let signalled = if continuous {
+            self.interface.irq_pending()?
+        } else {
+            let timeout_ms = self.rx_timeout_ms;
+            self.start_rx(timeout_ms)?;
+            // The radio raises Timeout on DIO1 at the end of the window
+            self.wait_irq(timeout_ms + 1)
+        };
 
-        // Wait for RX done interrupt
-        while !self.dio1.is_high().map_err(|_| RadioError::Gpio)? {
-            core::hint::spin_loop();
+        if continuous && !signalled {
+            return Ok(0);
         }
 
-        // Get the packet status
-        let mut status = [0u8; 2];
-        self.read_command(commands::GET_PKT_STATUS, &mut status)?;
+        let status = self.irq_status()?;
+        let result = self.read_packet(status, buffer);
+        self.clear_irq_status(irq::ALL)?;
 
-        // Read the received data
-        let mut rx_len = [0u8];
-        self.read_command(commands::READ_BUFFER, &mut rx_len)?;
-        let len = rx_len[0] as usize;
-        if len > buffer.len() {
-            return Err(RadioError::Config);
+        // Continuous reception goes on, a window ends in standby
+        if !continuous {
+            self.standby()?;
         }
 
-        self.cs.set_low().map_err(|_| RadioError::Gpio)?;
-        self.spi
-            .write(&[commands::READ_BUFFER, 0x00])
-            .map_err(|_| RadioError::Spi)?;
-        self.spi
-            .transfer(&mut buffer[..len])
-            .map_err(|_| RadioError::Spi)?;
-        self.cs.set_high().map_err(|_| RadioError::Gpio)?;
-
-        // Clear IRQ status
-        self.write_command(commands::CLR_IRQ_STATUS, &[0xFF, 0xFF])?;
-
-        Ok(len)
+        result
     }
 
     fn configure_tx(&mut self, config: TxConfig) -> Result<(), Self::Error> {
         self.set_frequency(config.frequency)?;
         self.set_tx_power(config.power)?;
 
-        // Set modulation parameters
-        let sf = config.modulation.spreading_factor.clamp(5, 12);
-        let bw = match config.modulation.bandwidth {
-            b if b <= 10_400 => 0x00,
-            b if b <= 15_600 => 0x01,
-            b if b <= 20_800 => 0x02,
-            b if b <= 31_250 => 0x03,
-            b if b <= 41_700 => 0x04,
-            b if b <= 62_500 => 0x05,
-            b if b <= 125_000 => 0x06,
-            b if b <= 250_000 => 0x07,
-            _ => 0x08,
-        };
-        let cr = config.modulation.coding_rate.clamp(5, 8) - 4;
-
-        let mod_params = [
-            sf,   // SF5-SF12
-            bw,   // Bandwidth
-            cr,   // Coding rate
-            0x00, // Low data rate optimize off
-        ];
-
-        self.write_command(commands::SET_MODULATION_PARAMS, &mod_params)
+        self.set_modulation(&config.modulation)?;
+        self.set_sync_word(config.modulation.sync_word)?;
+        self.tx_modulation = Some(config.modulation);
+        Ok(())
     }
 
     fn configure_rx(&mut self, config: RxConfig) -> Result<(), Self::Error> {
         self.set_frequency(config.frequency)?;
 
-        // Set modulation parameters (similar to TX)
-        let sf = config.modulation.spreading_factor.clamp(5, 12);
-        let bw = match config.modulation.bandwidth {
-            b if b <= 10_400 => 0x00,
-            b if b <= 15_600 => 0x01,
-            b if b <= 20_800 => 0x02,
-            b if b <= 31_250 => 0x03,
-            b if b <= 41_700 => 0x04,
-            b if b <= 62_500 => 0x05,
-            b if b <= 125_000 => 0x06,
-            b if b <= 250_000 => 0x07,
-            _ => 0x08,
-        };
-        let cr = config.modulation.coding_rate.clamp(5, 8) - 4;
-
-        let mod_params = [
-            sf, bw, cr, 0x00, // Low data rate optimize off
-        ];
+        self.set_modulation(&config.modulation)?;
+        self.set_packet_params(&config.modulation, u8::MAX)?;
+        self.set_sync_word(config.modulation.sync_word)?;
 
-        self.write_command(commands::SET_MODULATION_PARAMS, &mod_params)?;
-
-        // Set to RX continuous mode
-        self.write_command(commands::SET_RX, &[0xFF, 0xFF, 0xFF])
-    }
-
-    fn is_receiving(&mut self) -> Result<bool, Self::Error> {
-        let mut irq_status = [0u8; 2];
-        self.read_command(commands::GET_IRQ_STATUS, &mut irq_status)?;
-        Ok((irq_status[0] & 0x02) != 0) // RX done bit
+        // A window is opened by `receive`, continuous reception starts now
+        self.rx_timeout_ms = config.timeout_ms;
+        if config.timeout_ms == 0 {
+            self.start_rx(0)?;
+        }
+        Ok(())
     }
 
     fn get_rssi(&mut self) -> Result<i16, Self::Error> {
@@ -397,17 +861,835 @@ where
         Ok((status[1] as i8) / 4)
     }
 
+    fn cad(&mut self, params: &ModulationParams) -> Result<bool, Self::Error> {
+        self.standby()?;
+        self.set_modulation(params)?;
+        // Needed by the reception that may follow a detection
+        self.set_packet_params(params, u8::MAX)?;
+        self.set_sync_word(params.sync_word)?;
+
+        let peak = CAD_DET_PEAK[params.spreading_factor.clamp(7, 12) as usize - 7];
+        // Timeout of the reception following a detection, in steps of 15.625 us
+        let rx_timeout = self
+            .rx_timeout_ms
+            .saturating_mul(64)
+            .min(0xFF_FFFF)
+            .to_be_bytes();
+        self.write_command(
+            commands::SET_CAD_PARAMS,
+            &[
+                self.cad_symbols as u8,
+                peak,
+                CAD_DET_MIN,
+                self.cad_exit_mode as u8,
+                rx_timeout[1],
+                rx_timeout[2],
+                rx_timeout[3],
+            ],
+        )?;
+        self.set_irq_params(irq::CAD_DONE | irq::CAD_DETECTED)?;
+        self.write_command(commands::SET_CAD, &[])?;
+
+        // One more symbol for the processing of the last one
+        let symbols = (1u32 << self.cad_symbols as u8) + 1;
+        let done = self.wait_irq(symbols * symbol_time_us(params) / 1_000 + 1);
+        let status = self.irq_status()?;
+        self.clear_irq_status(irq::CAD_DONE | irq::CAD_DETECTED)?;
+
+        if !done {
+            self.standby()?;
+            return Err(RadioError::Timeout);
+        }
+        Ok(status & irq::CAD_DETECTED != 0)
+    }
+
+    fn random_u32(&mut self) -> Result<u32, Self::Error> {
+        // The generator samples the RSSI noise during continuous reception
+        self.set_irq_params(0)?;
+        let timeout = RX_CONTINUOUS.to_be_bytes();
+        self.write_command(commands::SET_RX, &timeout[1..])?;
+        let mut value = [0u8; 4];
+        self.read_register(registers::REG_RANDOM_NUMBER_GEN_0, &mut value)?;
+        self.standby()?;
+        Ok(u32::from_be_bytes(value))
+    }
+
     fn sleep(&mut self) -> Result<(), Self::Error> {
-        self.write_command(commands::SET_SLEEP, &[0x00])
+        self.write_command(commands::SET_SLEEP, &[SLEEP_COLD_START])?;
+        // Waking up from a cold start runs the power-on calibration again
+        self.calibrated_band = None;
+        Ok(())
+    }
+
+    fn is_transmitting(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.irq_status()? & irq::TX_DONE != 0)
     }
 
     fn standby(&mut self) -> Result<(), Self::Error> {
         self.write_command(commands::SET_STANDBY, &[0x00])
     }
 
-    fn is_transmitting(&mut self) -> Result<bool, Self::Error> {
-        let mut status = [0u8; 2];
-        self.read_command(commands::GET_IRQ_STATUS, &mut status)?;
-        Ok((status[0] & 0x01) != 0) // TX done bit
+    fn set_rx_gain(&mut self, gain: u8) -> Result<(), Self::Error> {
+        // Gain 0 is the maximum gain, as on the SX127x
+        let rx_gain = if gain == 0 {
+            RX_GAIN_BOOSTED
+        } else {
+            RX_GAIN_POWER_SAVING
+        };
+        self.write_register(registers::REG_RX_GAIN, &[rx_gain])
+    }
+
+    fn set_low_power_mode(&mut self, enabled: bool) -> Result<(), Self::Error> {
+        if enabled {
+            // Warm start keeps the configuration for the next exchange
+            self.write_command(commands::SET_SLEEP, &[SLEEP_WARM_START])
+        } else {
+            self.standby()
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), Self::Error> {
+        self.hard_reset()?;
+        self.init()?;
+        if self.frequency != 0 {
+            let frequency = self.frequency;
+            self.set_frequency(frequency)?;
+        }
+        Ok(())
+    }
+
+    fn get_time(&self) -> u32 {
+        // No time source on the radio
+        0
+    }
+
+    fn capabilities(&self) -> RadioCapabilities {
+        // Power range of the PA set up by `set_tx_power`
+        let (min_power_dbm, max_power_dbm) = match self.config.variant {
+            Sx126xVariant::Sx1261 => (-17, 15),
+            Sx126xVariant::Sx1262 => (2, 22),
+        };
+        RadioCapabilities {
+            continuous_rx: true,
+            cad: true,
+            supports_fsk: false,
+            min_power_dbm,
+            max_power_dbm,
+            min_frequency: 150_000_000,
+            max_frequency: 960_000_000,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lorawan::region::DataRate;
+    use core::cell::{Cell, RefCell};
+    use core::convert::Infallible;
+    use heapless::Vec;
+
+    /// SX126x answering commands from a scripted state
+    struct Chip {
+        /// Pending IRQ flags returned by GetIrqStatus
+        irq_status: u16,
+        /// IRQ flags raised once SetRx is sent
+        rx_irq: u16,
+        /// IRQ flags raised once SetCad is sent
+        cad_irq: u16,
+        /// Payload length and start offset returned by GetRxBufferStatus
+        rx_buffer_status: [u8; 2],
+        buffer: [u8; 256],
+        /// Value of the random number generator registers
+        random: [u8; 4],
+        /// Bytes sent in each transaction since the log was cleared
+        log: Vec<Vec<u8, 40>, 24>,
+    }
+
+    impl Chip {
+        fn new() -> Self {
+            Self {
+                irq_status: 0,
+                rx_irq: 0,
+                cad_irq: 0,
+                rx_buffer_status: [0; 2],
+                buffer: [0; 256],
+                random: [0; 4],
+                log: Vec::new(),
+            }
+        }
+
+        /// Exchange one byte of the current transaction
+        fn exchange(&mut self, byte: u8) -> u8 {
+            let Some(transaction) = self.log.last_mut() else {
+                return 0;
+            };
+            let position = transaction.len();
+            let _ = transaction.push(byte);
+            let response = match (transaction[0], position) {
+                (commands::GET_IRQ_STATUS, 2..=3) => self.irq_status.to_be_bytes()[position - 2],
+                (commands::GET_RX_BUFFER_STATUS, 2..=3) => self.rx_buffer_status[position - 2],
+                (commands::READ_BUFFER, 3..) => {
+                    self.buffer[(transaction[1] as usize + position - 3) % 256]
+                }
+                (commands::READ_REGISTER, 4..=7) => self.random[position - 4],
+                _ => 0,
+            };
+            match (transaction[0], position) {
+                (commands::CLR_IRQ_STATUS, 2) => {
+                    let flags = u16::from_be_bytes([transaction[1], transaction[2]]);
+                    self.irq_status &= !flags;
+                }
+                (commands::SET_RX, 3) => self.irq_status |= self.rx_irq,
+                (commands::SET_CAD, 0) => self.irq_status |= self.cad_irq,
+                _ => {}
+            }
+            response
+        }
+    }
+
+    struct Spi<'a>(&'a RefCell<Chip>);
+
+    impl Transfer<u8> for Spi<'_> {
+        type Error = Infallible;
+
+        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+            let mut chip = self.0.borrow_mut();
+            for word in words.iter_mut() {
+                *word = chip.exchange(*word);
+            }
+            Ok(words)
+        }
+    }
+
+    impl Write<u8> for Spi<'_> {
+        type Error = Infallible;
+
+        fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+            let mut chip = self.0.borrow_mut();
+            for &word in words {
+                chip.exchange(word);
+            }
+            Ok(())
+        }
+    }
+
+    /// Chip select, starts a new transaction on each falling edge
+    struct Cs<'a>(&'a RefCell<Chip>);
+
+    impl OutputPin for Cs<'_> {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            let _ = self.0.borrow_mut().log.push(Vec::new());
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    struct Pin(bool);
+
+    impl OutputPin for Pin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    impl InputPin for Pin {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            Ok(self.0)
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            Ok(!self.0)
+        }
+    }
+
+    /// Delay counting the elapsed milliseconds
+    struct Delay<'a>(&'a Cell<u32>);
+
+    impl DelayMs<u32> for Delay<'_> {
+        fn delay_ms(&mut self, ms: u32) {
+            self.0.set(self.0.get() + ms);
+        }
+    }
+
+    type TestRadio<'a> = SX126x<SpiInterface<Spi<'a>, Cs<'a>, Pin, Pin, Pin>, Delay<'a>>;
+
+    /// Radio with a 100 ms receive window configured and DIO1 at `dio1`
+    fn window_radio<'a>(
+        chip: &'a RefCell<Chip>,
+        elapsed: &'a Cell<u32>,
+        dio1: bool,
+    ) -> TestRadio<'a> {
+        let mut radio = SX126x::new(
+            Spi(chip),
+            Cs(chip),
+            Pin(true),
+            Pin(false),
+            Pin(dio1),
+            Delay(elapsed),
+            Sx126xConfig::default(),
+        )
+        .unwrap();
+        radio
+            .configure_rx(RxConfig {
+                frequency: 868_100_000,
+                timeout_ms: 100,
+                modulation: DataRate::SF7BW125.downlink_modulation(),
+            })
+            .unwrap();
+        chip.borrow_mut().log.clear();
+        radio
+    }
+
+    /// Check the bytes of the transactions since the last clear
+    fn assert_transactions(chip: &RefCell<Chip>, expected: &[&[u8]]) {
+        let log = core::mem::take(&mut chip.borrow_mut().log);
+        let log: Vec<&[u8], 24> = log.iter().map(|t| &t[..]).collect();
+        assert_eq!(&log[..], expected);
+    }
+
+    /// Transactions opening a 100 ms receive window
+    const START_WINDOW: [&[u8]; 3] = [
+        &[
+            commands::SET_DIO_IRQ_PARAMS,
+            0x02,
+            0x42,
+            0x02,
+            0x42,
+            0,
+            0,
+            0,
+            0,
+        ],
+        &[commands::CLR_IRQ_STATUS, 0xFF, 0xFF],
+        // 100 ms in steps of 15.625 us
+        &[commands::SET_RX, 0x00, 0x19, 0x00],
+    ];
+
+    #[test]
+    fn test_receive_command_sequence() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = window_radio(&chip, &elapsed, true);
+        {
+            let mut chip = chip.borrow_mut();
+            chip.rx_irq = irq::RX_DONE;
+            chip.rx_buffer_status = [5, 0x80];
+            chip.buffer[0x80..0x85].copy_from_slice(&[1, 2, 3, 4, 5]);
+        }
+
+        let mut buffer = [0u8; 64];
+        assert_eq!(radio.receive(&mut buffer).unwrap(), 5);
+        assert_eq!(buffer[..5], [1, 2, 3, 4, 5]);
+        assert!(buffer[5..].iter().all(|&b| b == 0));
+
+        let mut expected: Vec<&[u8], 24> = Vec::from_slice(&START_WINDOW).unwrap();
+        expected
+            .extend_from_slice(&[
+                &[commands::GET_IRQ_STATUS, 0, 0, 0],
+                &[commands::GET_RX_BUFFER_STATUS, 0, 0, 0],
+                // Offset, status byte, then the payload
+                &[commands::READ_BUFFER, 0x80, 0, 0, 0, 0, 0, 0],
+                &[commands::CLR_IRQ_STATUS, 0xFF, 0xFF],
+                &[commands::SET_STANDBY, 0x00],
+            ])
+            .unwrap();
+        assert_transactions(&chip, &expected);
+    }
+
+    #[test]
+    fn test_receive_crc_error() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = window_radio(&chip, &elapsed, true);
+        {
+            let mut chip = chip.borrow_mut();
+            chip.rx_irq = irq::RX_DONE | irq::CRC_ERR;
+            chip.rx_buffer_status = [5, 0x00];
+        }
+
+        let mut buffer = [0u8; 64];
+        assert!(matches!(radio.receive(&mut buffer), Err(RadioError::Crc)));
+        assert!(buffer.iter().all(|&b| b == 0));
+
+        // The buffer is not read, the IRQ flags are cleared
+        let mut expected: Vec<&[u8], 24> = Vec::from_slice(&START_WINDOW).unwrap();
+        expected
+            .extend_from_slice(&[
+                &[commands::GET_IRQ_STATUS, 0, 0, 0],
+                &[commands::CLR_IRQ_STATUS, 0xFF, 0xFF],
+                &[commands::SET_STANDBY, 0x00],
+            ])
+            .unwrap();
+        assert_transactions(&chip, &expected);
+        assert_eq!(chip.borrow().irq_status, 0);
+    }
+
+    #[test]
+    fn test_receive_window_timeout() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+
+        // Timeout IRQ raised by the radio
+        let mut radio = window_radio(&chip, &elapsed, true);
+        chip.borrow_mut().rx_irq = irq::TIMEOUT;
+        let mut buffer = [0u8; 64];
+        assert_eq!(radio.receive(&mut buffer).unwrap(), 0);
+
+        // DIO1 never asserted
+        let mut radio = window_radio(&chip, &elapsed, false);
+        elapsed.set(0);
+        assert_eq!(radio.receive(&mut buffer).unwrap(), 0);
+        assert_eq!(elapsed.get(), 101);
+    }
+
+    #[test]
+    fn test_receive_packet_larger_than_buffer() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = window_radio(&chip, &elapsed, true);
+        {
+            let mut chip = chip.borrow_mut();
+            chip.rx_irq = irq::RX_DONE;
+            chip.rx_buffer_status = [32, 0x00];
+        }
+
+        let mut buffer = [0u8; 16];
+        assert!(matches!(
+            radio.receive(&mut buffer),
+            Err(RadioError::Config)
+        ));
+    }
+
+    #[test]
+    fn test_continuous_receive() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = SX126x::new(
+            Spi(&chip),
+            Cs(&chip),
+            Pin(true),
+            Pin(false),
+            Pin(true),
+            Delay(&elapsed),
+            Sx126xConfig::default(),
+        )
+        .unwrap();
+        radio
+            .configure_rx(RxConfig {
+                frequency: 869_525_000,
+                timeout_ms: 0,
+                modulation: DataRate::SF9BW125.downlink_modulation(),
+            })
+            .unwrap();
+        let log = core::mem::take(&mut chip.borrow_mut().log);
+        assert_eq!(
+            &log[log.len() - 1][..],
+            &[commands::SET_RX, 0xFF, 0xFF, 0xFF]
+        );
+
+        {
+            let mut chip = chip.borrow_mut();
+            chip.irq_status = irq::RX_DONE;
+            chip.rx_buffer_status = [2, 0x10];
+            chip.buffer[0x10..0x12].copy_from_slice(&[0xAB, 0xCD]);
+        }
+        let mut buffer = [0u8; 64];
+        assert_eq!(radio.receive(&mut buffer).unwrap(), 2);
+        assert_eq!(buffer[..2], [0xAB, 0xCD]);
+
+        // Reception goes on without entering standby
+        let log = core::mem::take(&mut chip.borrow_mut().log);
+        assert_eq!(
+            &log[log.len() - 1][..],
+            &[commands::CLR_IRQ_STATUS, 0xFF, 0xFF]
+        );
+    }
+
+    /// Opcodes of the transactions since the last clear
+    fn opcodes(chip: &RefCell<Chip>) -> Vec<u8, 24> {
+        let log = core::mem::take(&mut chip.borrow_mut().log);
+        log.iter().map(|t| t[0]).collect()
+    }
+
+    #[test]
+    fn test_image_calibration_on_band_change() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = window_radio(&chip, &elapsed, false);
+        radio.set_frequency(868_100_000).unwrap();
+        chip.borrow_mut().log.clear();
+
+        // Within the 863-870 MHz band
+        radio.set_frequency(868_300_000).unwrap();
+        assert_eq!(opcodes(&chip), [commands::SET_RF_FREQUENCY]);
+
+        // Into the 902-928 MHz band
+        radio.set_frequency(915_000_000).unwrap();
+        let log = core::mem::take(&mut chip.borrow_mut().log);
+        assert_eq!(&log[0][..], &[commands::CALIBRATE_IMAGE, 0xE1, 0xE9]);
+        assert_eq!(log[1][0], commands::SET_RF_FREQUENCY);
+        assert_eq!(log.len(), 2);
+
+        // And back
+        radio.set_frequency(868_100_000).unwrap();
+        let log = core::mem::take(&mut chip.borrow_mut().log);
+        assert_eq!(&log[0][..], &[commands::CALIBRATE_IMAGE, 0xD7, 0xDB]);
+    }
+
+    #[test]
+    fn test_packet_params_and_sync_word() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = window_radio(&chip, &elapsed, true);
+        let written = |chip: &RefCell<Chip>, expected: &[u8]| {
+            chip.borrow().log.iter().any(|t| t[..] == *expected)
+        };
+
+        // Downlink window: explicit header, inverted IQ, public sync word
+        radio
+            .configure_rx(RxConfig {
+                frequency: 868_100_000,
+                timeout_ms: 100,
+                modulation: DataRate::SF7BW125.downlink_modulation(),
+            })
+            .unwrap();
+        assert!(written(
+            &chip,
+            &[commands::SET_PKT_PARAMS, 0x00, 0x08, 0x00, 0xFF, 0x01, 0x01]
+        ));
+        assert!(written(
+            &chip,
+            &[commands::WRITE_REGISTER, 0x07, 0x36, 0x00]
+        ));
+        assert!(written(
+            &chip,
+            &[commands::WRITE_REGISTER, 0x07, 0x40, 0x34, 0x44]
+        ));
+        chip.borrow_mut().log.clear();
+
+        // Beacon: 10 symbol preamble, implicit header, no CRC, standard IQ
+        radio
+            .configure_rx(RxConfig {
+                frequency: 869_525_000,
+                timeout_ms: 100,
+                modulation: DataRate::SF9BW125.beacon_modulation(17),
+            })
+            .unwrap();
+        assert!(written(
+            &chip,
+            &[commands::SET_PKT_PARAMS, 0x00, 0x0A, 0x01, 17, 0x00, 0x00]
+        ));
+        assert!(written(
+            &chip,
+            &[commands::WRITE_REGISTER, 0x07, 0x36, 0x04]
+        ));
+        chip.borrow_mut().log.clear();
+
+        // Uplink on a private network
+        radio
+            .configure_tx(TxConfig {
+                frequency: 868_100_000,
+                power: 14,
+                modulation: ModulationParams {
+                    sync_word: 0x12,
+                    ..DataRate::SF7BW125.modulation()
+                },
+            })
+            .unwrap();
+        radio.transmit(&[1, 2, 3]).unwrap();
+        assert!(written(
+            &chip,
+            &[commands::WRITE_REGISTER, 0x07, 0x40, 0x14, 0x24]
+        ));
+        assert!(written(
+            &chip,
+            &[commands::SET_PKT_PARAMS, 0x00, 0x08, 0x00, 3, 0x01, 0x00]
+        ));
+    }
+
+    #[test]
+    fn test_rx_gain_and_low_power_mode() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = window_radio(&chip, &elapsed, false);
+
+        radio.set_rx_gain(0).unwrap();
+        radio.set_rx_gain(3).unwrap();
+        radio.set_low_power_mode(true).unwrap();
+        radio.set_low_power_mode(false).unwrap();
+        assert_transactions(
+            &chip,
+            &[
+                &[commands::WRITE_REGISTER, 0x08, 0xAC, 0x96],
+                &[commands::WRITE_REGISTER, 0x08, 0xAC, 0x94],
+                &[commands::SET_SLEEP, 0x04],
+                &[commands::SET_STANDBY, 0x00],
+            ],
+        );
+    }
+
+    #[test]
+    fn test_reset_reinitializes() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = window_radio(&chip, &elapsed, false);
+        radio.set_frequency(868_100_000).unwrap();
+        chip.borrow_mut().log.clear();
+
+        radio.reset().unwrap();
+        assert_eq!(radio.get_frequency(), 868_100_000);
+        let sent = opcodes(&chip);
+        assert_eq!(sent[0], commands::SET_STANDBY);
+        assert!(sent.contains(&commands::SET_PKT_TYPE));
+        // The image is calibrated again for the current frequency
+        assert_eq!(
+            &sent[sent.len() - 2..],
+            &[commands::CALIBRATE_IMAGE, commands::SET_RF_FREQUENCY]
+        );
+    }
+
+    /// Command stream of `init` with `config`
+    fn init_transactions(config: Sx126xConfig) -> Vec<Vec<u8, 40>, 24> {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = SX126x::new(
+            Spi(&chip),
+            Cs(&chip),
+            Pin(true),
+            Pin(false),
+            Pin(false),
+            Delay(&elapsed),
+            config,
+        )
+        .unwrap();
+        radio.init().unwrap();
+        let log = core::mem::take(&mut chip.borrow_mut().log);
+        log
+    }
+
+    #[test]
+    fn test_init_xtal_and_tcxo() {
+        let xtal = init_transactions(Sx126xConfig::default());
+        let expected: [&[u8]; 6] = [
+            &[commands::SET_STANDBY, 0x00],
+            &[commands::SET_REGULATOR_MODE, 0x01],
+            &[commands::CALIBRATE, 0x7F],
+            &[commands::SET_PKT_TYPE, 0x01],
+            &[commands::SET_DIO2_AS_RF_SWITCH_CTRL, 0x01],
+            &[commands::WRITE_REGISTER, 0x07, 0x40, 0x34, 0x44],
+        ];
+        let xtal: Vec<&[u8], 24> = xtal.iter().map(|t| &t[..]).collect();
+        assert_eq!(&xtal[..], &expected);
+
+        // TCXO at 1.8 V with a 5 ms startup, powered before the calibration
+        let tcxo = init_transactions(
+            Sx126xConfig::default()
+                .with_tcxo(TcxoVoltage::V1_8, 5)
+                .with_regulator(RegulatorMode::Ldo)
+                .with_dio2_as_rf_switch(false),
+        );
+        let expected: [&[u8]; 6] = [
+            &[commands::SET_STANDBY, 0x00],
+            &[commands::SET_DIO3_AS_TCXO_CTRL, 0x02, 0x00, 0x01, 0x40],
+            &[commands::SET_REGULATOR_MODE, 0x00],
+            &[commands::CALIBRATE, 0x7F],
+            &[commands::SET_PKT_TYPE, 0x01],
+            &[commands::WRITE_REGISTER, 0x07, 0x40, 0x34, 0x44],
+        ];
+        let tcxo: Vec<&[u8], 24> = tcxo.iter().map(|t| &t[..]).collect();
+        assert_eq!(&tcxo[..], &expected);
+    }
+
+    #[test]
+    fn test_pa_config_per_variant() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = window_radio(&chip, &elapsed, false);
+        assert_eq!(radio.capabilities().clamp_power(30), 22);
+        radio.set_tx_power(30).unwrap();
+        assert_transactions(
+            &chip,
+            &[
+                &[commands::SET_PA_CONFIG, 0x04, 0x07, 0x00, 0x01],
+                &[commands::SET_TX_PARAMS, 22, 0x04],
+            ],
+        );
+
+        let mut radio = SX126x::new(
+            Spi(&chip),
+            Cs(&chip),
+            Pin(true),
+            Pin(false),
+            Pin(false),
+            Delay(&elapsed),
+            Sx126xConfig::default().with_variant(Sx126xVariant::Sx1261),
+        )
+        .unwrap();
+        chip.borrow_mut().log.clear();
+        assert_eq!(radio.capabilities().clamp_power(30), 15);
+        assert_eq!(radio.capabilities().clamp_power(-20), -17);
+        radio.set_tx_power(30).unwrap();
+        assert_transactions(
+            &chip,
+            &[
+                &[commands::SET_PA_CONFIG, 0x06, 0x00, 0x01, 0x01],
+                &[commands::SET_TX_PARAMS, 15, 0x04],
+            ],
+        );
+    }
+
+    /// Transport recording the frames and reset edges it is asked for
+    #[derive(Default)]
+    struct RecordingInterface {
+        /// Header and data length of each frame
+        frames: Vec<(Vec<u8, 12>, usize), 24>,
+        reset: Vec<bool, 4>,
+        irq: bool,
+    }
+
+    impl Sx126xInterface for RecordingInterface {
+        fn write(&mut self, header: &[u8], data: &[u8]) -> Result<(), RadioError> {
+            let mut frame = Vec::from_slice(header).unwrap();
+            frame.extend_from_slice(data).unwrap();
+            let _ = self.frames.push((frame, 0));
+            Ok(())
+        }
+
+        fn read(&mut self, header: &[u8], data: &mut [u8]) -> Result<(), RadioError> {
+            data.fill(0);
+            let _ = self
+                .frames
+                .push((Vec::from_slice(header).unwrap(), data.len()));
+            Ok(())
+        }
+
+        fn is_busy(&mut self) -> Result<bool, RadioError> {
+            Ok(false)
+        }
+
+        fn irq_pending(&mut self) -> Result<bool, RadioError> {
+            Ok(self.irq)
+        }
+
+        fn set_reset(&mut self, asserted: bool) -> Result<(), RadioError> {
+            let _ = self.reset.push(asserted);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_custom_interface_framing() {
+        let elapsed = Cell::new(0);
+        let mut radio = SX126x::with_interface(
+            RecordingInterface::default(),
+            Delay(&elapsed),
+            Sx126xConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(&radio.interface().reset[..], &[true, false]);
+        radio.interface.frames.clear();
+
+        radio.interface.irq = true;
+        radio.set_rx_gain(0).unwrap();
+        let mut buffer = [0u8; 8];
+        radio.read_buffer(0x80, &mut buffer[..3]).unwrap();
+        assert!(radio.wait_irq(0));
+        let frames: Vec<(&[u8], usize), 24> = radio
+            .interface()
+            .frames
+            .iter()
+            .map(|(header, len)| (&header[..], *len))
+            .collect();
+        assert_eq!(
+            &frames[..],
+            &[
+                (&[commands::WRITE_REGISTER, 0x08, 0xAC, 0x96][..], 0),
+                (&[commands::READ_BUFFER, 0x80, 0][..], 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cad_params_and_result() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = window_radio(&chip, &elapsed, true);
+        let modulation = DataRate::SF9BW125.downlink_modulation();
+
+        chip.borrow_mut().cad_irq = irq::CAD_DONE;
+        assert!(!radio.cad(&modulation).unwrap());
+        let log = core::mem::take(&mut chip.borrow_mut().log);
+        // Two symbols, SF9 peak, CAD only
+        assert!(log.iter().any(|t| t[..]
+            == [
+                commands::SET_CAD_PARAMS,
+                0x01,
+                23,
+                10,
+                0x00,
+                0x00,
+                0x19,
+                0x00
+            ]));
+        assert_eq!(&log[log.len() - 3][..], &[commands::SET_CAD]);
+
+        chip.borrow_mut().cad_irq = irq::CAD_DONE | irq::CAD_DETECTED;
+        radio.set_cad_params(CadSymbols::Four, CadExitMode::CadRx);
+        assert!(radio.cad(&modulation).unwrap());
+        let log = core::mem::take(&mut chip.borrow_mut().log);
+        assert!(log.iter().any(|t| t[..]
+            == [
+                commands::SET_CAD_PARAMS,
+                0x02,
+                23,
+                10,
+                0x01,
+                0x00,
+                0x19,
+                0x00
+            ]));
+
+        // CadDone never raised
+        let mut radio = window_radio(&chip, &elapsed, false);
+        chip.borrow_mut().cad_irq = 0;
+        elapsed.set(0);
+        assert!(matches!(radio.cad(&modulation), Err(RadioError::Timeout)));
+        // Three symbols of 4.096 ms and a margin
+        assert_eq!(elapsed.get(), 13);
+    }
+
+    #[test]
+    fn test_random_number() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = window_radio(&chip, &elapsed, false);
+        chip.borrow_mut().random = [0x12, 0x34, 0x56, 0x78];
+
+        assert_eq!(radio.random_u32().unwrap(), 0x1234_5678);
+        let log = core::mem::take(&mut chip.borrow_mut().log);
+        let tail: Vec<&[u8], 3> = log[log.len() - 3..].iter().map(|t| &t[..]).collect();
+        // Sampled in continuous reception, then back to standby
+        assert_eq!(
+            &tail[..],
+            &[
+                &[commands::SET_RX, 0xFF, 0xFF, 0xFF][..],
+                &[commands::READ_REGISTER, 0x08, 0x19, 0, 0, 0, 0, 0],
+                &[commands::SET_STANDBY, 0x00],
+            ]
+        );
     }
 }
@@ -0,0 +1,836 @@
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use super::fsk_lora_regs::*;
+use super::sx127x::SX127xError;
+use super::traits::{ModulationParams, Radio, RadioCapabilities, RxConfig, TxConfig};
+use crate::lorawan::phy::{symbol_time_us, time_on_air};
+
+// RegPaDac, 0x87 enables +20 dBm on PA_BOOST
+const REG_PA_DAC: u8 = 0x5A;
+const PA_DAC_DEFAULT: u8 = 0x84;
+const PA_DAC_20DBM: u8 = 0x87;
+
+// PaSelect bit of RegPaConfig, output on PA_BOOST
+const PA_BOOST: u8 = 0x80;
+
+// RSSI offset of packet and channel RSSI
+const RSSI_OFFSET: i16 = -139;
+
+// RegModemConfig1 fields: Bw in bits 7-6, CodingRate in bits 5-3
+const IMPLICIT_HEADER_MODE: u8 = 0x04;
+const RX_PAYLOAD_CRC_ON: u8 = 0x02;
+const LOW_DATA_RATE_OPTIMIZE: u8 = 0x01;
+
+// AgcAutoOn bit of RegModemConfig2
+const AGC_AUTO_ON: u8 = 0x04;
+
+/// SX1272/SX1273 driver
+///
+/// Shares the register map and errors of the SX127x driver. The SX1272 only
+/// covers the 860-1020 MHz band with 125, 250 and 500 kHz bandwidths.
+pub struct SX1272<SPI, CS, RESET, DIO0, DIO1, DELAY>
+where
+    SPI: Transfer<u8> + Write<u8>,
+    CS: OutputPin,
+    RESET: OutputPin,
+    DIO0: InputPin,
+    DIO1: InputPin,
+    DELAY: DelayMs<u32>,
+{
+    spi: SPI,
+    cs: CS,
+    reset: RESET,
+    dio0: DIO0,
+    dio1: DIO1,
+    delay: DELAY,
+    frequency: u32,
+    /// Modulation of the last transmit configuration, bounds the TX wait
+    tx_modulation: Option<ModulationParams>,
+    /// RX timeout of the last receive configuration, 0 for continuous reception
+    rx_timeout_ms: u32,
+    /// RSSI of the last received packet, until the next reception starts
+    packet_rssi: Option<i16>,
+    /// SNR of the last received packet, until the next reception starts
+    packet_snr: Option<i8>,
+}
+
+impl<SPI, CS, RESET, DIO0, DIO1, DELAY, E, CSE, RESETE> SX1272<SPI, CS, RESET, DIO0, DIO1, DELAY>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    CS: OutputPin<Error = CSE>,
+    RESET: OutputPin<Error = RESETE>,
+    DIO0: InputPin,
+    DIO1: InputPin,
+    DELAY: DelayMs<u32>,
+    E: core::fmt::Debug,
+    CSE: core::fmt::Debug,
+    RESETE: core::fmt::Debug,
+{
+    /// Create new instance
+    ///
+    /// `delay` times the reset pulse and paces the polling of DIO0 and DIO1
+    /// while waiting for the end of a transmission or reception.
+    pub fn new(
+        spi: SPI,
+        cs: CS,
+        reset: RESET,
+        dio0: DIO0,
+        dio1: DIO1,
+        delay: DELAY,
+    ) -> Result<Self, SX127xError<E, CSE, RESETE>> {
+        let mut sx1272 = Self {
+            spi,
+            cs,
+            reset,
+            dio0,
+            dio1,
+            delay,
+            frequency: 0,
+            tx_modulation: None,
+            rx_timeout_ms: 0,
+            packet_rssi: None,
+            packet_snr: None,
+        };
+
+        // Initialize the radio
+        sx1272.init()?;
+
+        Ok(sx1272)
+    }
+
+    /// Read a single register
+    fn read_u8(&mut self, addr: u8) -> Result<u8, SX127xError<E, CSE, RESETE>> {
+        self.cs.set_low().map_err(SX127xError::Cs)?;
+        let mut buffer = [addr & !SPI_WRITE, 0x00];
+        self.spi.transfer(&mut buffer).map_err(SX127xError::Spi)?;
+        self.cs.set_high().map_err(SX127xError::Cs)?;
+        Ok(buffer[1])
+    }
+
+    /// Write register
+    fn write_register(&mut self, addr: u8, value: u8) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        self.cs.set_low().map_err(SX127xError::Cs)?;
+        self.spi
+            .write(&[addr | SPI_WRITE, value])
+            .map_err(SX127xError::Spi)?;
+        self.cs.set_high().map_err(SX127xError::Cs)
+    }
+
+    /// Read from FIFO
+    fn read_fifo(&mut self, buffer: &mut [u8]) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        self.cs.set_low().map_err(SX127xError::Cs)?;
+        self.spi.write(&[REG_FIFO]).map_err(SX127xError::Spi)?;
+        self.spi.transfer(buffer).map_err(SX127xError::Spi)?;
+        self.cs.set_high().map_err(SX127xError::Cs)
+    }
+
+    /// Write to FIFO
+    fn write_fifo(&mut self, data: &[u8]) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        self.cs.set_low().map_err(SX127xError::Cs)?;
+        self.spi
+            .write(&[REG_FIFO | SPI_WRITE])
+            .map_err(SX127xError::Spi)?;
+        self.spi.write(data).map_err(SX127xError::Spi)?;
+        self.cs.set_high().map_err(SX127xError::Cs)
+    }
+
+    /// Set operating mode
+    fn set_mode(&mut self, mode: u8) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        self.write_register(REG_OP_MODE, mode | LONG_RANGE_MODE)
+    }
+
+    /// Pulse NRESET
+    ///
+    /// NRESET of the SX1272 is active high: it is held high for 1 ms, then
+    /// the chip is ready 5 ms after its release.
+    fn hard_reset(&mut self) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        self.reset.set_high().map_err(SX127xError::Reset)?;
+        self.delay.delay_ms(1);
+        self.reset.set_low().map_err(SX127xError::Reset)?;
+        self.delay.delay_ms(5);
+        Ok(())
+    }
+
+    /// Poll DIO0 every millisecond until it is set or `timeout_ms` elapsed
+    ///
+    /// A set DIO1 (RxTimeout) ends the wait early.
+    fn wait_dio0(&mut self, timeout_ms: u32) -> bool {
+        let mut elapsed_ms = 0;
+        loop {
+            if self.dio0.is_high().unwrap_or(false) {
+                return true;
+            }
+            if elapsed_ms >= timeout_ms || self.dio1.is_high().unwrap_or(false) {
+                return false;
+            }
+            self.delay.delay_ms(1);
+            elapsed_ms += 1;
+        }
+    }
+
+    /// Map DIO0 to RxDone and enter RX mode
+    fn start_rx(&mut self) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        self.write_register(REG_DIO_MAPPING_1, DIO0_RX_DONE)?;
+        self.set_mode(MODE_RX)
+    }
+
+    /// Read the received packet from the FIFO, with its RSSI and SNR
+    fn read_packet(&mut self, buffer: &mut [u8]) -> Result<usize, SX127xError<E, CSE, RESETE>> {
+        let len = self.read_u8(REG_RX_NB_BYTES)? as usize;
+        if len > buffer.len() {
+            return Err(SX127xError::BufferTooSmall);
+        }
+
+        // The packet starts at the FIFO address of the last reception
+        let start = self.read_u8(REG_FIFO_RX_CURRENT_ADDR)?;
+        self.write_register(REG_FIFO_ADDR_PTR, start)?;
+        self.read_fifo(&mut buffer[..len])?;
+
+        // SNR is in quarter dB, and lowers the RSSI of packets below the noise floor
+        let snr = self.read_u8(REG_PKT_SNR_VALUE)? as i8 / 4;
+        let mut rssi = RSSI_OFFSET + self.read_u8(REG_PKT_RSSI_VALUE)? as i16;
+        if snr < 0 {
+            rssi += snr as i16;
+        }
+        self.packet_snr = Some(snr);
+        self.packet_rssi = Some(rssi);
+        Ok(len)
+    }
+
+    /// Set preamble length in symbols
+    pub fn set_preamble_length(&mut self, length: u16) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        self.write_register(REG_PREAMBLE_MSB, (length >> 8) as u8)?;
+        self.write_register(REG_PREAMBLE_LSB, length as u8)
+    }
+
+    /// Invert the I and Q signals in reception, as LoRaWAN downlinks require
+    pub fn set_iq_inverted(&mut self, inverted: bool) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        if inverted {
+            self.write_register(REG_INVERT_IQ, INVERT_IQ_RX)?;
+            self.write_register(REG_INVERT_IQ_2, INVERT_IQ_2_ON)
+        } else {
+            self.write_register(REG_INVERT_IQ, INVERT_IQ_OFF)?;
+            self.write_register(REG_INVERT_IQ_2, INVERT_IQ_2_OFF)
+        }
+    }
+
+    /// Set the sync word, 0x34 on public LoRaWAN networks
+    pub fn set_sync_word(&mut self, sync_word: u8) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        self.write_register(REG_SYNC_WORD, sync_word)
+    }
+
+    /// Configure all modulation parameters
+    ///
+    /// Bandwidths other than 125, 250 and 500 kHz are rounded up.
+    fn set_modulation(
+        &mut self,
+        modulation: &ModulationParams,
+    ) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        let sf = modulation.spreading_factor.clamp(6, 12);
+        let bw = match modulation.bandwidth {
+            b if b <= 125_000 => 0,
+            b if b <= 250_000 => 1,
+            _ => 2,
+        };
+        let cr = modulation.coding_rate.clamp(5, 8) - 4;
+
+        let mut modem_config1 = (bw << 6) | (cr << 3);
+        if modulation.implicit_header.is_some() {
+            modem_config1 |= IMPLICIT_HEADER_MODE;
+        }
+        if modulation.crc {
+            modem_config1 |= RX_PAYLOAD_CRC_ON;
+        }
+        // Mandatory when a symbol lasts longer than 16 ms (SF11 and SF12 at 125 kHz)
+        if bw == 0 && sf >= 11 {
+            modem_config1 |= LOW_DATA_RATE_OPTIMIZE;
+        }
+        let modem_config2 = (sf << 4) | AGC_AUTO_ON;
+
+        self.write_register(REG_MODEM_CONFIG_1, modem_config1)?;
+        self.write_register(REG_MODEM_CONFIG_2, modem_config2)?;
+        if let Some(length) = modulation.implicit_header {
+            self.write_register(REG_PAYLOAD_LENGTH, length)?;
+        }
+        self.set_preamble_length(modulation.preamble_length)?;
+        self.set_iq_inverted(modulation.iq_inverted)?;
+        self.set_sync_word(modulation.sync_word)
+    }
+}
+
+impl<SPI, CS, RESET, DIO0, DIO1, DELAY, E, CSE, RESETE> Radio
+    for SX1272<SPI, CS, RESET, DIO0, DIO1, DELAY>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    CS: OutputPin<Error = CSE>,
+    RESET: OutputPin<Error = RESETE>,
+    DIO0: InputPin,
+    DIO1: InputPin,
+    DELAY: DelayMs<u32>,
+    E: core::fmt::Debug,
+    CSE: core::fmt::Debug,
+    RESETE: core::fmt::Debug,
+{
+    type Error = SX127xError<E, CSE, RESETE>;
+
+    fn init(&mut self) -> Result<(), Self::Error> {
+        self.hard_reset()?;
+
+        // LoRa mode can only be selected in sleep mode
+        self.set_mode(MODE_SLEEP)?;
+
+        self.write_register(REG_FIFO_TX_BASE_ADDR, FIFO_BASE_ADDR)?;
+        self.write_register(REG_FIFO_RX_BASE_ADDR, FIFO_BASE_ADDR)?;
+        self.write_register(REG_LNA, LNA_MAX_GAIN | LNA_BOOST_HF)?;
+
+        // Only TxDone, RxDone, RxTimeout and the CAD IRQs are used
+        self.write_register(REG_IRQ_FLAGS_MASK, !USED_IRQS)?;
+        self.write_register(REG_IRQ_FLAGS, IRQ_ALL_MASK)?;
+        self.write_register(REG_DIO_MAPPING_1, DIO0_RX_DONE)?;
+
+        Ok(())
+    }
+
+    fn set_frequency(&mut self, freq: u32) -> Result<(), Self::Error> {
+        if !(860_000_000..=1_020_000_000).contains(&freq) {
+            return Err(SX127xError::InvalidFrequency);
+        }
+
+        self.frequency = freq;
+
+        // Frf = freq * 2^19 / 32 MHz
+        let frf = (freq as u64 * (1 << 19) / 32_000_000) as u32;
+        self.write_register(REG_FRF_MSB, (frf >> 16) as u8)?;
+        self.write_register(REG_FRF_MID, (frf >> 8) as u8)?;
+        self.write_register(REG_FRF_LSB, frf as u8)
+    }
+
+    fn get_frequency(&self) -> u32 {
+        self.frequency
+    }
+
+    /// Set the output power on PA_BOOST, 2 to 20 dBm
+    ///
+    /// Above 17 dBm the high power PA DAC is enabled and the output power
+    /// is 5 dB above the OutputPower field.
+    fn set_tx_power(&mut self, power: i8) -> Result<(), Self::Error> {
+        if !(2..=20).contains(&power) {
+            return Err(SX127xError::InvalidPower);
+        }
+        if power > 17 {
+            self.write_register(REG_PA_DAC, PA_DAC_20DBM)?;
+            self.write_register(REG_PA_CONFIG, PA_BOOST | (power - 5) as u8)
+        } else {
+            self.write_register(REG_PA_DAC, PA_DAC_DEFAULT)?;
+            self.write_register(REG_PA_CONFIG, PA_BOOST | (power - 2) as u8)
+        }
+    }
+
+    fn configure_tx(&mut self, config: TxConfig) -> Result<(), Self::Error> {
+        self.set_frequency(config.frequency)?;
+        self.set_tx_power(config.power)?;
+
+        self.set_modulation(&config.modulation)?;
+        self.tx_modulation = Some(config.modulation);
+
+        Ok(())
+    }
+
+    fn configure_rx(&mut self, config: RxConfig) -> Result<(), Self::Error> {
+        self.set_frequency(config.frequency)?;
+        self.set_modulation(&config.modulation)?;
+
+        // Signal quality reads the channel again until a packet is received
+        self.packet_rssi = None;
+        self.packet_snr = None;
+
+        self.rx_timeout_ms = config.timeout_ms;
+        self.start_rx()
+    }
+
+    fn transmit(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        // The FIFO is only accessible in standby
+        self.set_mode(MODE_STDBY)?;
+        self.write_register(REG_DIO_MAPPING_1, DIO0_TX_DONE)?;
+
+        self.write_register(REG_FIFO_ADDR_PTR, FIFO_BASE_ADDR)?;
+        self.write_fifo(data)?;
+        self.write_register(REG_PAYLOAD_LENGTH, data.len() as u8)?;
+
+        self.set_mode(MODE_TX)?;
+
+        // Wait for TX done using DIO0, at most the time-on-air of the packet
+        let timeout_ms = self
+            .tx_modulation
+            .map_or(DEFAULT_TX_TIMEOUT_MS, |modulation| {
+                time_on_air(data.len(), &modulation) + TX_TIMEOUT_MARGIN_MS
+            });
+        let done = self.wait_dio0(timeout_ms);
+
+        self.write_register(REG_IRQ_FLAGS, IRQ_TX_DONE_MASK)?;
+        self.set_mode(MODE_STDBY)?;
+
+        if done {
+            Ok(())
+        } else {
+            Err(SX127xError::Timeout)
+        }
+    }
+
+    /// Receive a packet
+    ///
+    /// With an RX timeout, reception is restarted and waits up to the timeout
+    /// for a packet. In continuous reception (timeout 0) the packet received
+    /// since the last call is read, if any, without waiting. Returns 0 if no
+    /// packet was received.
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        let continuous = self.rx_timeout_ms == 0;
+        let received = if continuous {
+            self.dio0.is_high().unwrap_or(false)
+        } else {
+            self.start_rx()?;
+            let timeout_ms = self.rx_timeout_ms;
+            self.wait_dio0(timeout_ms)
+        };
+
+        if continuous && !received {
+            return Ok(0);
+        }
+
+        let result = if received {
+            self.read_packet(buffer)
+        } else {
+            Ok(0)
+        };
+
+        self.write_register(REG_IRQ_FLAGS, IRQ_RX_DONE_MASK | IRQ_RX_TIMEOUT_MASK)?;
+
+        // Continuous reception goes on, a window ends in standby
+        if !continuous {
+            self.set_mode(MODE_STDBY)?;
+        }
+
+        result
+    }
+
+    fn get_rssi(&mut self) -> Result<i16, Self::Error> {
+        match self.packet_rssi {
+            Some(rssi) => Ok(rssi),
+            None => Ok(RSSI_OFFSET + self.read_u8(REG_RSSI_VALUE)? as i16),
+        }
+    }
+
+    fn get_snr(&mut self) -> Result<i8, Self::Error> {
+        match self.packet_snr {
+            Some(snr) => Ok(snr),
+            None => Ok(self.read_u8(REG_PKT_SNR_VALUE)? as i8 / 4),
+        }
+    }
+
+    fn cad(&mut self, params: &ModulationParams) -> Result<bool, Self::Error> {
+        self.set_mode(MODE_STDBY)?;
+        self.set_modulation(params)?;
+        self.write_register(REG_IRQ_FLAGS, IRQ_ALL_MASK)?;
+        self.write_register(REG_DIO_MAPPING_1, DIO0_CAD_DONE)?;
+        self.set_mode(MODE_CAD)?;
+
+        // CAD listens for about two symbols, then returns to standby
+        let done = self.wait_dio0(3 * symbol_time_us(params) / 1_000 + 1);
+        let flags = self.read_u8(REG_IRQ_FLAGS)?;
+        self.write_register(REG_IRQ_FLAGS, IRQ_CAD_DONE_MASK | IRQ_CAD_DETECTED_MASK)?;
+        self.write_register(REG_DIO_MAPPING_1, DIO0_RX_DONE)?;
+
+        if !done {
+            self.set_mode(MODE_STDBY)?;
+            return Err(SX127xError::Timeout);
+        }
+        Ok(flags & IRQ_CAD_DETECTED_MASK != 0)
+    }
+
+    fn random_u32(&mut self) -> Result<u32, Self::Error> {
+        // The LSB of the wideband RSSI is noise while receiving
+        self.set_mode(MODE_RX)?;
+        let mut value = 0;
+        for _ in 0..32 {
+            self.delay.delay_ms(1);
+            value = (value << 1) | u32::from(self.read_u8(REG_RSSI_WIDEBAND)? & 0x01);
+        }
+        self.set_mode(MODE_STDBY)?;
+        Ok(value)
+    }
+
+    fn is_transmitting(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.read_u8(REG_IRQ_FLAGS)? & IRQ_TX_DONE_MASK != 0)
+    }
+
+    fn set_rx_gain(&mut self, gain: u8) -> Result<(), Self::Error> {
+        // LnaGain G1 (maximum) to G6 (-48 dB)
+        let lna_gain = (gain.min(5) + 1) << 5;
+        self.write_register(REG_LNA, lna_gain | LNA_BOOST_HF)
+    }
+
+    fn set_low_power_mode(&mut self, enabled: bool) -> Result<(), Self::Error> {
+        if enabled {
+            self.set_mode(MODE_SLEEP)
+        } else {
+            self.set_mode(MODE_STDBY)
+        }
+    }
+
+    fn sleep(&mut self) -> Result<(), Self::Error> {
+        self.set_mode(MODE_SLEEP)
+    }
+
+    fn standby(&mut self) -> Result<(), Self::Error> {
+        self.set_mode(MODE_STDBY)
+    }
+
+    fn reset(&mut self) -> Result<(), Self::Error> {
+        self.init()?;
+        if self.frequency != 0 {
+            let frequency = self.frequency;
+            self.set_frequency(frequency)?;
+        }
+        Ok(())
+    }
+
+    fn get_time(&self) -> u32 {
+        // No time source on the radio
+        0
+    }
+
+    fn capabilities(&self) -> RadioCapabilities {
+        RadioCapabilities {
+            continuous_rx: true,
+            cad: true,
+            supports_fsk: false,
+            min_power_dbm: 2,
+            max_power_dbm: 20,
+            min_frequency: 860_000_000,
+            max_frequency: 1_020_000_000,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lorawan::region::DataRate;
+    use crate::radio::chip_mock::{assert_transactions, Chip, Cs, Delay, Pin, Spi};
+    use core::cell::{Cell, RefCell};
+
+    type TestRadio<'a> = SX1272<Spi<'a>, Cs<'a>, Pin, Pin, Pin, Delay<'a>>;
+
+    /// Radio with DIO0 at `dio0`, the log cleared after init
+    fn radio<'a>(chip: &'a RefCell<Chip>, elapsed: &'a Cell<u32>, dio0: bool) -> TestRadio<'a> {
+        let radio = SX1272::new(
+            Spi(chip),
+            Cs(chip),
+            Pin(false),
+            Pin(dio0),
+            Pin(false),
+            Delay(elapsed),
+        )
+        .unwrap();
+        chip.borrow_mut().log.clear();
+        elapsed.set(0);
+        radio
+    }
+
+    /// Radio in a 100 ms receive window with a 23-byte packet at FIFO address 0x40
+    fn radio_with_packet<'a>(chip: &'a RefCell<Chip>, elapsed: &'a Cell<u32>) -> TestRadio<'a> {
+        {
+            let mut chip = chip.borrow_mut();
+            for (i, byte) in chip.fifo[0x40..0x40 + 23].iter_mut().enumerate() {
+                *byte = i as u8 + 1;
+            }
+            chip.registers[REG_FIFO_RX_CURRENT_ADDR as usize] = 0x40;
+            chip.registers[REG_RX_NB_BYTES as usize] = 23;
+            chip.registers[REG_PKT_RSSI_VALUE as usize] = 100;
+            chip.registers[REG_PKT_SNR_VALUE as usize] = 0xEC; // -5 dB
+            chip.registers[REG_RSSI_VALUE as usize] = 40;
+        }
+
+        let mut radio = radio(chip, elapsed, true);
+        radio
+            .configure_rx(RxConfig {
+                frequency: 868_100_000,
+                timeout_ms: 100,
+                modulation: DataRate::SF7BW125.downlink_modulation(),
+            })
+            .unwrap();
+        chip.borrow_mut().log.clear();
+        radio
+    }
+
+    #[test]
+    fn test_init_register_sequence() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        SX1272::new(
+            Spi(&chip),
+            Cs(&chip),
+            Pin(false),
+            Pin(false),
+            Pin(false),
+            Delay(&elapsed),
+        )
+        .unwrap();
+
+        assert_transactions(
+            &chip,
+            &[
+                // LoRa mode, sleep
+                &[REG_OP_MODE | 0x80, 0x80],
+                &[REG_FIFO_TX_BASE_ADDR | 0x80, 0x00],
+                &[REG_FIFO_RX_BASE_ADDR | 0x80, 0x00],
+                &[REG_LNA | 0x80, 0x23],
+                &[REG_IRQ_FLAGS_MASK | 0x80, 0x32],
+                &[REG_IRQ_FLAGS | 0x80, 0xFF],
+                &[REG_DIO_MAPPING_1 | 0x80, DIO0_RX_DONE],
+            ],
+        );
+        // Reset pulse and ready time
+        assert_eq!(elapsed.get(), 6);
+    }
+
+    #[test]
+    fn test_modem_config_layout() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = radio(&chip, &elapsed, false);
+        let register = |reg: u8| chip.borrow().registers[reg as usize];
+
+        // 125 kHz, 4/5, explicit header, CRC on; SF7 with AGC
+        radio
+            .configure_tx(TxConfig {
+                frequency: 868_100_000,
+                power: 14,
+                modulation: DataRate::SF7BW125.modulation(),
+            })
+            .unwrap();
+        assert_eq!(register(REG_MODEM_CONFIG_1), 0x0A);
+        assert_eq!(register(REG_MODEM_CONFIG_2), 0x74);
+        assert_eq!(register(REG_INVERT_IQ), INVERT_IQ_OFF);
+        assert_eq!(register(REG_SYNC_WORD), 0x34);
+        assert_eq!(register(REG_PREAMBLE_LSB), 8);
+
+        // Low data rate optimization at SF12 125 kHz
+        radio
+            .configure_rx(RxConfig {
+                frequency: 869_525_000,
+                timeout_ms: 0,
+                modulation: DataRate::SF12BW125.downlink_modulation(),
+            })
+            .unwrap();
+        assert_eq!(register(REG_MODEM_CONFIG_1), 0x0B);
+        assert_eq!(register(REG_MODEM_CONFIG_2), 0xC4);
+        assert_eq!(register(REG_INVERT_IQ), INVERT_IQ_RX);
+        assert_eq!(register(REG_INVERT_IQ_2), INVERT_IQ_2_ON);
+
+        // 500 kHz
+        radio
+            .configure_rx(RxConfig {
+                frequency: 923_300_000,
+                timeout_ms: 0,
+                modulation: DataRate::SF8BW500.downlink_modulation(),
+            })
+            .unwrap();
+        assert_eq!(register(REG_MODEM_CONFIG_1), 0x8A);
+        assert_eq!(register(REG_MODEM_CONFIG_2), 0x84);
+
+        // Beacons: implicit header of fixed length, no CRC
+        radio
+            .configure_rx(RxConfig {
+                frequency: 869_525_000,
+                timeout_ms: 0,
+                modulation: DataRate::SF9BW125.beacon_modulation(17),
+            })
+            .unwrap();
+        assert_eq!(register(REG_MODEM_CONFIG_1), 0x0C);
+        assert_eq!(register(REG_PAYLOAD_LENGTH), 17);
+        assert_eq!(register(REG_PREAMBLE_LSB), 10);
+    }
+
+    #[test]
+    fn test_frequency_and_power() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = radio(&chip, &elapsed, false);
+
+        radio.set_frequency(868_100_000).unwrap();
+        assert_transactions(
+            &chip,
+            &[
+                &[REG_FRF_MSB | 0x80, 0xD9],
+                &[REG_FRF_MID | 0x80, 0x06],
+                &[REG_FRF_LSB | 0x80, 0x66],
+            ],
+        );
+        // The SX1272 has no low frequency port
+        assert!(matches!(
+            radio.set_frequency(433_175_000),
+            Err(SX127xError::InvalidFrequency)
+        ));
+
+        radio.set_tx_power(14).unwrap();
+        radio.set_tx_power(20).unwrap();
+        assert_transactions(
+            &chip,
+            &[
+                &[REG_PA_DAC | 0x80, 0x84],
+                &[REG_PA_CONFIG | 0x80, 0x8C],
+                &[REG_PA_DAC | 0x80, 0x87],
+                &[REG_PA_CONFIG | 0x80, 0x8F],
+            ],
+        );
+        assert!(matches!(
+            radio.set_tx_power(21),
+            Err(SX127xError::InvalidPower)
+        ));
+    }
+
+    #[test]
+    fn test_tx_byte_sequence() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = radio(&chip, &elapsed, true);
+
+        radio.transmit(&[0xAA, 0xBB, 0xCC]).unwrap();
+        assert_transactions(
+            &chip,
+            &[
+                &[REG_OP_MODE | 0x80, MODE_STDBY | 0x80],
+                &[REG_DIO_MAPPING_1 | 0x80, DIO0_TX_DONE],
+                &[REG_FIFO_ADDR_PTR | 0x80, 0x00],
+                &[0x80, 0xAA, 0xBB, 0xCC],
+                &[REG_PAYLOAD_LENGTH | 0x80, 3],
+                &[REG_OP_MODE | 0x80, MODE_TX | 0x80],
+                &[REG_IRQ_FLAGS | 0x80, IRQ_TX_DONE_MASK],
+                &[REG_OP_MODE | 0x80, MODE_STDBY | 0x80],
+            ],
+        );
+        assert_eq!(chip.borrow().fifo[..3], [0xAA, 0xBB, 0xCC]);
+    }
+
+    #[test]
+    fn test_tx_times_out_without_tx_done() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = radio(&chip, &elapsed, false);
+
+        let modulation = DataRate::SF7BW125.modulation();
+        radio
+            .configure_tx(TxConfig {
+                frequency: 868_100_000,
+                power: 14,
+                modulation,
+            })
+            .unwrap();
+        assert!(matches!(
+            radio.transmit(&[0u8; 20]),
+            Err(SX127xError::Timeout)
+        ));
+        assert_eq!(
+            elapsed.get(),
+            time_on_air(20, &modulation) + TX_TIMEOUT_MARGIN_MS
+        );
+    }
+
+    #[test]
+    fn test_rx_byte_sequence_and_signal_quality() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = radio_with_packet(&chip, &elapsed);
+
+        let mut buffer = [0u8; 64];
+        assert_eq!(radio.receive(&mut buffer).unwrap(), 23);
+        let expected: [u8; 23] = core::array::from_fn(|i| i as u8 + 1);
+        assert_eq!(buffer[..23], expected);
+
+        let mut fifo_read = [0u8; 24];
+        fifo_read[0] = REG_FIFO;
+        assert_transactions(
+            &chip,
+            &[
+                &[REG_DIO_MAPPING_1 | 0x80, DIO0_RX_DONE],
+                &[REG_OP_MODE | 0x80, MODE_RX | 0x80],
+                &[REG_RX_NB_BYTES, 0x00],
+                &[REG_FIFO_RX_CURRENT_ADDR, 0x00],
+                &[REG_FIFO_ADDR_PTR | 0x80, 0x40],
+                &fifo_read,
+                &[REG_PKT_SNR_VALUE, 0x00],
+                &[REG_PKT_RSSI_VALUE, 0x00],
+                &[REG_IRQ_FLAGS | 0x80, IRQ_RX_DONE_MASK | IRQ_RX_TIMEOUT_MASK],
+                &[REG_OP_MODE | 0x80, MODE_STDBY | 0x80],
+            ],
+        );
+
+        // Packet RSSI with the SX1272 offset, lowered by the negative SNR
+        assert_eq!(radio.get_rssi().unwrap(), -139 + 100 - 5);
+        assert_eq!(radio.get_snr().unwrap(), -5);
+
+        let mut small = [0u8; 16];
+        assert!(matches!(
+            radio.receive(&mut small),
+            Err(SX127xError::BufferTooSmall)
+        ));
+    }
+
+    #[test]
+    fn test_rx_window_times_out_without_rx_done() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = radio(&chip, &elapsed, false);
+
+        radio
+            .configure_rx(RxConfig {
+                frequency: 868_100_000,
+                timeout_ms: 50,
+                modulation: DataRate::SF7BW125.downlink_modulation(),
+            })
+            .unwrap();
+        let mut buffer = [0u8; 64];
+        assert_eq!(radio.receive(&mut buffer).unwrap(), 0);
+        assert_eq!(elapsed.get(), 50);
+        assert_eq!(
+            chip.borrow().registers[REG_OP_MODE as usize],
+            MODE_STDBY | 0x80
+        );
+
+        // Channel RSSI with the SX1272 offset
+        chip.borrow_mut().registers[REG_RSSI_VALUE as usize] = 40;
+        assert_eq!(radio.get_rssi().unwrap(), -139 + 40);
+    }
+
+    #[test]
+    fn test_reset_restores_frequency() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = radio(&chip, &elapsed, false);
+        radio.set_frequency(915_000_000).unwrap();
+        chip.borrow_mut().log.clear();
+
+        radio.reset().unwrap();
+        assert_eq!(radio.get_frequency(), 915_000_000);
+        // NRESET pulse and wake-up time
+        assert_eq!(elapsed.get(), 6);
+        let log = core::mem::take(&mut chip.borrow_mut().log);
+        assert_eq!(&log[0][..], &[REG_OP_MODE | 0x80, MODE_SLEEP | 0x80]);
+        assert_eq!(
+            &log[log.len() - 3..]
+                .iter()
+                .map(|t| t[0])
+                .collect::<heapless::Vec<u8, 3>>()[..],
+            &[REG_FRF_MSB | 0x80, REG_FRF_MID | 0x80, REG_FRF_LSB | 0x80]
+        );
+
+        radio.standby().unwrap();
+        assert_eq!(
+            chip.borrow().registers[REG_OP_MODE as usize],
+            MODE_STDBY | 0x80
+        );
+    }
+}
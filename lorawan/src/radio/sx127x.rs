@@ -1,29 +1,18 @@
+use embedded_hal::blocking::delay::DelayMs;
 use embedded_hal::blocking::spi::{Transfer, Write};
 use embedded_hal::digital::v2::{InputPin, OutputPin};
 
-use super::traits::{Radio, RxConfig, TxConfig};
-
-// Register addresses
-const REG_FIFO: u8 = 0x00;
-const REG_OP_MODE: u8 = 0x01;
-const REG_FRF_MSB: u8 = 0x06;
-const REG_FRF_MID: u8 = 0x07;
-const REG_FRF_LSB: u8 = 0x08;
-const REG_PA_CONFIG: u8 = 0x09;
-const REG_MODEM_CONFIG_1: u8 = 0x1D;
-const REG_MODEM_CONFIG_2: u8 = 0x1E;
-const REG_IRQ_FLAGS: u8 = 0x12;
-
-// Operating modes
-const MODE_SLEEP: u8 = 0x00;
-const MODE_STDBY: u8 = 0x01;
-const MODE_TX: u8 = 0x03;
-const MODE_RX: u8 = 0x05;
-
-// IRQ flags
-const IRQ_TX_DONE_MASK: u8 = 0x08;
-const IRQ_RX_DONE_MASK: u8 = 0x40;
-const IRQ_RX_TIMEOUT_MASK: u8 = 0x80;
+use super::fsk_lora_regs::*;
+use super::traits::{ModulationParams, Radio, RadioCapabilities, RxConfig, TxConfig};
+use crate::lorawan::phy::{symbol_time_us, time_on_air};
+
+// RSSI offsets of the high (>= 779 MHz) and low frequency ports
+const RSSI_OFFSET_HF: i16 = -157;
+const RSSI_OFFSET_LF: i16 = -164;
+const HF_PORT_MIN_FREQUENCY: u32 = 779_000_000;
+
+// ImplicitHeaderModeOn bit of RegModemConfig1
+const IMPLICIT_HEADER_MODE: u8 = 0x01;
 
 /// SPI error trait
 pub trait SpiError: core::fmt::Debug {}
@@ -33,6 +22,7 @@ impl<E: core::fmt::Debug> SpiError for E {}
 
 /// Radio errors
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SX127xError<E, CSE, RESETE> {
     /// SPI error
     Spi(E),
@@ -46,10 +36,45 @@ pub enum SX127xError<E, CSE, RESETE> {
     InvalidPower,
     /// Invalid configuration
     InvalidConfig,
+    /// Received packet larger than the receive buffer
+    BufferTooSmall,
+    /// TxDone was not signalled within the time-on-air of the packet
+    Timeout,
+}
+
+impl<E, CSE, RESETE> core::fmt::Display for SX127xError<E, CSE, RESETE> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SX127xError::Spi(_) => f.write_str("SPI error"),
+            SX127xError::Cs(_) => f.write_str("CS pin error"),
+            SX127xError::Reset(_) => f.write_str("reset pin error"),
+            SX127xError::InvalidFrequency => f.write_str("invalid frequency"),
+            SX127xError::InvalidPower => f.write_str("invalid TX power"),
+            SX127xError::InvalidConfig => f.write_str("invalid configuration"),
+            SX127xError::BufferTooSmall => f.write_str("received packet larger than the buffer"),
+            SX127xError::Timeout => f.write_str("transmission not done in time"),
+        }
+    }
+}
+
+impl<E, CSE, RESETE> core::error::Error for SX127xError<E, CSE, RESETE>
+where
+    E: core::error::Error + 'static,
+    CSE: core::error::Error + 'static,
+    RESETE: core::error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            SX127xError::Spi(error) => Some(error),
+            SX127xError::Cs(error) => Some(error),
+            SX127xError::Reset(error) => Some(error),
+            _ => None,
+        }
+    }
 }
 
 /// SX127x driver
-pub struct SX127x<SPI, CS, RESET, BUSY, DIO0, DIO1>
+pub struct SX127x<SPI, CS, RESET, BUSY, DIO0, DIO1, DELAY>
 where
     SPI: Transfer<u8> + Write<u8>,
     CS: OutputPin,
@@ -57,6 +82,7 @@ where
     BUSY: InputPin,
     DIO0: InputPin,
     DIO1: InputPin,
+    DELAY: DelayMs<u32>,
 {
     spi: SPI,
     cs: CS,
@@ -64,10 +90,20 @@ where
     busy: BUSY,
     dio0: DIO0,
     dio1: DIO1,
+    delay: DELAY,
     frequency: u32,
+    /// Modulation of the last transmit configuration, bounds the TX wait
+    tx_modulation: Option<ModulationParams>,
+    /// RX timeout of the last receive configuration, 0 for continuous reception
+    rx_timeout_ms: u32,
+    /// RSSI of the last received packet, until the next reception starts
+    packet_rssi: Option<i16>,
+    /// SNR of the last received packet, until the next reception starts
+    packet_snr: Option<i8>,
 }
 
-impl<SPI, CS, RESET, BUSY, DIO0, DIO1, E, CSE, RESETE> SX127x<SPI, CS, RESET, BUSY, DIO0, DIO1>
+impl<SPI, CS, RESET, BUSY, DIO0, DIO1, DELAY, E, CSE, RESETE>
+    SX127x<SPI, CS, RESET, BUSY, DIO0, DIO1, DELAY>
 where
     SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
     CS: OutputPin<Error = CSE>,
@@ -75,11 +111,15 @@ where
     BUSY: InputPin,
     DIO0: InputPin,
     DIO1: InputPin,
+    DELAY: DelayMs<u32>,
     E: core::fmt::Debug,
     CSE: core::fmt::Debug,
     RESETE: core::fmt::Debug,
 {
     /// Create new instance
+    ///
+    /// `delay` paces the polling of DIO0 and DIO1 while waiting for the end
+    /// of a transmission or reception.
     pub fn new(
         spi: SPI,
         cs: CS,
@@ -87,6 +127,7 @@ where
         busy: BUSY,
         dio0: DIO0,
         dio1: DIO1,
+        delay: DELAY,
     ) -> Result<Self, SX127xError<E, CSE, RESETE>> {
         let mut sx127x = Self {
             spi,
@@ -95,7 +136,12 @@ where
             busy,
             dio0,
             dio1,
+            delay,
             frequency: 0,
+            tx_modulation: None,
+            rx_timeout_ms: 0,
+            packet_rssi: None,
+            packet_snr: None,
         };
 
         // Initialize the radio
@@ -114,8 +160,8 @@ where
         // Set CS low to start transaction
         self.cs.set_low().map_err(SX127xError::Cs)?;
 
-        // Send address and read command
-        let mut read_cmd = [addr | 0x80];
+        // Send address with the write bit clear
+        let mut read_cmd = [addr & !SPI_WRITE];
         self.spi.transfer(&mut read_cmd).map_err(SX127xError::Spi)?;
 
         // Read data
@@ -134,15 +180,50 @@ where
     /// Write register
     fn write_register(&mut self, addr: u8, value: u8) -> Result<(), SX127xError<E, CSE, RESETE>> {
         self.cs.set_low().map_err(|e| SX127xError::Cs(e))?;
-        let buffer = [addr | 0x80, value];
+        let buffer = [addr | SPI_WRITE, value];
         self.spi.write(&buffer).map_err(|e| SX127xError::Spi(e))?;
         self.cs.set_high().map_err(|e| SX127xError::Cs(e))?;
         Ok(())
     }
 
+    /// Pulse NRESET low
+    fn hard_reset(&mut self) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        self.reset.set_low().map_err(SX127xError::Reset)?;
+        // Wait for reset
+        for _ in 0..100 {
+            if self.busy.is_low().unwrap_or(false) {
+                break;
+            }
+        }
+        self.reset.set_high().map_err(SX127xError::Reset)
+    }
+
     /// Set operating mode
     fn set_mode(&mut self, mode: u8) -> Result<(), SX127xError<E, CSE, RESETE>> {
-        self.write_register(REG_OP_MODE, mode | 0x80)
+        self.write_register(REG_OP_MODE, mode | LONG_RANGE_MODE)
+    }
+
+    /// Poll DIO0 every millisecond until it is set or `timeout_ms` elapsed
+    ///
+    /// A set DIO1 (RxTimeout) ends the wait early.
+    fn wait_dio0(&mut self, timeout_ms: u32) -> bool {
+        let mut elapsed_ms = 0;
+        loop {
+            if self.dio0.is_high().unwrap_or(false) {
+                return true;
+            }
+            if elapsed_ms >= timeout_ms || self.dio1.is_high().unwrap_or(false) {
+                return false;
+            }
+            self.delay.delay_ms(1);
+            elapsed_ms += 1;
+        }
+    }
+
+    /// Map DIO0 to RxDone and enter RX mode
+    fn start_rx(&mut self) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        self.write_register(REG_DIO_MAPPING_1, DIO0_RX_DONE)?;
+        self.set_mode(MODE_RX)
     }
 
     /// Read from FIFO
@@ -151,7 +232,7 @@ where
         self.cs.set_low().map_err(SX127xError::Cs)?;
 
         // First byte is the FIFO read command
-        let mut read_cmd = [0x00];
+        let mut read_cmd = [REG_FIFO & !SPI_WRITE];
         self.spi.transfer(&mut read_cmd).map_err(SX127xError::Spi)?;
 
         // Read the actual data
@@ -167,17 +248,122 @@ where
 
     /// Write to FIFO
     fn write_fifo(&mut self, data: &[u8]) -> Result<(), SX127xError<E, CSE, RESETE>> {
-        let spi_buffer = [REG_FIFO & 0x7F];
+        // First byte is the FIFO write command
+        let spi_buffer = [REG_FIFO | SPI_WRITE];
         self.cs.set_low().map_err(SX127xError::Cs)?;
         self.spi.write(&spi_buffer).map_err(SX127xError::Spi)?;
         self.spi.write(data).map_err(SX127xError::Spi)?;
         self.cs.set_high().map_err(SX127xError::Cs)?;
         Ok(())
     }
+
+    /// Read a single register
+    fn read_u8(&mut self, addr: u8) -> Result<u8, SX127xError<E, CSE, RESETE>> {
+        let mut buffer = [0u8];
+        self.read_register(addr, &mut buffer, 1)?;
+        Ok(buffer[0])
+    }
+
+    /// RSSI offset of the port the current frequency is on
+    fn rssi_offset(&self) -> i16 {
+        if self.frequency >= HF_PORT_MIN_FREQUENCY {
+            RSSI_OFFSET_HF
+        } else {
+            RSSI_OFFSET_LF
+        }
+    }
+
+    /// Read the received packet from the FIFO, with its RSSI and SNR
+    fn read_packet(&mut self, buffer: &mut [u8]) -> Result<usize, SX127xError<E, CSE, RESETE>> {
+        let len = self.read_u8(REG_RX_NB_BYTES)? as usize;
+        if len > buffer.len() {
+            return Err(SX127xError::BufferTooSmall);
+        }
+
+        // The packet starts at the FIFO address of the last reception
+        let start = self.read_u8(REG_FIFO_RX_CURRENT_ADDR)?;
+        self.write_register(REG_FIFO_ADDR_PTR, start)?;
+        self.read_fifo(&mut buffer[..len])?;
+
+        // SNR is in quarter dB
+        let snr = self.read_u8(REG_PKT_SNR_VALUE)? as i8 / 4;
+        let rssi = self.rssi_offset() + self.read_u8(REG_PKT_RSSI_VALUE)? as i16;
+        self.packet_snr = Some(snr);
+        self.packet_rssi = Some(rssi);
+        Ok(len)
+    }
+
+    /// Set preamble length in symbols
+    pub fn set_preamble_length(&mut self, length: u16) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        self.write_register(REG_PREAMBLE_MSB, (length >> 8) as u8)?;
+        self.write_register(REG_PREAMBLE_LSB, length as u8)
+    }
+
+    /// Invert the I and Q signals in reception, as LoRaWAN downlinks require
+    pub fn set_iq_inverted(&mut self, inverted: bool) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        if inverted {
+            self.write_register(REG_INVERT_IQ, INVERT_IQ_RX)?;
+            self.write_register(REG_INVERT_IQ_2, INVERT_IQ_2_ON)
+        } else {
+            self.write_register(REG_INVERT_IQ, INVERT_IQ_OFF)?;
+            self.write_register(REG_INVERT_IQ_2, INVERT_IQ_2_OFF)
+        }
+    }
+
+    /// Set the sync word, 0x34 on public LoRaWAN networks
+    pub fn set_sync_word(&mut self, sync_word: u8) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        self.write_register(REG_SYNC_WORD, sync_word)
+    }
+
+    /// Select implicit header mode with a fixed payload length, or explicit header mode
+    pub fn set_implicit_header(
+        &mut self,
+        length: Option<u8>,
+    ) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        let modem_config1 = self.read_u8(REG_MODEM_CONFIG_1)? & !IMPLICIT_HEADER_MODE;
+        match length {
+            Some(length) => {
+                self.write_register(REG_MODEM_CONFIG_1, modem_config1 | IMPLICIT_HEADER_MODE)?;
+                self.write_register(REG_PAYLOAD_LENGTH, length)
+            }
+            None => self.write_register(REG_MODEM_CONFIG_1, modem_config1),
+        }
+    }
+
+    /// Configure all modulation parameters
+    fn set_modulation(
+        &mut self,
+        modulation: &ModulationParams,
+    ) -> Result<(), SX127xError<E, CSE, RESETE>> {
+        let sf = modulation.spreading_factor.clamp(6, 12);
+        let bw = match modulation.bandwidth {
+            b if b <= 7800 => 0,
+            b if b <= 10400 => 1,
+            b if b <= 15600 => 2,
+            b if b <= 20800 => 3,
+            b if b <= 31250 => 4,
+            b if b <= 41700 => 5,
+            b if b <= 62500 => 6,
+            b if b <= 125000 => 7,
+            b if b <= 250000 => 8,
+            _ => 9,
+        };
+        let cr = modulation.coding_rate.clamp(5, 8) - 4;
+
+        let modem_config1 = (bw << 4) | (cr << 1);
+        let modem_config2 = (sf << 4) | ((modulation.crc as u8) << 2);
+
+        self.write_register(REG_MODEM_CONFIG_1, modem_config1)?;
+        self.write_register(REG_MODEM_CONFIG_2, modem_config2)?;
+        self.set_implicit_header(modulation.implicit_header)?;
+        self.set_preamble_length(modulation.preamble_length)?;
+        self.set_iq_inverted(modulation.iq_inverted)?;
+        self.set_sync_word(modulation.sync_word)
+    }
 }
 
-impl<SPI, CS, RESET, BUSY, DIO0, DIO1, E, CSE, RESETE> Radio
-    for SX127x<SPI, CS, RESET, BUSY, DIO0, DIO1>
+impl<SPI, CS, RESET, BUSY, DIO0, DIO1, DELAY, E, CSE, RESETE> Radio
+    for SX127x<SPI, CS, RESET, BUSY, DIO0, DIO1, DELAY>
 where
     SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
     CS: OutputPin<Error = CSE>,
@@ -185,6 +371,7 @@ where
     BUSY: InputPin,
     DIO0: InputPin,
     DIO1: InputPin,
+    DELAY: DelayMs<u32>,
     E: core::fmt::Debug,
     CSE: core::fmt::Debug,
     RESETE: core::fmt::Debug,
@@ -192,19 +379,20 @@ where
     type Error = SX127xError<E, CSE, RESETE>;
 
     fn init(&mut self) -> Result<(), Self::Error> {
-        // Reset radio
-        self.reset.set_low().map_err(SX127xError::Reset)?;
-        // Wait for reset
-        for _ in 0..100 {
-            if self.busy.is_low().unwrap_or(false) {
-                break;
-            }
-        }
-        self.reset.set_high().map_err(SX127xError::Reset)?;
+        self.hard_reset()?;
 
-        // Set sleep mode
+        // LoRa mode can only be selected in sleep mode
         self.set_mode(MODE_SLEEP)?;
 
+        self.write_register(REG_FIFO_TX_BASE_ADDR, FIFO_BASE_ADDR)?;
+        self.write_register(REG_FIFO_RX_BASE_ADDR, FIFO_BASE_ADDR)?;
+        self.write_register(REG_LNA, LNA_MAX_GAIN | LNA_BOOST_HF)?;
+
+        // Only TxDone, RxDone, RxTimeout and the CAD IRQs are used
+        self.write_register(REG_IRQ_FLAGS_MASK, !USED_IRQS)?;
+        self.write_register(REG_IRQ_FLAGS, IRQ_ALL_MASK)?;
+        self.write_register(REG_DIO_MAPPING_1, DIO0_RX_DONE)?;
+
         Ok(())
     }
 
@@ -226,6 +414,10 @@ where
         Ok(())
     }
 
+    fn get_frequency(&self) -> u32 {
+        self.frequency
+    }
+
     fn set_tx_power(&mut self, power: i8) -> Result<(), Self::Error> {
         if power < 2 || power > 20 {
             return Err(SX127xError::InvalidPower);
@@ -237,27 +429,8 @@ where
         self.set_frequency(config.frequency)?;
         self.set_tx_power(config.power)?;
 
-        // Configure modulation parameters
-        let sf = config.modulation.spreading_factor.clamp(6, 12);
-        let bw = match config.modulation.bandwidth {
-            b if b <= 7800 => 0,
-            b if b <= 10400 => 1,
-            b if b <= 15600 => 2,
-            b if b <= 20800 => 3,
-            b if b <= 31250 => 4,
-            b if b <= 41700 => 5,
-            b if b <= 62500 => 6,
-            b if b <= 125000 => 7,
-            b if b <= 250000 => 8,
-            _ => 9,
-        };
-        let cr = config.modulation.coding_rate.clamp(5, 8) - 4;
-
-        let modem_config1 = (bw << 4) | (cr << 1) | 0x00; // Explicit header mode
-        let modem_config2 = (sf << 4) | 0x04; // CRC on
-
-        self.write_register(REG_MODEM_CONFIG_1, modem_config1)?;
-        self.write_register(REG_MODEM_CONFIG_2, modem_config2)?;
+        self.set_modulation(&config.modulation)?;
+        self.tx_modulation = Some(config.modulation);
 
         Ok(())
     }
@@ -265,43 +438,38 @@ where
     fn configure_rx(&mut self, config: RxConfig) -> Result<(), Self::Error> {
         self.set_frequency(config.frequency)?;
 
-        // Configure modulation parameters
-        let sf = config.modulation.spreading_factor.clamp(6, 12);
-        let bw = match config.modulation.bandwidth {
-            b if b <= 7800 => 0,
-            b if b <= 10400 => 1,
-            b if b <= 15600 => 2,
-            b if b <= 20800 => 3,
-            b if b <= 31250 => 4,
-            b if b <= 41700 => 5,
-            b if b <= 62500 => 6,
-            b if b <= 125000 => 7,
-            b if b <= 250000 => 8,
-            _ => 9,
-        };
-        let cr = config.modulation.coding_rate.clamp(5, 8) - 4;
-
-        let modem_config1 = (bw << 4) | (cr << 1) | 0x00;
-        let modem_config2 = (sf << 4) | 0x04;
+        self.set_modulation(&config.modulation)?;
 
-        self.write_register(REG_MODEM_CONFIG_1, modem_config1)?;
-        self.write_register(REG_MODEM_CONFIG_2, modem_config2)?;
+        // Signal quality reads the channel again until a packet is received
+        self.packet_rssi = None;
+        self.packet_snr = None;
 
-        // Set RX mode
-        self.set_mode(MODE_RX)?;
+        self.rx_timeout_ms = config.timeout_ms;
+        self.start_rx()?;
 
         Ok(())
     }
 
     fn transmit(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        // The FIFO is only accessible in standby
+        self.set_mode(MODE_STDBY)?;
+        self.write_register(REG_DIO_MAPPING_1, DIO0_TX_DONE)?;
+
         // Write data to FIFO
+        self.write_register(REG_FIFO_ADDR_PTR, FIFO_BASE_ADDR)?;
         self.write_fifo(data)?;
+        self.write_register(REG_PAYLOAD_LENGTH, data.len() as u8)?;
 
         // Set TX mode
         self.set_mode(MODE_TX)?;
 
-        // Wait for TX done using DIO0
-        while !self.dio0.is_high().unwrap_or(false) {}
+        // Wait for TX done using DIO0, at most the time-on-air of the packet
+        let timeout_ms = self
+            .tx_modulation
+            .map_or(DEFAULT_TX_TIMEOUT_MS, |modulation| {
+                time_on_air(data.len(), &modulation) + TX_TIMEOUT_MARGIN_MS
+            });
+        let done = self.wait_dio0(timeout_ms);
 
         // Clear IRQ flags
         self.write_register(REG_IRQ_FLAGS, IRQ_TX_DONE_MASK)?;
@@ -309,47 +477,95 @@ where
         // Back to standby
         self.set_mode(MODE_STDBY)?;
 
-        Ok(())
+        if done {
+            Ok(())
+        } else {
+            Err(SX127xError::Timeout)
+        }
     }
 
+    /// Receive a packet
+    ///
+    /// With an RX timeout, reception is restarted and waits up to the timeout
+    /// for a packet. In continuous reception (timeout 0) the packet received
+    /// since the last call is read, if any, without waiting. Returns 0 if no
+    /// packet was received.
     fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
-        // Set RX mode
-        self.set_mode(MODE_RX)?;
+        let continuous = self.rx_timeout_ms == 0;
+        let received = if continuous {
+            self.dio0.is_high().unwrap_or(false)
+        } else {
+            self.start_rx()?;
+            let timeout_ms = self.rx_timeout_ms;
+            self.wait_dio0(timeout_ms)
+        };
 
-        // Wait for RX done or timeout using DIO0 and DIO1
-        loop {
-            if self.dio0.is_high().unwrap_or(false) {
-                // RX done
-                break;
-            }
-            if self.dio1.is_high().unwrap_or(false) {
-                // RX timeout
-                return Ok(0);
-            }
+        if continuous && !received {
+            return Ok(0);
         }
 
-        // Read data from FIFO
-        self.read_fifo(buffer)?;
+        // Read the packet from FIFO
+        let result = if received {
+            self.read_packet(buffer)
+        } else {
+            Ok(0)
+        };
 
         // Clear IRQ flags
         self.write_register(REG_IRQ_FLAGS, IRQ_RX_DONE_MASK | IRQ_RX_TIMEOUT_MASK)?;
 
-        // Back to standby
-        self.set_mode(MODE_STDBY)?;
+        // Continuous reception goes on, a window ends in standby
+        if !continuous {
+            self.set_mode(MODE_STDBY)?;
+        }
 
-        Ok(buffer.len())
+        result
     }
 
     fn get_rssi(&mut self) -> Result<i16, Self::Error> {
-        let mut buffer = [0u8];
-        self.read_register(0x1B, &mut buffer, 1)?;
-        Ok(-157 + buffer[0] as i16)
+        match self.packet_rssi {
+            Some(rssi) => Ok(rssi),
+            None => Ok(self.rssi_offset() + self.read_u8(REG_RSSI_VALUE)? as i16),
+        }
     }
 
     fn get_snr(&mut self) -> Result<i8, Self::Error> {
-        let mut buffer = [0u8];
-        self.read_register(0x19, &mut buffer, 1)?;
-        Ok((buffer[0] as i8) / 4)
+        match self.packet_snr {
+            Some(snr) => Ok(snr),
+            None => Ok(self.read_u8(REG_PKT_SNR_VALUE)? as i8 / 4),
+        }
+    }
+
+    fn cad(&mut self, params: &ModulationParams) -> Result<bool, Self::Error> {
+        self.set_mode(MODE_STDBY)?;
+        self.set_modulation(params)?;
+        self.write_register(REG_IRQ_FLAGS, IRQ_ALL_MASK)?;
+        self.write_register(REG_DIO_MAPPING_1, DIO0_CAD_DONE)?;
+        self.set_mode(MODE_CAD)?;
+
+        // CAD listens for about two symbols, then returns to standby
+        let done = self.wait_dio0(3 * symbol_time_us(params) / 1_000 + 1);
+        let flags = self.read_u8(REG_IRQ_FLAGS)?;
+        self.write_register(REG_IRQ_FLAGS, IRQ_CAD_DONE_MASK | IRQ_CAD_DETECTED_MASK)?;
+        self.write_register(REG_DIO_MAPPING_1, DIO0_RX_DONE)?;
+
+        if !done {
+            self.set_mode(MODE_STDBY)?;
+            return Err(SX127xError::Timeout);
+        }
+        Ok(flags & IRQ_CAD_DETECTED_MASK != 0)
+    }
+
+    fn random_u32(&mut self) -> Result<u32, Self::Error> {
+        // The LSB of the wideband RSSI is noise while receiving
+        self.set_mode(MODE_RX)?;
+        let mut value = 0;
+        for _ in 0..32 {
+            self.delay.delay_ms(1);
+            value = (value << 1) | u32::from(self.read_u8(REG_RSSI_WIDEBAND)? & 0x01);
+        }
+        self.set_mode(MODE_STDBY)?;
+        Ok(value)
     }
 
     fn is_transmitting(&mut self) -> Result<bool, Self::Error> {
@@ -369,7 +585,7 @@ where
             5 => 0xC0, // Max gain - 48dB
             _ => 0x20, // Default to max gain
         };
-        self.write_register(0x0C, lna_gain)
+        self.write_register(REG_LNA, lna_gain | LNA_BOOST_HF)
     }
 
     fn set_low_power_mode(&mut self, enabled: bool) -> Result<(), Self::Error> {
@@ -384,15 +600,16 @@ where
         self.set_mode(MODE_SLEEP)
     }
 
+    fn standby(&mut self) -> Result<(), Self::Error> {
+        self.set_mode(MODE_STDBY)
+    }
+
     fn reset(&mut self) -> Result<(), Self::Error> {
-        self.reset.set_low().map_err(SX127xError::Reset)?;
-        // Wait for reset
-        for _ in 0..100 {
-            if self.busy.is_low().unwrap_or(false) {
-                break;
-            }
+        self.init()?;
+        if self.frequency != 0 {
+            let frequency = self.frequency;
+            self.set_frequency(frequency)?;
         }
-        self.reset.set_high().map_err(SX127xError::Reset)?;
         Ok(())
     }
 
@@ -400,4 +617,492 @@ where
         // Simple counter implementation - you may want to replace this with a real time source
         0
     }
+
+    fn capabilities(&self) -> RadioCapabilities {
+        // PA_BOOST output, the driver runs the LoRa modem only
+        RadioCapabilities {
+            continuous_rx: true,
+            cad: true,
+            supports_fsk: false,
+            min_power_dbm: 2,
+            max_power_dbm: 20,
+            min_frequency: 137_000_000,
+            max_frequency: 1_020_000_000,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lorawan::region::DataRate;
+    use crate::radio::chip_mock::{assert_transactions, Chip, Cs, Delay, Pin, Spi};
+    use core::cell::{Cell, RefCell};
+
+    type TestRadio<'a> = SX127x<Spi<'a>, Cs<'a>, Pin, Pin, Pin, Pin, Delay<'a>>;
+
+    /// Radio with a 23-byte packet received at FIFO address 0x40
+    fn radio_with_packet<'a>(chip: &'a RefCell<Chip>, elapsed: &'a Cell<u32>) -> TestRadio<'a> {
+        {
+            let mut chip = chip.borrow_mut();
+            for (i, byte) in chip.fifo[0x40..0x40 + 23].iter_mut().enumerate() {
+                *byte = i as u8 + 1;
+            }
+            chip.registers[REG_FIFO_RX_CURRENT_ADDR as usize] = 0x40;
+            chip.registers[REG_RX_NB_BYTES as usize] = 23;
+            chip.registers[REG_PKT_RSSI_VALUE as usize] = 100;
+            chip.registers[REG_PKT_SNR_VALUE as usize] = 0xEC; // -5 dB
+            chip.registers[REG_RSSI_VALUE as usize] = 40;
+        }
+
+        let mut radio = SX127x::new(
+            Spi(chip),
+            Cs(chip),
+            Pin(true),
+            Pin(false),
+            Pin(true), // RX done
+            Pin(false),
+            Delay(elapsed),
+        )
+        .unwrap();
+        radio
+            .configure_rx(RxConfig {
+                frequency: 868_100_000,
+                timeout_ms: 100,
+                modulation: DataRate::SF7BW125.modulation(),
+            })
+            .unwrap();
+        radio
+    }
+
+    #[test]
+    fn test_receive_packet_length() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = radio_with_packet(&chip, &elapsed);
+
+        // Instantaneous channel RSSI before the packet is read
+        assert_eq!(radio.get_rssi().unwrap(), -157 + 40);
+
+        let mut buffer = [0u8; 255];
+        assert_eq!(radio.receive(&mut buffer).unwrap(), 23);
+        let expected: [u8; 23] = core::array::from_fn(|i| i as u8 + 1);
+        assert_eq!(buffer[..23], expected);
+        assert!(buffer[23..].iter().all(|&b| b == 0));
+
+        // Signal quality of the packet, not of the channel
+        chip.borrow_mut().registers[REG_RSSI_VALUE as usize] = 10;
+        assert_eq!(radio.get_rssi().unwrap(), -157 + 100);
+        assert_eq!(radio.get_snr().unwrap(), -5);
+    }
+
+    #[test]
+    fn test_receive_packet_larger_than_buffer() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = radio_with_packet(&chip, &elapsed);
+
+        let mut buffer = [0u8; 16];
+        assert!(matches!(
+            radio.receive(&mut buffer),
+            Err(SX127xError::BufferTooSmall)
+        ));
+        // The radio is back in standby
+        assert_eq!(
+            chip.borrow().registers[REG_OP_MODE as usize],
+            MODE_STDBY | 0x80
+        );
+    }
+
+    #[test]
+    fn test_spi_byte_sequences() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = radio_with_packet(&chip, &elapsed);
+        chip.borrow_mut().log.clear();
+
+        // Register read: address with the MSB clear, then a dummy byte
+        radio.get_rssi().unwrap();
+        assert_transactions(&chip, &[&[REG_RSSI_VALUE, 0x00]]);
+
+        // Register write: address with the MSB set, then the value
+        radio.set_tx_power(14).unwrap();
+        assert_transactions(&chip, &[&[REG_PA_CONFIG | 0x80, 0x8C]]);
+        assert_eq!(chip.borrow().registers[REG_PA_CONFIG as usize], 0x8C);
+
+        // FIFO write before TX
+        radio.transmit(&[0xAA, 0xBB, 0xCC]).unwrap();
+        assert_transactions(
+            &chip,
+            &[
+                &[REG_OP_MODE | 0x80, MODE_STDBY | 0x80],
+                &[REG_DIO_MAPPING_1 | 0x80, DIO0_TX_DONE],
+                &[REG_FIFO_ADDR_PTR | 0x80, 0x00],
+                &[0x80, 0xAA, 0xBB, 0xCC],
+                &[REG_PAYLOAD_LENGTH | 0x80, 3],
+                &[REG_OP_MODE | 0x80, MODE_TX | 0x80],
+                &[REG_IRQ_FLAGS | 0x80, IRQ_TX_DONE_MASK],
+                &[REG_OP_MODE | 0x80, MODE_STDBY | 0x80],
+            ],
+        );
+
+        // FIFO read after RX
+        let mut buffer = [0u8; 64];
+        assert_eq!(radio.receive(&mut buffer).unwrap(), 23);
+        let mut fifo_read = [0u8; 24];
+        fifo_read[0] = REG_FIFO;
+        assert_transactions(
+            &chip,
+            &[
+                &[REG_DIO_MAPPING_1 | 0x80, DIO0_RX_DONE],
+                &[REG_OP_MODE | 0x80, MODE_RX | 0x80],
+                &[REG_RX_NB_BYTES, 0x00],
+                &[REG_FIFO_RX_CURRENT_ADDR, 0x00],
+                &[REG_FIFO_ADDR_PTR | 0x80, 0x40],
+                &fifo_read,
+                &[REG_PKT_SNR_VALUE, 0x00],
+                &[REG_PKT_RSSI_VALUE, 0x00],
+                &[REG_IRQ_FLAGS | 0x80, IRQ_RX_DONE_MASK | IRQ_RX_TIMEOUT_MASK],
+                &[REG_OP_MODE | 0x80, MODE_STDBY | 0x80],
+            ],
+        );
+    }
+
+    #[test]
+    fn test_init_register_sequence() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        SX127x::new(
+            Spi(&chip),
+            Cs(&chip),
+            Pin(true),
+            Pin(false),
+            Pin(false),
+            Pin(false),
+            Delay(&elapsed),
+        )
+        .unwrap();
+
+        assert_transactions(
+            &chip,
+            &[
+                // LoRa mode, HF band, sleep
+                &[REG_OP_MODE | 0x80, 0x80],
+                &[REG_FIFO_TX_BASE_ADDR | 0x80, 0x00],
+                &[REG_FIFO_RX_BASE_ADDR | 0x80, 0x00],
+                &[REG_LNA | 0x80, 0x23],
+                &[REG_IRQ_FLAGS_MASK | 0x80, 0x32],
+                &[REG_IRQ_FLAGS | 0x80, 0xFF],
+                &[REG_DIO_MAPPING_1 | 0x80, DIO0_RX_DONE],
+            ],
+        );
+    }
+
+    #[test]
+    fn test_dio0_remapped_around_tx_and_rx() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = radio_with_packet(&chip, &elapsed);
+        let dio_mapping = || chip.borrow().registers[REG_DIO_MAPPING_1 as usize];
+
+        radio.transmit(&[0x01]).unwrap();
+        assert_eq!(dio_mapping(), DIO0_TX_DONE);
+        assert_eq!(chip.borrow().registers[REG_PAYLOAD_LENGTH as usize], 1);
+
+        let mut buffer = [0u8; 64];
+        radio.receive(&mut buffer).unwrap();
+        assert_eq!(dio_mapping(), DIO0_RX_DONE);
+
+        radio.transmit(&[0x01]).unwrap();
+        assert_eq!(dio_mapping(), DIO0_TX_DONE);
+        radio
+            .configure_rx(RxConfig {
+                frequency: 868_100_000,
+                timeout_ms: 0,
+                modulation: DataRate::SF7BW125.modulation(),
+            })
+            .unwrap();
+        assert_eq!(dio_mapping(), DIO0_RX_DONE);
+    }
+    /// Radio whose DIO0 never asserts
+    fn silent_radio<'a>(chip: &'a RefCell<Chip>, elapsed: &'a Cell<u32>) -> TestRadio<'a> {
+        SX127x::new(
+            Spi(chip),
+            Cs(chip),
+            Pin(true),
+            Pin(false),
+            Pin(false),
+            Pin(false),
+            Delay(elapsed),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_tx_times_out_without_tx_done() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = silent_radio(&chip, &elapsed);
+
+        let modulation = DataRate::SF7BW125.modulation();
+        radio
+            .configure_tx(TxConfig {
+                frequency: 868_100_000,
+                power: 14,
+                modulation,
+            })
+            .unwrap();
+        assert!(matches!(
+            radio.transmit(&[0u8; 20]),
+            Err(SX127xError::Timeout)
+        ));
+
+        // The wait is bounded by the time-on-air of the packet
+        assert_eq!(
+            elapsed.get(),
+            time_on_air(20, &modulation) + TX_TIMEOUT_MARGIN_MS
+        );
+        assert_eq!(
+            chip.borrow().registers[REG_OP_MODE as usize],
+            MODE_STDBY | 0x80
+        );
+    }
+
+    #[test]
+    fn test_rx_window_times_out_without_rx_done() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = silent_radio(&chip, &elapsed);
+
+        let mut config = RxConfig {
+            frequency: 868_100_000,
+            timeout_ms: 50,
+            modulation: DataRate::SF7BW125.modulation(),
+        };
+        radio.configure_rx(config).unwrap();
+        let mut buffer = [0u8; 64];
+        assert_eq!(radio.receive(&mut buffer).unwrap(), 0);
+        assert_eq!(elapsed.get(), 50);
+        assert_eq!(
+            chip.borrow().registers[REG_OP_MODE as usize],
+            MODE_STDBY | 0x80
+        );
+
+        // Continuous reception returns at once and stays in RX
+        config.timeout_ms = 0;
+        radio.configure_rx(config).unwrap();
+        assert_eq!(radio.receive(&mut buffer).unwrap(), 0);
+        assert_eq!(elapsed.get(), 50);
+        assert_eq!(
+            chip.borrow().registers[REG_OP_MODE as usize],
+            MODE_RX | 0x80
+        );
+    }
+
+    #[test]
+    fn test_iq_inversion_sequence() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = silent_radio(&chip, &elapsed);
+        chip.borrow_mut().log.clear();
+
+        radio.set_iq_inverted(true).unwrap();
+        assert_transactions(
+            &chip,
+            &[
+                &[REG_INVERT_IQ | 0x80, 0x66],
+                &[REG_INVERT_IQ_2 | 0x80, 0x19],
+            ],
+        );
+
+        radio.set_iq_inverted(false).unwrap();
+        assert_transactions(
+            &chip,
+            &[
+                &[REG_INVERT_IQ | 0x80, 0x27],
+                &[REG_INVERT_IQ_2 | 0x80, 0x1D],
+            ],
+        );
+    }
+
+    #[test]
+    fn test_sync_word_and_preamble_sequence() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = silent_radio(&chip, &elapsed);
+        chip.borrow_mut().log.clear();
+
+        radio.set_sync_word(0x34).unwrap();
+        assert_transactions(&chip, &[&[REG_SYNC_WORD | 0x80, 0x34]]);
+
+        radio.set_preamble_length(0x0102).unwrap();
+        assert_transactions(
+            &chip,
+            &[
+                &[REG_PREAMBLE_MSB | 0x80, 0x01],
+                &[REG_PREAMBLE_LSB | 0x80, 0x02],
+            ],
+        );
+    }
+
+    #[test]
+    fn test_implicit_header_sequence() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = silent_radio(&chip, &elapsed);
+        chip.borrow_mut().registers[REG_MODEM_CONFIG_1 as usize] = 0x72;
+        chip.borrow_mut().log.clear();
+
+        // Only the header mode bit of RegModemConfig1 changes
+        radio.set_implicit_header(Some(17)).unwrap();
+        assert_transactions(
+            &chip,
+            &[
+                &[REG_MODEM_CONFIG_1, 0x00],
+                &[REG_MODEM_CONFIG_1 | 0x80, 0x73],
+                &[REG_PAYLOAD_LENGTH | 0x80, 17],
+            ],
+        );
+
+        radio.set_implicit_header(None).unwrap();
+        assert_transactions(
+            &chip,
+            &[
+                &[REG_MODEM_CONFIG_1, 0x00],
+                &[REG_MODEM_CONFIG_1 | 0x80, 0x72],
+            ],
+        );
+    }
+
+    #[test]
+    fn test_rx_windows_invert_iq() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = silent_radio(&chip, &elapsed);
+        let register = |reg: u8| chip.borrow().registers[reg as usize];
+
+        radio
+            .configure_tx(TxConfig {
+                frequency: 868_100_000,
+                power: 14,
+                modulation: DataRate::SF7BW125.modulation(),
+            })
+            .unwrap();
+        assert_eq!(register(REG_INVERT_IQ), INVERT_IQ_OFF);
+        assert_eq!(register(REG_INVERT_IQ_2), INVERT_IQ_2_OFF);
+        assert_eq!(register(REG_SYNC_WORD), 0x34);
+        assert_eq!(register(REG_MODEM_CONFIG_1) & IMPLICIT_HEADER_MODE, 0);
+
+        radio
+            .configure_rx(RxConfig {
+                frequency: 869_525_000,
+                timeout_ms: 0,
+                modulation: DataRate::SF9BW125.downlink_modulation(),
+            })
+            .unwrap();
+        assert_eq!(register(REG_INVERT_IQ), INVERT_IQ_RX);
+        assert_eq!(register(REG_INVERT_IQ_2), INVERT_IQ_2_ON);
+
+        // Beacons use implicit header mode with a fixed length
+        radio
+            .configure_rx(RxConfig {
+                frequency: 869_525_000,
+                timeout_ms: 0,
+                modulation: DataRate::SF9BW125.beacon_modulation(17),
+            })
+            .unwrap();
+        assert_eq!(
+            register(REG_MODEM_CONFIG_1) & IMPLICIT_HEADER_MODE,
+            IMPLICIT_HEADER_MODE
+        );
+        assert_eq!(register(REG_PAYLOAD_LENGTH), 17);
+        assert_eq!(register(REG_PREAMBLE_LSB), 10);
+        assert_eq!(register(REG_MODEM_CONFIG_2) & 0x04, 0);
+    }
+
+    #[test]
+    fn test_reset_restores_frequency() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = silent_radio(&chip, &elapsed);
+        assert_eq!(radio.get_frequency(), 0);
+        radio.set_frequency(868_100_000).unwrap();
+        radio.standby().unwrap();
+        chip.borrow_mut().log.clear();
+
+        radio.reset().unwrap();
+        assert_eq!(radio.get_frequency(), 868_100_000);
+        let log = core::mem::take(&mut chip.borrow_mut().log);
+        // Configured from scratch, then tuned back to the frequency
+        assert_eq!(&log[0][..], &[REG_OP_MODE | 0x80, MODE_SLEEP | 0x80]);
+        assert_eq!(
+            &log[log.len() - 3..]
+                .iter()
+                .map(|t| t[0])
+                .collect::<heapless::Vec<u8, 3>>()[..],
+            &[REG_FRF_MSB | 0x80, REG_FRF_MID | 0x80, REG_FRF_LSB | 0x80]
+        );
+
+        radio.standby().unwrap();
+        assert_eq!(
+            chip.borrow().registers[REG_OP_MODE as usize],
+            MODE_STDBY | 0x80
+        );
+    }
+
+    #[test]
+    fn test_cad_sequence() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = radio_with_packet(&chip, &elapsed);
+        chip.borrow_mut().log.clear();
+
+        let modulation = DataRate::SF7BW125.downlink_modulation();
+        assert!(radio.cad(&modulation).unwrap());
+        let log = core::mem::take(&mut chip.borrow_mut().log);
+        assert_eq!(&log[0][..], &[REG_OP_MODE | 0x80, MODE_STDBY | 0x80]);
+        let tail: heapless::Vec<&[u8], 6> = log[log.len() - 6..].iter().map(|t| &t[..]).collect();
+        assert_eq!(
+            &tail[..],
+            &[
+                &[REG_IRQ_FLAGS | 0x80, IRQ_ALL_MASK][..],
+                &[REG_DIO_MAPPING_1 | 0x80, DIO0_CAD_DONE],
+                &[REG_OP_MODE | 0x80, MODE_CAD | 0x80],
+                &[REG_IRQ_FLAGS, 0],
+                &[
+                    REG_IRQ_FLAGS | 0x80,
+                    IRQ_CAD_DONE_MASK | IRQ_CAD_DETECTED_MASK
+                ],
+                &[REG_DIO_MAPPING_1 | 0x80, DIO0_RX_DONE],
+            ]
+        );
+
+        // Without CadDone the wait is bounded by three symbols
+        let chip = RefCell::new(Chip::new());
+        let mut radio = silent_radio(&chip, &elapsed);
+        elapsed.set(0);
+        assert!(matches!(radio.cad(&modulation), Err(SX127xError::Timeout)));
+        assert_eq!(elapsed.get(), 4);
+        assert_eq!(
+            chip.borrow().registers[REG_OP_MODE as usize],
+            MODE_STDBY | 0x80
+        );
+    }
+
+    #[test]
+    fn test_random_number_from_wideband_rssi() {
+        let chip = RefCell::new(Chip::new());
+        let elapsed = Cell::new(0);
+        let mut radio = silent_radio(&chip, &elapsed);
+        chip.borrow_mut().registers[REG_RSSI_WIDEBAND as usize] = 0x01;
+        elapsed.set(0);
+
+        assert_eq!(radio.random_u32().unwrap(), 0xFFFF_FFFF);
+        // One bit per millisecond, radio left in standby
+        assert_eq!(elapsed.get(), 32);
+        assert_eq!(
+            chip.borrow().registers[REG_OP_MODE as usize],
+            MODE_STDBY | 0x80
+        );
+    }
 }
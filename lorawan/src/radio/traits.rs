@@ -1,5 +1,6 @@
 /// Radio error type
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum RadioError {
     /// SPI communication error
     Spi,
@@ -11,6 +12,19 @@ pub enum RadioError {
     Timeout,
 }
 
+impl core::fmt::Display for RadioError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RadioError::Spi => f.write_str("SPI communication error"),
+            RadioError::Gpio => f.write_str("GPIO error"),
+            RadioError::InvalidConfig => f.write_str("invalid configuration"),
+            RadioError::Timeout => f.write_str("operation timeout"),
+        }
+    }
+}
+
+impl core::error::Error for RadioError {}
+
 /// Radio modulation parameters
 #[derive(Debug, Clone, Copy)]
 pub struct ModulationParams {
@@ -20,6 +34,16 @@ pub struct ModulationParams {
     pub bandwidth: u32,
     /// Coding rate (4/5, 4/6, 4/7, 4/8)
     pub coding_rate: u8,
+    /// Preamble length in symbols
+    pub preamble_length: u16,
+    /// Implicit header mode with its fixed payload length, no PHY header is sent
+    pub implicit_header: Option<u8>,
+    /// Payload CRC enabled
+    pub crc: bool,
+    /// I and Q signals inverted, as used by LoRaWAN downlinks
+    pub iq_inverted: bool,
+    /// Sync word, 0x34 on public LoRaWAN networks
+    pub sync_word: u8,
 }
 
 /// Radio transmit configuration
@@ -44,6 +68,41 @@ pub struct RxConfig {
     pub modulation: ModulationParams,
 }
 
+/// Features and limits of a radio driver
+///
+/// Returned by `Radio::capabilities`, the MAC layer and device classes
+/// check it before using a feature.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RadioCapabilities {
+    /// Continuous reception, as needed by Class C
+    pub continuous_rx: bool,
+    /// Channel Activity Detection
+    pub cad: bool,
+    /// FSK modulation, e.g. for the FSK data rate of EU868
+    pub supports_fsk: bool,
+    /// Lowest output power in dBm
+    pub min_power_dbm: i8,
+    /// Highest output power in dBm
+    pub max_power_dbm: i8,
+    /// Lowest frequency in Hz
+    pub min_frequency: u32,
+    /// Highest frequency in Hz
+    pub max_frequency: u32,
+}
+
+impl RadioCapabilities {
+    /// Limit an output power in dBm to the range of the radio
+    pub fn clamp_power(&self, power: i8) -> i8 {
+        power.clamp(self.min_power_dbm, self.max_power_dbm)
+    }
+
+    /// Check if the radio can tune to `frequency` in Hz
+    pub fn supports_frequency(&self, frequency: u32) -> bool {
+        (self.min_frequency..=self.max_frequency).contains(&frequency)
+    }
+}
+
 /// Radio trait for LoRaWAN devices
 pub trait Radio {
     /// Error type returned by radio operations
@@ -55,6 +114,9 @@ pub trait Radio {
     /// Set the radio frequency
     fn set_frequency(&mut self, freq: u32) -> Result<(), Self::Error>;
 
+    /// Get the radio frequency, 0 until one is set
+    fn get_frequency(&self) -> u32;
+
     /// Set the radio output power
     fn set_tx_power(&mut self, power: i8) -> Result<(), Self::Error>;
 
@@ -76,6 +138,17 @@ pub trait Radio {
     /// Get SNR value
     fn get_snr(&mut self) -> Result<i8, Self::Error>;
 
+    /// Run a Channel Activity Detection on the current frequency
+    ///
+    /// Returns true if a LoRa preamble with `params` was detected. The radio
+    /// is left in standby.
+    fn cad(&mut self, params: &ModulationParams) -> Result<bool, Self::Error>;
+
+    /// Get a random number sampled from the wideband RSSI noise
+    ///
+    /// Interrupts any ongoing reception, the radio is left in standby.
+    fn random_u32(&mut self) -> Result<u32, Self::Error>;
+
     /// Check if radio is currently transmitting
     fn is_transmitting(&mut self) -> Result<bool, Self::Error>;
 
@@ -88,9 +161,63 @@ pub trait Radio {
     /// Put radio in sleep mode
     fn sleep(&mut self) -> Result<(), Self::Error>;
 
-    /// Reset the radio
+    /// Put radio in standby mode
+    fn standby(&mut self) -> Result<(), Self::Error>;
+
+    /// Reset the radio and restore its configuration and frequency
     fn reset(&mut self) -> Result<(), Self::Error>;
 
     /// Get current time in milliseconds
     fn get_time(&self) -> u32;
+
+    /// Get the features and limits of the radio
+    fn capabilities(&self) -> RadioCapabilities;
+}
+
+/// Asynchronous radio trait for LoRaWAN devices
+///
+/// Mirrors `Radio` for drivers that await SPI transfers and DIO interrupts,
+/// e.g. on top of `embedded-hal-async`. `receive` waits until a frame
+/// arrives or the configured timeout expires, and returns 0 on timeout.
+#[cfg(feature = "async")]
+#[allow(async_fn_in_trait)]
+pub trait AsyncRadio {
+    /// Error type returned by radio operations
+    type Error;
+
+    /// Initialize the radio
+    async fn init(&mut self) -> Result<(), Self::Error>;
+
+    /// Set the radio frequency
+    async fn set_frequency(&mut self, freq: u32) -> Result<(), Self::Error>;
+
+    /// Set the radio output power
+    async fn set_tx_power(&mut self, power: i8) -> Result<(), Self::Error>;
+
+    /// Transmit data, returning once the transmission is done
+    async fn transmit(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Receive data
+    async fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error>;
+
+    /// Configure radio for transmission
+    async fn configure_tx(&mut self, config: TxConfig) -> Result<(), Self::Error>;
+
+    /// Configure radio for reception
+    async fn configure_rx(&mut self, config: RxConfig) -> Result<(), Self::Error>;
+
+    /// Get RSSI value
+    async fn get_rssi(&mut self) -> Result<i16, Self::Error>;
+
+    /// Get SNR value
+    async fn get_snr(&mut self) -> Result<i8, Self::Error>;
+
+    /// Put radio in sleep mode
+    async fn sleep(&mut self) -> Result<(), Self::Error>;
+
+    /// Reset the radio
+    async fn reset(&mut self) -> Result<(), Self::Error>;
+
+    /// Get current time in milliseconds
+    fn get_time(&self) -> u32;
 }
@@ -0,0 +1,363 @@
+//! LoRaWAN repeater
+//!
+//! A repeater listens for the uplinks of devices out of reach of a gateway
+//! and transmits them again. `Repeater` receives with a `Sniffer`, forwards
+//! the valid data uplinks and counts what it did in `RepeaterMetrics`.
+//!
+//! Without a memory of the frames already forwarded, a repeater forwards its
+//! own retransmissions again, and two repeaters in range of each other pass
+//! a frame back and forth forever. `DedupCache` remembers the frames
+//! forwarded recently, identified by DevAddr, FCnt and MIC, and tells which
+//! ones to forward.
+
+use heapless::LinearMap;
+
+use crate::{
+    config::device::{DevAddr, SessionState},
+    crypto::{SoftwareCrypto, MIC_SIZE},
+    lorawan::{
+        frame::{self, DataFrame, DataFrameParams, FCtrl, PhyPayload},
+        region::{DataRate, Region},
+    },
+    radio::traits::{Radio, TxConfig},
+    sniffer::{FrameKind, Sniffer},
+    timing::has_elapsed,
+};
+
+/// Time a frame is remembered by default, longer than any retransmission
+pub const DEFAULT_DEDUP_TTL_MS: u32 = 30_000;
+
+/// Identity of a data frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FrameKey {
+    dev_addr: DevAddr,
+    fcnt: u16,
+    mic: [u8; MIC_SIZE],
+}
+
+/// Cache of the last `N` frames forwarded
+pub struct DedupCache<const N: usize> {
+    /// Frames forwarded and the time they were last seen
+    seen: LinearMap<FrameKey, u32, N>,
+    /// Time a frame is remembered after it was last seen
+    ttl_ms: u32,
+    /// Address of the repeater's own session, never forwarded
+    own_addr: Option<DevAddr>,
+}
+
+impl<const N: usize> DedupCache<N> {
+    /// Create an empty cache remembering frames for `DEFAULT_DEDUP_TTL_MS`
+    pub fn new() -> Self {
+        Self::with_ttl(DEFAULT_DEDUP_TTL_MS)
+    }
+
+    /// Create an empty cache remembering frames for `ttl_ms`
+    pub fn with_ttl(ttl_ms: u32) -> Self {
+        Self {
+            seen: LinearMap::new(),
+            ttl_ms,
+            own_addr: None,
+        }
+    }
+
+    /// Set the address of the repeater's own session, e.g. for metrics
+    ///
+    /// Frames of this address are never forwarded.
+    pub fn set_own_address(&mut self, dev_addr: Option<DevAddr>) {
+        self.own_addr = dev_addr;
+    }
+
+    /// Check if a frame should be forwarded, and remember it
+    ///
+    /// `now` is the time in milliseconds of a `Clock`. Returns `false` for
+    /// a frame seen in the last TTL and for the repeater's own frames. When
+    /// the cache is full, the frame seen least recently is forgotten.
+    pub fn should_forward(&mut self, frame: &DataFrame, now: u32) -> bool {
+        if Some(frame.fhdr.dev_addr) == self.own_addr {
+            return false;
+        }
+        let key = FrameKey {
+            dev_addr: frame.fhdr.dev_addr,
+            fcnt: frame.fhdr.f_cnt,
+            mic: frame.mic,
+        };
+
+        self.expire(now);
+        if let Some(seen) = self.seen.get_mut(&key) {
+            *seen = now;
+            return false;
+        }
+        if self.seen.len() == N {
+            self.evict_least_recent(now);
+        }
+        // Cannot fail, an entry was evicted if the cache was full
+        let _ = self.seen.insert(key, now);
+        true
+    }
+
+    /// Forget the frames whose TTL has expired at `now`
+    fn expire(&mut self, now: u32) {
+        let ttl_ms = self.ttl_ms;
+        while let Some(key) = self
+            .seen
+            .iter()
+            .find(|(_, &seen)| has_elapsed(now, seen.wrapping_add(ttl_ms)))
+            .map(|(key, _)| *key)
+        {
+            self.seen.remove(&key);
+        }
+    }
+
+    /// Forget the frame seen least recently
+    fn evict_least_recent(&mut self, now: u32) {
+        if let Some(key) = self
+            .seen
+            .iter()
+            .max_by_key(|(_, &seen)| now.wrapping_sub(seen))
+            .map(|(key, _)| *key)
+        {
+            self.seen.remove(&key);
+        }
+    }
+
+    /// Get the number of frames remembered
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    /// Check if no frame is remembered
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    /// Forget all frames
+    pub fn clear(&mut self) {
+        self.seen.clear();
+    }
+}
+
+impl<const N: usize> Default for DedupCache<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counters of a repeater
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RepeaterMetrics {
+    /// Frames received
+    pub packets_seen: u32,
+    /// Frames transmitted again
+    pub packets_forwarded: u32,
+    /// Frames not forwarded, duplicates included
+    pub packets_dropped: u32,
+    /// Frames not forwarded because they were forwarded recently
+    pub duplicates: u32,
+    /// RSSI of the last frame received in dBm
+    pub last_rssi: i16,
+    /// SNR of the last frame received in dB
+    pub last_snr: i8,
+}
+
+impl RepeaterMetrics {
+    /// Size of the encoded metrics
+    pub const SIZE: usize = 19;
+
+    /// Encode the metrics for an uplink, little endian
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.packets_seen.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.packets_forwarded.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.packets_dropped.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.duplicates.to_le_bytes());
+        bytes[16..18].copy_from_slice(&self.last_rssi.to_le_bytes());
+        bytes[18] = self.last_snr as u8;
+        bytes
+    }
+}
+
+/// Outcome of a frame received by the repeater
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RepeaterEvent {
+    /// Frame transmitted again
+    Forwarded {
+        /// Frequency the frame was transmitted on in Hz
+        frequency: u32,
+    },
+    /// Frame forwarded recently, or sent by the repeater itself
+    Duplicate,
+    /// Frame that is not a data uplink, or is malformed
+    Invalid,
+}
+
+/// Repeater forwarding the data uplinks it hears
+///
+/// Frames are forwarded on the frequency they were received on, unless a
+/// frequency map is set. Join requests are not forwarded. Remembers the
+/// last `N` frames forwarded.
+pub struct Repeater<R: Radio, REG: Region, const N: usize = 64> {
+    sniffer: Sniffer<R, REG>,
+    cache: DedupCache<N>,
+    /// Frequency to forward a frame received on a frequency
+    frequency_map: Option<fn(u32) -> u32>,
+    metrics: RepeaterMetrics,
+    /// Session of the repeater and port its metrics are sent on
+    session: Option<(SessionState, u8)>,
+}
+
+impl<R: Radio, REG: Region, const N: usize> Repeater<R, REG, N> {
+    /// Create a repeater listening on the enabled channels of `region`
+    ///
+    /// The repeater hops to the next channel every `dwell_ms`, or stays on
+    /// the first channel if it is 0.
+    pub fn new(radio: R, region: REG, dwell_ms: u32) -> Self {
+        Self {
+            sniffer: Sniffer::new(radio, region, dwell_ms),
+            cache: DedupCache::new(),
+            frequency_map: None,
+            metrics: RepeaterMetrics::default(),
+            session: None,
+        }
+    }
+
+    /// Forward frames on the frequency `map` returns for their frequency
+    pub fn set_frequency_map(&mut self, map: Option<fn(u32) -> u32>) {
+        self.frequency_map = map;
+    }
+
+    /// Listen at `data_rate` on the channels supporting it
+    pub fn set_data_rate(&mut self, data_rate: Option<DataRate>) {
+        self.sniffer.set_data_rate(data_rate);
+    }
+
+    /// Send the metrics of the repeater on `port` with an ABP session
+    ///
+    /// Frames of the session are never forwarded.
+    pub fn set_metrics_session(&mut self, session: SessionState, port: u8) {
+        self.cache.set_own_address(Some(session.dev_addr));
+        self.session = Some((session, port));
+    }
+
+    /// Get the session the metrics are sent with
+    pub fn metrics_session(&self) -> Option<&SessionState> {
+        self.session.as_ref().map(|(session, _)| session)
+    }
+
+    /// Get the counters of the repeater
+    pub fn metrics(&self) -> RepeaterMetrics {
+        self.metrics
+    }
+
+    /// Reset the counters of the repeater
+    pub fn reset_metrics(&mut self) {
+        self.metrics = RepeaterMetrics::default();
+    }
+
+    /// Receive the next frame and forward it if needed
+    ///
+    /// Returns `None` if nothing was received.
+    pub fn poll(&mut self) -> Result<Option<RepeaterEvent>, R::Error> {
+        let Some(sniffed) = self.sniffer.poll()? else {
+            return Ok(None);
+        };
+        self.metrics.packets_seen = self.metrics.packets_seen.wrapping_add(1);
+        self.metrics.last_rssi = sniffed.rssi;
+        self.metrics.last_snr = sniffed.snr;
+
+        let now = self.sniffer.radio_mut().get_time();
+        let event = match PhyPayload::parse(&sniffed.raw) {
+            Ok(PhyPayload::DataUp(frame)) if sniffed.kind == FrameKind::DataUp => {
+                if self.cache.should_forward(&frame, now) {
+                    let frequency = self
+                        .frequency_map
+                        .map_or(sniffed.frequency, |map| map(sniffed.frequency));
+                    self.transmit(frequency, &sniffed.raw)?;
+                    RepeaterEvent::Forwarded { frequency }
+                } else {
+                    RepeaterEvent::Duplicate
+                }
+            }
+            _ => RepeaterEvent::Invalid,
+        };
+
+        match event {
+            RepeaterEvent::Forwarded { .. } => {
+                self.metrics.packets_forwarded = self.metrics.packets_forwarded.wrapping_add(1);
+            }
+            RepeaterEvent::Duplicate => {
+                self.metrics.duplicates = self.metrics.duplicates.wrapping_add(1);
+                self.metrics.packets_dropped = self.metrics.packets_dropped.wrapping_add(1);
+            }
+            RepeaterEvent::Invalid => {
+                self.metrics.packets_dropped = self.metrics.packets_dropped.wrapping_add(1);
+            }
+        }
+        Ok(Some(event))
+    }
+
+    /// Send the metrics as an unconfirmed uplink of the metrics session
+    ///
+    /// The uplink is sent on the current channel and no receive window is
+    /// opened. Returns `false` without a metrics session or before the
+    /// first poll.
+    pub fn send_metrics(&mut self) -> Result<bool, R::Error> {
+        let Some(frequency) = self.sniffer.frequency() else {
+            return Ok(false);
+        };
+        let payload = self.metrics.to_bytes();
+        let Some((session, port)) = self.session.as_mut() else {
+            return Ok(false);
+        };
+        let Ok(uplink) = frame::build_data_up(
+            &SoftwareCrypto,
+            &DataFrameParams {
+                confirmed: false,
+                dev_addr: session.dev_addr,
+                f_ctrl: FCtrl::new(),
+                fcnt: session.fcnt_up,
+                f_opts: &[],
+                f_port: Some(*port),
+                payload: &payload,
+            },
+            &session.nwk_skey,
+            &session.app_skey,
+        ) else {
+            return Ok(false);
+        };
+        session.fcnt_up = session.fcnt_up.wrapping_add(1);
+        self.transmit(frequency, &uplink)?;
+        Ok(true)
+    }
+
+    /// Transmit a frame at the data rate listened at, then listen again
+    fn transmit(&mut self, frequency: u32, data: &[u8]) -> Result<(), R::Error> {
+        let data_rate = self.sniffer.data_rate().unwrap_or(DataRate::SF10BW125);
+        let region = self.sniffer.region();
+        let power = region.tx_power_dbm(region.tx_power());
+        let radio = self.sniffer.radio_mut();
+        radio.configure_tx(TxConfig {
+            frequency,
+            power,
+            modulation: data_rate.modulation(),
+        })?;
+        radio.transmit(data)?;
+        self.sniffer.resume()
+    }
+
+    /// Get the sniffer receiving the frames
+    pub fn sniffer(&self) -> &Sniffer<R, REG> {
+        &self.sniffer
+    }
+
+    /// Get mutable access to the radio
+    pub fn radio_mut(&mut self) -> &mut R {
+        self.sniffer.radio_mut()
+    }
+
+    /// Take back the radio and the region
+    pub fn release(self) -> (R, REG) {
+        self.sniffer.release()
+    }
+}
@@ -0,0 +1,289 @@
+//! Packet sniffer for site surveys
+//!
+//! `Sniffer` listens on the enabled channels of a region without joining a
+//! network. Every frame received is parsed with the frame codec and
+//! classified, and the frames seen of each device are counted. With a dwell
+//! time the sniffer hops to the next channel whenever it expires, so a scan
+//! covers the whole channel plan.
+//!
+//! Uplinks are sent with IQ not inverted and downlinks with IQ inverted, so a
+//! sniffer hears one direction at a time, see `listen_downlinks`.
+
+use heapless::{LinearMap, Vec};
+
+use crate::{
+    config::device::DevAddr,
+    lorawan::{
+        frame::{MType, PhyPayload, MAX_FRAME_SIZE},
+        region::{DataRate, Region},
+    },
+    radio::traits::{Radio, RxConfig},
+    timing::has_elapsed,
+};
+
+/// Number of devices whose frames are counted
+pub const MAX_SNIFFED_DEVICES: usize = 32;
+
+/// Kind of a sniffed frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FrameKind {
+    /// Join request of a device
+    JoinRequest,
+    /// Join accept of the network
+    JoinAccept,
+    /// Data frame sent by a device
+    DataUp,
+    /// Data frame sent by the network
+    DataDown,
+    /// Proprietary, unsupported or malformed frame
+    Unknown,
+}
+
+/// Frame received by the sniffer
+#[derive(Debug, Clone)]
+pub struct SniffedFrame {
+    /// MHDR, the first byte of the frame
+    pub mhdr: u8,
+    /// Kind of the frame
+    pub kind: FrameKind,
+    /// Device address of a data frame
+    pub dev_addr: Option<DevAddr>,
+    /// 16-bit frame counter of a data frame
+    pub fcnt: Option<u16>,
+    /// RSSI in dBm
+    pub rssi: i16,
+    /// SNR in dB
+    pub snr: i8,
+    /// Frequency the frame was received on in Hz
+    pub frequency: u32,
+    /// Frame as received
+    pub raw: Vec<u8, MAX_FRAME_SIZE>,
+}
+
+impl SniffedFrame {
+    /// Classify a received frame
+    fn parse(data: &[u8], rssi: i16, snr: i8, frequency: u32) -> Self {
+        let (kind, header) = match PhyPayload::parse(data) {
+            Ok(PhyPayload::JoinRequest(_)) => (FrameKind::JoinRequest, None),
+            Ok(PhyPayload::JoinAccept(_)) => (FrameKind::JoinAccept, None),
+            Ok(PhyPayload::DataUp(frame)) => (FrameKind::DataUp, Some(frame.fhdr)),
+            Ok(PhyPayload::DataDown(frame)) => (FrameKind::DataDown, Some(frame.fhdr)),
+            Err(_) => (FrameKind::Unknown, None),
+        };
+        // Receive buffers are at most MAX_FRAME_SIZE bytes
+        let raw = Vec::from_slice(&data[..data.len().min(MAX_FRAME_SIZE)]).unwrap_or_default();
+        Self {
+            mhdr: data.first().copied().unwrap_or(0),
+            kind,
+            dev_addr: header.as_ref().map(|fhdr| fhdr.dev_addr),
+            fcnt: header.as_ref().map(|fhdr| fhdr.f_cnt),
+            rssi,
+            snr,
+            frequency,
+            raw,
+        }
+    }
+
+    /// Get the message type of the frame, `None` for the RFU type
+    pub fn mtype(&self) -> Option<MType> {
+        MType::from_mhdr(self.mhdr)
+    }
+}
+
+/// Frames seen of one device
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DeviceTraffic {
+    /// Data frames sent by the device
+    pub uplinks: u32,
+    /// Data frames sent to the device
+    pub downlinks: u32,
+    /// Frame counter of the last data frame
+    pub last_fcnt: u16,
+}
+
+/// Promiscuous receiver on the channels of a region
+pub struct Sniffer<R: Radio, REG: Region> {
+    radio: R,
+    region: REG,
+    /// Time on a channel before hopping to the next, 0 to stay
+    dwell_ms: u32,
+    /// Data rate to listen at, the most robust of each channel if `None`
+    data_rate: Option<DataRate>,
+    /// Listen for downlinks instead of uplinks
+    downlinks: bool,
+    /// Index among the enabled channels, frequency and data rate listened on
+    channel: Option<(usize, u32, DataRate)>,
+    /// Time to hop to the next channel
+    hop_at: u32,
+    devices: LinearMap<DevAddr, DeviceTraffic, MAX_SNIFFED_DEVICES>,
+    /// Data frames of devices that did not fit in the map
+    untracked_frames: u32,
+}
+
+impl<R: Radio, REG: Region> Sniffer<R, REG> {
+    /// Create a sniffer listening on the enabled channels of `region`
+    ///
+    /// The sniffer hops to the next channel every `dwell_ms`, or stays on
+    /// the first channel if it is 0.
+    pub fn new(radio: R, region: REG, dwell_ms: u32) -> Self {
+        Self {
+            radio,
+            region,
+            dwell_ms,
+            data_rate: None,
+            downlinks: false,
+            channel: None,
+            hop_at: 0,
+            devices: LinearMap::new(),
+            untracked_frames: 0,
+        }
+    }
+
+    /// Listen at `data_rate` on the channels supporting it
+    ///
+    /// Other channels, and all channels with `None`, are listened on at
+    /// their most robust data rate. Applies from the next hop.
+    pub fn set_data_rate(&mut self, data_rate: Option<DataRate>) {
+        self.data_rate = data_rate;
+    }
+
+    /// Listen for downlinks (IQ inverted) instead of uplinks
+    ///
+    /// Applies from the next hop.
+    pub fn listen_downlinks(&mut self, enabled: bool) {
+        self.downlinks = enabled;
+    }
+
+    /// Get the frequency listened on, `None` before the first poll
+    pub fn frequency(&self) -> Option<u32> {
+        self.channel.map(|(_, frequency, _)| frequency)
+    }
+
+    /// Get the data rate listened at, `None` before the first poll
+    pub fn data_rate(&self) -> Option<DataRate> {
+        self.channel.map(|(_, _, data_rate)| data_rate)
+    }
+
+    /// Get the region
+    pub fn region(&self) -> &REG {
+        &self.region
+    }
+
+    /// Receive the next frame, if any
+    ///
+    /// Hops to the next enabled channel when the dwell time has expired.
+    /// Returns `None` if nothing was received or no channel is enabled.
+    pub fn poll(&mut self) -> Result<Option<SniffedFrame>, R::Error> {
+        let now = self.radio.get_time();
+        if self.channel.is_none() || (self.dwell_ms > 0 && has_elapsed(now, self.hop_at)) {
+            self.hop(now)?;
+        }
+        let Some((_, frequency, _)) = self.channel else {
+            return Ok(None);
+        };
+
+        let mut buffer = [0u8; MAX_FRAME_SIZE];
+        let len = self.radio.receive(&mut buffer)?;
+        if len == 0 {
+            return Ok(None);
+        }
+        let rssi = self.radio.get_rssi()?;
+        let snr = self.radio.get_snr()?;
+        let frame = SniffedFrame::parse(&buffer[..len], rssi, snr, frequency);
+        self.record(&frame);
+        Ok(Some(frame))
+    }
+
+    /// Listen on the next enabled channel
+    fn hop(&mut self, now: u32) -> Result<(), R::Error> {
+        self.hop_at = now.wrapping_add(self.dwell_ms);
+        let count = self.region.enabled_channels().count();
+        let index = match self.channel {
+            Some((index, _, _)) if count > 0 => (index + 1) % count,
+            _ => 0,
+        };
+        let Some(channel) = self.region.enabled_channels().nth(index) else {
+            self.channel = None;
+            return Ok(());
+        };
+
+        let data_rate = match self.data_rate {
+            Some(data_rate) if data_rate.bandwidth() == channel.min_dr.bandwidth() => data_rate,
+            _ => channel.min_dr,
+        };
+        self.channel = Some((index, channel.frequency, data_rate));
+        self.resume()
+    }
+
+    /// Listen again on the current channel, e.g. after transmitting
+    pub fn resume(&mut self) -> Result<(), R::Error> {
+        let Some((_, frequency, data_rate)) = self.channel else {
+            return Ok(());
+        };
+        let modulation = if self.downlinks {
+            data_rate.downlink_modulation()
+        } else {
+            data_rate.modulation()
+        };
+        self.radio.configure_rx(RxConfig {
+            frequency,
+            timeout_ms: 0,
+            modulation,
+        })
+    }
+
+    /// Count a data frame for its device
+    fn record(&mut self, frame: &SniffedFrame) {
+        let (Some(dev_addr), Some(fcnt)) = (frame.dev_addr, frame.fcnt) else {
+            return;
+        };
+        if !self.devices.contains_key(&dev_addr)
+            && self
+                .devices
+                .insert(dev_addr, DeviceTraffic::default())
+                .is_err()
+        {
+            self.untracked_frames = self.untracked_frames.wrapping_add(1);
+            return;
+        }
+        if let Some(traffic) = self.devices.get_mut(&dev_addr) {
+            match frame.kind {
+                FrameKind::DataDown => traffic.downlinks = traffic.downlinks.wrapping_add(1),
+                _ => traffic.uplinks = traffic.uplinks.wrapping_add(1),
+            }
+            traffic.last_fcnt = fcnt;
+        }
+    }
+
+    /// Get the frames seen of a device
+    pub fn device(&self, dev_addr: &DevAddr) -> Option<&DeviceTraffic> {
+        self.devices.get(dev_addr)
+    }
+
+    /// Get the frames seen of every tracked device
+    pub fn devices(&self) -> impl Iterator<Item = (&DevAddr, &DeviceTraffic)> {
+        self.devices.iter()
+    }
+
+    /// Get the number of data frames of devices beyond `MAX_SNIFFED_DEVICES`
+    pub fn untracked_frames(&self) -> u32 {
+        self.untracked_frames
+    }
+
+    /// Forget the devices seen so far
+    pub fn clear(&mut self) {
+        self.devices.clear();
+        self.untracked_frames = 0;
+    }
+
+    /// Get mutable access to the radio
+    pub fn radio_mut(&mut self) -> &mut R {
+        &mut self.radio
+    }
+
+    /// Take back the radio and the region
+    pub fn release(self) -> (R, REG) {
+        (self.radio, self.region)
+    }
+}
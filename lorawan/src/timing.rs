@@ -0,0 +1,33 @@
+//! Monotonic time source
+//!
+//! Receive windows, Class B beacons and ping slots, and network time all
+//! depend on a millisecond clock. Every radio provides one through
+//! `Radio::get_time`, the default of the MAC layer. Other time sources, e.g.
+//! an RTC or a monotonic timer, implement `Clock` and replace it with
+//! `MacLayer::with_clock` or `set_clock`.
+
+use crate::radio::traits::Radio;
+
+/// Monotonic millisecond clock
+pub trait Clock {
+    /// Get the current time in milliseconds, wrapping around at `u32::MAX`
+    fn now_ms(&self) -> u32;
+
+    /// Wait until the clock reaches `instant_ms`
+    ///
+    /// The default implementation busy-waits on `now_ms`.
+    fn delay_until(&self, instant_ms: u32) {
+        while !has_elapsed(self.now_ms(), instant_ms) {}
+    }
+}
+
+impl<R: Radio> Clock for R {
+    fn now_ms(&self) -> u32 {
+        self.get_time()
+    }
+}
+
+/// Check if `instant_ms` has been reached at `now_ms`, across wrap-around
+pub fn has_elapsed(now_ms: u32, instant_ms: u32) -> bool {
+    (now_ms.wrapping_sub(instant_ms) as i32) >= 0
+}
@@ -0,0 +1,315 @@
+#![no_std]
+
+use lorawan::{
+    async_device::AsyncLoRaWANDevice,
+    class::OperatingMode,
+    config::device::{AESKey, DeviceConfig, SessionState},
+    crypto::{self, Direction},
+    device::{DeviceError, JoinPolicy},
+    lorawan::{mac::MacError, region::US915},
+    radio::traits::{AsyncRadio, RxConfig, TxConfig},
+    timing::Clock,
+};
+
+use embassy_futures::block_on;
+use embedded_hal_async::delay::DelayNs;
+use heapless::Vec;
+mod mock;
+use mock::{MockClock, MockError};
+
+/// Async radio answering each receive with the next queued reply
+struct AsyncMockRadio<'a> {
+    clock: &'a MockClock,
+    /// Frame received in each window, `None` for an empty window
+    replies: Vec<Option<Vec<u8, 64>>, 8>,
+    /// Transmitted frames with the time they were sent at
+    tx: Vec<(u32, Vec<u8, 64>), 8>,
+    /// Receive windows with the time they were opened at
+    rx: Vec<(u32, RxConfig), 8>,
+}
+
+impl<'a> AsyncMockRadio<'a> {
+    fn new(clock: &'a MockClock) -> Self {
+        Self {
+            clock,
+            replies: Vec::new(),
+            tx: Vec::new(),
+            rx: Vec::new(),
+        }
+    }
+}
+
+impl AsyncRadio for AsyncMockRadio<'_> {
+    type Error = MockError;
+
+    async fn init(&mut self) -> Result<(), MockError> {
+        Ok(())
+    }
+
+    async fn set_frequency(&mut self, _freq: u32) -> Result<(), MockError> {
+        Ok(())
+    }
+
+    async fn set_tx_power(&mut self, _power: i8) -> Result<(), MockError> {
+        Ok(())
+    }
+
+    async fn transmit(&mut self, data: &[u8]) -> Result<(), MockError> {
+        let frame = Vec::from_slice(data).map_err(|_| MockError::Error)?;
+        self.tx.push((self.clock.now_ms(), frame)).unwrap();
+        // Time on air
+        self.clock.advance(50);
+        Ok(())
+    }
+
+    async fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, MockError> {
+        if self.replies.is_empty() {
+            return Ok(0);
+        }
+        match self.replies.remove(0) {
+            Some(frame) => {
+                buffer[..frame.len()].copy_from_slice(&frame);
+                Ok(frame.len())
+            }
+            None => Ok(0),
+        }
+    }
+
+    async fn configure_tx(&mut self, _config: TxConfig) -> Result<(), MockError> {
+        Ok(())
+    }
+
+    async fn configure_rx(&mut self, config: RxConfig) -> Result<(), MockError> {
+        self.rx.push((self.clock.now_ms(), config)).unwrap();
+        Ok(())
+    }
+
+    async fn get_rssi(&mut self) -> Result<i16, MockError> {
+        Ok(-60)
+    }
+
+    async fn get_snr(&mut self) -> Result<i8, MockError> {
+        Ok(7)
+    }
+
+    async fn sleep(&mut self) -> Result<(), MockError> {
+        Ok(())
+    }
+
+    async fn reset(&mut self) -> Result<(), MockError> {
+        Ok(())
+    }
+
+    fn get_time(&self) -> u32 {
+        self.clock.now_ms()
+    }
+}
+
+/// Delay advancing the mock clock instead of sleeping
+struct MockDelay<'a> {
+    clock: &'a MockClock,
+}
+
+impl DelayNs for MockDelay<'_> {
+    async fn delay_ns(&mut self, ns: u32) {
+        self.clock.advance(ns.div_ceil(1_000_000));
+    }
+
+    async fn delay_ms(&mut self, ms: u32) {
+        self.clock.advance(ms);
+    }
+}
+
+/// Build an encrypted join accept for `app_key`
+fn build_join_accept(app_key: &AESKey) -> Vec<u8, 64> {
+    let mut message = Vec::<u8, 64>::new();
+    message
+        .extend_from_slice(&[
+            0x20, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x00, 0x01,
+        ])
+        .unwrap();
+    let mic = crypto::compute_join_accept_mic(app_key, &message);
+    message.extend_from_slice(&mic).unwrap();
+
+    let mut join_accept = Vec::new();
+    join_accept.push(message[0]).unwrap();
+    join_accept
+        .extend_from_slice(&crypto::encrypt_join_accept(app_key, &message[1..]))
+        .unwrap();
+    join_accept
+}
+
+/// Build an unconfirmed downlink with application data
+fn build_downlink(session: &SessionState, fcnt: u32, f_port: u8, payload: &[u8]) -> Vec<u8, 64> {
+    let mut frame = Vec::<u8, 64>::new();
+    frame.push(0x60).unwrap();
+    frame
+        .extend_from_slice(session.dev_addr.as_bytes())
+        .unwrap();
+    frame.push(0x00).unwrap();
+    frame
+        .extend_from_slice(&(fcnt as u16).to_le_bytes())
+        .unwrap();
+    frame.push(f_port).unwrap();
+    frame
+        .extend_from_slice(
+            &crypto::encrypt_payload(
+                &session.app_skey,
+                session.dev_addr,
+                fcnt,
+                Direction::Down,
+                payload,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+    let mic = crypto::compute_mic(
+        &session.nwk_skey,
+        &frame,
+        session.dev_addr,
+        fcnt,
+        Direction::Down,
+    );
+    frame.extend_from_slice(&mic).unwrap();
+    frame
+}
+
+#[test]
+fn test_async_join_then_uplink() {
+    let clock = MockClock::new();
+    clock.set(1_000);
+    let app_key = AESKey::new([0x2B; 16]);
+
+    let mut radio = AsyncMockRadio::new(&clock);
+    // Nothing in RX1, the join accept arrives in RX2
+    radio.replies.push(None).unwrap();
+    radio
+        .replies
+        .push(Some(build_join_accept(&app_key)))
+        .unwrap();
+
+    block_on(async {
+        let config = DeviceConfig::new_otaa([0x01; 8], [0x02; 8], app_key.clone());
+        let mut device = AsyncLoRaWANDevice::new(
+            radio,
+            MockDelay { clock: &clock },
+            config,
+            US915::new(),
+            OperatingMode::ClassA,
+        )
+        .await
+        .unwrap();
+
+        device
+            .join_otaa([0x01; 8], [0x02; 8], app_key.clone())
+            .await
+            .unwrap();
+        let session = device.device().get_session_state();
+        assert!(session.is_joined());
+
+        // No downlink yet
+        assert!(device.next_downlink().await.unwrap().is_none());
+
+        // The receive windows are timed from the end of the transmission
+        let radio = device.radio();
+        assert_eq!(radio.tx.len(), 1);
+        assert_eq!(radio.tx[0].1[0], 0x00);
+        let tx_end = radio.tx[0].0 + 50;
+        assert_eq!(radio.rx[0].0, tx_end + 5_000);
+        assert_eq!(radio.rx[1].0, tx_end + 6_000);
+        assert_eq!(radio.rx[1].1.frequency, 923_300_000);
+
+        // Application data in RX1 of an unconfirmed uplink
+        device
+            .radio_mut()
+            .replies
+            .push(Some(build_downlink(&session, 0, 3, b"pong")))
+            .unwrap();
+        assert_eq!(device.send_data(1, b"ping", false).await.unwrap(), None);
+        let radio = device.radio();
+        assert_eq!(radio.tx.len(), 2);
+        assert_eq!(radio.tx[1].1[0], 0x40);
+        // RxDelay 1 of the join accept
+        assert_eq!(radio.rx[2].0, radio.tx[1].0 + 50 + 1_000);
+        // RX2 is not opened once a downlink was received in RX1
+        assert_eq!(radio.rx.len(), 3);
+
+        let downlink = device.next_downlink().await.unwrap().unwrap();
+        assert_eq!(downlink.port, 3);
+        assert_eq!(&downlink.payload[..], b"pong");
+        assert_eq!(downlink.rssi, -60);
+        assert_eq!(downlink.snr, 7);
+        assert!(device.next_downlink().await.unwrap().is_none());
+    });
+}
+
+#[test]
+fn test_async_join_timeout() {
+    let clock = MockClock::new();
+    let app_key = AESKey::new([0x2B; 16]);
+
+    block_on(async {
+        let config = DeviceConfig::new_otaa([0x01; 8], [0x02; 8], app_key.clone());
+        let mut device = AsyncLoRaWANDevice::new(
+            AsyncMockRadio::new(&clock),
+            MockDelay { clock: &clock },
+            config,
+            US915::new(),
+            OperatingMode::ClassA,
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            device.join_otaa([0x01; 8], [0x02; 8], app_key).await,
+            Err(DeviceError::Mac(MacError::Timeout))
+        ));
+        assert!(!device.device().get_session_state().is_joined());
+        assert_eq!(device.radio().rx.len(), 2);
+    });
+}
+
+#[test]
+fn test_async_join_retries_after_backoff() {
+    let clock = MockClock::new();
+    let app_key = AESKey::new([0x2B; 16]);
+
+    let mut radio = AsyncMockRadio::new(&clock);
+    // The first join request goes unanswered, the second is accepted in RX1
+    radio.replies.push(None).unwrap();
+    radio.replies.push(None).unwrap();
+    radio
+        .replies
+        .push(Some(build_join_accept(&app_key)))
+        .unwrap();
+
+    block_on(async {
+        let config = DeviceConfig::new_otaa([0x01; 8], [0x02; 8], app_key.clone());
+        let mut device = AsyncLoRaWANDevice::new(
+            radio,
+            MockDelay { clock: &clock },
+            config,
+            US915::new(),
+            OperatingMode::ClassA,
+        )
+        .await
+        .unwrap();
+        device.device_mut().set_join_policy(JoinPolicy {
+            max_attempts: 3,
+            base_backoff_ms: 2_000,
+            ..JoinPolicy::default()
+        });
+
+        device
+            .join_otaa([0x01; 8], [0x02; 8], app_key)
+            .await
+            .unwrap();
+        assert_eq!(device.device().join_attempts(), 2);
+
+        // The retry waits for the backoff after RX2 of the first request
+        let radio = device.radio();
+        assert_eq!(radio.tx.len(), 2);
+        assert_eq!(radio.tx[1].0, radio.tx[0].0 + 50 + 6_000 + 2_000);
+        assert_eq!(radio.rx.len(), 3);
+    });
+}
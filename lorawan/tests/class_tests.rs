@@ -1,15 +1,75 @@
 #![no_std]
 
 use lorawan::{
-    class::{class_b::ClassB, class_c::ClassC, DeviceClass, OperatingMode},
-    config::device::{AESKey, DeviceConfig, SessionState},
-    lorawan::{mac::MacLayer, region::US915},
+    class::{
+        class_a::ClassA,
+        class_b::{
+            beacon::{beacon_crc, BeaconFrame, BeaconState},
+            timing::NetworkTime,
+            ClassB,
+        },
+        class_c::{ClassC, QueueOverflow},
+        DeviceClass, OperatingMode,
+    },
+    config::device::{AESKey, DevAddr, DeviceConfig, SessionState},
+    crypto::{self, Direction, SoftwareCrypto},
+    device::power::PowerState,
+    lorawan::{
+        commands::MacCommand,
+        frame::{self, DataFrameParams, FCtrl},
+        mac::{rx_window_timeout, Downlink, MacError, MacLayer},
+        multicast::MulticastGroup,
+        region::{BeaconLayout, Region, AS923, CN470, US915},
+    },
+    radio::traits::Radio,
+    timing::{has_elapsed, Clock},
 };
 
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
 use heapless::Vec;
 
 mod mock;
-use mock::MockRadio;
+use mock::{MockClock, MockRadio};
+
+/// Send an uplink and receive with any device class
+fn exchange<R: Radio, REG: Region>(
+    device: &mut impl DeviceClass<R, REG>,
+) -> Result<usize, MacError<R::Error>> {
+    device.send_data(1, b"hi", false)?;
+    device.process()?;
+    let mut buffer = [0u8; 64];
+    device.receive(&mut buffer)
+}
+
+#[test]
+fn test_device_class_trait_for_all_classes() {
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x11; 16]),
+        AESKey::new([0x22; 16]),
+    );
+    let mac = || MacLayer::new(MockRadio::new(), US915::new(), session.clone());
+
+    let mut class_a = ClassA::new(mac());
+    let mut class_b = ClassB::new(mac());
+    let mut class_c = ClassC::new(mac(), 923_300_000, 8);
+    assert!(matches!(exchange(&mut class_a), Ok(0)));
+    assert!(matches!(exchange(&mut class_b), Ok(0)));
+    assert!(matches!(exchange(&mut class_c), Ok(0)));
+
+    assert_eq!(class_a.operating_mode(), OperatingMode::ClassA);
+    assert_eq!(class_b.operating_mode(), OperatingMode::ClassB);
+    assert_eq!(class_c.operating_mode(), OperatingMode::ClassC);
+    for session in [
+        class_a.get_session_state(),
+        class_b.get_session_state(),
+        class_c.get_session_state(),
+    ] {
+        assert_eq!(session.fcnt_up, 1);
+    }
+    assert!(class_c.get_mac_layer().get_radio().get_last_tx().is_some());
+}
 
 #[test]
 fn test_class_c_continuous_reception() {
@@ -39,6 +99,62 @@ fn test_class_c_power_management() {
     assert!(device.receive(&mut buffer).is_ok());
 }
 
+#[test]
+fn test_class_c_critical_battery_suspends_rx() {
+    let mac = MacLayer::new(MockRadio::new(), US915::new(), SessionState::new());
+    let mut device = ClassC::new(mac, 923_300_000, 8);
+    let rx_time = |device: &ClassC<MockRadio, US915>| {
+        device.get_mac_layer().power_manager().get_metrics().rx_time
+    };
+    let rx_count = |device: &ClassC<MockRadio, US915>| {
+        device.get_mac_layer().get_radio().get_rx_configs().len()
+    };
+
+    // Continuous reception counts as RX time
+    device.configure_rx2(923_300_000, 8).unwrap();
+    device.get_mac_layer_mut().get_radio_mut().set_time(1_000);
+    device.process().unwrap();
+    assert_eq!(rx_time(&device), Duration::from_millis(1_000));
+
+    // A critical battery stops it
+    device.update_power_state(5);
+    device.get_mac_layer_mut().get_radio_mut().set_time(2_000);
+    device.process().unwrap();
+    let suspended_at = rx_count(&device);
+    device.get_mac_layer_mut().get_radio_mut().set_time(5_000);
+    device.process().unwrap();
+    assert_eq!(rx_time(&device), Duration::from_millis(2_000));
+    assert_eq!(rx_count(&device), suspended_at);
+
+    // Until the battery recovers
+    device.update_power_state(200);
+    device.process().unwrap();
+    assert_eq!(rx_count(&device), suspended_at + 1);
+}
+
+#[test]
+fn test_class_c_critical_battery_survives_uplink() {
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x11; 16]),
+        AESKey::new([0x22; 16]),
+    );
+    let mac = MacLayer::new(MockRadio::new(), US915::new(), session);
+    let mut device = ClassC::new(mac, 923_300_000, 8);
+
+    // Without a status provider uplinks keep the level set by the application
+    device.update_power_state(5);
+    device.send_data(1, b"hi", false).unwrap();
+    for time in [1_000, 2_000, 5_000] {
+        device.get_mac_layer_mut().get_radio_mut().set_time(time);
+        device.process().unwrap();
+    }
+    let mac = device.get_mac_layer();
+    assert_eq!(mac.power_manager().get_state(), PowerState::Critical);
+    let rx_configs = mac.get_radio().get_rx_configs();
+    assert!(rx_configs.iter().all(|config| config.timeout_ms > 0));
+}
+
 #[test]
 fn test_class_b_beacon_sync() {
     let radio = MockRadio::new();
@@ -65,6 +181,368 @@ fn test_class_b_ping_slots() {
     assert!(device.receive(&mut buffer).is_ok());
 }
 
+/// Build a beacon with valid CRCs
+fn build_beacon(layout: BeaconLayout, gps_time: u32) -> Vec<u8, 23> {
+    let mut beacon = Vec::new();
+    beacon.resize(layout.rfu1, 0).unwrap();
+    beacon.extend_from_slice(&gps_time.to_le_bytes()).unwrap();
+    let crc = beacon_crc(&beacon);
+    beacon.extend_from_slice(&crc.to_le_bytes()).unwrap();
+
+    // InfoDesc 0 with the coordinates of the gateway, then RFU
+    let second = beacon.len();
+    beacon
+        .extend_from_slice(&[0x00, 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC])
+        .unwrap();
+    beacon.resize(beacon.len() + layout.rfu2, 0).unwrap();
+    let crc = beacon_crc(&beacon[second..]);
+    beacon.extend_from_slice(&crc.to_le_bytes()).unwrap();
+    beacon
+}
+
+#[test]
+fn test_beacon_layouts() {
+    // CRC-16/XMODEM check value
+    assert_eq!(beacon_crc(b"123456789"), 0x31C3);
+
+    assert_eq!(AS923::new().beacon_layout().size(), 17);
+    assert_eq!(CN470::new().beacon_layout().size(), 19);
+    assert_eq!(US915::new().beacon_layout().size(), 23);
+    assert_eq!(build_beacon(CN470::new().beacon_layout(), 0).len(), 19);
+}
+
+#[test]
+fn test_beacon_frame_crc_checks() {
+    let layout = US915::new().beacon_layout();
+    let beacon = build_beacon(layout, 1_234_567_808);
+    let frame = BeaconFrame::parse(&beacon, layout).unwrap();
+    assert_eq!(frame.time, 1_234_567_808);
+    assert_eq!(
+        frame.gw_specific,
+        [0x00, 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC]
+    );
+
+    // A flipped bit in either part, or in either CRC
+    for index in [2, 6, 10, 12, 16, 22] {
+        let mut corrupted = beacon.clone();
+        corrupted[index] ^= 0x01;
+        assert_eq!(BeaconFrame::parse(&corrupted, layout), None, "{}", index);
+    }
+
+    // The layout of another region
+    assert_eq!(BeaconFrame::parse(&beacon, BeaconLayout::DEFAULT), None);
+    assert_eq!(
+        BeaconFrame::parse(&beacon[..17], BeaconLayout::DEFAULT),
+        None
+    );
+}
+
+#[test]
+fn test_class_b_beacon_feeds_network_time() {
+    let mut radio = MockRadio::new();
+    let mut beacon = build_beacon(US915::new().beacon_layout(), 1_000_000);
+    beacon[8] ^= 0x80;
+    radio.set_rx_data(&beacon);
+    let mac = MacLayer::new(radio, US915::new(), SessionState::new());
+    let mut device = ClassB::new(mac);
+
+    // A beacon failing its CRC is ignored
+    device.start().unwrap();
+    device.process().unwrap();
+    assert_eq!(device.beacon_tracker().state(), BeaconState::Searching);
+    assert_eq!(device.beacon_tracker().last_gps_time(), None);
+
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_rx_data(&build_beacon(US915::new().beacon_layout(), 1_000_000));
+    device.process().unwrap();
+    assert_eq!(device.beacon_tracker().state(), BeaconState::Synchronized);
+    assert_eq!(device.beacon_tracker().last_gps_time(), Some(1_000_000));
+    let network_time = device.get_mac_layer().get_network_time();
+    let gps_time_ms = network_time
+        .gps_time_ms(device.get_mac_layer().get_radio())
+        .unwrap();
+    assert!(gps_time_ms >= 1_000_000_000);
+    assert!(gps_time_ms < 1_000_001_000);
+}
+
+#[test]
+fn test_class_b_bit_follows_beacon_lock() {
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x01; 16]),
+        AESKey::new([0x02; 16]),
+    );
+    let mac = MacLayer::new(MockRadio::new(), US915::new(), session);
+    let mut device = ClassB::new(mac);
+    let f_ctrl = |device: &mut ClassB<MockRadio, US915>| {
+        device.send_data(1, b"hi", false).unwrap();
+        device.get_mac_layer().get_radio().get_last_tx().unwrap()[5]
+    };
+
+    // Searching for beacons, the device still behaves as Class A
+    device.process().unwrap();
+    assert_eq!(device.beacon_tracker().state(), BeaconState::Searching);
+    assert!(!device.is_active());
+    assert_eq!(f_ctrl(&mut device) & 0x10, 0);
+
+    // The first beacon locks the device, uplinks declare Class B
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_rx_data(&build_beacon(US915::new().beacon_layout(), 1_234_567_808));
+    device.process().unwrap();
+    assert!(device.is_active());
+    assert_eq!(f_ctrl(&mut device) & 0x10, 0x10);
+
+    // A missed beacon, Class B continues without beacons
+    let last_beacon = device.beacon_tracker().last_beacon_time();
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.set_time(last_beacon + 128_000);
+    device.process().unwrap();
+    assert_eq!(device.beacon_tracker().state(), BeaconState::BeaconLess);
+    assert_eq!(f_ctrl(&mut device) & 0x10, 0x10);
+
+    // 2 hours without beacon, the bit is cleared while reacquiring
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.set_time(last_beacon + 7_200_001);
+    device.process().unwrap();
+    assert_eq!(device.beacon_tracker().state(), BeaconState::Lost);
+    assert!(!device.is_active());
+    assert_eq!(f_ctrl(&mut device) & 0x10, 0);
+
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_rx_data(&build_beacon(US915::new().beacon_layout(), 1_234_568_192));
+    device.process().unwrap();
+    assert!(device.is_active());
+    assert_eq!(f_ctrl(&mut device) & 0x10, 0x10);
+}
+
+#[test]
+fn test_beaconless_operation_widens_ping_slots() {
+    let layout = US915::new().beacon_layout();
+    let mut radio = MockRadio::new();
+    radio.set_rx_data(&build_beacon(layout, 1_000_000));
+    let mac = MacLayer::new(radio, US915::new(), SessionState::new());
+    let mut device = ClassB::new(mac);
+    device.process().unwrap();
+    assert_eq!(
+        device.take_beacon_state_change(),
+        Some(BeaconState::Synchronized)
+    );
+
+    // The local clock counts 127 872 ms for a 128 s beacon period
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.set_time(127_872);
+    radio.set_rx_data(&build_beacon(layout, 1_000_128));
+    device.process().unwrap();
+    assert_eq!(device.take_beacon_state_change(), None);
+    let drift = device
+        .get_mac_layer()
+        .get_network_time()
+        .drift_compensation();
+    assert_eq!(drift, 1_000);
+    let timeout = |device: &ClassB<MockRadio, US915>| {
+        let rx_configs = device.get_mac_layer().get_radio().get_rx_configs();
+        rx_configs.last().unwrap().timeout_ms
+    };
+    assert_eq!(timeout(&device), 30);
+
+    // The next beacon is missed, ping slots go on with wider windows
+    let last_beacon = device.beacon_tracker().last_beacon_time();
+    for (elapsed, expected) in [
+        (128_000, 30 + 2 * 128),
+        (1_800_000, 30 + 2 * 1_800),
+        (7_200_000, 30 + 2 * 7_200),
+    ] {
+        let radio = device.get_mac_layer_mut().get_radio_mut();
+        radio.set_time(last_beacon + elapsed);
+        device.process().unwrap();
+        assert_eq!(device.beacon_tracker().state(), BeaconState::BeaconLess);
+        assert!(device.is_active());
+        assert_eq!(timeout(&device), expected);
+    }
+    assert_eq!(
+        device.take_beacon_state_change(),
+        Some(BeaconState::BeaconLess)
+    );
+    // 28 periods are extrapolated from the last beacon
+    assert_eq!(
+        device
+            .beacon_tracker()
+            .beacon_period(last_beacon + 3_600_000),
+        Some((1_000_128 + 28 * 128, last_beacon + 28 * 128_000))
+    );
+
+    // After 2 hours the device falls back to Class A and stops ping slots
+    let rx_count = device.get_mac_layer().get_radio().get_rx_configs().len();
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.set_time(last_beacon + 7_200_001);
+    device.process().unwrap();
+    assert_eq!(device.take_beacon_state_change(), Some(BeaconState::Lost));
+    assert!(!device.is_active());
+    device.process().unwrap();
+    // Only the widened beacon search window is opened
+    let rx_configs = device.get_mac_layer().get_radio().get_rx_configs();
+    assert_eq!(rx_configs.len(), rx_count + 1);
+    assert_eq!(timeout(&device), 128_880);
+}
+
+/// Build an empty Unconfirmed Data Down frame
+fn build_downlink(session: &SessionState, fcnt: u16) -> Vec<u8, 32> {
+    let mut downlink = Vec::<u8, 32>::new();
+    downlink.push(0x60).unwrap();
+    downlink
+        .extend_from_slice(session.dev_addr.as_bytes())
+        .unwrap();
+    downlink.push(0x00).unwrap();
+    downlink.extend_from_slice(&fcnt.to_le_bytes()).unwrap();
+    let mic = crypto::compute_mic(
+        &session.nwk_skey,
+        &downlink,
+        session.dev_addr,
+        u32::from(fcnt),
+        Direction::Down,
+    );
+    downlink.extend_from_slice(&mic).unwrap();
+    downlink
+}
+
+#[test]
+fn test_class_b_ping_slot_state() {
+    let session = SessionState::new_abp(
+        DevAddr::new([0x04, 0x03, 0x02, 0x01]),
+        AESKey::new([0x11; 16]),
+        AESKey::new([0x22; 16]),
+    );
+    let mut radio = MockRadio::new();
+    radio.set_rx_data(&build_beacon(US915::new().beacon_layout(), 1_234_567_808));
+    let mac = MacLayer::new(radio, US915::new(), session.clone());
+    let mut device = ClassB::new(mac);
+    device.configure_ping_slots(3).unwrap();
+
+    // The beacon is received, the first ping slot stays empty
+    device.process().unwrap();
+    let state = *device.ping_slot_state();
+    assert_eq!(state.opened, 1);
+    assert_eq!(state.consecutive_missed, 1);
+    // pingOffset 130 with a ping period of 256 slots
+    assert_eq!(state.last_slot, Some((1_234_567_808, 2_120 + 130 * 30)));
+
+    // The same slot is not opened twice
+    let rx_count = device.get_mac_layer().get_radio().get_rx_configs().len();
+    device.process().unwrap();
+    assert_eq!(device.ping_slot_state().opened, 1);
+    assert_eq!(
+        device.get_mac_layer().get_radio().get_rx_configs().len(),
+        rx_count
+    );
+
+    // Unconfirmed Data Down in the second ping slot
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.set_time(2_120 + 130 * 30);
+    radio.set_rx_data(&build_downlink(&session, 0));
+    device.process().unwrap();
+    let state = *device.ping_slot_state();
+    assert_eq!(state.opened, 2);
+    assert_eq!(state.received, 1);
+    assert_eq!(state.missed(), 1);
+    assert_eq!(state.consecutive_missed, 0);
+    assert_eq!(
+        state.last_slot,
+        Some((1_234_567_808, 2_120 + (130 + 256) * 30))
+    );
+}
+
+#[test]
+fn test_network_time_drift_from_beacons() {
+    let mut network_time = NetworkTime::new();
+    network_time.update(1_000_000, 5_000);
+    assert_eq!(network_time.drift_compensation(), 0);
+
+    // The local clock counts 127 872 ms for a 128 s beacon period
+    network_time.update(1_000_128, 5_000 + 127_872);
+    assert_eq!(network_time.drift_compensation(), 1_000);
+    // The same beacon again
+    network_time.update(1_000_128, 5_000 + 127_900);
+    assert_eq!(network_time.drift_compensation(), 1_000);
+}
+
+#[test]
+fn test_ping_slot_channel_req_moves_ping_slots() {
+    let mut radio = MockRadio::new();
+    radio.set_rx_data(&build_beacon(US915::new().beacon_layout(), 1_234_567_808));
+    let mac = MacLayer::new(radio, US915::new(), SessionState::new());
+    let mut device = ClassB::new(mac);
+    device.configure_ping_slots(3).unwrap();
+
+    // The network moves the ping slots to 923.9 MHz at DR9
+    let request = MacCommand::PingSlotChannelReq {
+        freq: 923_900_000,
+        data_rate: 9,
+    };
+    device
+        .get_mac_layer_mut()
+        .process_mac_commands(&[request])
+        .unwrap();
+    assert_eq!(
+        device.get_mac_layer().get_pending_commands(),
+        &[
+            MacCommand::PingSlotInfoReq { periodicity: 3 },
+            MacCommand::PingSlotChannelAns {
+                channel_freq_ok: true,
+                data_rate_ok: true,
+            },
+        ]
+    );
+
+    // The first beacon synchronizes the device and the next ping slot opens
+    device.start().unwrap();
+    device.process().unwrap();
+    let rx_config = *device
+        .get_mac_layer()
+        .get_radio()
+        .get_rx_configs()
+        .last()
+        .unwrap();
+    assert_eq!(rx_config.frequency, 923_900_000);
+    assert_eq!(rx_config.modulation.spreading_factor, 11);
+    assert_eq!(rx_config.modulation.bandwidth, 500_000);
+}
+
+#[test]
+fn test_beacon_freq_req_moves_beacon_channel() {
+    let mac = MacLayer::new(MockRadio::new(), US915::new(), SessionState::new());
+    let mut device = ClassB::new(mac);
+
+    // Frequencies outside the region are refused
+    device
+        .get_mac_layer_mut()
+        .process_mac_commands(&[
+            MacCommand::BeaconFreqReq { freq: 868_100_000 },
+            MacCommand::BeaconFreqReq { freq: 925_100_000 },
+        ])
+        .unwrap();
+    assert_eq!(
+        device.get_mac_layer().get_pending_commands(),
+        &[
+            MacCommand::BeaconFreqAns {
+                beacon_freq_ok: false,
+            },
+            MacCommand::BeaconFreqAns {
+                beacon_freq_ok: true,
+            },
+        ]
+    );
+
+    device.start().unwrap();
+    let rx_configs = device.get_mac_layer().get_radio().get_rx_configs();
+    assert_eq!(rx_configs.last().unwrap().frequency, 925_100_000);
+}
+
 #[test]
 fn test_error_recovery() {
     let mut radio = MockRadio::new();
@@ -83,7 +561,11 @@ fn test_error_recovery() {
 fn test_window_switching() {
     let radio = MockRadio::new();
     let region = US915::new();
-    let session = SessionState::new();
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x11; 16]),
+        AESKey::new([0x22; 16]),
+    );
     let mac = MacLayer::new(radio, region, session);
     let mut device = ClassC::new(mac, 923_300_000, 8);
 
@@ -94,3 +576,363 @@ fn test_window_switching() {
     let mut buffer = [0u8; 256];
     assert!(device.receive(&mut buffer).is_ok());
 }
+
+#[test]
+fn test_class_c_acknowledges_confirmed_downlink() {
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x11; 16]),
+        AESKey::new([0x22; 16]),
+    );
+
+    // Confirmed Data Down without FOpts or payload, FCnt 0
+    let mut downlink = Vec::<u8, 32>::new();
+    downlink.push(0xA0).unwrap();
+    downlink
+        .extend_from_slice(session.dev_addr.as_bytes())
+        .unwrap();
+    downlink.extend_from_slice(&[0x00, 0x00, 0x00]).unwrap();
+    let mic = crypto::compute_mic(
+        &session.nwk_skey,
+        &downlink,
+        session.dev_addr,
+        0,
+        Direction::Down,
+    );
+    downlink.extend_from_slice(&mic).unwrap();
+
+    let mut radio = MockRadio::new();
+    radio.set_rx_data(&downlink);
+    let mac = MacLayer::new(radio, US915::new(), session);
+    let mut device = ClassC::new(mac, 923_300_000, 8);
+    device.set_ack_timeout(Some(1_000));
+
+    device.process().unwrap();
+    assert!(device.get_mac_layer().has_pending_ack());
+    assert!(device.get_mac_layer().get_radio().get_last_tx().is_none());
+
+    // No application uplink within the timeout, an empty uplink carries the ACK
+    device.get_mac_layer_mut().get_radio_mut().set_time(1_000);
+    device.process().unwrap();
+    let tx = device.get_mac_layer().get_radio().get_last_tx().unwrap();
+    assert_eq!(tx.len(), 12);
+    assert_eq!(tx[5] & 0x20, 0x20);
+    assert!(!device.get_mac_layer().has_pending_ack());
+}
+
+#[test]
+fn test_class_c_rx_sequence_around_uplink() {
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x11; 16]),
+        AESKey::new([0x22; 16]),
+    );
+    let mac = MacLayer::new(MockRadio::new(), US915::new(), session.clone());
+    let mut device = ClassC::new(mac, 923_300_000, 8);
+    let rx_configs = |device: &ClassC<MockRadio, US915>| {
+        let configs = device.get_mac_layer().get_radio().get_rx_configs();
+        configs
+            .iter()
+            .map(|config| (config.frequency, config.timeout_ms))
+            .collect::<Vec<_, 16>>()
+    };
+
+    // RXC right after the uplink, until RX1 opens
+    device.send_data(1, b"hi", false).unwrap();
+    assert_eq!(&rx_configs(&device)[..], &[(923_300_000, 0)]);
+    device.get_mac_layer_mut().get_radio_mut().set_time(999);
+    device.process().unwrap();
+    assert_eq!(rx_configs(&device).len(), 1);
+
+    // RX1 at one second, then continuous RX2 again
+    let channel = device
+        .get_mac_layer()
+        .get_last_uplink_channel()
+        .cloned()
+        .unwrap();
+    let (rx1_frequency, rx1_data_rate) = device.get_mac_layer().rx1_window(&channel);
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.set_time(1_000);
+    radio.set_rx_data(&build_downlink(&session, 0));
+    device.process().unwrap();
+    assert_eq!(
+        &rx_configs(&device)[1..],
+        &[
+            (rx1_frequency, rx_window_timeout(rx1_data_rate)),
+            (923_300_000, 0),
+        ]
+    );
+    assert_eq!(device.get_mac_layer().get_session_state().fcnt_down, 1);
+
+    // Nothing received, RX2 stays armed
+    device.process().unwrap();
+    assert_eq!(rx_configs(&device).len(), 3);
+
+    // The radio is re-armed after each packet received in RX2
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_rx_data(&build_downlink(&session, 1));
+    device.process().unwrap();
+    assert_eq!(&rx_configs(&device)[3..], &[(923_300_000, 0)]);
+    assert_eq!(device.get_mac_layer().get_session_state().fcnt_down, 2);
+}
+
+/// Build a Class C device with downlinks FCnt 0, 1 and 2 on port 5 to receive
+fn class_c_with_downlinks() -> ClassC<MockRadio, US915> {
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x11; 16]),
+        AESKey::new([0x22; 16]),
+    );
+    let mut radio = MockRadio::new();
+    for fcnt in 0..3u16 {
+        let mut frame = Vec::<u8, 32>::new();
+        frame.push(0x60).unwrap();
+        frame
+            .extend_from_slice(session.dev_addr.as_bytes())
+            .unwrap();
+        frame.push(0x00).unwrap();
+        frame.extend_from_slice(&fcnt.to_le_bytes()).unwrap();
+        frame.push(5).unwrap();
+        let payload = crypto::encrypt_payload(
+            &session.app_skey,
+            session.dev_addr,
+            u32::from(fcnt),
+            Direction::Down,
+            &[fcnt as u8],
+        )
+        .unwrap();
+        frame.extend_from_slice(&payload).unwrap();
+        let mic = crypto::compute_mic(
+            &session.nwk_skey,
+            &frame,
+            session.dev_addr,
+            u32::from(fcnt),
+            Direction::Down,
+        );
+        frame.extend_from_slice(&mic).unwrap();
+        radio.queue_rx_data(&frame);
+    }
+    let mac = MacLayer::new(radio, US915::new(), session);
+    ClassC::new(mac, 923_300_000, 8)
+}
+
+#[test]
+fn test_class_c_downlink_queue() {
+    // Downlinks are queued in order
+    let mut device = class_c_with_downlinks();
+    for _ in 0..3 {
+        device.process().unwrap();
+    }
+    for fcnt in 0..3 {
+        let downlink = device.pop_downlink().unwrap();
+        assert_eq!((downlink.port, downlink.fcnt), (5, fcnt));
+        assert_eq!(&downlink.payload[..], &[fcnt as u8]);
+    }
+    assert!(device.pop_downlink().is_none());
+    assert_eq!(device.dropped_downlinks(), 0);
+
+    // A full queue drops its oldest downlink by default
+    let mut device = class_c_with_downlinks();
+    device.set_downlink_queue_depth(2);
+    for _ in 0..3 {
+        device.process().unwrap();
+    }
+    assert_eq!(device.dropped_downlinks(), 1);
+    assert_eq!(device.pop_downlink().unwrap().fcnt, 1);
+    assert_eq!(device.pop_downlink().unwrap().fcnt, 2);
+    assert!(device.pop_downlink().is_none());
+
+    // Or rejects the new one
+    let mut device = class_c_with_downlinks();
+    device.set_downlink_queue_depth(2);
+    device.set_queue_overflow(QueueOverflow::Reject);
+    for _ in 0..3 {
+        device.process().unwrap();
+    }
+    assert_eq!(device.dropped_downlinks(), 1);
+    assert_eq!(device.pop_downlink().unwrap().fcnt, 0);
+    assert_eq!(device.pop_downlink().unwrap().fcnt, 1);
+    assert!(device.pop_downlink().is_none());
+}
+
+#[test]
+fn test_class_c_downlink_handler() {
+    static RECEIVED: AtomicU32 = AtomicU32::new(0);
+    fn handler(downlink: &Downlink) {
+        // One bit per FCnt, in order
+        let received = RECEIVED.load(Ordering::Relaxed);
+        assert_eq!(received, (1 << downlink.fcnt) - 1);
+        RECEIVED.store(received | 1 << downlink.fcnt, Ordering::Relaxed);
+    }
+
+    let mut device = class_c_with_downlinks();
+    device.set_downlink_handler(Some(handler));
+    for _ in 0..3 {
+        device.process().unwrap();
+    }
+    assert_eq!(RECEIVED.load(Ordering::Relaxed), 0b111);
+    assert!(device.pop_downlink().is_none());
+}
+
+#[test]
+fn test_class_c_uses_session_rx2_params() {
+    let mut session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x11; 16]),
+        AESKey::new([0x22; 16]),
+    );
+    session.rx2_frequency = Some(923_900_000);
+    session.rx2_data_rate = Some(10);
+    let mac = MacLayer::new(MockRadio::new(), US915::new(), session);
+    let mut device = ClassC::new(mac, 923_300_000, 8);
+    device.send_data(1, b"hi", false).unwrap();
+
+    let rx_config = device.get_mac_layer().get_radio().get_rx_configs()[0];
+    assert_eq!(rx_config.frequency, 923_900_000);
+    assert_eq!(rx_config.modulation.spreading_factor, 10);
+    assert_eq!(rx_config.modulation.bandwidth, 500_000);
+    assert_eq!(rx_config.timeout_ms, 0);
+}
+
+/// Build a Data Down frame of a multicast group
+fn build_multicast(group: &MulticastGroup, fcnt: u32, confirmed: bool, data: &[u8]) -> Vec<u8, 64> {
+    let frame = frame::build_data_down(
+        &SoftwareCrypto,
+        &DataFrameParams {
+            confirmed,
+            dev_addr: group.addr,
+            f_ctrl: FCtrl::new(),
+            fcnt,
+            f_opts: &[],
+            f_port: Some(200),
+            payload: data,
+        },
+        &group.nwk_skey,
+        &group.app_skey,
+    )
+    .unwrap();
+    Vec::from_slice(&frame).unwrap()
+}
+
+#[test]
+fn test_class_c_multicast_downlink() {
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x11; 16]),
+        AESKey::new([0x22; 16]),
+    );
+    let mut group = MulticastGroup::new(
+        DevAddr::new([0xAA, 0xBB, 0xCC, 0xDD]),
+        AESKey::new([0x33; 16]),
+        AESKey::new([0x44; 16]),
+    );
+    group.fcnt_down = 7;
+
+    let mut radio = MockRadio::new();
+    for _ in 0..3 {
+        radio.queue_rx_data(&build_multicast(&group, 7, false, b"chunk 0"));
+    }
+    radio.queue_rx_data(&build_multicast(&group, 8, false, b"chunk 1"));
+    radio.queue_rx_data(&build_multicast(&group, 9, true, b"chunk 2"));
+    let mac = MacLayer::new(radio, US915::new(), session);
+    let mut device = ClassC::new(mac, 923_300_000, 8);
+
+    // Addressed to another device until the group is added
+    assert!(matches!(device.process(), Err(MacError::InvalidAddress)));
+    assert!(device.pop_downlink().is_none());
+    device
+        .get_mac_layer_mut()
+        .add_multicast_group(group.clone())
+        .unwrap();
+
+    // Verified and decrypted with the group keys, duplicates are dropped
+    device.process().unwrap();
+    let downlink = device.pop_downlink().unwrap();
+    assert!(downlink.multicast);
+    assert_eq!(downlink.port, 200);
+    assert_eq!(downlink.fcnt, 7);
+    assert_eq!(downlink.payload.as_slice(), b"chunk 0");
+    assert!(matches!(device.process(), Err(MacError::DuplicateFrame)));
+    device.process().unwrap();
+    assert_eq!(
+        device.pop_downlink().unwrap().payload.as_slice(),
+        b"chunk 1"
+    );
+
+    // Confirmed frames are not allowed for multicast
+    assert!(matches!(device.process(), Err(MacError::InvalidFrame)));
+
+    // Only the counter of the group advanced
+    let mac = device.get_mac_layer();
+    assert_eq!(mac.multicast_groups()[0].fcnt_down, 9);
+    assert_eq!(mac.get_session_state().fcnt_down, 0);
+    assert!(!mac.has_pending_ack());
+
+    // Never accepted outside of RXC and the ping slots
+    let mut mac = device.into_mac_layer();
+    let frame = build_multicast(&group, 10, false, b"chunk 3");
+    assert!(matches!(
+        mac.handle_downlink(&frame),
+        Err(MacError::InvalidAddress)
+    ));
+    assert!(mac.remove_multicast_group(group.addr).is_some());
+    assert!(mac.multicast_groups().is_empty());
+}
+
+#[test]
+fn test_class_b_multicast_in_ping_slot() {
+    let session = SessionState::new_abp(
+        DevAddr::new([0x04, 0x03, 0x02, 0x01]),
+        AESKey::new([0x11; 16]),
+        AESKey::new([0x22; 16]),
+    );
+    let group = MulticastGroup::new(
+        DevAddr::new([0xAA, 0xBB, 0xCC, 0xDD]),
+        AESKey::new([0x33; 16]),
+        AESKey::new([0x44; 16]),
+    );
+    let mut radio = MockRadio::new();
+    radio.set_rx_data(&build_beacon(US915::new().beacon_layout(), 1_234_567_808));
+    let mut mac = MacLayer::new(radio, US915::new(), session);
+    mac.add_multicast_group(group.clone()).unwrap();
+    let mut device = ClassB::new(mac);
+    device.configure_ping_slots(3).unwrap();
+    device.process().unwrap();
+
+    // Multicast downlink in the second ping slot
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.set_time(2_120 + 130 * 30);
+    radio.set_rx_data(&build_multicast(&group, 0, false, b"group"));
+    device.process().unwrap();
+    assert_eq!(device.ping_slot_state().received, 1);
+    let downlink = device.get_mac_layer_mut().take_downlink().unwrap();
+    assert!(downlink.multicast);
+    assert_eq!(downlink.payload.as_slice(), b"group");
+}
+
+#[test]
+fn test_network_time_follows_clock() {
+    let clock = MockClock::new();
+    let mut network_time = NetworkTime::new();
+    network_time.set_time_offset(500);
+
+    clock.set(1_000);
+    assert_eq!(network_time.current_time(&clock), 1_500);
+
+    clock.advance(2_000);
+    assert_eq!(network_time.current_time(&clock), 3_500);
+}
+
+#[test]
+fn test_clock_wraps_around() {
+    let clock = MockClock::new();
+    clock.set(u32::MAX - 10);
+    let deadline = clock.now_ms().wrapping_add(20);
+    assert!(!has_elapsed(clock.now_ms(), deadline));
+
+    clock.advance(20);
+    assert!(has_elapsed(clock.now_ms(), deadline));
+    clock.delay_until(deadline);
+}
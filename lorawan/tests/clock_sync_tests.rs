@@ -0,0 +1,143 @@
+#![no_std]
+
+use lorawan::{
+    applayer::clock_sync::{ClockSync, ClockSyncError},
+    class::class_b::timing::NetworkTime,
+};
+
+/// GPS time of the tests in seconds, 0x4D7C6D00
+const GPS_TIME: u32 = 1_300_000_000;
+
+/// Network time synchronized to `GPS_TIME` at local time 0
+fn synced() -> NetworkTime {
+    let mut network_time = NetworkTime::new();
+    network_time.sync_gps_time(GPS_TIME as u64 * 1_000, 0);
+    network_time
+}
+
+#[test]
+fn test_app_time_req_payload() {
+    let mut clock_sync = ClockSync::new();
+
+    // DeviceTime 0x4D7C6D02 after 2.5 s, TokenReq 0 with AnsRequired
+    let request = clock_sync.app_time_req(&synced(), 2_500, true);
+    assert_eq!(request, [0x01, 0x02, 0x6D, 0x7C, 0x4D, 0x10]);
+
+    // Without a network time the device sends 0
+    let request = clock_sync.app_time_req(&NetworkTime::new(), 2_500, false);
+    assert_eq!(request, [0x01, 0x00, 0x00, 0x00, 0x00, 0x00]);
+}
+
+#[test]
+fn test_app_time_ans_correction() {
+    let mut clock_sync = ClockSync::new();
+    let mut network_time = synced();
+    clock_sync.app_time_req(&network_time, 1_000, true);
+
+    // The device is 5 s ahead: TimeCorrection -5, TokenAns 0
+    let answer = [0x01, 0xFB, 0xFF, 0xFF, 0xFF, 0x00];
+    let outcome = clock_sync
+        .handle_downlink(&answer, &mut network_time, 3_000)
+        .unwrap();
+    assert_eq!(outcome.correction, Some(-5));
+    assert!(outcome.answer.is_empty());
+    assert_eq!(clock_sync.last_correction(), Some(-5));
+
+    // Applied to the time of the request, not of the answer
+    let expected_ms = (GPS_TIME as u64 - 5 + 1) * 1_000;
+    assert_eq!(network_time.gps_time_ms_at(1_000), Some(expected_ms));
+    assert_eq!(
+        network_time.gps_time_ms_at(4_000),
+        Some(expected_ms + 3_000)
+    );
+
+    // 9 s later the next request has DeviceTime 0x4D7C6D05 and TokenReq 1
+    let request = clock_sync.app_time_req(&network_time, 10_000, false);
+    assert_eq!(request, [0x01, 0x05, 0x6D, 0x7C, 0x4D, 0x01]);
+}
+
+#[test]
+fn test_app_time_ans_token_matching() {
+    let mut clock_sync = ClockSync::new();
+    let mut network_time = NetworkTime::new();
+
+    // No request sent yet
+    let answer = [0x01, 0x0A, 0x00, 0x00, 0x00, 0x00];
+    let outcome = clock_sync
+        .handle_downlink(&answer, &mut network_time, 0)
+        .unwrap();
+    assert_eq!(outcome.correction, None);
+
+    // An answer to another token is ignored
+    clock_sync.app_time_req(&network_time, 0, true);
+    let stale = [0x01, 0x0A, 0x00, 0x00, 0x00, 0x0F];
+    let outcome = clock_sync
+        .handle_downlink(&stale, &mut network_time, 0)
+        .unwrap();
+    assert_eq!(outcome.correction, None);
+    assert_eq!(network_time.gps_time_ms_at(0), None);
+
+    // From DeviceTime 0 the correction is the GPS time
+    let answer = [0x01, 0x00, 0x6D, 0x7C, 0x4D, 0x00];
+    let outcome = clock_sync
+        .handle_downlink(&answer, &mut network_time, 0)
+        .unwrap();
+    assert_eq!(outcome.correction, Some(GPS_TIME as i32));
+    assert_eq!(
+        network_time.gps_time_ms_at(0),
+        Some(GPS_TIME as u64 * 1_000)
+    );
+    assert_eq!(clock_sync.token(), 1);
+
+    // A second copy of the answer no longer matches
+    let outcome = clock_sync
+        .handle_downlink(&answer, &mut network_time, 0)
+        .unwrap();
+    assert_eq!(outcome.correction, None);
+
+    // The token wraps after 16 requests
+    for _ in 1..16 {
+        clock_sync.app_time_req(&network_time, 0, true);
+        let answer = [0x01, 0x00, 0x00, 0x00, 0x00, clock_sync.token()];
+        clock_sync
+            .handle_downlink(&answer, &mut network_time, 0)
+            .unwrap();
+    }
+    assert_eq!(clock_sync.token(), 0);
+}
+
+#[test]
+fn test_package_commands() {
+    let mut clock_sync = ClockSync::new();
+    let mut network_time = synced();
+
+    // PackageVersionAns: PackageIdentifier 1, PackageVersion 1
+    let outcome = clock_sync
+        .handle_downlink(&[0x00], &mut network_time, 0)
+        .unwrap();
+    assert_eq!(outcome.answer.as_slice(), &[0x00, 0x01, 0x01]);
+
+    // Periodicity not supported, answered with the device time
+    let outcome = clock_sync
+        .handle_downlink(&[0x00, 0x02, 0x05], &mut network_time, 2_000)
+        .unwrap();
+    assert_eq!(
+        outcome.answer.as_slice(),
+        &[0x00, 0x01, 0x01, 0x02, 0x01, 0x02, 0x6D, 0x7C, 0x4D]
+    );
+
+    // ForceDeviceResyncReq with NbTransmissions 3
+    let outcome = clock_sync
+        .handle_downlink(&[0x03, 0xFB], &mut network_time, 0)
+        .unwrap();
+    assert_eq!(outcome.resync_transmissions, 3);
+
+    assert_eq!(
+        clock_sync.handle_downlink(&[0x01, 0x00, 0x00], &mut network_time, 0),
+        Err(ClockSyncError::InvalidLength)
+    );
+    assert_eq!(
+        clock_sync.handle_downlink(&[0x7F], &mut network_time, 0),
+        Err(ClockSyncError::UnknownCommand(0x7F))
+    );
+}
@@ -0,0 +1,77 @@
+#![no_std]
+
+use lorawan::{
+    config::device::{AESKey, DevAddr, SessionError, SessionState},
+    crypto::{CryptoError, SoftwareCrypto},
+    device::{power::PowerState, DeviceError},
+    lorawan::{
+        frame::{self, DataFrameParams, FCtrl, FrameError, MType},
+        mac::{LinkStats, MacError, MacLayer, MacStats},
+        region::{Channel, DataRate, US915},
+    },
+    radio::{sx127x::SX127xError, traits::RadioError},
+};
+
+mod mock;
+use mock::MockRadio;
+
+/// Compiles only if `T` can be logged with defmt
+fn assert_format<T: defmt::Format>() {}
+
+#[test]
+fn test_public_types_implement_format() {
+    assert_format::<MacError<RadioError>>();
+    assert_format::<DeviceError<RadioError>>();
+    assert_format::<SX127xError<RadioError, RadioError, RadioError>>();
+    assert_format::<RadioError>();
+    assert_format::<FrameError>();
+    assert_format::<CryptoError>();
+    assert_format::<SessionError>();
+    assert_format::<FCtrl>();
+    assert_format::<MType>();
+    assert_format::<Channel>();
+    assert_format::<DataRate>();
+    assert_format::<DevAddr>();
+    assert_format::<AESKey>();
+    assert_format::<SessionState>();
+    assert_format::<MacStats>();
+    assert_format::<LinkStats>();
+    assert_format::<PowerState>();
+}
+
+#[test]
+fn test_mac_layer_logs_with_defmt() {
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x11; 16]),
+        AESKey::new([0x22; 16]),
+    );
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), session.clone());
+    mac.send_unconfirmed(1, b"hi").unwrap();
+
+    // A downlink with a forged MIC is logged and dropped
+    let downlink = frame::build_data_down(
+        &SoftwareCrypto,
+        &DataFrameParams {
+            confirmed: false,
+            dev_addr: session.dev_addr,
+            f_ctrl: FCtrl::new(),
+            fcnt: 0,
+            f_opts: &[],
+            f_port: Some(1),
+            payload: b"hi",
+        },
+        &AESKey::new([0x33; 16]),
+        &session.app_skey,
+    )
+    .unwrap();
+    mac.get_radio_mut().set_rx_data(&downlink);
+    let mut buffer = [0u8; 64];
+    let len = mac
+        .receive_window(923_300_000, DataRate::SF10BW500, &mut buffer)
+        .unwrap();
+    assert!(matches!(
+        mac.receive_downlink(&buffer[..len]),
+        Err(MacError::InvalidMic)
+    ));
+}
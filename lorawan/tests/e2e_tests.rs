@@ -0,0 +1,452 @@
+#![no_std]
+
+use heapless::Vec;
+use lorawan::{
+    class::OperatingMode,
+    config::device::{AESKey, DevAddr, DeviceConfig},
+    crypto,
+    device::{DeviceEvent, LoRaWANDevice},
+    lorawan::{
+        commands::MacCommand,
+        region::{Region, US915},
+    },
+};
+
+mod mock;
+mod simulator;
+use mock::MockRadio;
+use simulator::{NetworkServer, Window};
+
+const DEV_EUI: [u8; 8] = [0x70, 0xB3, 0xD5, 0x7E, 0xD0, 0x00, 0x00, 0x01];
+const APP_EUI: [u8; 8] = [0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+fn app_key() -> AESKey {
+    AESKey::new([0x2B; 16])
+}
+
+/// Device under test and the network server it talks to
+struct Network {
+    device: LoRaWANDevice<MockRadio, US915>,
+    server: NetworkServer,
+    now: u32,
+    /// Receive windows of the downlinks delivered
+    windows: Vec<Window, 16>,
+}
+
+impl Network {
+    fn new() -> Self {
+        let mut region = US915::new();
+        region.set_sub_band(1);
+        let config = DeviceConfig::new_otaa(DEV_EUI, APP_EUI, app_key());
+        let device =
+            LoRaWANDevice::new(MockRadio::new(), config, region, OperatingMode::ClassA).unwrap();
+        Self {
+            device,
+            server: NetworkServer::new(DEV_EUI, app_key()),
+            now: 1_000,
+            windows: Vec::new(),
+        }
+    }
+
+    fn radio(&mut self) -> &mut MockRadio {
+        self.device.get_mac_layer_mut().get_radio_mut()
+    }
+
+    /// Poll the device until the exchange ends, delivering its frames to the server
+    fn run(&mut self) -> Vec<DeviceEvent, 8> {
+        let mut events = Vec::new();
+        loop {
+            let now = self.now;
+            self.radio().set_time(now);
+            let sent = self.radio().get_tx_log().len();
+            let event = self.device.poll(now).unwrap();
+
+            let radio = self.device.get_mac_layer_mut().get_radio_mut();
+            if let Some((_, frame)) = radio.get_tx_log().get(sent) {
+                let frame = frame.clone();
+                if let Some(downlink) = self.server.handle_uplink(&frame, now).unwrap() {
+                    radio.schedule_rx_data(&downlink.frame, downlink.at);
+                    self.windows.push(downlink.window).unwrap();
+                }
+            }
+
+            if let Some(event) = event {
+                events.push(event).unwrap();
+                continue;
+            }
+            match self.device.next_rx_window() {
+                Some((opens_at, _, _)) => self.now = opens_at,
+                None if self.device.is_busy() => self.now += 100,
+                None => break,
+            }
+        }
+        // Leave some time between exchanges
+        self.now += 10_000;
+        events
+    }
+
+    /// Join the network
+    fn join(&mut self) {
+        self.device.start_join(DEV_EUI, APP_EUI, app_key()).unwrap();
+        let events = self.run();
+        assert!(matches!(
+            events[..],
+            [DeviceEvent::TxComplete, DeviceEvent::JoinAccepted]
+        ));
+    }
+
+    /// Send an uplink and run the exchange
+    fn uplink(&mut self, port: u8, data: &[u8], confirmed: bool) -> Vec<DeviceEvent, 8> {
+        self.device.start_uplink(port, data, confirmed).unwrap();
+        self.run()
+    }
+}
+
+#[test]
+fn test_e2e_otaa_join() {
+    let mut network = Network::new();
+    let dev_addr = DevAddr::new([0x78, 0x56, 0x34, 0x26]);
+    network
+        .server
+        .set_join_params([0x0A, 0x0B, 0x0C], [0x13, 0x00, 0x00], dev_addr);
+    // RX1DROffset 2, RX2 DR8, RX1 after 2 s
+    network.server.set_rx_params(0x28, 2);
+    network.join();
+
+    let session = network.device.get_session_state();
+    let server = network.server.session().unwrap();
+    assert!(session.is_joined());
+    assert_eq!(session.dev_addr, dev_addr);
+    assert_eq!(session.nwk_skey.as_bytes(), server.nwk_skey.as_bytes());
+    assert_eq!(session.app_skey.as_bytes(), server.app_skey.as_bytes());
+    assert_eq!(session.rx1_dr_offset, 2);
+    assert_eq!(session.rx2_data_rate, Some(8));
+    assert_eq!(session.rx1_delay, 2);
+
+    // The join request went out on a channel of sub-band 2
+    let (tx_config, _) = &network.radio().get_tx_log()[0];
+    assert!((903_900_000..=905_300_000).contains(&tx_config.frequency));
+    assert_eq!(network.windows[..], [Window::Rx1]);
+
+    // The first uplink uses the session, the server answers after RxDelay
+    let events = network.uplink(1, b"first", true);
+    assert!(events
+        .iter()
+        .any(|event| matches!(event, DeviceEvent::AckReceived)));
+    assert_eq!(network.server.uplinks()[0].fcnt, 0);
+}
+
+#[test]
+fn test_e2e_confirmed_uplink_ack() {
+    let mut network = Network::new();
+    network.join();
+
+    let events = network.uplink(1, b"ping", true);
+    assert!(matches!(
+        events[..],
+        [DeviceEvent::TxComplete, DeviceEvent::AckReceived]
+    ));
+    let uplink = &network.server.uplinks()[0];
+    assert!(uplink.confirmed);
+    assert_eq!(uplink.port, Some(1));
+    assert_eq!(uplink.payload.as_slice(), b"ping");
+
+    // Acknowledged in RX2
+    network.server.answer_in_rx2(true);
+    let events = network.uplink(1, b"ping", true);
+    assert!(events
+        .iter()
+        .any(|event| matches!(event, DeviceEvent::AckReceived)));
+    assert_eq!(network.windows.last(), Some(&Window::Rx2));
+    assert_eq!(network.server.uplinks()[1].fcnt, 1);
+
+    // Application data for an unconfirmed uplink
+    network.server.answer_in_rx2(false);
+    network.server.queue_downlink(5, b"data");
+    let events = network.uplink(2, b"pong", false);
+    match &events[..] {
+        [DeviceEvent::TxComplete, DeviceEvent::DownlinkReceived(downlink)] => {
+            assert_eq!(downlink.port, 5);
+            assert_eq!(downlink.payload.as_slice(), b"data");
+        }
+        _ => panic!("Expected a downlink, got {:?}", events),
+    }
+
+    // Nothing to answer to an unconfirmed uplink
+    let events = network.uplink(2, b"pong", false);
+    assert!(matches!(events[..], [DeviceEvent::TxComplete]));
+}
+
+#[test]
+fn test_e2e_link_adr_req() {
+    let mut network = Network::new();
+    network.join();
+
+    // DR3, TX power 2, channels 8-15, one transmission
+    network
+        .server
+        .queue_mac_command(&[0x03, 0x32, 0x00, 0xFF, 0x01]);
+    let events = network.uplink(1, b"adr", false);
+    assert!(events.iter().any(|event| matches!(
+        event,
+        DeviceEvent::MacCommandApplied(MacCommand::LinkADRReq { .. })
+    )));
+    let region = network.device.get_mac_layer().get_region();
+    assert_eq!(region.data_rate(), 3);
+    assert_eq!(region.tx_power(), 2);
+
+    // The answer goes out with the next uplink, at the new data rate
+    network.uplink(1, b"adr", false);
+    let uplink = network.server.uplinks().last().unwrap();
+    assert_eq!(uplink.mac_commands.as_slice(), &[0x03, 0x07]);
+    let (tx_config, _) = network.radio().get_tx_log().last().unwrap().clone();
+    assert_eq!(tx_config.modulation.spreading_factor, 7);
+    assert!((903_900_000..=905_300_000).contains(&tx_config.frequency));
+}
+
+#[test]
+fn test_e2e_fopts_commands_with_app_data() {
+    let mut network = Network::new();
+    network.join();
+
+    // LinkADRReq in FOpts next to application data on port 5
+    network
+        .server
+        .queue_mac_command(&[0x03, 0x32, 0x00, 0xFF, 0x01]);
+    network.server.queue_downlink(5, b"config");
+    let events = network.uplink(1, b"adr", false);
+    assert!(events.iter().any(|event| matches!(
+        event,
+        DeviceEvent::MacCommandApplied(MacCommand::LinkADRReq { .. })
+    )));
+    let downlink = events
+        .iter()
+        .find_map(|event| match event {
+            DeviceEvent::DownlinkReceived(downlink) => Some(downlink),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(downlink.port, 5);
+    assert_eq!(downlink.payload.as_slice(), b"config");
+    let region = network.device.get_mac_layer().get_region();
+    assert_eq!(region.data_rate(), 3);
+    assert_eq!(region.tx_power(), 2);
+
+    // The answer goes out in FOpts of the next uplink
+    network.uplink(1, b"adr", false);
+    let uplink = network.server.uplinks().last().unwrap();
+    assert_eq!(uplink.mac_commands.as_slice(), &[0x03, 0x07]);
+    assert_eq!(uplink.payload.as_slice(), b"adr");
+}
+
+#[test]
+fn test_e2e_dev_status() {
+    let mut network = Network::new();
+    network.join();
+    network.radio().set_snr(7);
+
+    network.server.queue_mac_command(&[0x06]);
+    let events = network.uplink(1, b"status", false);
+    assert!(events.iter().any(|event| matches!(
+        event,
+        DeviceEvent::MacCommandApplied(MacCommand::DevStatusReq)
+    )));
+
+    network.uplink(1, b"status", false);
+    let uplink = network.server.uplinks().last().unwrap();
+    assert_eq!(uplink.mac_commands.len(), 3);
+    assert_eq!(uplink.mac_commands[0], 0x06);
+    // Margin is the SNR of the downlink carrying the request
+    assert_eq!(uplink.mac_commands[2], 7);
+}
+
+#[test]
+fn test_e2e_fcnt_rollover() {
+    let mut network = Network::new();
+    network.join();
+    network.device.set_frame_counters(0xFFFE, 0);
+    network.server.set_fcnt_up(0xFFFE);
+
+    // The server extends the 16-bit FCnt and verifies the MIC across the boundary
+    for _ in 0..3 {
+        let events = network.uplink(1, b"roll", true);
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, DeviceEvent::AckReceived)));
+    }
+    // Including after lost frames
+    network.device.set_frame_counters(0x1_0010, 3);
+    let events = network.uplink(1, b"roll", true);
+    assert!(events
+        .iter()
+        .any(|event| matches!(event, DeviceEvent::AckReceived)));
+
+    let fcnts: Vec<u32, 4> = network
+        .server
+        .uplinks()
+        .iter()
+        .map(|uplink| uplink.fcnt)
+        .collect();
+    assert_eq!(fcnts[..], [0xFFFE, 0xFFFF, 0x1_0000, 0x1_0010]);
+}
+
+#[test]
+fn test_e2e_rejoin_at_threshold() {
+    let mut network = Network::new();
+    network.device.set_rejoin_threshold(2);
+    network.device.set_auto_rejoin(true);
+    network.join();
+    let first_session = network.server.session().unwrap().clone();
+
+    let events = network.uplink(1, b"one", false);
+    assert!(matches!(events[..], [DeviceEvent::TxComplete]));
+
+    // The uplink reaching the threshold is followed by a join
+    let events = network.uplink(1, b"two", false);
+    assert!(matches!(
+        events[..],
+        [
+            DeviceEvent::TxComplete,
+            DeviceEvent::RejoinSuggested { fcnt_up: 2 },
+            DeviceEvent::TxComplete,
+            DeviceEvent::JoinAccepted
+        ]
+    ));
+    let session = network.device.get_session_state();
+    assert_eq!(session.fcnt_up, 0);
+    assert_ne!(
+        session.app_skey.as_bytes(),
+        first_session.app_skey.as_bytes()
+    );
+
+    // The new session works, the event is reported once per session
+    let events = network.uplink(1, b"three", false);
+    assert!(matches!(events[..], [DeviceEvent::TxComplete]));
+    assert_eq!(network.server.uplinks()[0].fcnt, 0);
+}
+
+#[test]
+fn test_e2e_clock_sync() {
+    let mut network = Network::new();
+    network.join();
+
+    // AppTimeReq on port 202, answered with a correction of 1300000000 s
+    network
+        .server
+        .queue_downlink(202, &[0x01, 0x00, 0x6D, 0x7C, 0x4D, 0x00]);
+    network.device.request_time_sync().unwrap();
+    let events = network.run();
+    assert!(matches!(
+        events[..],
+        [
+            DeviceEvent::TxComplete,
+            DeviceEvent::TimeSynced {
+                correction_s: 1_300_000_000
+            }
+        ]
+    ));
+    let uplink = network.server.uplinks().last().unwrap();
+    assert_eq!(uplink.port, Some(202));
+    // DeviceTime 0 before the first synchronization, AnsRequired
+    assert_eq!(uplink.payload.as_slice(), &[0x01, 0, 0, 0, 0, 0x10]);
+    let now = network.now;
+    let gps_time_ms = network.device.network_time().gps_time_ms_at(now).unwrap();
+    assert!(gps_time_ms > 1_300_000_000_000);
+
+    // PackageVersionReq is answered in the next uplink
+    network.server.queue_downlink(202, &[0x00]);
+    let events = network.uplink(1, b"data", false);
+    assert!(matches!(
+        events[..],
+        [DeviceEvent::TxComplete, DeviceEvent::TxComplete]
+    ));
+    let uplink = network.server.uplinks().last().unwrap();
+    assert_eq!(uplink.port, Some(202));
+    assert_eq!(uplink.payload.as_slice(), &[0x00, 0x01, 0x01]);
+
+    // Port 202 is application data while the package is disabled
+    network.device.set_clock_sync(false);
+    network.server.queue_downlink(202, &[0x00]);
+    let events = network.uplink(1, b"data", false);
+    assert!(matches!(
+        &events[..],
+        [DeviceEvent::TxComplete, DeviceEvent::DownlinkReceived(downlink)] if downlink.port == 202
+    ));
+}
+
+#[test]
+fn test_e2e_multicast_session() {
+    let mut network = Network::new();
+    network.join();
+    network
+        .server
+        .queue_downlink(202, &[0x01, 0x00, 0x6D, 0x7C, 0x4D, 0x00]);
+    network.device.request_time_sync().unwrap();
+    network.run();
+
+    // McGroupSetupReq for group 0 with McKey encrypted by McKEKey
+    let gen_app_key = AESKey::new([0x5A; 16]);
+    network.device.set_multicast_setup(Some(&gen_app_key));
+    let mc_ke_key = crypto::derive_mc_ke_key(&gen_app_key);
+    let mc_key = crypto::wrap_mc_key(&mc_ke_key, &AESKey::new([0x3C; 16]));
+    let mut request = [0u8; 30];
+    request[0] = 0x02;
+    request[2..6].copy_from_slice(&[0x01, 0x02, 0x03, 0x04]);
+    request[6..22].copy_from_slice(&mc_key);
+    request[26..30].copy_from_slice(&100u32.to_le_bytes());
+    network.server.queue_downlink(200, &request);
+    let events = network.uplink(1, b"data", false);
+    assert!(matches!(
+        events[..],
+        [DeviceEvent::TxComplete, DeviceEvent::TxComplete]
+    ));
+    let uplink = network.server.uplinks().last().unwrap();
+    assert_eq!(uplink.port, Some(200));
+    assert_eq!(uplink.payload.as_slice(), &[0x02, 0x00]);
+    let group = &network.device.multicast_groups()[0];
+    assert_eq!(group.addr, DevAddr::new([0x01, 0x02, 0x03, 0x04]));
+    let (_, app_skey) = crypto::derive_mc_session_keys(&AESKey::new([0x3C; 16]), group.addr);
+    assert_eq!(group.app_skey.as_bytes(), app_skey.as_bytes());
+
+    // McClassCSessionReq starting in 30 s for 2^4 s on 923.3 MHz DR8
+    let now = network.now;
+    let gps_time_ms = network.device.network_time().gps_time_ms_at(now).unwrap();
+    let session_time = (gps_time_ms / 1_000) as u32 + 30;
+    let mut request = [0x04, 0x00, 0, 0, 0, 0, 0x04, 0x68, 0xE2, 0x8C, 0x08];
+    request[2..6].copy_from_slice(&session_time.to_le_bytes());
+    network.server.queue_downlink(200, &request);
+    network.uplink(1, b"data", false);
+    let uplink = network.server.uplinks().last().unwrap();
+    assert_eq!(uplink.port, Some(200));
+    assert_eq!(uplink.payload[..2], [0x04, 0x00]);
+    assert_eq!(network.device.multicast_session().unwrap().duration_s, 16);
+    let default_rx2 = network.device.get_mac_layer().rx2_window().0;
+
+    // The device switches to Class C for the session and back to Class A
+    let mut events: Vec<(u32, DeviceEvent), 4> = Vec::new();
+    let start = network.now;
+    while events.len() < 2 && network.now < start + 60_000 {
+        network.now += 500;
+        let now = network.now;
+        network.radio().set_time(now);
+        if let Some(event) = network.device.poll(now).unwrap() {
+            if matches!(event, DeviceEvent::MulticastSessionStarted { group_id: 0 }) {
+                assert_eq!(network.device.operating_mode(), OperatingMode::ClassC);
+                let rx2_frequency = network.device.get_mac_layer().rx2_window().0;
+                assert_eq!(rx2_frequency, 923_300_000);
+            }
+            events.push((now, event)).unwrap();
+        }
+    }
+    assert!(matches!(
+        events[..],
+        [
+            (_, DeviceEvent::MulticastSessionStarted { group_id: 0 }),
+            (_, DeviceEvent::MulticastSessionEnded { group_id: 0 })
+        ]
+    ));
+    assert!((16_000..=17_000).contains(&(events[1].0 - events[0].0)));
+    assert_eq!(network.device.operating_mode(), OperatingMode::ClassA);
+    assert_eq!(network.device.get_mac_layer().rx2_window().0, default_rx2);
+    assert!(network.device.multicast_session().is_none());
+}
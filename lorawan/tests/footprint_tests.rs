@@ -0,0 +1,94 @@
+#![no_std]
+
+//! RAM footprint of the main types
+//!
+//! The MAC layer owns the only frame buffer of the receive path. The device
+//! classes, `LoRaWANDevice::poll` and confirmed uplinks receive into it
+//! instead of a 256-byte array on the stack each, and uplinks are encrypted
+//! in place in the frame being built instead of in a 242-byte copy.
+//!
+//! Sizes in bytes on a 64-bit host, without the radio driver:
+//!
+//! | Type                | Before | After | After, `max-frame-64` |
+//! |---------------------|--------|-------|-----------------------|
+//! | `MacLayer`          | 2536   | 2792  | 2408                  |
+//! | `ClassA`            | 2568   | 2824  | 2440                  |
+//! | `ClassC`            | 4792   | 5048  | 3128                  |
+//! | `LoRaWANDevice`     | 5272   | 5528  | 3416                  |
+//! | `DownlinkFrame`     | 304    | 304   | 112                   |
+//! | `Downlink`          | 272    | 272   | 80                    |
+//!
+//! The MAC layer grew by its frame buffer, while the stack of `process`,
+//! `poll` and confirmed uplinks shrank by 256 bytes, and that of every
+//! uplink by 242 bytes. The tests check the sizes against the frame size so
+//! that new fields do not go unnoticed.
+//!
+//! The table of `MAX_MULTICAST_GROUPS` multicast groups later added 216
+//! bytes to the MAC layer and the types holding it, the state of the clock
+//! synchronization package 24 bytes to the device. The remote multicast
+//! setup package added 80 bytes and its two scheduled sessions, each with
+//! the settings restored after it, 48 bytes each.
+
+use core::mem::size_of;
+use lorawan::{
+    applayer::{clock_sync::ClockSync, multicast_setup::MulticastSetup},
+    class::{
+        class_a::ClassA,
+        class_c::{ClassC, MAX_DOWNLINK_QUEUE_DEPTH},
+    },
+    device::LoRaWANDevice,
+    lorawan::{
+        frame::{MAX_FRAME_SIZE, MAX_MAC_PAYLOAD},
+        mac::{Downlink, DownlinkFrame, MacLayer},
+        multicast::{MulticastGroup, MAX_MULTICAST_GROUPS},
+        region::US915,
+    },
+};
+
+mod mock;
+use mock::MockRadio;
+
+/// Size of the MAC layer state besides the frame buffer, the downlink and
+/// the multicast groups
+const MAC_STATE_SIZE: usize = 2_304;
+
+/// Get the size of a type holding a `MockRadio`, without the radio
+fn size_without_radio<T>() -> usize {
+    size_of::<T>() - size_of::<MockRadio>()
+}
+
+#[test]
+fn test_frame_types_follow_frame_size() {
+    // A frame with the largest FRMPayload fits in the frame buffers
+    const { assert!(1 + 7 + 1 + MAX_MAC_PAYLOAD + 4 <= MAX_FRAME_SIZE) };
+    assert!(size_of::<Downlink>() <= MAX_MAC_PAYLOAD + 32);
+    assert!(size_of::<DownlinkFrame>() <= MAX_MAC_PAYLOAD + 64);
+}
+
+#[test]
+fn test_mac_layer_size() {
+    let mac = size_without_radio::<MacLayer<MockRadio, US915>>();
+    assert!(mac >= MAX_FRAME_SIZE);
+    let groups = size_of::<[MulticastGroup; MAX_MULTICAST_GROUPS]>();
+    assert!(mac <= MAX_FRAME_SIZE + size_of::<Option<Downlink>>() + groups + MAC_STATE_SIZE);
+}
+
+#[test]
+fn test_class_sizes() {
+    let mac = size_of::<MacLayer<MockRadio, US915>>();
+
+    // Class A adds its receive window state only
+    assert!(size_of::<ClassA<MockRadio, US915>>() <= mac + 64);
+
+    // Class C adds its downlink queue
+    let queue = MAX_DOWNLINK_QUEUE_DEPTH * size_of::<Downlink>();
+    let class_c = size_of::<ClassC<MockRadio, US915>>();
+    assert!(class_c <= mac + queue + 128);
+
+    // The device adds the payload of the next uplink and the application
+    // layer packages to the largest class
+    let device = size_of::<LoRaWANDevice<MockRadio, US915>>();
+    let sessions = 2 * 48;
+    let packages = size_of::<Option<ClockSync>>() + size_of::<Option<MulticastSetup>>() + sessions;
+    assert!(device <= class_c + MAX_MAC_PAYLOAD + packages + 256);
+}
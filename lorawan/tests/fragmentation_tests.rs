@@ -0,0 +1,344 @@
+#![no_std]
+
+use lorawan::applayer::fragmentation::{
+    FragmentStore, Fragmentation, FragmentationError, FRAGMENTATION_PORT,
+};
+
+/// Number of uncoded fragments of the tests
+const NB_FRAG: u16 = 20;
+
+/// Fragment size of the tests
+const FRAG_SIZE: usize = 12;
+
+/// Missing fragments the tests can recover
+const MAX_MISSING: u16 = 8;
+
+/// Fragment store in RAM
+struct RamStore {
+    data: [u8; 1024],
+    /// Fail all writes, like a worn out flash
+    broken: bool,
+}
+
+impl RamStore {
+    fn new() -> Self {
+        Self {
+            data: [0xA5; 1024],
+            broken: false,
+        }
+    }
+}
+
+impl FragmentStore for RamStore {
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    fn write_block(&mut self, offset: usize, data: &[u8]) -> bool {
+        if self.broken {
+            return false;
+        }
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+        true
+    }
+
+    fn read_block(&mut self, offset: usize, data: &mut [u8]) -> bool {
+        data.copy_from_slice(&self.data[offset..offset + data.len()]);
+        true
+    }
+}
+
+/// Data block of the tests, `NB_FRAG` fragments minus 5 bytes of padding
+fn block() -> [u8; NB_FRAG as usize * FRAG_SIZE] {
+    let mut block = [0; NB_FRAG as usize * FRAG_SIZE];
+    for (i, byte) in block.iter_mut().enumerate() {
+        *byte = (i * 7 + i / 13) as u8;
+    }
+    let len = block.len();
+    block[len - 5..].fill(0);
+    block
+}
+
+/// PRBS of the fragmentation matrix, from the TS004 pseudo-code
+fn prbs23(x: u32) -> u32 {
+    let b0 = x & 1;
+    let b1 = (x & 32) / 32;
+    x / 2 + (b0 ^ b1) * (1 << 22)
+}
+
+/// Row of coded fragment `n`, from the TS004 pseudo-code
+fn matrix_line(n: u32, m: u32) -> [bool; NB_FRAG as usize] {
+    let mut line = [false; NB_FRAG as usize];
+    let power2 = if m & (m - 1) == 0 { 1 } else { 0 };
+    let mut x = 1 + 1001 * n;
+    for _ in 0..m / 2 {
+        let mut r = 1 << 16;
+        while r >= m {
+            x = prbs23(x);
+            r = x % (m + power2);
+        }
+        line[r as usize] = true;
+    }
+    line
+}
+
+/// Build DataFragment `n` of the block, coded after the uncoded fragments
+fn data_fragment(block: &[u8], n: u16) -> [u8; 3 + FRAG_SIZE] {
+    let mut frame = [0; 3 + FRAG_SIZE];
+    frame[0] = 0x08;
+    frame[1..3].copy_from_slice(&n.to_le_bytes());
+    let fragment = &mut frame[3..];
+    if n <= NB_FRAG {
+        let start = (n as usize - 1) * FRAG_SIZE;
+        fragment.copy_from_slice(&block[start..start + FRAG_SIZE]);
+    } else {
+        let line = matrix_line((n - NB_FRAG) as u32, NB_FRAG as u32);
+        for (i, _) in line.iter().enumerate().filter(|(_, &set)| set) {
+            let start = i * FRAG_SIZE;
+            for (byte, data) in fragment.iter_mut().zip(&block[start..start + FRAG_SIZE]) {
+                *byte ^= data;
+            }
+        }
+    }
+    frame
+}
+
+/// FragSessionSetupReq of session 0 for the test block
+const SETUP: [u8; 11] = [
+    0x02,
+    0x01,
+    NB_FRAG as u8,
+    0x00,
+    FRAG_SIZE as u8,
+    0x00,
+    0x05,
+    0x78,
+    0x56,
+    0x34,
+    0x12,
+];
+
+fn setup() -> Fragmentation<RamStore> {
+    let mut fragmentation = Fragmentation::new(RamStore::new(), MAX_MISSING);
+    let outcome = fragmentation.handle_downlink(&SETUP).unwrap();
+    assert_eq!(outcome.answer.as_slice(), &[0x02, 0x00]);
+    fragmentation
+}
+
+fn assert_block(fragmentation: &mut Fragmentation<RamStore>) {
+    let block = block();
+    let mut data = [0; NB_FRAG as usize * FRAG_SIZE - 5];
+    fragmentation.read_data(0, &mut data).unwrap();
+    assert_eq!(&data[..], &block[..data.len()]);
+}
+
+#[test]
+fn test_session_setup() {
+    assert_eq!(FRAGMENTATION_PORT, 201);
+    let fragmentation = setup();
+    let session = fragmentation.session().unwrap();
+    assert_eq!(session.mc_group_mask, 0x01);
+    assert_eq!(session.nb_frag, NB_FRAG);
+    assert_eq!(session.frag_size as usize, FRAG_SIZE);
+    assert_eq!(session.block_ack_delay_s, 32);
+    assert_eq!(session.data_len(), NB_FRAG as usize * FRAG_SIZE - 5);
+    assert_eq!(session.descriptor, 0x1234_5678);
+    assert!(
+        Fragmentation::<RamStore>::required_capacity(NB_FRAG, FRAG_SIZE as u8, MAX_MISSING) <= 1024
+    );
+
+    let mut fragmentation = Fragmentation::new(RamStore::new(), MAX_MISSING);
+
+    // PackageVersionAns: PackageIdentifier 3, PackageVersion 1
+    let outcome = fragmentation.handle_downlink(&[0x00]).unwrap();
+    assert_eq!(outcome.answer.as_slice(), &[0x00, 0x03, 0x01]);
+
+    // Fragmentation matrix 1 and session 2 are not supported
+    let mut request = SETUP;
+    request[5] = 0x08;
+    request[1] = 0x20;
+    let outcome = fragmentation.handle_downlink(&request).unwrap();
+    assert_eq!(outcome.answer.as_slice(), &[0x02, 0x85]);
+
+    // 400 fragments do not fit in the store
+    let mut request = SETUP;
+    request[2..4].copy_from_slice(&400u16.to_le_bytes());
+    let outcome = fragmentation.handle_downlink(&request).unwrap();
+    assert_eq!(outcome.answer.as_slice(), &[0x02, 0x02]);
+    assert!(fragmentation.session().is_none());
+
+    assert_eq!(
+        fragmentation.handle_downlink(&SETUP[..8]),
+        Err(FragmentationError::InvalidLength)
+    );
+    assert_eq!(
+        fragmentation.handle_downlink(&[0x10]),
+        Err(FragmentationError::UnknownCommand(0x10))
+    );
+
+    // FragSessionDeleteReq, then the session no longer exists
+    let mut fragmentation = setup();
+    let outcome = fragmentation.handle_downlink(&[0x03, 0x00]).unwrap();
+    assert_eq!(outcome.answer.as_slice(), &[0x03, 0x00]);
+    let outcome = fragmentation.handle_downlink(&[0x03, 0x00]).unwrap();
+    assert_eq!(outcome.answer.as_slice(), &[0x03, 0x04]);
+
+    fragmentation.store_mut().broken = true;
+    assert_eq!(
+        fragmentation.handle_downlink(&SETUP),
+        Err(FragmentationError::Store)
+    );
+}
+
+#[test]
+fn test_uncoded_fragments() {
+    let block = block();
+    let mut fragmentation = setup();
+
+    for n in 1..NB_FRAG {
+        let outcome = fragmentation
+            .handle_downlink(&data_fragment(&block, n))
+            .unwrap();
+        assert!(!outcome.complete);
+    }
+    // A repeated fragment counts as received but changes nothing
+    fragmentation
+        .handle_downlink(&data_fragment(&block, 3))
+        .unwrap();
+    let outcome = fragmentation
+        .handle_downlink(&data_fragment(&block, NB_FRAG))
+        .unwrap();
+    assert!(outcome.complete);
+    assert!(fragmentation.is_complete());
+    assert_block(&mut fragmentation);
+
+    // Coded fragments after completion are ignored
+    let outcome = fragmentation
+        .handle_downlink(&data_fragment(&block, NB_FRAG + 1))
+        .unwrap();
+    assert!(!outcome.complete);
+    assert_block(&mut fragmentation);
+
+    // Reading beyond the data block fails
+    let mut data = [0; 8];
+    assert_eq!(
+        fragmentation.read_data(NB_FRAG as usize * FRAG_SIZE - 7, &mut data),
+        Err(FragmentationError::InvalidLength)
+    );
+}
+
+#[test]
+fn test_missing_fragments_recovered() {
+    let block = block();
+    let mut fragmentation = setup();
+
+    // Fragments 1, 7, 8 and 20 are lost
+    let lost = [1, 7, 8, 20];
+    for n in (1..=NB_FRAG).filter(|n| !lost.contains(n)) {
+        fragmentation
+            .handle_downlink(&data_fragment(&block, n))
+            .unwrap();
+    }
+    assert!(!fragmentation.is_complete());
+
+    let mut completed_by = None;
+    for n in NB_FRAG + 1..=NB_FRAG + 12 {
+        let outcome = fragmentation
+            .handle_downlink(&data_fragment(&block, n))
+            .unwrap();
+        if outcome.complete {
+            completed_by = Some(n);
+            break;
+        }
+    }
+    // At least one coded fragment per lost fragment is needed
+    let completed_by = completed_by.expect("block not reconstructed");
+    assert!(completed_by >= NB_FRAG + lost.len() as u16);
+    assert!(fragmentation.is_complete());
+    assert_block(&mut fragmentation);
+}
+
+#[test]
+fn test_coded_fragments_lost_too() {
+    let block = block();
+    let mut fragmentation = setup();
+
+    // Every fifth fragment is lost, uncoded and coded
+    for n in (1..=NB_FRAG + 16).filter(|n| n % 5 != 0) {
+        fragmentation
+            .handle_downlink(&data_fragment(&block, n))
+            .unwrap();
+        if fragmentation.is_complete() {
+            break;
+        }
+    }
+    assert!(fragmentation.is_complete());
+    assert_block(&mut fragmentation);
+}
+
+#[test]
+fn test_session_status() {
+    let block = block();
+    let mut fragmentation = setup();
+
+    for n in (1..=NB_FRAG).filter(|&n| n != 2 && n != 9) {
+        fragmentation
+            .handle_downlink(&data_fragment(&block, n))
+            .unwrap();
+    }
+    // 18 received, 2 missing
+    let outcome = fragmentation.handle_downlink(&[0x01, 0x00]).unwrap();
+    assert_eq!(outcome.answer.as_slice(), &[0x01, 18, 0x00, 2, 0x00]);
+
+    // Session 1 does not exist
+    let outcome = fragmentation.handle_downlink(&[0x01, 0x03]).unwrap();
+    assert!(outcome.answer.is_empty());
+
+    let mut n = NB_FRAG;
+    while !fragmentation.is_complete() {
+        n += 1;
+        fragmentation
+            .handle_downlink(&data_fragment(&block, n))
+            .unwrap();
+    }
+    assert_block(&mut fragmentation);
+
+    // Once complete only answered when all participants are asked
+    let outcome = fragmentation.handle_downlink(&[0x01, 0x00]).unwrap();
+    assert!(outcome.answer.is_empty());
+    let outcome = fragmentation.handle_downlink(&[0x01, 0x01]).unwrap();
+    let received = 18 + (n - NB_FRAG) as u8;
+    assert_eq!(outcome.answer.as_slice(), &[0x01, received, 0x00, 0, 0x00]);
+}
+
+#[test]
+fn test_too_many_missing_fragments() {
+    let block = block();
+    let mut fragmentation = setup();
+
+    // 10 lost fragments, more than the matrix holds
+    for n in (1..=NB_FRAG).filter(|n| n % 2 == 0) {
+        fragmentation
+            .handle_downlink(&data_fragment(&block, n))
+            .unwrap();
+    }
+    for n in NB_FRAG + 1..=NB_FRAG + 20 {
+        fragmentation
+            .handle_downlink(&data_fragment(&block, n))
+            .unwrap();
+    }
+    assert!(!fragmentation.is_complete());
+
+    let outcome = fragmentation.handle_downlink(&[0x01, 0x00]).unwrap();
+    assert_eq!(outcome.answer.as_slice(), &[0x01, 30, 0x00, 10, 0x01]);
+
+    // A new session starts over
+    fragmentation.handle_downlink(&SETUP).unwrap();
+    for n in 1..=NB_FRAG {
+        fragmentation
+            .handle_downlink(&data_fragment(&block, n))
+            .unwrap();
+    }
+    assert!(fragmentation.is_complete());
+    assert_block(&mut fragmentation);
+}
@@ -0,0 +1,430 @@
+#![no_std]
+
+use lorawan::{
+    config::device::{AESKey, DevAddr, SessionState},
+    crypto::{self, Direction, SoftwareCrypto},
+    lorawan::{
+        frame::{
+            self, DataFrameParams, FCtrl, FrameError, JoinAccept, MType, PhyPayload, FHDR,
+            JOIN_REQUEST_SIZE, MAX_MAC_PAYLOAD, MIN_FHDR_SIZE,
+        },
+        mac::MacLayer,
+        region::US915,
+    },
+};
+
+use heapless::Vec;
+mod mock;
+use mock::MockRadio;
+
+fn nwk_skey() -> AESKey {
+    AESKey::new([0x11; 16])
+}
+
+fn app_skey() -> AESKey {
+    AESKey::new([0x22; 16])
+}
+
+fn app_key() -> AESKey {
+    AESKey::new([0x2B; 16])
+}
+
+fn dev_addr() -> DevAddr {
+    DevAddr::new([0x01, 0x02, 0x03, 0x04])
+}
+
+fn data_params<'a>(f_opts: &'a [u8], f_port: Option<u8>, payload: &'a [u8]) -> DataFrameParams<'a> {
+    DataFrameParams {
+        confirmed: false,
+        dev_addr: dev_addr(),
+        f_ctrl: FCtrl::new(),
+        fcnt: 0x0001_0005,
+        f_opts,
+        f_port,
+        payload,
+    }
+}
+
+#[test]
+fn test_mtype_from_mhdr() {
+    assert_eq!(MType::from_mhdr(0x00), Some(MType::JoinRequest));
+    assert_eq!(MType::from_mhdr(0x20), Some(MType::JoinAccept));
+    assert_eq!(MType::from_mhdr(0x40), Some(MType::UnconfirmedDataUp));
+    assert_eq!(MType::from_mhdr(0x60), Some(MType::UnconfirmedDataDown));
+    assert_eq!(MType::from_mhdr(0x80), Some(MType::ConfirmedDataUp));
+    assert_eq!(MType::from_mhdr(0xA0), Some(MType::ConfirmedDataDown));
+    assert_eq!(MType::from_mhdr(0xC0), None);
+    assert_eq!(MType::from_mhdr(0xE0), Some(MType::Proprietary));
+    assert_eq!(MType::ConfirmedDataDown.mhdr(), 0xA0);
+}
+
+#[test]
+fn test_join_request_round_trip() {
+    let app_eui = [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01];
+    let dev_eui = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
+    let data = frame::build_join_request(&SoftwareCrypto, &app_key(), app_eui, dev_eui, 0x1234);
+    assert_eq!(data.len(), JOIN_REQUEST_SIZE);
+    assert_eq!(data[0], 0x00);
+    assert_eq!(&data[17..19], &[0x34, 0x12]);
+    assert_eq!(
+        &data[19..],
+        &crypto::compute_join_request_mic(&app_key(), &data[..19])
+    );
+
+    let PhyPayload::JoinRequest(request) = PhyPayload::parse(&data).unwrap() else {
+        panic!("not a join request");
+    };
+    assert_eq!(request.app_eui, app_eui);
+    assert_eq!(request.dev_eui, dev_eui);
+    assert_eq!(request.dev_nonce, 0x1234);
+    assert!(request.verify_mic(&SoftwareCrypto, &app_key()));
+    assert!(!request.verify_mic(&SoftwareCrypto, &nwk_skey()));
+}
+
+#[test]
+fn test_join_accept_round_trip() {
+    let mut join_accept = JoinAccept {
+        app_nonce: [0x01, 0x02, 0x03],
+        net_id: [0x13, 0x00, 0x00],
+        dev_addr: dev_addr(),
+        dl_settings: 0x12,
+        rx_delay: 0x05,
+        cf_list: None,
+    };
+
+    for cf_list in [None, Some([0xA5; 16])] {
+        join_accept.cf_list = cf_list;
+        let data = frame::build_join_accept(&app_key(), &join_accept);
+        assert_eq!(data.len(), if cf_list.is_some() { 33 } else { 17 });
+
+        let PhyPayload::JoinAccept(encrypted) = PhyPayload::parse(&data).unwrap() else {
+            panic!("not a join accept");
+        };
+        let decrypted = encrypted.decrypt(&SoftwareCrypto, &app_key()).unwrap();
+        assert_eq!(decrypted, join_accept);
+        assert_eq!(decrypted.join_nonce(), 0x03_0201);
+        assert_eq!(
+            encrypted.decrypt(&SoftwareCrypto, &nwk_skey()),
+            Err(FrameError::InvalidMic)
+        );
+    }
+}
+
+#[test]
+fn test_data_up_round_trip() {
+    let mut params = data_params(&[0x02], Some(10), b"hello");
+    params.confirmed = true;
+    params.f_ctrl.adr = true;
+    params.f_ctrl.adr_ack_req = true;
+    params.f_ctrl.class_b = true;
+    let data = frame::build_data_up(&SoftwareCrypto, &params, &nwk_skey(), &app_skey()).unwrap();
+    assert_eq!(data[0], 0x80);
+    assert_eq!(data[5], 0xD1);
+    assert_eq!(&data[6..8], &[0x05, 0x00]);
+    assert_eq!(data.len(), 1 + 7 + 1 + 1 + 5 + 4);
+
+    let PhyPayload::DataUp(frame) = PhyPayload::parse(&data).unwrap() else {
+        panic!("not an uplink");
+    };
+    assert!(frame.confirmed);
+    assert_eq!(frame.direction, Direction::Up);
+    assert_eq!(frame.fhdr.dev_addr, dev_addr());
+    assert_eq!(frame.fhdr.f_cnt, 5);
+    assert_eq!(frame.fhdr.f_opts.as_slice(), &[0x02]);
+    let f_ctrl = frame.fhdr.f_ctrl;
+    assert!(f_ctrl.adr && f_ctrl.adr_ack_req && f_ctrl.class_b && !f_ctrl.fpending);
+    assert_eq!(f_ctrl.foptslen, 1);
+    assert_eq!(frame.f_port, Some(10));
+    assert_ne!(frame.frm_payload, b"hello");
+
+    // The MIC covers the full 32-bit counter
+    assert!(frame.verify_mic(&SoftwareCrypto, &nwk_skey(), 0x0001_0005));
+    assert!(!frame.verify_mic(&SoftwareCrypto, &nwk_skey(), 5));
+    assert!(!frame.verify_mic(&SoftwareCrypto, &app_skey(), 0x0001_0005));
+    let payload = frame
+        .decrypt_payload(&SoftwareCrypto, &nwk_skey(), &app_skey(), 0x0001_0005)
+        .unwrap();
+    assert_eq!(payload.as_slice(), b"hello");
+}
+
+#[test]
+fn test_data_down_round_trip() {
+    // Port 0 FRMPayload is encrypted with the NwkSKey
+    let mut params = data_params(&[], Some(0), &[0x06]);
+    params.f_ctrl.ack = true;
+    params.f_ctrl.fpending = true;
+    let data = frame::build_data_down(&SoftwareCrypto, &params, &nwk_skey(), &app_skey()).unwrap();
+    assert_eq!(data[0], 0x60);
+    let expected = crypto::encrypt_payload(
+        &nwk_skey(),
+        dev_addr(),
+        0x0001_0005,
+        Direction::Down,
+        &[0x06],
+    )
+    .unwrap();
+    assert_eq!(&data[9..10], expected.as_slice());
+
+    let PhyPayload::DataDown(frame) = PhyPayload::parse(&data).unwrap() else {
+        panic!("not a downlink");
+    };
+    assert!(!frame.confirmed);
+    assert_eq!(frame.direction, Direction::Down);
+    assert!(frame.fhdr.f_ctrl.ack && frame.fhdr.f_ctrl.fpending);
+    assert_eq!(frame.f_port, Some(0));
+    assert!(frame.verify_mic(&SoftwareCrypto, &nwk_skey(), 0x0001_0005));
+    let payload = frame
+        .decrypt_payload(&SoftwareCrypto, &nwk_skey(), &app_skey(), 0x0001_0005)
+        .unwrap();
+    assert_eq!(payload.as_slice(), &[0x06]);
+
+    // Without FRMPayload there is no port
+    let data = frame::build_data_down(
+        &SoftwareCrypto,
+        &data_params(&[0x06], None, &[]),
+        &nwk_skey(),
+        &app_skey(),
+    )
+    .unwrap();
+    assert_eq!(data.len(), 1 + 7 + 1 + 4);
+    let PhyPayload::DataDown(frame) = PhyPayload::parse(&data).unwrap() else {
+        panic!("not a downlink");
+    };
+    assert_eq!(frame.f_port, None);
+    assert!(frame.frm_payload.is_empty());
+
+    // Maximum payload
+    let payload = [0x5A; MAX_MAC_PAYLOAD];
+    let data = frame::build_data_down(
+        &SoftwareCrypto,
+        &data_params(&[], Some(1), &payload),
+        &nwk_skey(),
+        &app_skey(),
+    )
+    .unwrap();
+    let PhyPayload::DataDown(frame) = PhyPayload::parse(&data).unwrap() else {
+        panic!("not a downlink");
+    };
+    assert_eq!(
+        frame
+            .decrypt_payload(&SoftwareCrypto, &nwk_skey(), &app_skey(), 0x0001_0005)
+            .unwrap()
+            .as_slice(),
+        &payload[..]
+    );
+}
+
+#[test]
+fn test_fhdr_fopts_round_trip() {
+    let options = [0x06; 15];
+    for len in 0..=options.len() {
+        // A stale FOptsLen is replaced by the length of FOpts
+        let mut f_ctrl = FCtrl::new();
+        f_ctrl.ack = true;
+        f_ctrl.foptslen = 0x0F - len as u8;
+        let fhdr = FHDR {
+            dev_addr: dev_addr(),
+            f_ctrl,
+            f_cnt: 0x1234,
+            f_opts: Vec::from_slice(&options[..len]).unwrap(),
+        };
+        let data = fhdr.serialize();
+        assert_eq!(data.len(), MIN_FHDR_SIZE + len);
+        assert_eq!(data[4], 0x20 | len as u8);
+
+        let (parsed, size) = FHDR::parse(&data).unwrap();
+        assert_eq!(size, data.len());
+        assert_eq!(parsed.f_ctrl, FCtrl::from_byte(0x20 | len as u8));
+        assert_eq!(parsed.f_cnt, 0x1234);
+        assert_eq!(parsed.f_opts.as_slice(), &options[..len]);
+
+        // FOpts is truncated
+        if len > 0 {
+            assert!(FHDR::parse(&data[..data.len() - 1]).is_none());
+        }
+
+        // The frame codec agrees, FPort and FRMPayload follow FOpts
+        let data = frame::build_data_down(
+            &SoftwareCrypto,
+            &data_params(&options[..len], Some(3), b"app"),
+            &nwk_skey(),
+            &app_skey(),
+        )
+        .unwrap();
+        assert_eq!(data.len(), 1 + MIN_FHDR_SIZE + len + 1 + 3 + 4);
+        let PhyPayload::DataDown(frame) = PhyPayload::parse(&data).unwrap() else {
+            panic!("not a downlink");
+        };
+        assert_eq!(frame.fhdr.f_ctrl.foptslen as usize, len);
+        assert_eq!(frame.fhdr.f_opts.as_slice(), &options[..len]);
+        assert_eq!(frame.f_port, Some(3));
+        assert!(frame.verify_mic(&SoftwareCrypto, &nwk_skey(), 0x0001_0005));
+    }
+}
+
+#[test]
+fn test_build_data_rejects_bad_fields() {
+    let build = |params: &DataFrameParams| {
+        frame::build_data_up(&SoftwareCrypto, params, &nwk_skey(), &app_skey())
+    };
+    assert_eq!(
+        build(&data_params(&[], None, b"hi")),
+        Err(FrameError::InvalidFrame)
+    );
+    assert_eq!(
+        build(&data_params(&[0x02], Some(0), &[0x02])),
+        Err(FrameError::InvalidFrame)
+    );
+    assert_eq!(
+        build(&data_params(&[0x02; 16], None, &[])),
+        Err(FrameError::InvalidLength)
+    );
+    assert_eq!(
+        build(&data_params(&[], Some(1), &[0; MAX_MAC_PAYLOAD + 1])),
+        Err(FrameError::InvalidLength)
+    );
+}
+
+#[test]
+fn test_parse_rejects_malformed_frames() {
+    assert_eq!(
+        PhyPayload::parse(&[]).err(),
+        Some(FrameError::InvalidLength)
+    );
+
+    let data = frame::build_data_down(
+        &SoftwareCrypto,
+        &data_params(&[0x06], Some(3), b"data"),
+        &nwk_skey(),
+        &app_skey(),
+    )
+    .unwrap();
+
+    // Major version, proprietary and RFU message types
+    for mhdr in [0x61, 0xC0, 0xE0] {
+        let mut bad = data.clone();
+        bad[0] = mhdr;
+        assert_eq!(
+            PhyPayload::parse(&bad).err(),
+            Some(FrameError::InvalidFrame)
+        );
+    }
+
+    // FOpts longer than the frame
+    let mut bad = data.clone();
+    bad[5] = 0x0F;
+    assert_eq!(
+        PhyPayload::parse(&bad).err(),
+        Some(FrameError::InvalidLength)
+    );
+
+    // MAC commands in FOpts and on port 0
+    let mut bad = data.clone();
+    bad[9] = 0;
+    assert_eq!(
+        PhyPayload::parse(&bad).err(),
+        Some(FrameError::InvalidFrame)
+    );
+
+    // Join requests and join accepts have fixed sizes
+    let join_request = frame::build_join_request(&SoftwareCrypto, &app_key(), [1; 8], [2; 8], 1);
+    assert_eq!(
+        PhyPayload::parse(&join_request[..22]).err(),
+        Some(FrameError::InvalidLength)
+    );
+    let mut join_accept = Vec::<u8, 40>::from_slice(&[0x20; 18]).unwrap();
+    assert_eq!(
+        PhyPayload::parse(&join_accept).err(),
+        Some(FrameError::InvalidLength)
+    );
+    join_accept.truncate(17);
+    assert!(matches!(
+        PhyPayload::parse(&join_accept),
+        Ok(PhyPayload::JoinAccept(_))
+    ));
+
+    // Every truncation of a valid frame is rejected or parsed, never panics
+    for len in 0..data.len() {
+        let result = PhyPayload::parse(&data[..len]);
+        if len < 12 {
+            assert_eq!(result.err(), Some(FrameError::InvalidLength));
+        }
+    }
+}
+
+#[test]
+fn test_parse_random_frames() {
+    // Random frames of every length must not panic, and a structurally
+    // valid data frame must not pass the MIC check
+    let mut seed = 0x1234_5678u32;
+    let mut next = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed
+    };
+    let mut buffer = [0u8; 270];
+    for len in 0..buffer.len() {
+        for _ in 0..8 {
+            for byte in &mut buffer[..len] {
+                *byte = next() as u8;
+            }
+            match PhyPayload::parse(&buffer[..len]) {
+                Ok(PhyPayload::DataUp(frame)) | Ok(PhyPayload::DataDown(frame)) => {
+                    assert!(frame.frm_payload.len() <= MAX_MAC_PAYLOAD);
+                    assert!(!frame.verify_mic(&SoftwareCrypto, &nwk_skey(), 0));
+                    let _ = frame.decrypt_payload(&SoftwareCrypto, &nwk_skey(), &app_skey(), 0);
+                }
+                Ok(PhyPayload::JoinAccept(join_accept)) => {
+                    assert!(join_accept.decrypt(&SoftwareCrypto, &app_key()).is_err());
+                }
+                Ok(PhyPayload::JoinRequest(request)) => {
+                    assert!(!request.verify_mic(&SoftwareCrypto, &app_key()));
+                }
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+#[test]
+fn test_mac_layer_frames_match_codec() {
+    let mut session = SessionState::new_abp(dev_addr(), nwk_skey(), app_skey());
+    session.fcnt_up = 7;
+
+    let mut region = US915::new();
+    region.set_sub_band(1);
+    let mut mac = MacLayer::new(MockRadio::new(), region, session);
+    mac.send_unconfirmed(2, b"ping").unwrap();
+
+    let data = mac.get_radio().get_last_tx().unwrap();
+    let PhyPayload::DataUp(frame) = PhyPayload::parse(data).unwrap() else {
+        panic!("not an uplink");
+    };
+    assert!(!frame.confirmed);
+    assert_eq!(frame.fhdr.f_cnt, 7);
+    assert_eq!(frame.f_port, Some(2));
+    assert!(frame.verify_mic(&SoftwareCrypto, &nwk_skey(), 7));
+    assert_eq!(
+        frame
+            .decrypt_payload(&SoftwareCrypto, &nwk_skey(), &app_skey(), 7)
+            .unwrap()
+            .as_slice(),
+        b"ping"
+    );
+
+    // A downlink built by the codec is accepted by the MAC layer
+    let downlink = frame::build_data_down(
+        &SoftwareCrypto,
+        &DataFrameParams {
+            fcnt: 0,
+            ..data_params(&[], Some(5), b"pong")
+        },
+        &nwk_skey(),
+        &app_skey(),
+    )
+    .unwrap();
+    let received = mac.receive_downlink(&downlink).unwrap();
+    assert_eq!(received.f_port, Some(5));
+    assert_eq!(received.payload.as_slice(), b"pong");
+}
@@ -0,0 +1,228 @@
+#![no_std]
+
+use lorawan::{
+    class::class_b::beacon::{beacon_crc, BeaconFrame},
+    config::device::{AESKey, DevAddr, SessionState, SESSION_STATE_SIZE},
+    crypto::{self, SoftwareCrypto},
+    lorawan::{
+        commands::MacCommand,
+        frame::{self, DataFrameParams, FCtrl, PhyPayload, FHDR},
+        mac::MacLayer,
+        region::{BeaconLayout, RU864, US915},
+    },
+};
+
+mod mock;
+use mock::MockRadio;
+
+/// Pseudo-random buffers fed to each parser, none of them may panic
+const ITERATIONS: usize = 4_000;
+
+/// Xorshift generator, so failures can be reproduced
+struct Rng(u32);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    /// Fill the start of `buffer` with random bytes, up to `max_len` of them
+    fn fill<'a>(&mut self, buffer: &'a mut [u8], max_len: usize) -> &'a mut [u8] {
+        let len = self.next() as usize % (max_len.min(buffer.len()) + 1);
+        for byte in &mut buffer[..len] {
+            *byte = self.next() as u8;
+        }
+        &mut buffer[..len]
+    }
+}
+
+fn abp_session() -> SessionState {
+    SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x11; 16]),
+        AESKey::new([0x22; 16]),
+    )
+}
+
+#[test]
+fn test_fuzz_phy_payload() {
+    let mut rng = Rng(0x1357_9BDF);
+    let mut buffer = [0u8; 300];
+    let key = AESKey::new([0x11; 16]);
+    for _ in 0..ITERATIONS {
+        let data = rng.fill(&mut buffer, 300);
+        // Bias towards valid message types
+        if let Some(mhdr) = data.first_mut() {
+            *mhdr &= 0xE0;
+        }
+        match PhyPayload::parse(data) {
+            Ok(PhyPayload::DataUp(frame)) | Ok(PhyPayload::DataDown(frame)) => {
+                let _ = frame.verify_mic(&SoftwareCrypto, &key, rng.next());
+                let _ = frame.decrypt_payload(&SoftwareCrypto, &key, &key, rng.next());
+            }
+            Ok(PhyPayload::JoinAccept(join_accept)) => {
+                let _ = join_accept.decrypt(&SoftwareCrypto, &key);
+            }
+            Ok(PhyPayload::JoinRequest(request)) => {
+                let _ = request.verify_mic(&SoftwareCrypto, &key);
+            }
+            Err(_) => {}
+        }
+        let _ = FHDR::parse(data);
+    }
+}
+
+#[test]
+fn test_fuzz_mac_commands() {
+    let mut rng = Rng(0x2468_ACE0);
+    let mut buffer = [0u8; 32];
+    let mac = MacLayer::new(MockRadio::new(), US915::new(), abp_session());
+    for _ in 0..ITERATIONS {
+        let data = rng.fill(&mut buffer, 32);
+        if let Some((&cid, payload)) = data.split_first() {
+            for command in [
+                MacCommand::from_bytes(cid, payload),
+                MacCommand::from_uplink_bytes(cid, payload),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                assert!(command.len() <= payload.len());
+            }
+        }
+        if let Some(commands) = mac.extract_mac_commands(data) {
+            let len: usize = commands.iter().map(|c| 1 + c.len()).sum();
+            assert_eq!(len, data.len());
+        }
+    }
+
+    // Truncated commands at the end of a payload
+    for len in 0..6 {
+        let payload = [0x07, 0x03, 0x18, 0x4F, 0x84, 0x50];
+        assert_eq!(
+            mac.extract_mac_commands(&payload[..len]).is_some(),
+            len == 0 || len == 6
+        );
+    }
+}
+
+#[test]
+fn test_fuzz_downlink_mac_commands() {
+    // Frames with a valid MIC carrying random MAC commands reach the
+    // command handlers of the MAC layer
+    let mut rng = Rng(0x0BAD_CAFE);
+    let mut buffer = [0u8; 64];
+    let session = abp_session();
+    let mut us915 = MacLayer::new(MockRadio::new(), US915::new(), session.clone());
+    let mut ru864 = MacLayer::new(MockRadio::new(), RU864::new(), session.clone());
+    for fcnt in 0..ITERATIONS as u32 {
+        let commands = rng.fill(&mut buffer, 48);
+        let (f_opts, f_port, payload): (&[u8], _, &[u8]) = if fcnt % 2 == 0 {
+            (&commands[..commands.len().min(15)], None, &[])
+        } else {
+            (&[], Some(0), commands)
+        };
+        let downlink = frame::build_data_down(
+            &SoftwareCrypto,
+            &DataFrameParams {
+                confirmed: fcnt % 3 == 0,
+                dev_addr: session.dev_addr,
+                f_ctrl: FCtrl::new(),
+                fcnt,
+                f_opts,
+                f_port,
+                payload,
+            },
+            &session.nwk_skey,
+            &session.app_skey,
+        )
+        .unwrap();
+        let _ = us915.handle_downlink(&downlink);
+        let _ = ru864.handle_downlink(&downlink);
+    }
+}
+
+#[test]
+fn test_fuzz_join_accept() {
+    let mut rng = Rng(0xDEAD_BEEF);
+    let mut buffer = [0u8; 40];
+    let app_key = AESKey::new([0x2B; 16]);
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), SessionState::new());
+    mac.join_request([0x01; 8], [0x02; 8], app_key.clone())
+        .unwrap();
+    for i in 0..ITERATIONS {
+        let data = rng.fill(&mut buffer, 40);
+        if i % 2 == 0 {
+            if let Some(mhdr) = data.first_mut() {
+                *mhdr = 0x20;
+            }
+        }
+        assert!(mac.process_join_accept(data).is_err());
+        assert!(mac.is_join_pending());
+    }
+
+    // Join accepts with a valid MIC and random fields are processed
+    for _ in 0..64 {
+        let mut fields = [0u8; 12];
+        rng.fill(&mut fields, 12);
+        let mut message = [0u8; 13];
+        message[0] = 0x20;
+        message[1..].copy_from_slice(&fields);
+        let mut frame = [0u8; 17];
+        frame[0] = 0x20;
+        let mic = crypto::compute_join_accept_mic(&app_key, &message);
+        let mut plain = [0u8; 16];
+        plain[..12].copy_from_slice(&fields);
+        plain[12..].copy_from_slice(&mic);
+        frame[1..].copy_from_slice(&crypto::encrypt_join_accept(&app_key, &plain));
+        let _ = mac.process_join_accept(&frame);
+    }
+}
+
+#[test]
+fn test_fuzz_beacon() {
+    let mut rng = Rng(0x7777_1111);
+    let mut buffer = [0u8; 32];
+    let layouts = [
+        BeaconLayout::DEFAULT,
+        BeaconLayout { rfu1: 3, rfu2: 1 },
+        BeaconLayout { rfu1: 5, rfu2: 3 },
+    ];
+    for i in 0..ITERATIONS {
+        let layout = layouts[i % layouts.len()];
+        let data = rng.fill(&mut buffer, 32);
+        let _ = BeaconFrame::parse(data, layout);
+
+        // Random fields with valid CRCs
+        let data = &mut buffer[..layout.size()];
+        for byte in data.iter_mut() {
+            *byte = rng.next() as u8;
+        }
+        let first = layout.rfu1 + 4;
+        let crc = beacon_crc(&data[..first]);
+        data[first..first + 2].copy_from_slice(&crc.to_le_bytes());
+        let end = data.len() - 2;
+        let crc = beacon_crc(&data[first + 2..end]);
+        data[end..].copy_from_slice(&crc.to_le_bytes());
+        assert!(BeaconFrame::parse(data, layout).is_some());
+    }
+}
+
+#[test]
+fn test_fuzz_session_state() {
+    let mut rng = Rng(0x4242_4242);
+    let mut buffer = [0u8; SESSION_STATE_SIZE + 4];
+    for _ in 0..ITERATIONS {
+        let data = rng.fill(&mut buffer, SESSION_STATE_SIZE + 4);
+        let _ = SessionState::from_bytes(data);
+    }
+    let mut data = abp_session().to_bytes();
+    for i in 0..SESSION_STATE_SIZE {
+        data[i] ^= rng.next() as u8 | 1;
+        assert!(SessionState::from_bytes(&data).is_err());
+        data[i] = abp_session().to_bytes()[i];
+    }
+}
@@ -1,92 +1,839 @@
 #![no_std]
 
+// Status providers are leaked for the `&'static mut` the MAC layer keeps
+extern crate alloc;
+
+use alloc::boxed::Box;
 use lorawan::{
-    class::OperatingMode,
-    config::device::{AESKey, DevAddr, DeviceConfig},
-    crypto,
-    device::LoRaWANDevice,
-    lorawan::{commands::MacCommand, region::US915},
+    class::{class_a::ClassA, DeviceClass, OperatingMode},
+    config::device::{
+        AESKey, ActivationState, DevAddr, DevNonceStore, DeviceConfig, FrameCounterStore,
+        SessionError, SessionState, MAX_FCNT_GAP,
+    },
+    crypto::{self, CryptoBackend, Direction, SoftwareCrypto, BLOCK_SIZE},
+    device::{
+        power::{PowerState, StatusProvider},
+        DeviceError, DeviceEvent, JoinPolicy, LoRaWANDevice,
+    },
+    lorawan::{
+        commands::MacCommand,
+        frame::{MType, JOIN_REQUEST_SIZE},
+        mac::{
+            rx_window_timeout, AdrState, ConfirmedResult, LinkStats, MacError, MacLayer,
+            ADR_ACK_DELAY, ADR_ACK_LIMIT, DEFAULT_CONFIRMED_ATTEMPTS, MAX_MAC_COMMANDS,
+        },
+        phy::{NetworkType, TimingParams},
+        region::{DataRate, Region, RU864, US915},
+        replay::{ReplayCache, DEFAULT_REPLAY_CACHE_SIZE, MAX_REPLAY_CACHE_SIZE},
+    },
+    radio::traits::{Radio, RadioCapabilities, RxConfig, TxConfig},
 };
 
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
 use heapless::Vec;
 mod mock;
-use mock::MockRadio;
-
-// #[test]
-// fn test_join_procedure() {
-//     let mut mock_radio = MockRadio::new();
-//     let dev_eui = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
-//     let app_eui = [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01];
-//     let app_key = AESKey::new([
-//         0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F,
-//         0x10,
-//     ]);
-
-//     // First create the join accept payload
-//     let mut join_accept_payload = Vec::<u8, 32>::new();
-//     join_accept_payload.extend_from_slice(&[
-//         0x01, 0x02, 0x03,      // AppNonce
-//         0x04, 0x05, 0x06,      // NetID
-//         0x07, 0x08, 0x09, 0x0A, // DevAddr
-//         0x00,                   // DLSettings
-//         0x01,                   // RxDelay
-//     ]).unwrap();
-
-//     // Create the full message with MHDR
-//     let mut full_message = Vec::<u8, 32>::new();
-//     full_message.push(0x20).unwrap();  // MHDR for join-accept
-//     full_message.extend_from_slice(&join_accept_payload).unwrap();
-
-//     // Calculate MIC over MHDR|JoinAcceptPayload
-//     let mic = crypto::compute_mic(
-//         &app_key,
-//         &full_message,
-//         DevAddr::new([0; 4]),
-//         0,
-//         crypto::Direction::Down
-//     );
-//     full_message.extend_from_slice(&mic).unwrap();
-
-//     // Encrypt the message (except MHDR)
-//     let encrypted_accept = crypto::encrypt_join_accept(&app_key, &full_message);
-
-//     // Set up mock radio before creating device
-//     mock_radio.simulate_join_accept(&encrypted_accept);
-
-//     let config = DeviceConfig::new_otaa(dev_eui, app_eui, app_key.clone());
-//     let mut device = LoRaWANDevice::new(
-//         mock_radio,
-//         config,
-//         US915::new(),
-//         OperatingMode::ClassA,
-//     )
-//     .expect("Failed to create device");
-
-//     // Attempt join
-//     device.join_otaa(dev_eui, app_eui, app_key.clone())
-//         .expect("Join failed");
-
-//     // Process join accept
-//     let mut rx_buffer = [0u8; 256];
-//     device.process().expect("Failed to process");
-//     let rx_size = device.receive(&mut rx_buffer).expect("Failed to receive");
-//     assert!(rx_size > 0, "No join accept received");
-
-//     // Verify session state
-//     let session = device.get_session_state();
-//     assert!(session.is_joined(), "Device should be joined");
-//     assert_eq!(session.dev_addr.as_bytes(), &[0x07, 0x08, 0x09, 0x0A]);
-
-//     // Verify session keys
-//     let (nwk_skey, app_skey) = crypto::derive_session_keys(
-//         &app_key,
-//         &[0x01, 0x02, 0x03],
-//         &[0x04, 0x05, 0x06],
-//         0x0000,
-//     );
-//     assert_eq!(session.nwk_skey.as_bytes(), nwk_skey.as_bytes());
-//     assert_eq!(session.app_skey.as_bytes(), app_skey.as_bytes());
-// }
+use mock::{MockCall, MockClock, MockRadio};
+
+#[test]
+fn test_join_procedure() {
+    let mut mock_radio = MockRadio::new();
+    let dev_eui = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
+    let app_eui = [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01];
+    let app_key = AESKey::new([
+        0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F,
+        0x10,
+    ]);
+
+    // Create the full message with MHDR
+    let mut full_message = Vec::<u8, 32>::new();
+    full_message.push(0x20).unwrap(); // MHDR for join-accept
+    full_message
+        .extend_from_slice(&[
+            0x01, 0x02, 0x03, // AppNonce
+            0x04, 0x05, 0x06, // NetID
+            0x07, 0x08, 0x09, 0x0A, // DevAddr
+            0x28, // DLSettings (RX1DROffset 2, RX2 DR8)
+            0x05, // RxDelay
+        ])
+        .unwrap();
+
+    // Calculate MIC over MHDR|JoinAcceptPayload
+    let mic = crypto::compute_join_accept_mic(&app_key, &full_message);
+    full_message.extend_from_slice(&mic).unwrap();
+
+    // Encrypt the message (except MHDR)
+    let mut join_accept = Vec::<u8, 32>::new();
+    join_accept.push(full_message[0]).unwrap();
+    join_accept
+        .extend_from_slice(&crypto::encrypt_join_accept(&app_key, &full_message[1..]))
+        .unwrap();
+
+    // The network answers in RX1, 5 s after the join request, and the first
+    // transmission fails
+    mock_radio.schedule_rx_data(&join_accept, 5_000);
+    mock_radio.fail_next(MockCall::Transmit);
+
+    let config = DeviceConfig::new_otaa(dev_eui, app_eui, app_key.clone());
+    let mut device = LoRaWANDevice::new(mock_radio, config, US915::new(), OperatingMode::ClassA)
+        .expect("Failed to create device");
+
+    // Attempt join
+    assert!(device.join_otaa(dev_eui, app_eui, app_key.clone()).is_err());
+    device
+        .join_otaa(dev_eui, app_eui, app_key.clone())
+        .expect("Join failed");
+    let radio = device.get_mac_layer().get_radio();
+    let tx_log = radio.get_tx_log();
+    assert_eq!(tx_log.len(), 1);
+    let (tx_config, join_request) = &tx_log[0];
+    assert_eq!(join_request.len(), JOIN_REQUEST_SIZE);
+    assert_eq!(join_request[0], MType::JoinRequest.mhdr());
+    assert_eq!(tx_config.frequency, radio.get_last_tx_frequency());
+    assert_eq!(radio.get_tx_configs().len(), 2);
+    let dev_nonce = u16::from_le_bytes([join_request[17], join_request[18]]);
+
+    // Nothing is received before RX1
+    device.process().expect("Failed to process");
+    let session = device.get_session_state();
+    assert_eq!(session.activation_state, ActivationState::Joining);
+    assert!(!session.is_joined());
+
+    // Process join accept
+    device.get_mac_layer_mut().get_radio_mut().set_time(5_000);
+    device.process().expect("Failed to process");
+    assert_eq!(device.get_mac_layer().get_radio().scheduled_rx_count(), 0);
+
+    // Verify session state
+    let session = device.get_session_state();
+    assert!(session.is_joined(), "Device should be joined");
+    assert_eq!(session.activation_state, ActivationState::OTAAActivated);
+    assert_eq!(session.dev_nonce, dev_nonce);
+    assert_eq!(session.dev_addr.as_bytes(), &[0x07, 0x08, 0x09, 0x0A]);
+    assert_eq!(session.rx1_dr_offset, 2);
+    assert_eq!(session.rx2_data_rate, Some(8));
+    assert_eq!(session.rx1_delay, 5);
+
+    // Verify session keys, derived with the DevNonce of the join request sent
+    let (nwk_skey, app_skey) = crypto::derive_session_keys(
+        &app_key,
+        &[0x01, 0x02, 0x03],
+        &[0x04, 0x05, 0x06],
+        dev_nonce,
+    );
+    assert_eq!(session.nwk_skey.as_bytes(), nwk_skey.as_bytes());
+    assert_eq!(session.app_skey.as_bytes(), app_skey.as_bytes());
+}
+
+#[test]
+fn test_poll_join_then_uplink() {
+    let app_key = AESKey::new([0x2B; 16]);
+    let config = DeviceConfig::new_otaa([0x01; 8], [0x02; 8], app_key.clone());
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        US915::new(),
+        OperatingMode::ClassA,
+    )
+    .unwrap();
+
+    // The join request goes out on the first poll
+    device
+        .start_join([0x01; 8], [0x02; 8], app_key.clone())
+        .unwrap();
+    assert!(matches!(
+        device.start_uplink(1, &[0x01], false),
+        Err(DeviceError::InvalidState)
+    ));
+    assert!(matches!(
+        device.poll(100),
+        Ok(Some(DeviceEvent::TxComplete))
+    ));
+    assert_eq!(
+        device.get_mac_layer().get_radio().get_last_tx().unwrap()[0],
+        0x00
+    );
+
+    // Nothing in RX1, the join accept arrives in RX2
+    assert!(matches!(device.poll(5_099), Ok(None)));
+    assert!(matches!(device.poll(5_100), Ok(None)));
+    let mut full_message = Vec::<u8, 32>::new();
+    full_message
+        .extend_from_slice(&[
+            0x20, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x00, 0x01,
+        ])
+        .unwrap();
+    let mic = crypto::compute_join_accept_mic(&app_key, &full_message);
+    full_message.extend_from_slice(&mic).unwrap();
+    let mut join_accept = Vec::<u8, 32>::new();
+    join_accept.push(full_message[0]).unwrap();
+    join_accept
+        .extend_from_slice(&crypto::encrypt_join_accept(&app_key, &full_message[1..]))
+        .unwrap();
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_rx_data(&join_accept);
+    assert!(matches!(
+        device.poll(6_100),
+        Ok(Some(DeviceEvent::JoinAccepted))
+    ));
+    assert!(device.get_session_state().is_joined());
+    assert!(!device.is_busy());
+    let session = device.get_session_state();
+
+    // A confirmed uplink acknowledged in RX1 by a downlink with a MAC command
+    device.start_uplink(1, b"hi", true).unwrap();
+    assert!(matches!(
+        device.poll(10_000),
+        Ok(Some(DeviceEvent::TxComplete))
+    ));
+    assert_eq!(
+        device.get_mac_layer().get_radio().get_last_tx().unwrap()[0],
+        0x80
+    );
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_rx_data(&build_downlink_frame(
+            &session,
+            0x60,
+            0,
+            0x20,
+            &[],
+            Some(0),
+            &[0x06],
+        ));
+    assert!(matches!(device.poll(10_999), Ok(None)));
+    assert!(matches!(
+        device.poll(11_000),
+        Ok(Some(DeviceEvent::AckReceived))
+    ));
+    assert!(matches!(
+        device.poll(11_001),
+        Ok(Some(DeviceEvent::MacCommandApplied(
+            MacCommand::DevStatusReq
+        )))
+    ));
+    assert!(matches!(device.poll(12_000), Ok(None)));
+
+    // Application data of an unconfirmed uplink's RX2
+    device.start_uplink(2, b"hi", false).unwrap();
+    assert!(matches!(
+        device.poll(20_000),
+        Ok(Some(DeviceEvent::TxComplete))
+    ));
+    assert!(matches!(device.poll(21_000), Ok(None)));
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_rx_data(&build_downlink(&session, 1, &[], Some(5), b"data"));
+    match device.poll(22_000) {
+        Ok(Some(DeviceEvent::DownlinkReceived(downlink))) => {
+            assert_eq!(downlink.port, 5);
+            assert_eq!(downlink.payload.as_slice(), b"data");
+        }
+        _ => panic!("Expected a downlink"),
+    }
+
+    // A confirmed uplink without answer times out after RX2
+    device.start_uplink(1, b"hi", true).unwrap();
+    assert!(matches!(
+        device.poll(30_000),
+        Ok(Some(DeviceEvent::TxComplete))
+    ));
+    assert!(matches!(device.poll(31_000), Ok(None)));
+    assert!(matches!(
+        device.poll(32_000),
+        Ok(Some(DeviceEvent::AckTimeout))
+    ));
+    assert!(!device.is_busy());
+}
+
+#[test]
+fn test_poll_join_timeout() {
+    let app_key = AESKey::new([0x2B; 16]);
+    let config = DeviceConfig::new_otaa([0x01; 8], [0x02; 8], app_key.clone());
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        US915::new(),
+        OperatingMode::ClassA,
+    )
+    .unwrap();
+
+    device.start_join([0x01; 8], [0x02; 8], app_key).unwrap();
+    assert!(matches!(device.poll(0), Ok(Some(DeviceEvent::TxComplete))));
+    assert!(matches!(device.poll(5_000), Ok(None)));
+    assert!(matches!(
+        device.poll(6_000),
+        Ok(Some(DeviceEvent::JoinTimeout))
+    ));
+    assert!(!device.get_session_state().is_joined());
+    assert!(!device.is_busy());
+}
+
+#[test]
+fn test_join_policy_backoff_and_data_rates() {
+    let app_key = AESKey::new([0x2B; 16]);
+    let config = DeviceConfig::new_otaa([0x01; 8], [0x02; 8], app_key.clone());
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        US915::new(),
+        OperatingMode::ClassA,
+    )
+    .unwrap();
+    device.set_join_policy(JoinPolicy {
+        max_attempts: 3,
+        base_backoff_ms: 1_000,
+        max_backoff_ms: 1_500,
+        dr_sequence: Vec::from_slice(&[0, 4]).unwrap(),
+    });
+    let bandwidth = |device: &LoRaWANDevice<MockRadio, US915>| {
+        let config = device.get_mac_layer().get_radio().get_last_tx_config();
+        config.unwrap().modulation.bandwidth
+    };
+
+    // DR0 on a 125 kHz channel first
+    device.start_join([0x01; 8], [0x02; 8], app_key).unwrap();
+    assert!(matches!(device.poll(0), Ok(Some(DeviceEvent::TxComplete))));
+    assert_eq!(bandwidth(&device), 125_000);
+    assert!(matches!(device.poll(5_000), Ok(None)));
+    assert!(matches!(device.poll(6_000), Ok(None)));
+    assert!(device.is_busy());
+    assert_eq!(device.next_join_attempt(), Some(7_000));
+
+    // DR4 on a 500 kHz channel after the backoff
+    assert!(matches!(device.poll(6_999), Ok(None)));
+    assert_eq!(device.join_attempts(), 1);
+    assert!(matches!(
+        device.poll(7_000),
+        Ok(Some(DeviceEvent::TxComplete))
+    ));
+    assert_eq!(device.join_attempts(), 2);
+    assert_eq!(bandwidth(&device), 500_000);
+    assert!(matches!(device.poll(12_000), Ok(None)));
+    assert!(matches!(device.poll(13_000), Ok(None)));
+
+    // The doubled backoff is capped
+    assert_eq!(device.next_join_attempt(), Some(14_500));
+    assert!(matches!(
+        device.poll(14_500),
+        Ok(Some(DeviceEvent::TxComplete))
+    ));
+    assert_eq!(bandwidth(&device), 125_000);
+    assert!(matches!(device.poll(19_500), Ok(None)));
+    assert!(matches!(
+        device.poll(20_500),
+        Ok(Some(DeviceEvent::JoinTimeout))
+    ));
+    assert!(!device.is_busy());
+    assert_eq!(device.next_join_attempt(), None);
+    assert_eq!(device.join_attempts(), 3);
+}
+
+#[test]
+fn test_join_accept_bad_mic() {
+    let app_key = AESKey::new([0x2B; 16]);
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), SessionState::new());
+    mac.join_request([0x01; 8], [0x02; 8], app_key.clone())
+        .unwrap();
+
+    let mut full_message = Vec::<u8, 32>::new();
+    full_message
+        .extend_from_slice(&[
+            0x20, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A,
+        ])
+        .unwrap();
+    full_message.extend_from_slice(&[0x00, 0x01]).unwrap();
+    full_message
+        .extend_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF])
+        .unwrap();
+
+    let mut join_accept = Vec::<u8, 32>::new();
+    join_accept.push(full_message[0]).unwrap();
+    join_accept
+        .extend_from_slice(&crypto::encrypt_join_accept(&app_key, &full_message[1..]))
+        .unwrap();
+
+    assert!(mac.process_join_accept(&join_accept).is_err());
+    assert!(mac.is_join_pending());
+    assert!(!mac.get_session_state().is_joined());
+}
+
+/// Software backend that counts the AES operations it performs
+struct CountingCrypto {
+    blocks: AtomicUsize,
+    cmacs: AtomicUsize,
+}
+
+impl CountingCrypto {
+    fn counts(&self) -> (usize, usize) {
+        (
+            self.blocks.load(Ordering::Relaxed),
+            self.cmacs.load(Ordering::Relaxed),
+        )
+    }
+}
+
+impl CryptoBackend for CountingCrypto {
+    fn encrypt_block(&self, key: &AESKey, block: &mut [u8; BLOCK_SIZE]) {
+        self.blocks.fetch_add(1, Ordering::Relaxed);
+        SoftwareCrypto.encrypt_block(key, block)
+    }
+
+    fn cmac(&self, key: &AESKey, parts: &[&[u8]]) -> [u8; BLOCK_SIZE] {
+        self.cmacs.fetch_add(1, Ordering::Relaxed);
+        SoftwareCrypto.cmac(key, parts)
+    }
+}
+
+static COUNTING_CRYPTO: CountingCrypto = CountingCrypto {
+    blocks: AtomicUsize::new(0),
+    cmacs: AtomicUsize::new(0),
+};
+
+#[test]
+fn test_mac_layer_uses_crypto_backend() {
+    let app_key = AESKey::new([0x2B; 16]);
+    let mut mac = MacLayer::with_crypto_backend(
+        MockRadio::new(),
+        US915::new(),
+        SessionState::new(),
+        &COUNTING_CRYPTO,
+    );
+
+    // Join request MIC
+    mac.join_request([0x01; 8], [0x02; 8], app_key.clone())
+        .unwrap();
+    assert_eq!(COUNTING_CRYPTO.counts(), (0, 1));
+
+    let mut full_message = Vec::<u8, 32>::new();
+    full_message
+        .extend_from_slice(&[
+            0x20, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x00, 0x01,
+        ])
+        .unwrap();
+    let mic = crypto::compute_join_accept_mic(&app_key, &full_message);
+    full_message.extend_from_slice(&mic).unwrap();
+
+    let mut join_accept = Vec::<u8, 32>::new();
+    join_accept.push(full_message[0]).unwrap();
+    join_accept
+        .extend_from_slice(&crypto::encrypt_join_accept(&app_key, &full_message[1..]))
+        .unwrap();
+
+    // One block to decrypt, the MIC, and one block per session key
+    mac.process_join_accept(&join_accept).unwrap();
+    assert_eq!(COUNTING_CRYPTO.counts(), (3, 2));
+
+    // The session keys come from the backend
+    let (nwk_skey, app_skey) = crypto::derive_session_keys(
+        &app_key,
+        &[0x01, 0x02, 0x03],
+        &[0x04, 0x05, 0x06],
+        mac.get_dev_nonce(),
+    );
+    let session = mac.get_session_state().clone();
+    assert_eq!(session.nwk_skey.as_bytes(), nwk_skey.as_bytes());
+    assert_eq!(session.app_skey.as_bytes(), app_skey.as_bytes());
+
+    // Uplink: one keystream block and the MIC
+    mac.send_unconfirmed(1, &[0x01]).unwrap();
+    assert_eq!(COUNTING_CRYPTO.counts(), (4, 3));
+
+    // Downlink: the MIC and one keystream block
+    let frame = mac
+        .receive_downlink(&build_downlink(&session, 0, &[], Some(1), &[0x42]))
+        .unwrap();
+    assert_eq!(frame.payload.as_slice(), &[0x42]);
+    assert_eq!(COUNTING_CRYPTO.counts(), (5, 4));
+}
+
+#[test]
+fn test_join_request_frame() {
+    let dev_eui = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
+    let app_eui = [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01];
+    let app_key = AESKey::new([0x2B; 16]);
+
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), SessionState::new());
+    mac.join_request(dev_eui, app_eui, app_key.clone())
+        .expect("Join request failed");
+
+    let frame = mac.get_radio().get_last_tx().expect("Nothing transmitted");
+    assert_eq!(frame.len(), 23);
+    assert_eq!(frame[0], 0x00); // MHDR: JoinRequest
+    assert_eq!(&frame[1..9], &app_eui);
+    assert_eq!(&frame[9..17], &dev_eui);
+    assert_eq!(
+        u16::from_le_bytes([frame[17], frame[18]]),
+        mac.get_dev_nonce()
+    );
+    assert_eq!(
+        frame[19..],
+        crypto::compute_join_request_mic(&app_key, &frame[..19])
+    );
+
+    // A second attempt must not reuse the DevNonce
+    let first_nonce = mac.get_dev_nonce();
+    mac.join_request(dev_eui, app_eui, app_key.clone()).unwrap();
+    assert_ne!(mac.get_dev_nonce(), first_nonce);
+
+    // Without a store, DevNonces are counted up in RAM from 0
+    assert_eq!(first_nonce, 0);
+    assert_eq!(mac.get_dev_nonce(), 1);
+    assert_eq!(mac.get_session_state().last_dev_nonce, Some(1));
+
+    // Random DevNonces only when opted in
+    let mut radio = MockRadio::new();
+    radio.set_random_seed(7);
+    let mut mac = MacLayer::new(radio.clone(), US915::new(), SessionState::new());
+    mac.set_random_dev_nonces(true);
+    mac.join_request(dev_eui, app_eui, app_key).unwrap();
+    assert_eq!(mac.get_dev_nonce(), radio.random_u32().unwrap() as u16);
+}
+
+#[test]
+fn test_join_request_rx1_timeout() {
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), SessionState::new());
+    mac.join_request([0x01; 8], [0x02; 8], AESKey::new([0x2B; 16]))
+        .unwrap();
+
+    // RX1 stays open long enough to detect a preamble, not JoinAcceptDelay1
+    let channel = mac.get_last_uplink_channel().cloned().unwrap();
+    let (frequency, data_rate) = mac.get_region().rx1_window(&channel, 0);
+    let config = mac.get_radio().get_rx_configs().last().unwrap();
+    assert_eq!(config.frequency, frequency);
+    assert_eq!(config.timeout_ms, rx_window_timeout(data_rate));
+}
+
+/// DevNonce store standing in for flash, it survives the MAC layer
+struct FlashNonceStore {
+    /// Last DevNonce plus one, 0 when erased
+    value: AtomicU32,
+    /// Fail all writes
+    read_only: AtomicBool,
+}
+
+impl DevNonceStore for FlashNonceStore {
+    fn load(&self) -> Option<u16> {
+        match self.value.load(Ordering::Relaxed) {
+            0 => None,
+            value => Some((value - 1) as u16),
+        }
+    }
+
+    fn save(&self, dev_nonce: u16) -> bool {
+        if self.read_only.load(Ordering::Relaxed) {
+            return false;
+        }
+        self.value.store(dev_nonce as u32 + 1, Ordering::Relaxed);
+        true
+    }
+}
+
+static FLASH_NONCE_STORE: FlashNonceStore = FlashNonceStore {
+    value: AtomicU32::new(0),
+    read_only: AtomicBool::new(false),
+};
+
+#[test]
+fn test_dev_nonce_store_survives_reboot() {
+    let app_key = AESKey::new([0x2B; 16]);
+    let boot = || {
+        let mut mac = MacLayer::new(MockRadio::new(), US915::new(), SessionState::new());
+        mac.set_dev_nonce_store(&FLASH_NONCE_STORE);
+        mac
+    };
+
+    let mut mac = boot();
+    mac.join_request([0x01; 8], [0x02; 8], app_key.clone())
+        .unwrap();
+    mac.join_request([0x01; 8], [0x02; 8], app_key.clone())
+        .unwrap();
+    assert_eq!(mac.get_dev_nonce(), 1);
+    assert_eq!(mac.get_session_state().last_dev_nonce, Some(1));
+    assert_eq!(FLASH_NONCE_STORE.load(), Some(1));
+
+    // After a reboot the count continues from the stored value
+    let mut mac = boot();
+    assert_eq!(mac.get_dev_nonce(), 0);
+    mac.join_request([0x01; 8], [0x02; 8], app_key.clone())
+        .unwrap();
+    assert_eq!(mac.get_dev_nonce(), 2);
+    let frame = mac.get_radio().get_last_tx().unwrap();
+    assert_eq!(&frame[17..19], &[0x02, 0x00]);
+
+    // Nothing is sent if the new value cannot be stored
+    FLASH_NONCE_STORE.read_only.store(true, Ordering::Relaxed);
+    let mut mac = boot();
+    assert!(matches!(
+        mac.join_request([0x01; 8], [0x02; 8], app_key.clone()),
+        Err(MacError::DevNonceUnavailable)
+    ));
+    assert!(mac.get_radio().get_last_tx().is_none());
+    assert!(!mac.is_join_pending());
+    FLASH_NONCE_STORE.read_only.store(false, Ordering::Relaxed);
+
+    // Nor once all DevNonces have been used
+    FLASH_NONCE_STORE.save(u16::MAX);
+    let mut mac = boot();
+    assert!(matches!(
+        mac.join_request([0x01; 8], [0x02; 8], app_key),
+        Err(MacError::DevNonceUnavailable)
+    ));
+    assert!(mac.get_radio().get_last_tx().is_none());
+}
+
+/// Frame counter store in simulated flash, counting its writes
+struct FlashCounterStore {
+    fcnt_up: AtomicU32,
+    fcnt_down: AtomicU32,
+    writes: AtomicU32,
+}
+
+impl FrameCounterStore for FlashCounterStore {
+    fn load(&self) -> Option<(u32, u32)> {
+        (self.writes.load(Ordering::Relaxed) > 0).then(|| {
+            (
+                self.fcnt_up.load(Ordering::Relaxed),
+                self.fcnt_down.load(Ordering::Relaxed),
+            )
+        })
+    }
+
+    fn save(&self, fcnt_up: u32, fcnt_down: u32) -> bool {
+        self.fcnt_up.store(fcnt_up, Ordering::Relaxed);
+        self.fcnt_down.store(fcnt_down, Ordering::Relaxed);
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
+
+static FLASH_COUNTER_STORE: FlashCounterStore = FlashCounterStore {
+    fcnt_up: AtomicU32::new(0),
+    fcnt_down: AtomicU32::new(0),
+    writes: AtomicU32::new(0),
+};
+
+#[test]
+fn test_abp_frame_counters_survive_reset() {
+    let session = abp_session();
+    let boot = || {
+        let config = DeviceConfig::new_abp(
+            [0x01; 8],
+            [0x02; 8],
+            session.dev_addr,
+            session.nwk_skey.clone(),
+            session.app_skey.clone(),
+        );
+        let mut device = LoRaWANDevice::new(
+            MockRadio::new(),
+            config,
+            US915::new(),
+            OperatingMode::ClassA,
+        )
+        .unwrap();
+        device.set_frame_counter_store(&FLASH_COUNTER_STORE, 4);
+        device
+    };
+    let last_fcnt = |device: &LoRaWANDevice<MockRadio, US915>| {
+        let frame = device.get_mac_layer().get_radio().get_last_tx().unwrap();
+        u16::from_le_bytes([frame[6], frame[7]])
+    };
+
+    // ABP sessions start with the RX2 defaults of the region
+    let mut device = boot();
+    let state = device.get_session_state();
+    assert_eq!(state.rx2_frequency, Some(923_300_000));
+    assert_eq!(state.rx2_data_rate, Some(8));
+
+    // The counters are written every 4 uplinks, sent after the receive
+    // windows of the previous one
+    for i in 0..5 {
+        device
+            .get_mac_layer_mut()
+            .get_radio_mut()
+            .set_time(i * 10_000);
+        device.send_data(1, &[0x01], false).unwrap();
+    }
+    assert_eq!(FLASH_COUNTER_STORE.writes.load(Ordering::Relaxed), 1);
+    assert_eq!(FLASH_COUNTER_STORE.load(), Some((4, 0)));
+
+    // After a reset the uplinks possibly sent since the last write are skipped
+    let mut device = boot();
+    assert_eq!(device.get_session_state().fcnt_up, 7);
+    device.send_data(1, &[0x02], false).unwrap();
+    assert_eq!(last_fcnt(&device), 7);
+
+    // Counters persisted by the application
+    device.set_frame_counters(100, 20);
+    device.get_mac_layer_mut().get_radio_mut().set_time(10_000);
+    device.send_data(1, &[0x03], false).unwrap();
+    assert_eq!(last_fcnt(&device), 100);
+    assert_eq!(device.get_session_state().fcnt_down, 20);
+}
+
+#[test]
+fn test_restored_session_continues_fcnt() {
+    let session = abp_session();
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        US915::new(),
+        OperatingMode::ClassA,
+    )
+    .unwrap();
+    device.send_data(1, &[0x01], false).unwrap();
+    device.get_mac_layer_mut().get_radio_mut().set_time(10_000);
+    device.send_data(1, &[0x02], false).unwrap();
+    let saved = device.save_session();
+
+    // After a reboot the device starts without a session
+    let config = DeviceConfig::new_otaa([0x01; 8], [0x02; 8], AESKey::new([0x2B; 16]));
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        US915::new(),
+        OperatingMode::ClassA,
+    )
+    .unwrap();
+    assert!(!device.get_session_state().is_joined());
+
+    device.restore_session(&saved).unwrap();
+    assert!(device.get_session_state().is_joined());
+    device.send_data(1, &[0x03], false).unwrap();
+
+    let frame = device.get_mac_layer().get_radio().get_last_tx().unwrap();
+    assert_eq!(&frame[1..5], session.dev_addr.as_bytes());
+    assert_eq!(u16::from_le_bytes([frame[6], frame[7]]), 2);
+    let mic = crypto::compute_mic(
+        &session.nwk_skey,
+        &frame[..frame.len() - 4],
+        session.dev_addr,
+        2,
+        Direction::Up,
+    );
+    assert_eq!(&frame[frame.len() - 4..], &mic);
+    assert_eq!(device.get_session_state().fcnt_up, 3);
+
+    // A corrupted session is refused and leaves the device unchanged
+    let mut corrupted = saved;
+    corrupted[3] ^= 0xFF;
+    assert!(matches!(
+        device.restore_session(&corrupted),
+        Err(DeviceError::Session(SessionError::InvalidChecksum))
+    ));
+    assert_eq!(device.get_session_state().fcnt_up, 3);
+}
+
+#[test]
+fn test_send_requires_session() {
+    // OTAA devices have no session until the join accept
+    let config = DeviceConfig::new_otaa([0x01; 8], [0x02; 8], AESKey::new([0x2B; 16]));
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        US915::new(),
+        OperatingMode::ClassA,
+    )
+    .unwrap();
+    assert!(matches!(
+        device.send_data(1, &[0x01], false),
+        Err(DeviceError::NotJoined)
+    ));
+    assert!(matches!(
+        device.send_data(1, &[0x01], true),
+        Err(DeviceError::NotJoined)
+    ));
+    assert!(matches!(
+        device.start_uplink(1, &[0x01], false),
+        Err(DeviceError::NotJoined)
+    ));
+    assert!(!device.is_busy());
+    assert!(device.get_mac_layer().get_radio().get_last_tx().is_none());
+    assert_eq!(device.get_session_state().fcnt_up, 0);
+
+    // Nor can downlinks be verified
+    let session = abp_session();
+    let frame = build_downlink(&session, 0, &[], None, &[]);
+    assert!(matches!(
+        device.get_mac_layer_mut().handle_downlink(&frame),
+        Err(MacError::NotJoined)
+    ));
+
+    // ABP devices are joined right away
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        US915::new(),
+        OperatingMode::ClassA,
+    )
+    .unwrap();
+    assert_eq!(device.send_data(1, &[0x01], false).unwrap(), None);
+    assert_eq!(device.get_session_state().fcnt_up, 1);
+}
+
+#[test]
+fn test_mode_switch_keeps_mac_state() {
+    let session = abp_session();
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        US915::new(),
+        OperatingMode::ClassA,
+    )
+    .unwrap();
+    device.set_adr(true);
+    device.send_data(1, &[0x01], false).unwrap();
+    device.get_mac_layer_mut().request_link_check().unwrap();
+
+    // The pending LinkCheckReq and the frame counter move to Class C
+    assert_eq!(
+        device.get_session_state().device_class,
+        OperatingMode::ClassA
+    );
+    device.set_operating_mode(OperatingMode::ClassC).unwrap();
+    assert_eq!(device.operating_mode(), OperatingMode::ClassC);
+    let session = device.get_session_state();
+    assert_eq!(session.device_class, OperatingMode::ClassC);
+    assert_eq!(session.activation_state, ActivationState::ABPActivated);
+    device.send_data(1, &[0x02], false).unwrap();
+    let frame = device.get_mac_layer().get_radio().get_last_tx().unwrap();
+    assert_eq!(frame[5], 0x80 | 1);
+    assert_eq!(u16::from_le_bytes([frame[6], frame[7]]), 1);
+    assert_eq!(frame[8], 0x02);
+
+    device.set_operating_mode(OperatingMode::ClassA).unwrap();
+    device.send_data(1, &[0x03], false).unwrap();
+    let frame = device.get_mac_layer().get_radio().get_last_tx().unwrap();
+    assert_eq!(frame[5], 0x80);
+    assert_eq!(u16::from_le_bytes([frame[6], frame[7]]), 2);
+    assert!(device.get_adr_state().enabled);
+    assert_eq!(device.get_session_state().fcnt_up, 3);
+}
 
 #[test]
 fn test_downlink_commands() {
@@ -102,3 +849,2116 @@ fn test_downlink_commands() {
         _ => panic!("Wrong command type"),
     }
 }
+
+/// Build an unconfirmed data down frame for a session
+fn build_downlink(
+    session: &SessionState,
+    fcnt: u32,
+    f_opts: &[u8],
+    f_port: Option<u8>,
+    payload: &[u8],
+) -> Vec<u8, 64> {
+    build_downlink_frame(session, 0x60, fcnt, 0x00, f_opts, f_port, payload)
+}
+
+/// Build a downlink data frame with the given MHDR and FCtrl flags
+fn build_downlink_frame(
+    session: &SessionState,
+    mhdr: u8,
+    fcnt: u32,
+    f_ctrl: u8,
+    f_opts: &[u8],
+    f_port: Option<u8>,
+    payload: &[u8],
+) -> Vec<u8, 64> {
+    let mut frame = Vec::<u8, 64>::new();
+    frame.push(mhdr).unwrap();
+    frame
+        .extend_from_slice(session.dev_addr.as_bytes())
+        .unwrap();
+    frame.push(f_ctrl | f_opts.len() as u8).unwrap(); // FCtrl
+    frame
+        .extend_from_slice(&(fcnt as u16).to_le_bytes())
+        .unwrap();
+    frame.extend_from_slice(f_opts).unwrap();
+
+    if let Some(f_port) = f_port {
+        let key = if f_port == 0 {
+            &session.nwk_skey
+        } else {
+            &session.app_skey
+        };
+        frame.push(f_port).unwrap();
+        frame
+            .extend_from_slice(
+                &crypto::encrypt_payload(key, session.dev_addr, fcnt, Direction::Down, payload)
+                    .unwrap(),
+            )
+            .unwrap();
+    }
+
+    let mic = crypto::compute_mic(
+        &session.nwk_skey,
+        &frame,
+        session.dev_addr,
+        fcnt,
+        Direction::Down,
+    );
+    frame.extend_from_slice(&mic).unwrap();
+    frame
+}
+
+/// Build a port 0 downlink carrying MAC commands for a session
+fn build_mac_downlink(session: &SessionState, commands: &[u8]) -> Vec<u8, 64> {
+    build_downlink(session, session.fcnt_down, &[], Some(0), commands)
+}
+
+#[test]
+fn test_link_adr_req_applies_to_next_uplink() {
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x11; 16]),
+        AESKey::new([0x22; 16]),
+    );
+    let mut region = US915::new();
+    region.set_sub_band(1);
+
+    // LinkADRReq block: all 125 kHz channels off, then channels 8-11 on,
+    // DR3 and TXPower 10 (10 dBm)
+    let downlink = build_mac_downlink(
+        &session,
+        &[
+            0x03, 0x00, 0x00, 0x00, 0x70, // ChMaskCntl 7, no 500 kHz channel
+            0x03, 0x3A, 0x00, 0x0F, 0x01, // ChMaskCntl 0, channels 8-11
+        ],
+    );
+    let mut radio = MockRadio::new();
+    radio.set_rx_data(&downlink);
+
+    let mut device = ClassA::new(MacLayer::new(radio, region, session));
+    device.process().unwrap();
+
+    let mac = device.get_mac_layer();
+    assert_eq!(mac.get_region().data_rate(), 3);
+    assert_eq!(mac.get_region().tx_power(), 10);
+    assert_eq!(mac.get_region().get_enabled_channels().len(), 4);
+    assert_eq!(mac.get_pending_commands().len(), 2);
+
+    device.send_data(1, &[0x01, 0x02], false).unwrap();
+
+    let config = device
+        .get_mac_layer()
+        .get_radio()
+        .get_last_tx_config()
+        .unwrap();
+    assert!((903_900_000..=904_500_000).contains(&config.frequency));
+    assert_eq!(config.power, 10);
+    assert_eq!(config.modulation.spreading_factor, 7);
+    assert_eq!(config.modulation.bandwidth, 125_000);
+}
+
+#[test]
+fn test_manual_data_rate_and_tx_power() {
+    let session = abp_session();
+    let mut region = US915::new();
+    region.set_sub_band(1);
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device =
+        LoRaWANDevice::new(MockRadio::new(), config, region, OperatingMode::ClassA).unwrap();
+    let tx_config = |device: &LoRaWANDevice<MockRadio, US915>| {
+        device
+            .get_mac_layer()
+            .get_radio()
+            .get_last_tx_config()
+            .unwrap()
+    };
+
+    // SF9 and 13 dBm, rounded down to the 2 dB steps of US915
+    device.set_data_rate(DataRate::SF9BW125).unwrap();
+    device.set_tx_power_dbm(13).unwrap();
+    assert_eq!(device.data_rate(), Some(DataRate::SF9BW125));
+    assert_eq!(device.tx_power_dbm(), 12);
+    device.send_data(1, &[0x01], false).unwrap();
+    let config = tx_config(&device);
+    assert_eq!(config.modulation.spreading_factor, 9);
+    assert_eq!(config.modulation.bandwidth, 125_000);
+    assert_eq!(config.power, 12);
+
+    // Settings outside the region are refused
+    assert!(matches!(
+        device.set_tx_power_dbm(31),
+        Err(DeviceError::Mac(MacError::InvalidValue))
+    ));
+    assert!(matches!(
+        device.set_data_rate(DataRate::SF12BW125),
+        Err(DeviceError::Mac(MacError::InvalidDataRate))
+    ));
+    assert_eq!(device.data_rate(), Some(DataRate::SF9BW125));
+
+    // With ADR the network takes over: DR3 and 10 dBm
+    device.set_adr(true);
+    let downlink = build_mac_downlink(&session, &[0x03, 0x3A, 0xFF, 0x00, 0x01]);
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.set_rx_data(&downlink);
+    radio.set_time(10_000);
+    device.process().unwrap();
+    assert_eq!(device.data_rate(), Some(DataRate::SF7BW125));
+    assert_eq!(device.tx_power_dbm(), 10);
+    device.send_data(1, &[0x02], false).unwrap();
+    assert_eq!(tx_config(&device).modulation.spreading_factor, 7);
+    assert_eq!(tx_config(&device).power, 10);
+
+    // Until ADR is disabled again
+    device.set_adr(false);
+    assert_eq!(device.data_rate(), Some(DataRate::SF9BW125));
+    assert_eq!(device.tx_power_dbm(), 12);
+}
+
+#[test]
+fn test_link_adr_power_with_antenna_gain() {
+    let session = abp_session();
+    let mut region = US915::new();
+    region.set_sub_band(1);
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device =
+        LoRaWANDevice::new(MockRadio::new(), config, region, OperatingMode::ClassA).unwrap();
+    device.get_mac_layer_mut().set_max_tx_power(22);
+    device.set_antenna_gain(2);
+    device.set_adr(true);
+
+    // TX power index 5 is 20 dBm EIRP, 18 dBm at the radio
+    let downlink = build_mac_downlink(&session, &[0x03, 0x35, 0xFF, 0x00, 0x01]);
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.set_rx_data(&downlink);
+    device.process().unwrap();
+    assert_eq!(device.tx_power_dbm(), 18);
+    device.send_data(1, &[0x01], false).unwrap();
+    let power = |device: &LoRaWANDevice<MockRadio, US915>| {
+        device
+            .get_mac_layer()
+            .get_radio()
+            .get_last_tx_config()
+            .unwrap()
+            .power
+    };
+    assert_eq!(power(&device), 18);
+
+    // Index 0 is limited by the radio
+    let downlink = build_downlink(&session, 1, &[], Some(0), &[0x03, 0x30, 0xFF, 0x00, 0x01]);
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.set_rx_data(&downlink);
+    radio.set_time(10_000);
+    device.process().unwrap();
+    device.send_data(1, &[0x02], false).unwrap();
+    assert_eq!(power(&device), 22);
+}
+
+#[test]
+fn test_tx_power_clamped_to_radio() {
+    let session = abp_session();
+    let mut region = US915::new();
+    region.set_sub_band(1);
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut radio = MockRadio::new();
+    radio.set_capabilities(RadioCapabilities {
+        min_power_dbm: 6,
+        max_power_dbm: 20,
+        ..radio.capabilities()
+    });
+    let mut device = LoRaWANDevice::new(radio, config, region, OperatingMode::ClassA).unwrap();
+    device.get_mac_layer_mut().set_max_tx_power(30);
+
+    // Within the radio range
+    device.set_tx_power_dbm(18).unwrap();
+    assert_eq!(device.tx_power_dbm(), 18);
+
+    // Above the radio, the highest power is applied and reported
+    assert!(matches!(
+        device.set_tx_power_dbm(26),
+        Err(DeviceError::Mac(MacError::TxPowerClamped {
+            applied_dbm: 20
+        }))
+    ));
+    assert_eq!(device.tx_power_dbm(), 20);
+    device.send_data(1, &[0x01], false).unwrap();
+    let config = device.get_mac_layer().get_radio().get_last_tx_config();
+    assert_eq!(config.unwrap().power, 20);
+
+    // Below the radio
+    assert!(matches!(
+        device.set_tx_power_dbm(2),
+        Err(DeviceError::Mac(MacError::TxPowerClamped {
+            applied_dbm: 6
+        }))
+    ));
+    assert_eq!(device.tx_power_dbm(), 6);
+
+    // The antenna gain is taken into account, 20 dBm at the radio
+    device.set_antenna_gain(3);
+    assert!(matches!(
+        device.set_tx_power_dbm(30),
+        Err(DeviceError::Mac(MacError::TxPowerClamped {
+            applied_dbm: 22
+        }))
+    ));
+    assert_eq!(device.tx_power_dbm(), 19);
+    device.set_tx_power_dbm(22).unwrap();
+
+    // The region limit is still an error
+    assert!(matches!(
+        device.set_tx_power_dbm(31),
+        Err(DeviceError::Mac(MacError::InvalidValue))
+    ));
+}
+
+#[test]
+fn test_class_c_requires_continuous_rx() {
+    let session = abp_session();
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut radio = MockRadio::new();
+    radio.set_capabilities(RadioCapabilities {
+        continuous_rx: false,
+        ..radio.capabilities()
+    });
+
+    assert!(matches!(
+        LoRaWANDevice::new(
+            radio.clone(),
+            config.clone(),
+            US915::new(),
+            OperatingMode::ClassC
+        ),
+        Err(DeviceError::UnsupportedClass(OperatingMode::ClassC))
+    ));
+
+    // Class A and B do not need it, switching to Class C fails
+    let mut device =
+        LoRaWANDevice::new(radio, config, US915::new(), OperatingMode::ClassA).unwrap();
+    device.set_operating_mode(OperatingMode::ClassB).unwrap();
+    assert!(matches!(
+        device.set_operating_mode(OperatingMode::ClassC),
+        Err(DeviceError::UnsupportedClass(OperatingMode::ClassC))
+    ));
+    assert_eq!(device.operating_mode(), OperatingMode::ClassB);
+}
+
+#[test]
+fn test_raw_exchange_resumes_class_c() {
+    let session = abp_session();
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        US915::new(),
+        OperatingMode::ClassC,
+    )
+    .unwrap();
+    let in_rx2 = |device: &LoRaWANDevice<MockRadio, US915>| {
+        let rx = device
+            .get_mac_layer()
+            .get_radio()
+            .get_rx_configs()
+            .last()
+            .copied();
+        matches!(rx, Some(rx) if rx.frequency == 923_300_000 && rx.timeout_ms == 0)
+    };
+
+    // Listening on RX2 until RX1 of the uplink opens
+    device.send_data(1, &[0x01], false).unwrap();
+    assert!(in_rx2(&device));
+    let fcnt_up = device.get_session_state().fcnt_up;
+
+    // Point-to-point frame on another frequency, then back to RX2
+    let modulation = DataRate::SF7BW125.modulation();
+    device
+        .send_raw(
+            b"p2p",
+            TxConfig {
+                frequency: 915_000_000,
+                power: 14,
+                modulation,
+            },
+        )
+        .unwrap();
+    let radio = device.get_mac_layer().get_radio();
+    let (tx_config, frame) = radio.get_tx_log().last().unwrap();
+    assert_eq!(tx_config.frequency, 915_000_000);
+    assert_eq!(frame.as_slice(), b"p2p");
+    assert!(in_rx2(&device));
+
+    // The reply is returned as is, with the timeout of the call
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_rx_data(b"pong");
+    let mut buffer = [0u8; 16];
+    let rx_config = RxConfig {
+        frequency: 915_000_000,
+        timeout_ms: 0,
+        modulation,
+    };
+    let len = device.receive_raw(&mut buffer, rx_config, 500).unwrap();
+    assert_eq!(&buffer[..len], b"pong");
+    let rx_configs = device.get_mac_layer().get_radio().get_rx_configs();
+    let raw = rx_configs[rx_configs.len() - 2];
+    assert_eq!((raw.frequency, raw.timeout_ms), (915_000_000, 500));
+    assert!(in_rx2(&device));
+
+    // The guard restores RX2 when dropped
+    {
+        let mut radio = device.radio_mut().unwrap();
+        radio.configure_rx(rx_config).unwrap();
+    }
+    assert!(in_rx2(&device));
+
+    // The session is untouched and RX1 of the uplink still opens
+    assert_eq!(device.get_session_state().fcnt_up, fcnt_up);
+    assert_eq!(device.stats().uplinks, 1);
+    device.get_mac_layer_mut().get_radio_mut().set_time(1_000);
+    device.process().unwrap();
+    let rx_configs = device.get_mac_layer().get_radio().get_rx_configs();
+    let rx1 = rx_configs[rx_configs.len() - 2];
+    assert!(rx1.timeout_ms > 0 && rx1.frequency != 915_000_000);
+    assert!(in_rx2(&device));
+}
+
+#[test]
+fn test_raw_tx_counts_duty_cycle() {
+    let session = abp_session();
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        RU864::new(),
+        OperatingMode::ClassA,
+    )
+    .unwrap();
+    let tx_config = TxConfig {
+        frequency: 868_900_000,
+        power: 14,
+        modulation: DataRate::SF12BW125.modulation(),
+    };
+
+    // The 1% band stays silent for 99 times the time-on-air of the frame
+    device.send_raw(&[0x55; 14], tx_config).unwrap();
+    let off_time = DataRate::SF12BW125.time_on_air_ms(14) * 99;
+    assert_eq!(device.time_until_next_tx(), off_time);
+    assert!(matches!(
+        device.send_raw(&[0x55; 14], tx_config),
+        Err(DeviceError::TooEarly { retry_after_ms }) if retry_after_ms == off_time
+    ));
+    assert_eq!(device.stats().uplinks, 0);
+
+    // Not while an exchange waits for its receive windows
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_time(off_time);
+    device.start_uplink(1, &[0x01], false).unwrap();
+    device.poll(off_time).unwrap();
+    assert!(matches!(
+        device.send_raw(&[0x55; 14], tx_config),
+        Err(DeviceError::InvalidState)
+    ));
+    assert!(matches!(device.radio_mut(), Err(DeviceError::InvalidState)));
+}
+
+#[test]
+fn test_iq_and_sync_word_per_direction() {
+    let session = abp_session();
+    let mut region = US915::new();
+    region.set_sub_band(1);
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device =
+        LoRaWANDevice::new(MockRadio::new(), config, region, OperatingMode::ClassA).unwrap();
+
+    // Uplinks with standard IQ, the receive windows with inverted IQ
+    device.send_data(1, &[0x01], false).unwrap();
+    for at in [1_000, 2_000] {
+        device.get_mac_layer_mut().get_radio_mut().set_time(at);
+        device.process().unwrap();
+    }
+    let radio = device.get_mac_layer().get_radio();
+    let tx = radio.get_last_tx_config().unwrap().modulation;
+    assert!(!tx.iq_inverted);
+    assert_eq!(tx.sync_word, 0x34);
+    let rx_configs = radio.get_rx_configs();
+    assert_eq!(rx_configs.len(), 2);
+    assert!(rx_configs
+        .iter()
+        .all(|rx| rx.modulation.iq_inverted && rx.modulation.sync_word == 0x34));
+
+    // A private network changes the sync word in both directions
+    device.set_network_type(NetworkType::Private);
+    device.get_mac_layer_mut().get_radio_mut().set_time(10_000);
+    device.send_data(1, &[0x02], false).unwrap();
+    device.get_mac_layer_mut().get_radio_mut().set_time(11_000);
+    device.process().unwrap();
+    let radio = device.get_mac_layer().get_radio();
+    assert_eq!(
+        radio.get_last_tx_config().unwrap().modulation.sync_word,
+        0x12
+    );
+    let rx = radio.get_rx_configs().last().unwrap().modulation;
+    assert!(rx.iq_inverted);
+    assert_eq!(rx.sync_word, 0x12);
+}
+
+#[test]
+fn test_next_tx_opportunity_after_duty_cycle() {
+    let session = abp_session();
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        RU864::new(),
+        OperatingMode::ClassA,
+    )
+    .unwrap();
+    device.set_data_rate(DataRate::SF12BW125).unwrap();
+    assert_eq!(device.next_tx_opportunity(1).unwrap(), 0);
+
+    // A 14 byte frame at SF12 keeps the 1% band silent for 99 times its
+    // time-on-air
+    device.send_data(1, &[0x01], false).unwrap();
+    let off_time = DataRate::SF12BW125.time_on_air_ms(14) * 99;
+    assert_eq!(device.next_tx_opportunity(1).unwrap(), off_time);
+    assert!(matches!(
+        device.send_data(1, &[0x02], false),
+        Err(DeviceError::TooEarly { retry_after_ms }) if retry_after_ms == off_time
+    ));
+
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_time(off_time);
+    assert_eq!(device.next_tx_opportunity(1).unwrap(), 0);
+    device.send_data(1, &[0x02], false).unwrap();
+
+    // Payloads not fitting SF12 are refused up front
+    assert!(matches!(
+        device.next_tx_opportunity(100),
+        Err(DeviceError::Mac(MacError::InvalidPayloadSize { .. }))
+    ));
+}
+
+#[test]
+fn test_power_metrics_track_airtime() {
+    let session = abp_session();
+    let mut region = US915::new();
+    region.set_sub_band(1);
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device =
+        LoRaWANDevice::new(MockRadio::new(), config, region, OperatingMode::ClassA).unwrap();
+    device.set_data_rate(DataRate::SF10BW125).unwrap();
+    assert_eq!(device.power_metrics().tx_time, Duration::ZERO);
+
+    // Three 14 byte frames, each followed by its receive windows
+    for i in 0..3 {
+        let radio = device.get_mac_layer_mut().get_radio_mut();
+        radio.set_time(i * 10_000);
+        device.send_data(1, &[0x01], false).unwrap();
+        for at in [1_000, 2_000] {
+            let radio = device.get_mac_layer_mut().get_radio_mut();
+            radio.set_time(i * 10_000 + at);
+            device.process().unwrap();
+        }
+    }
+    let airtime = DataRate::SF10BW125.time_on_air_ms(14);
+    let metrics = device.power_metrics();
+    assert_eq!(metrics.tx_time, Duration::from_millis(3 * airtime as u64));
+    assert!(metrics.rx_time > Duration::ZERO);
+    assert_eq!(metrics.sleep_time, Duration::ZERO);
+
+    // The radio sleeps until the next uplink
+    device.sleep().unwrap();
+    device.get_mac_layer_mut().get_radio_mut().set_time(60_000);
+    device.send_data(1, &[0x01], false).unwrap();
+    let metrics = device.power_metrics();
+    assert_eq!(metrics.tx_time, Duration::from_millis(4 * airtime as u64));
+    assert_eq!(metrics.sleep_time, Duration::from_millis(60_000 - 22_000));
+    assert!(metrics.current_consumption > 0);
+    assert!(metrics.get_duty_cycle_permille() > 10);
+    assert_eq!(device.power_state(), PowerState::Normal);
+}
+
+#[test]
+fn test_link_stats_count_traffic() {
+    let session = abp_session();
+    let mut region = US915::new();
+    region.set_sub_band(1);
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device =
+        LoRaWANDevice::new(MockRadio::new(), config, region, OperatingMode::ClassA).unwrap();
+    device.set_data_rate(DataRate::SF10BW125).unwrap();
+    assert_eq!(device.stats(), LinkStats::default());
+
+    // An uplink answered by a downlink
+    device.send_data(1, &[0x01], false).unwrap();
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.set_rssi(-90);
+    radio.set_rx_data(&build_downlink(&session, 0, &[], Some(1), &[0x01]));
+    radio.set_time(1_000);
+    device.process().unwrap();
+
+    // An uplink answered by a downlink with a wrong key
+    let mut forged = session.clone();
+    forged.nwk_skey = AESKey::new([0x33; 16]);
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.set_time(10_000);
+    device.send_data(1, &[0x01], false).unwrap();
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.set_rx_data(&build_downlink(&forged, 1, &[], Some(1), &[0x01]));
+    radio.set_time(11_000);
+    assert!(device.process().is_err());
+
+    let airtime = DataRate::SF10BW125.time_on_air_ms(14);
+    let stats = device.stats();
+    assert_eq!(stats.uplinks, 2);
+    assert_eq!(stats.downlinks, 1);
+    assert_eq!(stats.mic_failures, 1);
+    assert_eq!(stats.rx_errors, 0);
+    assert_eq!(stats.join_attempts, 0);
+    assert_eq!(stats.last_rssi, Some(-90));
+    assert_eq!(stats.last_error, Some(MacError::InvalidMic));
+    assert_eq!(stats.airtime_ms, 2 * airtime);
+    assert_eq!(
+        stats.duty_cycle_permille as u32,
+        2 * airtime * 1000 / 11_000
+    );
+
+    // A reception failing in the radio
+    let mut buffer = [0u8; 64];
+    let mac = device.get_mac_layer_mut();
+    mac.get_radio_mut().set_error_mode(true);
+    assert!(mac.receive(&mut buffer).is_err());
+    let stats = device.stats();
+    assert_eq!(stats.rx_errors, 1);
+    assert_eq!(stats.last_error, Some(MacError::Radio(())));
+
+    device.reset_stats();
+    let stats = device.stats();
+    assert_eq!(stats.uplinks, 0);
+    assert_eq!(stats.mic_failures, 0);
+    assert_eq!(stats.last_error, None);
+    assert_eq!(stats.duty_cycle_permille, 0);
+    assert_eq!(device.get_mac_layer().get_stats().mic_failures, 0);
+}
+
+#[test]
+fn test_clock_replaces_radio_time() {
+    let session = abp_session();
+    let mut region = US915::new();
+    region.set_sub_band(1);
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device =
+        LoRaWANDevice::new(MockRadio::new(), config, region, OperatingMode::ClassA).unwrap();
+    let clock: &'static MockClock = Box::leak(Box::new(MockClock::new()));
+    device.set_clock(clock);
+
+    // The radio clock stays at 0, the receive windows follow the clock
+    clock.set(50_000);
+    device.start_uplink(1, b"hi", false).unwrap();
+    assert!(matches!(
+        device.poll(50_000),
+        Ok(Some(DeviceEvent::TxComplete))
+    ));
+    assert_eq!(device.get_mac_layer().get_time(), 50_000);
+    assert_eq!(device.get_mac_layer().get_radio().get_time(), 0);
+    let (opens_at, _, _) = device.next_rx_window().unwrap();
+    assert_eq!(opens_at, 51_000);
+
+    clock.advance(1_000);
+    assert_eq!(device.get_mac_layer().get_time(), 51_000);
+}
+
+#[test]
+fn test_battery_level_drives_power_state() {
+    let session = abp_session();
+    let mut region = US915::new();
+    region.set_sub_band(1);
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device =
+        LoRaWANDevice::new(MockRadio::new(), config, region, OperatingMode::ClassA).unwrap();
+
+    // The level is read after each uplink
+    device.set_status_provider(Box::leak(Box::new(|| 8)));
+    assert_eq!(device.power_state(), PowerState::Normal);
+    device.send_data(1, &[0x01], false).unwrap();
+    assert_eq!(device.power_state(), PowerState::Critical);
+    assert_eq!(device.power_metrics().battery_level, 8);
+
+    // External power is never low
+    device.set_status_provider(Box::leak(Box::new(|| 0)));
+    device.get_mac_layer_mut().get_radio_mut().set_time(10_000);
+    device.send_data(1, &[0x01], false).unwrap();
+    assert_eq!(device.power_state(), PowerState::Normal);
+}
+
+#[test]
+fn test_airtime_budget_limits_uplinks() {
+    let session = abp_session();
+    let mut region = US915::new();
+    region.set_sub_band(1);
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device =
+        LoRaWANDevice::new(MockRadio::new(), config, region, OperatingMode::ClassA).unwrap();
+    device.set_data_rate(DataRate::SF10BW125).unwrap();
+    let airtime = DataRate::SF10BW125.time_on_air_ms(14);
+
+    // Room for two frames per minute
+    device.set_airtime_budget(airtime * 2, 60_000);
+    for i in 0..2 {
+        device
+            .get_mac_layer_mut()
+            .get_radio_mut()
+            .set_time(i * 10_000);
+        device.send_data(1, &[0x01], false).unwrap();
+    }
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.set_time(20_000);
+    assert_eq!(device.next_tx_opportunity(1).unwrap(), 40_000);
+    assert!(matches!(
+        device.send_data(1, &[0x02], false),
+        Err(DeviceError::TooEarly {
+            retry_after_ms: 40_000
+        })
+    ));
+
+    // A new period starts once the first one is over
+    device.get_mac_layer_mut().get_radio_mut().set_time(60_000);
+    assert_eq!(device.next_tx_opportunity(1).unwrap(), 0);
+    device.send_data(1, &[0x02], false).unwrap();
+}
+
+#[test]
+fn test_link_adr_req_block_is_atomic() {
+    let mut region = US915::new();
+    region.set_sub_band(1);
+    let mut mac = MacLayer::new(MockRadio::new(), region, SessionState::new());
+
+    // The second command has an invalid data rate, so the whole block is rejected
+    mac.process_mac_commands(&[
+        MacCommand::LinkADRReq {
+            data_rate: 0,
+            tx_power: 0,
+            ch_mask: 0x0000,
+            ch_mask_cntl: 7,
+            nb_trans: 0,
+        },
+        MacCommand::LinkADRReq {
+            data_rate: 7,
+            tx_power: 2,
+            ch_mask: 0x00FF,
+            ch_mask_cntl: 0,
+            nb_trans: 3,
+        },
+    ])
+    .unwrap();
+
+    assert_eq!(mac.get_region().get_enabled_channels().len(), 9);
+    assert_eq!(mac.get_region().tx_power(), 0);
+    assert_eq!(mac.get_nb_trans(), 1);
+
+    let answers = mac.get_pending_commands();
+    assert_eq!(answers.len(), 2);
+    assert!(answers.iter().all(|answer| matches!(
+        answer,
+        MacCommand::LinkADRAns {
+            power_ack: true,
+            data_rate_ack: false,
+            channel_mask_ack: true,
+        }
+    )));
+}
+
+#[test]
+fn test_failed_mac_command_does_not_abort_the_others() {
+    let mut region = US915::new();
+    region.set_sub_band(1);
+    let mut mac = MacLayer::new(MockRadio::new(), region, SessionState::new());
+
+    // Room for a single answer
+    for _ in 1..MAX_MAC_COMMANDS {
+        mac.queue_mac_command(MacCommand::DutyCycleAns).unwrap();
+    }
+
+    // The invalid DutyCycleReq and the DevStatusAns not fitting the queue
+    // are skipped, the commands around them are still processed
+    mac.process_mac_commands(&[
+        MacCommand::DutyCycleReq { max_duty_cycle: 16 },
+        MacCommand::LinkADRReq {
+            data_rate: 2,
+            tx_power: 2,
+            ch_mask: 0x00FF,
+            ch_mask_cntl: 0,
+            nb_trans: 2,
+        },
+        MacCommand::DevStatusReq,
+        MacCommand::LinkCheckAns {
+            margin: 10,
+            gateway_count: 2,
+        },
+    ])
+    .unwrap();
+
+    assert_eq!(mac.get_region().data_rate(), 2);
+    assert_eq!(mac.get_region().tx_power(), 2);
+    assert_eq!(mac.get_nb_trans(), 2);
+    assert_eq!(mac.last_link_check().unwrap().gateway_count, 2);
+
+    let answers = mac.get_pending_commands();
+    assert_eq!(answers.len(), MAX_MAC_COMMANDS);
+    assert!(matches!(
+        answers.last(),
+        Some(MacCommand::LinkADRAns {
+            power_ack: true,
+            data_rate_ack: true,
+            channel_mask_ack: true,
+        })
+    ));
+}
+
+#[test]
+fn test_adr_backoff_without_downlinks() {
+    let mut region = US915::new();
+    region.set_sub_band(1);
+    region.set_data_rate(2);
+    region.set_tx_power(4);
+    let mut mac = MacLayer::new(MockRadio::new(), region, abp_session());
+    mac.set_adr(true);
+
+    let adr_ack_req =
+        |mac: &MacLayer<MockRadio, US915>| mac.get_radio().get_last_tx().unwrap()[5] & 0x40 != 0;
+
+    // ADRACKReq is set once ADR_ACK_LIMIT uplinks went unanswered
+    for _ in 0..ADR_ACK_LIMIT {
+        mac.send_unconfirmed(1, &[0x01]).unwrap();
+        assert!(!adr_ack_req(&mac));
+    }
+    mac.send_unconfirmed(1, &[0x01]).unwrap();
+    assert!(adr_ack_req(&mac));
+
+    let send_delay = |mac: &mut MacLayer<MockRadio, US915>| {
+        while (mac.get_adr_state().ack_counter - ADR_ACK_LIMIT) % ADR_ACK_DELAY != 0 {
+            mac.send_unconfirmed(1, &[0x01]).unwrap();
+        }
+    };
+
+    // Without answer the TX power is raised first
+    send_delay(&mut mac);
+    assert_eq!(mac.get_adr_state().data_rate, 2);
+    assert_eq!(mac.get_adr_state().tx_power, 0);
+
+    // Then the data rate steps down to the default
+    mac.send_unconfirmed(1, &[0x01]).unwrap();
+    send_delay(&mut mac);
+    assert_eq!(mac.get_adr_state().data_rate, 1);
+    mac.send_unconfirmed(1, &[0x01]).unwrap();
+    send_delay(&mut mac);
+    assert_eq!(mac.get_adr_state().data_rate, 0);
+    assert_eq!(mac.get_region().get_enabled_channels().len(), 9);
+
+    // And finally all default channels are enabled again
+    mac.send_unconfirmed(1, &[0x01]).unwrap();
+    send_delay(&mut mac);
+    assert_eq!(mac.get_region().get_enabled_channels().len(), 72);
+    assert!(adr_ack_req(&mac));
+}
+
+#[test]
+fn test_adr_ack_counter_reset_by_downlink() {
+    let dev_addr = DevAddr::new([0x01, 0x02, 0x03, 0x04]);
+    let nwk_skey = AESKey::new([0x11; 16]);
+    let app_skey = AESKey::new([0x22; 16]);
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        dev_addr,
+        nwk_skey.clone(),
+        app_skey.clone(),
+    );
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        US915::new(),
+        OperatingMode::ClassA,
+    )
+    .unwrap();
+    device.set_adr(true);
+
+    for i in 0..3 {
+        device
+            .get_mac_layer_mut()
+            .get_radio_mut()
+            .set_time(i * 10_000);
+        device.send_data(1, &[0x01], false).unwrap();
+    }
+    assert_eq!(
+        device.get_adr_state(),
+        AdrState {
+            enabled: true,
+            ack_counter: 3,
+            data_rate: 0,
+            tx_power: 0,
+        }
+    );
+
+    // Any downlink resets the counter
+    let session = SessionState::new_abp(dev_addr, nwk_skey, app_skey);
+    let downlink = build_mac_downlink(&session, &[]);
+    let mut radio = MockRadio::new();
+    radio.set_rx_data(&downlink);
+    let mut device = ClassA::new(MacLayer::new(radio, US915::new(), session));
+    device.get_mac_layer_mut().set_adr(true);
+    device.send_data(1, &[0x01], false).unwrap();
+    assert_eq!(device.get_mac_layer().get_adr_state().ack_counter, 1);
+
+    // The downlink arrives in RX1
+    device.get_mac_layer_mut().get_radio_mut().set_time(1_000);
+    device.process().unwrap();
+    assert_eq!(device.get_mac_layer().get_adr_state().ack_counter, 0);
+}
+
+#[test]
+fn test_mac_answers_sent_in_fopts() {
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x11; 16]),
+        AESKey::new([0x22; 16]),
+    );
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), session);
+    mac.queue_mac_command(MacCommand::LinkADRAns {
+        power_ack: true,
+        data_rate_ack: false,
+        channel_mask_ack: true,
+    })
+    .unwrap();
+    mac.queue_mac_command(MacCommand::DevStatusAns {
+        battery: 200,
+        margin: -5,
+    })
+    .unwrap();
+    mac.queue_mac_command(MacCommand::RXTimingSetupAns).unwrap();
+
+    mac.send_unconfirmed(1, &[0xAA, 0xBB]).unwrap();
+
+    // MHDR | DevAddr | FCtrl | FCnt | FOpts | FPort | FRMPayload | MIC
+    let frame = mac.get_radio().get_last_tx().unwrap();
+    assert_eq!(frame[5] & 0x0F, 6);
+    assert_eq!(&frame[8..14], &[0x03, 0x05, 0x06, 200, 0x3B, 0x08]);
+    assert_eq!(frame[14], 1);
+    assert_eq!(frame.len(), 8 + 6 + 1 + 2 + 4);
+
+    // RXTimingSetupAns is repeated until a downlink arrives
+    assert_eq!(
+        mac.get_pending_commands(),
+        &[MacCommand::RXTimingSetupAns][..]
+    );
+}
+
+#[test]
+fn test_mac_answers_spill_to_port_zero() {
+    let nwk_skey = AESKey::new([0x11; 16]);
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        nwk_skey.clone(),
+        AESKey::new([0x22; 16]),
+    );
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), session.clone());
+    // DR0 is too small for full FOpts
+    mac.get_region_mut().set_data_rate(3);
+    for battery in 0..6 {
+        mac.queue_mac_command(MacCommand::DevStatusAns { battery, margin: 0 })
+            .unwrap();
+    }
+
+    // Five answers fill FOpts, the last one waits for the next uplink
+    mac.send_unconfirmed(1, &[0xAA]).unwrap();
+    let frame = mac.get_radio().get_last_tx().unwrap();
+    assert_eq!(frame[5] & 0x0F, 15);
+    assert_eq!(mac.get_pending_commands().len(), 1);
+
+    for battery in 6..11 {
+        mac.queue_mac_command(MacCommand::DevStatusAns { battery, margin: 0 })
+            .unwrap();
+    }
+
+    // Without application payload all answers go to a port 0 FRMPayload
+    mac.send_mac_commands().unwrap();
+    let frame = mac.get_radio().get_last_tx().unwrap();
+    assert_eq!(frame[5] & 0x0F, 0);
+    assert_eq!(frame[8], 0);
+
+    let payload = crypto::encrypt_payload(
+        &nwk_skey,
+        session.dev_addr,
+        1,
+        Direction::Up,
+        &frame[9..frame.len() - 4],
+    )
+    .unwrap();
+    assert_eq!(payload.len(), 18);
+    for (i, command) in payload.chunks(3).enumerate() {
+        assert_eq!(command, &[0x06, 5 + i as u8, 0x00]);
+    }
+    assert!(!mac.has_pending_commands());
+}
+
+#[test]
+fn test_mac_answers_kept_when_transmit_fails() {
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), abp_session());
+    mac.queue_mac_command(MacCommand::DutyCycleAns).unwrap();
+
+    mac.get_radio_mut().set_error_mode(true);
+    assert!(mac.send_unconfirmed(1, &[0x01]).is_err());
+    assert!(mac.has_pending_commands());
+
+    mac.get_radio_mut().set_error_mode(false);
+    mac.send_unconfirmed(1, &[0x01]).unwrap();
+    assert_eq!(
+        &mac.get_radio().get_last_tx().unwrap()[5..9],
+        &[0x01, 0x00, 0x00, 0x04]
+    );
+    assert!(!mac.has_pending_commands());
+}
+
+#[test]
+fn test_mac_answers_sent_after_failed_command() {
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), abp_session());
+
+    // The invalid DutyCycleReq does not drop the answer queued before it
+    mac.process_mac_commands(&[
+        MacCommand::DevStatusReq,
+        MacCommand::DutyCycleReq { max_duty_cycle: 16 },
+        MacCommand::RXTimingSetupReq { delay: 2 },
+    ])
+    .unwrap();
+
+    mac.send_unconfirmed(1, &[0x01]).unwrap();
+    let frame = mac.get_radio().get_last_tx().unwrap();
+    assert_eq!(frame[5] & 0x0F, 4);
+    assert_eq!(&frame[8..12], &[0x06, 255, 0x00, 0x08]);
+}
+
+fn abp_session() -> SessionState {
+    SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x11; 16]),
+        AESKey::new([0x22; 16]),
+    )
+}
+
+#[test]
+fn test_parse_downlink_with_fopts() {
+    let session = abp_session();
+    let mac = MacLayer::new(MockRadio::new(), US915::new(), session.clone());
+
+    // DevStatusReq in FOpts next to an application payload on port 10
+    let downlink = build_downlink(&session, 0, &[0x06], Some(10), b"hello");
+    let frame = mac.parse_downlink(&downlink).unwrap();
+    assert!(!frame.confirmed);
+    assert_eq!(frame.fhdr.f_ctrl.foptslen, 1);
+    assert_eq!(frame.fhdr.f_opts.as_slice(), &[0x06]);
+    assert_eq!(frame.fcnt, 0);
+    assert_eq!(frame.f_port, Some(10));
+    assert_eq!(frame.payload.as_slice(), b"hello");
+
+    // A frame with FOpts only has no port and payload
+    let downlink = build_downlink(&session, 0, &[0x06], None, &[]);
+    let frame = mac.parse_downlink(&downlink).unwrap();
+    assert_eq!(frame.f_port, None);
+    assert!(frame.payload.is_empty());
+}
+
+#[test]
+fn test_parse_downlink_port_zero() {
+    let session = abp_session();
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), session.clone());
+
+    // Port 0 FRMPayload is encrypted with the NwkSKey
+    let downlink = build_downlink(&session, 0, &[], Some(0), &[0x06, 0x08, 0x01]);
+    let frame = mac.receive_downlink(&downlink).unwrap();
+    assert_eq!(frame.f_port, Some(0));
+    let commands = mac.extract_mac_commands(&frame.payload).unwrap();
+    assert_eq!(
+        commands.as_slice(),
+        &[
+            MacCommand::DevStatusReq,
+            MacCommand::RXTimingSetupReq { delay: 1 }
+        ]
+    );
+    assert_eq!(mac.get_frame_counter_down(), 1);
+
+    // MAC commands in both FOpts and FRMPayload are rejected
+    let downlink = build_downlink(&session, 1, &[0x06], Some(0), &[0x06]);
+    assert!(matches!(
+        mac.parse_downlink(&downlink),
+        Err(MacError::InvalidFrame)
+    ));
+}
+
+#[test]
+fn test_fopts_commands_processed_with_app_data() {
+    let session = abp_session();
+    let mut radio = MockRadio::new();
+    // LinkADRReq DR3, TX power 2, channels 0-7 in FOpts, data on port 5
+    radio.set_rx_data(&build_downlink(
+        &session,
+        0,
+        &[0x03, 0x32, 0xFF, 0x00, 0x01],
+        Some(5),
+        b"data",
+    ));
+    let mut device = ClassA::new(MacLayer::new(radio, US915::new(), session));
+    device.process().unwrap();
+
+    let mac = device.get_mac_layer_mut();
+    assert_eq!(mac.get_region().data_rate(), 3);
+    assert_eq!(mac.get_region().tx_power(), 2);
+    assert_eq!(
+        mac.get_pending_commands(),
+        &[MacCommand::LinkADRAns {
+            power_ack: true,
+            data_rate_ack: true,
+            channel_mask_ack: true,
+        }]
+    );
+    let downlink = mac.take_downlink().unwrap();
+    assert_eq!(downlink.port, 5);
+    assert_eq!(downlink.payload.as_slice(), b"data");
+}
+
+#[test]
+fn test_parse_downlink_rejects_bad_frames() {
+    let session = abp_session();
+    let mac = MacLayer::new(MockRadio::new(), US915::new(), session.clone());
+
+    // Corrupted MIC
+    let mut downlink = build_downlink(&session, 0, &[], Some(1), &[0x01]);
+    let last = downlink.len() - 1;
+    downlink[last] ^= 0xFF;
+    assert!(matches!(
+        mac.parse_downlink(&downlink),
+        Err(MacError::InvalidMic)
+    ));
+
+    // Frame for another device
+    let mut other = session.clone();
+    other.dev_addr = DevAddr::new([0x05, 0x06, 0x07, 0x08]);
+    let downlink = build_downlink(&other, 0, &[], Some(1), &[0x01]);
+    assert!(matches!(
+        mac.parse_downlink(&downlink),
+        Err(MacError::InvalidAddress)
+    ));
+
+    // Uplink frames and truncated FOpts
+    let mut downlink = build_downlink(&session, 0, &[], Some(1), &[0x01]);
+    downlink[0] = 0x40;
+    assert!(matches!(
+        mac.parse_downlink(&downlink),
+        Err(MacError::InvalidFrame)
+    ));
+    let mut downlink = build_downlink(&session, 0, &[], None, &[]);
+    downlink[5] = 0x0F;
+    assert!(matches!(
+        mac.parse_downlink(&downlink),
+        Err(MacError::InvalidLength)
+    ));
+}
+
+#[test]
+fn test_downlink_for_other_device_ignored() {
+    let session = abp_session();
+    let mut other = session.clone();
+    other.dev_addr = DevAddr::new([0x05, 0x06, 0x07, 0x08]);
+
+    let mut radio = MockRadio::new();
+    radio.set_rx_data(&build_mac_downlink(&other, &[0x06]));
+    let mut device = ClassA::new(MacLayer::new(radio, US915::new(), session.clone()));
+
+    assert!(matches!(device.process(), Err(MacError::InvalidAddress)));
+    let mac = device.get_mac_layer();
+    assert_eq!(mac.get_frames_for_other_devices(), 1);
+    assert_eq!(mac.get_mic_failures(), 0);
+    assert_eq!(mac.get_frame_counter_down(), 0);
+    assert!(!mac.has_pending_commands());
+
+    // A frame with our address but a wrong key fails the MIC instead
+    let mut forged = session.clone();
+    forged.nwk_skey = AESKey::new([0x33; 16]);
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_rx_data(&build_mac_downlink(&forged, &[0x06]));
+
+    assert!(matches!(device.process(), Err(MacError::InvalidMic)));
+    let mac = device.get_mac_layer();
+    assert_eq!(mac.get_frames_for_other_devices(), 1);
+    assert_eq!(mac.get_mic_failures(), 1);
+    assert_eq!(mac.get_frame_counter_down(), 0);
+}
+
+#[test]
+fn test_downlink_fcnt_rollover() {
+    let mut session = abp_session();
+    session.fcnt_down = 0xFFFE;
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), session.clone());
+
+    // Frames may be lost, the counter is extended across the 16-bit boundary
+    let frame = mac
+        .receive_downlink(&build_downlink(&session, 0x1_0002, &[], Some(1), &[0x01]))
+        .unwrap();
+    assert_eq!(frame.fcnt, 0x1_0002);
+    assert_eq!(mac.get_frame_counter_down(), 0x1_0003);
+
+    let frame = mac
+        .receive_downlink(&build_downlink(&session, 0x1_0010, &[], Some(1), &[0x01]))
+        .unwrap();
+    assert_eq!(frame.fcnt, 0x1_0010);
+    assert_eq!(mac.get_frame_counter_down(), 0x1_0011);
+}
+
+#[test]
+fn test_uplink_fcnt_rollover() {
+    let mut session = abp_session();
+    session.fcnt_up = 0xFFFF;
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), session.clone());
+
+    // FHDR carries the 16 low bits, the MIC covers all 32
+    for (i, fcnt) in [0xFFFF, 0x1_0000, 0x1_0001].into_iter().enumerate() {
+        mac.get_radio_mut().set_time(i as u32 * 10_000);
+        mac.send_unconfirmed(1, &[0x01]).unwrap();
+        let frame = mac.get_radio().get_last_tx().unwrap();
+        assert_eq!(u16::from_le_bytes([frame[6], frame[7]]), fcnt as u16);
+        let (msg, mic) = frame.split_at(frame.len() - 4);
+        let expected = crypto::compute_mic(
+            &session.nwk_skey,
+            msg,
+            session.dev_addr,
+            fcnt,
+            Direction::Up,
+        );
+        assert_eq!(mic, &expected);
+        if fcnt > 0xFFFF {
+            let truncated = crypto::compute_mic(
+                &session.nwk_skey,
+                msg,
+                session.dev_addr,
+                fcnt & 0xFFFF,
+                Direction::Up,
+            );
+            assert_ne!(mic, &truncated);
+        }
+    }
+    assert_eq!(mac.get_frame_counter_up(), 0x1_0002);
+
+    // A restored session continues above 16 bits
+    let saved = mac.get_session_state().to_bytes();
+    let restored = SessionState::from_bytes(&saved).unwrap();
+    assert_eq!(restored.fcnt_up, 0x1_0002);
+}
+
+#[test]
+fn test_uplink_fcnt_exhausted() {
+    let mut session = abp_session();
+    session.fcnt_up = u32::MAX - 1;
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), session);
+
+    // The counter never rolls over to 0 within a session
+    mac.send_unconfirmed(1, &[0x01]).unwrap();
+    assert!(mac.frame_counter_exhausted());
+    mac.get_radio_mut().set_time(10_000);
+    assert!(matches!(
+        mac.send_unconfirmed(1, &[0x02]),
+        Err(MacError::FrameCounterExhausted)
+    ));
+    assert_eq!(mac.get_radio().get_tx_log().len(), 1);
+    assert_eq!(mac.get_frame_counter_up(), u32::MAX);
+
+    // The device refuses the uplink up front
+    let session = abp_session();
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        US915::new(),
+        OperatingMode::ClassA,
+    )
+    .unwrap();
+    device.set_frame_counters(u32::MAX, 0);
+    assert!(matches!(
+        device.start_uplink(1, &[0x01], false),
+        Err(DeviceError::Mac(MacError::FrameCounterExhausted))
+    ));
+    assert!(!device.is_busy());
+}
+
+#[test]
+fn test_downlink_fcnt_replay_rejected() {
+    let session = abp_session();
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), session.clone());
+
+    let downlink = build_downlink(&session, 5, &[], Some(1), &[0x01]);
+    mac.receive_downlink(&downlink).unwrap();
+    assert_eq!(mac.get_frame_counter_down(), 6);
+
+    // The same frame again
+    assert!(matches!(
+        mac.receive_downlink(&downlink),
+        Err(MacError::DuplicateFrame)
+    ));
+    assert_eq!(mac.get_frame_counter_down(), 6);
+
+    // An older frame
+    let old = build_downlink(&session, 4, &[], Some(1), &[0x01]);
+    assert!(matches!(
+        mac.receive_downlink(&old),
+        Err(MacError::InvalidFrameCounter)
+    ));
+    assert_eq!(mac.get_frame_counter_down(), 6);
+
+    // A counter beyond the maximum gap
+    let far = build_downlink(&session, 6 + MAX_FCNT_GAP + 1, &[], Some(1), &[0x01]);
+    assert!(matches!(
+        mac.receive_downlink(&far),
+        Err(MacError::InvalidFrameCounter)
+    ));
+
+    // A valid counter with a bad MIC does not advance the counter
+    let mut forged = build_downlink(&session, 7, &[], Some(1), &[0x01]);
+    let last = forged.len() - 1;
+    forged[last] ^= 0xFF;
+    assert!(matches!(
+        mac.receive_downlink(&forged),
+        Err(MacError::InvalidMic)
+    ));
+    assert_eq!(mac.get_frame_counter_down(), 6);
+
+    let frame = mac
+        .receive_downlink(&build_downlink(&session, 7, &[], Some(1), &[0x01]))
+        .unwrap();
+    assert_eq!(frame.fcnt, 7);
+}
+
+#[test]
+fn test_duplicate_downlink_dropped() {
+    let session = abp_session();
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        US915::new(),
+        OperatingMode::ClassA,
+    )
+    .unwrap();
+    let downlink = build_downlink(&session, 0, &[], Some(3), b"once");
+
+    device.start_uplink(1, b"hi", false).unwrap();
+    assert!(matches!(device.poll(0), Ok(Some(DeviceEvent::TxComplete))));
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_rx_data(&downlink);
+    assert!(matches!(
+        device.poll(1_000),
+        Ok(Some(DeviceEvent::DownlinkReceived(_)))
+    ));
+
+    // The same downlink delivered again by another gateway
+    device.start_uplink(1, b"hi", false).unwrap();
+    assert!(matches!(
+        device.poll(10_000),
+        Ok(Some(DeviceEvent::TxComplete))
+    ));
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_rx_data(&downlink);
+    assert!(matches!(device.poll(11_000), Ok(None)));
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_rx_data(&downlink);
+    assert!(matches!(device.poll(12_000), Ok(None)));
+    assert!(!device.is_busy());
+
+    let stats = device.get_mac_layer().get_stats();
+    assert_eq!(stats.duplicates_dropped, 2);
+    assert_eq!(stats.mic_failures, 0);
+    assert_eq!(device.get_mac_layer().get_frame_counter_down(), 1);
+}
+
+#[test]
+fn test_rx_timing_setup_shifts_rx1() {
+    let session = abp_session();
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        US915::new(),
+        OperatingMode::ClassA,
+    )
+    .unwrap();
+    assert_eq!(device.get_mac_layer().receive_delays(), (1_000, 2_000));
+    assert_eq!(device.get_mac_layer().join_accept_delays(), (5_000, 6_000));
+
+    // RXTimingSetupReq with a delay of 3 seconds in RX1
+    device.start_uplink(1, b"hi", false).unwrap();
+    assert!(matches!(device.poll(0), Ok(Some(DeviceEvent::TxComplete))));
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_rx_data(&build_mac_downlink(&session, &[0x08, 0x03]));
+    device.poll(1_000).unwrap();
+    assert!(!device.is_busy());
+
+    let timing = *device.get_mac_layer().timing();
+    assert_eq!(timing.rx1_delay, 3_000);
+    assert_eq!(timing.rx2_delay, 4_000);
+    assert_eq!(timing.join_accept_delay1, 5_000);
+
+    // The windows of the next uplink open later
+    device.start_uplink(1, b"hi", false).unwrap();
+    assert!(matches!(
+        device.poll(10_000),
+        Ok(Some(DeviceEvent::TxComplete))
+    ));
+    assert_eq!(device.next_rx_window().unwrap().0, 13_000);
+    let rx_configs = device.get_mac_layer().get_radio().get_rx_configs().len();
+    assert!(matches!(device.poll(12_999), Ok(None)));
+    assert_eq!(
+        device.get_mac_layer().get_radio().get_rx_configs().len(),
+        rx_configs
+    );
+    assert!(matches!(device.poll(13_000), Ok(None)));
+    assert_eq!(device.next_rx_window().unwrap().0, 14_000);
+
+    // Timing set by the application
+    device.get_mac_layer_mut().set_timing(TimingParams {
+        rx1_delay: 2_000,
+        rx2_delay: 3_000,
+        ..timing
+    });
+    assert!(matches!(device.poll(14_000), Ok(None)));
+    device.start_uplink(1, b"hi", false).unwrap();
+    assert!(matches!(
+        device.poll(20_000),
+        Ok(Some(DeviceEvent::TxComplete))
+    ));
+    assert_eq!(device.next_rx_window().unwrap().0, 22_000);
+}
+
+/// Build an encrypted join accept carrying `app_nonce`
+fn build_join_accept(app_key: &AESKey, app_nonce: [u8; 3]) -> Vec<u8, 32> {
+    let mut message = Vec::<u8, 32>::new();
+    message.push(0x20).unwrap();
+    message.extend_from_slice(&app_nonce).unwrap();
+    message
+        .extend_from_slice(&[0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x00, 0x01])
+        .unwrap();
+    let mic = crypto::compute_join_accept_mic(app_key, &message);
+    message.extend_from_slice(&mic).unwrap();
+
+    let mut join_accept = Vec::<u8, 32>::new();
+    join_accept.push(message[0]).unwrap();
+    join_accept
+        .extend_from_slice(&crypto::encrypt_join_accept(app_key, &message[1..]))
+        .unwrap();
+    join_accept
+}
+
+#[test]
+fn test_join_accept_replay_rejected() {
+    let app_key = AESKey::new([0x2B; 16]);
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), SessionState::new());
+    let mut join = |mac: &mut MacLayer<MockRadio, US915>, app_nonce| {
+        mac.join_request([0x01; 8], [0x02; 8], app_key.clone())
+            .unwrap();
+        mac.process_join_accept(&build_join_accept(&app_key, app_nonce))
+    };
+
+    join(&mut mac, [0x10, 0x00, 0x00]).unwrap();
+    // Replayed, then older than the last JoinNonce
+    assert!(matches!(
+        join(&mut mac, [0x10, 0x00, 0x00]),
+        Err(MacError::DuplicateFrame)
+    ));
+    assert!(matches!(
+        join(&mut mac, [0x0F, 0x00, 0x00]),
+        Err(MacError::DuplicateFrame)
+    ));
+    assert!(mac.is_join_pending());
+    // JoinNonce is little endian
+    join(&mut mac, [0x00, 0x01, 0x00]).unwrap();
+    assert_eq!(mac.get_stats().duplicates_dropped, 2);
+
+    // Random JoinNonces only have to be new
+    mac.get_replay_cache_mut().set_increasing_join_nonce(false);
+    join(&mut mac, [0x05, 0x00, 0x00]).unwrap();
+    assert!(matches!(
+        join(&mut mac, [0x10, 0x00, 0x00]),
+        Err(MacError::DuplicateFrame)
+    ));
+    assert_eq!(mac.get_stats().duplicates_dropped, 3);
+}
+
+#[test]
+fn test_replay_cache_size() {
+    let session = abp_session();
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), session.clone());
+    assert_eq!(mac.get_replay_cache().size(), DEFAULT_REPLAY_CACHE_SIZE);
+    *mac.get_replay_cache_mut() = ReplayCache::new(1);
+    assert_eq!(ReplayCache::new(100).size(), MAX_REPLAY_CACHE_SIZE);
+
+    let first = build_downlink(&session, 0, &[], Some(1), &[0x01]);
+    let second = build_downlink(&session, 1, &[], Some(1), &[0x02]);
+    mac.receive_downlink(&first).unwrap();
+    mac.receive_downlink(&second).unwrap();
+
+    // Only the last downlink is remembered, the first is an old counter
+    assert!(matches!(
+        mac.receive_downlink(&second),
+        Err(MacError::DuplicateFrame)
+    ));
+    assert!(matches!(
+        mac.receive_downlink(&first),
+        Err(MacError::InvalidFrameCounter)
+    ));
+    assert_eq!(mac.get_stats().duplicates_dropped, 1);
+}
+
+/// Class A device on US915 sub-band 2 at DR3 for confirmed uplink tests
+fn confirmed_test_device(radio: MockRadio) -> ClassA<MockRadio, US915> {
+    let mut region = US915::new();
+    region.configure_ttn_us915();
+    region.set_data_rate(3);
+    ClassA::new(MacLayer::new(radio, region, abp_session()))
+}
+
+#[test]
+fn test_confirmed_uplink_acked_first_attempt() {
+    let session = abp_session();
+    let mut radio = MockRadio::new();
+    radio.set_rx_data(&build_downlink_frame(
+        &session,
+        0x60,
+        0,
+        0x20,
+        &[],
+        None,
+        &[],
+    ));
+    let mut device = confirmed_test_device(radio);
+
+    let result = device.send_data(1, &[0x01], true).unwrap();
+    assert_eq!(
+        result,
+        Some(ConfirmedResult {
+            acked: true,
+            attempts: 1
+        })
+    );
+    let mac = device.get_mac_layer();
+    assert_eq!(mac.get_frame_counter_up(), 1);
+    assert_eq!(mac.get_frame_counter_down(), 1);
+}
+
+#[test]
+fn test_confirmed_uplink_acked_on_retry() {
+    let session = abp_session();
+    let mut radio = MockRadio::new();
+    // Nothing in RX1 and RX2 of the first attempt, ACK in RX2 of the second
+    radio.queue_rx_data(&[]);
+    radio.queue_rx_data(&[]);
+    radio.queue_rx_data(&[]);
+    radio.queue_rx_data(&build_downlink_frame(
+        &session,
+        0x60,
+        0,
+        0x20,
+        &[],
+        None,
+        &[],
+    ));
+    let mut device = confirmed_test_device(radio);
+
+    let result = device.send_data(1, &[0x01], true).unwrap();
+    assert_eq!(
+        result,
+        Some(ConfirmedResult {
+            acked: true,
+            attempts: 2
+        })
+    );
+
+    // Retransmissions reuse the frame counter
+    assert_eq!(device.get_mac_layer().get_frame_counter_up(), 1);
+    let tx = device.get_mac_layer().get_radio().get_last_tx().unwrap();
+    assert_eq!(&tx[6..8], &[0x00, 0x00]);
+}
+
+#[test]
+fn test_confirmed_uplink_not_acked() {
+    let session = abp_session();
+    let mut radio = MockRadio::new();
+    // A downlink without the ACK bit does not end the retransmissions
+    radio.set_rx_data(&build_downlink(&session, 0, &[], None, &[]));
+    let mut device = confirmed_test_device(radio);
+
+    let result = device.send_data(1, &[0x01], true).unwrap();
+    assert_eq!(
+        result,
+        Some(ConfirmedResult {
+            acked: false,
+            attempts: DEFAULT_CONFIRMED_ATTEMPTS
+        })
+    );
+    assert_eq!(device.get_mac_layer().get_frame_counter_up(), 1);
+
+    // The data rate dropped by one every two attempts, from SF7 to SF10,
+    // and is restored for the next uplink
+    let tx_log = device.get_mac_layer().get_radio().get_tx_log();
+    let spreading_factors: Vec<u8, 8> = tx_log
+        .iter()
+        .map(|(config, _)| config.modulation.spreading_factor)
+        .collect();
+    assert_eq!(spreading_factors[..], [7, 7, 8, 8, 9, 9, 10, 10]);
+    assert_eq!(device.get_mac_layer().get_region().data_rate(), 3);
+
+    // Unconfirmed uplinks report no result
+    assert_eq!(device.send_data(1, &[0x01], false).unwrap(), None);
+}
+
+#[test]
+#[cfg(not(feature = "max-frame-64"))]
+fn test_confirmed_uplink_keeps_data_rate_of_large_frame() {
+    let session = abp_session();
+    let mut radio = MockRadio::new();
+    radio.set_rx_data(&build_downlink(&session, 0, &[], None, &[]));
+    let mut device = confirmed_test_device(radio);
+
+    // A MACPayload of 108 bytes fits DR2 (133) but not DR1 (61)
+    let result = device.send_data(1, &[0x55; 100], true).unwrap();
+    assert_eq!(
+        result,
+        Some(ConfirmedResult {
+            acked: false,
+            attempts: DEFAULT_CONFIRMED_ATTEMPTS
+        })
+    );
+    let tx_log = device.get_mac_layer().get_radio().get_tx_log();
+    assert_eq!(tx_log.len(), DEFAULT_CONFIRMED_ATTEMPTS as usize);
+    assert!(tx_log.iter().all(|(_, frame)| frame.len() == 113));
+    assert!(tx_log[2..]
+        .iter()
+        .all(|(config, _)| config.modulation.spreading_factor == 8));
+    assert_eq!(device.get_mac_layer().get_region().data_rate(), 3);
+}
+
+#[test]
+fn test_confirmed_downlink_acked_in_next_uplink() {
+    let session = abp_session();
+    let mut radio = MockRadio::new();
+    radio.set_rx_data(&build_downlink_frame(
+        &session,
+        0xA0,
+        0,
+        0x00,
+        &[],
+        Some(1),
+        &[0x01],
+    ));
+    let mut device = ClassA::new(MacLayer::new(radio, US915::new(), session.clone()));
+
+    device.process().unwrap();
+    assert!(device.get_mac_layer().has_pending_ack());
+
+    device.send_data(1, &[0x01], false).unwrap();
+    let tx = device.get_mac_layer().get_radio().get_last_tx().unwrap();
+    assert_eq!(tx[5] & 0x20, 0x20);
+    assert!(!device.get_mac_layer().has_pending_ack());
+
+    // The ACK is only sent once
+    device.send_data(1, &[0x01], false).unwrap();
+    let tx = device.get_mac_layer().get_radio().get_last_tx().unwrap();
+    assert_eq!(tx[5] & 0x20, 0);
+}
+
+#[test]
+fn test_fpending_auto_poll() {
+    let session = abp_session();
+    let mut config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+
+    // Two queued downlinks, the first one signals the second with FPending
+    let mut radio = MockRadio::new();
+    radio.queue_rx_data(&build_downlink_frame(
+        &session,
+        0x60,
+        0,
+        0x10,
+        &[],
+        Some(1),
+        &[0x01],
+    ));
+    radio.queue_rx_data(&build_downlink(&session, 1, &[], Some(1), &[0x02]));
+
+    // Without auto-poll the application has to send the next uplink
+    let mut device = LoRaWANDevice::new(
+        radio.clone(),
+        config.clone(),
+        US915::new(),
+        OperatingMode::ClassA,
+    )
+    .unwrap();
+    device.process().unwrap();
+    assert!(device.downlink_pending());
+    assert_eq!(device.get_session_state().fcnt_up, 0);
+
+    config.auto_poll = true;
+    let mut device =
+        LoRaWANDevice::new(radio, config, US915::new(), OperatingMode::ClassA).unwrap();
+    device.process().unwrap();
+    assert!(!device.downlink_pending());
+    assert_eq!(device.get_session_state().fcnt_up, 1);
+
+    // The second downlink arrives in RX1 of the poll
+    device.get_mac_layer_mut().get_radio_mut().set_time(1_000);
+    device.process().unwrap();
+    assert!(!device.downlink_pending());
+    let session = device.get_session_state();
+    assert_eq!(session.fcnt_down, 2);
+    assert_eq!(session.fcnt_up, 1);
+}
+
+#[test]
+fn test_fpending_auto_poll_waits_for_duty_cycle() {
+    let session = abp_session();
+    let mut config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    config.auto_poll = true;
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        RU864::new(),
+        OperatingMode::ClassA,
+    )
+    .unwrap();
+    device.set_data_rate(DataRate::SF12BW125).unwrap();
+
+    // The uplink keeps the 1% band busy, its RX1 signals another downlink
+    device.send_data(1, &[0x01], false).unwrap();
+    let off_time = DataRate::SF12BW125.time_on_air_ms(14) * 99;
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.queue_rx_data(&build_downlink_frame(
+        &session,
+        0x60,
+        0,
+        0x10,
+        &[],
+        Some(1),
+        &[0x01],
+    ));
+    radio.set_time(1_000);
+    device.process().unwrap();
+    assert!(device.downlink_pending());
+    assert_eq!(device.get_session_state().fcnt_down, 1);
+
+    // The poll waits for the band
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_time(off_time - 1);
+    device.process().unwrap();
+    assert!(device.downlink_pending());
+    assert_eq!(device.get_session_state().fcnt_up, 1);
+
+    device
+        .get_mac_layer_mut()
+        .get_radio_mut()
+        .set_time(off_time);
+    device.process().unwrap();
+    assert!(!device.downlink_pending());
+    assert_eq!(device.get_session_state().fcnt_up, 2);
+}
+
+#[test]
+fn test_class_a_receive_windows_after_uplink() {
+    let mut region = US915::new();
+    region.configure_ttn_us915();
+    region.set_data_rate(3);
+    let mut device = ClassA::new(MacLayer::new(MockRadio::new(), region, abp_session()));
+
+    device.send_data(1, &[0x01], false).unwrap();
+    let radio = device.get_mac_layer().get_radio();
+    assert_eq!(radio.get_last_tx_frequency(), 904_100_000);
+    assert!(radio.get_rx_configs().is_empty());
+
+    // RX1 opens one second after the uplink on the matching downlink channel
+    device.get_mac_layer_mut().get_radio_mut().set_time(999);
+    device.process().unwrap();
+    assert!(device
+        .get_mac_layer()
+        .get_radio()
+        .get_rx_configs()
+        .is_empty());
+
+    device.get_mac_layer_mut().get_radio_mut().set_time(1_000);
+    device.process().unwrap();
+    let rx_configs = device.get_mac_layer().get_radio().get_rx_configs();
+    assert_eq!(rx_configs.len(), 1);
+    assert_eq!(rx_configs[0].frequency, 923_900_000);
+    assert_eq!(rx_configs[0].modulation.spreading_factor, 7);
+    assert_eq!(rx_configs[0].modulation.bandwidth, 500_000);
+
+    // Nothing received in RX1, RX2 opens one second later
+    device.get_mac_layer_mut().get_radio_mut().set_time(1_500);
+    device.process().unwrap();
+    assert_eq!(device.get_mac_layer().get_radio().get_rx_configs().len(), 1);
+
+    device.get_mac_layer_mut().get_radio_mut().set_time(2_000);
+    device.process().unwrap();
+    let rx_configs = device.get_mac_layer().get_radio().get_rx_configs();
+    assert_eq!(rx_configs.len(), 2);
+    assert_eq!(rx_configs[1].frequency, 923_300_000);
+    assert_eq!(rx_configs[1].modulation.spreading_factor, 12);
+    assert_eq!(rx_configs[1].modulation.bandwidth, 500_000);
+
+    // The window covers the preamble of a SF12 downlink
+    assert!(rx_configs[1].timeout_ms >= 8 * 8);
+
+    // No further windows until the next uplink
+    device.get_mac_layer_mut().get_radio_mut().set_time(5_000);
+    device.process().unwrap();
+    assert_eq!(device.get_mac_layer().get_radio().get_rx_configs().len(), 2);
+}
+
+#[test]
+fn test_downlink_payload_returned_to_application() {
+    let session = abp_session();
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+    let mut radio = MockRadio::new();
+    radio.set_rssi(-87);
+    radio.set_snr(6);
+    let mut device =
+        LoRaWANDevice::new(radio, config, US915::new(), OperatingMode::ClassA).unwrap();
+
+    // Application data is decrypted and returned with its port
+    device.send_data(1, &[0x01], false).unwrap();
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.set_rx_data(&build_downlink_frame(
+        &session,
+        0xA0,
+        0,
+        0x00,
+        &[],
+        Some(10),
+        b"hello",
+    ));
+    radio.set_time(1_000);
+    device.process().unwrap();
+
+    let downlink = device.take_downlink().expect("No downlink");
+    assert_eq!(downlink.port, 10);
+    assert_eq!(downlink.payload.as_slice(), b"hello");
+    assert_eq!(downlink.rssi, -87);
+    assert_eq!(downlink.snr, 6);
+    assert_eq!(downlink.fcnt, 0);
+    assert!(downlink.confirmed);
+    assert!(device.take_downlink().is_none());
+
+    // MAC commands are applied by the device and not returned
+    device.send_data(1, &[0x02], false).unwrap();
+    let radio = device.get_mac_layer_mut().get_radio_mut();
+    radio.set_rx_data(&build_downlink(&session, 1, &[], Some(0), &[0x06]));
+    radio.set_time(2_000);
+    device.process().unwrap();
+    assert!(device.take_downlink().is_none());
+    assert_eq!(device.get_session_state().fcnt_down, 2);
+    assert!(device.get_mac_layer().has_pending_commands());
+}
+
+#[test]
+fn test_rx_param_setup_changes_rx2() {
+    let mut region = US915::new();
+    region.configure_ttn_us915();
+    let mut device = ClassA::new(MacLayer::new(MockRadio::new(), region, abp_session()));
+
+    // RX1DROffset 1, RX2 on 925.1 MHz at DR10
+    let mac = device.get_mac_layer_mut();
+    mac.process_mac_command(MacCommand::RXParamSetupReq {
+        rx1_dr_offset: 1,
+        rx2_data_rate: 10,
+        freq: 925_100_000,
+    })
+    .unwrap();
+    mac.process_mac_command(MacCommand::RXTimingSetupReq { delay: 2 })
+        .unwrap();
+    assert_eq!(mac.get_session_state().rx1_dr_offset, 1);
+    assert_eq!(mac.receive_delays(), (2_000, 3_000));
+
+    device.send_data(1, &[0x01], false).unwrap();
+    device.get_mac_layer_mut().get_radio_mut().set_time(2_000);
+    device.process().unwrap();
+    device.get_mac_layer_mut().get_radio_mut().set_time(3_000);
+    device.process().unwrap();
+
+    let rx_configs = device.get_mac_layer().get_radio().get_rx_configs();
+    assert_eq!(rx_configs.len(), 2);
+    assert_eq!(rx_configs[1].frequency, 925_100_000);
+    assert_eq!(rx_configs[1].modulation.spreading_factor, 10);
+    assert_eq!(rx_configs[1].modulation.bandwidth, 500_000);
+
+    // The answers are repeated until a downlink arrives
+    let pending = device.get_mac_layer().get_pending_commands();
+    assert_eq!(pending.len(), 2);
+    assert!(pending.iter().all(|command| command.is_sticky()));
+
+    device.send_data(1, &[0x01], false).unwrap();
+    let tx = device.get_mac_layer().get_radio().get_last_tx().unwrap();
+    assert_eq!(tx[5] & 0x0F, 3);
+
+    let session = device.get_mac_layer().get_session_state().clone();
+    device
+        .get_mac_layer_mut()
+        .receive_downlink(&build_downlink(&session, 0, &[], None, &[]))
+        .unwrap();
+    assert!(!device.get_mac_layer().has_pending_commands());
+}
+
+#[test]
+fn test_link_check() {
+    let session = abp_session();
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+
+    // LinkCheckAns with 20 dB margin from 3 gateways, received in RX1
+    let mut radio = MockRadio::new();
+    radio.set_time_step(10);
+    radio.queue_rx_data(&build_downlink(&session, 0, &[], Some(0), &[0x02, 20, 3]));
+    let mut device =
+        LoRaWANDevice::new(radio, config.clone(), US915::new(), OperatingMode::ClassA).unwrap();
+    assert_eq!(device.last_link_check(), None);
+
+    let result = device.check_link(5_000).unwrap().unwrap();
+    assert_eq!((result.margin, result.gateway_count), (20, 3));
+    assert!(result.received_at >= 1_000);
+    assert_eq!(device.last_link_check(), Some(result));
+
+    // The uplink carried the LinkCheckReq in FOpts
+    let frame = device.get_mac_layer().get_radio().get_last_tx().unwrap();
+    assert_eq!(frame[5] & 0x0F, 1);
+    assert_eq!(frame[8], 0x02);
+
+    // Without an answer the check times out and the last answer is kept
+    assert_eq!(device.check_link(5_000).unwrap(), None);
+    assert_eq!(device.last_link_check(), Some(result));
+}
+
+/// Fuel gauge standing in for a battery level read over I2C
+struct FuelGauge {
+    level: u8,
+}
+
+impl StatusProvider for FuelGauge {
+    fn battery_level(&mut self) -> u8 {
+        self.level
+    }
+}
+
+#[test]
+fn test_dev_status_reports_battery_and_margin() {
+    let session = abp_session();
+    let mut radio = MockRadio::new();
+    radio.set_snr(-7);
+    radio.set_rx_data(&build_downlink(&session, 0, &[], Some(0), &[0x06]));
+    let mut mac = MacLayer::new(radio, US915::new(), session.clone());
+    mac.set_status_provider(Box::leak(Box::new(FuelGauge { level: 200 })));
+
+    let mut buffer = [0u8; 64];
+    let len = mac.receive(&mut buffer).unwrap();
+    mac.handle_downlink(&buffer[..len]).unwrap();
+    assert_eq!(mac.get_last_snr(), Some(-7));
+
+    // Margin -7 dB in 6-bit two's complement
+    let mut payload: Vec<u8, 15> = Vec::new();
+    assert!(mac.get_pending_commands()[0].serialize(&mut payload));
+    assert_eq!(payload.as_slice(), &[0x06, 200, 0x39]);
+
+    // The margin is limited to -32..31 dB
+    mac.get_radio_mut().set_snr(-40);
+    mac.get_radio_mut()
+        .set_rx_data(&build_downlink(&session, 1, &[], Some(0), &[0x06]));
+    let len = mac.receive(&mut buffer).unwrap();
+    mac.handle_downlink(&buffer[..len]).unwrap();
+    assert_eq!(
+        mac.get_pending_commands()[1],
+        MacCommand::DevStatusAns {
+            battery: 200,
+            margin: -32,
+        }
+    );
+}
+
+#[test]
+fn test_device_time_sync() {
+    let session = abp_session();
+    let config = DeviceConfig::new_abp(
+        [0x01; 8],
+        [0x02; 8],
+        session.dev_addr,
+        session.nwk_skey.clone(),
+        session.app_skey.clone(),
+    );
+
+    // DeviceTimeAns with 1_400_000_000 s and 64/256 s since the GPS epoch
+    let seconds: u32 = 1_400_000_000;
+    let mut answer = [0x0D, 0, 0, 0, 0, 64];
+    answer[1..5].copy_from_slice(&seconds.to_le_bytes());
+    let mut radio = MockRadio::new();
+    radio.set_time_step(10);
+    radio.queue_rx_data(&build_downlink(&session, 0, &[], Some(0), &answer));
+    let mut device =
+        LoRaWANDevice::new(radio, config, US915::new(), OperatingMode::ClassA).unwrap();
+    assert_eq!(device.network_time().time_offset(), 0);
+
+    device.request_device_time().unwrap();
+    device.send_data(1, &[0x01], false).unwrap();
+
+    // The uplink carried the DeviceTimeReq in FOpts
+    let frame = device.get_mac_layer().get_radio().get_last_tx().unwrap();
+    assert_eq!(frame[5] & 0x0F, 1);
+    assert_eq!(frame[8], 0x0D);
+
+    // The answer arrives in RX1
+    for _ in 0..200 {
+        device.process().unwrap();
+    }
+    let radio = device.get_mac_layer().get_radio();
+    let gps_time_ms = device.network_time().gps_time_ms(radio).unwrap();
+    assert!(gps_time_ms >= 1_400_000_000_250);
+    assert!(gps_time_ms < 1_400_000_010_000);
+    assert_ne!(device.network_time().time_offset(), 0);
+    assert_eq!(
+        device.network_time().unix_time(radio).unwrap(),
+        (gps_time_ms / 1_000) as u32 + 315_964_800
+    );
+}
@@ -1,7 +1,38 @@
 #![no_std]
 
+use core::cell::Cell;
 use heapless::Vec;
-use lorawan::radio::traits::{Radio, RxConfig, TxConfig};
+use lorawan::radio::traits::{ModulationParams, Radio, RadioCapabilities, RxConfig, TxConfig};
+use lorawan::timing::{has_elapsed, Clock};
+
+/// Manually advanced clock for timing tests
+#[derive(Debug, Default)]
+pub struct MockClock {
+    now: Cell<u32>,
+}
+
+impl MockClock {
+    /// Create new mock clock at time 0
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set current time
+    pub fn set(&self, time: u32) {
+        self.now.set(time);
+    }
+
+    /// Advance current time
+    pub fn advance(&self, ms: u32) {
+        self.now.set(self.now.get().wrapping_add(ms));
+    }
+}
+
+impl Clock for MockClock {
+    fn now_ms(&self) -> u32 {
+        self.now.get()
+    }
+}
 
 /// Mock radio error type
 #[derive(Debug)]
@@ -10,15 +41,58 @@ pub enum MockError {
     Error,
 }
 
+/// Radio call that can be made to fail with `MockRadio::fail_next`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MockCall {
+    Init,
+    SetFrequency,
+    SetTxPower,
+    ConfigureTx,
+    ConfigureRx,
+    Transmit,
+    Receive,
+    GetRssi,
+    GetSnr,
+    Cad,
+    RandomU32,
+    IsTransmitting,
+    SetRxGain,
+    SetLowPowerMode,
+    Sleep,
+    Standby,
+    Reset,
+}
+
 /// Mock radio for testing
 #[derive(Clone)]
 pub struct MockRadio {
     frequency: u32,
     power: i8,
     last_tx: Option<Vec<u8, 256>>,
+    last_tx_frequency: u32,
+    last_tx_config: Option<TxConfig>,
+    rx_configs: Vec<RxConfig, 16>,
+    /// Every `configure_tx` call
+    tx_configs: Vec<TxConfig, 32>,
+    /// Every transmission with the TxConfig in effect, oldest dropped when full
+    tx_log: Vec<(TxConfig, Vec<u8, 256>), 32>,
     rx_data: Option<Vec<u8, 256>>,
+    rx_queue: Vec<Vec<u8, 256>, 8>,
+    /// Downlinks received in order once the time reaches theirs
+    scheduled_rx: Vec<(u32, Vec<u8, 256>), 8>,
     error_mode: bool,
-    time_counter: u32,
+    /// Calls failing once, in addition to every call in error mode
+    failing_calls: Vec<MockCall, 8>,
+    time_counter: Cell<u32>,
+    time_step: u32,
+    rssi: i16,
+    snr: i8,
+    busy_frequencies: Vec<u32, 8>,
+    cad_result: bool,
+    cad_count: u32,
+    /// State of the xorshift generator behind `random_u32`
+    random_state: u32,
+    capabilities: RadioCapabilities,
 }
 
 impl MockRadio {
@@ -28,9 +102,33 @@ impl MockRadio {
             frequency: 0,
             power: 0,
             last_tx: None,
+            last_tx_frequency: 0,
+            last_tx_config: None,
+            rx_configs: Vec::new(),
+            tx_configs: Vec::new(),
+            tx_log: Vec::new(),
             rx_data: None,
+            rx_queue: Vec::new(),
+            scheduled_rx: Vec::new(),
             error_mode: false,
-            time_counter: 0,
+            failing_calls: Vec::new(),
+            time_counter: Cell::new(0),
+            time_step: 0,
+            rssi: -50,
+            snr: 10,
+            busy_frequencies: Vec::new(),
+            cad_result: false,
+            cad_count: 0,
+            random_state: 0x2545_F491,
+            capabilities: RadioCapabilities {
+                continuous_rx: true,
+                cad: true,
+                supports_fsk: false,
+                min_power_dbm: -9,
+                max_power_dbm: 22,
+                min_frequency: 150_000_000,
+                max_frequency: 960_000_000,
+            },
         }
     }
 
@@ -41,12 +139,25 @@ impl MockRadio {
         self.rx_data = Some(rx_data);
     }
 
-    /// Simulate join accept timing
-    pub fn simulate_join_accept(&mut self, data: &[u8]) {
-        // Store data for RX1 window
-        self.set_rx_data(data);
-        // Set time to RX1 window
-        self.time_counter = 5000; // 5 seconds, typical RX1 delay
+    /// Queue data for a later receive call, an empty frame means nothing is received
+    pub fn queue_rx_data(&mut self, data: &[u8]) {
+        let mut rx_data = Vec::new();
+        rx_data.extend_from_slice(data).unwrap();
+        self.rx_queue.push(rx_data).unwrap();
+    }
+
+    /// Schedule data to be received once the time reaches `available_at`
+    ///
+    /// Scheduled frames are received in order, after the queued ones.
+    pub fn schedule_rx_data(&mut self, data: &[u8], available_at: u32) {
+        let mut rx_data = Vec::new();
+        rx_data.extend_from_slice(data).unwrap();
+        self.scheduled_rx.push((available_at, rx_data)).unwrap();
+    }
+
+    /// Get the number of scheduled frames not received yet
+    pub fn scheduled_rx_count(&self) -> usize {
+        self.scheduled_rx.len()
     }
 
     /// Get last transmitted data
@@ -54,6 +165,61 @@ impl MockRadio {
         self.last_tx.as_ref().map(|v| v.as_slice())
     }
 
+    /// Get frequency of last transmission
+    pub fn get_last_tx_frequency(&self) -> u32 {
+        self.last_tx_frequency
+    }
+
+    /// Get configuration of last transmission
+    pub fn get_last_tx_config(&self) -> Option<TxConfig> {
+        self.last_tx_config
+    }
+
+    /// Get the transmit configurations applied since creation
+    pub fn get_tx_configs(&self) -> &[TxConfig] {
+        &self.tx_configs
+    }
+
+    /// Get every transmission with the TX configuration in effect, in order
+    pub fn get_tx_log(&self) -> &[(TxConfig, Vec<u8, 256>)] {
+        &self.tx_log
+    }
+
+    /// Get the receive configurations applied since creation
+    pub fn get_rx_configs(&self) -> &[RxConfig] {
+        &self.rx_configs
+    }
+
+    /// Set RSSI reported on free channels
+    pub fn set_rssi(&mut self, rssi: i16) {
+        self.rssi = rssi;
+    }
+
+    /// Mark a frequency as occupied by another transmitter
+    pub fn set_channel_busy(&mut self, frequency: u32) {
+        self.busy_frequencies.push(frequency).unwrap();
+    }
+
+    /// Set the result of CAD on channels not marked busy
+    pub fn set_cad_result(&mut self, detected: bool) {
+        self.cad_result = detected;
+    }
+
+    /// Get the number of CADs run since creation
+    pub fn get_cad_count(&self) -> u32 {
+        self.cad_count
+    }
+
+    /// Seed the generator behind `random_u32`, 0 is replaced by 1
+    pub fn set_random_seed(&mut self, seed: u32) {
+        self.random_state = seed.max(1);
+    }
+
+    /// Set the capabilities reported by `capabilities`
+    pub fn set_capabilities(&mut self, capabilities: RadioCapabilities) {
+        self.capabilities = capabilities;
+    }
+
     /// Set error mode
     pub fn set_error_mode(&mut self, enabled: bool) {
         self.error_mode = enabled;
@@ -61,7 +227,51 @@ impl MockRadio {
 
     /// Set current time
     pub fn set_time(&mut self, time: u32) {
-        self.time_counter = time;
+        self.time_counter.set(time);
+    }
+
+    /// Set SNR reported for received frames
+    pub fn set_snr(&mut self, snr: i8) {
+        self.snr = snr;
+    }
+
+    /// Advance the time by `step` ms every time it is read
+    pub fn set_time_step(&mut self, step: u32) {
+        self.time_step = step;
+    }
+
+    /// Make the next `call` fail once
+    pub fn fail_next(&mut self, call: MockCall) {
+        self.failing_calls.push(call).unwrap();
+    }
+
+    /// Fail a call in error mode, or if it was set to fail once
+    fn check(&mut self, call: MockCall) -> Result<(), MockError> {
+        if self.error_mode {
+            return Err(MockError::Error);
+        }
+        match self.failing_calls.iter().position(|&c| c == call) {
+            Some(i) => {
+                self.failing_calls.remove(i);
+                Err(MockError::Error)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Take the next frame to receive, if any
+    fn next_rx(&mut self) -> Option<Vec<u8, 256>> {
+        if let Some(rx_data) = self.rx_data.take() {
+            return Some(rx_data);
+        }
+        if !self.rx_queue.is_empty() {
+            return Some(self.rx_queue.remove(0));
+        }
+        let now = self.time_counter.get();
+        match self.scheduled_rx.first() {
+            Some(&(at, _)) if has_elapsed(now, at) => Some(self.scheduled_rx.remove(0).1),
+            _ => None,
+        }
     }
 }
 
@@ -69,130 +279,128 @@ impl Radio for MockRadio {
     type Error = MockError;
 
     fn init(&mut self) -> Result<(), Self::Error> {
-        if self.error_mode {
-            Err(MockError::Error)
-        } else {
-            Ok(())
-        }
+        self.check(MockCall::Init)
     }
 
     fn set_frequency(&mut self, freq: u32) -> Result<(), Self::Error> {
-        if self.error_mode {
-            Err(MockError::Error)
-        } else {
-            self.frequency = freq;
-            Ok(())
-        }
+        self.check(MockCall::SetFrequency)?;
+        self.frequency = freq;
+        Ok(())
+    }
+
+    fn get_frequency(&self) -> u32 {
+        self.frequency
     }
 
     fn set_tx_power(&mut self, power: i8) -> Result<(), Self::Error> {
-        if self.error_mode {
-            Err(MockError::Error)
-        } else {
-            self.power = power;
-            Ok(())
-        }
+        self.check(MockCall::SetTxPower)?;
+        self.power = power;
+        Ok(())
     }
 
     fn configure_tx(&mut self, config: TxConfig) -> Result<(), Self::Error> {
-        if self.error_mode {
-            Err(MockError::Error)
-        } else {
-            self.frequency = config.frequency;
-            self.power = config.power;
-            Ok(())
-        }
+        self.check(MockCall::ConfigureTx)?;
+        self.frequency = config.frequency;
+        self.power = config.power;
+        self.last_tx_config = Some(config);
+        let _ = self.tx_configs.push(config);
+        Ok(())
     }
 
     fn configure_rx(&mut self, config: RxConfig) -> Result<(), Self::Error> {
-        if self.error_mode {
-            Err(MockError::Error)
-        } else {
-            self.frequency = config.frequency;
-            Ok(())
-        }
+        self.check(MockCall::ConfigureRx)?;
+        self.frequency = config.frequency;
+        let _ = self.rx_configs.push(config);
+        Ok(())
     }
 
     fn transmit(&mut self, data: &[u8]) -> Result<(), Self::Error> {
-        if self.error_mode {
-            Err(MockError::Error)
-        } else {
-            let mut tx_data = Vec::new();
-            tx_data.extend_from_slice(data).unwrap();
-            self.last_tx = Some(tx_data);
-            Ok(())
+        self.check(MockCall::Transmit)?;
+        let mut tx_data = Vec::new();
+        tx_data.extend_from_slice(data).unwrap();
+        if let Some(config) = self.last_tx_config {
+            if self.tx_log.is_full() {
+                self.tx_log.remove(0);
+            }
+            let _ = self.tx_log.push((config, tx_data.clone()));
         }
+        self.last_tx = Some(tx_data);
+        self.last_tx_frequency = self.frequency;
+        Ok(())
     }
 
     fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
-        if self.error_mode {
-            Err(MockError::Error)
-        } else if let Some(rx_data) = self.rx_data.take() {
-            let len = rx_data.len().min(buffer.len());
-            buffer[..len].copy_from_slice(&rx_data[..len]);
-            Ok(len)
-        } else {
-            Ok(0)
-        }
+        self.check(MockCall::Receive)?;
+        let Some(rx_data) = self.next_rx() else {
+            return Ok(0);
+        };
+        let len = rx_data.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&rx_data[..len]);
+        Ok(len)
     }
 
     fn get_rssi(&mut self) -> Result<i16, Self::Error> {
-        if self.error_mode {
-            Err(MockError::Error)
+        self.check(MockCall::GetRssi)?;
+        if self.busy_frequencies.contains(&self.frequency) {
+            Ok(-30) // Someone else is transmitting
         } else {
-            Ok(-50) // Mock RSSI value
+            Ok(self.rssi)
         }
     }
 
     fn get_snr(&mut self) -> Result<i8, Self::Error> {
-        if self.error_mode {
-            Err(MockError::Error)
-        } else {
-            Ok(10) // Mock SNR value
-        }
+        self.check(MockCall::GetSnr)?;
+        Ok(self.snr)
+    }
+
+    fn cad(&mut self, _params: &ModulationParams) -> Result<bool, Self::Error> {
+        self.check(MockCall::Cad)?;
+        self.cad_count += 1;
+        Ok(self.cad_result || self.busy_frequencies.contains(&self.frequency))
+    }
+
+    fn random_u32(&mut self) -> Result<u32, Self::Error> {
+        self.check(MockCall::RandomU32)?;
+        let mut x = self.random_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random_state = x;
+        Ok(x)
     }
 
     fn is_transmitting(&mut self) -> Result<bool, Self::Error> {
-        if self.error_mode {
-            Err(MockError::Error)
-        } else {
-            Ok(false)
-        }
+        self.check(MockCall::IsTransmitting)?;
+        Ok(false)
     }
 
     fn set_rx_gain(&mut self, _gain: u8) -> Result<(), Self::Error> {
-        if self.error_mode {
-            Err(MockError::Error)
-        } else {
-            Ok(())
-        }
+        self.check(MockCall::SetRxGain)
     }
 
     fn set_low_power_mode(&mut self, _enabled: bool) -> Result<(), Self::Error> {
-        if self.error_mode {
-            Err(MockError::Error)
-        } else {
-            Ok(())
-        }
+        self.check(MockCall::SetLowPowerMode)
     }
 
     fn sleep(&mut self) -> Result<(), Self::Error> {
-        if self.error_mode {
-            Err(MockError::Error)
-        } else {
-            Ok(())
-        }
+        self.check(MockCall::Sleep)
+    }
+
+    fn standby(&mut self) -> Result<(), Self::Error> {
+        self.check(MockCall::Standby)
     }
 
     fn reset(&mut self) -> Result<(), Self::Error> {
-        if self.error_mode {
-            Err(MockError::Error)
-        } else {
-            Ok(())
-        }
+        self.check(MockCall::Reset)
     }
 
     fn get_time(&self) -> u32 {
-        self.time_counter
+        let time = self.time_counter.get();
+        self.time_counter.set(time.wrapping_add(self.time_step));
+        time
+    }
+
+    fn capabilities(&self) -> RadioCapabilities {
+        self.capabilities
     }
 }
@@ -0,0 +1,215 @@
+#![no_std]
+
+use lorawan::{
+    applayer::multicast_setup::{
+        McGroupChange, MulticastSetup, MulticastSetupError, MULTICAST_SETUP_PORT,
+    },
+    class::OperatingMode,
+    config::device::{AESKey, DevAddr},
+    crypto,
+    lorawan::region::US915,
+};
+
+/// GenAppKey of the device
+const GEN_APP_KEY: [u8; 16] = [0x5A; 16];
+
+/// McKey of the group, as sent encrypted by the network
+const MC_KEY: [u8; 16] = [
+    0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF, 0x10, 0x32, 0x54, 0x76, 0x98, 0xBA, 0xDC, 0xFE,
+];
+
+/// GPS time of the tests in seconds
+const GPS_TIME: u32 = 1_300_000_000;
+
+/// Build a McGroupSetupReq for `group_id` with McAddr `addr`
+fn group_setup(group_id: u8, addr: [u8; 4], min_fcnt: u32, max_fcnt: u32) -> [u8; 30] {
+    let mc_ke_key = crypto::derive_mc_ke_key(&AESKey::new(GEN_APP_KEY));
+    let mut request = [0u8; 30];
+    request[0] = 0x02;
+    request[1] = group_id;
+    request[2..6].copy_from_slice(&addr);
+    request[6..22].copy_from_slice(&crypto::wrap_mc_key(&mc_ke_key, &AESKey::new(MC_KEY)));
+    request[22..26].copy_from_slice(&min_fcnt.to_le_bytes());
+    request[26..30].copy_from_slice(&max_fcnt.to_le_bytes());
+    request
+}
+
+/// Build a McClassCSessionReq or McClassBSessionReq (`cid` 4 or 5)
+fn session_req(
+    cid: u8,
+    group_id: u8,
+    session_time: u32,
+    timeout: u8,
+    frequency: u32,
+    dr: u8,
+) -> [u8; 11] {
+    let mut request = [0u8; 11];
+    request[0] = cid;
+    request[1] = group_id;
+    request[2..6].copy_from_slice(&session_time.to_le_bytes());
+    request[6] = timeout;
+    request[7..10].copy_from_slice(&(frequency / 100).to_le_bytes()[..3]);
+    request[10] = dr;
+    request
+}
+
+#[test]
+fn test_group_setup_unwraps_key() {
+    assert_eq!(MULTICAST_SETUP_PORT, 200);
+    let mut setup = MulticastSetup::new(&AESKey::new(GEN_APP_KEY));
+    let region = US915::new();
+
+    let outcome = setup
+        .handle_downlink(
+            &group_setup(1, [0x04, 0x03, 0x02, 0x01], 5, 100),
+            &region,
+            None,
+        )
+        .unwrap();
+    assert_eq!(outcome.answer.as_slice(), &[0x02, 0x01]);
+    let [McGroupChange::Setup(group)] = &outcome.changes[..] else {
+        panic!("group not set up");
+    };
+    let addr = DevAddr::new([0x04, 0x03, 0x02, 0x01]);
+    let (nwk_skey, app_skey) = crypto::derive_mc_session_keys(&AESKey::new(MC_KEY), addr);
+    assert_eq!(group.addr, addr);
+    assert_eq!(group.nwk_skey.as_bytes(), nwk_skey.as_bytes());
+    assert_eq!(group.app_skey.as_bytes(), app_skey.as_bytes());
+    assert_eq!(group.fcnt_down, 5);
+    assert_eq!(setup.group(1).unwrap().max_fcnt, 100);
+
+    // Setting up the McGroupID with another address replaces the group
+    let outcome = setup
+        .handle_downlink(
+            &group_setup(1, [0x05, 0x03, 0x02, 0x01], 0, 100),
+            &region,
+            None,
+        )
+        .unwrap();
+    assert!(matches!(
+        &outcome.changes[..],
+        [McGroupChange::Delete(old), McGroupChange::Setup(_)] if *old == addr
+    ));
+}
+
+#[test]
+fn test_group_status_and_delete() {
+    let mut setup = MulticastSetup::new(&AESKey::new(GEN_APP_KEY));
+    let region = US915::new();
+    setup
+        .handle_downlink(
+            &group_setup(0, [0x11, 0x22, 0x33, 0x44], 0, 10),
+            &region,
+            None,
+        )
+        .unwrap();
+    setup
+        .handle_downlink(
+            &group_setup(2, [0x55, 0x66, 0x77, 0x88], 0, 10),
+            &region,
+            None,
+        )
+        .unwrap();
+
+    // PackageVersionAns, then the status of groups 1 and 2: 2 groups defined
+    let outcome = setup
+        .handle_downlink(&[0x00, 0x01, 0x06], &region, None)
+        .unwrap();
+    assert_eq!(
+        outcome.answer.as_slice(),
+        &[0x00, 0x02, 0x01, 0x01, 0x24, 0x02, 0x55, 0x66, 0x77, 0x88]
+    );
+
+    let outcome = setup.handle_downlink(&[0x03, 0x00], &region, None).unwrap();
+    assert_eq!(outcome.answer.as_slice(), &[0x03, 0x00]);
+    assert!(matches!(
+        &outcome.changes[..],
+        [McGroupChange::Delete(addr)] if *addr == DevAddr::new([0x11, 0x22, 0x33, 0x44])
+    ));
+    let outcome = setup.handle_downlink(&[0x03, 0x00], &region, None).unwrap();
+    assert_eq!(outcome.answer.as_slice(), &[0x03, 0x04]);
+    assert!(setup.group(0).is_none());
+
+    assert_eq!(
+        setup
+            .handle_downlink(&[0x02, 0x00, 0x01], &region, None)
+            .err(),
+        Some(MulticastSetupError::InvalidLength)
+    );
+    assert_eq!(
+        setup.handle_downlink(&[0x06], &region, None).err(),
+        Some(MulticastSetupError::UnknownCommand(0x06))
+    );
+}
+
+#[test]
+fn test_class_c_session() {
+    let mut setup = MulticastSetup::new(&AESKey::new(GEN_APP_KEY));
+    let region = US915::new();
+    setup
+        .handle_downlink(
+            &group_setup(0, [0x11, 0x22, 0x33, 0x44], 0, 10),
+            &region,
+            None,
+        )
+        .unwrap();
+
+    // Starts in 300 s, lasts 2^6 s on 923.3 MHz DR8
+    let request = session_req(0x04, 0, GPS_TIME + 300, 6, 923_300_000, 8);
+    let outcome = setup
+        .handle_downlink(&request, &region, Some(GPS_TIME))
+        .unwrap();
+    assert_eq!(outcome.answer.as_slice(), &[0x04, 0x00, 0x2C, 0x01, 0x00]);
+    let session = outcome.session.unwrap();
+    assert_eq!(session.mode, OperatingMode::ClassC);
+    assert_eq!(session.time_to_start_s, 300);
+    assert_eq!(session.duration_s, 64);
+    assert_eq!(session.frequency, 923_300_000);
+    assert_eq!(session.data_rate, 8);
+
+    // Started 10 s ago, 54 s left
+    let request = session_req(0x04, 0, GPS_TIME - 10, 6, 923_300_000, 8);
+    let outcome = setup
+        .handle_downlink(&request, &region, Some(GPS_TIME))
+        .unwrap();
+    assert_eq!(outcome.answer.as_slice(), &[0x04, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(outcome.session.unwrap().duration_s, 54);
+
+    // Without the device time the session starts right away
+    let outcome = setup.handle_downlink(&request, &region, None).unwrap();
+    assert_eq!(outcome.session.unwrap().time_to_start_s, 0);
+
+    // Undefined group, frequency outside of the region, unknown data rate
+    let request = session_req(0x04, 3, GPS_TIME, 6, 868_100_000, 15);
+    let outcome = setup
+        .handle_downlink(&request, &region, Some(GPS_TIME))
+        .unwrap();
+    assert_eq!(outcome.answer.as_slice(), &[0x04, 0x1F]);
+    assert!(outcome.session.is_none());
+}
+
+#[test]
+fn test_class_b_session() {
+    let mut setup = MulticastSetup::new(&AESKey::new(GEN_APP_KEY));
+    let region = US915::new();
+    setup
+        .handle_downlink(
+            &group_setup(1, [0x11, 0x22, 0x33, 0x44], 0, 10),
+            &region,
+            None,
+        )
+        .unwrap();
+
+    // Periodicity 3 on the default ping slot channel, 2^2 beacon periods
+    let request = session_req(0x05, 1, GPS_TIME + 128, 0x32, 0, 8);
+    let outcome = setup
+        .handle_downlink(&request, &region, Some(GPS_TIME))
+        .unwrap();
+    assert_eq!(outcome.answer.as_slice(), &[0x05, 0x01, 0x80, 0x00, 0x00]);
+    let session = outcome.session.unwrap();
+    assert_eq!(session.mode, OperatingMode::ClassB);
+    assert_eq!(session.addr, DevAddr::new([0x11, 0x22, 0x33, 0x44]));
+    assert_eq!(session.duration_s, 512);
+    assert_eq!(session.periodicity, 3);
+    assert_eq!(session.frequency, 0);
+}
@@ -0,0 +1,958 @@
+#![no_std]
+
+use lorawan::{
+    class::OperatingMode,
+    config::device::{AESKey, DevAddr, DeviceConfig, SessionState},
+    device::LoRaWANDevice,
+    lorawan::{
+        commands::MacCommand,
+        mac::{MacError, MacLayer},
+        region::{
+            AS923Group, ChannelSelection, DataRate, Region, AS923, CN470, EU868, IN865, KR920,
+            RU864, US915,
+        },
+    },
+};
+
+mod mock;
+use mock::MockRadio;
+
+#[test]
+fn test_as923_frequency_groups() {
+    let as923_1 = AS923::new();
+    assert_eq!(as923_1.get_channel(0).unwrap().frequency, 923_200_000);
+    assert_eq!(as923_1.get_channel(1).unwrap().frequency, 923_400_000);
+    assert_eq!(as923_1.rx2_window(), (923_200_000, DataRate::SF10BW125));
+    assert!(as923_1.is_valid_frequency(915_000_000));
+    assert!(as923_1.is_valid_frequency(927_900_000));
+    assert!(!as923_1.is_valid_frequency(928_100_000));
+
+    let as923_2 = AS923::with_group(AS923Group::Group2);
+    assert_eq!(as923_2.get_channel(0).unwrap().frequency, 921_400_000);
+    assert_eq!(as923_2.rx2_frequency(), 921_400_000);
+    assert!(as923_2.is_valid_frequency(921_400_000));
+    assert!(!as923_2.is_valid_frequency(923_200_000));
+
+    let as923_3 = AS923::with_group(AS923Group::Group3);
+    assert_eq!(as923_3.get_channel(0).unwrap().frequency, 916_600_000);
+    assert_eq!(as923_3.get_channel(1).unwrap().frequency, 916_800_000);
+    assert!(as923_3.is_valid_frequency(916_600_000));
+    assert!(!as923_3.is_valid_frequency(922_000_000));
+
+    let as923_4 = AS923::with_group(AS923Group::Group4);
+    assert_eq!(as923_4.get_channel(0).unwrap().frequency, 917_300_000);
+    assert_eq!(as923_4.rx2_frequency(), 917_300_000);
+    assert!(as923_4.is_valid_frequency(917_500_000));
+    assert!(!as923_4.is_valid_frequency(916_000_000));
+}
+
+#[test]
+fn test_as923_dwell_time_payload_sizes() {
+    let mut region = AS923::new();
+
+    // No dwell time limit by default
+    assert_eq!(region.max_payload_size(0), 59);
+    assert_eq!(region.max_payload_size(2), 59);
+    assert_eq!(region.max_payload_size(5), 250);
+
+    // 400 ms dwell time makes DR0/DR1 unusable and shrinks DR2-DR4
+    assert!(region.set_tx_params(true, false, 5));
+    assert!(region.uplink_dwell_time());
+    assert_eq!(region.max_payload_size(0), 0);
+    assert_eq!(region.max_payload_size(1), 0);
+    assert_eq!(region.max_payload_size(2), 19);
+    assert_eq!(region.max_payload_size(3), 61);
+    assert_eq!(region.max_payload_size(4), 133);
+    assert_eq!(region.max_payload_size(5), 250);
+    assert_eq!(region.max_eirp(), 16);
+
+    // The uplink data rate cannot drop below DR2
+    region.set_data_rate(0);
+    assert_eq!(region.data_rate(), 2);
+}
+
+#[test]
+fn test_tx_param_setup_enforces_dwell_time() {
+    let mut mac = MacLayer::new(MockRadio::new(), AS923::new(), SessionState::new());
+    mac.get_region_mut().set_data_rate(0);
+
+    mac.process_mac_command(MacCommand::TxParamSetupReq {
+        downlink_dwell_time: true,
+        uplink_dwell_time: true,
+        max_eirp: 0x0F,
+    })
+    .unwrap();
+
+    let region = mac.get_region();
+    assert!(region.uplink_dwell_time());
+    assert!(region.downlink_dwell_time());
+    assert_eq!(region.max_eirp(), 36);
+    assert_eq!(region.data_rate(), 2);
+    assert_eq!(region.max_payload_size(region.data_rate()), 19);
+
+    // RX1 data rate also respects the downlink dwell time limit
+    let channel = region.get_channel(0).unwrap().clone();
+    assert_eq!(region.rx1_window(&channel, 0).1, DataRate::SF10BW125);
+}
+
+#[test]
+fn test_in865_frequency_bounds() {
+    let region = IN865::new();
+
+    assert_eq!(region.channels(), 3);
+    assert_eq!(region.get_channel(0).unwrap().frequency, 865_062_500);
+    assert_eq!(region.get_channel(1).unwrap().frequency, 865_402_500);
+    assert_eq!(region.get_channel(2).unwrap().frequency, 865_985_000);
+
+    assert!(region.is_valid_frequency(865_000_000));
+    assert!(region.is_valid_frequency(866_550_000));
+    assert!(region.is_valid_frequency(867_000_000));
+    assert!(!region.is_valid_frequency(864_900_000));
+    assert!(!region.is_valid_frequency(868_100_000));
+
+    assert!(region.is_valid_data_rate(5));
+    assert!(!region.is_valid_data_rate(6));
+    assert_eq!(region.max_payload_size(0), 59);
+    assert_eq!(region.max_payload_size(5), 250);
+}
+
+#[test]
+fn test_in865_rx_windows() {
+    let mut region = IN865::new();
+    let channel = region.get_channel(1).unwrap().clone();
+
+    region.set_data_rate(5);
+    assert_eq!(
+        region.rx1_window(&channel, 0),
+        (865_402_500, DataRate::SF7BW125)
+    );
+    assert_eq!(region.rx1_window(&channel, 3).1, DataRate::SF10BW125);
+    assert_eq!(region.rx1_window(&channel, 5).1, DataRate::SF12BW125);
+
+    // Offsets 6 and 7 raise the data rate, capped at DR5
+    region.set_data_rate(3);
+    assert_eq!(region.rx1_window(&channel, 6).1, DataRate::SF8BW125);
+    assert_eq!(region.rx1_window(&channel, 7).1, DataRate::SF7BW125);
+    region.set_data_rate(4);
+    assert_eq!(region.rx1_window(&channel, 7).1, DataRate::SF7BW125);
+
+    assert_eq!(region.rx2_window(), (866_550_000, DataRate::SF10BW125));
+}
+
+#[test]
+fn test_in865_rx1_params_with_offset() {
+    let mut session = SessionState::new();
+    session.rx1_dr_offset = 2;
+
+    let mut mac = MacLayer::new(MockRadio::new(), IN865::new(), session);
+    mac.get_region_mut().set_data_rate(5);
+
+    let (frequency, data_rate) = mac.get_rx1_params().unwrap();
+    assert!(mac.get_region().is_valid_frequency(frequency));
+    assert_eq!(data_rate, DataRate::SF9BW125);
+}
+
+#[test]
+fn test_in865_new_channel_req() {
+    let mut mac = MacLayer::new(MockRadio::new(), IN865::new(), SessionState::new());
+
+    mac.process_mac_command(MacCommand::NewChannelReq {
+        ch_index: 15,
+        freq: 866_100_000,
+        min_dr: 0,
+        max_dr: 5,
+    })
+    .unwrap();
+
+    let region = mac.get_region();
+    assert_eq!(region.channels(), 16);
+    assert_eq!(region.get_channel(15).unwrap().frequency, 866_100_000);
+    assert!(region.get_channel(15).unwrap().enabled);
+    assert!(!region.get_channel(3).unwrap().enabled);
+
+    // Default channels cannot be modified
+    let mut mac = MacLayer::new(MockRadio::new(), IN865::new(), SessionState::new());
+    mac.process_mac_command(MacCommand::NewChannelReq {
+        ch_index: 0,
+        freq: 866_100_000,
+        min_dr: 0,
+        max_dr: 5,
+    })
+    .unwrap();
+    assert_eq!(
+        mac.get_region().get_channel(0).unwrap().frequency,
+        865_062_500
+    );
+}
+
+#[test]
+fn test_in865_device() {
+    let config = DeviceConfig::new_otaa([0x01; 8], [0x02; 8], AESKey::new([0x03; 16]));
+    let mut device = LoRaWANDevice::new(
+        MockRadio::new(),
+        config,
+        IN865::new(),
+        OperatingMode::ClassA,
+    )
+    .expect("Failed to create device");
+
+    device
+        .join_otaa([0x01; 8], [0x02; 8], AESKey::new([0x03; 16]))
+        .expect("Join failed");
+}
+
+#[test]
+fn test_kr920_region() {
+    let region = KR920::new();
+
+    assert_eq!(region.channels(), 3);
+    assert_eq!(region.get_channel(0).unwrap().frequency, 922_100_000);
+    assert!(region.is_valid_frequency(920_900_000));
+    assert!(region.is_valid_frequency(923_300_000));
+    assert!(!region.is_valid_frequency(923_500_000));
+    assert_eq!(region.rx2_window(), (921_900_000, DataRate::SF12BW125));
+
+    let lbt = region.lbt_config().expect("KR920 requires LBT");
+    assert_eq!(lbt.threshold_dbm, -65);
+}
+
+#[test]
+fn test_kr920_lbt_hops_busy_channel() {
+    let mut radio = MockRadio::new();
+    radio.set_rssi(-90);
+    radio.set_channel_busy(922_300_000);
+
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x01; 16]),
+        AESKey::new([0x02; 16]),
+    );
+    let mut mac = MacLayer::new(radio, KR920::new(), session);
+
+    // The first channel picked is busy, the uplink goes out on the next one
+    mac.send_unconfirmed(1, b"hello").unwrap();
+    assert!(mac.get_radio().get_last_tx().is_some());
+    assert_eq!(mac.get_radio().get_last_tx_frequency(), 922_500_000);
+}
+
+#[test]
+fn test_kr920_lbt_all_channels_busy() {
+    let mut radio = MockRadio::new();
+    radio.set_rssi(-60);
+
+    let mut mac = MacLayer::new(radio, KR920::new(), SessionState::new());
+
+    let result = mac.join_request([0x01; 8], [0x02; 8], AESKey::new([0x03; 16]));
+    assert!(matches!(result, Err(MacError::ChannelBusy)));
+    assert!(mac.get_radio().get_last_tx().is_none());
+}
+
+#[test]
+fn test_cn470_channel_plan() {
+    let mut region = CN470::new();
+
+    assert_eq!(region.channels(), 96);
+    assert_eq!(region.get_max_channels(), 96);
+    assert_eq!(region.get_channel(0).unwrap().frequency, 470_300_000);
+    assert_eq!(region.get_channel(95).unwrap().frequency, 489_300_000);
+    assert_eq!(region.rx2_window(), (505_300_000, DataRate::SF12BW125));
+
+    // Common 8-channel gateways
+    region.set_sub_band(1);
+    let enabled = region.get_enabled_channels();
+    assert_eq!(enabled.len(), 8);
+    assert_eq!(enabled[0].frequency, 471_900_000);
+
+    // ChMaskCntl 6 re-enables all channels
+    region.apply_channel_mask(0, 6);
+    assert_eq!(region.get_enabled_channels().len(), 96);
+}
+
+#[test]
+fn test_cn470_rx1_channel_mapping() {
+    let region = CN470::new();
+
+    for (uplink, downlink) in [(0u8, 0u8), (1, 1), (47, 47), (48, 0), (50, 2), (95, 47)] {
+        assert_eq!(CN470::rx1_channel(uplink), downlink);
+
+        let channel = region.get_channel(uplink).unwrap();
+        let (rx1_freq, rx1_dr) = region.rx1_window(channel, 0);
+        assert_eq!(rx1_freq, 500_300_000 + downlink as u32 * 200_000);
+        assert_eq!(rx1_dr, DataRate::SF12BW125);
+    }
+}
+
+#[test]
+fn test_ru864_region() {
+    let mut region = RU864::new();
+
+    assert_eq!(region.channels(), 2);
+    assert_eq!(region.get_channel(0).unwrap().frequency, 868_900_000);
+    assert_eq!(region.get_channel(1).unwrap().frequency, 869_100_000);
+
+    // Frequency validation
+    assert!(region.is_valid_frequency(864_000_000));
+    assert!(region.is_valid_frequency(868_900_000));
+    assert!(region.is_valid_frequency(870_000_000));
+    assert!(!region.is_valid_frequency(863_900_000));
+    assert!(!region.is_valid_frequency(870_100_000));
+
+    // RX windows
+    assert_eq!(region.rx2_window(), (869_100_000, DataRate::SF12BW125));
+    let channel = region.get_channel(0).unwrap().clone();
+    region.set_data_rate(5);
+    assert_eq!(
+        region.rx1_window(&channel, 1),
+        (868_900_000, DataRate::SF8BW125)
+    );
+
+    // Max payload sizes per DR
+    assert_eq!(region.max_payload_size(0), 59);
+    assert_eq!(region.max_payload_size(2), 59);
+    assert_eq!(region.max_payload_size(3), 123);
+    assert_eq!(region.max_payload_size(6), 250);
+    assert_eq!(region.max_payload_size(7), 0);
+
+    // Class B beacon channel
+    let beacons = region.get_beacon_channels();
+    assert_eq!(beacons.len(), 1);
+    assert_eq!(beacons[0].frequency, 869_100_000);
+}
+
+#[test]
+fn test_ru864_new_channel_req() {
+    let mut mac = MacLayer::new(MockRadio::new(), RU864::new(), SessionState::new());
+
+    mac.process_mac_command(MacCommand::NewChannelReq {
+        ch_index: 2,
+        freq: 864_100_000,
+        min_dr: 0,
+        max_dr: 6,
+    })
+    .unwrap();
+
+    let channel = mac.get_region().get_channel(2).unwrap();
+    assert_eq!(channel.frequency, 864_100_000);
+    assert_eq!(channel.max_dr, DataRate::SF7BW250);
+}
+
+#[test]
+fn test_us915_link_adr_enables_all_125khz_channels() {
+    let mut region = US915::new();
+    region.set_sub_band(1);
+    let mut mac = MacLayer::new(MockRadio::new(), region, SessionState::new());
+
+    // ChMaskCntl 6 turns all 125 kHz channels on, ChMask selects 500 kHz ones
+    mac.process_mac_command(MacCommand::LinkADRReq {
+        data_rate: 3,
+        tx_power: 5,
+        ch_mask: 0x0002,
+        ch_mask_cntl: 6,
+        nb_trans: 1,
+    })
+    .unwrap();
+
+    let region = mac.get_region();
+    let enabled = region.get_enabled_channels();
+    assert_eq!(enabled.len(), 65);
+    assert_eq!(
+        enabled
+            .iter()
+            .filter(|c| c.min_dr != DataRate::SF8BW500)
+            .count(),
+        64
+    );
+    assert!(region.get_channel(65).unwrap().enabled);
+    assert_eq!(region.data_rate(), 3);
+    assert_eq!(region.tx_power(), 5);
+    assert!(matches!(
+        mac.get_pending_commands(),
+        [MacCommand::LinkADRAns {
+            power_ack: true,
+            data_rate_ack: true,
+            channel_mask_ack: true,
+        }]
+    ));
+}
+
+#[test]
+fn test_link_adr_data_rate_outside_channel_ranges() {
+    let mut mac = MacLayer::new(MockRadio::new(), IN865::new(), SessionState::new());
+    mac.process_mac_command(MacCommand::NewChannelReq {
+        ch_index: 3,
+        freq: 866_000_000,
+        min_dr: 0,
+        max_dr: 2,
+    })
+    .unwrap();
+
+    // Only channel 3 stays enabled, DR5 is beyond its range
+    let link_adr_req = |data_rate| MacCommand::LinkADRReq {
+        data_rate,
+        tx_power: 1,
+        ch_mask: 0x0008,
+        ch_mask_cntl: 0,
+        nb_trans: 1,
+    };
+    mac.process_mac_command(link_adr_req(5)).unwrap();
+    mac.process_mac_command(link_adr_req(2)).unwrap();
+    assert_eq!(
+        &mac.get_pending_commands()[1..],
+        &[
+            MacCommand::LinkADRAns {
+                power_ack: true,
+                data_rate_ack: false,
+                channel_mask_ack: true,
+            },
+            MacCommand::LinkADRAns {
+                power_ack: true,
+                data_rate_ack: true,
+                channel_mask_ack: true,
+            },
+        ]
+    );
+    assert_eq!(mac.get_region().data_rate(), 2);
+
+    // DR8 uses the bandwidth of the 500 kHz channel, which only carries DR4
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), SessionState::new());
+    mac.process_mac_command(MacCommand::LinkADRReq {
+        data_rate: 8,
+        tx_power: 1,
+        ch_mask: 0x0001,
+        ch_mask_cntl: 7,
+        nb_trans: 1,
+    })
+    .unwrap();
+    assert_eq!(
+        mac.get_pending_commands(),
+        &[MacCommand::LinkADRAns {
+            power_ack: true,
+            data_rate_ack: false,
+            channel_mask_ack: true,
+        }]
+    );
+}
+
+#[test]
+fn test_us915_channel_mask_banks() {
+    let mut region = US915::new();
+
+    // ChMaskCntl 7 turns all 125 kHz channels off
+    region.apply_channel_mask(0x0001, 7);
+    assert_eq!(region.get_enabled_channels().len(), 1);
+    assert!(region.get_channel(64).unwrap().enabled);
+
+    // Banks 0-3 cover 16 125 kHz channels each, bank 4 the 500 kHz channels
+    region.apply_channel_mask(0xFF00, 1);
+    region.apply_channel_mask(0x0000, 4);
+    let enabled = region.get_enabled_channels();
+    assert_eq!(enabled.len(), 8);
+    assert_eq!(enabled[0].frequency, 902_300_000 + 24 * 200_000);
+
+    assert!(region.is_valid_channel_mask(0x00FF, 6));
+    assert!(!region.is_valid_channel_mask(0x0100, 7));
+    assert!(!region.is_valid_channel_mask(0x0000, 5));
+}
+
+#[test]
+fn test_new_channel_req_zero_frequency_disables_channel() {
+    let mut mac = MacLayer::new(MockRadio::new(), IN865::new(), SessionState::new());
+    mac.process_mac_command(MacCommand::NewChannelReq {
+        ch_index: 3,
+        freq: 866_000_000,
+        max_dr: 5,
+        min_dr: 0,
+    })
+    .unwrap();
+    assert_eq!(mac.get_region().enabled_channels().count(), 4);
+
+    // Frequency 0 removes the channel again
+    mac.process_mac_command(MacCommand::NewChannelReq {
+        ch_index: 3,
+        freq: 0,
+        max_dr: 0,
+        min_dr: 0,
+    })
+    .unwrap();
+    assert_eq!(mac.get_region().enabled_channels().count(), 3);
+
+    // Default channels cannot be disabled
+    mac.process_mac_command(MacCommand::NewChannelReq {
+        ch_index: 0,
+        freq: 0,
+        max_dr: 0,
+        min_dr: 0,
+    })
+    .unwrap();
+    assert_eq!(mac.get_region().enabled_channels().count(), 3);
+
+    assert_eq!(
+        &mac.get_pending_commands()[1..],
+        &[
+            MacCommand::NewChannelAns {
+                channel_freq_ok: true,
+                data_rate_ok: true,
+            },
+            MacCommand::NewChannelAns {
+                channel_freq_ok: false,
+                data_rate_ok: false,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_new_channel_req_out_of_range_index() {
+    let mut mac = MacLayer::new(MockRadio::new(), IN865::new(), SessionState::new());
+
+    // The index is refused, the next command of the downlink still processed
+    mac.process_mac_commands(&[
+        MacCommand::NewChannelReq {
+            ch_index: 200,
+            freq: 866_000_000,
+            max_dr: 5,
+            min_dr: 0,
+        },
+        MacCommand::RXTimingSetupReq { delay: 2 },
+    ])
+    .unwrap();
+    assert_eq!(mac.get_region().enabled_channels().count(), 3);
+    assert_eq!(
+        mac.get_pending_commands(),
+        &[
+            MacCommand::NewChannelAns {
+                channel_freq_ok: false,
+                data_rate_ok: true,
+            },
+            MacCommand::RXTimingSetupAns,
+        ]
+    );
+}
+
+#[test]
+fn test_added_channel_joins_rotation() {
+    let mut region = AS923::new();
+
+    assert!(region.add_or_modify_channel(2, 923_600_000, 0, 5));
+    assert_eq!(region.enabled_channels().count(), 3);
+    assert!(region
+        .enabled_channels()
+        .any(|c| c.frequency == 923_600_000));
+
+    // The new channel is used in the rotation
+    let mut used = [false; 3];
+    for _ in 0..3 {
+        let channel = region.get_next_channel().unwrap();
+        let index = (0..3)
+            .position(|i| region.get_channel(i).unwrap().frequency == channel.frequency)
+            .unwrap();
+        used[index] = true;
+    }
+    assert_eq!(used, [true; 3]);
+
+    // Unknown data rates and default channels are refused
+    assert!(!region.add_or_modify_channel(3, 923_800_000, 0, 9));
+    assert!(!region.add_or_modify_channel(0, 923_800_000, 0, 5));
+    assert!(!region.remove_channel(1));
+
+    assert!(region.remove_channel(2));
+    assert_eq!(region.enabled_channels().count(), 2);
+}
+
+#[test]
+fn test_us915_refuses_new_channel() {
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), SessionState::new());
+    mac.process_mac_command(MacCommand::NewChannelReq {
+        ch_index: 3,
+        freq: 915_000_000,
+        min_dr: 0,
+        max_dr: 3,
+    })
+    .unwrap();
+
+    assert_eq!(
+        mac.get_region().get_channel(3).unwrap().frequency,
+        902_900_000
+    );
+    assert_eq!(
+        mac.get_pending_commands(),
+        &[MacCommand::NewChannelAns {
+            channel_freq_ok: false,
+            data_rate_ok: true,
+        }]
+    );
+}
+
+#[test]
+fn test_dl_channel_req_moves_rx1() {
+    let mut mac = MacLayer::new(MockRadio::new(), IN865::new(), SessionState::new());
+    mac.process_mac_command(MacCommand::DlChannelReq {
+        ch_index: 1,
+        freq: 866_000_000,
+    })
+    .unwrap();
+
+    let channel = mac.get_region().get_channel(1).unwrap().clone();
+    assert_eq!(mac.rx1_window(&channel).0, 866_000_000);
+
+    // Other channels keep using the uplink frequency
+    let channel = mac.get_region().get_channel(0).unwrap().clone();
+    assert_eq!(mac.rx1_window(&channel).0, 865_062_500);
+
+    // Frequency 0 restores the uplink frequency
+    mac.process_mac_command(MacCommand::DlChannelReq {
+        ch_index: 1,
+        freq: 0,
+    })
+    .unwrap();
+    let channel = mac.get_region().get_channel(1).unwrap().clone();
+    assert_eq!(mac.rx1_window(&channel).0, 865_402_500);
+
+    // Undefined channels are refused
+    mac.process_mac_command(MacCommand::DlChannelReq {
+        ch_index: 5,
+        freq: 866_000_000,
+    })
+    .unwrap();
+
+    assert_eq!(
+        mac.get_pending_commands(),
+        &[
+            MacCommand::DlChannelAns {
+                channel_freq_ok: true,
+                uplink_freq_exists: true,
+            },
+            MacCommand::DlChannelAns {
+                channel_freq_ok: true,
+                uplink_freq_exists: true,
+            },
+            MacCommand::DlChannelAns {
+                channel_freq_ok: true,
+                uplink_freq_exists: false,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_time_on_air() {
+    assert_eq!(DataRate::SF7BW125.time_on_air_ms(14), 47);
+    assert_eq!(DataRate::SF12BW125.time_on_air_ms(14), 1_156);
+    assert_eq!(DataRate::SF8BW500.time_on_air_ms(14), 21);
+}
+
+#[test]
+fn test_ru864_duty_cycle_limits_uplinks() {
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x01; 16]),
+        AESKey::new([0x02; 16]),
+    );
+    let mut mac = MacLayer::new(MockRadio::new(), RU864::new(), session);
+    mac.get_region_mut().set_data_rate(0);
+    assert_eq!(mac.time_until_next_tx(), 0);
+
+    // A 14 byte SF12 uplink keeps the 1% band silent for 99 times its airtime
+    mac.send_unconfirmed(1, &[0x01]).unwrap();
+    let retry_after = 1_156 * 99;
+    assert_eq!(mac.time_until_next_tx(), retry_after);
+    assert!(matches!(
+        mac.send_unconfirmed(1, &[0x02]),
+        Err(MacError::DutyCycleLimited { retry_after_ms }) if retry_after_ms == retry_after
+    ));
+    assert_eq!(mac.get_session_state().fcnt_up, 1);
+
+    mac.get_radio_mut().set_time(retry_after - 1);
+    assert_eq!(mac.time_until_next_tx(), 1);
+    mac.get_radio_mut().set_time(retry_after);
+    mac.send_unconfirmed(1, &[0x02]).unwrap();
+    assert_eq!(mac.get_session_state().fcnt_up, 2);
+}
+
+#[test]
+fn test_eu868_duty_cycle_sub_bands() {
+    let region = EU868::new();
+    assert_eq!(region.channels(), 3);
+    assert_eq!(region.rx2_window(), (869_525_000, DataRate::SF12BW125));
+    assert_eq!(region.max_payload_size(4), 230);
+
+    // g1 at 1%, g2 at 0.1% and g3 at 10%
+    let duty_cycle = |frequency| {
+        region
+            .band_index(frequency)
+            .map(|index| region.bands()[index].duty_cycle)
+    };
+    assert_eq!(duty_cycle(868_100_000), Some(100));
+    assert_eq!(duty_cycle(868_800_000), Some(1_000));
+    assert_eq!(duty_cycle(869_525_000), Some(10));
+    assert_eq!(duty_cycle(869_300_000), None);
+
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x01; 16]),
+        AESKey::new([0x02; 16]),
+    );
+    let mut mac = MacLayer::new(MockRadio::new(), region, session);
+    mac.get_region_mut().set_data_rate(0);
+
+    // A 14 byte SF12 uplink in g1 blocks the next one for 99 times its airtime
+    mac.send_unconfirmed(1, &[0x01]).unwrap();
+    assert!(matches!(
+        mac.send_unconfirmed(1, &[0x02]),
+        Err(MacError::DutyCycleLimited { retry_after_ms }) if retry_after_ms == 1_156 * 99
+    ));
+
+    // Only a channel in g2 is left, 999 times the airtime after its uplink
+    mac.process_mac_command(MacCommand::NewChannelReq {
+        ch_index: 3,
+        freq: 868_800_000,
+        min_dr: 0,
+        max_dr: 5,
+    })
+    .unwrap();
+    mac.get_region_mut().apply_channel_mask(0x0008, 0);
+    mac.send_unconfirmed(1, &[0x02]).unwrap();
+    let (config, _) = mac.get_radio().get_tx_log().last().unwrap();
+    assert_eq!(config.frequency, 868_800_000);
+    assert!(mac.time_until_next_tx() >= 1_156 * 999);
+}
+
+#[test]
+fn test_duty_cycle_req_limits_all_bands() {
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x01; 16]),
+        AESKey::new([0x02; 16]),
+    );
+    let mut mac = MacLayer::new(MockRadio::new(), IN865::new(), session);
+    mac.get_region_mut().set_data_rate(5);
+
+    // IN865 has no duty cycle bands
+    mac.send_unconfirmed(1, &[0x01]).unwrap();
+    assert_eq!(mac.time_until_next_tx(), 0);
+
+    // 1/2^3 aggregated duty cycle
+    mac.process_mac_command(MacCommand::DutyCycleReq { max_duty_cycle: 3 })
+        .unwrap();
+    assert_eq!(mac.get_max_duty_cycle(), 3);
+
+    // The pending DutyCycleAns is sent in FOpts, 15 bytes in total
+    mac.send_unconfirmed(1, &[0x01]).unwrap();
+    assert_eq!(
+        mac.time_until_next_tx(),
+        DataRate::SF7BW125.time_on_air_ms(15) * 7
+    );
+}
+
+#[test]
+#[cfg(not(feature = "max-frame-64"))]
+fn test_us915_payload_size_limits() {
+    let session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x01; 16]),
+        AESKey::new([0x02; 16]),
+    );
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), session);
+    let payload = [0x55; 243];
+
+    // FRMPayload limit is the MACPayload limit minus FHDR and FPort
+    for (data_rate, max_size) in [(0u8, 11usize), (1, 53), (2, 125), (3, 242), (4, 242)] {
+        mac.get_region_mut().set_data_rate(data_rate);
+        assert!(matches!(
+            mac.send_unconfirmed(1, &payload[..max_size + 1]),
+            Err(MacError::InvalidPayloadSize { max_size: max }) if max == max_size
+        ));
+        mac.send_unconfirmed(1, &payload[..max_size]).unwrap();
+        assert_eq!(
+            mac.get_radio().get_last_tx().unwrap().len(),
+            max_size + 8 + 5
+        );
+    }
+
+    // Pending MAC answers in FOpts reduce the space left
+    mac.get_region_mut().set_data_rate(0);
+    mac.queue_mac_command(MacCommand::RXTimingSetupAns).unwrap();
+    assert!(matches!(
+        mac.send_unconfirmed(1, &payload[..11]),
+        Err(MacError::InvalidPayloadSize { max_size: 10 })
+    ));
+    assert!(matches!(
+        mac.time_until_uplink(11),
+        Err(MacError::InvalidPayloadSize { max_size: 10 })
+    ));
+    assert!(mac.time_until_uplink(10).is_ok());
+    assert_eq!(mac.get_session_state().fcnt_up, 5);
+}
+
+#[test]
+fn test_us915_random_channel_selection() {
+    let mut mac = MacLayer::new(MockRadio::new(), US915::new(), SessionState::new());
+    mac.configure_for_ttn().unwrap();
+    mac.enable_random_channels().unwrap();
+
+    let mut sequence = [0u32; 16];
+    for frequency in sequence.iter_mut() {
+        let channel = mac.get_next_channel().unwrap();
+        assert!(mac
+            .get_region()
+            .enabled_channels()
+            .any(|c| c.frequency == channel.frequency));
+        *frequency = channel.frequency;
+    }
+
+    // Not the round-robin order
+    let mut region = US915::new();
+    region.configure_ttn_us915();
+    assert!(sequence
+        .iter()
+        .any(|&frequency| frequency != region.get_next_channel().unwrap().frequency));
+
+    // The same seed gives the same sequence
+    let mut mac2 = MacLayer::new(MockRadio::new(), US915::new(), SessionState::new());
+    mac2.configure_for_ttn().unwrap();
+    mac2.enable_random_channels().unwrap();
+    for frequency in sequence {
+        assert_eq!(mac2.get_next_channel().unwrap().frequency, frequency);
+    }
+
+    // Only US915 supports it
+    let mut mac = MacLayer::new(MockRadio::new(), AS923::new(), SessionState::new());
+    assert!(matches!(
+        mac.enable_random_channels(),
+        Err(MacError::InvalidConfig)
+    ));
+}
+
+/// Index of the US915 uplink channel on `frequency`
+fn us915_channel_index(frequency: u32) -> usize {
+    if (frequency - 902_300_000) % 200_000 == 0 && frequency < 915_000_000 {
+        ((frequency - 902_300_000) / 200_000) as usize
+    } else {
+        64 + ((frequency - 903_000_000) / 1_600_000) as usize
+    }
+}
+
+#[test]
+fn test_us915_shuffled_hop_cycles() {
+    const CYCLES: usize = 450;
+    let mut region = US915::new();
+    region.configure_ttn_us915();
+    assert_eq!(region.channel_selection(), ChannelSelection::RoundRobin);
+    region.set_channel_selection(ChannelSelection::Random { seed: 0x00C0_FFEE });
+
+    let enabled = region.enabled_channels().count();
+    assert_eq!(enabled, 9);
+    let mut counts = [0usize; 72];
+    let mut firsts = [0usize; 72];
+    for _ in 0..CYCLES {
+        // Every enabled channel once per cycle
+        let mut cycle = [false; 72];
+        for position in 0..enabled {
+            let index = us915_channel_index(region.get_next_channel().unwrap().frequency);
+            assert!((8..16).contains(&index) || index == 65);
+            assert!(!cycle[index]);
+            cycle[index] = true;
+            counts[index] += 1;
+            if position == 0 {
+                firsts[index] += 1;
+            }
+        }
+    }
+
+    // Uniform coverage of the sub-band, and no fixed order
+    for index in (8..16).chain([65]) {
+        assert_eq!(counts[index], CYCLES);
+        assert!((25..=75).contains(&firsts[index]), "{}", firsts[index]);
+    }
+}
+
+#[test]
+fn test_us915_shuffled_full_band() {
+    let mut region = US915::new();
+    region.set_channel_selection(ChannelSelection::Random { seed: 42 });
+
+    let mut counts = [0usize; 72];
+    for _ in 0..72 * 50 {
+        counts[us915_channel_index(region.get_next_channel().unwrap().frequency)] += 1;
+    }
+    assert!(counts.iter().all(|&count| count == 50));
+
+    // Back to the sequential order
+    region.set_channel_selection(ChannelSelection::RoundRobin);
+    let first = region.get_next_channel().unwrap().frequency;
+    let second = region.get_next_channel().unwrap().frequency;
+    assert_eq!(us915_channel_index(second), us915_channel_index(first) + 1);
+}
+
+#[test]
+fn test_us915_lowest_airtime_selection() {
+    let mut region = US915::new();
+    region.configure_ttn_us915();
+    region.set_channel_selection(ChannelSelection::LowestAirtime);
+
+    // Without airtime the channels are used in turn
+    let mut counts = [0usize; 72];
+    for _ in 0..9 {
+        let channel = region.get_next_channel().unwrap();
+        counts[us915_channel_index(channel.frequency)] += 1;
+        region.record_airtime(channel.frequency, 100);
+    }
+    assert!((8..16).chain([65]).all(|index| counts[index] == 1));
+
+    // Busy channels are avoided until the others catch up
+    region.record_airtime(903_900_000, 500);
+    region.record_airtime(904_100_000, 500);
+    for _ in 0..7 * 5 {
+        let channel = region.get_next_channel().unwrap();
+        assert!(channel.frequency != 903_900_000 && channel.frequency != 904_100_000);
+        region.record_airtime(channel.frequency, 100);
+    }
+    let mut counts = [0usize; 72];
+    for _ in 0..9 {
+        let channel = region.get_next_channel().unwrap();
+        counts[us915_channel_index(channel.frequency)] += 1;
+        region.record_airtime(channel.frequency, 100);
+    }
+    assert!((8..16).chain([65]).all(|index| counts[index] == 1));
+}
+
+#[test]
+fn test_us915_beacon_channel_hopping() {
+    let region = US915::new();
+    // floor(1_234_567_808 / 128) mod 8 = 5
+    let beacon_time = 1_234_567_808;
+    let beacon = |time: u32| region.beacon_channel(time).unwrap().frequency;
+    assert_eq!(beacon(beacon_time), 926_300_000);
+    assert_eq!(beacon(beacon_time + 127), 926_300_000);
+    assert_eq!(beacon(beacon_time + 128), 926_900_000);
+    assert_eq!(beacon(beacon_time + 2 * 128), 927_500_000);
+    assert_eq!(beacon(beacon_time + 3 * 128), 923_300_000);
+
+    // Ping slots are offset by the DevAddr, (0x01020304 + 9645061) mod 8 = 1
+    let dev_addr = DevAddr::new([0x04, 0x03, 0x02, 0x01]);
+    let ping = |time: u32| region.ping_slot_channel(time, dev_addr).unwrap().frequency;
+    assert_eq!(ping(beacon_time), 923_900_000);
+    assert_eq!(ping(beacon_time + 128), 924_500_000);
+    assert_eq!(ping(beacon_time + 7 * 128), 923_300_000);
+
+    // The MAC layer uses the region ping slot channel of the session
+    let session = SessionState::new_abp(dev_addr, AESKey::new([0x01; 16]), AESKey::new([0x02; 16]));
+    let mac = MacLayer::new(MockRadio::new(), US915::new(), session);
+    assert_eq!(mac.ping_slot_frequency(beacon_time), Some(923_900_000));
+    assert_eq!(
+        mac.beacon_channel(beacon_time).unwrap().frequency,
+        926_300_000
+    );
+
+    // Single-channel regions always use the same channel
+    let region = RU864::new();
+    let first = region.beacon_channel(0).unwrap().frequency;
+    assert_eq!(region.beacon_channel(beacon_time).unwrap().frequency, first);
+    assert_eq!(
+        region
+            .ping_slot_channel(beacon_time, dev_addr)
+            .unwrap()
+            .frequency,
+        first
+    );
+}
@@ -0,0 +1,108 @@
+#![no_std]
+
+use lorawan::config::device::{AESKey, DevAddr, DeviceConfig, Eui64, SessionState};
+
+/// Serialize `value` to JSON in `buffer`
+fn to_json<'a, T: serde::Serialize>(value: &T, buffer: &'a mut [u8]) -> &'a str {
+    let len = serde_json_core::to_slice(value, buffer).unwrap();
+    core::str::from_utf8(&buffer[..len]).unwrap()
+}
+
+fn key() -> AESKey {
+    AESKey::new([
+        0x2B, 0x7E, 0x15, 0x16, 0x28, 0xAE, 0xD2, 0xA6, 0xAB, 0xF7, 0x15, 0x88, 0x09, 0xCF, 0x4F,
+        0x3C,
+    ])
+}
+
+#[test]
+fn test_key_serialized_as_hex() {
+    let mut buffer = [0u8; 64];
+    let json = to_json(&key(), &mut buffer);
+    assert_eq!(json, "\"2B7E151628AED2A6ABF7158809CF4F3C\"");
+
+    let (parsed, _) = serde_json_core::from_str::<AESKey>(json).unwrap();
+    assert_eq!(parsed.as_bytes(), key().as_bytes());
+}
+
+#[test]
+fn test_hex_separators_and_case() {
+    for text in [
+        "\"2b7e151628aed2a6abf7158809cf4f3c\"",
+        "\"2B:7E:15:16:28:AE:D2:A6:AB:F7:15:88:09:CF:4F:3C\"",
+        "\"2B-7E-15-16 28_AE_D2_A6 ABF7158809CF4F3C\"",
+    ] {
+        let (parsed, _) = serde_json_core::from_str::<AESKey>(text).unwrap();
+        assert_eq!(parsed.as_bytes(), key().as_bytes());
+    }
+
+    let (addr, _) = serde_json_core::from_str::<DevAddr>("\"26:01:1b:da\"").unwrap();
+    assert_eq!(addr, DevAddr::new([0xDA, 0x1B, 0x01, 0x26]));
+
+    // Wrong length or characters
+    for text in [
+        "\"2B7E151628AED2A6ABF7158809CF4F\"",
+        "\"2B7E151628AED2A6ABF7158809CF4F3C00\"",
+        "\"2B7E151628AED2A6ABF7158809CF4F3G\"",
+        "\"\"",
+    ] {
+        assert!(serde_json_core::from_str::<AESKey>(text).is_err());
+    }
+}
+
+#[test]
+fn test_device_config_round_trip() {
+    // EUIs are written most significant byte first, as consoles show them
+    let config = DeviceConfig::new_otaa(
+        [0x01, 0x00, 0x00, 0xD0, 0x7E, 0xD5, 0xB3, 0x70],
+        [0x00; 8],
+        key(),
+    );
+    let mut buffer = [0u8; 256];
+    let json = to_json(&config, &mut buffer);
+    assert_eq!(
+        json,
+        concat!(
+            "{\"dev_eui\":\"70B3D57ED0000001\",\"app_eui\":\"0000000000000000\",",
+            "\"app_key\":\"2B7E151628AED2A6ABF7158809CF4F3C\",\"dev_addr\":null,",
+            "\"nwk_skey\":null,\"app_skey\":null,\"auto_poll\":false}"
+        )
+    );
+
+    // Hand-written configs may leave out the optional fields
+    let input = concat!(
+        "{\"dev_eui\":\"70-B3-D5-7E-D0-00-00-01\",\"app_eui\":\"0000000000000000\",",
+        "\"app_key\":\"2B7E151628AED2A6ABF7158809CF4F3C\"}"
+    );
+    let (parsed, _) = serde_json_core::from_str::<DeviceConfig>(input).unwrap();
+    assert_eq!(parsed.dev_eui, config.dev_eui);
+    assert_eq!(parsed.app_key.as_bytes(), key().as_bytes());
+    assert!(parsed.dev_addr.is_none());
+    assert!(!parsed.auto_poll);
+}
+
+#[test]
+fn test_eui_serialized_msb_first() {
+    let eui = Eui64::from_hex_msb("70B3D57ED0000001").unwrap();
+    let mut buffer = [0u8; 32];
+    assert_eq!(to_json(&eui, &mut buffer), "\"70B3D57ED0000001\"");
+    let (parsed, _) = serde_json_core::from_str::<Eui64>("\"70b3d57ed0000001\"").unwrap();
+    assert_eq!(parsed, eui);
+}
+
+#[test]
+fn test_session_state_round_trip() {
+    let mut session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        key(),
+        AESKey::new([0x22; 16]),
+    );
+    session.fcnt_up = 42;
+    session.rx2_data_rate = Some(8);
+    session.last_dev_nonce = Some(0x1234);
+
+    let mut buffer = [0u8; 512];
+    let json = to_json(&session, &mut buffer);
+    let (parsed, _) = serde_json_core::from_str::<SessionState>(json).unwrap();
+    assert_eq!(parsed.to_bytes(), session.to_bytes());
+}
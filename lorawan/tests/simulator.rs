@@ -0,0 +1,297 @@
+#![no_std]
+
+//! Network server simulator for end-to-end tests
+//!
+//! `NetworkServer` plays the server side of LoRaWAN 1.0.3 for one device. It
+//! answers join requests, verifies uplinks and builds the downlinks of their
+//! receive windows with the crate's own crypto. Frames are exchanged as
+//! bytes, tests move them between the server and a `MockRadio`.
+
+use heapless::Vec;
+use lorawan::{
+    config::device::{AESKey, DevAddr, SessionState},
+    crypto::{self, SoftwareCrypto},
+    lorawan::frame::{self, DataFrameParams, FCtrl, JoinAccept, PhyPayload},
+};
+
+/// Receive delay of join accepts in ms
+pub const JOIN_ACCEPT_DELAY1: u32 = 5_000;
+
+/// Receive window a downlink is sent in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Window {
+    Rx1,
+    Rx2,
+}
+
+/// Downlink to deliver to the device
+#[derive(Debug, Clone)]
+pub struct Downlink {
+    /// Frame to receive
+    pub frame: Vec<u8, 256>,
+    /// Time the receive window opens
+    pub at: u32,
+    /// Receive window of the frame
+    pub window: Window,
+}
+
+/// Uplink rejected by the server
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SimError {
+    /// Frame that cannot be parsed
+    Malformed,
+    /// Frame of another device
+    UnknownDevice,
+    /// Data frame before the join
+    NotJoined,
+    /// MIC mismatch
+    InvalidMic,
+}
+
+/// Uplink received by the server
+#[derive(Debug, Clone, Default)]
+pub struct Uplink {
+    /// Full frame counter
+    pub fcnt: u32,
+    /// Confirmed uplink
+    pub confirmed: bool,
+    /// Frame port, `None` without FRMPayload
+    pub port: Option<u8>,
+    /// Decrypted application data
+    pub payload: Vec<u8, 242>,
+    /// MAC commands of FOpts and of a port 0 FRMPayload
+    pub mac_commands: Vec<u8, 242>,
+}
+
+/// Server side of one device
+pub struct NetworkServer {
+    dev_eui: [u8; 8],
+    app_key: AESKey,
+    join_accept: JoinAccept,
+    /// Session of the last join, or set for ABP
+    session: Option<SessionState>,
+    fcnt_down: u32,
+    /// Answer in RX2 instead of RX1
+    use_rx2: bool,
+    /// MAC commands for the next downlink
+    mac_commands: Vec<u8, 64>,
+    /// Application data for the next downlink
+    downlink: Option<(u8, Vec<u8, 64>)>,
+    /// Uplinks received since the join
+    uplinks: Vec<Uplink, 16>,
+}
+
+impl NetworkServer {
+    /// Create a server for the device with `dev_eui`, as sent over the air
+    pub fn new(dev_eui: [u8; 8], app_key: AESKey) -> Self {
+        Self {
+            dev_eui,
+            app_key,
+            join_accept: JoinAccept {
+                app_nonce: [0x01, 0x00, 0x00],
+                net_id: [0x13, 0x00, 0x00],
+                dev_addr: DevAddr::new([0x34, 0x12, 0x01, 0x26]),
+                dl_settings: 0x00,
+                rx_delay: 1,
+                cf_list: None,
+            },
+            session: None,
+            fcnt_down: 0,
+            use_rx2: false,
+            mac_commands: Vec::new(),
+            downlink: None,
+            uplinks: Vec::new(),
+        }
+    }
+
+    /// Set the fields of the next join accept
+    ///
+    /// The AppNonce is incremented after every join accept.
+    pub fn set_join_params(&mut self, app_nonce: [u8; 3], net_id: [u8; 3], dev_addr: DevAddr) {
+        self.join_accept.app_nonce = app_nonce;
+        self.join_accept.net_id = net_id;
+        self.join_accept.dev_addr = dev_addr;
+    }
+
+    /// Set the DLSettings and RxDelay of the next join accept
+    pub fn set_rx_params(&mut self, dl_settings: u8, rx_delay: u8) {
+        self.join_accept.dl_settings = dl_settings;
+        self.join_accept.rx_delay = rx_delay;
+    }
+
+    /// Answer in RX2 instead of RX1
+    pub fn answer_in_rx2(&mut self, enabled: bool) {
+        self.use_rx2 = enabled;
+    }
+
+    /// Send a MAC command, CID and payload, with the next downlink
+    pub fn queue_mac_command(&mut self, command: &[u8]) {
+        self.mac_commands.extend_from_slice(command).unwrap();
+    }
+
+    /// Send application data with the next downlink
+    pub fn queue_downlink(&mut self, port: u8, data: &[u8]) {
+        self.downlink = Some((port, Vec::from_slice(data).unwrap()));
+    }
+
+    /// Set the next uplink frame counter, as for a session restored by the device
+    pub fn set_fcnt_up(&mut self, fcnt_up: u32) {
+        if let Some(session) = self.session.as_mut() {
+            session.fcnt_up = fcnt_up;
+        }
+    }
+
+    /// Get the session of the device
+    pub fn session(&self) -> Option<&SessionState> {
+        self.session.as_ref()
+    }
+
+    /// Get the uplinks received since the join
+    pub fn uplinks(&self) -> &[Uplink] {
+        &self.uplinks
+    }
+
+    /// Handle an uplink transmitted until `tx_end`
+    ///
+    /// Returns the downlink to deliver in its receive window, if any.
+    pub fn handle_uplink(
+        &mut self,
+        data: &[u8],
+        tx_end: u32,
+    ) -> Result<Option<Downlink>, SimError> {
+        match PhyPayload::parse(data).map_err(|_| SimError::Malformed)? {
+            PhyPayload::JoinRequest(request) => {
+                if request.dev_eui != self.dev_eui {
+                    return Err(SimError::UnknownDevice);
+                }
+                if !request.verify_mic(&SoftwareCrypto, &self.app_key) {
+                    return Err(SimError::InvalidMic);
+                }
+                Ok(Some(self.accept_join(request.dev_nonce, tx_end)))
+            }
+            PhyPayload::DataUp(frame) => {
+                let session = self.session.as_mut().ok_or(SimError::NotJoined)?;
+                if frame.fhdr.dev_addr != session.dev_addr {
+                    return Err(SimError::UnknownDevice);
+                }
+                // Full counter from the 16 bits sent, counters only increase
+                let mut fcnt = (session.fcnt_up & !0xFFFF) | frame.fhdr.f_cnt as u32;
+                if fcnt < session.fcnt_up {
+                    fcnt = fcnt.wrapping_add(0x1_0000);
+                }
+                if !frame.verify_mic(&SoftwareCrypto, &session.nwk_skey, fcnt) {
+                    return Err(SimError::InvalidMic);
+                }
+                session.fcnt_up = fcnt.wrapping_add(1);
+
+                let decrypted = frame
+                    .decrypt_payload(&SoftwareCrypto, &session.nwk_skey, &session.app_skey, fcnt)
+                    .map_err(|_| SimError::Malformed)?;
+                let mut uplink = Uplink {
+                    fcnt,
+                    confirmed: frame.confirmed,
+                    port: frame.f_port,
+                    ..Uplink::default()
+                };
+                uplink
+                    .mac_commands
+                    .extend_from_slice(&frame.fhdr.f_opts)
+                    .unwrap();
+                match frame.f_port {
+                    Some(0) => uplink.mac_commands.extend_from_slice(&decrypted).unwrap(),
+                    Some(_) => uplink.payload.extend_from_slice(&decrypted).unwrap(),
+                    None => {}
+                }
+                if self.uplinks.is_full() {
+                    self.uplinks.remove(0);
+                }
+                let _ = self.uplinks.push(uplink);
+                Ok(self.answer(frame.confirmed, tx_end))
+            }
+            _ => Err(SimError::Malformed),
+        }
+    }
+
+    /// Start a session and build its join accept
+    fn accept_join(&mut self, dev_nonce: u16, tx_end: u32) -> Downlink {
+        let (nwk_skey, app_skey) = crypto::derive_session_keys(
+            &self.app_key,
+            &self.join_accept.app_nonce,
+            &self.join_accept.net_id,
+            dev_nonce,
+        );
+        let mut session = SessionState::new_abp(self.join_accept.dev_addr, nwk_skey, app_skey);
+        session.rx1_dr_offset = (self.join_accept.dl_settings >> 4) & 0x07;
+        session.rx2_data_rate = Some(self.join_accept.dl_settings & 0x0F);
+        session.rx1_delay = self.join_accept.rx_delay;
+        self.session = Some(session);
+        self.fcnt_down = 0;
+        self.uplinks.clear();
+
+        let frame = frame::build_join_accept(&self.app_key, &self.join_accept);
+        // Devices reject join accepts with a used AppNonce
+        let app_nonce = u32::from_le_bytes([
+            self.join_accept.app_nonce[0],
+            self.join_accept.app_nonce[1],
+            self.join_accept.app_nonce[2],
+            0,
+        ]);
+        self.join_accept
+            .app_nonce
+            .copy_from_slice(&app_nonce.wrapping_add(1).to_le_bytes()[..3]);
+        self.downlink_at(Vec::from_slice(&frame).unwrap(), tx_end, JOIN_ACCEPT_DELAY1)
+    }
+
+    /// Build the downlink after a data uplink, if there is anything to send
+    ///
+    /// MAC commands go in FOpts next to the application data. Commands that
+    /// do not fit in FOpts are sent on port 0, application data in the
+    /// following downlink.
+    fn answer(&mut self, ack: bool, tx_end: u32) -> Option<Downlink> {
+        let session = self.session.as_ref()?;
+        let mut f_opts: Vec<u8, 15> = Vec::new();
+        let (f_port, payload): (Option<u8>, Vec<u8, 64>) = if self.mac_commands.len() > 15 {
+            (Some(0), core::mem::take(&mut self.mac_commands))
+        } else {
+            f_opts.extend_from_slice(&self.mac_commands).unwrap();
+            self.mac_commands.clear();
+            match self.downlink.take() {
+                Some((port, data)) => (Some(port), data),
+                None if ack || !f_opts.is_empty() => (None, Vec::new()),
+                None => return None,
+            }
+        };
+
+        let mut f_ctrl = FCtrl::new();
+        f_ctrl.ack = ack;
+        let frame = frame::build_data_down(
+            &SoftwareCrypto,
+            &DataFrameParams {
+                confirmed: false,
+                dev_addr: session.dev_addr,
+                f_ctrl,
+                fcnt: self.fcnt_down,
+                f_opts: &f_opts,
+                f_port,
+                payload: &payload,
+            },
+            &session.nwk_skey,
+            &session.app_skey,
+        )
+        .unwrap();
+        self.fcnt_down = self.fcnt_down.wrapping_add(1);
+
+        let rx1_delay = u32::from(session.rx1_delay.max(1)) * 1_000;
+        Some(self.downlink_at(Vec::from_slice(&frame).unwrap(), tx_end, rx1_delay))
+    }
+
+    /// Schedule a downlink in RX1 `rx1_delay` after `tx_end`, or in RX2
+    fn downlink_at(&self, frame: Vec<u8, 256>, tx_end: u32, rx1_delay: u32) -> Downlink {
+        let (at, window) = if self.use_rx2 {
+            (tx_end.wrapping_add(rx1_delay + 1_000), Window::Rx2)
+        } else {
+            (tx_end.wrapping_add(rx1_delay), Window::Rx1)
+        };
+        Downlink { frame, at, window }
+    }
+}
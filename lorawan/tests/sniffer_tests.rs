@@ -0,0 +1,250 @@
+#![no_std]
+
+use lorawan::{
+    config::device::{AESKey, DevAddr, SessionState},
+    crypto::SoftwareCrypto,
+    lorawan::{
+        frame::{self, DataFrameParams, FCtrl, MType, PhyPayload, MAX_FRAME_SIZE},
+        region::{DataRate, US915},
+    },
+    repeater::{Repeater, RepeaterEvent, RepeaterMetrics},
+    sniffer::{FrameKind, Sniffer, MAX_SNIFFED_DEVICES},
+};
+
+mod mock;
+use mock::MockRadio;
+
+fn data_frame(dev_addr: DevAddr, fcnt: u32, up: bool) -> heapless::Vec<u8, MAX_FRAME_SIZE> {
+    let params = DataFrameParams {
+        confirmed: false,
+        dev_addr,
+        f_ctrl: FCtrl::new(),
+        fcnt,
+        f_opts: &[],
+        f_port: Some(1),
+        payload: b"survey",
+    };
+    let key = AESKey::new([0x11; 16]);
+    if up {
+        frame::build_data_up(&SoftwareCrypto, &params, &key, &key).unwrap()
+    } else {
+        frame::build_data_down(&SoftwareCrypto, &params, &key, &key).unwrap()
+    }
+}
+
+fn sub_band_2() -> US915 {
+    let mut region = US915::new();
+    region.set_sub_band(1);
+    region
+}
+
+#[test]
+fn test_sniffer_classifies_frames() {
+    let device = DevAddr::new([0x01, 0x02, 0x03, 0x04]);
+    let other = DevAddr::new([0x0A, 0x0B, 0x0C, 0x0D]);
+    let mut radio = MockRadio::new();
+    radio.set_rssi(-97);
+    radio.set_snr(-4);
+    let join_request = frame::build_join_request(
+        &SoftwareCrypto,
+        &AESKey::new([0x2B; 16]),
+        [0x02; 8],
+        [0x01; 8],
+        7,
+    );
+    radio.queue_rx_data(&join_request);
+    radio.queue_rx_data(&data_frame(device, 5, true));
+    radio.queue_rx_data(&data_frame(device, 6, true));
+    radio.queue_rx_data(&data_frame(other, 40, true));
+    radio.queue_rx_data(&data_frame(device, 3, false));
+    radio.queue_rx_data(&[0x40, 0x01, 0x02]);
+
+    let mut sniffer = Sniffer::new(radio, sub_band_2(), 0);
+    let frame = sniffer.poll().unwrap().unwrap();
+    assert_eq!(frame.kind, FrameKind::JoinRequest);
+    assert_eq!(frame.mtype(), Some(MType::JoinRequest));
+    assert_eq!(frame.dev_addr, None);
+    assert_eq!(frame.rssi, -97);
+    assert_eq!(frame.snr, -4);
+    assert_eq!(frame.frequency, 903_900_000);
+    assert_eq!(&frame.raw[..], &join_request[..]);
+
+    let frame = sniffer.poll().unwrap().unwrap();
+    assert_eq!(frame.kind, FrameKind::DataUp);
+    assert_eq!(frame.mhdr, MType::UnconfirmedDataUp.mhdr());
+    assert_eq!(frame.dev_addr, Some(device));
+    assert_eq!(frame.fcnt, Some(5));
+
+    let kinds = [
+        FrameKind::DataUp,
+        FrameKind::DataUp,
+        FrameKind::DataDown,
+        FrameKind::Unknown,
+    ];
+    for kind in kinds {
+        assert_eq!(sniffer.poll().unwrap().unwrap().kind, kind);
+    }
+    assert!(sniffer.poll().unwrap().is_none());
+
+    let traffic = sniffer.device(&device).unwrap();
+    assert_eq!((traffic.uplinks, traffic.downlinks), (2, 1));
+    assert_eq!(traffic.last_fcnt, 3);
+    assert_eq!(sniffer.device(&other).unwrap().last_fcnt, 40);
+    assert_eq!(sniffer.devices().count(), 2);
+    assert_eq!(sniffer.untracked_frames(), 0);
+}
+
+#[test]
+fn test_sniffer_channel_scan() {
+    let mut radio = MockRadio::new();
+    radio.set_time(1_000);
+    let mut sniffer = Sniffer::new(radio, sub_band_2(), 500);
+    sniffer.set_data_rate(Some(DataRate::SF7BW125));
+
+    // One poll per dwell period visits the 8 125 kHz channels then the 500 kHz one
+    for i in 0..10u32 {
+        sniffer.radio_mut().set_time(1_000 + i * 500);
+        assert!(sniffer.poll().unwrap().is_none());
+        sniffer.poll().unwrap();
+    }
+    let (radio, _) = sniffer.release();
+    let configs = radio.get_rx_configs();
+    assert_eq!(configs.len(), 10);
+    for (i, config) in configs.iter().take(8).enumerate() {
+        assert_eq!(config.frequency, 903_900_000 + i as u32 * 200_000);
+        assert_eq!(config.modulation.spreading_factor, 7);
+        assert_eq!(config.modulation.bandwidth, 125_000);
+        assert!(!config.modulation.iq_inverted);
+    }
+    // SF7BW125 is not available on the 500 kHz channel
+    assert_eq!(configs[8].frequency, 904_600_000);
+    assert_eq!(configs[8].modulation.spreading_factor, 8);
+    assert_eq!(configs[8].modulation.bandwidth, 500_000);
+    assert_eq!(configs[9].frequency, 903_900_000);
+}
+
+#[test]
+fn test_sniffer_downlinks_and_capacity() {
+    let mut sniffer = Sniffer::new(MockRadio::new(), sub_band_2(), 0);
+    sniffer.listen_downlinks(true);
+    for i in 0..=MAX_SNIFFED_DEVICES as u8 {
+        let dev_addr = DevAddr::new([i, 0, 0, 0x26]);
+        sniffer
+            .radio_mut()
+            .queue_rx_data(&data_frame(dev_addr, 1, true));
+        sniffer.poll().unwrap();
+    }
+    assert_eq!(sniffer.devices().count(), MAX_SNIFFED_DEVICES);
+    assert_eq!(sniffer.untracked_frames(), 1);
+    assert_eq!(sniffer.frequency(), Some(903_900_000));
+
+    sniffer.clear();
+    assert_eq!(sniffer.devices().count(), 0);
+
+    let (mut radio, _) = sniffer.release();
+    assert!(radio.get_rx_configs()[0].modulation.iq_inverted);
+    radio.set_error_mode(true);
+    let mut sniffer = Sniffer::new(radio, sub_band_2(), 0);
+    assert!(sniffer.poll().is_err());
+}
+
+#[test]
+fn test_repeater_forwarding() {
+    let device = DevAddr::new([0x01, 0x02, 0x03, 0x04]);
+    let uplink = data_frame(device, 9, true);
+    let mut radio = MockRadio::new();
+    radio.set_rssi(-110);
+    radio.set_snr(-12);
+    radio.queue_rx_data(&uplink);
+    radio.queue_rx_data(&uplink);
+    radio.queue_rx_data(&data_frame(device, 2, false));
+    radio.queue_rx_data(&[0xE0, 0x01]);
+
+    let mut repeater: Repeater<_, _> = Repeater::new(radio, sub_band_2(), 0);
+    repeater.set_data_rate(Some(DataRate::SF9BW125));
+    assert_eq!(
+        repeater.poll().unwrap(),
+        Some(RepeaterEvent::Forwarded {
+            frequency: 903_900_000
+        })
+    );
+    let radio = repeater.radio_mut();
+    assert_eq!(radio.get_last_tx(), Some(&uplink[..]));
+    let config = radio.get_last_tx_config().unwrap();
+    assert_eq!(config.frequency, 903_900_000);
+    assert_eq!(config.modulation.spreading_factor, 9);
+    assert!(!config.modulation.iq_inverted);
+    // Listening again after forwarding
+    assert_eq!(radio.get_rx_configs().len(), 2);
+
+    assert_eq!(repeater.poll().unwrap(), Some(RepeaterEvent::Duplicate));
+    assert_eq!(repeater.poll().unwrap(), Some(RepeaterEvent::Invalid));
+    assert_eq!(repeater.poll().unwrap(), Some(RepeaterEvent::Invalid));
+    assert_eq!(repeater.poll().unwrap(), None);
+    assert_eq!(
+        repeater.metrics(),
+        RepeaterMetrics {
+            packets_seen: 4,
+            packets_forwarded: 1,
+            packets_dropped: 3,
+            duplicates: 1,
+            last_rssi: -110,
+            last_snr: -12,
+        }
+    );
+
+    // Forwarding on a mapped frequency
+    repeater.set_frequency_map(Some(|frequency| frequency + 1_600_000));
+    repeater
+        .radio_mut()
+        .queue_rx_data(&data_frame(device, 10, true));
+    assert_eq!(
+        repeater.poll().unwrap(),
+        Some(RepeaterEvent::Forwarded {
+            frequency: 905_500_000
+        })
+    );
+    assert_eq!(repeater.metrics().packets_forwarded, 2);
+    repeater.reset_metrics();
+    assert_eq!(repeater.metrics(), RepeaterMetrics::default());
+}
+
+#[test]
+fn test_repeater_metrics_uplink() {
+    let own = DevAddr::new([0x26, 0x0B, 0x0B, 0x0B]);
+    let mut session = SessionState::new_abp(own, AESKey::new([0x11; 16]), AESKey::new([0x11; 16]));
+    session.fcnt_up = 5;
+    let mut repeater: Repeater<_, _, 8> = Repeater::new(MockRadio::new(), sub_band_2(), 0);
+    assert!(!repeater.send_metrics().unwrap());
+    repeater.set_metrics_session(session, 200);
+
+    // Frames of the repeater itself, e.g. heard from another repeater, are not forwarded
+    repeater
+        .radio_mut()
+        .queue_rx_data(&data_frame(own, 1, true));
+    assert_eq!(repeater.poll().unwrap(), Some(RepeaterEvent::Duplicate));
+
+    assert!(repeater.send_metrics().unwrap());
+    assert_eq!(repeater.metrics_session().unwrap().fcnt_up, 6);
+    let uplink = repeater.radio_mut().get_last_tx().unwrap();
+    let Ok(PhyPayload::DataUp(frame)) = PhyPayload::parse(uplink) else {
+        panic!("metrics are not a data uplink");
+    };
+    assert_eq!(frame.fhdr.dev_addr, own);
+    assert_eq!(frame.fhdr.f_cnt, 5);
+    assert_eq!(frame.f_port, Some(200));
+    assert_eq!(frame.frm_payload.len(), RepeaterMetrics::SIZE);
+
+    let metrics = RepeaterMetrics {
+        packets_seen: 1,
+        packets_dropped: 1,
+        duplicates: 1,
+        last_rssi: -90,
+        last_snr: -3,
+        ..RepeaterMetrics::default()
+    };
+    assert_eq!(
+        metrics.to_bytes(),
+        [1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0xA6, 0xFF, 0xFD]
+    );
+}
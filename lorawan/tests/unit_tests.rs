@@ -1,11 +1,35 @@
 #![no_std]
 
 use lorawan::{
-    config::device::{AESKey, DevAddr, DeviceConfig, SessionState},
-    crypto::{self, Direction},
-    lorawan::region::{DataRate, Region, US915},
+    class::OperatingMode,
+    config::device::{
+        AESKey, ActivationState, ConfigError, DevAddr, DeviceConfig, Eui64, ParseError,
+        SessionError, SessionState, SESSION_STATE_SIZE,
+    },
+    crypto::{self, CryptoError, Direction},
+    device::{
+        power::{PowerConfig, PowerManager, PowerMetrics, PowerState},
+        DeviceError, LoRaWANDevice,
+    },
+    lorawan::{
+        commands::MacCommand,
+        frame::{self, DataFrameParams, FCtrl, FrameError, PhyPayload, MAX_FRAME_SIZE},
+        mac::MacError,
+        phy::{self, PhyLayer},
+        region::{DataRate, Region, US915},
+    },
+    radio::{
+        sx127x::SX127xError,
+        traits::{ModulationParams, RadioError},
+    },
+    repeater::DedupCache,
 };
 
+use core::error::Error;
+use core::fmt::Write;
+use core::time::Duration;
+use heapless::{String, Vec};
+
 mod mock;
 use mock::MockRadio;
 
@@ -23,6 +47,183 @@ fn test_device_config() {
     assert!(config.dev_addr.is_none());
 }
 
+#[test]
+fn test_hex_credentials() {
+    // EUIs as shown by the console, most significant byte first
+    let config = DeviceConfig::new_otaa_hex(
+        "70B3D57ED0000001",
+        "00:00:00:00:00:00:00:02",
+        "2b7e151628aed2a6abf7158809cf4f3c",
+    )
+    .unwrap();
+    assert_eq!(
+        config.dev_eui,
+        [0x01, 0x00, 0x00, 0xD0, 0x7E, 0xD5, 0xB3, 0x70]
+    );
+    assert_eq!(config.app_eui, [0x02, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(config.app_key.as_bytes()[..2], [0x2B, 0x7E]);
+
+    let msb = Eui64::from_hex_msb("70-B3-D5-7E-D0-00-00-01").unwrap();
+    let lsb = Eui64::from_hex_lsb("01 00 00 D0 7E D5 B3 70").unwrap();
+    assert_eq!(msb, lsb);
+    assert_eq!(msb.as_bytes(), &config.dev_eui);
+
+    let addr = DevAddr::from_hex("260B1A2C").unwrap();
+    assert_eq!(addr.as_bytes(), &[0x2C, 0x1A, 0x0B, 0x26]);
+
+    let mut output: String<64> = String::new();
+    write!(output, "{} {:x} {} {:x}", msb, msb, addr, addr).unwrap();
+    assert_eq!(
+        output,
+        "70B3D57ED0000001 70b3d57ed0000001 260B1A2C 260b1a2c"
+    );
+
+    // The key is only printed on purpose
+    let key = AESKey::from_hex("000102030405060708090A0B0C0D0E0F").unwrap();
+    output.clear();
+    write!(output, "{:?} {:x}", key, key).unwrap();
+    assert_eq!(output, "AESKey(****) 000102030405060708090a0b0c0d0e0f");
+
+    // Odd lengths, wrong lengths and non-hex characters
+    assert_eq!(DevAddr::from_hex("260B1A2"), Err(ParseError::InvalidLength));
+    assert_eq!(
+        DevAddr::from_hex("260B1A2C00"),
+        Err(ParseError::InvalidLength)
+    );
+    assert_eq!(DevAddr::from_hex(""), Err(ParseError::InvalidLength));
+    assert_eq!(
+        Eui64::from_hex_msb("70B3D57ED000000G"),
+        Err(ParseError::InvalidCharacter)
+    );
+    assert_eq!(
+        Eui64::from_hex_lsb("0x70B3D57ED00000"),
+        Err(ParseError::InvalidCharacter)
+    );
+    assert!(AESKey::from_hex("2B7E151628AED2A6ABF7158809CF4F3").is_err());
+    assert!(matches!(
+        DeviceConfig::new_otaa_hex("70B3D57ED0000001", "0000000000000002", "2B7E"),
+        Err(ParseError::InvalidLength)
+    ));
+}
+
+#[test]
+fn test_device_config_validation() {
+    let key = AESKey::new([0x03; 16]);
+    let zero_key = AESKey::new([0; 16]);
+    let dev_addr = DevAddr::new([0x01, 0x02, 0x03, 0x04]);
+    assert_eq!(
+        DeviceConfig::new_otaa([0x01; 8], [0; 8], key.clone()).validate(),
+        Ok(())
+    );
+    assert_eq!(
+        DeviceConfig::new_otaa([0; 8], [0x02; 8], key.clone()).validate(),
+        Err(ConfigError::ZeroDevEui)
+    );
+    assert_eq!(
+        DeviceConfig::new_otaa([0x01; 8], [0x02; 8], zero_key.clone()).validate(),
+        Err(ConfigError::ZeroAppKey)
+    );
+    assert_eq!(
+        DeviceConfig::new_otaa([0xFF; 8], [0x02; 8], key.clone()).validate(),
+        Err(ConfigError::InvalidEui)
+    );
+    assert_eq!(
+        DeviceConfig::new_otaa([0x01; 8], [0xFF; 8], key.clone()).validate(),
+        Err(ConfigError::InvalidEui)
+    );
+
+    // ABP devices need no AppKey, but an address and both session keys
+    let abp = DeviceConfig::new_abp([0x01; 8], [0; 8], dev_addr, key.clone(), key.clone());
+    assert_eq!(abp.validate(), Ok(()));
+    let zero_addr = DevAddr::new([0; 4]);
+    assert_eq!(
+        DeviceConfig::new_abp([0x01; 8], [0; 8], zero_addr, key.clone(), key.clone()).validate(),
+        Err(ConfigError::ZeroDevAddr)
+    );
+    assert_eq!(
+        DeviceConfig::new_abp([0x01; 8], [0; 8], dev_addr, zero_key.clone(), key.clone())
+            .validate(),
+        Err(ConfigError::InvalidSessionKey)
+    );
+    assert_eq!(
+        DeviceConfig::new_abp([0x01; 8], [0; 8], dev_addr, key.clone(), zero_key.clone())
+            .validate(),
+        Err(ConfigError::InvalidSessionKey)
+    );
+    let mut missing_key = abp.clone();
+    missing_key.app_skey = None;
+    assert_eq!(missing_key.validate(), Err(ConfigError::InvalidSessionKey));
+
+    // Device creation rejects the configuration, unless unchecked
+    let config = DeviceConfig::new_otaa([0x01; 8], [0x02; 8], zero_key);
+    assert!(matches!(
+        LoRaWANDevice::new(
+            MockRadio::new(),
+            config.clone(),
+            US915::new(),
+            OperatingMode::ClassA
+        ),
+        Err(DeviceError::InvalidConfig(ConfigError::ZeroAppKey))
+    ));
+    let device = LoRaWANDevice::new_unchecked(
+        MockRadio::new(),
+        config,
+        US915::new(),
+        OperatingMode::ClassA,
+    );
+    assert_eq!(device.operating_mode(), OperatingMode::ClassA);
+}
+
+/// Render an error with `Display`
+fn message(error: &dyn Error) -> String<64> {
+    let mut output = String::new();
+    write!(output, "{}", error).unwrap();
+    output
+}
+
+#[test]
+fn test_error_messages_and_sources() {
+    let error: DeviceError<RadioError> = DeviceError::Mac(MacError::Radio(RadioError::Timeout));
+    assert_eq!(message(&error), "MAC layer error");
+    let mac = error.source().unwrap();
+    assert_eq!(message(mac), "radio error");
+    let radio = mac.source().unwrap();
+    assert_eq!(message(radio), "operation timeout");
+    assert!(radio.source().is_none());
+
+    let error: MacError<RadioError> = MacError::DutyCycleLimited {
+        retry_after_ms: 1500,
+    };
+    assert_eq!(message(&error), "duty cycle limited, retry after 1500 ms");
+    assert!(error.source().is_none());
+    let error: MacError<RadioError> = MacError::InvalidPayloadSize { max_size: 11 };
+    assert_eq!(message(&error), "payload too large, at most 11 bytes");
+
+    let error: DeviceError<RadioError> = DeviceError::InvalidConfig(ConfigError::ZeroAppKey);
+    assert_eq!(message(&error), "invalid device configuration");
+    assert_eq!(message(error.source().unwrap()), "AppKey is zero");
+    let error: DeviceError<RadioError> = DeviceError::Session(SessionError::InvalidChecksum);
+    assert_eq!(
+        message(error.source().unwrap()),
+        "session state checksum mismatch"
+    );
+    let error: DeviceError<RadioError> = DeviceError::TooEarly {
+        retry_after_ms: 200,
+    };
+    assert_eq!(message(&error), "too early to transmit, retry after 200 ms");
+
+    let error: SX127xError<RadioError, RadioError, RadioError> = SX127xError::Cs(RadioError::Gpio);
+    assert_eq!(message(&error), "CS pin error");
+    assert_eq!(message(error.source().unwrap()), "GPIO error");
+
+    assert_eq!(message(&FrameError::InvalidMic), "invalid MIC");
+    assert_eq!(message(&CryptoError::PayloadTooLong), "payload too long");
+    assert_eq!(
+        message(&ParseError::InvalidCharacter),
+        "invalid hex character"
+    );
+}
+
 #[test]
 fn test_session_state() {
     let dev_addr = DevAddr::new([0x01, 0x02, 0x03, 0x04]);
@@ -31,11 +232,123 @@ fn test_session_state() {
 
     let session = SessionState::new_abp(dev_addr, nwk_skey, app_skey);
 
+    assert_eq!(session.activation_state, ActivationState::ABPActivated);
+    assert_eq!(session.device_class, OperatingMode::ClassA);
     assert_eq!(session.dev_addr, dev_addr);
     assert_eq!(session.nwk_skey.as_bytes(), &[0x01; 16]);
     assert_eq!(session.app_skey.as_bytes(), &[0x02; 16]);
     assert_eq!(session.fcnt_up, 0);
     assert_eq!(session.fcnt_down, 0);
+    assert_eq!(session.dev_nonce, 0);
+    assert!(session.is_joined());
+
+    // Joined follows the activation state, all-zero keys are valid
+    let session = SessionState {
+        activation_state: ActivationState::ABPActivated,
+        device_class: OperatingMode::ClassA,
+        dev_addr: DevAddr::new([0x00; 4]),
+        nwk_skey: AESKey::new([0x00; 16]),
+        app_skey: AESKey::new([0x00; 16]),
+        fcnt_up: 0,
+        fcnt_down: 0,
+        dev_nonce: 0,
+        ..SessionState::new()
+    };
+    assert!(session.is_joined());
+    assert!(!SessionState::new().is_joined());
+    let joining = SessionState {
+        activation_state: ActivationState::Joining,
+        ..SessionState::new()
+    };
+    assert!(!joining.is_joined());
+}
+
+#[test]
+fn test_session_state_serialization() {
+    let mut session = SessionState::new_abp(
+        DevAddr::new([0x01, 0x02, 0x03, 0x04]),
+        AESKey::new([0x11; 16]),
+        AESKey::new([0x22; 16]),
+    );
+    session.fcnt_up = 0x0001_2345;
+    session.fcnt_down = 42;
+    session.rx1_dr_offset = 2;
+    session.rx2_data_rate = Some(8);
+    session.rx1_delay = 5;
+    session.max_fcnt_gap = 1_000;
+    session.last_dev_nonce = Some(0x0102);
+    session.activation_state = ActivationState::OTAAActivated;
+    session.device_class = OperatingMode::ClassC;
+    session.dev_nonce = 0x0101;
+
+    let bytes = session.to_bytes();
+    let restored = SessionState::from_bytes(&bytes).unwrap();
+    assert_eq!(restored.dev_addr, session.dev_addr);
+    assert_eq!(restored.nwk_skey.as_bytes(), &[0x11; 16]);
+    assert_eq!(restored.app_skey.as_bytes(), &[0x22; 16]);
+    assert_eq!(restored.fcnt_up, 0x0001_2345);
+    assert_eq!(restored.fcnt_down, 42);
+    assert_eq!(restored.rx1_dr_offset, 2);
+    assert_eq!(restored.rx2_data_rate, Some(8));
+    assert_eq!(restored.rx2_frequency, None);
+    assert_eq!(restored.rx1_delay, 5);
+    assert_eq!(restored.max_fcnt_gap, 1_000);
+    assert_eq!(restored.last_dev_nonce, Some(0x0102));
+    assert_eq!(restored.activation_state, ActivationState::OTAAActivated);
+    assert_eq!(restored.device_class, OperatingMode::ClassC);
+    assert_eq!(restored.dev_nonce, 0x0101);
+    assert_eq!(restored.to_bytes(), bytes);
+
+    // Corrupted, truncated and unknown layouts are rejected
+    let mut corrupted = bytes;
+    corrupted[40] ^= 0x01;
+    assert_eq!(
+        SessionState::from_bytes(&corrupted).unwrap_err(),
+        SessionError::InvalidChecksum
+    );
+    assert_eq!(
+        SessionState::from_bytes(&bytes[..SESSION_STATE_SIZE - 1]).unwrap_err(),
+        SessionError::InvalidLength
+    );
+    let mut future = bytes;
+    future[0] = 3;
+    assert_eq!(
+        SessionState::from_bytes(&future).unwrap_err(),
+        SessionError::UnsupportedVersion
+    );
+}
+
+#[test]
+fn test_session_state_v1_layout() {
+    // Written before the activation state was stored, with a DevNonce
+    let v1 = [
+        0x01, 0x05, 0x01, 0x02, 0x03, 0x04, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x07, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00,
+        0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x40, 0x00, 0x00, 0x02, 0x01, 0x91,
+        0x9D,
+    ];
+    let session = SessionState::from_bytes(&v1).unwrap();
+    assert_eq!(session.activation_state, ActivationState::OTAAActivated);
+    assert_eq!(session.device_class, OperatingMode::ClassA);
+    assert_eq!(session.dev_addr, DevAddr::new([0x01, 0x02, 0x03, 0x04]));
+    assert_eq!(session.fcnt_up, 7);
+    assert_eq!(session.fcnt_down, 3);
+    assert_eq!(session.rx2_data_rate, Some(8));
+    assert_eq!(session.last_dev_nonce, Some(0x0102));
+    assert_eq!(session.dev_nonce, 0x0102);
+
+    // Saved again in the current layout
+    let restored = SessionState::from_bytes(&session.to_bytes()).unwrap();
+    assert_eq!(restored.activation_state, ActivationState::OTAAActivated);
+    assert_eq!(restored.fcnt_up, 7);
+
+    let mut corrupted = v1;
+    corrupted[40] ^= 0x01;
+    assert_eq!(
+        SessionState::from_bytes(&corrupted).unwrap_err(),
+        SessionError::InvalidChecksum
+    );
 }
 
 #[test]
@@ -46,24 +359,130 @@ fn test_crypto_encrypt_decrypt() {
     let payload = b"Hello LoRaWAN";
 
     // Test encryption
-    let encrypted = crypto::encrypt_payload(&key, dev_addr, fcnt, Direction::Up, payload);
+    let encrypted = crypto::encrypt_payload(&key, dev_addr, fcnt, Direction::Up, payload).unwrap();
 
     // Test decryption
-    let decrypted = crypto::encrypt_payload(&key, dev_addr, fcnt, Direction::Up, &encrypted);
+    let decrypted =
+        crypto::encrypt_payload(&key, dev_addr, fcnt, Direction::Up, &encrypted).unwrap();
 
     assert_eq!(&decrypted[..], payload);
 }
 
+#[test]
+fn test_crypto_encrypt_known_answer() {
+    // Uplink 40F17DBE4900020001954378762B11FF0D from the lora-packet test suite,
+    // FRMPayload "test" on port 1
+    let key = AESKey::new([
+        0xEC, 0x92, 0x58, 0x02, 0xAE, 0x43, 0x0C, 0xA7, 0x7F, 0xD3, 0xDD, 0x73, 0xCB, 0x2C, 0xC5,
+        0x88,
+    ]);
+    let dev_addr = DevAddr::new([0xF1, 0x7D, 0xBE, 0x49]);
+    let fcnt = 2;
+
+    let encrypted = crypto::encrypt_payload(&key, dev_addr, fcnt, Direction::Up, b"test").unwrap();
+    assert_eq!(&encrypted[..], &[0x95, 0x43, 0x78, 0x76]);
+
+    // Blocks after the first use their own counter
+    let keystream = crypto::encrypt_payload(&key, dev_addr, fcnt, Direction::Up, &[0; 32]).unwrap();
+    assert_eq!(keystream[0] ^ b't', 0x95);
+    assert_ne!(keystream[..16], keystream[16..]);
+
+    // Payloads longer than a frame can carry are rejected
+    let payload = [0u8; crypto::MAX_PAYLOAD_SIZE + 1];
+    assert_eq!(
+        crypto::encrypt_payload(&key, dev_addr, fcnt, Direction::Up, &payload),
+        Err(CryptoError::PayloadTooLong)
+    );
+}
+
 #[test]
 fn test_crypto_mic() {
-    let key = AESKey::new([0x01; 16]);
-    let dev_addr = DevAddr::new([0x01, 0x02, 0x03, 0x04]);
-    let fcnt = 1;
-    let data = b"Test Data";
+    // Uplink 40F17DBE4900020001954378762B11FF0D from the lora-packet test suite
+    let key = AESKey::new([
+        0x44, 0x02, 0x42, 0x41, 0xED, 0x4C, 0xE9, 0xA6, 0x8C, 0x6A, 0x8B, 0xC0, 0x55, 0x23, 0x3F,
+        0xD3,
+    ]);
+    let dev_addr = DevAddr::new([0xF1, 0x7D, 0xBE, 0x49]);
+    let fcnt = 2;
+    let data = [
+        0x40, 0xF1, 0x7D, 0xBE, 0x49, 0x00, 0x02, 0x00, 0x01, 0x95, 0x43, 0x78, 0x76,
+    ];
+
+    let mic = crypto::compute_mic(&key, &data, dev_addr, fcnt, Direction::Up);
+
+    assert_eq!(mic, [0x2B, 0x11, 0xFF, 0x0D]);
+}
+
+#[test]
+fn test_crypto_mic_matches() {
+    let mic = [0x2B, 0x11, 0xFF, 0x0D];
+    assert!(crypto::mic_matches(&mic, &mic));
+    assert!(!crypto::mic_matches(&[0x2B, 0x11, 0xFF, 0x0C], &mic));
+    assert!(!crypto::mic_matches(&[0x2A, 0x11, 0xFF, 0x0D], &mic));
+    assert!(!crypto::mic_matches(&mic[..3], &mic));
+    assert!(!crypto::mic_matches(&[], &mic));
+}
 
-    let mic = crypto::compute_mic(&key, data, dev_addr, fcnt, Direction::Up);
+#[test]
+fn test_aes_key_debug_is_redacted() {
+    let key = AESKey::new([0xA5; 16]);
+    let config = DeviceConfig::new_otaa([0x01; 8], [0x02; 8], key.clone());
 
-    assert_eq!(mic.len(), 4);
+    let mut output: String<256> = String::new();
+    write!(output, "{:?} {:?}", key, config).unwrap();
+    assert!(output.starts_with("AESKey(****)"));
+    // Key bytes would be printed as decimal numbers
+    assert!(!output.contains("165"));
+}
+
+#[test]
+fn test_crypto_cmac_rfc4493() {
+    // RFC 4493 section 4 test vectors (first four bytes of the tag)
+    let key = AESKey::new([
+        0x2B, 0x7E, 0x15, 0x16, 0x28, 0xAE, 0xD2, 0xA6, 0xAB, 0xF7, 0x15, 0x88, 0x09, 0xCF, 0x4F,
+        0x3C,
+    ]);
+    let message = [
+        0x6B, 0xC1, 0xBE, 0xE2, 0x2E, 0x40, 0x9F, 0x96, 0xE9, 0x3D, 0x7E, 0x11, 0x73, 0x93, 0x17,
+        0x2A, 0xAE, 0x2D, 0x8A, 0x57, 0x1E, 0x03, 0xAC, 0x9C, 0x9E, 0xB7, 0x6F, 0xAC, 0x45, 0xAF,
+        0x8E, 0x51, 0x30, 0xC8, 0x1C, 0x46, 0xA3, 0x5C, 0xE4, 0x11, 0xE5, 0xFB, 0xC1, 0x19, 0x1A,
+        0x0A, 0x52, 0xEF, 0xF6, 0x9F, 0x24, 0x45, 0xDF, 0x4F, 0x9B, 0x17, 0xAD, 0x2B, 0x41, 0x7B,
+        0xE6, 0x6C, 0x37, 0x10,
+    ];
+
+    assert_eq!(
+        crypto::compute_join_request_mic(&key, &message[..0]),
+        [0xBB, 0x1D, 0x69, 0x29]
+    );
+    assert_eq!(
+        crypto::compute_join_request_mic(&key, &message[..16]),
+        [0x07, 0x0A, 0x16, 0xB4]
+    );
+    assert_eq!(
+        crypto::compute_join_request_mic(&key, &message[..40]),
+        [0xDF, 0xA6, 0x67, 0x47]
+    );
+    assert_eq!(
+        crypto::compute_join_request_mic(&key, &message),
+        [0x51, 0xF0, 0xBE, 0xBF]
+    );
+}
+
+#[test]
+fn test_crypto_join_request_mic() {
+    let key = AESKey::new([
+        0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F,
+        0x10,
+    ]);
+    // MHDR | AppEUI | DevEUI | DevNonce
+    let join_request = [
+        0x00, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06,
+        0x07, 0x08, 0x01, 0x00,
+    ];
+
+    let mic = crypto::compute_join_request_mic(&key, &join_request);
+
+    assert_eq!(mic, [0x22, 0xC1, 0xA5, 0xB1]);
 }
 
 #[test]
@@ -98,11 +517,409 @@ fn test_us915_region() {
 
     // Test RX windows
     let channel = region.get_next_channel().unwrap();
-    let (rx1_freq, rx1_dr) = region.rx1_window(&channel);
-    assert!(rx1_freq < channel.frequency);
-    assert_eq!(rx1_dr, region.get_data_rate());
+    let (rx1_freq, rx1_dr) = region.rx1_window(&channel, 0);
+    assert_eq!(channel.frequency, 905_700_000); // Channel 17
+    assert_eq!(rx1_freq, 923_900_000); // Downlink channel 1
+    assert_eq!(rx1_dr, DataRate::SF10BW500); // DR0 -> DR10
+
+    // RX1DROffset lowers the RX1 data rate down to DR8
+    assert_eq!(region.rx1_window(&channel, 2).1, DataRate::SF12BW500);
+    assert_eq!(region.rx1_window(&channel, 3).1, DataRate::SF12BW500);
 
     let (rx2_freq, rx2_dr) = region.rx2_window();
     assert_eq!(rx2_freq, 923_300_000);
-    assert_eq!(rx2_dr, DataRate::SF12BW125);
+    assert_eq!(rx2_dr, DataRate::SF12BW500);
+}
+
+/// One instance of every MAC command with all fields in use
+const MAC_COMMANDS: [MacCommand; 26] = [
+    MacCommand::LinkCheckReq,
+    MacCommand::LinkCheckAns {
+        margin: 20,
+        gateway_count: 3,
+    },
+    MacCommand::LinkADRReq {
+        data_rate: 5,
+        tx_power: 3,
+        ch_mask: 0xFF01,
+        ch_mask_cntl: 6,
+        nb_trans: 2,
+    },
+    MacCommand::LinkADRAns {
+        power_ack: true,
+        data_rate_ack: false,
+        channel_mask_ack: true,
+    },
+    MacCommand::DutyCycleReq { max_duty_cycle: 7 },
+    MacCommand::DutyCycleAns,
+    MacCommand::RXParamSetupReq {
+        rx1_dr_offset: 2,
+        rx2_data_rate: 8,
+        freq: 923_300_000,
+    },
+    MacCommand::RXParamSetupAns {
+        rx1_dr_offset_ack: false,
+        rx2_data_rate_ack: true,
+        channel_ack: true,
+    },
+    MacCommand::DevStatusReq,
+    MacCommand::DevStatusAns {
+        battery: 254,
+        margin: -32,
+    },
+    MacCommand::NewChannelReq {
+        ch_index: 3,
+        freq: 867_100_000,
+        max_dr: 5,
+        min_dr: 0,
+    },
+    MacCommand::NewChannelAns {
+        channel_freq_ok: true,
+        data_rate_ok: false,
+    },
+    MacCommand::RXTimingSetupReq { delay: 15 },
+    MacCommand::RXTimingSetupAns,
+    MacCommand::TxParamSetupReq {
+        downlink_dwell_time: true,
+        uplink_dwell_time: false,
+        max_eirp: 13,
+    },
+    MacCommand::TxParamSetupAns,
+    MacCommand::DlChannelReq {
+        ch_index: 4,
+        freq: 868_500_000,
+    },
+    MacCommand::DlChannelAns {
+        channel_freq_ok: false,
+        uplink_freq_exists: true,
+    },
+    MacCommand::DeviceTimeReq,
+    MacCommand::DeviceTimeAns {
+        seconds: 1_400_000_000,
+        fraction: 128,
+    },
+    MacCommand::PingSlotInfoReq { periodicity: 5 },
+    MacCommand::PingSlotInfoAns,
+    MacCommand::PingSlotChannelReq {
+        freq: 923_900_000,
+        data_rate: 9,
+    },
+    MacCommand::PingSlotChannelAns {
+        channel_freq_ok: true,
+        data_rate_ok: false,
+    },
+    MacCommand::BeaconFreqReq { freq: 925_100_000 },
+    MacCommand::BeaconFreqAns {
+        beacon_freq_ok: true,
+    },
+];
+
+#[test]
+fn test_mac_command_round_trip() {
+    for command in MAC_COMMANDS {
+        let mut buf: Vec<u8, 15> = Vec::new();
+        assert!(command.serialize(&mut buf));
+        assert_eq!(buf[0], command.cid());
+        assert_eq!(buf.len(), 1 + command.len());
+
+        let parsed = if command.is_uplink() {
+            MacCommand::from_uplink_bytes(buf[0], &buf[1..])
+        } else {
+            MacCommand::from_bytes(buf[0], &buf[1..])
+        };
+        assert_eq!(parsed, Some(command));
+    }
+}
+
+#[test]
+fn test_mac_command_wire_format() {
+    let mut buf: Vec<u8, 15> = Vec::new();
+    MacCommand::LinkADRReq {
+        data_rate: 5,
+        tx_power: 3,
+        ch_mask: 0xFF01,
+        ch_mask_cntl: 6,
+        nb_trans: 2,
+    }
+    .serialize(&mut buf);
+    MacCommand::DevStatusAns {
+        battery: 254,
+        margin: -1,
+    }
+    .serialize(&mut buf);
+    MacCommand::NewChannelAns {
+        channel_freq_ok: true,
+        data_rate_ok: false,
+    }
+    .serialize(&mut buf);
+    assert_eq!(
+        buf.as_slice(),
+        &[0x03, 0x53, 0x01, 0xFF, 0x62, 0x06, 0xFE, 0x3F, 0x07, 0x01]
+    );
+
+    // Commands that do not fit are rejected without touching the buffer
+    for _ in 0..4 {
+        MacCommand::DutyCycleAns.serialize(&mut buf);
+    }
+    assert_eq!(buf.len(), 14);
+    assert!(!MacCommand::DevStatusAns {
+        battery: 0,
+        margin: 0,
+    }
+    .serialize(&mut buf));
+    assert_eq!(buf.len(), 14);
+}
+
+#[test]
+fn test_mac_command_frequency_encoding() {
+    // NewChannelReq for 868.1 MHz (EU868 default channel)
+    let command = MacCommand::from_bytes(0x07, &[0x03, 0x28, 0x76, 0x84, 0x50]).unwrap();
+    assert_eq!(
+        command,
+        MacCommand::NewChannelReq {
+            ch_index: 3,
+            freq: 868_100_000,
+            max_dr: 5,
+            min_dr: 0,
+        }
+    );
+
+    // RXParamSetupReq for the US915 RX2 channel at 923.3 MHz
+    let command = MacCommand::from_bytes(0x05, &[0x08, 0x68, 0xE2, 0x8C]).unwrap();
+    assert_eq!(
+        command,
+        MacCommand::RXParamSetupReq {
+            rx1_dr_offset: 0,
+            rx2_data_rate: 8,
+            freq: 923_300_000,
+        }
+    );
+
+    let mut buf: Vec<u8, 15> = Vec::new();
+    MacCommand::DlChannelReq {
+        ch_index: 1,
+        freq: 868_300_000,
+    }
+    .serialize(&mut buf);
+    assert_eq!(buf.as_slice(), &[0x0A, 0x01, 0xF8, 0x7D, 0x84]);
+
+    // DeviceTimeAns carries little-endian GPS seconds and 1/256 s steps
+    let command = MacCommand::from_bytes(0x0D, &[0x00, 0x7A, 0x72, 0x53, 0x40]).unwrap();
+    assert_eq!(
+        command,
+        MacCommand::DeviceTimeAns {
+            seconds: 0x5372_7A00,
+            fraction: 0x40,
+        }
+    );
+    assert_eq!(
+        MacCommand::from_bytes(0x0D, &[0x00, 0x7A, 0x72, 0x53]),
+        None
+    );
+}
+
+#[test]
+fn test_time_on_air_table() {
+    // 20 byte payload, CR 4/5, explicit header, CRC on, 8 preamble symbols,
+    // matching the Semtech LoRa airtime calculator
+    let expected: [(u32, [u32; 6]); 3] = [
+        (125_000, [57, 103, 186, 371, 742, 1_319]),
+        (250_000, [29, 52, 93, 186, 330, 660]),
+        (500_000, [15, 26, 47, 93, 165, 330]),
+    ];
+
+    for (bandwidth, times) in expected {
+        for (sf, time) in (7..=12).zip(times) {
+            let params = ModulationParams {
+                spreading_factor: sf,
+                bandwidth,
+                coding_rate: 5,
+                preamble_length: 8,
+                implicit_header: None,
+                crc: true,
+                iq_inverted: false,
+                sync_word: 0x34,
+            };
+            assert_eq!(phy::time_on_air(20, &params), time, "SF{sf} {bandwidth} Hz");
+            assert_eq!(PhyLayer::<MockRadio>::time_on_air(20, &params), time);
+        }
+    }
+
+    // Implicit header, no CRC, CR 4/8 and a longer preamble
+    let params = ModulationParams {
+        spreading_factor: 9,
+        bandwidth: 125_000,
+        coding_rate: 8,
+        preamble_length: 12,
+        implicit_header: Some(10),
+        crc: false,
+        iq_inverted: false,
+        sync_word: 0x34,
+    };
+    assert_eq!(phy::time_on_air(10, &params), 165);
+}
+
+#[test]
+fn test_cad_then_receive() {
+    let mut phy = PhyLayer::new(MockRadio::new());
+    phy.radio.queue_rx_data(&[0x60, 0x01, 0x02]);
+    let mut buffer = [0u8; 64];
+
+    // A quiet channel is not listened to
+    assert_eq!(
+        phy.cad_then_receive(923_300_000, DataRate::SF12BW500, 100, &mut buffer)
+            .unwrap(),
+        0
+    );
+    assert_eq!(phy.radio.get_cad_count(), 1);
+    assert!(phy.radio.get_rx_configs().is_empty());
+
+    phy.radio.set_cad_result(true);
+    assert_eq!(
+        phy.cad_then_receive(923_300_000, DataRate::SF12BW500, 100, &mut buffer)
+            .unwrap(),
+        3
+    );
+    let rx = phy.radio.get_rx_configs();
+    assert_eq!(rx.len(), 1);
+    assert_eq!(rx[0].frequency, 923_300_000);
+    assert_eq!(rx[0].timeout_ms, 100);
+    assert!(rx[0].modulation.iq_inverted);
+    assert_eq!(&buffer[..3], &[0x60, 0x01, 0x02]);
+}
+
+#[test]
+fn test_power_metrics_long_tx() {
+    let mut metrics = PowerMetrics::new();
+    assert_eq!(metrics.get_duty_cycle_permille(), 0);
+    assert_eq!(metrics.average_current_ua(), 0);
+
+    // Ten SF12 frames of 2.5 s used to overflow the consumption estimate
+    for _ in 0..10 {
+        metrics.add_tx_time(Duration::from_millis(2_500));
+    }
+    assert_eq!(metrics.charge_mams(), 25_000 * 120);
+    assert_eq!(metrics.current_consumption, 120);
+
+    // An hour of RX and a day of sleep
+    metrics.add_rx_time(Duration::from_secs(3_600));
+    metrics.add_sleep_time(Duration::from_secs(86_400));
+    assert_eq!(metrics.charge_mams(), 3_000_000 + 43_200_000 + 86_400);
+    // 46_286_400 mA·ms over 90_025 s
+    assert_eq!(metrics.average_current_ua(), 514);
+    assert_eq!(metrics.current_consumption, 0);
+    assert_eq!(metrics.get_duty_cycle_permille(), 40);
+}
+
+#[test]
+fn test_power_manager_duty_cycle_limit() {
+    let mut manager = PowerManager::new(PowerConfig {
+        max_duty_cycle_permille: 10,
+        ..PowerConfig::default()
+    });
+    manager.record_tx(Duration::from_millis(1_000));
+    manager.record_sleep(Duration::from_millis(99_000));
+    assert_eq!(manager.get_metrics().get_duty_cycle_permille(), 10);
+    assert!(!manager.is_duty_cycle_exceeded());
+    manager.record_tx(Duration::from_millis(1_000));
+    assert!(manager.is_duty_cycle_exceeded());
+
+    assert_eq!(manager.update_battery(25), PowerState::PowerSaving);
+    assert_eq!(manager.update_battery(10), PowerState::Critical);
+    assert_eq!(manager.update_battery(255), PowerState::Normal);
+}
+
+/// Data frame of a device as heard by a repeater
+fn repeated_frame(dev_addr: DevAddr, fcnt: u32) -> Vec<u8, MAX_FRAME_SIZE> {
+    let key = AESKey::new([0x11; 16]);
+    frame::build_data_up(
+        &crypto::SoftwareCrypto,
+        &DataFrameParams {
+            confirmed: false,
+            dev_addr,
+            f_ctrl: FCtrl::new(),
+            fcnt,
+            f_opts: &[],
+            f_port: Some(1),
+            payload: &[0x42],
+        },
+        &key,
+        &key,
+    )
+    .unwrap()
+}
+
+fn should_forward<const N: usize>(cache: &mut DedupCache<N>, data: &[u8], now: u32) -> bool {
+    match PhyPayload::parse(data).unwrap() {
+        PhyPayload::DataUp(frame) => cache.should_forward(&frame, now),
+        _ => panic!("not a data frame"),
+    }
+}
+
+#[test]
+fn test_dedup_cache_expiry() {
+    let device = DevAddr::new([0x01, 0x02, 0x03, 0x04]);
+    let mut cache = DedupCache::<4>::with_ttl(1_000);
+    let first = repeated_frame(device, 1);
+    assert!(should_forward(&mut cache, &first, 0));
+    assert!(!should_forward(&mut cache, &first, 500));
+    // Seeing a duplicate again keeps it remembered
+    assert!(!should_forward(&mut cache, &first, 1_400));
+    assert!(should_forward(
+        &mut cache,
+        &repeated_frame(device, 2),
+        1_400
+    ));
+    assert_eq!(cache.len(), 2);
+
+    assert!(should_forward(&mut cache, &first, 2_400));
+    assert_eq!(cache.len(), 1);
+
+    // Expiry across the wrap-around of the clock
+    cache.clear();
+    assert!(should_forward(&mut cache, &first, u32::MAX - 100));
+    assert!(!should_forward(&mut cache, &first, 800));
+    assert!(should_forward(&mut cache, &first, 2_000));
+}
+
+#[test]
+fn test_dedup_cache_eviction() {
+    let mut cache = DedupCache::<2>::new();
+    let frames = [
+        repeated_frame(DevAddr::new([1, 0, 0, 0]), 1),
+        repeated_frame(DevAddr::new([2, 0, 0, 0]), 1),
+        repeated_frame(DevAddr::new([3, 0, 0, 0]), 1),
+    ];
+    assert!(should_forward(&mut cache, &frames[0], 0));
+    assert!(should_forward(&mut cache, &frames[1], 10));
+    assert!(!should_forward(&mut cache, &frames[0], 20));
+
+    // The second frame was seen least recently and is forgotten
+    assert!(should_forward(&mut cache, &frames[2], 30));
+    assert_eq!(cache.len(), 2);
+    assert!(!should_forward(&mut cache, &frames[0], 40));
+    assert!(should_forward(&mut cache, &frames[1], 50));
+
+    // Same DevAddr and FCnt with another MIC is another frame
+    let mut forged = frames[2].clone();
+    let last = forged.len() - 1;
+    forged[last] ^= 0xFF;
+    assert!(should_forward(&mut cache, &forged, 60));
+}
+
+#[test]
+fn test_dedup_cache_own_traffic() {
+    let own = DevAddr::new([0x26, 0x01, 0x02, 0x03]);
+    let mut cache = DedupCache::<4>::new();
+    cache.set_own_address(Some(own));
+    assert!(!should_forward(&mut cache, &repeated_frame(own, 1), 0));
+    assert!(cache.is_empty());
+    assert!(should_forward(
+        &mut cache,
+        &repeated_frame(DevAddr::new([0x26, 0x09, 0x09, 0x09]), 1),
+        0
+    ));
+
+    cache.set_own_address(None);
+    assert!(should_forward(&mut cache, &repeated_frame(own, 1), 0));
 }